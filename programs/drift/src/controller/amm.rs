@@ -180,17 +180,26 @@ pub fn update_spreads(amm: &mut AMM, reserve_price: u64) -> DriftResult<(u32, u3
         let signed_liquidity_ratio =
             liquidity_ratio.safe_mul(amm.get_protocol_owned_position()?.signum().cast()?)?;
 
-        amm_spread::calculate_reference_price_offset(
-            reserve_price,
-            amm.last_24h_avg_funding_rate,
-            signed_liquidity_ratio,
-            amm.min_order_size,
-            amm.historical_oracle_data.last_oracle_price_twap_5min,
-            amm.last_mark_price_twap_5min,
-            amm.historical_oracle_data.last_oracle_price_twap,
-            amm.last_mark_price_twap,
-            max_ref_offset,
-        )?
+        if amm.oracle_anchored {
+            // the mark premium would be the offset itself, so only inventory moves an oracle anchored amm
+            amm_spread::calculate_inventory_price_offset_pct(
+                signed_liquidity_ratio,
+                max_ref_offset,
+            )?
+            .cast()?
+        } else {
+            amm_spread::calculate_reference_price_offset(
+                reserve_price,
+                amm.last_24h_avg_funding_rate,
+                signed_liquidity_ratio,
+                amm.min_order_size,
+                amm.historical_oracle_data.last_oracle_price_twap_5min,
+                amm.last_mark_price_twap_5min,
+                amm.historical_oracle_data.last_oracle_price_twap,
+                amm.last_mark_price_twap,
+                max_ref_offset,
+            )?
+        }
     } else {
         0
     };
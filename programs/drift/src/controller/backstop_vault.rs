@@ -0,0 +1,237 @@
+use solana_program::msg;
+
+use crate::error::{DriftResult, ErrorCode};
+use crate::math::casting::Cast;
+use crate::math::insurance::{if_shares_to_vault_amount, vault_amount_to_if_shares};
+use crate::math::margin::{
+    calculate_margin_requirement_and_total_collateral_and_liability_info, calculate_user_equity,
+};
+use crate::math::safe_math::SafeMath;
+use crate::state::backstop_vault::{BackstopVault, BackstopVaultDepositor};
+use crate::state::events::{BackstopVaultRecord, StakeAction};
+use crate::state::margin_calculation::{MarginContext, MarginScope};
+use crate::state::oracle_map::OracleMap;
+use crate::state::perp_market_map::PerpMarketMap;
+use crate::state::spot_market_map::SpotMarketMap;
+use crate::state::user::User;
+use crate::{emit, validate};
+
+#[cfg(test)]
+mod tests;
+
+/// Net value of the vault's User. Shares are priced off this so every oracle has to be valid
+pub fn calculate_backstop_vault_equity(
+    user: &User,
+    perp_market_map: &PerpMarketMap,
+    spot_market_map: &SpotMarketMap,
+    oracle_map: &mut OracleMap,
+) -> DriftResult<u64> {
+    let (equity, all_oracles_valid) =
+        calculate_user_equity(user, perp_market_map, spot_market_map, oracle_map)?;
+
+    validate!(
+        all_oracles_valid,
+        ErrorCode::InvalidOracle,
+        "cant value backstop vault with invalid oracles"
+    )?;
+
+    equity.max(0).cast()
+}
+
+pub fn deposit_into_backstop_vault(
+    amount: u64,
+    vault_equity: u64,
+    backstop_vault: &mut BackstopVault,
+    depositor: &mut BackstopVaultDepositor,
+    now: i64,
+) -> DriftResult {
+    validate!(
+        !(vault_equity == 0 && backstop_vault.total_shares != 0),
+        ErrorCode::InvalidBackstopVaultEquity,
+        "backstop vault equity should be non-zero for new deposits"
+    )?;
+
+    validate!(
+        !depositor.has_withdraw_request(),
+        ErrorCode::BackstopVaultWithdrawRequestInProgress,
+        "withdraw request in progress"
+    )?;
+
+    let shares_before = depositor.shares();
+    let total_shares_before = backstop_vault.total_shares;
+
+    let n_shares = vault_amount_to_if_shares(amount, total_shares_before, vault_equity)?;
+
+    validate!(
+        n_shares > 0,
+        ErrorCode::InsufficientDeposit,
+        "deposit of {} mints no shares",
+        amount
+    )?;
+
+    // reset cost basis if no shares
+    depositor.cost_basis = if shares_before == 0 {
+        amount.cast()?
+    } else {
+        depositor.cost_basis.safe_add(amount.cast()?)?
+    };
+
+    depositor.increase_shares(n_shares)?;
+    backstop_vault.total_shares = backstop_vault.total_shares.safe_add(n_shares)?;
+
+    emit!(BackstopVaultRecord {
+        ts: now,
+        user_authority: depositor.authority,
+        action: StakeAction::Stake,
+        amount,
+        vault_equity_before: vault_equity,
+        shares_before,
+        total_shares_before,
+        shares_after: depositor.shares(),
+        total_shares_after: backstop_vault.total_shares,
+    });
+
+    Ok(())
+}
+
+pub fn request_withdraw_from_backstop_vault(
+    n_shares: u128,
+    vault_equity: u64,
+    backstop_vault: &BackstopVault,
+    depositor: &mut BackstopVaultDepositor,
+    now: i64,
+) -> DriftResult {
+    validate!(
+        !depositor.has_withdraw_request(),
+        ErrorCode::BackstopVaultWithdrawRequestInProgress,
+        "withdraw request already in progress"
+    )?;
+
+    validate!(
+        n_shares > 0 && n_shares <= depositor.shares(),
+        ErrorCode::InsufficientBackstopVaultShares,
+        "requested {} shares with {} shares",
+        n_shares,
+        depositor.shares()
+    )?;
+
+    depositor.last_withdraw_request_shares = n_shares;
+    depositor.last_withdraw_request_value =
+        if_shares_to_vault_amount(n_shares, backstop_vault.total_shares, vault_equity)?;
+    depositor.last_withdraw_request_ts = now;
+
+    emit!(BackstopVaultRecord {
+        ts: now,
+        user_authority: depositor.authority,
+        action: StakeAction::UnstakeRequest,
+        amount: depositor.last_withdraw_request_value,
+        vault_equity_before: vault_equity,
+        shares_before: depositor.shares(),
+        total_shares_before: backstop_vault.total_shares,
+        shares_after: depositor.shares(),
+        total_shares_after: backstop_vault.total_shares,
+    });
+
+    Ok(())
+}
+
+/// Burns the requested shares. The depositor gets the lower of the value at request time and the value
+/// now, so losses taken during the unstaking period are still shared
+pub fn withdraw_from_backstop_vault(
+    vault_equity: u64,
+    backstop_vault: &mut BackstopVault,
+    depositor: &mut BackstopVaultDepositor,
+    now: i64,
+) -> DriftResult<u64> {
+    let n_shares = depositor.last_withdraw_request_shares;
+
+    validate!(
+        n_shares > 0,
+        ErrorCode::NoBackstopVaultWithdrawRequestInProgress,
+        "must request a withdraw and wait the unstaking period"
+    )?;
+
+    let time_since_withdraw_request = now.safe_sub(depositor.last_withdraw_request_ts)?;
+
+    validate!(
+        time_since_withdraw_request >= backstop_vault.unstaking_period,
+        ErrorCode::TryingToRemoveLiquidityTooFast
+    )?;
+
+    let shares_before = depositor.shares();
+    let total_shares_before = backstop_vault.total_shares;
+
+    let amount = if_shares_to_vault_amount(n_shares, total_shares_before, vault_equity)?
+        .min(depositor.last_withdraw_request_value);
+
+    depositor.decrease_shares(n_shares)?;
+    backstop_vault.total_shares = backstop_vault.total_shares.safe_sub(n_shares)?;
+
+    depositor.cost_basis = depositor.cost_basis.safe_sub(amount.cast()?)?;
+
+    depositor.last_withdraw_request_shares = 0;
+    depositor.last_withdraw_request_value = 0;
+    depositor.last_withdraw_request_ts = now;
+
+    emit!(BackstopVaultRecord {
+        ts: now,
+        user_authority: depositor.authority,
+        action: StakeAction::Unstake,
+        amount,
+        vault_equity_before: vault_equity,
+        shares_before,
+        total_shares_before,
+        shares_after: depositor.shares(),
+        total_shares_after: backstop_vault.total_shares,
+    });
+
+    Ok(amount)
+}
+
+/// The vault only liquidates users that keepers have left in liquidation for the vault's liquidation delay.
+/// A liquidatable user that isn't flagged yet gets flagged here, which starts the delay
+///
+/// Returns true if the vault can liquidate the user now
+#[allow(clippy::too_many_arguments)]
+pub fn can_backstop_vault_liquidate(
+    user: &mut User,
+    margin_scope: MarginScope,
+    backstop_vault: &BackstopVault,
+    perp_market_map: &PerpMarketMap,
+    spot_market_map: &SpotMarketMap,
+    oracle_map: &mut OracleMap,
+    liquidation_margin_buffer_ratio: u32,
+    slot: u64,
+) -> DriftResult<bool> {
+    if !user.is_margin_scope_being_liquidated(margin_scope) {
+        let margin_calculation =
+            calculate_margin_requirement_and_total_collateral_and_liability_info(
+                user,
+                perp_market_map,
+                spot_market_map,
+                oracle_map,
+                MarginContext::liquidation(liquidation_margin_buffer_ratio).scope(margin_scope),
+            )?;
+
+        if margin_calculation.meets_margin_requirement() {
+            msg!("margin calculation: {:?}", margin_calculation);
+            return Err(ErrorCode::SufficientCollateral);
+        }
+
+        user.enter_margin_scope_liquidation(margin_scope, slot)?;
+
+        msg!("backstop vault liquidation delay starts at slot {}", slot);
+
+        return Ok(false);
+    }
+
+    validate!(
+        backstop_vault.can_liquidate(user.last_active_slot, slot)?,
+        ErrorCode::BackstopVaultLiquidationDelayNotOver,
+        "user in liquidation since slot {}, backstop vault waits {} slots",
+        user.last_active_slot,
+        backstop_vault.liquidation_delay
+    )?;
+
+    Ok(true)
+}
@@ -0,0 +1,310 @@
+pub mod deposit_and_withdraw {
+    use solana_program::pubkey::Pubkey;
+
+    use crate::controller::backstop_vault::{
+        deposit_into_backstop_vault, request_withdraw_from_backstop_vault,
+        withdraw_from_backstop_vault,
+    };
+    use crate::error::ErrorCode;
+    use crate::math::constants::QUOTE_PRECISION_U64;
+    use crate::state::backstop_vault::{BackstopVault, BackstopVaultDepositor};
+
+    #[test]
+    pub fn depositors_share_profits() {
+        let mut backstop_vault = BackstopVault {
+            unstaking_period: 60,
+            ..BackstopVault::default()
+        };
+        let mut depositor = BackstopVaultDepositor::new(Pubkey::new_unique());
+        let mut other_depositor = BackstopVaultDepositor::new(Pubkey::new_unique());
+
+        deposit_into_backstop_vault(
+            100 * QUOTE_PRECISION_U64,
+            0,
+            &mut backstop_vault,
+            &mut depositor,
+            0,
+        )
+        .unwrap();
+        assert_eq!(depositor.shares(), 100 * QUOTE_PRECISION_U64 as u128);
+        assert_eq!(depositor.cost_basis, 100 * QUOTE_PRECISION_U64 as i64);
+
+        // vault made $100 liquidating
+        deposit_into_backstop_vault(
+            100 * QUOTE_PRECISION_U64,
+            200 * QUOTE_PRECISION_U64,
+            &mut backstop_vault,
+            &mut other_depositor,
+            0,
+        )
+        .unwrap();
+        assert_eq!(other_depositor.shares(), 50 * QUOTE_PRECISION_U64 as u128);
+        assert_eq!(
+            backstop_vault.total_shares,
+            150 * QUOTE_PRECISION_U64 as u128
+        );
+
+        request_withdraw_from_backstop_vault(
+            depositor.shares(),
+            300 * QUOTE_PRECISION_U64,
+            &backstop_vault,
+            &mut depositor,
+            0,
+        )
+        .unwrap();
+        assert_eq!(
+            depositor.last_withdraw_request_value,
+            200 * QUOTE_PRECISION_U64
+        );
+
+        let result = deposit_into_backstop_vault(
+            QUOTE_PRECISION_U64,
+            300 * QUOTE_PRECISION_U64,
+            &mut backstop_vault,
+            &mut depositor,
+            0,
+        );
+        assert_eq!(
+            result,
+            Err(ErrorCode::BackstopVaultWithdrawRequestInProgress)
+        );
+
+        let result = withdraw_from_backstop_vault(
+            300 * QUOTE_PRECISION_U64,
+            &mut backstop_vault,
+            &mut depositor,
+            59,
+        );
+        assert_eq!(result, Err(ErrorCode::TryingToRemoveLiquidityTooFast));
+
+        // gains after the request stay in the vault
+        let amount = withdraw_from_backstop_vault(
+            330 * QUOTE_PRECISION_U64,
+            &mut backstop_vault,
+            &mut depositor,
+            60,
+        )
+        .unwrap();
+        assert_eq!(amount, 200 * QUOTE_PRECISION_U64);
+        assert_eq!(depositor.shares(), 0);
+        assert_eq!(depositor.cost_basis, -100 * QUOTE_PRECISION_U64 as i64);
+        assert_eq!(depositor.last_withdraw_request_shares, 0);
+        assert_eq!(
+            backstop_vault.total_shares,
+            50 * QUOTE_PRECISION_U64 as u128
+        );
+    }
+
+    #[test]
+    pub fn depositors_share_losses() {
+        let mut backstop_vault = BackstopVault::default();
+        let mut depositor = BackstopVaultDepositor::new(Pubkey::new_unique());
+
+        deposit_into_backstop_vault(
+            100 * QUOTE_PRECISION_U64,
+            0,
+            &mut backstop_vault,
+            &mut depositor,
+            0,
+        )
+        .unwrap();
+
+        request_withdraw_from_backstop_vault(
+            40 * QUOTE_PRECISION_U64 as u128,
+            100 * QUOTE_PRECISION_U64,
+            &backstop_vault,
+            &mut depositor,
+            0,
+        )
+        .unwrap();
+
+        // vault lost half its equity during the unstaking period
+        let amount = withdraw_from_backstop_vault(
+            50 * QUOTE_PRECISION_U64,
+            &mut backstop_vault,
+            &mut depositor,
+            0,
+        )
+        .unwrap();
+        assert_eq!(amount, 20 * QUOTE_PRECISION_U64);
+        assert_eq!(depositor.shares(), 60 * QUOTE_PRECISION_U64 as u128);
+
+        let result = withdraw_from_backstop_vault(
+            30 * QUOTE_PRECISION_U64,
+            &mut backstop_vault,
+            &mut depositor,
+            0,
+        );
+        assert_eq!(
+            result,
+            Err(ErrorCode::NoBackstopVaultWithdrawRequestInProgress)
+        );
+
+        // wiped out vault cant take new deposits
+        let result = deposit_into_backstop_vault(
+            100 * QUOTE_PRECISION_U64,
+            0,
+            &mut backstop_vault,
+            &mut depositor,
+            0,
+        );
+        assert_eq!(result, Err(ErrorCode::InvalidBackstopVaultEquity));
+    }
+}
+
+pub mod can_backstop_vault_liquidate {
+    use std::str::FromStr;
+
+    use anchor_lang::Owner;
+    use solana_program::pubkey::Pubkey;
+
+    use crate::controller::backstop_vault::can_backstop_vault_liquidate;
+    use crate::create_account_info;
+    use crate::create_anchor_account_info;
+    use crate::error::ErrorCode;
+    use crate::math::constants::{
+        AMM_RESERVE_PRECISION, BASE_PRECISION_I64, LIQUIDATION_FEE_PRECISION, MARGIN_PRECISION,
+        PEG_PRECISION, PRICE_PRECISION_I64, QUOTE_PRECISION_I64, SPOT_BALANCE_PRECISION,
+        SPOT_BALANCE_PRECISION_U64, SPOT_CUMULATIVE_INTEREST_PRECISION, SPOT_WEIGHT_PRECISION,
+    };
+    use crate::state::backstop_vault::BackstopVault;
+    use crate::state::margin_calculation::MarginScope;
+    use crate::state::oracle::{HistoricalOracleData, OracleSource};
+    use crate::state::oracle_map::OracleMap;
+    use crate::state::perp_market::{MarketStatus, PerpMarket, AMM};
+    use crate::state::perp_market_map::PerpMarketMap;
+    use crate::state::spot_market::{SpotBalanceType, SpotMarket};
+    use crate::state::spot_market_map::SpotMarketMap;
+    use crate::state::user::{PerpPosition, SpotPosition, User};
+    use crate::test_utils::*;
+    use crate::test_utils::{get_positions, get_pyth_price, get_spot_positions};
+
+    #[test]
+    pub fn waits_for_liquidation_delay() {
+        let slot = 0_u64;
+
+        let mut oracle_price = get_pyth_price(100, 6);
+        let oracle_price_key =
+            Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+        let pyth_program = crate::ids::pyth_program::id();
+        create_account_info!(
+            oracle_price,
+            &oracle_price_key,
+            &pyth_program,
+            oracle_account_info
+        );
+        let mut oracle_map = OracleMap::load_one(&oracle_account_info, slot, None).unwrap();
+
+        let mut market = PerpMarket {
+            amm: AMM {
+                base_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                sqrt_k: 100 * AMM_RESERVE_PRECISION,
+                peg_multiplier: 100 * PEG_PRECISION,
+                order_step_size: 10000000,
+                oracle: oracle_price_key,
+                historical_oracle_data: HistoricalOracleData::default_price(oracle_price.agg.price),
+                ..AMM::default()
+            },
+            margin_ratio_initial: 1000,
+            margin_ratio_maintenance: 500,
+            status: MarketStatus::Active,
+            liquidator_fee: LIQUIDATION_FEE_PRECISION / 100,
+            ..PerpMarket::default()
+        };
+        create_anchor_account_info!(market, PerpMarket, market_account_info);
+        let perp_market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+
+        let mut spot_market = SpotMarket {
+            market_index: 0,
+            oracle_source: OracleSource::QuoteAsset,
+            cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            decimals: 6,
+            initial_asset_weight: SPOT_WEIGHT_PRECISION,
+            maintenance_asset_weight: SPOT_WEIGHT_PRECISION,
+            deposit_balance: 100 * SPOT_BALANCE_PRECISION,
+            historical_oracle_data: HistoricalOracleData::default_price(PRICE_PRECISION_I64),
+            ..SpotMarket::default()
+        };
+        create_anchor_account_info!(spot_market, SpotMarket, spot_market_account_info);
+        let spot_market_map = SpotMarketMap::load_one(&spot_market_account_info, true).unwrap();
+
+        let backstop_vault = BackstopVault {
+            liquidation_delay: 150,
+            ..BackstopVault::default()
+        };
+
+        // 10 sol long with $100 of collateral only needs $70 with the 2% liquidation buffer
+        let mut user = User {
+            spot_positions: get_spot_positions(SpotPosition {
+                market_index: 0,
+                balance_type: SpotBalanceType::Deposit,
+                scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
+                ..SpotPosition::default()
+            }),
+            perp_positions: get_positions(PerpPosition {
+                market_index: 0,
+                base_asset_amount: 10 * BASE_PRECISION_I64,
+                quote_asset_amount: -1000 * QUOTE_PRECISION_I64,
+                ..PerpPosition::default()
+            }),
+            ..User::default()
+        };
+
+        let result = can_backstop_vault_liquidate(
+            &mut user,
+            MarginScope::Cross,
+            &backstop_vault,
+            &perp_market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            MARGIN_PRECISION / 50,
+            slot,
+        );
+        assert_eq!(result, Err(ErrorCode::SufficientCollateral));
+        assert!(!user.is_being_liquidated());
+
+        user.spot_positions[0].scaled_balance = 40 * SPOT_BALANCE_PRECISION_U64;
+
+        // first call flags the user and starts the delay
+        let can_liquidate = can_backstop_vault_liquidate(
+            &mut user,
+            MarginScope::Cross,
+            &backstop_vault,
+            &perp_market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            MARGIN_PRECISION / 50,
+            slot,
+        )
+        .unwrap();
+        assert!(!can_liquidate);
+        assert!(user.is_being_liquidated());
+        assert_eq!(user.last_active_slot, slot);
+
+        let result = can_backstop_vault_liquidate(
+            &mut user,
+            MarginScope::Cross,
+            &backstop_vault,
+            &perp_market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            MARGIN_PRECISION / 50,
+            slot + 149,
+        );
+        assert_eq!(result, Err(ErrorCode::BackstopVaultLiquidationDelayNotOver));
+
+        let can_liquidate = can_backstop_vault_liquidate(
+            &mut user,
+            MarginScope::Cross,
+            &backstop_vault,
+            &perp_market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            MARGIN_PRECISION / 50,
+            slot + 150,
+        )
+        .unwrap();
+        assert!(can_liquidate);
+    }
+}
@@ -251,7 +251,7 @@ pub fn update_funding_rate(
         let (funding_rate_long, funding_rate_short, funding_imbalance_revenue) =
             calculate_funding_rate_long_short(market, funding_rate.cast()?)?;
 
-        if market.amm.curve_update_intensity > 0 {
+        if market.amm.curve_update_intensity > 0 && !market.amm.oracle_anchored {
             // if funding_imbalance_revenue is positive, protocol receives.
             // if funding_imbalance_cost is positive, protocol spends.
             let funding_imbalance_cost = -funding_imbalance_revenue;
@@ -0,0 +1,226 @@
+use solana_program::msg;
+
+use crate::controller::spot_balance::update_spot_balances;
+use crate::error::{DriftResult, ErrorCode};
+use crate::math::casting::Cast;
+use crate::math::constants::QUOTE_SPOT_MARKET_INDEX;
+use crate::math::margin::{
+    meets_initial_margin_requirement, meets_withdraw_margin_requirement, MarginRequirementType,
+};
+use crate::state::oracle_map::OracleMap;
+use crate::state::perp_market_map::PerpMarketMap;
+use crate::state::spot_market::{SpotBalanceType, SpotMarket};
+use crate::state::spot_market_map::SpotMarketMap;
+use crate::state::user::{IsolatedPerpPosition, User};
+use crate::validate;
+
+#[cfg(test)]
+mod tests;
+
+/// Moves quote between the user's cross margin deposit and the deposit backing the isolated perp
+/// position in `perp_market_index`. A positive amount funds the isolated position (isolating the
+/// market if it wasn't already), a negative amount releases funds back to cross margin
+pub fn transfer_isolated_perp_position_deposit(
+    user: &mut User,
+    perp_market_index: u16,
+    amount: i64,
+    perp_market_map: &PerpMarketMap,
+    spot_market_map: &SpotMarketMap,
+    oracle_map: &mut OracleMap,
+) -> DriftResult {
+    validate!(!user.is_bankrupt(), ErrorCode::UserBankrupt)?;
+
+    validate!(
+        amount != 0,
+        ErrorCode::InsufficientDeposit,
+        "transfer amount cant be zero"
+    )?;
+
+    perp_market_map.get_ref(&perp_market_index)?;
+
+    let margin_scope = user.get_perp_margin_scope(perp_market_index);
+    let token_amount = amount.unsigned_abs().cast::<u128>()?;
+
+    if amount > 0 {
+        if !margin_scope.is_isolated() {
+            validate!(
+                !user.is_being_liquidated(),
+                ErrorCode::UserIsBeingLiquidated,
+                "cant isolate a perp position while being liquidated"
+            )?;
+
+            // pnl already accrued by the position was margined against the cross deposit
+            let is_flat = user
+                .get_perp_position(perp_market_index)
+                .map_or(true, |perp_position| perp_position.is_available());
+
+            validate!(
+                is_flat,
+                ErrorCode::InvalidIsolatedPerpPosition,
+                "perp position in market {} must be closed before it can be isolated",
+                perp_market_index
+            )?;
+
+            user.add_isolated_perp_position(perp_market_index)?;
+        }
+
+        let mut quote_spot_market = spot_market_map.get_quote_spot_market_mut()?;
+
+        let quote_deposit_token_amount =
+            user.get_spot_position(QUOTE_SPOT_MARKET_INDEX)
+                .map_or(Ok(0), |spot_position| {
+                    if spot_position.balance_type == SpotBalanceType::Deposit {
+                        spot_position.get_token_amount(&quote_spot_market)
+                    } else {
+                        Ok(0)
+                    }
+                })?;
+
+        validate!(
+            quote_deposit_token_amount >= token_amount,
+            ErrorCode::InsufficientDeposit,
+            "quote deposit {} is less than transfer amount {}",
+            quote_deposit_token_amount,
+            token_amount
+        )?;
+
+        update_spot_balances(
+            token_amount,
+            &SpotBalanceType::Borrow,
+            &mut quote_spot_market,
+            user.get_quote_spot_position_mut(),
+            false,
+        )?;
+
+        update_spot_balances(
+            token_amount,
+            &SpotBalanceType::Deposit,
+            &mut quote_spot_market,
+            user.get_isolated_perp_position_mut(perp_market_index)?,
+            false,
+        )?;
+
+        drop(quote_spot_market);
+
+        meets_withdraw_margin_requirement(
+            user,
+            perp_market_map,
+            spot_market_map,
+            oracle_map,
+            MarginRequirementType::Initial,
+        )?;
+    } else {
+        validate!(
+            margin_scope.is_isolated(),
+            ErrorCode::InvalidIsolatedPerpPosition,
+            "perp position in market {} isnt isolated",
+            perp_market_index
+        )?;
+
+        validate!(
+            !user.is_margin_scope_being_liquidated(margin_scope),
+            ErrorCode::UserIsBeingLiquidated,
+            "isolated perp position in market {} is being liquidated",
+            perp_market_index
+        )?;
+
+        let mut quote_spot_market = spot_market_map.get_quote_spot_market_mut()?;
+
+        let isolated_token_amount = user
+            .get_isolated_perp_position_mut(perp_market_index)?
+            .get_token_amount(&quote_spot_market)?;
+
+        validate!(
+            isolated_token_amount >= token_amount,
+            ErrorCode::InsufficientDeposit,
+            "isolated deposit {} is less than transfer amount {}",
+            isolated_token_amount,
+            token_amount
+        )?;
+
+        update_spot_balances(
+            token_amount,
+            &SpotBalanceType::Borrow,
+            &mut quote_spot_market,
+            user.get_isolated_perp_position_mut(perp_market_index)?,
+            false,
+        )?;
+
+        update_spot_balances(
+            token_amount,
+            &SpotBalanceType::Deposit,
+            &mut quote_spot_market,
+            user.force_get_spot_position_mut(QUOTE_SPOT_MARKET_INDEX)?,
+            false,
+        )?;
+
+        drop(quote_spot_market);
+
+        validate!(
+            meets_initial_margin_requirement(
+                user,
+                perp_market_map,
+                spot_market_map,
+                oracle_map,
+                margin_scope,
+            )?,
+            ErrorCode::InsufficientCollateral,
+            "isolated perp position in market {} doesnt meet initial margin after transfer",
+            perp_market_index
+        )?;
+
+        let is_flat = user
+            .get_perp_position(perp_market_index)
+            .map_or(true, |perp_position| perp_position.is_available());
+
+        if is_flat
+            && user
+                .get_isolated_perp_position_mut(perp_market_index)?
+                .scaled_balance
+                == 0
+        {
+            msg!(
+                "perp position in market {} no longer isolated",
+                perp_market_index
+            );
+            *user.get_isolated_perp_position_mut(perp_market_index)? =
+                IsolatedPerpPosition::default();
+        }
+    }
+
+    Ok(())
+}
+
+/// Moves the whole isolated deposit back to cross margin and stops isolating the market
+pub fn release_isolated_perp_position(
+    user: &mut User,
+    perp_market_index: u16,
+    quote_spot_market: &mut SpotMarket,
+) -> DriftResult {
+    let isolated_position = user.get_isolated_perp_position_mut(perp_market_index)?;
+    let token_amount = isolated_position.get_token_amount(quote_spot_market)?;
+
+    if token_amount > 0 {
+        update_spot_balances(
+            token_amount,
+            &SpotBalanceType::Borrow,
+            quote_spot_market,
+            isolated_position,
+            false,
+        )?;
+    }
+
+    *user.get_isolated_perp_position_mut(perp_market_index)? = IsolatedPerpPosition::default();
+
+    if token_amount > 0 {
+        update_spot_balances(
+            token_amount,
+            &SpotBalanceType::Deposit,
+            quote_spot_market,
+            user.force_get_spot_position_mut(QUOTE_SPOT_MARKET_INDEX)?,
+            false,
+        )?;
+    }
+
+    Ok(())
+}
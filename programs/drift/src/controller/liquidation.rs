@@ -4,12 +4,12 @@ use anchor_lang::prelude::*;
 use solana_program::msg;
 
 use crate::controller::amm::get_fee_pool_tokens;
-use crate::controller::funding::settle_funding_payment;
+use crate::controller::funding::{settle_funding_payment, settle_funding_payments};
 use crate::controller::lp::burn_lp_shares;
 use crate::controller::orders;
 use crate::controller::position::{
-    get_position_index, update_position_and_market, update_quote_asset_amount,
-    update_quote_asset_and_break_even_amount, PositionDirection,
+    get_position_index, update_position_and_market, update_position_with_base_asset_amount,
+    update_quote_asset_amount, update_quote_asset_and_break_even_amount, PositionDirection,
 };
 use crate::controller::repeg::update_amm_and_check_validity;
 use crate::controller::spot_balance::{
@@ -19,41 +19,51 @@ use crate::controller::spot_balance::{
 use crate::controller::spot_position::update_spot_balances_and_cumulative_deposits;
 use crate::error::{DriftResult, ErrorCode};
 use crate::get_then_update_id;
-use crate::math::bankruptcy::is_user_bankrupt;
+use crate::math::amm::update_mark_twap_from_estimates;
+use crate::math::bankruptcy::{is_isolated_perp_position_bankrupt, is_user_bankrupt};
 use crate::math::casting::Cast;
 use crate::math::constants::{
-    LIQUIDATION_FEE_PRECISION_U128, LIQUIDATION_PCT_PRECISION, QUOTE_PRECISION,
-    QUOTE_PRECISION_I128, QUOTE_PRECISION_U64, QUOTE_SPOT_MARKET_INDEX, SPOT_WEIGHT_PRECISION,
+    BASE_PRECISION_U64, LIQUIDATION_FEE_PRECISION_U128, LIQUIDATION_PCT_PRECISION,
+    PRICE_TIMES_AMM_TO_QUOTE_PRECISION_RATIO, QUOTE_PRECISION, QUOTE_PRECISION_I128,
+    QUOTE_PRECISION_U64, QUOTE_SPOT_MARKET_INDEX, SPOT_WEIGHT_PRECISION,
 };
 use crate::math::liquidation::{
-    calculate_asset_transfer_for_liability_transfer,
+    calculate_adl_score, calculate_asset_transfer_for_liability_transfer,
     calculate_base_asset_amount_to_cover_margin_shortage,
     calculate_cumulative_deposit_interest_delta_to_resolve_bankruptcy,
     calculate_funding_rate_deltas_to_resolve_bankruptcy,
     calculate_liability_transfer_implied_by_asset_amount,
     calculate_liability_transfer_to_cover_margin_shortage, calculate_liquidation_multiplier,
-    calculate_max_pct_to_liquidate, calculate_perp_if_fee, calculate_spot_if_fee,
-    validate_transfer_satisfies_limit_price, LiquidationMultiplierType,
+    calculate_liquidator_fee, calculate_max_pct_to_liquidate, calculate_perp_if_fee,
+    calculate_spot_if_fee, validate_transfer_satisfies_limit_price, LiquidationMultiplierType,
 };
 use crate::math::margin::{
     calculate_margin_requirement_and_total_collateral_and_liability_info,
-    calculate_user_safest_position_tiers, meets_initial_margin_requirement, MarginRequirementType,
+    calculate_user_safest_position_tiers, meets_initial_margin_requirement,
+    meets_maintenance_margin_requirement, MarginRequirementType,
 };
-use crate::math::oracle::DriftAction;
+use crate::math::oracle::{is_oracle_valid_for_action, DriftAction};
 use crate::math::orders::{
-    get_position_delta_for_fill, is_multiple_of_step_size, is_oracle_too_divergent_with_twap_5min,
-    standardize_base_asset_amount, standardize_base_asset_amount_ceil,
+    calculate_fill_price, get_position_delta_for_fill, is_multiple_of_step_size,
+    is_oracle_too_divergent_with_twap_5min, standardize_base_asset_amount,
+    standardize_base_asset_amount_ceil, validate_fill_price,
+    validate_fill_price_within_price_bands,
+};
+use crate::math::position::{
+    calculate_base_asset_value_and_pnl_with_oracle_price,
+    calculate_base_asset_value_with_oracle_price,
 };
-use crate::math::position::calculate_base_asset_value_with_oracle_price;
 use crate::math::safe_math::SafeMath;
 use crate::math::spot_balance::get_token_value;
 use crate::state::events::{
-    emit_stack, LPAction, LPRecord, LiquidateBorrowForPerpPnlRecord,
+    emit_stack, AdlRecord, LPAction, LPRecord, LiquidateBorrowForPerpPnlRecord,
     LiquidatePerpPnlForDepositRecord, LiquidatePerpRecord, LiquidateSpotRecord, LiquidationRecord,
     LiquidationType, OrderAction, OrderActionExplanation, OrderActionRecord, OrderRecord,
     PerpBankruptcyRecord, SpotBankruptcyRecord,
 };
-use crate::state::margin_calculation::{MarginCalculation, MarginContext, MarketIdentifier};
+use crate::state::margin_calculation::{
+    MarginCalculation, MarginContext, MarginScope, MarketIdentifier,
+};
 use crate::state::oracle_map::OracleMap;
 use crate::state::paused_operations::{PerpOperation, SpotOperation};
 use crate::state::perp_market::MarketStatus;
@@ -63,7 +73,9 @@ use crate::state::spot_market_map::SpotMarketMap;
 use crate::state::state::State;
 use crate::state::traits::Size;
 use crate::state::user::{MarketType, Order, OrderStatus, OrderType, User, UserStats};
+use crate::state::user_map::UserMap;
 use crate::validate;
+use crate::validation::perp_market::validate_amm_account_for_fill;
 
 #[cfg(test)]
 mod tests;
@@ -85,8 +97,49 @@ pub fn liquidate_perp(
     now: i64,
     state: &State,
 ) -> DriftResult {
+    liquidate_perp_with_margin_calculation(
+        market_index,
+        liquidator_max_base_asset_amount,
+        limit_price,
+        user,
+        user_key,
+        user_stats,
+        liquidator,
+        liquidator_key,
+        liquidator_stats,
+        perp_market_map,
+        spot_market_map,
+        oracle_map,
+        slot,
+        now,
+        state,
+        None,
+    )?;
+
+    Ok(())
+}
+
+fn liquidate_perp_with_margin_calculation(
+    market_index: u16,
+    liquidator_max_base_asset_amount: u64,
+    limit_price: Option<u64>,
+    user: &mut User,
+    user_key: &Pubkey,
+    user_stats: &mut UserStats,
+    liquidator: &mut User,
+    liquidator_key: &Pubkey,
+    liquidator_stats: &mut UserStats,
+    perp_market_map: &PerpMarketMap,
+    spot_market_map: &SpotMarketMap,
+    oracle_map: &mut OracleMap,
+    slot: u64,
+    now: i64,
+    state: &State,
+    margin_calculation: Option<MarginCalculation>,
+) -> DriftResult<MarginCalculation> {
     let liquidation_margin_buffer_ratio = state.liquidation_margin_buffer_ratio;
     let initial_pct_to_liquidate = state.initial_pct_to_liquidate as u128;
+    let initial_liquidator_fee_pct = state.initial_liquidator_fee_pct as u128;
     let liquidation_duration = state.liquidation_duration as u128;
 
     validate!(
@@ -128,21 +181,30 @@ pub fn liquidate_perp(
         now,
     )?;
 
-    let margin_calculation = calculate_margin_requirement_and_total_collateral_and_liability_info(
-        user,
-        perp_market_map,
-        spot_market_map,
-        oracle_map,
-        MarginContext::liquidation(liquidation_margin_buffer_ratio)
+    // an isolated position is only backed by its own deposit, so it is liquidated on its own
+    let margin_scope = user.get_perp_margin_scope(market_index);
+
+    let margin_calculation = match margin_calculation {
+        Some(margin_calculation) if !margin_scope.is_isolated() => margin_calculation
             .track_market_margin_requirement(MarketIdentifier::perp(market_index))?,
-    )?;
+        _ => calculate_margin_requirement_and_total_collateral_and_liability_info(
+            user,
+            perp_market_map,
+            spot_market_map,
+            oracle_map,
+            MarginContext::liquidation(liquidation_margin_buffer_ratio)
+                .scope(margin_scope)
+                .track_market_margin_requirement(MarketIdentifier::perp(market_index))?,
+        )?,
+    };
 
-    if !user.is_being_liquidated() && margin_calculation.meets_margin_requirement() {
+    let is_being_liquidated = user.is_margin_scope_being_liquidated(margin_scope);
+    if !is_being_liquidated && margin_calculation.meets_margin_requirement() {
         msg!("margin calculation: {:?}", margin_calculation);
         return Err(ErrorCode::SufficientCollateral);
-    } else if user.is_being_liquidated() && margin_calculation.can_exit_liquidation()? {
-        user.exit_liquidation();
-        return Ok(());
+    } else if is_being_liquidated && margin_calculation.can_exit_liquidation()? {
+        user.exit_margin_scope_liquidation(margin_scope)?;
+        return Ok(margin_calculation);
     }
 
     user.get_perp_position(market_index).map_err(|e| {
@@ -163,7 +225,14 @@ pub fn liquidate_perp(
             e
         })?;
 
-    let liquidation_id = user.enter_liquidation(slot)?;
+    validate!(
+        !liquidator.is_perp_position_isolated(market_index),
+        ErrorCode::InvalidIsolatedPerpPosition,
+        "Liquidator cant take on perp position in market {} with an isolated position",
+        market_index
+    )?;
+
+    let liquidation_id = user.enter_margin_scope_liquidation(margin_scope, slot)?;
     let mut margin_freed = 0_u64;
 
     let position_index = get_position_index(&user.perp_positions, market_index)?;
@@ -174,6 +243,12 @@ pub fn liquidate_perp(
         ErrorCode::PositionDoesntHaveOpenPositionOrOrders
     )?;
 
+    let (cancel_market_type, cancel_market_index) = if margin_scope.is_isolated() {
+        (Some(MarketType::Perp), Some(market_index))
+    } else {
+        (None, None)
+    };
+
     let canceled_order_ids = orders::cancel_orders(
         user,
         user_key,
@@ -184,8 +259,8 @@ pub fn liquidate_perp(
         now,
         slot,
         OrderActionExplanation::Liquidation,
-        None,
-        None,
+        cancel_market_type,
+        cancel_market_index,
         None,
     )?;
 
@@ -233,6 +308,9 @@ pub fn liquidate_perp(
     }
 
     // check if user exited liquidation territory
+    // the liquidation record reports the margin before any orders were canceled
+    let margin_requirement = margin_calculation.margin_requirement;
+    let total_collateral = margin_calculation.total_collateral;
     let intermediate_margin_calculation = if !canceled_order_ids.is_empty() || lp_shares > 0 {
         let intermediate_margin_calculation =
             calculate_margin_requirement_and_total_collateral_and_liability_info(
@@ -240,8 +318,7 @@ pub fn liquidate_perp(
                 perp_market_map,
                 spot_market_map,
                 oracle_map,
-                MarginContext::liquidation(liquidation_margin_buffer_ratio)
-                    .track_market_margin_requirement(MarketIdentifier::perp(market_index))?,
+                margin_calculation.context,
             )?;
 
         let initial_margin_shortage = margin_calculation.margin_shortage()?;
@@ -259,8 +336,8 @@ pub fn liquidate_perp(
                 liquidation_type: LiquidationType::LiquidatePerp,
                 user: *user_key,
                 liquidator: *liquidator_key,
-                margin_requirement: margin_calculation.margin_requirement,
-                total_collateral: margin_calculation.total_collateral,
+                margin_requirement,
+                total_collateral,
                 bankrupt: user.is_bankrupt(),
                 canceled_order_ids,
                 margin_freed,
@@ -273,8 +350,8 @@ pub fn liquidate_perp(
                 ..LiquidationRecord::default()
             });
 
-            user.exit_liquidation();
-            return Ok(());
+            user.exit_margin_scope_liquidation(margin_scope)?;
+            return Ok(intermediate_margin_calculation);
         }
 
         intermediate_margin_calculation
@@ -284,7 +361,7 @@ pub fn liquidate_perp(
 
     if user.perp_positions[position_index].base_asset_amount == 0 {
         msg!("User has no base asset amount");
-        return Ok(());
+        return Ok(intermediate_margin_calculation);
     }
 
     let liquidator_max_base_asset_amount = standardize_base_asset_amount(
@@ -332,7 +409,13 @@ pub fn liquidate_perp(
     let market = perp_market_map.get_ref(&market_index)?;
     let quote_spot_market = spot_market_map.get_ref(&market.quote_spot_market_index)?;
     let quote_oracle_price = oracle_map.get_price_data(&quote_spot_market.oracle)?.price;
-    let liquidator_fee = market.liquidator_fee;
+    let liquidator_fee = calculate_liquidator_fee(
+        user,
+        market.liquidator_fee,
+        slot,
+        initial_liquidator_fee_pct,
+        liquidation_duration,
+    )?;
     let if_liquidation_fee = calculate_perp_if_fee(
         intermediate_margin_calculation.tracked_market_margin_shortage(margin_shortage)?,
         user_base_asset_amount,
@@ -372,7 +455,7 @@ pub fn liquidate_perp(
 
     if max_base_asset_amount_allowed_to_be_transferred == 0 {
         msg!("max_base_asset_amount_allowed_to_be_transferred == 0");
-        return Ok(());
+        return Ok(intermediate_margin_calculation);
     }
 
     let base_asset_value =
@@ -502,25 +585,43 @@ pub fn liquidate_perp(
         )
     };
 
-    let margin_freed_for_perp_position = calculate_margin_freed(
+    // the user's orders were all canceled above, the liquidator's position may have been closed
+    orders::cancel_position_linked_orders(
+        liquidator,
+        liquidator_key,
+        market_index,
+        perp_market_map,
+        spot_market_map,
+        oracle_map,
+        now,
+        slot,
+        Some(liquidator_key),
+    )?;
+
+    let (margin_freed_for_perp_position, margin_calculation_after) = calculate_margin_freed(
         user,
         perp_market_map,
         spot_market_map,
         oracle_map,
-        liquidation_margin_buffer_ratio,
+        intermediate_margin_calculation.context,
         margin_shortage,
     )?;
     margin_freed = margin_freed.safe_add(margin_freed_for_perp_position)?;
     user.increment_margin_freed(margin_freed_for_perp_position)?;
 
     if base_asset_amount >= base_asset_amount_to_cover_margin_shortage {
-        user.exit_liquidation();
-    } else if is_user_bankrupt(user) {
+        user.exit_margin_scope_liquidation(margin_scope)?;
+    } else if !margin_scope.is_isolated() && is_user_bankrupt(user) {
         user.enter_bankruptcy();
     }
 
-    let liquidator_meets_initial_margin_requirement =
-        meets_initial_margin_requirement(liquidator, perp_market_map, spot_market_map, oracle_map)?;
+    let liquidator_meets_initial_margin_requirement = meets_initial_margin_requirement(
+        liquidator,
+        perp_market_map,
+        spot_market_map,
+        oracle_map,
+        MarginScope::Cross,
+    )?;
 
     validate!(
         liquidator_meets_initial_margin_requirement,
@@ -625,8 +726,8 @@ pub fn liquidate_perp(
         liquidation_type: LiquidationType::LiquidatePerp,
         user: *user_key,
         liquidator: *liquidator_key,
-        margin_requirement: margin_calculation.margin_requirement,
-        total_collateral: margin_calculation.total_collateral,
+        margin_requirement,
+        total_collateral,
         bankrupt: user.is_bankrupt(),
         canceled_order_ids,
         margin_freed,
@@ -645,28 +746,28 @@ pub fn liquidate_perp(
         ..LiquidationRecord::default()
     });
 
-    Ok(())
+    Ok(margin_calculation_after)
 }
 
-pub fn liquidate_spot(
-    asset_market_index: u16,
-    liability_market_index: u16,
-    liquidator_max_liability_transfer: u128,
+/// Lets a user below maintenance margin close their own perp position against the AMM instead of waiting
+/// for a liquidator. The user pays the liquidator fee the auction starts at plus the if fee, and both go
+/// to the insurance fund since there is no liquidator to pay
+pub fn self_liquidate_perp(
+    market_index: u16,
+    max_base_asset_amount: u64,
     limit_price: Option<u64>,
     user: &mut User,
     user_key: &Pubkey,
-    liquidator: &mut User,
-    liquidator_key: &Pubkey,
+    user_stats: &mut UserStats,
     perp_market_map: &PerpMarketMap,
     spot_market_map: &SpotMarketMap,
     oracle_map: &mut OracleMap,
-    now: i64,
     slot: u64,
+    now: i64,
     state: &State,
 ) -> DriftResult {
     let liquidation_margin_buffer_ratio = state.liquidation_margin_buffer_ratio;
-    let initial_pct_to_liquidate = state.initial_pct_to_liquidate as u128;
-    let liquidation_duration = state.liquidation_duration as u128;
+    let initial_liquidator_fee_pct = state.initial_liquidator_fee_pct as u128;
 
     validate!(
         !user.is_bankrupt(),
@@ -674,123 +775,607 @@ pub fn liquidate_spot(
         "user bankrupt",
     )?;
 
-    validate!(
-        !liquidator.is_bankrupt(),
-        ErrorCode::UserBankrupt,
-        "liquidator bankrupt",
-    )?;
-
-    let asset_spot_market = spot_market_map.get_ref(&asset_market_index)?;
+    let market = perp_market_map.get_ref(&market_index)?;
 
     validate!(
-        !asset_spot_market.is_operation_paused(SpotOperation::Liquidation),
+        !market.is_operation_paused(PerpOperation::Liquidation),
         ErrorCode::InvalidLiquidation,
         "Liquidation operation is paused for market {}",
-        asset_market_index
+        market_index
     )?;
 
-    drop(asset_spot_market);
-
-    let liability_spot_market = spot_market_map.get_ref(&liability_market_index)?;
+    validate!(
+        matches!(
+            market.status,
+            MarketStatus::Active | MarketStatus::ReduceOnly
+        ),
+        ErrorCode::MarketFillOrderPaused,
+        "Market not active",
+    )?;
 
     validate!(
-        !liability_spot_market.is_operation_paused(SpotOperation::Liquidation),
-        ErrorCode::InvalidLiquidation,
-        "Liquidation operation is paused for market {}",
-        liability_market_index
+        !state.amm_paused()? && !market.is_operation_paused(PerpOperation::AmmFill),
+        ErrorCode::MarketFillOrderPaused,
+        "AMM fills paused for market {}",
+        market_index
     )?;
 
-    drop(liability_spot_market);
+    drop(market);
 
-    // validate user and liquidator have spot balances
-    user.get_spot_position(asset_market_index).map_err(|_| {
-        msg!(
-            "User does not have a spot balance for asset market {}",
-            asset_market_index
-        );
-        ErrorCode::CouldNotFindSpotPosition
-    })?;
+    // Settle user's funding payments so that collateral is up to date
+    settle_funding_payment(
+        user,
+        user_key,
+        perp_market_map.get_ref_mut(&market_index)?.deref_mut(),
+        now,
+    )?;
 
-    user.get_spot_position(liability_market_index)
-        .map_err(|_| {
-            msg!(
-                "User does not have a spot balance for liability market {}",
-                liability_market_index
-            );
-            ErrorCode::CouldNotFindSpotPosition
-        })?;
+    let margin_scope = user.get_perp_margin_scope(market_index);
 
-    liquidator
-        .force_get_spot_position_mut(asset_market_index)
-        .map_err(|e| {
-            msg!("Liquidator has no available spot balances to take on deposit");
-            e
-        })?;
+    let margin_calculation = calculate_margin_requirement_and_total_collateral_and_liability_info(
+        user,
+        perp_market_map,
+        spot_market_map,
+        oracle_map,
+        MarginContext::liquidation(liquidation_margin_buffer_ratio)
+            .scope(margin_scope)
+            .track_market_margin_requirement(MarketIdentifier::perp(market_index))?,
+    )?;
 
-    liquidator
-        .force_get_spot_position_mut(liability_market_index)
-        .map_err(|e| {
-            msg!("Liquidator has no available spot balances to take on borrow");
-            e
-        })?;
+    let is_being_liquidated = user.is_margin_scope_being_liquidated(margin_scope);
+    if !is_being_liquidated && margin_calculation.meets_margin_requirement() {
+        msg!("margin calculation: {:?}", margin_calculation);
+        return Err(ErrorCode::SufficientCollateral);
+    } else if is_being_liquidated && margin_calculation.can_exit_liquidation()? {
+        user.exit_margin_scope_liquidation(margin_scope)?;
+        return Ok(());
+    }
 
-    let (asset_amount, asset_price, asset_decimals, asset_weight, asset_liquidation_multiplier) = {
-        let mut asset_market = spot_market_map.get_ref_mut(&asset_market_index)?;
-        let (asset_price_data, validity_guard_rails) =
-            oracle_map.get_price_data_and_guard_rails(&asset_market.oracle)?;
+    let position_index = get_position_index(&user.perp_positions, market_index)?;
+    validate!(
+        user.perp_positions[position_index].is_open_position(),
+        ErrorCode::UserHasNoPositionInMarket,
+        "user has no position in market {}",
+        market_index
+    )?;
 
-        update_spot_market_and_check_validity(
-            &mut asset_market,
-            asset_price_data,
-            validity_guard_rails,
-            now,
-            Some(DriftAction::Liquidate),
-        )?;
+    validate!(
+        user.perp_positions[position_index].lp_shares == 0,
+        ErrorCode::InvalidLiquidation,
+        "lp shares must be burned before self liquidating"
+    )?;
 
-        let spot_deposit_position = user.get_spot_position(asset_market_index)?;
+    let liquidation_id = user.enter_margin_scope_liquidation(margin_scope, slot)?;
 
-        validate!(
-            spot_deposit_position.balance_type == SpotBalanceType::Deposit,
-            ErrorCode::WrongSpotBalanceType,
-            "User did not have a deposit for the asset market index"
-        )?;
+    let (cancel_market_type, cancel_market_index) = if margin_scope.is_isolated() {
+        (Some(MarketType::Perp), Some(market_index))
+    } else {
+        (None, None)
+    };
 
-        let token_amount = spot_deposit_position.get_token_amount(&asset_market)?;
+    let canceled_order_ids = orders::cancel_orders(
+        user,
+        user_key,
+        None,
+        perp_market_map,
+        spot_market_map,
+        oracle_map,
+        now,
+        slot,
+        OrderActionExplanation::Liquidation,
+        cancel_market_type,
+        cancel_market_index,
+        None,
+    )?;
 
-        validate!(
-            token_amount != 0,
-            ErrorCode::InvalidSpotPosition,
-            "asset token amount zero for market index = {}",
-            asset_market_index
-        )?;
+    let mut market = perp_market_map.get_ref_mut(&market_index)?;
+    let oracle_price_data = oracle_map.get_price_data(&market.amm.oracle)?;
 
-        let asset_price = asset_price_data.price;
-        (
-            token_amount,
-            asset_price,
-            asset_market.decimals,
-            asset_market.maintenance_asset_weight,
-            calculate_liquidation_multiplier(
-                asset_market.liquidator_fee,
-                LiquidationMultiplierType::Premium,
-            )?,
-        )
-    };
+    update_amm_and_check_validity(
+        &mut market,
+        oracle_price_data,
+        state,
+        now,
+        slot,
+        Some(DriftAction::Liquidate),
+    )?;
 
-    let (
-        liability_amount,
-        liability_price,
-        liability_decimals,
-        liability_weight,
-        liability_liquidation_multiplier,
-    ) = {
-        let mut liability_market = spot_market_map.get_ref_mut(&liability_market_index)?;
-        let (liability_price_data, validity_guard_rails) =
-            oracle_map.get_price_data_and_guard_rails(&liability_market.oracle)?;
+    let oracle_price = oracle_price_data.price;
 
-        update_spot_market_and_check_validity(
-            &mut liability_market,
-            liability_price_data,
+    drop(market);
+
+    // the liquidation record reports the margin before any orders were canceled
+    let margin_requirement = margin_calculation.margin_requirement;
+    let total_collateral = margin_calculation.total_collateral;
+    let margin_calculation = if !canceled_order_ids.is_empty() {
+        calculate_margin_requirement_and_total_collateral_and_liability_info(
+            user,
+            perp_market_map,
+            spot_market_map,
+            oracle_map,
+            margin_calculation.context,
+        )?
+    } else {
+        margin_calculation
+    };
+
+    let oracle_price_too_divergent = is_oracle_too_divergent_with_twap_5min(
+        oracle_price,
+        perp_market_map
+            .get_ref(&market_index)?
+            .amm
+            .historical_oracle_data
+            .last_oracle_price_twap_5min,
+        state
+            .oracle_guard_rails
+            .max_oracle_twap_5min_percent_divergence()
+            .cast()?,
+    )?;
+
+    validate!(!oracle_price_too_divergent, ErrorCode::PriceBandsBreached)?;
+
+    let max_base_asset_amount = standardize_base_asset_amount(
+        max_base_asset_amount,
+        perp_market_map.get_ref(&market_index)?.amm.order_step_size,
+    )?;
+
+    validate!(
+        max_base_asset_amount != 0,
+        ErrorCode::InvalidBaseAssetAmountForLiquidatePerp,
+        "max_base_asset_amount must be greater or equal to the step size",
+    )?;
+
+    let user_base_asset_amount = user.perp_positions[position_index]
+        .base_asset_amount
+        .unsigned_abs();
+    let base_asset_amount = user_base_asset_amount.min(max_base_asset_amount);
+
+    let margin_shortage = margin_calculation.margin_shortage()?;
+
+    let market = perp_market_map.get_ref(&market_index)?;
+    let quote_spot_market = spot_market_map.get_ref(&market.quote_spot_market_index)?;
+    let quote_oracle_price = oracle_map.get_price_data(&quote_spot_market.oracle)?.price;
+    let margin_ratio_with_buffer = market
+        .get_margin_ratio(
+            user_base_asset_amount.cast()?,
+            MarginRequirementType::Maintenance,
+        )?
+        .safe_add(liquidation_margin_buffer_ratio)?;
+    // no waiting means no auction, the user always pays the fee liquidators start at
+    let liquidator_fee = market
+        .liquidator_fee
+        .cast::<u128>()?
+        .safe_mul(initial_liquidator_fee_pct.min(LIQUIDATION_PCT_PRECISION))?
+        .safe_div(LIQUIDATION_PCT_PRECISION)?
+        .cast::<u32>()?;
+    let if_liquidation_fee = calculate_perp_if_fee(
+        margin_calculation.tracked_market_margin_shortage(margin_shortage)?,
+        user_base_asset_amount,
+        margin_ratio_with_buffer,
+        liquidator_fee,
+        oracle_price,
+        quote_oracle_price,
+        market.if_liquidation_fee,
+    )?;
+    drop(market);
+    drop(quote_spot_market);
+
+    let base_asset_value =
+        calculate_base_asset_value_with_oracle_price(base_asset_amount.cast()?, oracle_price)?
+            .cast::<u64>()?;
+
+    let if_fee = -base_asset_value
+        .cast::<u128>()?
+        .safe_mul(liquidator_fee.safe_add(if_liquidation_fee)?.cast()?)?
+        .safe_div(LIQUIDATION_FEE_PRECISION_U128)?
+        .cast::<i64>()?;
+
+    let user_existing_position_direction = user.perp_positions[position_index].get_direction();
+    let user_position_direction_to_close =
+        user.perp_positions[position_index].get_direction_to_close();
+
+    let (quote_asset_amount, quote_asset_amount_surplus, fill_record_id) = {
+        let mut market = perp_market_map.get_ref_mut(&market_index)?;
+
+        validate_amm_account_for_fill(&market.amm, user_position_direction_to_close)?;
+
+        let reserve_price_before = market.amm.reserve_price()?;
+        let market_side_price = match user_position_direction_to_close {
+            PositionDirection::Long => market.amm.ask_price(reserve_price_before)?,
+            PositionDirection::Short => market.amm.bid_price(reserve_price_before)?,
+        };
+
+        let sanitize_clamp_denominator = market.get_sanitize_clamp_denominator()?;
+        update_mark_twap_from_estimates(
+            &mut market.amm,
+            now,
+            Some(market_side_price),
+            Some(user_position_direction_to_close),
+            sanitize_clamp_denominator,
+        )?;
+
+        // the amm takes the other side at its own price
+        let (quote_asset_amount, quote_asset_amount_surplus, _) =
+            update_position_with_base_asset_amount(
+                base_asset_amount,
+                user_position_direction_to_close,
+                &mut market,
+                user,
+                position_index,
+                None,
+            )?;
+
+        let fill_price =
+            calculate_fill_price(quote_asset_amount, base_asset_amount, BASE_PRECISION_U64)?;
+
+        validate_fill_price_within_price_bands(
+            fill_price,
+            user_position_direction_to_close,
+            oracle_price,
+            market
+                .amm
+                .historical_oracle_data
+                .last_oracle_price_twap_5min,
+            market.margin_ratio_initial,
+            state
+                .oracle_guard_rails
+                .max_oracle_twap_5min_percent_divergence(),
+        )?;
+
+        if let Some(limit_price) = limit_price {
+            validate_fill_price(
+                quote_asset_amount,
+                base_asset_amount,
+                BASE_PRECISION_U64,
+                user_position_direction_to_close,
+                limit_price,
+                true,
+            )?;
+        }
+
+        let user_position = &mut user.perp_positions[position_index];
+        update_quote_asset_and_break_even_amount(user_position, &mut market, if_fee)?;
+
+        validate!(
+            is_multiple_of_step_size(
+                user_position.base_asset_amount.unsigned_abs(),
+                market.amm.order_step_size
+            )?,
+            ErrorCode::InvalidPerpPosition,
+            "base asset amount {} step size {}",
+            user_position.base_asset_amount,
+            market.amm.order_step_size
+        )?;
+
+        market.amm.total_liquidation_fee = market
+            .amm
+            .total_liquidation_fee
+            .safe_add(if_fee.unsigned_abs().cast()?)?;
+
+        // the amm keeps its spread like any other fill
+        market.amm.total_fee = market
+            .amm
+            .total_fee
+            .safe_add(quote_asset_amount_surplus.cast()?)?;
+        market.amm.total_mm_fee = market
+            .amm
+            .total_mm_fee
+            .safe_add(quote_asset_amount_surplus.cast()?)?;
+        market.amm.total_fee_minus_distributions = market
+            .amm
+            .total_fee_minus_distributions
+            .safe_add(quote_asset_amount_surplus.cast()?)?;
+        market.amm.net_revenue_since_last_funding = market
+            .amm
+            .net_revenue_since_last_funding
+            .safe_add(quote_asset_amount_surplus)?;
+
+        let fill_record_id = get_then_update_id!(market, next_fill_record_id);
+
+        (
+            quote_asset_amount,
+            quote_asset_amount_surplus,
+            fill_record_id,
+        )
+    };
+
+    user_stats.update_taker_volume_30d(quote_asset_amount, now)?;
+
+    let (margin_freed, margin_calculation_after) = calculate_margin_freed(
+        user,
+        perp_market_map,
+        spot_market_map,
+        oracle_map,
+        margin_calculation.context,
+        margin_shortage,
+    )?;
+    user.increment_margin_freed(margin_freed)?;
+
+    if margin_calculation_after.can_exit_liquidation()? {
+        user.exit_margin_scope_liquidation(margin_scope)?;
+    } else if !margin_scope.is_isolated() && is_user_bankrupt(user) {
+        user.enter_bankruptcy();
+    }
+
+    let user_order_id = get_then_update_id!(user, next_order_id);
+
+    let user_order = Order {
+        slot,
+        price: limit_price.unwrap_or(0),
+        base_asset_amount,
+        order_id: user_order_id,
+        market_index,
+        status: OrderStatus::Open,
+        order_type: if limit_price.is_some() {
+            OrderType::Limit
+        } else {
+            OrderType::Market
+        },
+        market_type: MarketType::Perp,
+        direction: user_position_direction_to_close,
+        existing_position_direction: user_existing_position_direction,
+        ..Order::default()
+    };
+
+    emit!(OrderRecord {
+        ts: now,
+        user: *user_key,
+        order: user_order
+    });
+
+    let fill_record = OrderActionRecord {
+        ts: now,
+        action: OrderAction::Fill,
+        action_explanation: OrderActionExplanation::Liquidation,
+        market_index,
+        market_type: MarketType::Perp,
+        filler: None,
+        filler_reward: None,
+        fill_record_id: Some(fill_record_id),
+        base_asset_amount_filled: Some(base_asset_amount),
+        quote_asset_amount_filled: Some(quote_asset_amount),
+        taker_fee: Some(if_fee.unsigned_abs()),
+        maker_fee: None,
+        referrer_reward: None,
+        quote_asset_amount_surplus: Some(quote_asset_amount_surplus),
+        spot_fulfillment_method_fee: None,
+        taker: Some(*user_key),
+        taker_order_id: Some(user_order_id),
+        taker_order_direction: Some(user_position_direction_to_close),
+        taker_order_base_asset_amount: Some(base_asset_amount),
+        taker_order_cumulative_base_asset_amount_filled: Some(base_asset_amount),
+        taker_order_cumulative_quote_asset_amount_filled: Some(quote_asset_amount),
+        maker: None,
+        maker_order_id: None,
+        maker_order_direction: None,
+        maker_order_base_asset_amount: None,
+        maker_order_cumulative_base_asset_amount_filled: None,
+        maker_order_cumulative_quote_asset_amount_filled: None,
+        oracle_price,
+    };
+    emit!(fill_record);
+
+    let user_position_delta = get_position_delta_for_fill(
+        base_asset_amount,
+        quote_asset_amount,
+        user_position_direction_to_close,
+    )?;
+
+    emit!(LiquidationRecord {
+        ts: now,
+        liquidation_id,
+        liquidation_type: LiquidationType::LiquidatePerp,
+        user: *user_key,
+        liquidator: *user_key,
+        margin_requirement,
+        total_collateral,
+        bankrupt: user.is_bankrupt(),
+        canceled_order_ids,
+        margin_freed,
+        liquidate_perp: LiquidatePerpRecord {
+            market_index,
+            oracle_price,
+            base_asset_amount: user_position_delta.base_asset_amount,
+            quote_asset_amount: user_position_delta.quote_asset_amount,
+            user_order_id,
+            fill_record_id,
+            if_fee: if_fee.unsigned_abs(),
+            ..LiquidatePerpRecord::default()
+        },
+        ..LiquidationRecord::default()
+    });
+
+    Ok(())
+}
+
+pub fn liquidate_spot(
+    asset_market_index: u16,
+    liability_market_index: u16,
+    liquidator_max_liability_transfer: u128,
+    limit_price: Option<u64>,
+    user: &mut User,
+    user_key: &Pubkey,
+    liquidator: &mut User,
+    liquidator_key: &Pubkey,
+    perp_market_map: &PerpMarketMap,
+    spot_market_map: &SpotMarketMap,
+    oracle_map: &mut OracleMap,
+    now: i64,
+    slot: u64,
+    state: &State,
+) -> DriftResult {
+    liquidate_spot_with_margin_calculation(
+        asset_market_index,
+        liability_market_index,
+        liquidator_max_liability_transfer,
+        limit_price,
+        user,
+        user_key,
+        liquidator,
+        liquidator_key,
+        perp_market_map,
+        spot_market_map,
+        oracle_map,
+        now,
+        slot,
+        state,
+        None,
+    )?;
+
+    Ok(())
+}
+
+fn liquidate_spot_with_margin_calculation(
+    asset_market_index: u16,
+    liability_market_index: u16,
+    liquidator_max_liability_transfer: u128,
+    limit_price: Option<u64>,
+    user: &mut User,
+    user_key: &Pubkey,
+    liquidator: &mut User,
+    liquidator_key: &Pubkey,
+    perp_market_map: &PerpMarketMap,
+    spot_market_map: &SpotMarketMap,
+    oracle_map: &mut OracleMap,
+    now: i64,
+    slot: u64,
+    state: &State,
+    margin_calculation: Option<MarginCalculation>,
+) -> DriftResult<MarginCalculation> {
+    let liquidation_margin_buffer_ratio = state.liquidation_margin_buffer_ratio;
+    let initial_pct_to_liquidate = state.initial_pct_to_liquidate as u128;
+    let initial_liquidator_fee_pct = state.initial_liquidator_fee_pct as u128;
+    let liquidation_duration = state.liquidation_duration as u128;
+
+    validate!(
+        !user.is_bankrupt(),
+        ErrorCode::UserBankrupt,
+        "user bankrupt",
+    )?;
+
+    validate!(
+        !liquidator.is_bankrupt(),
+        ErrorCode::UserBankrupt,
+        "liquidator bankrupt",
+    )?;
+
+    let asset_spot_market = spot_market_map.get_ref(&asset_market_index)?;
+
+    validate!(
+        !asset_spot_market.is_operation_paused(SpotOperation::Liquidation),
+        ErrorCode::InvalidLiquidation,
+        "Liquidation operation is paused for market {}",
+        asset_market_index
+    )?;
+
+    drop(asset_spot_market);
+
+    let liability_spot_market = spot_market_map.get_ref(&liability_market_index)?;
+
+    validate!(
+        !liability_spot_market.is_operation_paused(SpotOperation::Liquidation),
+        ErrorCode::InvalidLiquidation,
+        "Liquidation operation is paused for market {}",
+        liability_market_index
+    )?;
+
+    drop(liability_spot_market);
+
+    // validate user and liquidator have spot balances
+    user.get_spot_position(asset_market_index).map_err(|_| {
+        msg!(
+            "User does not have a spot balance for asset market {}",
+            asset_market_index
+        );
+        ErrorCode::CouldNotFindSpotPosition
+    })?;
+
+    user.get_spot_position(liability_market_index)
+        .map_err(|_| {
+            msg!(
+                "User does not have a spot balance for liability market {}",
+                liability_market_index
+            );
+            ErrorCode::CouldNotFindSpotPosition
+        })?;
+
+    liquidator
+        .force_get_spot_position_mut(asset_market_index)
+        .map_err(|e| {
+            msg!("Liquidator has no available spot balances to take on deposit");
+            e
+        })?;
+
+    liquidator
+        .force_get_spot_position_mut(liability_market_index)
+        .map_err(|e| {
+            msg!("Liquidator has no available spot balances to take on borrow");
+            e
+        })?;
+
+    let (asset_amount, asset_price, asset_decimals, asset_weight, asset_liquidation_multiplier) = {
+        let mut asset_market = spot_market_map.get_ref_mut(&asset_market_index)?;
+        let (asset_price_data, validity_guard_rails) =
+            oracle_map.get_price_data_and_guard_rails(&asset_market.oracle)?;
+
+        update_spot_market_and_check_validity(
+            &mut asset_market,
+            asset_price_data,
+            validity_guard_rails,
+            now,
+            Some(DriftAction::Liquidate),
+        )?;
+
+        let spot_deposit_position = user.get_spot_position(asset_market_index)?;
+
+        validate!(
+            spot_deposit_position.balance_type == SpotBalanceType::Deposit,
+            ErrorCode::WrongSpotBalanceType,
+            "User did not have a deposit for the asset market index"
+        )?;
+
+        let token_amount = spot_deposit_position.get_token_amount(&asset_market)?;
+
+        validate!(
+            token_amount != 0,
+            ErrorCode::InvalidSpotPosition,
+            "asset token amount zero for market index = {}",
+            asset_market_index
+        )?;
+
+        let asset_price = asset_price_data.price;
+        (
+            token_amount,
+            asset_price,
+            asset_market.decimals,
+            asset_market.maintenance_asset_weight,
+            calculate_liquidation_multiplier(
+                calculate_liquidator_fee(
+                    user,
+                    asset_market.liquidator_fee,
+                    slot,
+                    initial_liquidator_fee_pct,
+                    liquidation_duration,
+                )?,
+                LiquidationMultiplierType::Premium,
+            )?,
+        )
+    };
+
+    let (
+        liability_amount,
+        liability_price,
+        liability_decimals,
+        liability_weight,
+        liability_liquidation_multiplier,
+    ) = {
+        let mut liability_market = spot_market_map.get_ref_mut(&liability_market_index)?;
+        let (liability_price_data, validity_guard_rails) =
+            oracle_map.get_price_data_and_guard_rails(&liability_market.oracle)?;
+
+        update_spot_market_and_check_validity(
+            &mut liability_market,
+            liability_price_data,
             validity_guard_rails,
             now,
             Some(DriftAction::Liquidate),
@@ -821,27 +1406,37 @@ pub fn liquidate_spot(
             liability_market.decimals,
             liability_market.maintenance_liability_weight,
             calculate_liquidation_multiplier(
-                liability_market.liquidator_fee,
+                calculate_liquidator_fee(
+                    user,
+                    liability_market.liquidator_fee,
+                    slot,
+                    initial_liquidator_fee_pct,
+                    liquidation_duration,
+                )?,
                 LiquidationMultiplierType::Discount,
             )?,
         )
     };
 
-    let margin_calculation = calculate_margin_requirement_and_total_collateral_and_liability_info(
-        user,
-        perp_market_map,
-        spot_market_map,
-        oracle_map,
-        MarginContext::liquidation(liquidation_margin_buffer_ratio)
+    let margin_calculation = match margin_calculation {
+        Some(margin_calculation) => margin_calculation
             .track_market_margin_requirement(MarketIdentifier::spot(liability_market_index))?,
-    )?;
+        None => calculate_margin_requirement_and_total_collateral_and_liability_info(
+            user,
+            perp_market_map,
+            spot_market_map,
+            oracle_map,
+            MarginContext::liquidation(liquidation_margin_buffer_ratio)
+                .track_market_margin_requirement(MarketIdentifier::spot(liability_market_index))?,
+        )?,
+    };
 
     if !user.is_being_liquidated() && margin_calculation.meets_margin_requirement() {
         msg!("margin calculation: {:?}", margin_calculation);
         return Err(ErrorCode::SufficientCollateral);
     } else if user.is_being_liquidated() && margin_calculation.can_exit_liquidation()? {
         user.exit_liquidation();
-        return Ok(());
+        return Ok(margin_calculation);
     }
 
     let liquidation_id = user.enter_liquidation(slot)?;
@@ -863,6 +1458,9 @@ pub fn liquidate_spot(
     )?;
 
     // check if user exited liquidation territory
+    // the liquidation record reports the margin before any orders were canceled
+    let margin_requirement = margin_calculation.margin_requirement;
+    let total_collateral = margin_calculation.total_collateral;
     let intermediate_margin_calculation = if !canceled_order_ids.is_empty() {
         let intermediate_margin_calculation =
             calculate_margin_requirement_and_total_collateral_and_liability_info(
@@ -870,10 +1468,7 @@ pub fn liquidate_spot(
                 perp_market_map,
                 spot_market_map,
                 oracle_map,
-                MarginContext::liquidation(liquidation_margin_buffer_ratio)
-                    .track_market_margin_requirement(MarketIdentifier::spot(
-                        liability_market_index,
-                    ))?,
+                margin_calculation.context,
             )?;
 
         let initial_margin_shortage = margin_calculation.margin_shortage()?;
@@ -891,8 +1486,8 @@ pub fn liquidate_spot(
                 liquidation_type: LiquidationType::LiquidateSpot,
                 user: *user_key,
                 liquidator: *liquidator_key,
-                margin_requirement: margin_calculation.margin_requirement,
-                total_collateral: margin_calculation.total_collateral,
+                margin_requirement,
+                total_collateral,
                 bankrupt: user.is_bankrupt(),
                 canceled_order_ids,
                 margin_freed,
@@ -909,7 +1504,7 @@ pub fn liquidate_spot(
             });
 
             user.exit_liquidation();
-            return Ok(());
+            return Ok(intermediate_margin_calculation);
         }
 
         intermediate_margin_calculation
@@ -962,7 +1557,7 @@ pub fn liquidate_spot(
 
     if max_liability_allowed_to_be_transferred == 0 {
         msg!("max_liability_allowed_to_be_transferred == 0");
-        return Ok(());
+        return Ok(intermediate_margin_calculation);
     }
 
     // Given the user's deposit amount, how much borrow can be transferred?
@@ -1116,12 +1711,12 @@ pub fn liquidate_spot(
         )?;
     }
 
-    let margin_freed_from_liability = calculate_margin_freed(
+    let (margin_freed_from_liability, margin_calculation_after) = calculate_margin_freed(
         user,
         perp_market_map,
         spot_market_map,
         oracle_map,
-        liquidation_margin_buffer_ratio,
+        intermediate_margin_calculation.context,
         margin_shortage,
     )?;
     margin_freed = margin_freed.safe_add(margin_freed_from_liability)?;
@@ -1133,8 +1728,13 @@ pub fn liquidate_spot(
         user.enter_bankruptcy();
     }
 
-    let liquidator_meets_initial_margin_requirement =
-        meets_initial_margin_requirement(liquidator, perp_market_map, spot_market_map, oracle_map)?;
+    let liquidator_meets_initial_margin_requirement = meets_initial_margin_requirement(
+        liquidator,
+        perp_market_map,
+        spot_market_map,
+        oracle_map,
+        MarginScope::Cross,
+    )?;
 
     validate!(
         liquidator_meets_initial_margin_requirement,
@@ -1148,8 +1748,8 @@ pub fn liquidate_spot(
         liquidation_type: LiquidationType::LiquidateSpot,
         user: *user_key,
         liquidator: *liquidator_key,
-        margin_requirement: margin_calculation.margin_requirement,
-        total_collateral: margin_calculation.total_collateral,
+        margin_requirement,
+        total_collateral,
         bankrupt: user.is_bankrupt(),
         margin_freed,
         liquidate_spot: LiquidateSpotRecord {
@@ -1164,7 +1764,7 @@ pub fn liquidate_spot(
         ..LiquidationRecord::default()
     });
 
-    Ok(())
+    Ok(margin_calculation_after)
 }
 
 pub fn liquidate_borrow_for_perp_pnl(
@@ -1183,8 +1783,53 @@ pub fn liquidate_borrow_for_perp_pnl(
     slot: u64,
     liquidation_margin_buffer_ratio: u32,
     initial_pct_to_liquidate: u128,
+    initial_liquidator_fee_pct: u128,
     liquidation_duration: u128,
 ) -> DriftResult {
+    liquidate_borrow_for_perp_pnl_with_margin_calculation(
+        perp_market_index,
+        liability_market_index,
+        liquidator_max_liability_transfer,
+        limit_price,
+        user,
+        user_key,
+        liquidator,
+        liquidator_key,
+        perp_market_map,
+        spot_market_map,
+        oracle_map,
+        now,
+        slot,
+        liquidation_margin_buffer_ratio,
+        initial_pct_to_liquidate,
+        initial_liquidator_fee_pct,
+        liquidation_duration,
+        None,
+    )?;
+
+    Ok(())
+}
+
+fn liquidate_borrow_for_perp_pnl_with_margin_calculation(
+    perp_market_index: u16,
+    liability_market_index: u16,
+    liquidator_max_liability_transfer: u128,
+    limit_price: Option<u64>,
+    user: &mut User,
+    user_key: &Pubkey,
+    liquidator: &mut User,
+    liquidator_key: &Pubkey,
+    perp_market_map: &PerpMarketMap,
+    spot_market_map: &SpotMarketMap,
+    oracle_map: &mut OracleMap,
+    now: i64,
+    slot: u64,
+    liquidation_margin_buffer_ratio: u32,
+    initial_pct_to_liquidate: u128,
+    initial_liquidator_fee_pct: u128,
+    liquidation_duration: u128,
+    margin_calculation: Option<MarginCalculation>,
+) -> DriftResult<MarginCalculation> {
     // liquidator takes over a user borrow in exchange for that user's positive perpetual pnl
     // can only be done once a user's perpetual position size is 0
     // blocks borrows where oracle is deemed invalid
@@ -1231,6 +1876,14 @@ pub fn liquidate_borrow_for_perp_pnl(
         e
     })?;
 
+    validate!(
+        !user.is_perp_position_isolated(perp_market_index)
+            && !liquidator.is_perp_position_isolated(perp_market_index),
+        ErrorCode::InvalidIsolatedPerpPosition,
+        "Perp pnl in market {} is settled against its isolated deposit",
+        perp_market_index
+    )?;
+
     user.get_spot_position(liability_market_index)
         .map_err(|_| {
             msg!(
@@ -1308,7 +1961,13 @@ pub fn liquidate_borrow_for_perp_pnl(
             6_u32,
             pnl_asset_weight,
             calculate_liquidation_multiplier(
-                market.liquidator_fee,
+                calculate_liquidator_fee(
+                    user,
+                    market.liquidator_fee,
+                    slot,
+                    initial_liquidator_fee_pct,
+                    liquidation_duration,
+                )?,
                 LiquidationMultiplierType::Premium,
             )?,
         )
@@ -1356,26 +2015,35 @@ pub fn liquidate_borrow_for_perp_pnl(
             liability_market.decimals,
             liability_market.maintenance_liability_weight,
             calculate_liquidation_multiplier(
-                liability_market.liquidator_fee,
+                calculate_liquidator_fee(
+                    user,
+                    liability_market.liquidator_fee,
+                    slot,
+                    initial_liquidator_fee_pct,
+                    liquidation_duration,
+                )?,
                 LiquidationMultiplierType::Discount,
             )?,
         )
     };
 
-    let margin_calculation = calculate_margin_requirement_and_total_collateral_and_liability_info(
-        user,
-        perp_market_map,
-        spot_market_map,
-        oracle_map,
-        MarginContext::liquidation(liquidation_margin_buffer_ratio),
-    )?;
+    let margin_calculation = match margin_calculation {
+        Some(margin_calculation) => margin_calculation,
+        None => calculate_margin_requirement_and_total_collateral_and_liability_info(
+            user,
+            perp_market_map,
+            spot_market_map,
+            oracle_map,
+            MarginContext::liquidation(liquidation_margin_buffer_ratio),
+        )?,
+    };
 
     if !user.is_being_liquidated() && margin_calculation.meets_margin_requirement() {
         msg!("margin calculation {:?}", margin_calculation);
         return Err(ErrorCode::SufficientCollateral);
     } else if user.is_being_liquidated() && margin_calculation.can_exit_liquidation()? {
         user.exit_liquidation();
-        return Ok(());
+        return Ok(margin_calculation);
     }
 
     let liquidation_id = user.enter_liquidation(slot)?;
@@ -1397,6 +2065,9 @@ pub fn liquidate_borrow_for_perp_pnl(
     )?;
 
     // check if user exited liquidation territory
+    // the liquidation record reports the margin before any orders were canceled
+    let margin_requirement = margin_calculation.margin_requirement;
+    let total_collateral = margin_calculation.total_collateral;
     let intermediate_margin_calculation = if !canceled_order_ids.is_empty() {
         let intermediate_margin_calculation =
             calculate_margin_requirement_and_total_collateral_and_liability_info(
@@ -1404,7 +2075,7 @@ pub fn liquidate_borrow_for_perp_pnl(
                 perp_market_map,
                 spot_market_map,
                 oracle_map,
-                MarginContext::liquidation(liquidation_margin_buffer_ratio),
+                margin_calculation.context,
             )?;
 
         let initial_margin_shortage = margin_calculation.margin_shortage()?;
@@ -1425,8 +2096,8 @@ pub fn liquidate_borrow_for_perp_pnl(
                 liquidation_type: LiquidationType::LiquidateBorrowForPerpPnl,
                 user: *user_key,
                 liquidator: *liquidator_key,
-                margin_requirement: margin_calculation.margin_requirement,
-                total_collateral: margin_calculation.total_collateral,
+                margin_requirement,
+                total_collateral,
                 bankrupt: user.is_bankrupt(),
                 canceled_order_ids,
                 margin_freed,
@@ -1442,7 +2113,7 @@ pub fn liquidate_borrow_for_perp_pnl(
             });
 
             user.exit_liquidation();
-            return Ok(());
+            return Ok(intermediate_margin_calculation);
         }
 
         intermediate_margin_calculation
@@ -1481,7 +2152,7 @@ pub fn liquidate_borrow_for_perp_pnl(
 
     if max_liability_allowed_to_be_transferred == 0 {
         msg!("max_liability_allowed_to_be_transferred == 0");
-        return Ok(());
+        return Ok(intermediate_margin_calculation);
     }
 
     // Given the user's deposit amount, how much borrow can be transferred?
@@ -1580,12 +2251,12 @@ pub fn liquidate_borrow_for_perp_pnl(
         update_quote_asset_amount(user_position, &mut market, -pnl_transfer.cast()?)?;
     }
 
-    let margin_freed_from_liability = calculate_margin_freed(
+    let (margin_freed_from_liability, margin_calculation_after) = calculate_margin_freed(
         user,
         perp_market_map,
         spot_market_map,
         oracle_map,
-        liquidation_margin_buffer_ratio,
+        intermediate_margin_calculation.context,
         margin_shortage,
     )?;
     margin_freed = margin_freed.safe_add(margin_freed_from_liability)?;
@@ -1597,8 +2268,13 @@ pub fn liquidate_borrow_for_perp_pnl(
         user.enter_bankruptcy();
     }
 
-    let liquidator_meets_initial_margin_requirement =
-        meets_initial_margin_requirement(liquidator, perp_market_map, spot_market_map, oracle_map)?;
+    let liquidator_meets_initial_margin_requirement = meets_initial_margin_requirement(
+        liquidator,
+        perp_market_map,
+        spot_market_map,
+        oracle_map,
+        MarginScope::Cross,
+    )?;
 
     validate!(
         liquidator_meets_initial_margin_requirement,
@@ -1617,8 +2293,8 @@ pub fn liquidate_borrow_for_perp_pnl(
         liquidation_type: LiquidationType::LiquidateBorrowForPerpPnl,
         user: *user_key,
         liquidator: *liquidator_key,
-        margin_requirement: margin_calculation.margin_requirement,
-        total_collateral: margin_calculation.total_collateral,
+        margin_requirement,
+        total_collateral,
         bankrupt: user.is_bankrupt(),
         margin_freed,
         liquidate_borrow_for_perp_pnl: LiquidateBorrowForPerpPnlRecord {
@@ -1632,7 +2308,7 @@ pub fn liquidate_borrow_for_perp_pnl(
         ..LiquidationRecord::default()
     });
 
-    Ok(())
+    Ok(margin_calculation_after)
 }
 
 pub fn liquidate_perp_pnl_for_deposit(
@@ -1651,8 +2327,53 @@ pub fn liquidate_perp_pnl_for_deposit(
     slot: u64,
     liquidation_margin_buffer_ratio: u32,
     initial_pct_to_liquidate: u128,
+    initial_liquidator_fee_pct: u128,
     liquidation_duration: u128,
 ) -> DriftResult {
+    liquidate_perp_pnl_for_deposit_with_margin_calculation(
+        perp_market_index,
+        asset_market_index,
+        liquidator_max_pnl_transfer,
+        limit_price,
+        user,
+        user_key,
+        liquidator,
+        liquidator_key,
+        perp_market_map,
+        spot_market_map,
+        oracle_map,
+        now,
+        slot,
+        liquidation_margin_buffer_ratio,
+        initial_pct_to_liquidate,
+        initial_liquidator_fee_pct,
+        liquidation_duration,
+        None,
+    )?;
+
+    Ok(())
+}
+
+fn liquidate_perp_pnl_for_deposit_with_margin_calculation(
+    perp_market_index: u16,
+    asset_market_index: u16,
+    liquidator_max_pnl_transfer: u128,
+    limit_price: Option<u64>,
+    user: &mut User,
+    user_key: &Pubkey,
+    liquidator: &mut User,
+    liquidator_key: &Pubkey,
+    perp_market_map: &PerpMarketMap,
+    spot_market_map: &SpotMarketMap,
+    oracle_map: &mut OracleMap,
+    now: i64,
+    slot: u64,
+    liquidation_margin_buffer_ratio: u32,
+    initial_pct_to_liquidate: u128,
+    initial_liquidator_fee_pct: u128,
+    liquidation_duration: u128,
+    margin_calculation: Option<MarginCalculation>,
+) -> DriftResult<MarginCalculation> {
     // liquidator takes over remaining negative perpetual pnl in exchange for a user deposit
     // can only be done once the perpetual position's size is 0
     // blocked when 1) user deposit oracle is deemed invalid
@@ -1700,6 +2421,14 @@ pub fn liquidate_perp_pnl_for_deposit(
         e
     })?;
 
+    validate!(
+        !user.is_perp_position_isolated(perp_market_index)
+            && !liquidator.is_perp_position_isolated(perp_market_index),
+        ErrorCode::InvalidIsolatedPerpPosition,
+        "Perp pnl in market {} is settled against its isolated deposit",
+        perp_market_index
+    )?;
+
     user.get_spot_position(asset_market_index).map_err(|_| {
         msg!(
             "User does not have a spot balance for asset market {}",
@@ -1781,7 +2510,13 @@ pub fn liquidate_perp_pnl_for_deposit(
             asset_market.decimals,
             asset_market.maintenance_asset_weight,
             calculate_liquidation_multiplier(
-                asset_market.liquidator_fee,
+                calculate_liquidator_fee(
+                    user,
+                    asset_market.liquidator_fee,
+                    slot,
+                    initial_liquidator_fee_pct,
+                    liquidation_duration,
+                )?,
                 LiquidationMultiplierType::Premium,
             )?,
         )
@@ -1832,26 +2567,35 @@ pub fn liquidate_perp_pnl_for_deposit(
             6_u32,
             SPOT_WEIGHT_PRECISION,
             calculate_liquidation_multiplier(
-                market.liquidator_fee,
+                calculate_liquidator_fee(
+                    user,
+                    market.liquidator_fee,
+                    slot,
+                    initial_liquidator_fee_pct,
+                    liquidation_duration,
+                )?,
                 LiquidationMultiplierType::Discount,
             )?,
         )
     };
 
-    let margin_calculation = calculate_margin_requirement_and_total_collateral_and_liability_info(
-        user,
-        perp_market_map,
-        spot_market_map,
-        oracle_map,
-        MarginContext::liquidation(liquidation_margin_buffer_ratio),
-    )?;
+    let margin_calculation = match margin_calculation {
+        Some(margin_calculation) => margin_calculation,
+        None => calculate_margin_requirement_and_total_collateral_and_liability_info(
+            user,
+            perp_market_map,
+            spot_market_map,
+            oracle_map,
+            MarginContext::liquidation(liquidation_margin_buffer_ratio),
+        )?,
+    };
 
     if !user.is_being_liquidated() && margin_calculation.meets_margin_requirement() {
         msg!("margin calculation {:?}", margin_calculation);
         return Err(ErrorCode::SufficientCollateral);
     } else if user.is_being_liquidated() && margin_calculation.can_exit_liquidation()? {
         user.exit_liquidation();
-        return Ok(());
+        return Ok(margin_calculation);
     }
 
     let liquidation_id = user.enter_liquidation(slot)?;
@@ -1878,6 +2622,9 @@ pub fn liquidate_perp_pnl_for_deposit(
         !(contract_tier.is_as_safe_as(&safest_tier_perp_liability, &safest_tier_spot_liability));
 
     // check if user exited liquidation territory
+    // the liquidation record reports the margin before any orders were canceled
+    let margin_requirement = margin_calculation.margin_requirement;
+    let total_collateral = margin_calculation.total_collateral;
     let intermediate_margin_calculation = if !canceled_order_ids.is_empty() {
         let intermediate_margin_calculation =
             calculate_margin_requirement_and_total_collateral_and_liability_info(
@@ -1885,7 +2632,7 @@ pub fn liquidate_perp_pnl_for_deposit(
                 perp_market_map,
                 spot_market_map,
                 oracle_map,
-                MarginContext::liquidation(liquidation_margin_buffer_ratio),
+                margin_calculation.context,
             )?;
 
         let initial_margin_shortage = margin_calculation.margin_shortage()?;
@@ -1908,8 +2655,8 @@ pub fn liquidate_perp_pnl_for_deposit(
                 liquidation_type: LiquidationType::LiquidatePerpPnlForDeposit,
                 user: *user_key,
                 liquidator: *liquidator_key,
-                margin_requirement: margin_calculation.margin_requirement,
-                total_collateral: margin_calculation.total_collateral,
+                margin_requirement,
+                total_collateral,
                 bankrupt: user.is_bankrupt(),
                 canceled_order_ids,
                 margin_freed,
@@ -1935,7 +2682,7 @@ pub fn liquidate_perp_pnl_for_deposit(
                     );
             }
 
-            return Ok(());
+            return Ok(intermediate_margin_calculation);
         }
 
         intermediate_margin_calculation
@@ -1981,7 +2728,7 @@ pub fn liquidate_perp_pnl_for_deposit(
 
     if max_pnl_allowed_to_be_transferred == 0 {
         msg!("max_pnl_allowed_to_be_transferred == 0");
-        return Ok(());
+        return Ok(intermediate_margin_calculation);
     }
 
     // Given the user's deposit amount, how much borrow can be transferred?
@@ -2075,12 +2822,12 @@ pub fn liquidate_perp_pnl_for_deposit(
         update_quote_asset_amount(user_position, &mut perp_market, pnl_transfer.cast()?)?;
     }
 
-    let margin_freed_from_liability = calculate_margin_freed(
+    let (margin_freed_from_liability, margin_calculation_after) = calculate_margin_freed(
         user,
         perp_market_map,
         spot_market_map,
         oracle_map,
-        liquidation_margin_buffer_ratio,
+        intermediate_margin_calculation.context,
         margin_shortage,
     )?;
     margin_freed = margin_freed.safe_add(margin_freed_from_liability)?;
@@ -2088,44 +2835,368 @@ pub fn liquidate_perp_pnl_for_deposit(
 
     if pnl_transfer >= pnl_transfer_to_cover_margin_shortage {
         user.exit_liquidation();
-    } else if is_user_bankrupt(user) {
-        user.enter_bankruptcy();
+    } else if is_user_bankrupt(user) {
+        user.enter_bankruptcy();
+    }
+
+    let liquidator_meets_initial_margin_requirement = meets_initial_margin_requirement(
+        liquidator,
+        perp_market_map,
+        spot_market_map,
+        oracle_map,
+        MarginScope::Cross,
+    )?;
+
+    validate!(
+        liquidator_meets_initial_margin_requirement,
+        ErrorCode::InsufficientCollateral,
+        "Liquidator doesnt have enough collateral to take over borrow"
+    )?;
+
+    let market_oracle_price = {
+        let market = perp_market_map.get_ref_mut(&perp_market_index)?;
+        oracle_map.get_price_data(&market.amm.oracle)?.price
+    };
+
+    emit!(LiquidationRecord {
+        ts: now,
+        liquidation_id,
+        liquidation_type: LiquidationType::LiquidatePerpPnlForDeposit,
+        user: *user_key,
+        liquidator: *liquidator_key,
+        margin_requirement,
+        total_collateral,
+        bankrupt: user.is_bankrupt(),
+        margin_freed,
+        liquidate_perp_pnl_for_deposit: LiquidatePerpPnlForDepositRecord {
+            perp_market_index,
+            market_oracle_price,
+            pnl_transfer,
+            asset_market_index,
+            asset_price,
+            asset_transfer,
+        },
+        ..LiquidationRecord::default()
+    });
+
+    Ok(margin_calculation_after)
+}
+
+/// One liquidation in a liquidate_user batch along with the liquidator's max transfer and limit
+/// price for it, matching the args of the single market liquidate instructions
+#[derive(Debug, Clone, Copy, AnchorSerialize, AnchorDeserialize, PartialEq, Eq)]
+pub enum LiquidationStep {
+    Perp {
+        market_index: u16,
+        max_base_asset_amount: u64,
+        limit_price: Option<u64>,
+    },
+    Spot {
+        asset_market_index: u16,
+        liability_market_index: u16,
+        max_liability_transfer: u128,
+        limit_price: Option<u64>,
+    },
+    BorrowForPerpPnl {
+        perp_market_index: u16,
+        liability_market_index: u16,
+        max_liability_transfer: u128,
+        limit_price: Option<u64>,
+    },
+    PerpPnlForDeposit {
+        perp_market_index: u16,
+        asset_market_index: u16,
+        max_pnl_transfer: u128,
+        limit_price: Option<u64>,
+    },
+}
+
+impl LiquidationStep {
+    /// perp positions are liquidated first, then borrows, then negative perp pnl
+    fn phase(&self) -> u8 {
+        match self {
+            LiquidationStep::Perp { .. } => 0,
+            LiquidationStep::Spot { .. } | LiquidationStep::BorrowForPerpPnl { .. } => 1,
+            LiquidationStep::PerpPnlForDeposit { .. } => 2,
+        }
+    }
+}
+
+/// Errors raised before a step transfers anything when it can't be liquidated right now
+/// (paused market, divergent oracle, limit price not met or nothing to transfer)
+fn is_skippable_liquidation_error(error: ErrorCode) -> bool {
+    matches!(
+        error,
+        ErrorCode::InvalidLiquidation
+            | ErrorCode::PriceBandsBreached
+            | ErrorCode::LiquidationDoesntSatisfyLimitPrice
+    )
+}
+
+/// Liquidates a user across several markets in one call: perp positions first, then borrows
+/// (against deposits or positive perp pnl), then negative perp pnl against deposits. Every step
+/// shares the liquidation_id and one margin calculation, which each step hands to the next. Steps
+/// the user has nothing to liquidate for, or that can't be liquidated right now, are skipped and
+/// the batch stops once the user can exit liquidation or becomes bankrupt.
+pub fn liquidate_user(
+    mut steps: Vec<LiquidationStep>,
+    user: &mut User,
+    user_key: &Pubkey,
+    user_stats: &mut UserStats,
+    liquidator: &mut User,
+    liquidator_key: &Pubkey,
+    liquidator_stats: &mut UserStats,
+    perp_market_map: &PerpMarketMap,
+    spot_market_map: &SpotMarketMap,
+    oracle_map: &mut OracleMap,
+    slot: u64,
+    now: i64,
+    state: &State,
+) -> DriftResult {
+    let liquidation_margin_buffer_ratio = state.liquidation_margin_buffer_ratio;
+    let initial_pct_to_liquidate = state.initial_pct_to_liquidate as u128;
+    let initial_liquidator_fee_pct = state.initial_liquidator_fee_pct as u128;
+    let liquidation_duration = state.liquidation_duration as u128;
+
+    validate!(
+        !user.is_bankrupt(),
+        ErrorCode::UserBankrupt,
+        "user bankrupt",
+    )?;
+
+    validate!(
+        !steps.is_empty(),
+        ErrorCode::InvalidLiquidation,
+        "no liquidation steps",
+    )?;
+
+    // settle funding and interest up front so the margin calculation stays valid for every step
+    settle_funding_payments(user, user_key, perp_market_map, now)?;
+
+    for spot_position in user.spot_positions.iter() {
+        if spot_position.is_available() {
+            continue;
+        }
+
+        let mut spot_market = spot_market_map.get_ref_mut(&spot_position.market_index)?;
+        let oracle_price_data = oracle_map.get_price_data(&spot_market.oracle)?;
+        update_spot_market_cumulative_interest(&mut spot_market, Some(oracle_price_data), now)?;
+    }
+
+    // per market margin requirements let each step track its own market
+    let margin_context =
+        MarginContext::liquidation(liquidation_margin_buffer_ratio).track_details();
+
+    let mut margin_calculation =
+        calculate_margin_requirement_and_total_collateral_and_liability_info(
+            user,
+            perp_market_map,
+            spot_market_map,
+            oracle_map,
+            margin_context,
+        )?;
+
+    if !user.is_being_liquidated() && margin_calculation.meets_margin_requirement() {
+        msg!("margin calculation: {:?}", margin_calculation);
+        return Err(ErrorCode::SufficientCollateral);
+    } else if user.is_being_liquidated() && margin_calculation.can_exit_liquidation()? {
+        user.exit_liquidation();
+        return Ok(());
     }
 
-    let liquidator_meets_initial_margin_requirement =
-        meets_initial_margin_requirement(liquidator, perp_market_map, spot_market_map, oracle_map)?;
+    let liquidation_id = user.enter_liquidation(slot)?;
+    msg!("liquidation_id {}", liquidation_id);
 
-    validate!(
-        liquidator_meets_initial_margin_requirement,
-        ErrorCode::InsufficientCollateral,
-        "Liquidator doesnt have enough collateral to take over borrow"
-    )?;
+    steps.sort_by_key(|step| step.phase());
 
-    let market_oracle_price = {
-        let market = perp_market_map.get_ref_mut(&perp_market_index)?;
-        oracle_map.get_price_data(&market.amm.oracle)?.price
+    let has_spot_balance = |user: &User, market_index: u16, balance_type: SpotBalanceType| {
+        user.get_spot_position(market_index)
+            .map(|spot_position| {
+                spot_position.scaled_balance != 0 && spot_position.balance_type == balance_type
+            })
+            .unwrap_or(false)
     };
 
-    emit!(LiquidationRecord {
-        ts: now,
-        liquidation_id,
-        liquidation_type: LiquidationType::LiquidatePerpPnlForDeposit,
-        user: *user_key,
-        liquidator: *liquidator_key,
-        margin_requirement: margin_calculation.margin_requirement,
-        total_collateral: margin_calculation.total_collateral,
-        bankrupt: user.is_bankrupt(),
-        margin_freed,
-        liquidate_perp_pnl_for_deposit: LiquidatePerpPnlForDepositRecord {
-            perp_market_index,
-            market_oracle_price,
-            pnl_transfer,
-            asset_market_index,
-            asset_price,
-            asset_transfer,
-        },
-        ..LiquidationRecord::default()
-    });
+    // perp pnl can only be liquidated once the position is closed
+    let get_closed_perp_pnl = |user: &User, market_index: u16| {
+        if user.is_perp_position_isolated(market_index) {
+            return 0;
+        }
+
+        user.get_perp_position(market_index)
+            .map(|perp_position| {
+                if perp_position.base_asset_amount == 0 && !perp_position.is_lp() {
+                    perp_position.quote_asset_amount
+                } else {
+                    0
+                }
+            })
+            .unwrap_or(0)
+    };
+
+    for step in steps {
+        if user.is_bankrupt() || !user.is_being_liquidated() {
+            break;
+        }
+
+        let has_something_to_liquidate = match step {
+            // isolated positions are liquidated on their own through liquidate_perp
+            LiquidationStep::Perp { market_index, .. } => {
+                !user.is_perp_position_isolated(market_index)
+                    && user
+                        .get_perp_position(market_index)
+                        .map(|perp_position| {
+                            perp_position.is_open_position()
+                                || perp_position.has_open_order()
+                                || perp_position.is_lp()
+                        })
+                        .unwrap_or(false)
+            }
+            LiquidationStep::Spot {
+                asset_market_index,
+                liability_market_index,
+                ..
+            } => {
+                has_spot_balance(user, liability_market_index, SpotBalanceType::Borrow)
+                    && has_spot_balance(user, asset_market_index, SpotBalanceType::Deposit)
+            }
+            LiquidationStep::BorrowForPerpPnl {
+                perp_market_index,
+                liability_market_index,
+                ..
+            } => {
+                has_spot_balance(user, liability_market_index, SpotBalanceType::Borrow)
+                    && get_closed_perp_pnl(user, perp_market_index) > 0
+            }
+            LiquidationStep::PerpPnlForDeposit {
+                perp_market_index,
+                asset_market_index,
+                ..
+            } => {
+                get_closed_perp_pnl(user, perp_market_index) < 0
+                    && has_spot_balance(user, asset_market_index, SpotBalanceType::Deposit)
+            }
+        };
+
+        if !has_something_to_liquidate {
+            msg!("nothing to liquidate for {:?}", step);
+            continue;
+        }
+
+        let result = match step {
+            LiquidationStep::Perp {
+                market_index,
+                max_base_asset_amount,
+                limit_price,
+            } => liquidate_perp_with_margin_calculation(
+                market_index,
+                max_base_asset_amount,
+                limit_price,
+                user,
+                user_key,
+                user_stats,
+                liquidator,
+                liquidator_key,
+                liquidator_stats,
+                perp_market_map,
+                spot_market_map,
+                oracle_map,
+                slot,
+                now,
+                state,
+                Some(margin_calculation),
+            ),
+            LiquidationStep::Spot {
+                asset_market_index,
+                liability_market_index,
+                max_liability_transfer,
+                limit_price,
+            } => liquidate_spot_with_margin_calculation(
+                asset_market_index,
+                liability_market_index,
+                max_liability_transfer,
+                limit_price,
+                user,
+                user_key,
+                liquidator,
+                liquidator_key,
+                perp_market_map,
+                spot_market_map,
+                oracle_map,
+                now,
+                slot,
+                state,
+                Some(margin_calculation),
+            ),
+            LiquidationStep::BorrowForPerpPnl {
+                perp_market_index,
+                liability_market_index,
+                max_liability_transfer,
+                limit_price,
+            } => liquidate_borrow_for_perp_pnl_with_margin_calculation(
+                perp_market_index,
+                liability_market_index,
+                max_liability_transfer,
+                limit_price,
+                user,
+                user_key,
+                liquidator,
+                liquidator_key,
+                perp_market_map,
+                spot_market_map,
+                oracle_map,
+                now,
+                slot,
+                liquidation_margin_buffer_ratio,
+                initial_pct_to_liquidate,
+                initial_liquidator_fee_pct,
+                liquidation_duration,
+                Some(margin_calculation),
+            ),
+            LiquidationStep::PerpPnlForDeposit {
+                perp_market_index,
+                asset_market_index,
+                max_pnl_transfer,
+                limit_price,
+            } => liquidate_perp_pnl_for_deposit_with_margin_calculation(
+                perp_market_index,
+                asset_market_index,
+                max_pnl_transfer,
+                limit_price,
+                user,
+                user_key,
+                liquidator,
+                liquidator_key,
+                perp_market_map,
+                spot_market_map,
+                oracle_map,
+                now,
+                slot,
+                liquidation_margin_buffer_ratio,
+                initial_pct_to_liquidate,
+                initial_liquidator_fee_pct,
+                liquidation_duration,
+                Some(margin_calculation),
+            ),
+        };
+
+        margin_calculation = match result {
+            Ok(margin_calculation_after) => margin_calculation_after,
+            Err(error) if is_skippable_liquidation_error(error) => {
+                msg!("skipping {:?}: {:?}", step, error);
+
+                // the step may have canceled orders or burned lp shares before stopping
+                calculate_margin_requirement_and_total_collateral_and_liability_info(
+                    user,
+                    perp_market_map,
+                    spot_market_map,
+                    oracle_map,
+                    margin_context,
+                )?
+            }
+            Err(error) => return Err(error),
+        };
+    }
 
     Ok(())
 }
@@ -2142,15 +3213,27 @@ pub fn resolve_perp_bankruptcy(
     now: i64,
     insurance_fund_vault_balance: u64,
 ) -> DriftResult<u64> {
-    if !user.is_bankrupt() && is_user_bankrupt(user) {
-        user.enter_bankruptcy();
-    }
+    let margin_scope = user.get_perp_margin_scope(market_index);
 
-    validate!(
-        user.is_bankrupt(),
-        ErrorCode::UserNotBankrupt,
-        "user not bankrupt",
-    )?;
+    if margin_scope.is_isolated() {
+        // the rest of the account isn't on the hook for an isolated position's losses
+        validate!(
+            is_isolated_perp_position_bankrupt(user, market_index),
+            ErrorCode::UserNotBankrupt,
+            "isolated perp position in market {} not bankrupt",
+            market_index
+        )?;
+    } else {
+        if !user.is_bankrupt() && is_user_bankrupt(user) {
+            user.enter_bankruptcy();
+        }
+
+        validate!(
+            user.is_bankrupt(),
+            ErrorCode::UserNotBankrupt,
+            "user not bankrupt",
+        )?;
+    }
 
     validate!(
         !liquidator.is_being_liquidated(),
@@ -2203,7 +3286,7 @@ pub fn resolve_perp_bankruptcy(
         perp_market_map,
         spot_market_map,
         oracle_map,
-        MarginContext::standard(MarginRequirementType::Maintenance),
+        MarginContext::standard(MarginRequirementType::Maintenance).scope(margin_scope),
     )?;
 
     // spot market's insurance fund draw attempt here (before social loss)
@@ -2326,7 +3409,9 @@ pub fn resolve_perp_bankruptcy(
     }
 
     // exit bankruptcy
-    if !is_user_bankrupt(user) {
+    if margin_scope.is_isolated() {
+        user.exit_margin_scope_liquidation(margin_scope)?;
+    } else if !is_user_bankrupt(user) {
         user.exit_bankruptcy();
     }
 
@@ -2355,6 +3440,345 @@ pub fn resolve_perp_bankruptcy(
     if_payment.cast()
 }
 
+/// Closes an underwater user's perp position against profitable positions on the opposite side at
+/// the user's bankruptcy price, so the part of the loss the insurance fund and fee pool can't cover
+/// comes out of the counterparties' profit instead of being socialized through funding.
+/// Every account in `adl_users` must be eligible and they are filled highest adl score first, so a
+/// higher scored counterparty that was passed is never skipped for a lower scored one
+pub fn auto_deleverage_perp(
+    market_index: u16,
+    user: &mut User,
+    user_key: &Pubkey,
+    adl_users: &UserMap,
+    perp_market_map: &PerpMarketMap,
+    spot_market_map: &SpotMarketMap,
+    oracle_map: &mut OracleMap,
+    now: i64,
+    slot: u64,
+    insurance_fund_vault_balance: u64,
+    state: &State,
+) -> DriftResult {
+    let margin_scope = user.get_perp_margin_scope(market_index);
+
+    validate!(
+        user.is_margin_scope_being_liquidated(margin_scope) && !user.is_bankrupt(),
+        ErrorCode::InvalidAutoDeleverage,
+        "user must be being liquidated and not bankrupt"
+    )?;
+
+    validate!(
+        !adl_users.0.is_empty(),
+        ErrorCode::InvalidAutoDeleverage,
+        "no users to auto-deleverage"
+    )?;
+
+    {
+        let market = perp_market_map.get_ref(&market_index)?;
+        validate!(
+            !market.is_operation_paused(PerpOperation::Liquidation),
+            ErrorCode::InvalidLiquidation,
+            "Liquidation operation is paused for market {}",
+            market_index
+        )?;
+    }
+
+    // the bankruptcy price and every counterparty's pnl are taken from the oracle
+    let oracle_price = {
+        let market = perp_market_map.get_ref(&market_index)?;
+        let (oracle_price_data, oracle_validity) = oracle_map.get_price_data_and_validity(
+            MarketType::Perp,
+            market_index,
+            &market.amm.oracle,
+            market.amm.historical_oracle_data.last_oracle_price_twap,
+            market.get_max_confidence_interval_multiplier()?,
+        )?;
+
+        validate!(
+            is_oracle_valid_for_action(oracle_validity, Some(DriftAction::Liquidate))?,
+            ErrorCode::InvalidOracle,
+            "oracle for perp market {} invalid for auto-deleverage: {:?}",
+            market_index,
+            oracle_validity
+        )?;
+
+        let oracle_price_too_divergent = is_oracle_too_divergent_with_twap_5min(
+            oracle_price_data.price,
+            market
+                .amm
+                .historical_oracle_data
+                .last_oracle_price_twap_5min,
+            state
+                .oracle_guard_rails
+                .max_oracle_twap_5min_percent_divergence()
+                .cast()?,
+        )?;
+
+        validate!(!oracle_price_too_divergent, ErrorCode::PriceBandsBreached)?;
+
+        oracle_price_data.price
+    };
+
+    settle_funding_payment(
+        user,
+        user_key,
+        perp_market_map.get_ref_mut(&market_index)?.deref_mut(),
+        now,
+    )?;
+
+    let position_index = get_position_index(&user.perp_positions, market_index)?;
+    let user_base_asset_amount = user.perp_positions[position_index].base_asset_amount;
+
+    validate!(
+        user_base_asset_amount != 0 && !user.perp_positions[position_index].is_lp(),
+        ErrorCode::InvalidAutoDeleverage,
+        "user must have a non lp position in market {}",
+        market_index
+    )?;
+
+    let user_direction = user.perp_positions[position_index].get_direction();
+    let user_direction_to_close = user.perp_positions[position_index].get_direction_to_close();
+
+    let total_collateral = calculate_margin_requirement_and_total_collateral_and_liability_info(
+        user,
+        perp_market_map,
+        spot_market_map,
+        oracle_map,
+        MarginContext::standard(MarginRequirementType::Maintenance).scope(margin_scope),
+    )?
+    .total_collateral;
+
+    // what resolve_perp_bankruptcy could draw from the insurance fund and fee pool
+    let loss_coverage = {
+        let perp_market = &mut perp_market_map.get_ref_mut(&market_index)?;
+        let quote_spot_market = &mut spot_market_map.get_ref_mut(&QUOTE_SPOT_MARKET_INDEX)?;
+
+        let max_insurance_withdraw = perp_market
+            .insurance_claim
+            .quote_max_insurance
+            .safe_sub(perp_market.insurance_claim.quote_settled_insurance)?
+            .cast::<u128>()?;
+
+        insurance_fund_vault_balance
+            .saturating_sub(1)
+            .cast::<u128>()?
+            .min(max_insurance_withdraw)
+            .safe_add(get_fee_pool_tokens(perp_market, quote_spot_market)?.cast()?)?
+    };
+
+    let loss_to_cover = total_collateral
+        .min(0)
+        .unsigned_abs()
+        .saturating_sub(loss_coverage);
+
+    validate!(
+        loss_to_cover > 0,
+        ErrorCode::InvalidAutoDeleverage,
+        "insurance fund and fee pool can cover total_collateral={}",
+        total_collateral
+    )?;
+
+    // closing the whole position at this price leaves only what the insurance fund and fee pool cover
+    let bankruptcy_price_offset = loss_to_cover
+        .safe_mul(PRICE_TIMES_AMM_TO_QUOTE_PRECISION_RATIO)?
+        .safe_div(user_base_asset_amount.unsigned_abs().cast()?)?
+        .cast::<i64>()?;
+
+    let bankruptcy_price = match user_direction {
+        PositionDirection::Long => oracle_price.safe_add(bankruptcy_price_offset)?,
+        PositionDirection::Short => oracle_price.safe_sub(bankruptcy_price_offset)?,
+    };
+
+    validate!(
+        bankruptcy_price > 0,
+        ErrorCode::InvalidAutoDeleverage,
+        "bankruptcy price {} must be positive",
+        bankruptcy_price
+    )?;
+
+    let mut candidates: Vec<(u128, Pubkey)> = Vec::with_capacity(adl_users.0.len());
+    for adl_user_key in adl_users.0.keys() {
+        validate!(
+            adl_user_key != user_key,
+            ErrorCode::InvalidAutoDeleverage,
+            "user cant auto-deleverage against themself"
+        )?;
+
+        let mut adl_user = adl_users.get_ref_mut(adl_user_key)?;
+
+        validate!(
+            !adl_user.is_being_liquidated(),
+            ErrorCode::InvalidAutoDeleverage,
+            "adl user {} is being liquidated",
+            adl_user_key
+        )?;
+
+        let adl_position_index = get_position_index(&adl_user.perp_positions, market_index)?;
+        let adl_position = &adl_user.perp_positions[adl_position_index];
+
+        validate!(
+            !adl_position.is_lp() && adl_position.get_direction() == user_direction_to_close,
+            ErrorCode::InvalidAutoDeleverage,
+            "adl user {} must have a non lp position opposite the user",
+            adl_user_key
+        )?;
+
+        validate!(
+            adl_position.base_asset_amount != 0,
+            ErrorCode::InvalidAutoDeleverage,
+            "adl user {} has no base in market {}",
+            adl_user_key,
+            market_index
+        )?;
+
+        settle_funding_payment(
+            &mut adl_user,
+            adl_user_key,
+            perp_market_map.get_ref_mut(&market_index)?.deref_mut(),
+            now,
+        )?;
+
+        let (base_asset_value, unrealized_pnl) =
+            calculate_base_asset_value_and_pnl_with_oracle_price(
+                &adl_user.perp_positions[adl_position_index],
+                oracle_price,
+            )?;
+
+        validate!(
+            unrealized_pnl > 0,
+            ErrorCode::InvalidAutoDeleverage,
+            "adl user {} position is not profitable",
+            adl_user_key
+        )?;
+
+        let adl_user_total_collateral =
+            calculate_margin_requirement_and_total_collateral_and_liability_info(
+                &adl_user,
+                perp_market_map,
+                spot_market_map,
+                oracle_map,
+                MarginContext::standard(MarginRequirementType::Maintenance),
+            )?
+            .total_collateral;
+
+        let adl_score = calculate_adl_score(
+            unrealized_pnl,
+            adl_user.perp_positions[adl_position_index].quote_entry_amount,
+            base_asset_value,
+            adl_user_total_collateral,
+        )?;
+
+        candidates.push((adl_score, *adl_user_key));
+    }
+
+    // most profitable and most leveraged first, ties broken by key
+    candidates.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| a.1.cmp(&b.1)));
+
+    let liquidation_id = user.next_liquidation_id.safe_sub(1)?;
+
+    let mut base_asset_amount_remaining = user_base_asset_amount.unsigned_abs();
+    for (adl_score, adl_user_key) in candidates {
+        if base_asset_amount_remaining == 0 {
+            break;
+        }
+
+        let mut adl_user = adl_users.get_ref_mut(&adl_user_key)?;
+        let adl_position_index = get_position_index(&adl_user.perp_positions, market_index)?;
+
+        let base_asset_amount = base_asset_amount_remaining.min(
+            adl_user.perp_positions[adl_position_index]
+                .base_asset_amount
+                .unsigned_abs(),
+        );
+
+        let quote_asset_amount = base_asset_amount
+            .cast::<u128>()?
+            .safe_mul(bankruptcy_price.cast()?)?
+            .safe_div(PRICE_TIMES_AMM_TO_QUOTE_PRECISION_RATIO)?
+            .cast::<u64>()?;
+
+        let user_position_delta = get_position_delta_for_fill(
+            base_asset_amount,
+            quote_asset_amount,
+            user_direction_to_close,
+        )?;
+
+        let adl_user_position_delta =
+            get_position_delta_for_fill(base_asset_amount, quote_asset_amount, user_direction)?;
+
+        {
+            let mut market = perp_market_map.get_ref_mut(&market_index)?;
+            update_position_and_market(
+                &mut user.perp_positions[position_index],
+                &mut market,
+                &user_position_delta,
+            )?;
+            update_position_and_market(
+                &mut adl_user.perp_positions[adl_position_index],
+                &mut market,
+                &adl_user_position_delta,
+            )?;
+        }
+
+        // take profit / stop loss orders of a position closed by the adl are left with nothing to close
+        orders::cancel_position_linked_orders(
+            &mut adl_user,
+            &adl_user_key,
+            market_index,
+            perp_market_map,
+            spot_market_map,
+            oracle_map,
+            now,
+            slot,
+            None,
+        )?;
+
+        // profit given up versus closing at the oracle price
+        let pnl_haircut = base_asset_amount
+            .cast::<u128>()?
+            .safe_mul(bankruptcy_price_offset.cast()?)?
+            .safe_div(PRICE_TIMES_AMM_TO_QUOTE_PRECISION_RATIO)?;
+
+        adl_user.increment_total_socialized_loss(pnl_haircut.cast()?)?;
+
+        let adl_user_meets_maintenance_margin_requirement = meets_maintenance_margin_requirement(
+            &adl_user,
+            perp_market_map,
+            spot_market_map,
+            oracle_map,
+            adl_user.get_perp_margin_scope(market_index),
+        )?;
+
+        validate!(
+            adl_user_meets_maintenance_margin_requirement,
+            ErrorCode::InsufficientCollateral,
+            "adl user {} doesnt meet maintenance margin after auto-deleveraging",
+            adl_user_key
+        )?;
+
+        base_asset_amount_remaining = base_asset_amount_remaining.safe_sub(base_asset_amount)?;
+
+        emit!(AdlRecord {
+            ts: now,
+            liquidation_id,
+            market_index,
+            bankrupt_user: *user_key,
+            user: adl_user_key,
+            base_asset_amount,
+            quote_asset_amount,
+            bankruptcy_price,
+            oracle_price,
+            pnl_haircut,
+            adl_score,
+        });
+    }
+
+    if !margin_scope.is_isolated() && is_user_bankrupt(user) {
+        user.enter_bankruptcy();
+    }
+
+    Ok(())
+}
+
 pub fn resolve_spot_bankruptcy(
     market_index: u16,
     user: &mut User,
@@ -2514,21 +3938,23 @@ pub fn calculate_margin_freed(
     perp_market_map: &PerpMarketMap,
     spot_market_map: &SpotMarketMap,
     oracle_map: &mut OracleMap,
-    liquidation_margin_buffer_ratio: u32,
+    margin_context: MarginContext,
     initial_margin_shortage: u128,
-) -> DriftResult<u64> {
+) -> DriftResult<(u64, MarginCalculation)> {
     let margin_calculation_after =
         calculate_margin_requirement_and_total_collateral_and_liability_info(
             user,
             perp_market_map,
             spot_market_map,
             oracle_map,
-            MarginContext::liquidation(liquidation_margin_buffer_ratio),
+            margin_context,
         )?;
 
     let new_margin_shortage = margin_calculation_after.margin_shortage()?;
 
-    initial_margin_shortage
+    let margin_freed = initial_margin_shortage
         .saturating_sub(new_margin_shortage)
-        .cast::<u64>()
+        .cast::<u64>()?;
+
+    Ok((margin_freed, margin_calculation_after))
 }
@@ -30,7 +30,8 @@ pub mod liquidate_perp {
     use crate::state::spot_market::{SpotBalanceType, SpotMarket};
     use crate::state::spot_market_map::SpotMarketMap;
     use crate::state::user::{
-        Order, OrderStatus, OrderType, PerpPosition, SpotPosition, User, UserStats,
+        IsolatedPerpPosition, IsolatedPerpPositionStatus, MarketType, Order, OrderBitFlag,
+        OrderStatus, OrderType, PerpPosition, SpotPosition, User, UserStats,
     };
     use crate::test_utils::*;
     use crate::test_utils::{get_orders, get_positions, get_pyth_price, get_spot_positions};
@@ -142,6 +143,7 @@ pub mod liquidate_perp {
         let state = State {
             liquidation_margin_buffer_ratio: 10,
             initial_pct_to_liquidate: LIQUIDATION_PCT_PRECISION as u16,
+            initial_liquidator_fee_pct: LIQUIDATION_PCT_PRECISION as u16,
             liquidation_duration: 150,
             ..Default::default()
         };
@@ -292,6 +294,7 @@ pub mod liquidate_perp {
         let state = State {
             liquidation_margin_buffer_ratio: 10,
             initial_pct_to_liquidate: LIQUIDATION_PCT_PRECISION as u16,
+            initial_liquidator_fee_pct: LIQUIDATION_PCT_PRECISION as u16,
             liquidation_duration: 150,
             ..Default::default()
         };
@@ -440,6 +443,7 @@ pub mod liquidate_perp {
         let state = State {
             liquidation_margin_buffer_ratio: 255,
             initial_pct_to_liquidate: LIQUIDATION_PCT_PRECISION as u16,
+            initial_liquidator_fee_pct: LIQUIDATION_PCT_PRECISION as u16,
             liquidation_duration: 150,
             ..Default::default()
         };
@@ -576,6 +580,7 @@ pub mod liquidate_perp {
         let state = State {
             liquidation_margin_buffer_ratio: MARGIN_PRECISION as u32 / 50,
             initial_pct_to_liquidate: LIQUIDATION_PCT_PRECISION as u16,
+            initial_liquidator_fee_pct: LIQUIDATION_PCT_PRECISION as u16,
             liquidation_duration: 150,
             ..Default::default()
         };
@@ -727,6 +732,7 @@ pub mod liquidate_perp {
         let state = State {
             liquidation_margin_buffer_ratio: MARGIN_PRECISION as u32 / 50,
             initial_pct_to_liquidate: LIQUIDATION_PCT_PRECISION as u16,
+            initial_liquidator_fee_pct: LIQUIDATION_PCT_PRECISION as u16,
             liquidation_duration: 150,
             ..Default::default()
         };
@@ -954,6 +960,7 @@ pub mod liquidate_perp {
         let state = State {
             liquidation_margin_buffer_ratio: 10,
             initial_pct_to_liquidate: LIQUIDATION_PCT_PRECISION as u16,
+            initial_liquidator_fee_pct: LIQUIDATION_PCT_PRECISION as u16,
             liquidation_duration: 150,
             ..Default::default()
         };
@@ -1101,6 +1108,7 @@ pub mod liquidate_perp {
         let state = State {
             liquidation_margin_buffer_ratio: 10,
             initial_pct_to_liquidate: LIQUIDATION_PCT_PRECISION as u16,
+            initial_liquidator_fee_pct: LIQUIDATION_PCT_PRECISION as u16,
             liquidation_duration: 150,
             ..Default::default()
         };
@@ -1233,6 +1241,7 @@ pub mod liquidate_perp {
         let state = State {
             liquidation_margin_buffer_ratio: 10,
             initial_pct_to_liquidate: LIQUIDATION_PCT_PRECISION as u16,
+            initial_liquidator_fee_pct: LIQUIDATION_PCT_PRECISION as u16,
             liquidation_duration: 150,
             ..Default::default()
         };
@@ -1358,6 +1367,7 @@ pub mod liquidate_perp {
         let state = State {
             liquidation_margin_buffer_ratio: 10,
             initial_pct_to_liquidate: LIQUIDATION_PCT_PRECISION as u16,
+            initial_liquidator_fee_pct: LIQUIDATION_PCT_PRECISION as u16,
             liquidation_duration: 150,
             ..Default::default()
         };
@@ -1513,6 +1523,7 @@ pub mod liquidate_perp {
         let state = State {
             liquidation_margin_buffer_ratio: MARGIN_PRECISION as u32 / 50,
             initial_pct_to_liquidate: (LIQUIDATION_PCT_PRECISION / 10) as u16,
+            initial_liquidator_fee_pct: LIQUIDATION_PCT_PRECISION as u16,
             liquidation_duration: 150,
             ..Default::default()
         };
@@ -1790,6 +1801,7 @@ pub mod liquidate_perp {
         let state = State {
             liquidation_margin_buffer_ratio: MARGIN_PRECISION as u32 / 50,
             initial_pct_to_liquidate: (LIQUIDATION_PCT_PRECISION / 10) as u16,
+            initial_liquidator_fee_pct: LIQUIDATION_PCT_PRECISION as u16,
             liquidation_duration: 150,
             ..Default::default()
         };
@@ -1915,6 +1927,7 @@ pub mod liquidate_perp {
         let state = State {
             liquidation_margin_buffer_ratio: MARGIN_PRECISION as u32 / 50,
             initial_pct_to_liquidate: (LIQUIDATION_PCT_PRECISION / 10) as u16,
+            initial_liquidator_fee_pct: LIQUIDATION_PCT_PRECISION as u16,
             liquidation_duration: 150,
             ..Default::default()
         };
@@ -2043,6 +2056,7 @@ pub mod liquidate_perp {
         let state = State {
             liquidation_margin_buffer_ratio: 10,
             initial_pct_to_liquidate: LIQUIDATION_PCT_PRECISION as u16,
+            initial_liquidator_fee_pct: LIQUIDATION_PCT_PRECISION as u16,
             liquidation_duration: 150,
             ..Default::default()
         };
@@ -2171,6 +2185,7 @@ pub mod liquidate_perp {
         let state = State {
             liquidation_margin_buffer_ratio: 200,
             initial_pct_to_liquidate: LIQUIDATION_PCT_PRECISION as u16,
+            initial_liquidator_fee_pct: LIQUIDATION_PCT_PRECISION as u16,
             liquidation_duration: 150,
             ..Default::default()
         };
@@ -2197,128 +2212,125 @@ pub mod liquidate_perp {
         assert!(!user.is_being_liquidated());
         assert_eq!(market_after.amm.total_liquidation_fee, 41787043);
     }
-}
-
-pub mod liquidate_spot {
-    use crate::state::state::State;
-    use std::ops::Deref;
-    use std::str::FromStr;
-
-    use anchor_lang::Owner;
-    use solana_program::pubkey::Pubkey;
-
-    use crate::controller::liquidation::liquidate_spot;
-    use crate::create_anchor_account_info;
-    use crate::error::ErrorCode;
-    use crate::math::constants::{
-        LIQUIDATION_FEE_PRECISION, LIQUIDATION_PCT_PRECISION, MARGIN_PRECISION,
-        MARGIN_PRECISION_U128, PRICE_PRECISION, PRICE_PRECISION_U64, SPOT_BALANCE_PRECISION,
-        SPOT_BALANCE_PRECISION_U64, SPOT_CUMULATIVE_INTEREST_PRECISION, SPOT_WEIGHT_PRECISION,
-    };
-    use crate::math::margin::calculate_margin_requirement_and_total_collateral_and_liability_info;
-    use crate::math::spot_balance::{get_strict_token_value, get_token_amount, get_token_value};
-    use crate::state::margin_calculation::{MarginCalculation, MarginContext};
-    use crate::state::oracle::OracleSource;
-    use crate::state::oracle::{HistoricalOracleData, StrictOraclePrice};
-    use crate::state::oracle_map::OracleMap;
-    use crate::state::perp_market_map::PerpMarketMap;
-    use crate::state::spot_market::{SpotBalanceType, SpotMarket};
-    use crate::state::spot_market_map::SpotMarketMap;
-    use crate::state::user::{Order, PerpPosition, SpotPosition, User};
-    use crate::test_utils::*;
-    use crate::test_utils::{get_pyth_price, get_spot_positions};
-    use crate::{create_account_info, QUOTE_PRECISION_I64};
 
     #[test]
-    pub fn successful_liquidation_liability_transfer_implied_by_asset_amount() {
+    pub fn liquidator_position_closed_cancels_linked_orders() {
         let now = 0_i64;
         let slot = 0_u64;
 
-        let mut sol_oracle_price = get_pyth_price(100, 6);
-        let sol_oracle_price_key =
+        let mut oracle_price = get_pyth_price(100, 6);
+        let oracle_price_key =
             Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
         let pyth_program = crate::ids::pyth_program::id();
         create_account_info!(
-            sol_oracle_price,
-            &sol_oracle_price_key,
+            oracle_price,
+            &oracle_price_key,
             &pyth_program,
             oracle_account_info
         );
         let mut oracle_map = OracleMap::load_one(&oracle_account_info, slot, None).unwrap();
 
-        let perp_market_map = PerpMarketMap::empty();
+        let mut market = PerpMarket {
+            amm: AMM {
+                base_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                bid_base_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+                bid_quote_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+                ask_base_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+                ask_quote_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+                sqrt_k: 100 * AMM_RESERVE_PRECISION,
+                peg_multiplier: 100 * PEG_PRECISION,
+                max_slippage_ratio: 50,
+                max_fill_reserve_fraction: 100,
+                order_step_size: 10000000,
+                quote_asset_amount: -150 * QUOTE_PRECISION_I128,
+                base_asset_amount_with_amm: BASE_PRECISION_I128,
+                oracle: oracle_price_key,
+                historical_oracle_data: HistoricalOracleData::default_price(oracle_price.agg.price),
+                ..AMM::default()
+            },
+            margin_ratio_initial: 1000,
+            margin_ratio_maintenance: 500,
+            number_of_users_with_base: 2,
+            number_of_users: 2,
+            status: MarketStatus::Initialized,
+            liquidator_fee: LIQUIDATION_FEE_PRECISION / 100,
+            if_liquidation_fee: LIQUIDATION_FEE_PRECISION / 100,
+            ..PerpMarket::default()
+        };
+        create_anchor_account_info!(market, PerpMarket, market_account_info);
+        let perp_market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
 
-        let mut usdc_market = SpotMarket {
+        let mut spot_market = SpotMarket {
             market_index: 0,
             oracle_source: OracleSource::QuoteAsset,
             cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
             decimals: 6,
             initial_asset_weight: SPOT_WEIGHT_PRECISION,
-            maintenance_asset_weight: SPOT_WEIGHT_PRECISION,
-            deposit_balance: 200 * SPOT_BALANCE_PRECISION,
-            liquidator_fee: 0,
-            historical_oracle_data: HistoricalOracleData {
-                last_oracle_price_twap: QUOTE_PRECISION_I64,
-                last_oracle_price_twap_5min: QUOTE_PRECISION_I64,
-                ..HistoricalOracleData::default()
-            },
-            ..SpotMarket::default()
-        };
-        create_anchor_account_info!(usdc_market, SpotMarket, usdc_spot_market_account_info);
-        let mut sol_market = SpotMarket {
-            market_index: 1,
-            oracle_source: OracleSource::Pyth,
-            oracle: sol_oracle_price_key,
-            cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
-            cumulative_borrow_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
-            decimals: 6,
-            initial_asset_weight: 8 * SPOT_WEIGHT_PRECISION / 10,
-            maintenance_asset_weight: 9 * SPOT_WEIGHT_PRECISION / 10,
-            initial_liability_weight: 12 * SPOT_WEIGHT_PRECISION / 10,
-            maintenance_liability_weight: 11 * SPOT_WEIGHT_PRECISION / 10,
-            deposit_balance: SPOT_BALANCE_PRECISION,
-            borrow_balance: SPOT_BALANCE_PRECISION,
-            liquidator_fee: LIQUIDATION_FEE_PRECISION / 1000,
             historical_oracle_data: HistoricalOracleData {
-                last_oracle_price_twap: (sol_oracle_price.agg.price * 99 / 100),
-                last_oracle_price_twap_5min: (sol_oracle_price.agg.price * 99 / 100),
+                last_oracle_price_twap: PRICE_PRECISION_I64,
+                last_oracle_price_twap_5min: PRICE_PRECISION_I64,
                 ..HistoricalOracleData::default()
             },
             ..SpotMarket::default()
         };
-        create_anchor_account_info!(sol_market, SpotMarket, sol_spot_market_account_info);
-        let spot_market_account_infos = Vec::from([
-            &usdc_spot_market_account_info,
-            &sol_spot_market_account_info,
-        ]);
-        let spot_market_map =
-            SpotMarketMap::load_multiple(spot_market_account_infos, true).unwrap();
+        create_anchor_account_info!(spot_market, SpotMarket, spot_market_account_info);
+        let spot_market_map = SpotMarketMap::load_one(&spot_market_account_info, true).unwrap();
 
-        let mut spot_positions = [SpotPosition::default(); 8];
-        spot_positions[0] = SpotPosition {
-            market_index: 0,
-            balance_type: SpotBalanceType::Deposit,
-            scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
-            ..SpotPosition::default()
-        };
-        spot_positions[1] = SpotPosition {
-            market_index: 1,
-            balance_type: SpotBalanceType::Borrow,
-            scaled_balance: SPOT_BALANCE_PRECISION_U64,
-            ..SpotPosition::default()
-        };
         let mut user = User {
-            orders: [Order::default(); 32],
-            perp_positions: [PerpPosition::default(); 8],
-            spot_positions,
+            orders: get_orders(Order {
+                market_index: 0,
+                status: OrderStatus::Open,
+                order_type: OrderType::Limit,
+                direction: PositionDirection::Long,
+                base_asset_amount: BASE_PRECISION_U64,
+                slot: 0,
+                ..Order::default()
+            }),
+            perp_positions: get_positions(PerpPosition {
+                market_index: 0,
+                base_asset_amount: BASE_PRECISION_I64,
+                quote_asset_amount: -150 * QUOTE_PRECISION_I64,
+                quote_entry_amount: -150 * QUOTE_PRECISION_I64,
+                quote_break_even_amount: -150 * QUOTE_PRECISION_I64,
+                open_orders: 1,
+                open_bids: BASE_PRECISION_I64,
+                ..PerpPosition::default()
+            }),
+            spot_positions: [SpotPosition::default(); 8],
+
             ..User::default()
         };
 
+        // short 1 entered at $100 with a position linked take profit
         let mut liquidator = User {
+            orders: get_orders(Order {
+                market_index: 0,
+                order_id: 1,
+                status: OrderStatus::Open,
+                order_type: OrderType::Limit,
+                market_type: MarketType::Perp,
+                direction: PositionDirection::Long,
+                base_asset_amount: BASE_PRECISION_U64,
+                price: 90 * PRICE_PRECISION_U64,
+                reduce_only: true,
+                bit_flags: OrderBitFlag::PositionLinked as u8,
+                ..Order::default()
+            }),
+            perp_positions: get_positions(PerpPosition {
+                market_index: 0,
+                base_asset_amount: -BASE_PRECISION_I64,
+                quote_asset_amount: 100 * QUOTE_PRECISION_I64,
+                quote_entry_amount: 100 * QUOTE_PRECISION_I64,
+                quote_break_even_amount: 100 * QUOTE_PRECISION_I64,
+                open_orders: 1,
+                open_bids: BASE_PRECISION_I64,
+                ..PerpPosition::default()
+            }),
             spot_positions: get_spot_positions(SpotPosition {
                 market_index: 0,
                 balance_type: SpotBalanceType::Deposit,
-                scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
+                scaled_balance: 50 * SPOT_BALANCE_PRECISION_U64,
                 ..SpotPosition::default()
             }),
             ..User::default()
@@ -2327,128 +2339,143 @@ pub mod liquidate_spot {
         let user_key = Pubkey::default();
         let liquidator_key = Pubkey::default();
 
+        let mut user_stats = UserStats::default();
+        let mut liquidator_stats = UserStats::default();
         let state = State {
             liquidation_margin_buffer_ratio: 10,
             initial_pct_to_liquidate: LIQUIDATION_PCT_PRECISION as u16,
+            initial_liquidator_fee_pct: LIQUIDATION_PCT_PRECISION as u16,
             liquidation_duration: 150,
             ..Default::default()
         };
-
-        liquidate_spot(
+        liquidate_perp(
             0,
-            1,
-            10_u128.pow(6),
+            BASE_PRECISION_U64,
             None,
             &mut user,
             &user_key,
+            &mut user_stats,
             &mut liquidator,
             &liquidator_key,
+            &mut liquidator_stats,
             &perp_market_map,
             &spot_market_map,
             &mut oracle_map,
-            now,
             slot,
+            now,
             &state,
         )
         .unwrap();
 
-        assert_eq!(user.spot_positions[0].scaled_balance, 0);
-        assert_eq!(user.spot_positions[1].scaled_balance, 999999);
+        assert_eq!(user.perp_positions[0].base_asset_amount, 0);
 
-        assert_eq!(
-            liquidator.spot_positions[0].balance_type,
-            SpotBalanceType::Deposit
-        );
-        assert_eq!(liquidator.spot_positions[0].scaled_balance, 200000000000);
-        assert_eq!(
-            liquidator.spot_positions[1].balance_type,
-            SpotBalanceType::Borrow
-        );
-        assert_eq!(liquidator.spot_positions[1].scaled_balance, 999000001);
+        // taking over the user's long closed the liquidator's short
+        assert_eq!(liquidator.perp_positions[0].base_asset_amount, 0);
+        assert_eq!(liquidator.orders[0], Order::default());
+        assert_eq!(liquidator.perp_positions[0].open_orders, 0);
+        assert_eq!(liquidator.perp_positions[0].open_bids, 0);
     }
 
     #[test]
-    pub fn successful_liquidation_liquidator_max_liability_transfer() {
+    pub fn isolated_perp_position_liquidated_without_touching_cross_margin() {
         let now = 0_i64;
         let slot = 0_u64;
 
-        let mut sol_oracle_price = get_pyth_price(100, 6);
-        let sol_oracle_price_key =
+        let mut oracle_price = get_pyth_price(100, 6);
+        let oracle_price_key =
             Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
         let pyth_program = crate::ids::pyth_program::id();
         create_account_info!(
-            sol_oracle_price,
-            &sol_oracle_price_key,
+            oracle_price,
+            &oracle_price_key,
             &pyth_program,
             oracle_account_info
         );
         let mut oracle_map = OracleMap::load_one(&oracle_account_info, slot, None).unwrap();
 
-        let perp_market_map = PerpMarketMap::empty();
+        let mut market = PerpMarket {
+            amm: AMM {
+                base_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                bid_base_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+                bid_quote_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+                ask_base_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+                ask_quote_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+                sqrt_k: 100 * AMM_RESERVE_PRECISION,
+                peg_multiplier: 100 * PEG_PRECISION,
+                max_slippage_ratio: 50,
+                max_fill_reserve_fraction: 100,
+                order_step_size: 10000000,
+                quote_asset_amount: -150 * QUOTE_PRECISION_I128,
+                base_asset_amount_with_amm: BASE_PRECISION_I128,
+                oracle: oracle_price_key,
+                historical_oracle_data: HistoricalOracleData::default_price(oracle_price.agg.price),
+                ..AMM::default()
+            },
+            margin_ratio_initial: 1000,
+            margin_ratio_maintenance: 500,
+            number_of_users_with_base: 1,
+            status: MarketStatus::Initialized,
+            liquidator_fee: LIQUIDATION_FEE_PRECISION / 100,
+            if_liquidation_fee: LIQUIDATION_FEE_PRECISION / 100,
+            ..PerpMarket::default()
+        };
+        create_anchor_account_info!(market, PerpMarket, market_account_info);
+        let perp_market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
 
-        let mut usdc_market = SpotMarket {
+        let mut spot_market = SpotMarket {
             market_index: 0,
             oracle_source: OracleSource::QuoteAsset,
             cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
             decimals: 6,
             initial_asset_weight: SPOT_WEIGHT_PRECISION,
             maintenance_asset_weight: SPOT_WEIGHT_PRECISION,
-            deposit_balance: 200 * SPOT_BALANCE_PRECISION,
-            liquidator_fee: 0,
             historical_oracle_data: HistoricalOracleData {
-                last_oracle_price_twap: QUOTE_PRECISION_I64,
-                last_oracle_price_twap_5min: QUOTE_PRECISION_I64,
+                last_oracle_price_twap: PRICE_PRECISION_I64,
+                last_oracle_price_twap_5min: PRICE_PRECISION_I64,
                 ..HistoricalOracleData::default()
             },
             ..SpotMarket::default()
         };
-        create_anchor_account_info!(usdc_market, SpotMarket, usdc_spot_market_account_info);
-        let mut sol_market = SpotMarket {
-            market_index: 1,
-            oracle_source: OracleSource::Pyth,
-            oracle: sol_oracle_price_key,
-            cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
-            cumulative_borrow_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
-            decimals: 6,
-            initial_asset_weight: 8 * SPOT_WEIGHT_PRECISION / 10,
-            maintenance_asset_weight: 9 * SPOT_WEIGHT_PRECISION / 10,
-            initial_liability_weight: 12 * SPOT_WEIGHT_PRECISION / 10,
-            maintenance_liability_weight: 11 * SPOT_WEIGHT_PRECISION / 10,
-            deposit_balance: SPOT_BALANCE_PRECISION,
-            borrow_balance: SPOT_BALANCE_PRECISION,
-            liquidator_fee: LIQUIDATION_FEE_PRECISION / 1000,
-            historical_oracle_data: HistoricalOracleData {
-                last_oracle_price_twap: (sol_oracle_price.agg.price * 1442 / 10000),
-                last_oracle_price_twap_5min: (sol_oracle_price.agg.price * 999 / 1000),
-                ..HistoricalOracleData::default()
-            },
-            ..SpotMarket::default()
-        };
-        create_anchor_account_info!(sol_market, SpotMarket, sol_spot_market_account_info);
-        let spot_market_account_infos = Vec::from([
-            &usdc_spot_market_account_info,
-            &sol_spot_market_account_info,
-        ]);
-        let spot_market_map =
-            SpotMarketMap::load_multiple(spot_market_account_infos, true).unwrap();
+        create_anchor_account_info!(spot_market, SpotMarket, spot_market_account_info);
+        let spot_market_map = SpotMarketMap::load_one(&spot_market_account_info, true).unwrap();
 
-        let mut spot_market = [SpotPosition::default(); 8];
-        spot_market[0] = SpotPosition {
-            market_index: 0,
-            balance_type: SpotBalanceType::Deposit,
-            scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
-            ..SpotPosition::default()
-        };
-        spot_market[1] = SpotPosition {
-            market_index: 1,
-            balance_type: SpotBalanceType::Borrow,
-            scaled_balance: SPOT_BALANCE_PRECISION_U64,
-            ..SpotPosition::default()
+        // the cross deposit would easily cover the loss, the isolated deposit doesnt
+        let mut isolated_perp_positions = [IsolatedPerpPosition::default(); 8];
+        isolated_perp_positions[0] = IsolatedPerpPosition {
+            scaled_balance: 40 * SPOT_BALANCE_PRECISION_U64,
+            perp_market_index: 0,
+            status: IsolatedPerpPositionStatus::Active as u8,
+            ..IsolatedPerpPosition::default()
         };
+
         let mut user = User {
-            orders: [Order::default(); 32],
-            perp_positions: [PerpPosition::default(); 8],
-            spot_positions: spot_market,
+            orders: get_orders(Order {
+                market_index: 0,
+                status: OrderStatus::Open,
+                order_type: OrderType::Limit,
+                direction: PositionDirection::Long,
+                base_asset_amount: BASE_PRECISION_U64,
+                slot: 0,
+                ..Order::default()
+            }),
+            perp_positions: get_positions(PerpPosition {
+                market_index: 0,
+                base_asset_amount: BASE_PRECISION_I64,
+                quote_asset_amount: -150 * QUOTE_PRECISION_I64,
+                quote_entry_amount: -150 * QUOTE_PRECISION_I64,
+                quote_break_even_amount: -150 * QUOTE_PRECISION_I64,
+                open_orders: 1,
+                open_bids: BASE_PRECISION_I64,
+                ..PerpPosition::default()
+            }),
+            spot_positions: get_spot_positions(SpotPosition {
+                market_index: 0,
+                balance_type: SpotBalanceType::Deposit,
+                scaled_balance: 1000 * SPOT_BALANCE_PRECISION_U64,
+                ..SpotPosition::default()
+            }),
+            isolated_perp_positions,
             ..User::default()
         };
 
@@ -2456,7 +2483,7 @@ pub mod liquidate_spot {
             spot_positions: get_spot_positions(SpotPosition {
                 market_index: 0,
                 balance_type: SpotBalanceType::Deposit,
-                scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
+                scaled_balance: 50 * SPOT_BALANCE_PRECISION_U64,
                 ..SpotPosition::default()
             }),
             ..User::default()
@@ -2465,159 +2492,180 @@ pub mod liquidate_spot {
         let user_key = Pubkey::default();
         let liquidator_key = Pubkey::default();
 
+        let mut user_stats = UserStats::default();
+        let mut liquidator_stats = UserStats::default();
         let state = State {
             liquidation_margin_buffer_ratio: 10,
             initial_pct_to_liquidate: LIQUIDATION_PCT_PRECISION as u16,
+            initial_liquidator_fee_pct: LIQUIDATION_PCT_PRECISION as u16,
             liquidation_duration: 150,
             ..Default::default()
         };
-        // oracle twap too volatile to liq rn
-        assert!(liquidate_spot(
-            0,
-            1,
-            10_u128.pow(6) / 10,
-            None,
-            &mut user,
-            &user_key,
-            &mut liquidator,
-            &liquidator_key,
-            &perp_market_map,
-            &spot_market_map,
-            &mut oracle_map,
-            now,
-            slot,
-            &state,
-        )
-        .is_err());
 
-        // move twap closer to oracle price (within 80% below)
-        let mut market1 = spot_market_map
-            .get_ref_mut(&sol_market.market_index)
+        let cross_margin_calculation =
+            calculate_margin_requirement_and_total_collateral_and_liability_info(
+                &user,
+                &perp_market_map,
+                &spot_market_map,
+                &mut oracle_map,
+                MarginContext::liquidation(state.liquidation_margin_buffer_ratio),
+            )
             .unwrap();
-        market1.historical_oracle_data.last_oracle_price_twap =
-            sol_oracle_price.agg.price * 6744 / 10000;
-        drop(market1);
+        assert_eq!(cross_margin_calculation.margin_requirement, 0);
+        assert_eq!(
+            cross_margin_calculation.total_collateral,
+            1000 * QUOTE_PRECISION_I128
+        );
 
-        liquidate_spot(
+        liquidate_perp(
             0,
-            1,
-            10_u128.pow(6) / 10,
+            BASE_PRECISION_U64,
             None,
             &mut user,
             &user_key,
+            &mut user_stats,
             &mut liquidator,
             &liquidator_key,
+            &mut liquidator_stats,
             &perp_market_map,
             &spot_market_map,
             &mut oracle_map,
-            now,
             slot,
+            now,
             &state,
         )
         .unwrap();
 
-        assert_eq!(user.spot_positions[0].scaled_balance, 89989990000);
-        assert_eq!(user.spot_positions[1].scaled_balance, 899999999);
+        assert_eq!(user.perp_positions[0].base_asset_amount, 0);
+        assert_eq!(
+            user.perp_positions[0].quote_asset_amount,
+            -51 * QUOTE_PRECISION_I64
+        );
+        assert_eq!(user.perp_positions[0].open_orders, 0);
 
+        // the loss is left for the isolated deposit, the rest of the account isnt liquidated
+        assert!(!user.is_being_liquidated());
         assert_eq!(
-            liquidator.spot_positions[0].balance_type,
-            SpotBalanceType::Deposit
+            user.spot_positions[0].scaled_balance,
+            1000 * SPOT_BALANCE_PRECISION_U64
         );
-        assert_eq!(liquidator.spot_positions[0].scaled_balance, 110010010000);
+        let isolated_perp_position = user.get_isolated_perp_position(0).unwrap();
+        assert!(isolated_perp_position.is_being_liquidated());
         assert_eq!(
-            liquidator.spot_positions[1].balance_type,
-            SpotBalanceType::Borrow
+            isolated_perp_position.scaled_balance,
+            40 * SPOT_BALANCE_PRECISION_U64
+        );
+
+        assert_eq!(
+            liquidator.perp_positions[0].base_asset_amount,
+            BASE_PRECISION_I64
         );
-        assert_eq!(liquidator.spot_positions[1].scaled_balance, 100000001);
     }
+}
+
+pub mod self_liquidate_perp {
+    use std::str::FromStr;
+
+    use anchor_lang::Owner;
+    use solana_program::pubkey::Pubkey;
+
+    use crate::controller::liquidation::self_liquidate_perp;
+    use crate::create_anchor_account_info;
+    use crate::error::ErrorCode;
+    use crate::math::constants::{
+        AMM_RESERVE_PRECISION, BASE_PRECISION_I128, BASE_PRECISION_I64, BASE_PRECISION_U64,
+        LIQUIDATION_FEE_PRECISION, LIQUIDATION_PCT_PRECISION, MARGIN_PRECISION, PEG_PRECISION,
+        PRICE_PRECISION_U64, QUOTE_PRECISION_I128, QUOTE_PRECISION_I64, SPOT_BALANCE_PRECISION,
+        SPOT_BALANCE_PRECISION_U64, SPOT_CUMULATIVE_INTEREST_PRECISION, SPOT_WEIGHT_PRECISION,
+    };
+    use crate::state::oracle::{HistoricalOracleData, OracleSource};
+    use crate::state::oracle_map::OracleMap;
+    use crate::state::perp_market::{MarketStatus, PerpMarket, AMM};
+    use crate::state::perp_market_map::PerpMarketMap;
+    use crate::state::spot_market::{SpotBalanceType, SpotMarket};
+    use crate::state::spot_market_map::SpotMarketMap;
+    use crate::state::state::State;
+    use crate::state::user::{PerpPosition, SpotPosition, User, UserStats};
+    use crate::test_utils::*;
+    use crate::test_utils::{get_positions, get_pyth_price, get_spot_positions};
+    use crate::{create_account_info, PRICE_PRECISION_I64};
 
     #[test]
-    pub fn successful_liquidation_liability_transfer_to_cover_margin_shortage() {
+    pub fn closes_against_amm_and_pays_fee_to_insurance_fund() {
         let now = 0_i64;
         let slot = 0_u64;
 
-        let mut sol_oracle_price = get_pyth_price(100, 6);
-        let sol_oracle_price_key =
+        let mut oracle_price = get_pyth_price(100, 6);
+        let oracle_price_key =
             Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
         let pyth_program = crate::ids::pyth_program::id();
         create_account_info!(
-            sol_oracle_price,
-            &sol_oracle_price_key,
+            oracle_price,
+            &oracle_price_key,
             &pyth_program,
             oracle_account_info
         );
         let mut oracle_map = OracleMap::load_one(&oracle_account_info, slot, None).unwrap();
 
-        let market_map = PerpMarketMap::empty();
+        let mut market = PerpMarket {
+            amm: AMM {
+                base_asset_reserve: 1000 * AMM_RESERVE_PRECISION,
+                quote_asset_reserve: 1000 * AMM_RESERVE_PRECISION,
+                bid_base_asset_reserve: 1000 * AMM_RESERVE_PRECISION,
+                bid_quote_asset_reserve: 1000 * AMM_RESERVE_PRECISION,
+                ask_base_asset_reserve: 1000 * AMM_RESERVE_PRECISION,
+                ask_quote_asset_reserve: 1000 * AMM_RESERVE_PRECISION,
+                sqrt_k: 1000 * AMM_RESERVE_PRECISION,
+                peg_multiplier: 100 * PEG_PRECISION,
+                max_base_asset_reserve: 2000 * AMM_RESERVE_PRECISION,
+                min_base_asset_reserve: 500 * AMM_RESERVE_PRECISION,
+                max_slippage_ratio: 50,
+                max_fill_reserve_fraction: 100,
+                order_step_size: 10000000,
+                base_asset_amount_long: 10 * BASE_PRECISION_I128,
+                base_asset_amount_with_amm: 10 * BASE_PRECISION_I128,
+                quote_asset_amount: -1000 * QUOTE_PRECISION_I128,
+                quote_entry_amount_long: -1000 * QUOTE_PRECISION_I128,
+                quote_break_even_amount_long: -1000 * QUOTE_PRECISION_I128,
+                oracle: oracle_price_key,
+                historical_oracle_data: HistoricalOracleData::default_price(oracle_price.agg.price),
+                ..AMM::default()
+            },
+            margin_ratio_initial: 1000,
+            margin_ratio_maintenance: 500,
+            number_of_users_with_base: 1,
+            number_of_users: 1,
+            status: MarketStatus::Active,
+            liquidator_fee: LIQUIDATION_FEE_PRECISION / 100,
+            if_liquidation_fee: LIQUIDATION_FEE_PRECISION / 100,
+            ..PerpMarket::default()
+        };
+        create_anchor_account_info!(market, PerpMarket, market_account_info);
+        let perp_market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
 
-        let mut usdc_market = SpotMarket {
+        let mut spot_market = SpotMarket {
             market_index: 0,
             oracle_source: OracleSource::QuoteAsset,
             cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
             decimals: 6,
             initial_asset_weight: SPOT_WEIGHT_PRECISION,
             maintenance_asset_weight: SPOT_WEIGHT_PRECISION,
-            deposit_balance: 200 * SPOT_BALANCE_PRECISION,
-            liquidator_fee: 0,
-            historical_oracle_data: HistoricalOracleData {
-                last_oracle_price_twap: QUOTE_PRECISION_I64,
-                last_oracle_price_twap_5min: QUOTE_PRECISION_I64,
-                ..HistoricalOracleData::default()
-            },
-            ..SpotMarket::default()
-        };
-        create_anchor_account_info!(usdc_market, SpotMarket, usdc_spot_market_account_info);
-        let mut sol_market = SpotMarket {
-            market_index: 1,
-            oracle_source: OracleSource::Pyth,
-            oracle: sol_oracle_price_key,
-            cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
-            cumulative_borrow_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
-            decimals: 6,
-            initial_asset_weight: 8 * SPOT_WEIGHT_PRECISION / 10,
-            maintenance_asset_weight: 9 * SPOT_WEIGHT_PRECISION / 10,
-            initial_liability_weight: 12 * SPOT_WEIGHT_PRECISION / 10,
-            maintenance_liability_weight: 11 * SPOT_WEIGHT_PRECISION / 10,
-            deposit_balance: SPOT_BALANCE_PRECISION,
-            borrow_balance: SPOT_BALANCE_PRECISION,
-            liquidator_fee: LIQUIDATION_FEE_PRECISION / 1000,
-            if_liquidation_fee: LIQUIDATION_FEE_PRECISION / 1000,
-            historical_oracle_data: HistoricalOracleData {
-                last_oracle_price_twap: (sol_oracle_price.agg.price * 99 / 100),
-                last_oracle_price_twap_5min: (sol_oracle_price.agg.price * 99 / 100),
-                ..HistoricalOracleData::default()
-            },
+            deposit_balance: 100 * SPOT_BALANCE_PRECISION,
+            historical_oracle_data: HistoricalOracleData::default_price(PRICE_PRECISION_I64),
             ..SpotMarket::default()
         };
-        create_anchor_account_info!(sol_market, SpotMarket, sol_spot_market_account_info);
-        let spot_market_account_infos = Vec::from([
-            &usdc_spot_market_account_info,
-            &sol_spot_market_account_info,
-        ]);
-        let spot_market_map =
-            SpotMarketMap::load_multiple(spot_market_account_infos, true).unwrap();
+        create_anchor_account_info!(spot_market, SpotMarket, spot_market_account_info);
+        let spot_market_map = SpotMarketMap::load_one(&spot_market_account_info, true).unwrap();
 
-        let mut spot_positions = [SpotPosition::default(); 8];
-        spot_positions[0] = SpotPosition {
-            market_index: 0,
-            balance_type: SpotBalanceType::Deposit,
-            scaled_balance: 105 * SPOT_BALANCE_PRECISION_U64,
-            ..SpotPosition::default()
-        };
-        spot_positions[1] = SpotPosition {
-            market_index: 1,
-            balance_type: SpotBalanceType::Borrow,
-            scaled_balance: SPOT_BALANCE_PRECISION_U64,
-            ..SpotPosition::default()
-        };
         let mut user = User {
-            orders: [Order::default(); 32],
-            perp_positions: [PerpPosition::default(); 8],
-            spot_positions,
-            ..User::default()
-        };
-
-        let mut liquidator = User {
+            perp_positions: get_positions(PerpPosition {
+                market_index: 0,
+                base_asset_amount: 10 * BASE_PRECISION_I64,
+                quote_asset_amount: -1000 * QUOTE_PRECISION_I64,
+                quote_entry_amount: -1000 * QUOTE_PRECISION_I64,
+                quote_break_even_amount: -1000 * QUOTE_PRECISION_I64,
+                ..PerpPosition::default()
+            }),
             spot_positions: get_spot_positions(SpotPosition {
                 market_index: 0,
                 balance_type: SpotBalanceType::Deposit,
@@ -2626,127 +2674,210 @@ pub mod liquidate_spot {
             }),
             ..User::default()
         };
-
         let user_key = Pubkey::default();
-        let liquidator_key = Pubkey::default();
+        let mut user_stats = UserStats::default();
 
         let state = State {
-            liquidation_margin_buffer_ratio: MARGIN_PRECISION as u32 / 50,
-            initial_pct_to_liquidate: LIQUIDATION_PCT_PRECISION as u16,
+            liquidation_margin_buffer_ratio: MARGIN_PRECISION / 50,
+            initial_liquidator_fee_pct: (LIQUIDATION_PCT_PRECISION / 4) as u16,
             liquidation_duration: 150,
             ..Default::default()
         };
-        liquidate_spot(
+
+        let result = self_liquidate_perp(
             0,
-            1,
-            10_u128.pow(6),
+            10 * BASE_PRECISION_U64,
             None,
             &mut user,
             &user_key,
-            &mut liquidator,
-            &liquidator_key,
-            &market_map,
+            &mut user_stats,
+            &perp_market_map,
             &spot_market_map,
             &mut oracle_map,
-            now,
             slot,
+            now,
             &state,
-        )
-        .unwrap();
+        );
+        assert_eq!(result, Err(ErrorCode::SufficientCollateral));
 
-        assert_eq!(user.spot_positions[0].scaled_balance, 45558159000);
-        assert_eq!(user.spot_positions[1].scaled_balance, 406768999);
+        // $45 of collateral against a $50 maintenance requirement
+        user.spot_positions[0].scaled_balance = 45 * SPOT_BALANCE_PRECISION_U64;
 
-        let liquidation_buffer = state.liquidation_margin_buffer_ratio;
-        let MarginCalculation {
-            margin_requirement,
-            total_collateral,
-            margin_requirement_plus_buffer,
-            ..
-        } = calculate_margin_requirement_and_total_collateral_and_liability_info(
-            &user,
-            &market_map,
+        self_liquidate_perp(
+            0,
+            10 * BASE_PRECISION_U64,
+            None,
+            &mut user,
+            &user_key,
+            &mut user_stats,
+            &perp_market_map,
             &spot_market_map,
             &mut oracle_map,
-            MarginContext::liquidation(liquidation_buffer),
+            slot,
+            now,
+            &state,
         )
         .unwrap();
 
-        assert_eq!(margin_requirement, 44744590);
-        assert_eq!(total_collateral, 45558159);
-        assert_eq!(margin_requirement_plus_buffer, 45558128);
+        assert_eq!(user.perp_positions[0].base_asset_amount, 0);
+        assert!(!user.is_being_liquidated());
 
-        let token_amount = get_token_amount(
-            user.spot_positions[1].scaled_balance as u128,
-            spot_market_map.get_ref(&1).unwrap().deref(),
-            &user.spot_positions[1].balance_type,
-        )
-        .unwrap();
-        let oracle_price_data = oracle_map.get_price_data(&sol_oracle_price_key).unwrap();
-        let token_value =
-            get_token_value(token_amount as i128, 6, oracle_price_data.price).unwrap();
+        // a quarter of the 1% liquidator fee plus the 1% if fee on $1000, all to the insurance fund
+        let market = perp_market_map.get_ref(&0).unwrap();
+        assert_eq!(market.amm.total_liquidation_fee, 12_500_000);
+        assert_eq!(market.amm.base_asset_amount_with_amm, 0);
 
-        let strict_price_1 = StrictOraclePrice {
-            current: oracle_price_data.price,
-            twap_5min: Some(oracle_price_data.price / 10),
-        };
-        let strict_token_value_1 =
-            get_strict_token_value(token_amount as i128, 6, &strict_price_1).unwrap();
+        // the amm's slippage plus the fee
+        let quote_asset_amount = user.perp_positions[0].quote_asset_amount;
+        assert!(quote_asset_amount < -12_500_000);
+        assert!(quote_asset_amount > -25 * QUOTE_PRECISION_I64);
+    }
 
-        let strict_price_2 = StrictOraclePrice {
-            current: oracle_price_data.price,
-            twap_5min: Some(oracle_price_data.price * 2),
-        };
-        let strict_token_value_2 =
-            get_strict_token_value(token_amount as i128, 6, &strict_price_2).unwrap();
+    #[test]
+    pub fn fill_must_satisfy_limit_price() {
+        let now = 0_i64;
+        let slot = 0_u64;
 
-        let strict_price_3 = StrictOraclePrice {
-            current: oracle_price_data.price,
-            twap_5min: Some(oracle_price_data.price * 2),
+        let mut oracle_price = get_pyth_price(100, 6);
+        let oracle_price_key =
+            Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+        let pyth_program = crate::ids::pyth_program::id();
+        create_account_info!(
+            oracle_price,
+            &oracle_price_key,
+            &pyth_program,
+            oracle_account_info
+        );
+        let mut oracle_map = OracleMap::load_one(&oracle_account_info, slot, None).unwrap();
+
+        let mut market = PerpMarket {
+            amm: AMM {
+                base_asset_reserve: 1000 * AMM_RESERVE_PRECISION,
+                quote_asset_reserve: 1000 * AMM_RESERVE_PRECISION,
+                bid_base_asset_reserve: 1000 * AMM_RESERVE_PRECISION,
+                bid_quote_asset_reserve: 1000 * AMM_RESERVE_PRECISION,
+                ask_base_asset_reserve: 1000 * AMM_RESERVE_PRECISION,
+                ask_quote_asset_reserve: 1000 * AMM_RESERVE_PRECISION,
+                sqrt_k: 1000 * AMM_RESERVE_PRECISION,
+                peg_multiplier: 100 * PEG_PRECISION,
+                max_base_asset_reserve: 2000 * AMM_RESERVE_PRECISION,
+                min_base_asset_reserve: 500 * AMM_RESERVE_PRECISION,
+                max_slippage_ratio: 50,
+                max_fill_reserve_fraction: 100,
+                order_step_size: 10000000,
+                base_asset_amount_long: 10 * BASE_PRECISION_I128,
+                base_asset_amount_with_amm: 10 * BASE_PRECISION_I128,
+                quote_asset_amount: -1000 * QUOTE_PRECISION_I128,
+                quote_entry_amount_long: -1000 * QUOTE_PRECISION_I128,
+                quote_break_even_amount_long: -1000 * QUOTE_PRECISION_I128,
+                oracle: oracle_price_key,
+                historical_oracle_data: HistoricalOracleData::default_price(oracle_price.agg.price),
+                ..AMM::default()
+            },
+            margin_ratio_initial: 1000,
+            margin_ratio_maintenance: 500,
+            number_of_users_with_base: 1,
+            number_of_users: 1,
+            status: MarketStatus::Active,
+            liquidator_fee: LIQUIDATION_FEE_PRECISION / 100,
+            if_liquidation_fee: LIQUIDATION_FEE_PRECISION / 100,
+            ..PerpMarket::default()
         };
-        let strict_token_value_3 =
-            get_strict_token_value(-(token_amount as i128), 6, &strict_price_3).unwrap();
+        create_anchor_account_info!(market, PerpMarket, market_account_info);
+        let perp_market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
 
-        assert_eq!(token_amount, 406769);
-        assert_eq!(token_value, 40676900);
-        assert_eq!(strict_token_value_1, 4067690); // if oracle price is more favorable than twap
-        assert_eq!(strict_token_value_2, token_value); // oracle price is less favorable than twap
-        assert_eq!(strict_token_value_3, -(token_value * 2)); // if liability and strict would value as twap
+        let mut spot_market = SpotMarket {
+            market_index: 0,
+            oracle_source: OracleSource::QuoteAsset,
+            cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            decimals: 6,
+            initial_asset_weight: SPOT_WEIGHT_PRECISION,
+            maintenance_asset_weight: SPOT_WEIGHT_PRECISION,
+            deposit_balance: 100 * SPOT_BALANCE_PRECISION,
+            historical_oracle_data: HistoricalOracleData::default_price(PRICE_PRECISION_I64),
+            ..SpotMarket::default()
+        };
+        create_anchor_account_info!(spot_market, SpotMarket, spot_market_account_info);
+        let spot_market_map = SpotMarketMap::load_one(&spot_market_account_info, true).unwrap();
 
-        let margin_ratio =
-            total_collateral.unsigned_abs() * MARGIN_PRECISION_U128 / token_value.unsigned_abs();
+        let mut user = User {
+            perp_positions: get_positions(PerpPosition {
+                market_index: 0,
+                base_asset_amount: 10 * BASE_PRECISION_I64,
+                quote_asset_amount: -1000 * QUOTE_PRECISION_I64,
+                quote_entry_amount: -1000 * QUOTE_PRECISION_I64,
+                quote_break_even_amount: -1000 * QUOTE_PRECISION_I64,
+                ..PerpPosition::default()
+            }),
+            spot_positions: get_spot_positions(SpotPosition {
+                market_index: 0,
+                balance_type: SpotBalanceType::Deposit,
+                scaled_balance: 45 * SPOT_BALANCE_PRECISION_U64,
+                ..SpotPosition::default()
+            }),
+            ..User::default()
+        };
+        let user_key = Pubkey::default();
+        let mut user_stats = UserStats::default();
 
-        assert_eq!(margin_ratio, 11200); // 112%
+        let state = State {
+            liquidation_margin_buffer_ratio: MARGIN_PRECISION / 50,
+            initial_liquidator_fee_pct: (LIQUIDATION_PCT_PRECISION / 4) as u16,
+            liquidation_duration: 150,
+            ..Default::default()
+        };
 
-        assert_eq!(
-            liquidator.spot_positions[0].balance_type,
-            SpotBalanceType::Deposit
-        );
-        assert_eq!(liquidator.spot_positions[0].scaled_balance, 159441841000);
-        assert_eq!(
-            liquidator.spot_positions[1].balance_type,
-            SpotBalanceType::Borrow
+        // selling 10 into the amm fills below the oracle price
+        let result = self_liquidate_perp(
+            0,
+            10 * BASE_PRECISION_U64,
+            Some(100 * PRICE_PRECISION_U64),
+            &mut user,
+            &user_key,
+            &mut user_stats,
+            &perp_market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            slot,
+            now,
+            &state,
         );
-        assert_eq!(liquidator.spot_positions[1].scaled_balance, 593824001);
+        assert_eq!(result, Err(ErrorCode::InvalidOrderFillPrice));
+    }
+}
 
-        let market_after = spot_market_map.get_ref(&1).unwrap();
-        let market_revenue = get_token_amount(
-            market_after.revenue_pool.scaled_balance,
-            &market_after,
-            &SpotBalanceType::Deposit,
-        )
-        .unwrap();
+pub mod liquidate_spot {
+    use crate::state::state::State;
+    use std::ops::Deref;
+    use std::str::FromStr;
 
-        assert_eq!(market_revenue, 593);
-        assert_eq!(
-            liquidator.spot_positions[1].scaled_balance + user.spot_positions[1].scaled_balance
-                - market_after.revenue_pool.scaled_balance as u64,
-            SPOT_BALANCE_PRECISION_U64
-        );
-    }
+    use anchor_lang::Owner;
+    use solana_program::pubkey::Pubkey;
+
+    use crate::controller::liquidation::liquidate_spot;
+    use crate::create_anchor_account_info;
+    use crate::error::ErrorCode;
+    use crate::math::constants::{
+        LIQUIDATION_FEE_PRECISION, LIQUIDATION_PCT_PRECISION, MARGIN_PRECISION,
+        MARGIN_PRECISION_U128, PRICE_PRECISION, PRICE_PRECISION_U64, SPOT_BALANCE_PRECISION,
+        SPOT_BALANCE_PRECISION_U64, SPOT_CUMULATIVE_INTEREST_PRECISION, SPOT_WEIGHT_PRECISION,
+    };
+    use crate::math::margin::calculate_margin_requirement_and_total_collateral_and_liability_info;
+    use crate::math::spot_balance::{get_strict_token_value, get_token_amount, get_token_value};
+    use crate::state::margin_calculation::{MarginCalculation, MarginContext};
+    use crate::state::oracle::OracleSource;
+    use crate::state::oracle::{HistoricalOracleData, StrictOraclePrice};
+    use crate::state::oracle_map::OracleMap;
+    use crate::state::perp_market_map::PerpMarketMap;
+    use crate::state::spot_market::{SpotBalanceType, SpotMarket};
+    use crate::state::spot_market_map::SpotMarketMap;
+    use crate::state::user::{Order, PerpPosition, SpotPosition, User};
+    use crate::test_utils::*;
+    use crate::test_utils::{get_pyth_price, get_spot_positions};
+    use crate::{create_account_info, QUOTE_PRECISION_I64};
 
     #[test]
-    pub fn failure_due_to_limit_price() {
+    pub fn successful_liquidation_liability_transfer_implied_by_asset_amount() {
         let now = 0_i64;
         let slot = 0_u64;
 
@@ -2842,18 +2973,20 @@ pub mod liquidate_spot {
 
         let user_key = Pubkey::default();
         let liquidator_key = Pubkey::default();
+
         let state = State {
-            liquidation_margin_buffer_ratio: MARGIN_PRECISION as u32 / 50,
+            liquidation_margin_buffer_ratio: 10,
             initial_pct_to_liquidate: LIQUIDATION_PCT_PRECISION as u16,
+            initial_liquidator_fee_pct: LIQUIDATION_PCT_PRECISION as u16,
             liquidation_duration: 150,
             ..Default::default()
         };
-        let limit_price = (100000000 * PRICE_PRECISION_U64 / 999000) + 1;
-        let result = liquidate_spot(
+
+        liquidate_spot(
             0,
             1,
             10_u128.pow(6),
-            Some(limit_price),
+            None,
             &mut user,
             &user_key,
             &mut liquidator,
@@ -2864,13 +2997,26 @@ pub mod liquidate_spot {
             now,
             slot,
             &state,
-        );
+        )
+        .unwrap();
 
-        assert_eq!(result, Err(ErrorCode::LiquidationDoesntSatisfyLimitPrice));
+        assert_eq!(user.spot_positions[0].scaled_balance, 0);
+        assert_eq!(user.spot_positions[1].scaled_balance, 999999);
+
+        assert_eq!(
+            liquidator.spot_positions[0].balance_type,
+            SpotBalanceType::Deposit
+        );
+        assert_eq!(liquidator.spot_positions[0].scaled_balance, 200000000000);
+        assert_eq!(
+            liquidator.spot_positions[1].balance_type,
+            SpotBalanceType::Borrow
+        );
+        assert_eq!(liquidator.spot_positions[1].scaled_balance, 999000001);
     }
 
     #[test]
-    pub fn success_with_to_limit_price() {
+    pub fn successful_liquidation_liquidator_max_liability_transfer() {
         let now = 0_i64;
         let slot = 0_u64;
 
@@ -2920,8 +3066,8 @@ pub mod liquidate_spot {
             borrow_balance: SPOT_BALANCE_PRECISION,
             liquidator_fee: LIQUIDATION_FEE_PRECISION / 1000,
             historical_oracle_data: HistoricalOracleData {
-                last_oracle_price_twap: (sol_oracle_price.agg.price * 99 / 100),
-                last_oracle_price_twap_5min: (sol_oracle_price.agg.price * 99 / 100),
+                last_oracle_price_twap: (sol_oracle_price.agg.price * 1442 / 10000),
+                last_oracle_price_twap_5min: (sol_oracle_price.agg.price * 999 / 1000),
                 ..HistoricalOracleData::default()
             },
             ..SpotMarket::default()
@@ -2934,14 +3080,14 @@ pub mod liquidate_spot {
         let spot_market_map =
             SpotMarketMap::load_multiple(spot_market_account_infos, true).unwrap();
 
-        let mut spot_positions = [SpotPosition::default(); 8];
-        spot_positions[0] = SpotPosition {
+        let mut spot_market = [SpotPosition::default(); 8];
+        spot_market[0] = SpotPosition {
             market_index: 0,
             balance_type: SpotBalanceType::Deposit,
             scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
             ..SpotPosition::default()
         };
-        spot_positions[1] = SpotPosition {
+        spot_market[1] = SpotPosition {
             market_index: 1,
             balance_type: SpotBalanceType::Borrow,
             scaled_balance: SPOT_BALANCE_PRECISION_U64,
@@ -2950,7 +3096,7 @@ pub mod liquidate_spot {
         let mut user = User {
             orders: [Order::default(); 32],
             perp_positions: [PerpPosition::default(); 8],
-            spot_positions,
+            spot_positions: spot_market,
             ..User::default()
         };
 
@@ -2966,18 +3112,20 @@ pub mod liquidate_spot {
 
         let user_key = Pubkey::default();
         let liquidator_key = Pubkey::default();
+
         let state = State {
-            liquidation_margin_buffer_ratio: MARGIN_PRECISION as u32 / 50,
+            liquidation_margin_buffer_ratio: 10,
             initial_pct_to_liquidate: LIQUIDATION_PCT_PRECISION as u16,
+            initial_liquidator_fee_pct: LIQUIDATION_PCT_PRECISION as u16,
             liquidation_duration: 150,
             ..Default::default()
         };
-        let limit_price = (100000000 * PRICE_PRECISION_U64 / 999000) - 1;
-        let result = liquidate_spot(
+        // oracle twap too volatile to liq rn
+        assert!(liquidate_spot(
             0,
             1,
-            10_u128.pow(6),
-            Some(limit_price),
+            10_u128.pow(6) / 10,
+            None,
             &mut user,
             &user_key,
             &mut liquidator,
@@ -2988,14 +3136,53 @@ pub mod liquidate_spot {
             now,
             slot,
             &state,
-        );
+        )
+        .is_err());
 
-        assert_eq!(result, Ok(()));
-    }
+        // move twap closer to oracle price (within 80% below)
+        let mut market1 = spot_market_map
+            .get_ref_mut(&sol_market.market_index)
+            .unwrap();
+        market1.historical_oracle_data.last_oracle_price_twap =
+            sol_oracle_price.agg.price * 6744 / 10000;
+        drop(market1);
 
-    #[test]
-    pub fn successful_liquidation_dust_borrow() {
-        let now = 0_i64;
+        liquidate_spot(
+            0,
+            1,
+            10_u128.pow(6) / 10,
+            None,
+            &mut user,
+            &user_key,
+            &mut liquidator,
+            &liquidator_key,
+            &perp_market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            now,
+            slot,
+            &state,
+        )
+        .unwrap();
+
+        assert_eq!(user.spot_positions[0].scaled_balance, 89989990000);
+        assert_eq!(user.spot_positions[1].scaled_balance, 899999999);
+
+        assert_eq!(
+            liquidator.spot_positions[0].balance_type,
+            SpotBalanceType::Deposit
+        );
+        assert_eq!(liquidator.spot_positions[0].scaled_balance, 110010010000);
+        assert_eq!(
+            liquidator.spot_positions[1].balance_type,
+            SpotBalanceType::Borrow
+        );
+        assert_eq!(liquidator.spot_positions[1].scaled_balance, 100000001);
+    }
+
+    #[test]
+    pub fn successful_liquidation_liability_transfer_to_cover_margin_shortage() {
+        let now = 0_i64;
         let slot = 0_u64;
 
         let mut sol_oracle_price = get_pyth_price(100, 6);
@@ -3063,13 +3250,13 @@ pub mod liquidate_spot {
         spot_positions[0] = SpotPosition {
             market_index: 0,
             balance_type: SpotBalanceType::Deposit,
-            scaled_balance: 107 * SPOT_BALANCE_PRECISION_U64 / 50,
+            scaled_balance: 105 * SPOT_BALANCE_PRECISION_U64,
             ..SpotPosition::default()
         };
         spot_positions[1] = SpotPosition {
             market_index: 1,
             balance_type: SpotBalanceType::Borrow,
-            scaled_balance: SPOT_BALANCE_PRECISION_U64 / 50,
+            scaled_balance: SPOT_BALANCE_PRECISION_U64,
             ..SpotPosition::default()
         };
         let mut user = User {
@@ -3095,6 +3282,7 @@ pub mod liquidate_spot {
         let state = State {
             liquidation_margin_buffer_ratio: MARGIN_PRECISION as u32 / 50,
             initial_pct_to_liquidate: LIQUIDATION_PCT_PRECISION as u16,
+            initial_liquidator_fee_pct: LIQUIDATION_PCT_PRECISION as u16,
             liquidation_duration: 150,
             ..Default::default()
         };
@@ -3116,17 +3304,101 @@ pub mod liquidate_spot {
         )
         .unwrap();
 
-        assert_eq!(user.spot_positions[0].scaled_balance, 0);
-        assert_eq!(user.spot_positions[1].scaled_balance, 19999);
+        assert_eq!(user.spot_positions[0].scaled_balance, 45558159000);
+        assert_eq!(user.spot_positions[1].scaled_balance, 406768999);
 
-        assert_eq!(liquidator.spot_positions[0].scaled_balance, 102140000000);
-        assert_eq!(liquidator.spot_positions[1].scaled_balance, 20000001); // ~$1 worth of liability
+        let liquidation_buffer = state.liquidation_margin_buffer_ratio;
+        let MarginCalculation {
+            margin_requirement,
+            total_collateral,
+            margin_requirement_plus_buffer,
+            ..
+        } = calculate_margin_requirement_and_total_collateral_and_liability_info(
+            &user,
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            MarginContext::liquidation(liquidation_buffer),
+        )
+        .unwrap();
+
+        assert_eq!(margin_requirement, 44744590);
+        assert_eq!(total_collateral, 45558159);
+        assert_eq!(margin_requirement_plus_buffer, 45558128);
+
+        let token_amount = get_token_amount(
+            user.spot_positions[1].scaled_balance as u128,
+            spot_market_map.get_ref(&1).unwrap().deref(),
+            &user.spot_positions[1].balance_type,
+        )
+        .unwrap();
+        let oracle_price_data = oracle_map.get_price_data(&sol_oracle_price_key).unwrap();
+        let token_value =
+            get_token_value(token_amount as i128, 6, oracle_price_data.price).unwrap();
+
+        let strict_price_1 = StrictOraclePrice {
+            current: oracle_price_data.price,
+            twap_5min: Some(oracle_price_data.price / 10),
+        };
+        let strict_token_value_1 =
+            get_strict_token_value(token_amount as i128, 6, &strict_price_1).unwrap();
+
+        let strict_price_2 = StrictOraclePrice {
+            current: oracle_price_data.price,
+            twap_5min: Some(oracle_price_data.price * 2),
+        };
+        let strict_token_value_2 =
+            get_strict_token_value(token_amount as i128, 6, &strict_price_2).unwrap();
+
+        let strict_price_3 = StrictOraclePrice {
+            current: oracle_price_data.price,
+            twap_5min: Some(oracle_price_data.price * 2),
+        };
+        let strict_token_value_3 =
+            get_strict_token_value(-(token_amount as i128), 6, &strict_price_3).unwrap();
+
+        assert_eq!(token_amount, 406769);
+        assert_eq!(token_value, 40676900);
+        assert_eq!(strict_token_value_1, 4067690); // if oracle price is more favorable than twap
+        assert_eq!(strict_token_value_2, token_value); // oracle price is less favorable than twap
+        assert_eq!(strict_token_value_3, -(token_value * 2)); // if liability and strict would value as twap
+
+        let margin_ratio =
+            total_collateral.unsigned_abs() * MARGIN_PRECISION_U128 / token_value.unsigned_abs();
+
+        assert_eq!(margin_ratio, 11200); // 112%
+
+        assert_eq!(
+            liquidator.spot_positions[0].balance_type,
+            SpotBalanceType::Deposit
+        );
+        assert_eq!(liquidator.spot_positions[0].scaled_balance, 159441841000);
+        assert_eq!(
+            liquidator.spot_positions[1].balance_type,
+            SpotBalanceType::Borrow
+        );
+        assert_eq!(liquidator.spot_positions[1].scaled_balance, 593824001);
+
+        let market_after = spot_market_map.get_ref(&1).unwrap();
+        let market_revenue = get_token_amount(
+            market_after.revenue_pool.scaled_balance,
+            &market_after,
+            &SpotBalanceType::Deposit,
+        )
+        .unwrap();
+
+        assert_eq!(market_revenue, 593);
+        assert_eq!(
+            liquidator.spot_positions[1].scaled_balance + user.spot_positions[1].scaled_balance
+                - market_after.revenue_pool.scaled_balance as u64,
+            SPOT_BALANCE_PRECISION_U64
+        );
     }
 
     #[test]
-    pub fn liquidate_over_multiple_slots() {
-        let now = 1_i64;
-        let slot = 1_u64;
+    pub fn failure_due_to_limit_price() {
+        let now = 0_i64;
+        let slot = 0_u64;
 
         let mut sol_oracle_price = get_pyth_price(100, 6);
         let sol_oracle_price_key =
@@ -3140,7 +3412,7 @@ pub mod liquidate_spot {
         );
         let mut oracle_map = OracleMap::load_one(&oracle_account_info, slot, None).unwrap();
 
-        let market_map = PerpMarketMap::empty();
+        let perp_market_map = PerpMarketMap::empty();
 
         let mut usdc_market = SpotMarket {
             market_index: 0,
@@ -3170,10 +3442,9 @@ pub mod liquidate_spot {
             maintenance_asset_weight: 9 * SPOT_WEIGHT_PRECISION / 10,
             initial_liability_weight: 12 * SPOT_WEIGHT_PRECISION / 10,
             maintenance_liability_weight: 11 * SPOT_WEIGHT_PRECISION / 10,
-            deposit_balance: 10 * SPOT_BALANCE_PRECISION,
-            borrow_balance: 10 * SPOT_BALANCE_PRECISION,
+            deposit_balance: SPOT_BALANCE_PRECISION,
+            borrow_balance: SPOT_BALANCE_PRECISION,
             liquidator_fee: LIQUIDATION_FEE_PRECISION / 1000,
-            if_liquidation_fee: LIQUIDATION_FEE_PRECISION / 1000,
             historical_oracle_data: HistoricalOracleData {
                 last_oracle_price_twap: (sol_oracle_price.agg.price * 99 / 100),
                 last_oracle_price_twap_5min: (sol_oracle_price.agg.price * 99 / 100),
@@ -3193,13 +3464,13 @@ pub mod liquidate_spot {
         spot_positions[0] = SpotPosition {
             market_index: 0,
             balance_type: SpotBalanceType::Deposit,
-            scaled_balance: 1050 * SPOT_BALANCE_PRECISION_U64,
+            scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
             ..SpotPosition::default()
         };
         spot_positions[1] = SpotPosition {
             market_index: 1,
             balance_type: SpotBalanceType::Borrow,
-            scaled_balance: 10 * SPOT_BALANCE_PRECISION_U64,
+            scaled_balance: SPOT_BALANCE_PRECISION_U64,
             ..SpotPosition::default()
         };
         let mut user = User {
@@ -3213,7 +3484,7 @@ pub mod liquidate_spot {
             spot_positions: get_spot_positions(SpotPosition {
                 market_index: 0,
                 balance_type: SpotBalanceType::Deposit,
-                scaled_balance: 1000 * SPOT_BALANCE_PRECISION_U64,
+                scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
                 ..SpotPosition::default()
             }),
             ..User::default()
@@ -3221,149 +3492,52 @@ pub mod liquidate_spot {
 
         let user_key = Pubkey::default();
         let liquidator_key = Pubkey::default();
-
         let state = State {
             liquidation_margin_buffer_ratio: MARGIN_PRECISION as u32 / 50,
-            initial_pct_to_liquidate: (LIQUIDATION_PCT_PRECISION / 10) as u16,
+            initial_pct_to_liquidate: LIQUIDATION_PCT_PRECISION as u16,
+            initial_liquidator_fee_pct: LIQUIDATION_PCT_PRECISION as u16,
             liquidation_duration: 150,
             ..Default::default()
         };
-
-        let liquidation_buffer = state.liquidation_margin_buffer_ratio;
-
-        liquidate_spot(
-            0,
-            1,
-            10 * 10_u128.pow(6),
-            None,
-            &mut user,
-            &user_key,
-            &mut liquidator,
-            &liquidator_key,
-            &market_map,
-            &spot_market_map,
-            &mut oracle_map,
-            now,
-            slot,
-            &state,
-        )
-        .unwrap();
-
-        assert_eq!(user.last_active_slot, 1);
-        assert_eq!(user.is_being_liquidated(), true);
-        assert_eq!(user.liquidation_margin_freed, 7000031);
-        assert_eq!(user.spot_positions[0].scaled_balance, 990558159000);
-        assert_eq!(user.spot_positions[1].scaled_balance, 9406768999);
-
-        let MarginCalculation {
-            total_collateral,
-            margin_requirement_plus_buffer,
-            ..
-        } = calculate_margin_requirement_and_total_collateral_and_liability_info(
-            &user,
-            &market_map,
-            &spot_market_map,
-            &mut oracle_map,
-            MarginContext::liquidation(liquidation_buffer),
-        )
-        .unwrap();
-
-        let margin_shortage =
-            ((margin_requirement_plus_buffer as i128) - total_collateral).unsigned_abs();
-
-        let pct_margin_freed = (user.liquidation_margin_freed as u128) * PRICE_PRECISION
-            / (margin_shortage + user.liquidation_margin_freed as u128);
-        assert_eq!(pct_margin_freed, 100000); // ~10%
-
-        let slot = 51_u64;
-        liquidate_spot(
+        let limit_price = (100000000 * PRICE_PRECISION_U64 / 999000) + 1;
+        let result = liquidate_spot(
             0,
             1,
-            10 * 10_u128.pow(6),
-            None,
+            10_u128.pow(6),
+            Some(limit_price),
             &mut user,
             &user_key,
             &mut liquidator,
             &liquidator_key,
-            &market_map,
+            &perp_market_map,
             &spot_market_map,
             &mut oracle_map,
             now,
             slot,
             &state,
-        )
-        .unwrap();
+        );
 
-        assert_eq!(user.last_active_slot, 1);
-        assert_eq!(user.liquidation_margin_freed, 30328714);
-        assert_eq!(user.spot_positions[0].scaled_balance, 792456458000);
-        assert_eq!(user.spot_positions[1].scaled_balance, 7429711998);
+        assert_eq!(result, Err(ErrorCode::LiquidationDoesntSatisfyLimitPrice));
+    }
 
-        let MarginCalculation {
-            total_collateral,
-            margin_requirement_plus_buffer,
-            ..
-        } = calculate_margin_requirement_and_total_collateral_and_liability_info(
-            &user,
-            &market_map,
-            &spot_market_map,
-            &mut oracle_map,
-            MarginContext::liquidation(liquidation_buffer),
-        )
-        .unwrap();
+    #[test]
+    pub fn success_with_to_limit_price() {
+        let now = 0_i64;
+        let slot = 0_u64;
 
-        let margin_shortage =
-            ((margin_requirement_plus_buffer as i128) - total_collateral).unsigned_abs();
+        let mut sol_oracle_price = get_pyth_price(100, 6);
+        let sol_oracle_price_key =
+            Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+        let pyth_program = crate::ids::pyth_program::id();
+        create_account_info!(
+            sol_oracle_price,
+            &sol_oracle_price_key,
+            &pyth_program,
+            oracle_account_info
+        );
+        let mut oracle_map = OracleMap::load_one(&oracle_account_info, slot, None).unwrap();
 
-        let pct_margin_freed = (user.liquidation_margin_freed as u128) * PRICE_PRECISION
-            / (margin_shortage + user.liquidation_margin_freed as u128);
-        assert_eq!(pct_margin_freed, 433267); // ~43.3%
-        assert_eq!(user.is_being_liquidated(), true);
-
-        let slot = 136_u64;
-        liquidate_spot(
-            0,
-            1,
-            10 * 10_u128.pow(6),
-            None,
-            &mut user,
-            &user_key,
-            &mut liquidator,
-            &liquidator_key,
-            &market_map,
-            &spot_market_map,
-            &mut oracle_map,
-            now,
-            slot,
-            &state,
-        )
-        .unwrap();
-
-        assert_eq!(user.last_active_slot, 1);
-        assert_eq!(user.liquidation_margin_freed, 0);
-        assert_eq!(user.spot_positions[0].scaled_balance, 455580082000);
-        assert_eq!(user.spot_positions[1].scaled_balance, 4067681997);
-        assert_eq!(user.is_being_liquidated(), false);
-    }
-
-    #[test]
-    pub fn successful_liquidation_half_if_fee() {
-        let now = 0_i64;
-        let slot = 0_u64;
-
-        let mut sol_oracle_price = get_pyth_price(100, 6);
-        let sol_oracle_price_key =
-            Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
-        let pyth_program = crate::ids::pyth_program::id();
-        create_account_info!(
-            sol_oracle_price,
-            &sol_oracle_price_key,
-            &pyth_program,
-            oracle_account_info
-        );
-        let mut oracle_map = OracleMap::load_one(&oracle_account_info, slot, None).unwrap();
-
-        let market_map = PerpMarketMap::empty();
+        let perp_market_map = PerpMarketMap::empty();
 
         let mut usdc_market = SpotMarket {
             market_index: 0,
@@ -3388,15 +3562,14 @@ pub mod liquidate_spot {
             oracle: sol_oracle_price_key,
             cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
             cumulative_borrow_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
-            decimals: 9,
+            decimals: 6,
             initial_asset_weight: 8 * SPOT_WEIGHT_PRECISION / 10,
             maintenance_asset_weight: 9 * SPOT_WEIGHT_PRECISION / 10,
             initial_liability_weight: 12 * SPOT_WEIGHT_PRECISION / 10,
             maintenance_liability_weight: 11 * SPOT_WEIGHT_PRECISION / 10,
             deposit_balance: SPOT_BALANCE_PRECISION,
             borrow_balance: SPOT_BALANCE_PRECISION,
-            liquidator_fee: LIQUIDATION_FEE_PRECISION / 100,
-            if_liquidation_fee: LIQUIDATION_FEE_PRECISION / 20,
+            liquidator_fee: LIQUIDATION_FEE_PRECISION / 1000,
             historical_oracle_data: HistoricalOracleData {
                 last_oracle_price_twap: (sol_oracle_price.agg.price * 99 / 100),
                 last_oracle_price_twap_5min: (sol_oracle_price.agg.price * 99 / 100),
@@ -3405,44 +3578,26 @@ pub mod liquidate_spot {
             ..SpotMarket::default()
         };
         create_anchor_account_info!(sol_market, SpotMarket, sol_spot_market_account_info);
-        let mut usdt_market = SpotMarket {
-            market_index: 2,
-            oracle_source: OracleSource::QuoteAsset,
-            cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
-            decimals: 6,
-            initial_asset_weight: 8 * SPOT_WEIGHT_PRECISION / 10,
-            maintenance_asset_weight: 9 * SPOT_WEIGHT_PRECISION / 10,
-            deposit_balance: 200 * SPOT_BALANCE_PRECISION,
-            liquidator_fee: LIQUIDATION_FEE_PRECISION / 100,
-            historical_oracle_data: HistoricalOracleData {
-                last_oracle_price_twap: QUOTE_PRECISION_I64,
-                last_oracle_price_twap_5min: QUOTE_PRECISION_I64,
-                ..HistoricalOracleData::default()
-            },
-            ..SpotMarket::default()
-        };
-        create_anchor_account_info!(usdt_market, SpotMarket, usdt_spot_market_account_info);
         let spot_market_account_infos = Vec::from([
             &usdc_spot_market_account_info,
             &sol_spot_market_account_info,
-            &usdt_spot_market_account_info,
         ]);
         let spot_market_map =
             SpotMarketMap::load_multiple(spot_market_account_infos, true).unwrap();
 
         let mut spot_positions = [SpotPosition::default(); 8];
+        spot_positions[0] = SpotPosition {
+            market_index: 0,
+            balance_type: SpotBalanceType::Deposit,
+            scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
+            ..SpotPosition::default()
+        };
         spot_positions[1] = SpotPosition {
             market_index: 1,
             balance_type: SpotBalanceType::Borrow,
             scaled_balance: SPOT_BALANCE_PRECISION_U64,
             ..SpotPosition::default()
         };
-        spot_positions[2] = SpotPosition {
-            market_index: 2,
-            balance_type: SpotBalanceType::Deposit,
-            scaled_balance: 105 * SPOT_BALANCE_PRECISION_U64,
-            ..SpotPosition::default()
-        };
         let mut user = User {
             orders: [Order::default(); 32],
             perp_positions: [PerpPosition::default(); 8],
@@ -3462,91 +3617,36 @@ pub mod liquidate_spot {
 
         let user_key = Pubkey::default();
         let liquidator_key = Pubkey::default();
-
-        let liquidation_buffer = MARGIN_PRECISION as u32 / 50;
         let state = State {
-            liquidation_margin_buffer_ratio: liquidation_buffer,
+            liquidation_margin_buffer_ratio: MARGIN_PRECISION as u32 / 50,
             initial_pct_to_liquidate: LIQUIDATION_PCT_PRECISION as u16,
+            initial_liquidator_fee_pct: LIQUIDATION_PCT_PRECISION as u16,
             liquidation_duration: 150,
             ..Default::default()
         };
-
-        liquidate_spot(
-            2,
+        let limit_price = (100000000 * PRICE_PRECISION_U64 / 999000) - 1;
+        let result = liquidate_spot(
+            0,
             1,
-            10_u128.pow(9),
-            None,
+            10_u128.pow(6),
+            Some(limit_price),
             &mut user,
             &user_key,
             &mut liquidator,
             &liquidator_key,
-            &market_map,
+            &perp_market_map,
             &spot_market_map,
             &mut oracle_map,
             now,
             slot,
             &state,
-        )
-        .unwrap();
-
-        let liability_market = spot_market_map.get_ref(&1).unwrap();
-        let revenue_pool_token_amount = get_token_amount(
-            liability_market.revenue_pool.scaled_balance,
-            &liability_market,
-            &SpotBalanceType::Deposit,
-        )
-        .unwrap();
-
-        assert_eq!(revenue_pool_token_amount, 23944781); // 2.39%
-
-        let margin_calc = calculate_margin_requirement_and_total_collateral_and_liability_info(
-            &user,
-            &market_map,
-            &spot_market_map,
-            &mut oracle_map,
-            MarginContext::liquidation(liquidation_buffer),
-        )
-        .unwrap();
+        );
 
-        print!("{:?}", margin_calc);
-        assert!(margin_calc.meets_margin_requirement());
+        assert_eq!(result, Ok(()));
     }
-}
-
-pub mod liquidate_borrow_for_perp_pnl {
-    use std::ops::Deref;
-    use std::str::FromStr;
-
-    use anchor_lang::Owner;
-    use solana_program::pubkey::Pubkey;
-
-    use crate::controller::liquidation::liquidate_borrow_for_perp_pnl;
-    use crate::create_account_info;
-    use crate::create_anchor_account_info;
-    use crate::error::ErrorCode;
-    use crate::math::constants::{
-        AMM_RESERVE_PRECISION, BASE_PRECISION_I128, LIQUIDATION_FEE_PRECISION,
-        LIQUIDATION_PCT_PRECISION, MARGIN_PRECISION, MARGIN_PRECISION_U128, PEG_PRECISION,
-        PERCENTAGE_PRECISION, PRICE_PRECISION, PRICE_PRECISION_U64, QUOTE_PRECISION_I128,
-        QUOTE_PRECISION_I64, SPOT_BALANCE_PRECISION, SPOT_BALANCE_PRECISION_U64,
-        SPOT_CUMULATIVE_INTEREST_PRECISION, SPOT_WEIGHT_PRECISION,
-    };
-    use crate::math::margin::calculate_margin_requirement_and_total_collateral_and_liability_info;
-    use crate::math::spot_balance::{get_token_amount, get_token_value};
-    use crate::state::margin_calculation::{MarginCalculation, MarginContext};
-    use crate::state::oracle::HistoricalOracleData;
-    use crate::state::oracle::OracleSource;
-    use crate::state::oracle_map::OracleMap;
-    use crate::state::perp_market::{MarketStatus, PerpMarket, AMM};
-    use crate::state::perp_market_map::PerpMarketMap;
-    use crate::state::spot_market::{SpotBalanceType, SpotMarket};
-    use crate::state::spot_market_map::SpotMarketMap;
-    use crate::state::user::{Order, PerpPosition, SpotPosition, User};
-    use crate::test_utils::*;
-    use crate::test_utils::{get_positions, get_pyth_price, get_spot_positions};
 
     #[test]
-    pub fn successful_liquidation_liquidator_max_liability_transfer() {
+    pub fn successful_liquidation_dust_borrow() {
         let now = 0_i64;
         let slot = 0_u64;
 
@@ -3562,35 +3662,7 @@ pub mod liquidate_borrow_for_perp_pnl {
         );
         let mut oracle_map = OracleMap::load_one(&oracle_account_info, slot, None).unwrap();
 
-        let mut market = PerpMarket {
-            amm: AMM {
-                base_asset_reserve: 100 * AMM_RESERVE_PRECISION,
-                quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
-                bid_base_asset_reserve: 101 * AMM_RESERVE_PRECISION,
-                bid_quote_asset_reserve: 99 * AMM_RESERVE_PRECISION,
-                ask_base_asset_reserve: 99 * AMM_RESERVE_PRECISION,
-                ask_quote_asset_reserve: 101 * AMM_RESERVE_PRECISION,
-                sqrt_k: 100 * AMM_RESERVE_PRECISION,
-                peg_multiplier: 100 * PEG_PRECISION,
-                max_slippage_ratio: 50,
-                max_fill_reserve_fraction: 100,
-                order_step_size: 10000000,
-                quote_asset_amount: 150 * QUOTE_PRECISION_I128,
-                base_asset_amount_with_amm: BASE_PRECISION_I128,
-                oracle: sol_oracle_price_key,
-                ..AMM::default()
-            },
-            margin_ratio_initial: 1000,
-            margin_ratio_maintenance: 500,
-            unrealized_pnl_initial_asset_weight: 9000,
-            unrealized_pnl_maintenance_asset_weight: 10000,
-            number_of_users_with_base: 1,
-            status: MarketStatus::Initialized,
-            liquidator_fee: LIQUIDATION_FEE_PRECISION / 100,
-            ..PerpMarket::default()
-        };
-        create_anchor_account_info!(market, PerpMarket, market_account_info);
-        let market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+        let market_map = PerpMarketMap::empty();
 
         let mut usdc_market = SpotMarket {
             market_index: 0,
@@ -3623,6 +3695,7 @@ pub mod liquidate_borrow_for_perp_pnl {
             deposit_balance: SPOT_BALANCE_PRECISION,
             borrow_balance: SPOT_BALANCE_PRECISION,
             liquidator_fee: LIQUIDATION_FEE_PRECISION / 1000,
+            if_liquidation_fee: LIQUIDATION_FEE_PRECISION / 1000,
             historical_oracle_data: HistoricalOracleData {
                 last_oracle_price_twap: (sol_oracle_price.agg.price * 99 / 100),
                 last_oracle_price_twap_5min: (sol_oracle_price.agg.price * 99 / 100),
@@ -3640,18 +3713,20 @@ pub mod liquidate_borrow_for_perp_pnl {
 
         let mut spot_positions = [SpotPosition::default(); 8];
         spot_positions[0] = SpotPosition {
+            market_index: 0,
+            balance_type: SpotBalanceType::Deposit,
+            scaled_balance: 107 * SPOT_BALANCE_PRECISION_U64 / 50,
+            ..SpotPosition::default()
+        };
+        spot_positions[1] = SpotPosition {
             market_index: 1,
             balance_type: SpotBalanceType::Borrow,
-            scaled_balance: SPOT_BALANCE_PRECISION_U64,
+            scaled_balance: SPOT_BALANCE_PRECISION_U64 / 50,
             ..SpotPosition::default()
         };
         let mut user = User {
             orders: [Order::default(); 32],
-            perp_positions: get_positions(PerpPosition {
-                market_index: 0,
-                quote_asset_amount: 100 * QUOTE_PRECISION_I64,
-                ..PerpPosition::default()
-            }),
+            perp_positions: [PerpPosition::default(); 8],
             spot_positions,
             ..User::default()
         };
@@ -3669,10 +3744,17 @@ pub mod liquidate_borrow_for_perp_pnl {
         let user_key = Pubkey::default();
         let liquidator_key = Pubkey::default();
 
-        liquidate_borrow_for_perp_pnl(
+        let state = State {
+            liquidation_margin_buffer_ratio: MARGIN_PRECISION as u32 / 50,
+            initial_pct_to_liquidate: LIQUIDATION_PCT_PRECISION as u16,
+            initial_liquidator_fee_pct: LIQUIDATION_PCT_PRECISION as u16,
+            liquidation_duration: 150,
+            ..Default::default()
+        };
+        liquidate_spot(
             0,
             1,
-            8 * 10_u128.pow(5), // .8
+            10_u128.pow(6),
             None,
             &mut user,
             &user_key,
@@ -3683,27 +3765,21 @@ pub mod liquidate_borrow_for_perp_pnl {
             &mut oracle_map,
             now,
             slot,
-            10,
-            PERCENTAGE_PRECISION,
-            150,
+            &state,
         )
         .unwrap();
 
-        assert_eq!(user.spot_positions[0].scaled_balance, 199999999);
-        assert_eq!(user.perp_positions[0].quote_asset_amount, 19119120);
+        assert_eq!(user.spot_positions[0].scaled_balance, 0);
+        assert_eq!(user.spot_positions[1].scaled_balance, 19999);
 
-        assert_eq!(
-            liquidator.spot_positions[1].balance_type,
-            SpotBalanceType::Borrow
-        );
-        assert_eq!(liquidator.spot_positions[1].scaled_balance, 800000001);
-        assert_eq!(liquidator.perp_positions[0].quote_asset_amount, 80880880);
+        assert_eq!(liquidator.spot_positions[0].scaled_balance, 102140000000);
+        assert_eq!(liquidator.spot_positions[1].scaled_balance, 20000001); // ~$1 worth of liability
     }
 
     #[test]
-    pub fn successful_liquidation_liability_transfer_to_cover_margin_shortage() {
-        let now = 0_i64;
-        let slot = 0_u64;
+    pub fn liquidate_over_multiple_slots() {
+        let now = 1_i64;
+        let slot = 1_u64;
 
         let mut sol_oracle_price = get_pyth_price(100, 6);
         let sol_oracle_price_key =
@@ -3717,35 +3793,7 @@ pub mod liquidate_borrow_for_perp_pnl {
         );
         let mut oracle_map = OracleMap::load_one(&oracle_account_info, slot, None).unwrap();
 
-        let mut market = PerpMarket {
-            amm: AMM {
-                base_asset_reserve: 100 * AMM_RESERVE_PRECISION,
-                quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
-                bid_base_asset_reserve: 101 * AMM_RESERVE_PRECISION,
-                bid_quote_asset_reserve: 99 * AMM_RESERVE_PRECISION,
-                ask_base_asset_reserve: 99 * AMM_RESERVE_PRECISION,
-                ask_quote_asset_reserve: 101 * AMM_RESERVE_PRECISION,
-                sqrt_k: 100 * AMM_RESERVE_PRECISION,
-                peg_multiplier: 100 * PEG_PRECISION,
-                max_slippage_ratio: 50,
-                max_fill_reserve_fraction: 100,
-                order_step_size: 10000000,
-                quote_asset_amount: 150 * QUOTE_PRECISION_I128,
-                base_asset_amount_with_amm: BASE_PRECISION_I128,
-                oracle: sol_oracle_price_key,
-                ..AMM::default()
-            },
-            margin_ratio_initial: 1000,
-            margin_ratio_maintenance: 500,
-            unrealized_pnl_initial_asset_weight: 9000,
-            unrealized_pnl_maintenance_asset_weight: 10000,
-            number_of_users_with_base: 1,
-            status: MarketStatus::Initialized,
-            liquidator_fee: LIQUIDATION_FEE_PRECISION / 100,
-            ..PerpMarket::default()
-        };
-        create_anchor_account_info!(market, PerpMarket, market_account_info);
-        let market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+        let market_map = PerpMarketMap::empty();
 
         let mut usdc_market = SpotMarket {
             market_index: 0,
@@ -3775,8 +3823,8 @@ pub mod liquidate_borrow_for_perp_pnl {
             maintenance_asset_weight: 9 * SPOT_WEIGHT_PRECISION / 10,
             initial_liability_weight: 12 * SPOT_WEIGHT_PRECISION / 10,
             maintenance_liability_weight: 11 * SPOT_WEIGHT_PRECISION / 10,
-            deposit_balance: SPOT_BALANCE_PRECISION,
-            borrow_balance: SPOT_BALANCE_PRECISION,
+            deposit_balance: 10 * SPOT_BALANCE_PRECISION,
+            borrow_balance: 10 * SPOT_BALANCE_PRECISION,
             liquidator_fee: LIQUIDATION_FEE_PRECISION / 1000,
             if_liquidation_fee: LIQUIDATION_FEE_PRECISION / 1000,
             historical_oracle_data: HistoricalOracleData {
@@ -3796,18 +3844,20 @@ pub mod liquidate_borrow_for_perp_pnl {
 
         let mut spot_positions = [SpotPosition::default(); 8];
         spot_positions[0] = SpotPosition {
+            market_index: 0,
+            balance_type: SpotBalanceType::Deposit,
+            scaled_balance: 1050 * SPOT_BALANCE_PRECISION_U64,
+            ..SpotPosition::default()
+        };
+        spot_positions[1] = SpotPosition {
             market_index: 1,
             balance_type: SpotBalanceType::Borrow,
-            scaled_balance: SPOT_BALANCE_PRECISION_U64,
+            scaled_balance: 10 * SPOT_BALANCE_PRECISION_U64,
             ..SpotPosition::default()
         };
         let mut user = User {
             orders: [Order::default(); 32],
-            perp_positions: get_positions(PerpPosition {
-                market_index: 0,
-                quote_asset_amount: 105 * QUOTE_PRECISION_I64,
-                ..PerpPosition::default()
-            }),
+            perp_positions: [PerpPosition::default(); 8],
             spot_positions,
             ..User::default()
         };
@@ -3816,7 +3866,7 @@ pub mod liquidate_borrow_for_perp_pnl {
             spot_positions: get_spot_positions(SpotPosition {
                 market_index: 0,
                 balance_type: SpotBalanceType::Deposit,
-                scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
+                scaled_balance: 1000 * SPOT_BALANCE_PRECISION_U64,
                 ..SpotPosition::default()
             }),
             ..User::default()
@@ -3825,11 +3875,20 @@ pub mod liquidate_borrow_for_perp_pnl {
         let user_key = Pubkey::default();
         let liquidator_key = Pubkey::default();
 
-        let liquidation_buffer = MARGIN_PRECISION as u32 / 50;
-        liquidate_borrow_for_perp_pnl(
+        let state = State {
+            liquidation_margin_buffer_ratio: MARGIN_PRECISION as u32 / 50,
+            initial_pct_to_liquidate: (LIQUIDATION_PCT_PRECISION / 10) as u16,
+            initial_liquidator_fee_pct: LIQUIDATION_PCT_PRECISION as u16,
+            liquidation_duration: 150,
+            ..Default::default()
+        };
+
+        let liquidation_buffer = state.liquidation_margin_buffer_ratio;
+
+        liquidate_spot(
             0,
             1,
-            2 * 10_u128.pow(6),
+            10 * 10_u128.pow(6),
             None,
             &mut user,
             &user_key,
@@ -3840,14 +3899,15 @@ pub mod liquidate_borrow_for_perp_pnl {
             &mut oracle_map,
             now,
             slot,
-            liquidation_buffer,
-            PERCENTAGE_PRECISION,
-            150,
+            &state,
         )
         .unwrap();
 
-        assert_eq!(user.spot_positions[0].scaled_balance, 357739999);
-        assert_eq!(user.perp_positions[0].quote_asset_amount, 40066807);
+        assert_eq!(user.last_active_slot, 1);
+        assert_eq!(user.is_being_liquidated(), true);
+        assert_eq!(user.liquidation_margin_freed, 7000031);
+        assert_eq!(user.spot_positions[0].scaled_balance, 990558159000);
+        assert_eq!(user.spot_positions[1].scaled_balance, 9406768999);
 
         let MarginCalculation {
             total_collateral,
@@ -3862,44 +3922,86 @@ pub mod liquidate_borrow_for_perp_pnl {
         )
         .unwrap();
 
-        assert_eq!(total_collateral, 40066807);
-        assert_eq!(margin_requirement_plus_buffer, 40066880);
+        let margin_shortage =
+            ((margin_requirement_plus_buffer as i128) - total_collateral).unsigned_abs();
 
-        let token_amount = get_token_amount(
-            user.spot_positions[0].scaled_balance as u128,
-            spot_market_map.get_ref(&1).unwrap().deref(),
-            &user.spot_positions[0].balance_type,
+        let pct_margin_freed = (user.liquidation_margin_freed as u128) * PRICE_PRECISION
+            / (margin_shortage + user.liquidation_margin_freed as u128);
+        assert_eq!(pct_margin_freed, 100000); // ~10%
+
+        let slot = 51_u64;
+        liquidate_spot(
+            0,
+            1,
+            10 * 10_u128.pow(6),
+            None,
+            &mut user,
+            &user_key,
+            &mut liquidator,
+            &liquidator_key,
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            now,
+            slot,
+            &state,
         )
         .unwrap();
-        let oracle_price_data = oracle_map.get_price_data(&sol_oracle_price_key).unwrap();
-        let token_value =
-            get_token_value(token_amount as i128, 6, oracle_price_data.price).unwrap();
 
-        let margin_ratio =
-            total_collateral.unsigned_abs() * MARGIN_PRECISION_U128 / token_value.unsigned_abs();
+        assert_eq!(user.last_active_slot, 1);
+        assert_eq!(user.liquidation_margin_freed, 30328714);
+        assert_eq!(user.spot_positions[0].scaled_balance, 792456458000);
+        assert_eq!(user.spot_positions[1].scaled_balance, 7429711998);
 
-        assert_eq!(margin_ratio, 11199); // ~112%
+        let MarginCalculation {
+            total_collateral,
+            margin_requirement_plus_buffer,
+            ..
+        } = calculate_margin_requirement_and_total_collateral_and_liability_info(
+            &user,
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            MarginContext::liquidation(liquidation_buffer),
+        )
+        .unwrap();
 
-        assert_eq!(
-            liquidator.spot_positions[1].balance_type,
-            SpotBalanceType::Borrow
-        );
-        assert_eq!(liquidator.spot_positions[1].scaled_balance, 642260001);
-        assert_eq!(liquidator.perp_positions[0].quote_asset_amount, 64933193);
+        let margin_shortage =
+            ((margin_requirement_plus_buffer as i128) - total_collateral).unsigned_abs();
 
-        let market_after = spot_market_map.get_ref(&1).unwrap();
-        let market_revenue = get_token_amount(
-            market_after.revenue_pool.scaled_balance,
-            &market_after,
-            &SpotBalanceType::Deposit,
+        let pct_margin_freed = (user.liquidation_margin_freed as u128) * PRICE_PRECISION
+            / (margin_shortage + user.liquidation_margin_freed as u128);
+        assert_eq!(pct_margin_freed, 433267); // ~43.3%
+        assert_eq!(user.is_being_liquidated(), true);
+
+        let slot = 136_u64;
+        liquidate_spot(
+            0,
+            1,
+            10 * 10_u128.pow(6),
+            None,
+            &mut user,
+            &user_key,
+            &mut liquidator,
+            &liquidator_key,
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            now,
+            slot,
+            &state,
         )
         .unwrap();
 
-        assert_eq!(market_revenue, 0);
+        assert_eq!(user.last_active_slot, 1);
+        assert_eq!(user.liquidation_margin_freed, 0);
+        assert_eq!(user.spot_positions[0].scaled_balance, 455580082000);
+        assert_eq!(user.spot_positions[1].scaled_balance, 4067681997);
+        assert_eq!(user.is_being_liquidated(), false);
     }
 
     #[test]
-    pub fn successful_liquidation_liability_transfer_implied_by_pnl() {
+    pub fn successful_liquidation_half_if_fee() {
         let now = 0_i64;
         let slot = 0_u64;
 
@@ -3915,34 +4017,7 @@ pub mod liquidate_borrow_for_perp_pnl {
         );
         let mut oracle_map = OracleMap::load_one(&oracle_account_info, slot, None).unwrap();
 
-        let mut market = PerpMarket {
-            amm: AMM {
-                base_asset_reserve: 100 * AMM_RESERVE_PRECISION,
-                quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
-                bid_base_asset_reserve: 101 * AMM_RESERVE_PRECISION,
-                bid_quote_asset_reserve: 99 * AMM_RESERVE_PRECISION,
-                ask_base_asset_reserve: 99 * AMM_RESERVE_PRECISION,
-                ask_quote_asset_reserve: 101 * AMM_RESERVE_PRECISION,
-                sqrt_k: 100 * AMM_RESERVE_PRECISION,
-                peg_multiplier: 100 * PEG_PRECISION,
-                max_slippage_ratio: 50,
-                max_fill_reserve_fraction: 100,
-                order_step_size: 10000000,
-                quote_asset_amount: 150 * QUOTE_PRECISION_I128,
-                base_asset_amount_with_amm: BASE_PRECISION_I128,
-                oracle: sol_oracle_price_key,
-                ..AMM::default()
-            },
-            margin_ratio_initial: 1000,
-            unrealized_pnl_initial_asset_weight: 9000,
-            unrealized_pnl_maintenance_asset_weight: 10000,
-            number_of_users_with_base: 1,
-            status: MarketStatus::Initialized,
-            liquidator_fee: LIQUIDATION_FEE_PRECISION / 100,
-            ..PerpMarket::default()
-        };
-        create_anchor_account_info!(market, PerpMarket, market_account_info);
-        let market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+        let market_map = PerpMarketMap::empty();
 
         let mut usdc_market = SpotMarket {
             market_index: 0,
@@ -3967,14 +4042,15 @@ pub mod liquidate_borrow_for_perp_pnl {
             oracle: sol_oracle_price_key,
             cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
             cumulative_borrow_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
-            decimals: 6,
+            decimals: 9,
             initial_asset_weight: 8 * SPOT_WEIGHT_PRECISION / 10,
             maintenance_asset_weight: 9 * SPOT_WEIGHT_PRECISION / 10,
             initial_liability_weight: 12 * SPOT_WEIGHT_PRECISION / 10,
             maintenance_liability_weight: 11 * SPOT_WEIGHT_PRECISION / 10,
             deposit_balance: SPOT_BALANCE_PRECISION,
             borrow_balance: SPOT_BALANCE_PRECISION,
-            liquidator_fee: LIQUIDATION_FEE_PRECISION / 1000,
+            liquidator_fee: LIQUIDATION_FEE_PRECISION / 100,
+            if_liquidation_fee: LIQUIDATION_FEE_PRECISION / 20,
             historical_oracle_data: HistoricalOracleData {
                 last_oracle_price_twap: (sol_oracle_price.agg.price * 99 / 100),
                 last_oracle_price_twap_5min: (sol_oracle_price.agg.price * 99 / 100),
@@ -3983,27 +4059,47 @@ pub mod liquidate_borrow_for_perp_pnl {
             ..SpotMarket::default()
         };
         create_anchor_account_info!(sol_market, SpotMarket, sol_spot_market_account_info);
+        let mut usdt_market = SpotMarket {
+            market_index: 2,
+            oracle_source: OracleSource::QuoteAsset,
+            cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            decimals: 6,
+            initial_asset_weight: 8 * SPOT_WEIGHT_PRECISION / 10,
+            maintenance_asset_weight: 9 * SPOT_WEIGHT_PRECISION / 10,
+            deposit_balance: 200 * SPOT_BALANCE_PRECISION,
+            liquidator_fee: LIQUIDATION_FEE_PRECISION / 100,
+            historical_oracle_data: HistoricalOracleData {
+                last_oracle_price_twap: QUOTE_PRECISION_I64,
+                last_oracle_price_twap_5min: QUOTE_PRECISION_I64,
+                ..HistoricalOracleData::default()
+            },
+            ..SpotMarket::default()
+        };
+        create_anchor_account_info!(usdt_market, SpotMarket, usdt_spot_market_account_info);
         let spot_market_account_infos = Vec::from([
             &usdc_spot_market_account_info,
             &sol_spot_market_account_info,
+            &usdt_spot_market_account_info,
         ]);
         let spot_market_map =
             SpotMarketMap::load_multiple(spot_market_account_infos, true).unwrap();
 
         let mut spot_positions = [SpotPosition::default(); 8];
-        spot_positions[0] = SpotPosition {
+        spot_positions[1] = SpotPosition {
             market_index: 1,
             balance_type: SpotBalanceType::Borrow,
             scaled_balance: SPOT_BALANCE_PRECISION_U64,
             ..SpotPosition::default()
         };
+        spot_positions[2] = SpotPosition {
+            market_index: 2,
+            balance_type: SpotBalanceType::Deposit,
+            scaled_balance: 105 * SPOT_BALANCE_PRECISION_U64,
+            ..SpotPosition::default()
+        };
         let mut user = User {
             orders: [Order::default(); 32],
-            perp_positions: get_positions(PerpPosition {
-                market_index: 0,
-                quote_asset_amount: 80 * QUOTE_PRECISION_I64,
-                ..PerpPosition::default()
-            }),
+            perp_positions: [PerpPosition::default(); 8],
             spot_positions,
             ..User::default()
         };
@@ -4021,10 +4117,19 @@ pub mod liquidate_borrow_for_perp_pnl {
         let user_key = Pubkey::default();
         let liquidator_key = Pubkey::default();
 
-        liquidate_borrow_for_perp_pnl(
-            0,
+        let liquidation_buffer = MARGIN_PRECISION as u32 / 50;
+        let state = State {
+            liquidation_margin_buffer_ratio: liquidation_buffer,
+            initial_pct_to_liquidate: LIQUIDATION_PCT_PRECISION as u16,
+            initial_liquidator_fee_pct: LIQUIDATION_PCT_PRECISION as u16,
+            liquidation_duration: 150,
+            ..Default::default()
+        };
+
+        liquidate_spot(
+            2,
             1,
-            2 * 10_u128.pow(6),
+            10_u128.pow(9),
             None,
             &mut user,
             &user_key,
@@ -4035,29 +4140,72 @@ pub mod liquidate_borrow_for_perp_pnl {
             &mut oracle_map,
             now,
             slot,
-            10,
-            PERCENTAGE_PRECISION,
-            150,
+            &state,
         )
         .unwrap();
 
-        assert_eq!(user.spot_positions[0].scaled_balance, 208711999);
-        assert_eq!(user.perp_positions[0].quote_asset_amount, 0);
+        let liability_market = spot_market_map.get_ref(&1).unwrap();
+        let revenue_pool_token_amount = get_token_amount(
+            liability_market.revenue_pool.scaled_balance,
+            &liability_market,
+            &SpotBalanceType::Deposit,
+        )
+        .unwrap();
 
-        assert_eq!(
-            liquidator.spot_positions[1].balance_type,
-            SpotBalanceType::Borrow
-        );
-        assert_eq!(liquidator.spot_positions[1].scaled_balance, 791288001);
-        assert_eq!(liquidator.perp_positions[0].quote_asset_amount, 80000000);
-    }
+        assert_eq!(revenue_pool_token_amount, 23944781); // 2.39%
 
-    #[test]
-    pub fn failure_due_to_limit_price() {
-        let now = 0_i64;
-        let slot = 0_u64;
+        let margin_calc = calculate_margin_requirement_and_total_collateral_and_liability_info(
+            &user,
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            MarginContext::liquidation(liquidation_buffer),
+        )
+        .unwrap();
 
-        let mut sol_oracle_price = get_pyth_price(100, 6);
+        print!("{:?}", margin_calc);
+        assert!(margin_calc.meets_margin_requirement());
+    }
+}
+
+pub mod liquidate_borrow_for_perp_pnl {
+    use std::ops::Deref;
+    use std::str::FromStr;
+
+    use anchor_lang::Owner;
+    use solana_program::pubkey::Pubkey;
+
+    use crate::controller::liquidation::liquidate_borrow_for_perp_pnl;
+    use crate::create_account_info;
+    use crate::create_anchor_account_info;
+    use crate::error::ErrorCode;
+    use crate::math::constants::{
+        AMM_RESERVE_PRECISION, BASE_PRECISION_I128, LIQUIDATION_FEE_PRECISION,
+        LIQUIDATION_PCT_PRECISION, MARGIN_PRECISION, MARGIN_PRECISION_U128, PEG_PRECISION,
+        PERCENTAGE_PRECISION, PRICE_PRECISION, PRICE_PRECISION_U64, QUOTE_PRECISION_I128,
+        QUOTE_PRECISION_I64, SPOT_BALANCE_PRECISION, SPOT_BALANCE_PRECISION_U64,
+        SPOT_CUMULATIVE_INTEREST_PRECISION, SPOT_WEIGHT_PRECISION,
+    };
+    use crate::math::margin::calculate_margin_requirement_and_total_collateral_and_liability_info;
+    use crate::math::spot_balance::{get_token_amount, get_token_value};
+    use crate::state::margin_calculation::{MarginCalculation, MarginContext};
+    use crate::state::oracle::HistoricalOracleData;
+    use crate::state::oracle::OracleSource;
+    use crate::state::oracle_map::OracleMap;
+    use crate::state::perp_market::{MarketStatus, PerpMarket, AMM};
+    use crate::state::perp_market_map::PerpMarketMap;
+    use crate::state::spot_market::{SpotBalanceType, SpotMarket};
+    use crate::state::spot_market_map::SpotMarketMap;
+    use crate::state::user::{Order, PerpPosition, SpotPosition, User, UserStatus};
+    use crate::test_utils::*;
+    use crate::test_utils::{get_positions, get_pyth_price, get_spot_positions};
+
+    #[test]
+    pub fn liquidator_fee_ramps_over_liquidation_duration() {
+        let now = 0_i64;
+        let slot = 0_u64;
+
+        let mut sol_oracle_price = get_pyth_price(100, 6);
         let sol_oracle_price_key =
             Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
         let pyth_program = crate::ids::pyth_program::id();
@@ -4176,12 +4324,43 @@ pub mod liquidate_borrow_for_perp_pnl {
         let user_key = Pubkey::default();
         let liquidator_key = Pubkey::default();
 
-        let limit_price = (80880880 * PRICE_PRECISION_U64 / 800000) + 1;
-        let result = liquidate_borrow_for_perp_pnl(
+        // auction starts at a 0% fee when the user enters liquidation
+        let mut user_at_start = user;
+        let mut liquidator_at_start = liquidator;
+
+        liquidate_borrow_for_perp_pnl(
             0,
             1,
             8 * 10_u128.pow(5), // .8
-            Some(limit_price),
+            None,
+            &mut user_at_start,
+            &user_key,
+            &mut liquidator_at_start,
+            &liquidator_key,
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            now,
+            slot,
+            10,
+            PERCENTAGE_PRECISION,
+            0,
+            150,
+        )
+        .unwrap();
+
+        assert!(user_at_start.perp_positions[0].quote_asset_amount > 19119120);
+        assert!(liquidator_at_start.perp_positions[0].quote_asset_amount < 80880880);
+
+        // full fee once the liquidation duration has passed
+        user.status = UserStatus::BeingLiquidated as u8;
+        user.last_active_slot = slot;
+
+        liquidate_borrow_for_perp_pnl(
+            0,
+            1,
+            8 * 10_u128.pow(5), // .8
+            None,
             &mut user,
             &user_key,
             &mut liquidator,
@@ -4190,17 +4369,20 @@ pub mod liquidate_borrow_for_perp_pnl {
             &spot_market_map,
             &mut oracle_map,
             now,
-            slot,
+            slot + 150,
             10,
             PERCENTAGE_PRECISION,
+            0,
             150,
-        );
+        )
+        .unwrap();
 
-        assert_eq!(result, Err(ErrorCode::LiquidationDoesntSatisfyLimitPrice));
+        assert_eq!(user.perp_positions[0].quote_asset_amount, 19119120);
+        assert_eq!(liquidator.perp_positions[0].quote_asset_amount, 80880880);
     }
 
     #[test]
-    pub fn success_with_limit_price() {
+    pub fn successful_liquidation_liquidator_max_liability_transfer() {
         let now = 0_i64;
         let slot = 0_u64;
 
@@ -4323,12 +4505,11 @@ pub mod liquidate_borrow_for_perp_pnl {
         let user_key = Pubkey::default();
         let liquidator_key = Pubkey::default();
 
-        let limit_price = (80880880 * PRICE_PRECISION_U64 / 800000) - 1;
-        let result = liquidate_borrow_for_perp_pnl(
+        liquidate_borrow_for_perp_pnl(
             0,
             1,
             8 * 10_u128.pow(5), // .8
-            Some(limit_price),
+            None,
             &mut user,
             &user_key,
             &mut liquidator,
@@ -4340,14 +4521,24 @@ pub mod liquidate_borrow_for_perp_pnl {
             slot,
             10,
             PERCENTAGE_PRECISION,
+            LIQUIDATION_PCT_PRECISION,
             150,
-        );
+        )
+        .unwrap();
 
-        assert_eq!(result, Ok(()));
+        assert_eq!(user.spot_positions[0].scaled_balance, 199999999);
+        assert_eq!(user.perp_positions[0].quote_asset_amount, 19119120);
+
+        assert_eq!(
+            liquidator.spot_positions[1].balance_type,
+            SpotBalanceType::Borrow
+        );
+        assert_eq!(liquidator.spot_positions[1].scaled_balance, 800000001);
+        assert_eq!(liquidator.perp_positions[0].quote_asset_amount, 80880880);
     }
 
     #[test]
-    pub fn successful_liquidation_dust_position() {
+    pub fn successful_liquidation_liability_transfer_to_cover_margin_shortage() {
         let now = 0_i64;
         let slot = 0_u64;
 
@@ -4422,7 +4613,7 @@ pub mod liquidate_borrow_for_perp_pnl {
             initial_liability_weight: 12 * SPOT_WEIGHT_PRECISION / 10,
             maintenance_liability_weight: 11 * SPOT_WEIGHT_PRECISION / 10,
             deposit_balance: SPOT_BALANCE_PRECISION,
-            borrow_balance: SPOT_BALANCE_PRECISION / 50,
+            borrow_balance: SPOT_BALANCE_PRECISION,
             liquidator_fee: LIQUIDATION_FEE_PRECISION / 1000,
             if_liquidation_fee: LIQUIDATION_FEE_PRECISION / 1000,
             historical_oracle_data: HistoricalOracleData {
@@ -4444,14 +4635,14 @@ pub mod liquidate_borrow_for_perp_pnl {
         spot_positions[0] = SpotPosition {
             market_index: 1,
             balance_type: SpotBalanceType::Borrow,
-            scaled_balance: SPOT_BALANCE_PRECISION_U64 / 50,
+            scaled_balance: SPOT_BALANCE_PRECISION_U64,
             ..SpotPosition::default()
         };
         let mut user = User {
             orders: [Order::default(); 32],
             perp_positions: get_positions(PerpPosition {
                 market_index: 0,
-                quote_asset_amount: 107 * QUOTE_PRECISION_I64 / 50,
+                quote_asset_amount: 105 * QUOTE_PRECISION_I64,
                 ..PerpPosition::default()
             }),
             spot_positions,
@@ -4488,25 +4679,67 @@ pub mod liquidate_borrow_for_perp_pnl {
             slot,
             liquidation_buffer,
             PERCENTAGE_PRECISION,
+            LIQUIDATION_PCT_PRECISION,
             150,
         )
         .unwrap();
 
-        assert_eq!(user.spot_positions[0].scaled_balance, 0);
-        assert_eq!(user.perp_positions[0].quote_asset_amount, 0);
+        assert_eq!(user.spot_positions[0].scaled_balance, 357739999);
+        assert_eq!(user.perp_positions[0].quote_asset_amount, 40066807);
+
+        let MarginCalculation {
+            total_collateral,
+            margin_requirement_plus_buffer,
+            ..
+        } = calculate_margin_requirement_and_total_collateral_and_liability_info(
+            &user,
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            MarginContext::liquidation(liquidation_buffer),
+        )
+        .unwrap();
+
+        assert_eq!(total_collateral, 40066807);
+        assert_eq!(margin_requirement_plus_buffer, 40066880);
+
+        let token_amount = get_token_amount(
+            user.spot_positions[0].scaled_balance as u128,
+            spot_market_map.get_ref(&1).unwrap().deref(),
+            &user.spot_positions[0].balance_type,
+        )
+        .unwrap();
+        let oracle_price_data = oracle_map.get_price_data(&sol_oracle_price_key).unwrap();
+        let token_value =
+            get_token_value(token_amount as i128, 6, oracle_price_data.price).unwrap();
+
+        let margin_ratio =
+            total_collateral.unsigned_abs() * MARGIN_PRECISION_U128 / token_value.unsigned_abs();
+
+        assert_eq!(margin_ratio, 11199); // ~112%
 
         assert_eq!(
             liquidator.spot_positions[1].balance_type,
             SpotBalanceType::Borrow
         );
-        assert_eq!(liquidator.spot_positions[1].scaled_balance, 20000001); // ~$1 liability taken over
-        assert_eq!(liquidator.perp_positions[0].quote_asset_amount, 2140000);
+        assert_eq!(liquidator.spot_positions[1].scaled_balance, 642260001);
+        assert_eq!(liquidator.perp_positions[0].quote_asset_amount, 64933193);
+
+        let market_after = spot_market_map.get_ref(&1).unwrap();
+        let market_revenue = get_token_amount(
+            market_after.revenue_pool.scaled_balance,
+            &market_after,
+            &SpotBalanceType::Deposit,
+        )
+        .unwrap();
+
+        assert_eq!(market_revenue, 0);
     }
 
     #[test]
-    pub fn successful_liquidation_over_multiple_slots() {
-        let now = 1_i64;
-        let slot = 1_u64;
+    pub fn successful_liquidation_liability_transfer_implied_by_pnl() {
+        let now = 0_i64;
+        let slot = 0_u64;
 
         let mut sol_oracle_price = get_pyth_price(100, 6);
         let sol_oracle_price_key =
@@ -4539,7 +4772,6 @@ pub mod liquidate_borrow_for_perp_pnl {
                 ..AMM::default()
             },
             margin_ratio_initial: 1000,
-            margin_ratio_maintenance: 500,
             unrealized_pnl_initial_asset_weight: 9000,
             unrealized_pnl_maintenance_asset_weight: 10000,
             number_of_users_with_base: 1,
@@ -4578,10 +4810,9 @@ pub mod liquidate_borrow_for_perp_pnl {
             maintenance_asset_weight: 9 * SPOT_WEIGHT_PRECISION / 10,
             initial_liability_weight: 12 * SPOT_WEIGHT_PRECISION / 10,
             maintenance_liability_weight: 11 * SPOT_WEIGHT_PRECISION / 10,
-            deposit_balance: 100 * SPOT_BALANCE_PRECISION,
-            borrow_balance: 11 * SPOT_BALANCE_PRECISION,
+            deposit_balance: SPOT_BALANCE_PRECISION,
+            borrow_balance: SPOT_BALANCE_PRECISION,
             liquidator_fee: LIQUIDATION_FEE_PRECISION / 1000,
-            if_liquidation_fee: LIQUIDATION_FEE_PRECISION / 1000,
             historical_oracle_data: HistoricalOracleData {
                 last_oracle_price_twap: (sol_oracle_price.agg.price * 99 / 100),
                 last_oracle_price_twap_5min: (sol_oracle_price.agg.price * 99 / 100),
@@ -4601,14 +4832,14 @@ pub mod liquidate_borrow_for_perp_pnl {
         spot_positions[0] = SpotPosition {
             market_index: 1,
             balance_type: SpotBalanceType::Borrow,
-            scaled_balance: 10 * SPOT_BALANCE_PRECISION_U64,
+            scaled_balance: SPOT_BALANCE_PRECISION_U64,
             ..SpotPosition::default()
         };
         let mut user = User {
             orders: [Order::default(); 32],
             perp_positions: get_positions(PerpPosition {
                 market_index: 0,
-                quote_asset_amount: 1050 * QUOTE_PRECISION_I64,
+                quote_asset_amount: 80 * QUOTE_PRECISION_I64,
                 ..PerpPosition::default()
             }),
             spot_positions,
@@ -4619,7 +4850,7 @@ pub mod liquidate_borrow_for_perp_pnl {
             spot_positions: get_spot_positions(SpotPosition {
                 market_index: 0,
                 balance_type: SpotBalanceType::Deposit,
-                scaled_balance: 1000 * SPOT_BALANCE_PRECISION_U64,
+                scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
                 ..SpotPosition::default()
             }),
             ..User::default()
@@ -4628,11 +4859,10 @@ pub mod liquidate_borrow_for_perp_pnl {
         let user_key = Pubkey::default();
         let liquidator_key = Pubkey::default();
 
-        let liquidation_buffer = MARGIN_PRECISION as u32 / 50;
         liquidate_borrow_for_perp_pnl(
             0,
             1,
-            10 * 10_u128.pow(6),
+            2 * 10_u128.pow(6),
             None,
             &mut user,
             &user_key,
@@ -4643,156 +4873,40 @@ pub mod liquidate_borrow_for_perp_pnl {
             &mut oracle_map,
             now,
             slot,
-            liquidation_buffer,
-            LIQUIDATION_PCT_PRECISION / 10,
+            10,
+            PERCENTAGE_PRECISION,
+            LIQUIDATION_PCT_PRECISION,
             150,
         )
         .unwrap();
 
-        assert_eq!(user.last_active_slot, 1);
-        assert_eq!(user.liquidation_margin_freed, 6999927);
-        assert_eq!(user.spot_positions[0].scaled_balance, 9357739999);
-        assert_eq!(user.perp_positions[0].quote_asset_amount, 985066807);
-
-        let MarginCalculation {
-            total_collateral,
-            margin_requirement_plus_buffer,
-            ..
-        } = calculate_margin_requirement_and_total_collateral_and_liability_info(
-            &user,
-            &market_map,
-            &spot_market_map,
-            &mut oracle_map,
-            MarginContext::liquidation(liquidation_buffer),
-        )
-        .unwrap();
-
-        let margin_shortage =
-            ((margin_requirement_plus_buffer as i128) - total_collateral).unsigned_abs();
+        assert_eq!(user.spot_positions[0].scaled_balance, 208711999);
+        assert_eq!(user.perp_positions[0].quote_asset_amount, 0);
 
-        let pct_margin_freed = (user.liquidation_margin_freed as u128) * PRICE_PRECISION
-            / (margin_shortage + user.liquidation_margin_freed as u128);
-        assert_eq!(pct_margin_freed, 99998); // ~10%
+        assert_eq!(
+            liquidator.spot_positions[1].balance_type,
+            SpotBalanceType::Borrow
+        );
+        assert_eq!(liquidator.spot_positions[1].scaled_balance, 791288001);
+        assert_eq!(liquidator.perp_positions[0].quote_asset_amount, 80000000);
+    }
 
-        let slot = 51_u64;
-        liquidate_borrow_for_perp_pnl(
-            0,
-            1,
-            10 * 10_u128.pow(6),
-            None,
-            &mut user,
-            &user_key,
-            &mut liquidator,
-            &liquidator_key,
-            &market_map,
-            &spot_market_map,
-            &mut oracle_map,
-            now,
-            slot,
-            liquidation_buffer,
-            LIQUIDATION_PCT_PRECISION / 10,
-            150,
-        )
-        .unwrap();
+    #[test]
+    pub fn failure_due_to_limit_price() {
+        let now = 0_i64;
+        let slot = 0_u64;
 
-        assert_eq!(user.last_active_slot, 1);
-        assert_eq!(user.liquidation_margin_freed, 30328628);
-        assert_eq!(user.spot_positions[0].scaled_balance, 7217275998);
-        assert_eq!(user.perp_positions[0].quote_asset_amount, 768663540);
-
-        let MarginCalculation {
-            total_collateral,
-            margin_requirement_plus_buffer,
-            ..
-        } = calculate_margin_requirement_and_total_collateral_and_liability_info(
-            &user,
-            &market_map,
-            &spot_market_map,
-            &mut oracle_map,
-            MarginContext::liquidation(liquidation_buffer),
-        )
-        .unwrap();
-
-        let margin_shortage =
-            ((margin_requirement_plus_buffer as i128) - total_collateral).unsigned_abs();
-
-        let pct_margin_freed = (user.liquidation_margin_freed as u128) * PRICE_PRECISION
-            / (margin_shortage + user.liquidation_margin_freed as u128);
-        assert_eq!(pct_margin_freed, 433266); // ~43.3%
-
-        let slot = 136_u64;
-        liquidate_borrow_for_perp_pnl(
-            0,
-            1,
-            10 * 10_u128.pow(6),
-            None,
-            &mut user,
-            &user_key,
-            &mut liquidator,
-            &liquidator_key,
-            &market_map,
-            &spot_market_map,
-            &mut oracle_map,
-            now,
-            slot,
-            liquidation_buffer,
-            LIQUIDATION_PCT_PRECISION / 10,
-            150,
-        )
-        .unwrap();
-
-        assert_eq!(user.liquidation_margin_freed, 0);
-        assert_eq!(user.last_active_slot, 1);
-    }
-}
-
-pub mod liquidate_perp_pnl_for_deposit {
-    use crate::state::state::State;
-    use std::str::FromStr;
-
-    use anchor_lang::Owner;
-    use solana_program::pubkey::Pubkey;
-
-    use crate::controller::liquidation::{liquidate_perp_pnl_for_deposit, liquidate_spot};
-    use crate::create_account_info;
-    use crate::create_anchor_account_info;
-    use crate::error::ErrorCode;
-    use crate::math::constants::{
-        AMM_RESERVE_PRECISION, BASE_PRECISION_I128, LIQUIDATION_FEE_PRECISION,
-        LIQUIDATION_PCT_PRECISION, MARGIN_PRECISION, PEG_PRECISION, PERCENTAGE_PRECISION,
-        PRICE_PRECISION, PRICE_PRECISION_U64, QUOTE_PRECISION_I128, QUOTE_PRECISION_I64,
-        SPOT_BALANCE_PRECISION, SPOT_BALANCE_PRECISION_U64, SPOT_CUMULATIVE_INTEREST_PRECISION,
-        SPOT_WEIGHT_PRECISION,
-    };
-    use crate::math::margin::calculate_margin_requirement_and_total_collateral_and_liability_info;
-    use crate::state::margin_calculation::{MarginCalculation, MarginContext};
-    use crate::state::oracle::HistoricalOracleData;
-    use crate::state::oracle::OracleSource;
-    use crate::state::oracle_map::OracleMap;
-    use crate::state::perp_market::{ContractTier, MarketStatus, PerpMarket, AMM};
-    use crate::state::perp_market_map::PerpMarketMap;
-    use crate::state::spot_market::{AssetTier, SpotBalanceType, SpotMarket};
-    use crate::state::spot_market_map::SpotMarketMap;
-    use crate::state::user::{Order, PerpPosition, SpotPosition, User, UserStatus};
-    use crate::test_utils::*;
-    use crate::test_utils::{get_positions, get_pyth_price, get_spot_positions};
-
-    #[test]
-    pub fn successful_liquidation_liquidator_max_pnl_transfer() {
-        let now = 0_i64;
-        let slot = 0_u64;
-
-        let mut sol_oracle_price = get_pyth_price(100, 6);
-        let sol_oracle_price_key =
-            Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
-        let pyth_program = crate::ids::pyth_program::id();
-        create_account_info!(
-            sol_oracle_price,
-            &sol_oracle_price_key,
-            &pyth_program,
-            oracle_account_info
-        );
-        let mut oracle_map = OracleMap::load_one(&oracle_account_info, slot, None).unwrap();
+        let mut sol_oracle_price = get_pyth_price(100, 6);
+        let sol_oracle_price_key =
+            Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+        let pyth_program = crate::ids::pyth_program::id();
+        create_account_info!(
+            sol_oracle_price,
+            &sol_oracle_price_key,
+            &pyth_program,
+            oracle_account_info
+        );
+        let mut oracle_map = OracleMap::load_one(&oracle_account_info, slot, None).unwrap();
 
         let mut market = PerpMarket {
             amm: AMM {
@@ -4853,7 +4967,7 @@ pub mod liquidate_perp_pnl_for_deposit {
             initial_liability_weight: 12 * SPOT_WEIGHT_PRECISION / 10,
             maintenance_liability_weight: 11 * SPOT_WEIGHT_PRECISION / 10,
             deposit_balance: SPOT_BALANCE_PRECISION,
-            borrow_balance: 0,
+            borrow_balance: SPOT_BALANCE_PRECISION,
             liquidator_fee: LIQUIDATION_FEE_PRECISION / 1000,
             historical_oracle_data: HistoricalOracleData {
                 last_oracle_price_twap: (sol_oracle_price.agg.price * 99 / 100),
@@ -4873,7 +4987,7 @@ pub mod liquidate_perp_pnl_for_deposit {
         let mut spot_positions = [SpotPosition::default(); 8];
         spot_positions[0] = SpotPosition {
             market_index: 1,
-            balance_type: SpotBalanceType::Deposit,
+            balance_type: SpotBalanceType::Borrow,
             scaled_balance: SPOT_BALANCE_PRECISION_U64,
             ..SpotPosition::default()
         };
@@ -4881,7 +4995,7 @@ pub mod liquidate_perp_pnl_for_deposit {
             orders: [Order::default(); 32],
             perp_positions: get_positions(PerpPosition {
                 market_index: 0,
-                quote_asset_amount: -100 * QUOTE_PRECISION_I64,
+                quote_asset_amount: 100 * QUOTE_PRECISION_I64,
                 ..PerpPosition::default()
             }),
             spot_positions,
@@ -4901,11 +5015,12 @@ pub mod liquidate_perp_pnl_for_deposit {
         let user_key = Pubkey::default();
         let liquidator_key = Pubkey::default();
 
-        liquidate_perp_pnl_for_deposit(
+        let limit_price = (80880880 * PRICE_PRECISION_U64 / 800000) + 1;
+        let result = liquidate_borrow_for_perp_pnl(
             0,
             1,
-            50 * 10_u128.pow(6), // .8
-            None,
+            8 * 10_u128.pow(5), // .8
+            Some(limit_price),
             &mut user,
             &user_key,
             &mut liquidator,
@@ -4917,23 +5032,15 @@ pub mod liquidate_perp_pnl_for_deposit {
             slot,
             10,
             PERCENTAGE_PRECISION,
+            LIQUIDATION_PCT_PRECISION,
             150,
-        )
-        .unwrap();
-
-        assert_eq!(user.spot_positions[0].scaled_balance, 494445000);
-        assert_eq!(user.perp_positions[0].quote_asset_amount, -50000000);
-
-        assert_eq!(
-            liquidator.spot_positions[1].balance_type,
-            SpotBalanceType::Deposit
         );
-        assert_eq!(liquidator.spot_positions[1].scaled_balance, 505555000);
-        assert_eq!(liquidator.perp_positions[0].quote_asset_amount, -50000000);
+
+        assert_eq!(result, Err(ErrorCode::LiquidationDoesntSatisfyLimitPrice));
     }
 
     #[test]
-    pub fn successful_liquidation_pnl_transfer_to_cover_margin_shortage() {
+    pub fn success_with_limit_price() {
         let now = 0_i64;
         let slot = 0_u64;
 
@@ -4974,7 +5081,6 @@ pub mod liquidate_perp_pnl_for_deposit {
             number_of_users_with_base: 1,
             status: MarketStatus::Initialized,
             liquidator_fee: LIQUIDATION_FEE_PRECISION / 100,
-            if_liquidation_fee: LIQUIDATION_FEE_PRECISION / 100,
             ..PerpMarket::default()
         };
         create_anchor_account_info!(market, PerpMarket, market_account_info);
@@ -5009,7 +5115,7 @@ pub mod liquidate_perp_pnl_for_deposit {
             initial_liability_weight: 12 * SPOT_WEIGHT_PRECISION / 10,
             maintenance_liability_weight: 11 * SPOT_WEIGHT_PRECISION / 10,
             deposit_balance: SPOT_BALANCE_PRECISION,
-            borrow_balance: 0,
+            borrow_balance: SPOT_BALANCE_PRECISION,
             liquidator_fee: LIQUIDATION_FEE_PRECISION / 1000,
             historical_oracle_data: HistoricalOracleData {
                 last_oracle_price_twap: (sol_oracle_price.agg.price * 99 / 100),
@@ -5029,7 +5135,7 @@ pub mod liquidate_perp_pnl_for_deposit {
         let mut spot_positions = [SpotPosition::default(); 8];
         spot_positions[0] = SpotPosition {
             market_index: 1,
-            balance_type: SpotBalanceType::Deposit,
+            balance_type: SpotBalanceType::Borrow,
             scaled_balance: SPOT_BALANCE_PRECISION_U64,
             ..SpotPosition::default()
         };
@@ -5037,7 +5143,7 @@ pub mod liquidate_perp_pnl_for_deposit {
             orders: [Order::default(); 32],
             perp_positions: get_positions(PerpPosition {
                 market_index: 0,
-                quote_asset_amount: -91 * QUOTE_PRECISION_I64,
+                quote_asset_amount: 100 * QUOTE_PRECISION_I64,
                 ..PerpPosition::default()
             }),
             spot_positions,
@@ -5057,11 +5163,12 @@ pub mod liquidate_perp_pnl_for_deposit {
         let user_key = Pubkey::default();
         let liquidator_key = Pubkey::default();
 
-        liquidate_perp_pnl_for_deposit(
+        let limit_price = (80880880 * PRICE_PRECISION_U64 / 800000) - 1;
+        let result = liquidate_borrow_for_perp_pnl(
             0,
             1,
-            200 * 10_u128.pow(6), // .8
-            None,
+            8 * 10_u128.pow(5), // .8
+            Some(limit_price),
             &mut user,
             &user_key,
             &mut liquidator,
@@ -5071,28 +5178,17 @@ pub mod liquidate_perp_pnl_for_deposit {
             &mut oracle_map,
             now,
             slot,
-            MARGIN_PRECISION as u32 / 50,
+            10,
             PERCENTAGE_PRECISION,
+            LIQUIDATION_PCT_PRECISION,
             150,
-        )
-        .unwrap();
-
-        assert_eq!(user.spot_positions[0].scaled_balance, 887655000);
-        assert_eq!(user.perp_positions[0].quote_asset_amount, -79888889);
-
-        assert_eq!(
-            liquidator.spot_positions[1].balance_type,
-            SpotBalanceType::Deposit
         );
-        assert_eq!(liquidator.spot_positions[1].scaled_balance, 112345000);
-        assert_eq!(liquidator.perp_positions[0].quote_asset_amount, -11111111);
 
-        let market_after = market_map.get_ref(&0).unwrap();
-        assert_eq!(market_after.amm.total_liquidation_fee, 0);
+        assert_eq!(result, Ok(()));
     }
 
     #[test]
-    pub fn successful_liquidation_pnl_transfer_implied_by_asset_amount() {
+    pub fn successful_liquidation_dust_position() {
         let now = 0_i64;
         let slot = 0_u64;
 
@@ -5167,8 +5263,9 @@ pub mod liquidate_perp_pnl_for_deposit {
             initial_liability_weight: 12 * SPOT_WEIGHT_PRECISION / 10,
             maintenance_liability_weight: 11 * SPOT_WEIGHT_PRECISION / 10,
             deposit_balance: SPOT_BALANCE_PRECISION,
-            borrow_balance: 0,
+            borrow_balance: SPOT_BALANCE_PRECISION / 50,
             liquidator_fee: LIQUIDATION_FEE_PRECISION / 1000,
+            if_liquidation_fee: LIQUIDATION_FEE_PRECISION / 1000,
             historical_oracle_data: HistoricalOracleData {
                 last_oracle_price_twap: (sol_oracle_price.agg.price * 99 / 100),
                 last_oracle_price_twap_5min: (sol_oracle_price.agg.price * 99 / 100),
@@ -5187,15 +5284,15 @@ pub mod liquidate_perp_pnl_for_deposit {
         let mut spot_positions = [SpotPosition::default(); 8];
         spot_positions[0] = SpotPosition {
             market_index: 1,
-            balance_type: SpotBalanceType::Deposit,
-            scaled_balance: SPOT_BALANCE_PRECISION_U64,
+            balance_type: SpotBalanceType::Borrow,
+            scaled_balance: SPOT_BALANCE_PRECISION_U64 / 50,
             ..SpotPosition::default()
         };
         let mut user = User {
             orders: [Order::default(); 32],
             perp_positions: get_positions(PerpPosition {
                 market_index: 0,
-                quote_asset_amount: -150 * QUOTE_PRECISION_I64,
+                quote_asset_amount: 107 * QUOTE_PRECISION_I64 / 50,
                 ..PerpPosition::default()
             }),
             spot_positions,
@@ -5215,10 +5312,11 @@ pub mod liquidate_perp_pnl_for_deposit {
         let user_key = Pubkey::default();
         let liquidator_key = Pubkey::default();
 
-        liquidate_perp_pnl_for_deposit(
+        let liquidation_buffer = MARGIN_PRECISION as u32 / 50;
+        liquidate_borrow_for_perp_pnl(
             0,
             1,
-            200 * 10_u128.pow(6), // .8
+            2 * 10_u128.pow(6),
             None,
             &mut user,
             &user_key,
@@ -5229,27 +5327,28 @@ pub mod liquidate_perp_pnl_for_deposit {
             &mut oracle_map,
             now,
             slot,
-            10,
+            liquidation_buffer,
             PERCENTAGE_PRECISION,
+            LIQUIDATION_PCT_PRECISION,
             150,
         )
         .unwrap();
 
         assert_eq!(user.spot_positions[0].scaled_balance, 0);
-        assert_eq!(user.perp_positions[0].quote_asset_amount, -51098901);
+        assert_eq!(user.perp_positions[0].quote_asset_amount, 0);
 
         assert_eq!(
             liquidator.spot_positions[1].balance_type,
-            SpotBalanceType::Deposit
+            SpotBalanceType::Borrow
         );
-        assert_eq!(liquidator.spot_positions[1].scaled_balance, 1000000000);
-        assert_eq!(liquidator.perp_positions[0].quote_asset_amount, -98901099);
+        assert_eq!(liquidator.spot_positions[1].scaled_balance, 20000001); // ~$1 liability taken over
+        assert_eq!(liquidator.perp_positions[0].quote_asset_amount, 2140000);
     }
 
     #[test]
-    pub fn failure_due_to_limit_price() {
-        let now = 0_i64;
-        let slot = 0_u64;
+    pub fn successful_liquidation_over_multiple_slots() {
+        let now = 1_i64;
+        let slot = 1_u64;
 
         let mut sol_oracle_price = get_pyth_price(100, 6);
         let sol_oracle_price_key =
@@ -5321,9 +5420,10 @@ pub mod liquidate_perp_pnl_for_deposit {
             maintenance_asset_weight: 9 * SPOT_WEIGHT_PRECISION / 10,
             initial_liability_weight: 12 * SPOT_WEIGHT_PRECISION / 10,
             maintenance_liability_weight: 11 * SPOT_WEIGHT_PRECISION / 10,
-            deposit_balance: SPOT_BALANCE_PRECISION,
-            borrow_balance: 0,
+            deposit_balance: 100 * SPOT_BALANCE_PRECISION,
+            borrow_balance: 11 * SPOT_BALANCE_PRECISION,
             liquidator_fee: LIQUIDATION_FEE_PRECISION / 1000,
+            if_liquidation_fee: LIQUIDATION_FEE_PRECISION / 1000,
             historical_oracle_data: HistoricalOracleData {
                 last_oracle_price_twap: (sol_oracle_price.agg.price * 99 / 100),
                 last_oracle_price_twap_5min: (sol_oracle_price.agg.price * 99 / 100),
@@ -5342,15 +5442,15 @@ pub mod liquidate_perp_pnl_for_deposit {
         let mut spot_positions = [SpotPosition::default(); 8];
         spot_positions[0] = SpotPosition {
             market_index: 1,
-            balance_type: SpotBalanceType::Deposit,
-            scaled_balance: SPOT_BALANCE_PRECISION_U64,
+            balance_type: SpotBalanceType::Borrow,
+            scaled_balance: 10 * SPOT_BALANCE_PRECISION_U64,
             ..SpotPosition::default()
         };
         let mut user = User {
             orders: [Order::default(); 32],
             perp_positions: get_positions(PerpPosition {
                 market_index: 0,
-                quote_asset_amount: -100 * QUOTE_PRECISION_I64,
+                quote_asset_amount: 1050 * QUOTE_PRECISION_I64,
                 ..PerpPosition::default()
             }),
             spot_positions,
@@ -5361,7 +5461,7 @@ pub mod liquidate_perp_pnl_for_deposit {
             spot_positions: get_spot_positions(SpotPosition {
                 market_index: 0,
                 balance_type: SpotBalanceType::Deposit,
-                scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
+                scaled_balance: 1000 * SPOT_BALANCE_PRECISION_U64,
                 ..SpotPosition::default()
             }),
             ..User::default()
@@ -5370,12 +5470,12 @@ pub mod liquidate_perp_pnl_for_deposit {
         let user_key = Pubkey::default();
         let liquidator_key = Pubkey::default();
 
-        let limit_price = 505555 * PRICE_PRECISION_U64 / 50000000 + 1;
-        let result = liquidate_perp_pnl_for_deposit(
+        let liquidation_buffer = MARGIN_PRECISION as u32 / 50;
+        liquidate_borrow_for_perp_pnl(
             0,
             1,
-            50 * 10_u128.pow(6), // .8
-            Some(limit_price),
+            10 * 10_u128.pow(6),
+            None,
             &mut user,
             &user_key,
             &mut liquidator,
@@ -5385,46 +5485,175 @@ pub mod liquidate_perp_pnl_for_deposit {
             &mut oracle_map,
             now,
             slot,
-            10,
-            PERCENTAGE_PRECISION,
+            liquidation_buffer,
+            LIQUIDATION_PCT_PRECISION / 10,
+            LIQUIDATION_PCT_PRECISION,
             150,
-        );
+        )
+        .unwrap();
 
-        assert_eq!(result, Err(ErrorCode::LiquidationDoesntSatisfyLimitPrice));
-    }
+        assert_eq!(user.last_active_slot, 1);
+        assert_eq!(user.liquidation_margin_freed, 6999927);
+        assert_eq!(user.spot_positions[0].scaled_balance, 9357739999);
+        assert_eq!(user.perp_positions[0].quote_asset_amount, 985066807);
 
-    #[test]
-    pub fn success_with_limit_price() {
-        let now = 0_i64;
-        let slot = 0_u64;
+        let MarginCalculation {
+            total_collateral,
+            margin_requirement_plus_buffer,
+            ..
+        } = calculate_margin_requirement_and_total_collateral_and_liability_info(
+            &user,
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            MarginContext::liquidation(liquidation_buffer),
+        )
+        .unwrap();
 
-        let mut sol_oracle_price = get_pyth_price(100, 6);
-        let sol_oracle_price_key =
-            Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
-        let pyth_program = crate::ids::pyth_program::id();
-        create_account_info!(
-            sol_oracle_price,
-            &sol_oracle_price_key,
-            &pyth_program,
-            oracle_account_info
-        );
-        let mut oracle_map = OracleMap::load_one(&oracle_account_info, slot, None).unwrap();
+        let margin_shortage =
+            ((margin_requirement_plus_buffer as i128) - total_collateral).unsigned_abs();
 
-        let mut market = PerpMarket {
-            amm: AMM {
-                base_asset_reserve: 100 * AMM_RESERVE_PRECISION,
-                quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
-                bid_base_asset_reserve: 101 * AMM_RESERVE_PRECISION,
-                bid_quote_asset_reserve: 99 * AMM_RESERVE_PRECISION,
-                ask_base_asset_reserve: 99 * AMM_RESERVE_PRECISION,
-                ask_quote_asset_reserve: 101 * AMM_RESERVE_PRECISION,
-                sqrt_k: 100 * AMM_RESERVE_PRECISION,
-                peg_multiplier: 100 * PEG_PRECISION,
-                max_slippage_ratio: 50,
-                max_fill_reserve_fraction: 100,
-                order_step_size: 10000000,
-                quote_asset_amount: 150 * QUOTE_PRECISION_I128,
-                base_asset_amount_with_amm: BASE_PRECISION_I128,
+        let pct_margin_freed = (user.liquidation_margin_freed as u128) * PRICE_PRECISION
+            / (margin_shortage + user.liquidation_margin_freed as u128);
+        assert_eq!(pct_margin_freed, 99998); // ~10%
+
+        let slot = 51_u64;
+        liquidate_borrow_for_perp_pnl(
+            0,
+            1,
+            10 * 10_u128.pow(6),
+            None,
+            &mut user,
+            &user_key,
+            &mut liquidator,
+            &liquidator_key,
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            now,
+            slot,
+            liquidation_buffer,
+            LIQUIDATION_PCT_PRECISION / 10,
+            LIQUIDATION_PCT_PRECISION,
+            150,
+        )
+        .unwrap();
+
+        assert_eq!(user.last_active_slot, 1);
+        assert_eq!(user.liquidation_margin_freed, 30328628);
+        assert_eq!(user.spot_positions[0].scaled_balance, 7217275998);
+        assert_eq!(user.perp_positions[0].quote_asset_amount, 768663540);
+
+        let MarginCalculation {
+            total_collateral,
+            margin_requirement_plus_buffer,
+            ..
+        } = calculate_margin_requirement_and_total_collateral_and_liability_info(
+            &user,
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            MarginContext::liquidation(liquidation_buffer),
+        )
+        .unwrap();
+
+        let margin_shortage =
+            ((margin_requirement_plus_buffer as i128) - total_collateral).unsigned_abs();
+
+        let pct_margin_freed = (user.liquidation_margin_freed as u128) * PRICE_PRECISION
+            / (margin_shortage + user.liquidation_margin_freed as u128);
+        assert_eq!(pct_margin_freed, 433266); // ~43.3%
+
+        let slot = 136_u64;
+        liquidate_borrow_for_perp_pnl(
+            0,
+            1,
+            10 * 10_u128.pow(6),
+            None,
+            &mut user,
+            &user_key,
+            &mut liquidator,
+            &liquidator_key,
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            now,
+            slot,
+            liquidation_buffer,
+            LIQUIDATION_PCT_PRECISION / 10,
+            LIQUIDATION_PCT_PRECISION,
+            150,
+        )
+        .unwrap();
+
+        assert_eq!(user.liquidation_margin_freed, 0);
+        assert_eq!(user.last_active_slot, 1);
+    }
+}
+
+pub mod liquidate_perp_pnl_for_deposit {
+    use crate::state::state::State;
+    use std::str::FromStr;
+
+    use anchor_lang::Owner;
+    use solana_program::pubkey::Pubkey;
+
+    use crate::controller::liquidation::{liquidate_perp_pnl_for_deposit, liquidate_spot};
+    use crate::create_account_info;
+    use crate::create_anchor_account_info;
+    use crate::error::ErrorCode;
+    use crate::math::constants::{
+        AMM_RESERVE_PRECISION, BASE_PRECISION_I128, LIQUIDATION_FEE_PRECISION,
+        LIQUIDATION_PCT_PRECISION, MARGIN_PRECISION, PEG_PRECISION, PERCENTAGE_PRECISION,
+        PRICE_PRECISION, PRICE_PRECISION_U64, QUOTE_PRECISION_I128, QUOTE_PRECISION_I64,
+        SPOT_BALANCE_PRECISION, SPOT_BALANCE_PRECISION_U64, SPOT_CUMULATIVE_INTEREST_PRECISION,
+        SPOT_WEIGHT_PRECISION,
+    };
+    use crate::math::margin::calculate_margin_requirement_and_total_collateral_and_liability_info;
+    use crate::state::margin_calculation::{MarginCalculation, MarginContext};
+    use crate::state::oracle::HistoricalOracleData;
+    use crate::state::oracle::OracleSource;
+    use crate::state::oracle_map::OracleMap;
+    use crate::state::perp_market::{ContractTier, MarketStatus, PerpMarket, AMM};
+    use crate::state::perp_market_map::PerpMarketMap;
+    use crate::state::spot_market::{AssetTier, SpotBalanceType, SpotMarket};
+    use crate::state::spot_market_map::SpotMarketMap;
+    use crate::state::user::{Order, PerpPosition, SpotPosition, User, UserStatus};
+    use crate::test_utils::*;
+    use crate::test_utils::{get_positions, get_pyth_price, get_spot_positions};
+
+    #[test]
+    pub fn successful_liquidation_liquidator_max_pnl_transfer() {
+        let now = 0_i64;
+        let slot = 0_u64;
+
+        let mut sol_oracle_price = get_pyth_price(100, 6);
+        let sol_oracle_price_key =
+            Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+        let pyth_program = crate::ids::pyth_program::id();
+        create_account_info!(
+            sol_oracle_price,
+            &sol_oracle_price_key,
+            &pyth_program,
+            oracle_account_info
+        );
+        let mut oracle_map = OracleMap::load_one(&oracle_account_info, slot, None).unwrap();
+
+        let mut market = PerpMarket {
+            amm: AMM {
+                base_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                bid_base_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+                bid_quote_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+                ask_base_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+                ask_quote_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+                sqrt_k: 100 * AMM_RESERVE_PRECISION,
+                peg_multiplier: 100 * PEG_PRECISION,
+                max_slippage_ratio: 50,
+                max_fill_reserve_fraction: 100,
+                order_step_size: 10000000,
+                quote_asset_amount: 150 * QUOTE_PRECISION_I128,
+                base_asset_amount_with_amm: BASE_PRECISION_I128,
                 oracle: sol_oracle_price_key,
                 ..AMM::default()
             },
@@ -5517,12 +5746,11 @@ pub mod liquidate_perp_pnl_for_deposit {
         let user_key = Pubkey::default();
         let liquidator_key = Pubkey::default();
 
-        let limit_price = 505555 * PRICE_PRECISION_U64 / 50000000 - 1;
-        let result = liquidate_perp_pnl_for_deposit(
+        liquidate_perp_pnl_for_deposit(
             0,
             1,
             50 * 10_u128.pow(6), // .8
-            Some(limit_price),
+            None,
             &mut user,
             &user_key,
             &mut liquidator,
@@ -5534,14 +5762,24 @@ pub mod liquidate_perp_pnl_for_deposit {
             slot,
             10,
             PERCENTAGE_PRECISION,
+            LIQUIDATION_PCT_PRECISION,
             150,
-        );
+        )
+        .unwrap();
 
-        assert_eq!(result, Ok(()));
+        assert_eq!(user.spot_positions[0].scaled_balance, 494445000);
+        assert_eq!(user.perp_positions[0].quote_asset_amount, -50000000);
+
+        assert_eq!(
+            liquidator.spot_positions[1].balance_type,
+            SpotBalanceType::Deposit
+        );
+        assert_eq!(liquidator.spot_positions[1].scaled_balance, 505555000);
+        assert_eq!(liquidator.perp_positions[0].quote_asset_amount, -50000000);
     }
 
     #[test]
-    pub fn successful_liquidate_dust_position() {
+    pub fn successful_liquidation_pnl_transfer_to_cover_margin_shortage() {
         let now = 0_i64;
         let slot = 0_u64;
 
@@ -5638,14 +5876,14 @@ pub mod liquidate_perp_pnl_for_deposit {
         spot_positions[0] = SpotPosition {
             market_index: 1,
             balance_type: SpotBalanceType::Deposit,
-            scaled_balance: SPOT_BALANCE_PRECISION_U64 / 50,
+            scaled_balance: SPOT_BALANCE_PRECISION_U64,
             ..SpotPosition::default()
         };
         let mut user = User {
             orders: [Order::default(); 32],
             perp_positions: get_positions(PerpPosition {
                 market_index: 0,
-                quote_asset_amount: -91 * QUOTE_PRECISION_I64 / 50,
+                quote_asset_amount: -91 * QUOTE_PRECISION_I64,
                 ..PerpPosition::default()
             }),
             spot_positions,
@@ -5681,25 +5919,29 @@ pub mod liquidate_perp_pnl_for_deposit {
             slot,
             MARGIN_PRECISION as u32 / 50,
             PERCENTAGE_PRECISION,
+            LIQUIDATION_PCT_PRECISION,
             150,
         )
         .unwrap();
 
-        assert_eq!(user.spot_positions[0].scaled_balance, 0);
-        assert_eq!(user.perp_positions[0].quote_asset_amount, 0);
+        assert_eq!(user.spot_positions[0].scaled_balance, 887655000);
+        assert_eq!(user.perp_positions[0].quote_asset_amount, -79888889);
 
         assert_eq!(
             liquidator.spot_positions[1].balance_type,
             SpotBalanceType::Deposit
         );
-        assert_eq!(liquidator.spot_positions[1].scaled_balance, 20000000);
-        assert_eq!(liquidator.perp_positions[0].quote_asset_amount, -1820000); // -$1
+        assert_eq!(liquidator.spot_positions[1].scaled_balance, 112345000);
+        assert_eq!(liquidator.perp_positions[0].quote_asset_amount, -11111111);
+
+        let market_after = market_map.get_ref(&0).unwrap();
+        assert_eq!(market_after.amm.total_liquidation_fee, 0);
     }
 
     #[test]
-    pub fn successful_liquidation_over_multiple_slots() {
-        let now = 1_i64;
-        let slot = 1_u64;
+    pub fn successful_liquidation_pnl_transfer_implied_by_asset_amount() {
+        let now = 0_i64;
+        let slot = 0_u64;
 
         let mut sol_oracle_price = get_pyth_price(100, 6);
         let sol_oracle_price_key =
@@ -5738,7 +5980,6 @@ pub mod liquidate_perp_pnl_for_deposit {
             number_of_users_with_base: 1,
             status: MarketStatus::Initialized,
             liquidator_fee: LIQUIDATION_FEE_PRECISION / 100,
-            if_liquidation_fee: LIQUIDATION_FEE_PRECISION / 100,
             ..PerpMarket::default()
         };
         create_anchor_account_info!(market, PerpMarket, market_account_info);
@@ -5794,14 +6035,14 @@ pub mod liquidate_perp_pnl_for_deposit {
         spot_positions[0] = SpotPosition {
             market_index: 1,
             balance_type: SpotBalanceType::Deposit,
-            scaled_balance: 10 * SPOT_BALANCE_PRECISION_U64,
+            scaled_balance: SPOT_BALANCE_PRECISION_U64,
             ..SpotPosition::default()
         };
         let mut user = User {
             orders: [Order::default(); 32],
             perp_positions: get_positions(PerpPosition {
                 market_index: 0,
-                quote_asset_amount: -950 * QUOTE_PRECISION_I64,
+                quote_asset_amount: -150 * QUOTE_PRECISION_I64,
                 ..PerpPosition::default()
             }),
             spot_positions,
@@ -5812,7 +6053,7 @@ pub mod liquidate_perp_pnl_for_deposit {
             spot_positions: get_spot_positions(SpotPosition {
                 market_index: 0,
                 balance_type: SpotBalanceType::Deposit,
-                scaled_balance: 1000 * SPOT_BALANCE_PRECISION_U64,
+                scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
                 ..SpotPosition::default()
             }),
             ..User::default()
@@ -5821,7 +6062,6 @@ pub mod liquidate_perp_pnl_for_deposit {
         let user_key = Pubkey::default();
         let liquidator_key = Pubkey::default();
 
-        let liquidation_buffer = MARGIN_PRECISION as u32 / 50;
         liquidate_perp_pnl_for_deposit(
             0,
             1,
@@ -5836,124 +6076,40 @@ pub mod liquidate_perp_pnl_for_deposit {
             &mut oracle_map,
             now,
             slot,
-            liquidation_buffer,
-            LIQUIDATION_PCT_PRECISION / 10,
+            10,
+            PERCENTAGE_PRECISION,
+            LIQUIDATION_PCT_PRECISION,
             150,
         )
         .unwrap();
 
-        assert_eq!(user.last_active_slot, 1);
-        assert_eq!(user.liquidation_margin_freed, 5000035);
-        assert_eq!(user.spot_positions[0].scaled_balance, 9438272000);
-        assert_eq!(user.perp_positions[0].quote_asset_amount, -894444445);
+        assert_eq!(user.spot_positions[0].scaled_balance, 0);
+        assert_eq!(user.perp_positions[0].quote_asset_amount, -51098901);
 
-        let MarginCalculation {
-            total_collateral,
-            margin_requirement_plus_buffer,
-            ..
-        } = calculate_margin_requirement_and_total_collateral_and_liability_info(
-            &user,
-            &market_map,
-            &spot_market_map,
-            &mut oracle_map,
-            MarginContext::liquidation(liquidation_buffer),
-        )
-        .unwrap();
+        assert_eq!(
+            liquidator.spot_positions[1].balance_type,
+            SpotBalanceType::Deposit
+        );
+        assert_eq!(liquidator.spot_positions[1].scaled_balance, 1000000000);
+        assert_eq!(liquidator.perp_positions[0].quote_asset_amount, -98901099);
+    }
 
-        let margin_shortage =
-            ((margin_requirement_plus_buffer as i128) - total_collateral).unsigned_abs();
+    #[test]
+    pub fn failure_due_to_limit_price() {
+        let now = 0_i64;
+        let slot = 0_u64;
 
-        let pct_margin_freed = (user.liquidation_margin_freed as u128) * PRICE_PRECISION
-            / (margin_shortage + user.liquidation_margin_freed as u128);
-        assert_eq!(pct_margin_freed, 100000); // ~10%
-
-        let slot = 51_u64;
-        liquidate_perp_pnl_for_deposit(
-            0,
-            1,
-            200 * 10_u128.pow(6), // .8
-            None,
-            &mut user,
-            &user_key,
-            &mut liquidator,
-            &liquidator_key,
-            &market_map,
-            &spot_market_map,
-            &mut oracle_map,
-            now,
-            slot,
-            liquidation_buffer,
-            LIQUIDATION_PCT_PRECISION / 10,
-            150,
-        )
-        .unwrap();
-
-        assert_eq!(user.last_active_slot, 1);
-        assert_eq!(user.liquidation_margin_freed, 23000055);
-        assert_eq!(user.spot_positions[0].scaled_balance, 7416050000);
-        assert_eq!(user.perp_positions[0].quote_asset_amount, -694444445);
-
-        let MarginCalculation {
-            total_collateral,
-            margin_requirement_plus_buffer,
-            ..
-        } = calculate_margin_requirement_and_total_collateral_and_liability_info(
-            &user,
-            &market_map,
-            &spot_market_map,
-            &mut oracle_map,
-            MarginContext::liquidation(liquidation_buffer),
-        )
-        .unwrap();
-
-        let margin_shortage =
-            ((margin_requirement_plus_buffer as i128) - total_collateral).unsigned_abs();
-
-        let pct_margin_freed = (user.liquidation_margin_freed as u128) * PRICE_PRECISION
-            / (margin_shortage + user.liquidation_margin_freed as u128);
-        assert_eq!(pct_margin_freed, 460001); // ~43%
-
-        let slot = 136_u64;
-        liquidate_perp_pnl_for_deposit(
-            0,
-            1,
-            2000 * 10_u128.pow(6), // .8
-            None,
-            &mut user,
-            &user_key,
-            &mut liquidator,
-            &liquidator_key,
-            &market_map,
-            &spot_market_map,
-            &mut oracle_map,
-            now,
-            slot,
-            liquidation_buffer,
-            LIQUIDATION_PCT_PRECISION / 10,
-            150,
-        )
-        .unwrap();
-
-        assert_eq!(user.last_active_slot, 1);
-        assert_eq!(user.liquidation_margin_freed, 0);
-    }
-
-    #[test]
-    pub fn failure_due_to_asset_tier_violation() {
-        let now = 0_i64;
-        let slot = 0_u64;
-
-        let mut sol_oracle_price = get_pyth_price(100, 6);
-        let sol_oracle_price_key =
-            Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
-        let pyth_program = crate::ids::pyth_program::id();
-        create_account_info!(
-            sol_oracle_price,
-            &sol_oracle_price_key,
-            &pyth_program,
-            oracle_account_info
-        );
-        let mut oracle_map = OracleMap::load_one(&oracle_account_info, slot, None).unwrap();
+        let mut sol_oracle_price = get_pyth_price(100, 6);
+        let sol_oracle_price_key =
+            Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+        let pyth_program = crate::ids::pyth_program::id();
+        create_account_info!(
+            sol_oracle_price,
+            &sol_oracle_price_key,
+            &pyth_program,
+            oracle_account_info
+        );
+        let mut oracle_map = OracleMap::load_one(&oracle_account_info, slot, None).unwrap();
 
         let mut market = PerpMarket {
             amm: AMM {
@@ -5994,11 +6150,9 @@ pub mod liquidate_perp_pnl_for_deposit {
             maintenance_asset_weight: SPOT_WEIGHT_PRECISION,
             deposit_balance: 200 * SPOT_BALANCE_PRECISION,
             liquidator_fee: 0,
-            asset_tier: AssetTier::Collateral,
             historical_oracle_data: HistoricalOracleData {
-                last_oracle_price_twap: PRICE_PRECISION as i64,
-                last_oracle_price_twap_5min: PRICE_PRECISION as i64,
-
+                last_oracle_price_twap: QUOTE_PRECISION_I64,
+                last_oracle_price_twap_5min: QUOTE_PRECISION_I64,
                 ..HistoricalOracleData::default()
             },
             ..SpotMarket::default()
@@ -6015,16 +6169,14 @@ pub mod liquidate_perp_pnl_for_deposit {
             maintenance_asset_weight: 9 * SPOT_WEIGHT_PRECISION / 10,
             initial_liability_weight: 12 * SPOT_WEIGHT_PRECISION / 10,
             maintenance_liability_weight: 11 * SPOT_WEIGHT_PRECISION / 10,
-            deposit_balance: 10 * SPOT_BALANCE_PRECISION,
-            borrow_balance: SPOT_BALANCE_PRECISION,
+            deposit_balance: SPOT_BALANCE_PRECISION,
+            borrow_balance: 0,
             liquidator_fee: LIQUIDATION_FEE_PRECISION / 1000,
             historical_oracle_data: HistoricalOracleData {
                 last_oracle_price_twap: (sol_oracle_price.agg.price * 99 / 100),
                 last_oracle_price_twap_5min: (sol_oracle_price.agg.price * 99 / 100),
-
                 ..HistoricalOracleData::default()
             },
-            asset_tier: AssetTier::Collateral,
             ..SpotMarket::default()
         };
         create_anchor_account_info!(sol_market, SpotMarket, sol_spot_market_account_info);
@@ -6037,14 +6189,8 @@ pub mod liquidate_perp_pnl_for_deposit {
 
         let mut spot_positions = [SpotPosition::default(); 8];
         spot_positions[0] = SpotPosition {
-            market_index: 0,
-            balance_type: SpotBalanceType::Deposit,
-            scaled_balance: 200 * SPOT_BALANCE_PRECISION_U64,
-            ..SpotPosition::default()
-        };
-        spot_positions[1] = SpotPosition {
             market_index: 1,
-            balance_type: SpotBalanceType::Borrow,
+            balance_type: SpotBalanceType::Deposit,
             scaled_balance: SPOT_BALANCE_PRECISION_U64,
             ..SpotPosition::default()
         };
@@ -6063,7 +6209,7 @@ pub mod liquidate_perp_pnl_for_deposit {
             spot_positions: get_spot_positions(SpotPosition {
                 market_index: 0,
                 balance_type: SpotBalanceType::Deposit,
-                scaled_balance: 2500 * SPOT_BALANCE_PRECISION_U64,
+                scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
                 ..SpotPosition::default()
             }),
             ..User::default()
@@ -6072,81 +6218,12 @@ pub mod liquidate_perp_pnl_for_deposit {
         let user_key = Pubkey::default();
         let liquidator_key = Pubkey::default();
 
-        assert!(liquidate_perp_pnl_for_deposit(
-            0,
-            0,
-            50 * 10_u128.pow(6), // .8
-            None,
-            &mut user,
-            &user_key,
-            &mut liquidator,
-            &liquidator_key,
-            &market_map,
-            &spot_market_map,
-            &mut oracle_map,
-            now,
-            slot,
-            10,
-            PERCENTAGE_PRECISION,
-            150,
-        )
-        .is_err());
-
-        let state = State {
-            liquidation_margin_buffer_ratio: MARGIN_PRECISION as u32 / 50,
-            initial_pct_to_liquidate: (PERCENTAGE_PRECISION / 10) as u16,
-            liquidation_duration: 150,
-            ..Default::default()
-        };
-
-        liquidate_spot(
+        let limit_price = 505555 * PRICE_PRECISION_U64 / 50000000 + 1;
+        let result = liquidate_perp_pnl_for_deposit(
             0,
             1,
-            10_u128.pow(9),
-            None,
-            &mut user,
-            &user_key,
-            &mut liquidator,
-            &liquidator_key,
-            &market_map,
-            &spot_market_map,
-            &mut oracle_map,
-            now,
-            slot,
-            &state,
-        )
-        .unwrap();
-
-        assert_eq!(user.spot_positions[1].scaled_balance, 0);
-
-        liquidate_perp_pnl_for_deposit(
-            0,
-            0,
-            50 * 10_u128.pow(6), // .8
-            None,
-            &mut user,
-            &user_key,
-            &mut liquidator,
-            &liquidator_key,
-            &market_map,
-            &spot_market_map,
-            &mut oracle_map,
-            now,
-            slot,
-            10,
-            PERCENTAGE_PRECISION,
-            150,
-        )
-        .unwrap();
-        assert_eq!(user.perp_positions[0].quote_asset_amount, -50000000);
-        assert_eq!(user.spot_positions[0].scaled_balance, 49394850000); // <$50
-        assert_eq!(user.status, UserStatus::BeingLiquidated as u8);
-
-        liquidate_perp_pnl_for_deposit(
-            0,
-            0,
             50 * 10_u128.pow(6), // .8
-            None,
+            Some(limit_price),
             &mut user,
             &user_key,
             &mut liquidator,
@@ -6158,18 +6235,15 @@ pub mod liquidate_perp_pnl_for_deposit {
             slot,
             10,
             PERCENTAGE_PRECISION,
+            LIQUIDATION_PCT_PRECISION,
             150,
-        )
-        .unwrap();
-        assert_eq!(user.spot_positions[0].scaled_balance, 0);
-        assert_eq!(user.spot_positions[1].scaled_balance, 0);
+        );
 
-        assert_eq!(user.perp_positions[0].quote_asset_amount, -1099098);
-        assert_eq!(user.status, UserStatus::Bankrupt as u8);
+        assert_eq!(result, Err(ErrorCode::LiquidationDoesntSatisfyLimitPrice));
     }
 
     #[test]
-    pub fn failure_due_to_contract_tier_violation() {
+    pub fn success_with_limit_price() {
         let now = 0_i64;
         let slot = 0_u64;
 
@@ -6210,48 +6284,10 @@ pub mod liquidate_perp_pnl_for_deposit {
             number_of_users_with_base: 1,
             status: MarketStatus::Initialized,
             liquidator_fee: LIQUIDATION_FEE_PRECISION / 100,
-            market_index: 0,
-            contract_tier: ContractTier::A,
             ..PerpMarket::default()
         };
         create_anchor_account_info!(market, PerpMarket, market_account_info);
-
-        let mut bonk_market = PerpMarket {
-            amm: AMM {
-                base_asset_reserve: 100 * AMM_RESERVE_PRECISION,
-                quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
-                bid_base_asset_reserve: 101 * AMM_RESERVE_PRECISION,
-                bid_quote_asset_reserve: 99 * AMM_RESERVE_PRECISION,
-                ask_base_asset_reserve: 99 * AMM_RESERVE_PRECISION,
-                ask_quote_asset_reserve: 101 * AMM_RESERVE_PRECISION,
-                sqrt_k: 100 * AMM_RESERVE_PRECISION,
-                peg_multiplier: 8000,
-                max_slippage_ratio: 50,
-                max_fill_reserve_fraction: 100,
-                order_step_size: 10000000,
-                quote_asset_amount: 150 * QUOTE_PRECISION_I128,
-                base_asset_amount_with_amm: BASE_PRECISION_I128,
-                oracle: sol_oracle_price_key,
-                ..AMM::default()
-            },
-            margin_ratio_initial: 1000,
-            margin_ratio_maintenance: 500,
-            unrealized_pnl_initial_asset_weight: 9000,
-            unrealized_pnl_maintenance_asset_weight: 10000,
-            number_of_users_with_base: 1,
-            status: MarketStatus::Initialized,
-            liquidator_fee: LIQUIDATION_FEE_PRECISION / 100,
-            contract_tier: ContractTier::Speculative,
-            market_index: 1,
-            ..PerpMarket::default()
-        };
-        create_anchor_account_info!(bonk_market, PerpMarket, bonk_market_account_info);
-
-        let market_map = PerpMarketMap::load_multiple(
-            vec![&market_account_info, &bonk_market_account_info],
-            true,
-        )
-        .unwrap();
+        let market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
 
         let mut usdc_market = SpotMarket {
             market_index: 0,
@@ -6301,15 +6337,9 @@ pub mod liquidate_perp_pnl_for_deposit {
 
         let mut spot_positions = [SpotPosition::default(); 8];
         spot_positions[0] = SpotPosition {
-            market_index: 0,
-            balance_type: SpotBalanceType::Deposit,
-            scaled_balance: 200 * SPOT_BALANCE_PRECISION_U64,
-            ..SpotPosition::default()
-        };
-        spot_positions[1] = SpotPosition {
             market_index: 1,
             balance_type: SpotBalanceType::Deposit,
-            scaled_balance: SPOT_BALANCE_PRECISION_U64 / 1000,
+            scaled_balance: SPOT_BALANCE_PRECISION_U64,
             ..SpotPosition::default()
         };
         let mut user = User {
@@ -6323,12 +6353,6 @@ pub mod liquidate_perp_pnl_for_deposit {
             ..User::default()
         };
 
-        user.perp_positions[1] = PerpPosition {
-            market_index: 1,
-            quote_asset_amount: -150 * QUOTE_PRECISION_I64,
-            ..PerpPosition::default()
-        };
-
         let mut liquidator = User {
             spot_positions: get_spot_positions(SpotPosition {
                 market_index: 0,
@@ -6342,11 +6366,12 @@ pub mod liquidate_perp_pnl_for_deposit {
         let user_key = Pubkey::default();
         let liquidator_key = Pubkey::default();
 
-        assert!(liquidate_perp_pnl_for_deposit(
-            1,
+        let limit_price = 505555 * PRICE_PRECISION_U64 / 50000000 - 1;
+        let result = liquidate_perp_pnl_for_deposit(
             0,
+            1,
             50 * 10_u128.pow(6), // .8
-            None,
+            Some(limit_price),
             &mut user,
             &user_key,
             &mut liquidator,
@@ -6358,37 +6383,1999 @@ pub mod liquidate_perp_pnl_for_deposit {
             slot,
             10,
             PERCENTAGE_PRECISION,
+            LIQUIDATION_PCT_PRECISION,
             150,
-        )
-        .is_err());
-        assert_eq!(user.perp_positions[0].quote_asset_amount, -100000000);
+        );
 
-        liquidate_perp_pnl_for_deposit(
-            0,
-            0,
-            5000 * 10_u128.pow(6),
-            None,
-            &mut user,
-            &user_key,
-            &mut liquidator,
-            &liquidator_key,
-            &market_map,
-            &spot_market_map,
-            &mut oracle_map,
-            now,
-            slot,
-            10,
+        assert_eq!(result, Ok(()));
+    }
+
+    #[test]
+    pub fn successful_liquidate_dust_position() {
+        let now = 0_i64;
+        let slot = 0_u64;
+
+        let mut sol_oracle_price = get_pyth_price(100, 6);
+        let sol_oracle_price_key =
+            Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+        let pyth_program = crate::ids::pyth_program::id();
+        create_account_info!(
+            sol_oracle_price,
+            &sol_oracle_price_key,
+            &pyth_program,
+            oracle_account_info
+        );
+        let mut oracle_map = OracleMap::load_one(&oracle_account_info, slot, None).unwrap();
+
+        let mut market = PerpMarket {
+            amm: AMM {
+                base_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                bid_base_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+                bid_quote_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+                ask_base_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+                ask_quote_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+                sqrt_k: 100 * AMM_RESERVE_PRECISION,
+                peg_multiplier: 100 * PEG_PRECISION,
+                max_slippage_ratio: 50,
+                max_fill_reserve_fraction: 100,
+                order_step_size: 10000000,
+                quote_asset_amount: 150 * QUOTE_PRECISION_I128,
+                base_asset_amount_with_amm: BASE_PRECISION_I128,
+                oracle: sol_oracle_price_key,
+                ..AMM::default()
+            },
+            margin_ratio_initial: 1000,
+            margin_ratio_maintenance: 500,
+            unrealized_pnl_initial_asset_weight: 9000,
+            unrealized_pnl_maintenance_asset_weight: 10000,
+            number_of_users_with_base: 1,
+            status: MarketStatus::Initialized,
+            liquidator_fee: LIQUIDATION_FEE_PRECISION / 100,
+            if_liquidation_fee: LIQUIDATION_FEE_PRECISION / 100,
+            ..PerpMarket::default()
+        };
+        create_anchor_account_info!(market, PerpMarket, market_account_info);
+        let market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+
+        let mut usdc_market = SpotMarket {
+            market_index: 0,
+            oracle_source: OracleSource::QuoteAsset,
+            cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            decimals: 6,
+            initial_asset_weight: SPOT_WEIGHT_PRECISION,
+            maintenance_asset_weight: SPOT_WEIGHT_PRECISION,
+            deposit_balance: 200 * SPOT_BALANCE_PRECISION,
+            liquidator_fee: 0,
+            historical_oracle_data: HistoricalOracleData {
+                last_oracle_price_twap: QUOTE_PRECISION_I64,
+                last_oracle_price_twap_5min: QUOTE_PRECISION_I64,
+                ..HistoricalOracleData::default()
+            },
+            ..SpotMarket::default()
+        };
+        create_anchor_account_info!(usdc_market, SpotMarket, usdc_spot_market_account_info);
+        let mut sol_market = SpotMarket {
+            market_index: 1,
+            oracle_source: OracleSource::Pyth,
+            oracle: sol_oracle_price_key,
+            cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            cumulative_borrow_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            decimals: 6,
+            initial_asset_weight: 8 * SPOT_WEIGHT_PRECISION / 10,
+            maintenance_asset_weight: 9 * SPOT_WEIGHT_PRECISION / 10,
+            initial_liability_weight: 12 * SPOT_WEIGHT_PRECISION / 10,
+            maintenance_liability_weight: 11 * SPOT_WEIGHT_PRECISION / 10,
+            deposit_balance: SPOT_BALANCE_PRECISION,
+            borrow_balance: 0,
+            liquidator_fee: LIQUIDATION_FEE_PRECISION / 1000,
+            historical_oracle_data: HistoricalOracleData {
+                last_oracle_price_twap: (sol_oracle_price.agg.price * 99 / 100),
+                last_oracle_price_twap_5min: (sol_oracle_price.agg.price * 99 / 100),
+                ..HistoricalOracleData::default()
+            },
+            ..SpotMarket::default()
+        };
+        create_anchor_account_info!(sol_market, SpotMarket, sol_spot_market_account_info);
+        let spot_market_account_infos = Vec::from([
+            &usdc_spot_market_account_info,
+            &sol_spot_market_account_info,
+        ]);
+        let spot_market_map =
+            SpotMarketMap::load_multiple(spot_market_account_infos, true).unwrap();
+
+        let mut spot_positions = [SpotPosition::default(); 8];
+        spot_positions[0] = SpotPosition {
+            market_index: 1,
+            balance_type: SpotBalanceType::Deposit,
+            scaled_balance: SPOT_BALANCE_PRECISION_U64 / 50,
+            ..SpotPosition::default()
+        };
+        let mut user = User {
+            orders: [Order::default(); 32],
+            perp_positions: get_positions(PerpPosition {
+                market_index: 0,
+                quote_asset_amount: -91 * QUOTE_PRECISION_I64 / 50,
+                ..PerpPosition::default()
+            }),
+            spot_positions,
+            ..User::default()
+        };
+
+        let mut liquidator = User {
+            spot_positions: get_spot_positions(SpotPosition {
+                market_index: 0,
+                balance_type: SpotBalanceType::Deposit,
+                scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
+                ..SpotPosition::default()
+            }),
+            ..User::default()
+        };
+
+        let user_key = Pubkey::default();
+        let liquidator_key = Pubkey::default();
+
+        liquidate_perp_pnl_for_deposit(
+            0,
+            1,
+            200 * 10_u128.pow(6), // .8
+            None,
+            &mut user,
+            &user_key,
+            &mut liquidator,
+            &liquidator_key,
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            now,
+            slot,
+            MARGIN_PRECISION as u32 / 50,
             PERCENTAGE_PRECISION,
+            LIQUIDATION_PCT_PRECISION,
             150,
         )
         .unwrap();
+
+        assert_eq!(user.spot_positions[0].scaled_balance, 0);
         assert_eq!(user.perp_positions[0].quote_asset_amount, 0);
 
-        liquidate_perp_pnl_for_deposit(
-            1,
-            0,
-            50 * 10_u128.pow(6), // .8
-            None,
+        assert_eq!(
+            liquidator.spot_positions[1].balance_type,
+            SpotBalanceType::Deposit
+        );
+        assert_eq!(liquidator.spot_positions[1].scaled_balance, 20000000);
+        assert_eq!(liquidator.perp_positions[0].quote_asset_amount, -1820000); // -$1
+    }
+
+    #[test]
+    pub fn successful_liquidation_over_multiple_slots() {
+        let now = 1_i64;
+        let slot = 1_u64;
+
+        let mut sol_oracle_price = get_pyth_price(100, 6);
+        let sol_oracle_price_key =
+            Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+        let pyth_program = crate::ids::pyth_program::id();
+        create_account_info!(
+            sol_oracle_price,
+            &sol_oracle_price_key,
+            &pyth_program,
+            oracle_account_info
+        );
+        let mut oracle_map = OracleMap::load_one(&oracle_account_info, slot, None).unwrap();
+
+        let mut market = PerpMarket {
+            amm: AMM {
+                base_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                bid_base_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+                bid_quote_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+                ask_base_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+                ask_quote_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+                sqrt_k: 100 * AMM_RESERVE_PRECISION,
+                peg_multiplier: 100 * PEG_PRECISION,
+                max_slippage_ratio: 50,
+                max_fill_reserve_fraction: 100,
+                order_step_size: 10000000,
+                quote_asset_amount: 150 * QUOTE_PRECISION_I128,
+                base_asset_amount_with_amm: BASE_PRECISION_I128,
+                oracle: sol_oracle_price_key,
+                ..AMM::default()
+            },
+            margin_ratio_initial: 1000,
+            margin_ratio_maintenance: 500,
+            unrealized_pnl_initial_asset_weight: 9000,
+            unrealized_pnl_maintenance_asset_weight: 10000,
+            number_of_users_with_base: 1,
+            status: MarketStatus::Initialized,
+            liquidator_fee: LIQUIDATION_FEE_PRECISION / 100,
+            if_liquidation_fee: LIQUIDATION_FEE_PRECISION / 100,
+            ..PerpMarket::default()
+        };
+        create_anchor_account_info!(market, PerpMarket, market_account_info);
+        let market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+
+        let mut usdc_market = SpotMarket {
+            market_index: 0,
+            oracle_source: OracleSource::QuoteAsset,
+            cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            decimals: 6,
+            initial_asset_weight: SPOT_WEIGHT_PRECISION,
+            maintenance_asset_weight: SPOT_WEIGHT_PRECISION,
+            deposit_balance: 200 * SPOT_BALANCE_PRECISION,
+            liquidator_fee: 0,
+            historical_oracle_data: HistoricalOracleData {
+                last_oracle_price_twap: QUOTE_PRECISION_I64,
+                last_oracle_price_twap_5min: QUOTE_PRECISION_I64,
+                ..HistoricalOracleData::default()
+            },
+            ..SpotMarket::default()
+        };
+        create_anchor_account_info!(usdc_market, SpotMarket, usdc_spot_market_account_info);
+        let mut sol_market = SpotMarket {
+            market_index: 1,
+            oracle_source: OracleSource::Pyth,
+            oracle: sol_oracle_price_key,
+            cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            cumulative_borrow_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            decimals: 6,
+            initial_asset_weight: 8 * SPOT_WEIGHT_PRECISION / 10,
+            maintenance_asset_weight: 9 * SPOT_WEIGHT_PRECISION / 10,
+            initial_liability_weight: 12 * SPOT_WEIGHT_PRECISION / 10,
+            maintenance_liability_weight: 11 * SPOT_WEIGHT_PRECISION / 10,
+            deposit_balance: SPOT_BALANCE_PRECISION,
+            borrow_balance: 0,
+            liquidator_fee: LIQUIDATION_FEE_PRECISION / 1000,
+            historical_oracle_data: HistoricalOracleData {
+                last_oracle_price_twap: (sol_oracle_price.agg.price * 99 / 100),
+                last_oracle_price_twap_5min: (sol_oracle_price.agg.price * 99 / 100),
+                ..HistoricalOracleData::default()
+            },
+            ..SpotMarket::default()
+        };
+        create_anchor_account_info!(sol_market, SpotMarket, sol_spot_market_account_info);
+        let spot_market_account_infos = Vec::from([
+            &usdc_spot_market_account_info,
+            &sol_spot_market_account_info,
+        ]);
+        let spot_market_map =
+            SpotMarketMap::load_multiple(spot_market_account_infos, true).unwrap();
+
+        let mut spot_positions = [SpotPosition::default(); 8];
+        spot_positions[0] = SpotPosition {
+            market_index: 1,
+            balance_type: SpotBalanceType::Deposit,
+            scaled_balance: 10 * SPOT_BALANCE_PRECISION_U64,
+            ..SpotPosition::default()
+        };
+        let mut user = User {
+            orders: [Order::default(); 32],
+            perp_positions: get_positions(PerpPosition {
+                market_index: 0,
+                quote_asset_amount: -950 * QUOTE_PRECISION_I64,
+                ..PerpPosition::default()
+            }),
+            spot_positions,
+            ..User::default()
+        };
+
+        let mut liquidator = User {
+            spot_positions: get_spot_positions(SpotPosition {
+                market_index: 0,
+                balance_type: SpotBalanceType::Deposit,
+                scaled_balance: 1000 * SPOT_BALANCE_PRECISION_U64,
+                ..SpotPosition::default()
+            }),
+            ..User::default()
+        };
+
+        let user_key = Pubkey::default();
+        let liquidator_key = Pubkey::default();
+
+        let liquidation_buffer = MARGIN_PRECISION as u32 / 50;
+        liquidate_perp_pnl_for_deposit(
+            0,
+            1,
+            200 * 10_u128.pow(6), // .8
+            None,
+            &mut user,
+            &user_key,
+            &mut liquidator,
+            &liquidator_key,
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            now,
+            slot,
+            liquidation_buffer,
+            LIQUIDATION_PCT_PRECISION / 10,
+            LIQUIDATION_PCT_PRECISION,
+            150,
+        )
+        .unwrap();
+
+        assert_eq!(user.last_active_slot, 1);
+        assert_eq!(user.liquidation_margin_freed, 5000035);
+        assert_eq!(user.spot_positions[0].scaled_balance, 9438272000);
+        assert_eq!(user.perp_positions[0].quote_asset_amount, -894444445);
+
+        let MarginCalculation {
+            total_collateral,
+            margin_requirement_plus_buffer,
+            ..
+        } = calculate_margin_requirement_and_total_collateral_and_liability_info(
+            &user,
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            MarginContext::liquidation(liquidation_buffer),
+        )
+        .unwrap();
+
+        let margin_shortage =
+            ((margin_requirement_plus_buffer as i128) - total_collateral).unsigned_abs();
+
+        let pct_margin_freed = (user.liquidation_margin_freed as u128) * PRICE_PRECISION
+            / (margin_shortage + user.liquidation_margin_freed as u128);
+        assert_eq!(pct_margin_freed, 100000); // ~10%
+
+        let slot = 51_u64;
+        liquidate_perp_pnl_for_deposit(
+            0,
+            1,
+            200 * 10_u128.pow(6), // .8
+            None,
+            &mut user,
+            &user_key,
+            &mut liquidator,
+            &liquidator_key,
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            now,
+            slot,
+            liquidation_buffer,
+            LIQUIDATION_PCT_PRECISION / 10,
+            LIQUIDATION_PCT_PRECISION,
+            150,
+        )
+        .unwrap();
+
+        assert_eq!(user.last_active_slot, 1);
+        assert_eq!(user.liquidation_margin_freed, 23000055);
+        assert_eq!(user.spot_positions[0].scaled_balance, 7416050000);
+        assert_eq!(user.perp_positions[0].quote_asset_amount, -694444445);
+
+        let MarginCalculation {
+            total_collateral,
+            margin_requirement_plus_buffer,
+            ..
+        } = calculate_margin_requirement_and_total_collateral_and_liability_info(
+            &user,
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            MarginContext::liquidation(liquidation_buffer),
+        )
+        .unwrap();
+
+        let margin_shortage =
+            ((margin_requirement_plus_buffer as i128) - total_collateral).unsigned_abs();
+
+        let pct_margin_freed = (user.liquidation_margin_freed as u128) * PRICE_PRECISION
+            / (margin_shortage + user.liquidation_margin_freed as u128);
+        assert_eq!(pct_margin_freed, 460001); // ~43%
+
+        let slot = 136_u64;
+        liquidate_perp_pnl_for_deposit(
+            0,
+            1,
+            2000 * 10_u128.pow(6), // .8
+            None,
+            &mut user,
+            &user_key,
+            &mut liquidator,
+            &liquidator_key,
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            now,
+            slot,
+            liquidation_buffer,
+            LIQUIDATION_PCT_PRECISION / 10,
+            LIQUIDATION_PCT_PRECISION,
+            150,
+        )
+        .unwrap();
+
+        assert_eq!(user.last_active_slot, 1);
+        assert_eq!(user.liquidation_margin_freed, 0);
+    }
+
+    #[test]
+    pub fn failure_due_to_asset_tier_violation() {
+        let now = 0_i64;
+        let slot = 0_u64;
+
+        let mut sol_oracle_price = get_pyth_price(100, 6);
+        let sol_oracle_price_key =
+            Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+        let pyth_program = crate::ids::pyth_program::id();
+        create_account_info!(
+            sol_oracle_price,
+            &sol_oracle_price_key,
+            &pyth_program,
+            oracle_account_info
+        );
+        let mut oracle_map = OracleMap::load_one(&oracle_account_info, slot, None).unwrap();
+
+        let mut market = PerpMarket {
+            amm: AMM {
+                base_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                bid_base_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+                bid_quote_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+                ask_base_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+                ask_quote_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+                sqrt_k: 100 * AMM_RESERVE_PRECISION,
+                peg_multiplier: 100 * PEG_PRECISION,
+                max_slippage_ratio: 50,
+                max_fill_reserve_fraction: 100,
+                order_step_size: 10000000,
+                quote_asset_amount: 150 * QUOTE_PRECISION_I128,
+                base_asset_amount_with_amm: BASE_PRECISION_I128,
+                oracle: sol_oracle_price_key,
+                ..AMM::default()
+            },
+            margin_ratio_initial: 1000,
+            margin_ratio_maintenance: 500,
+            unrealized_pnl_initial_asset_weight: 9000,
+            unrealized_pnl_maintenance_asset_weight: 10000,
+            number_of_users_with_base: 1,
+            status: MarketStatus::Initialized,
+            liquidator_fee: LIQUIDATION_FEE_PRECISION / 100,
+            ..PerpMarket::default()
+        };
+        create_anchor_account_info!(market, PerpMarket, market_account_info);
+        let market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+
+        let mut usdc_market = SpotMarket {
+            market_index: 0,
+            oracle_source: OracleSource::QuoteAsset,
+            cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            decimals: 6,
+            initial_asset_weight: SPOT_WEIGHT_PRECISION,
+            maintenance_asset_weight: SPOT_WEIGHT_PRECISION,
+            deposit_balance: 200 * SPOT_BALANCE_PRECISION,
+            liquidator_fee: 0,
+            asset_tier: AssetTier::Collateral,
+            historical_oracle_data: HistoricalOracleData {
+                last_oracle_price_twap: PRICE_PRECISION as i64,
+                last_oracle_price_twap_5min: PRICE_PRECISION as i64,
+
+                ..HistoricalOracleData::default()
+            },
+            ..SpotMarket::default()
+        };
+        create_anchor_account_info!(usdc_market, SpotMarket, usdc_spot_market_account_info);
+        let mut sol_market = SpotMarket {
+            market_index: 1,
+            oracle_source: OracleSource::Pyth,
+            oracle: sol_oracle_price_key,
+            cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            cumulative_borrow_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            decimals: 6,
+            initial_asset_weight: 8 * SPOT_WEIGHT_PRECISION / 10,
+            maintenance_asset_weight: 9 * SPOT_WEIGHT_PRECISION / 10,
+            initial_liability_weight: 12 * SPOT_WEIGHT_PRECISION / 10,
+            maintenance_liability_weight: 11 * SPOT_WEIGHT_PRECISION / 10,
+            deposit_balance: 10 * SPOT_BALANCE_PRECISION,
+            borrow_balance: SPOT_BALANCE_PRECISION,
+            liquidator_fee: LIQUIDATION_FEE_PRECISION / 1000,
+            historical_oracle_data: HistoricalOracleData {
+                last_oracle_price_twap: (sol_oracle_price.agg.price * 99 / 100),
+                last_oracle_price_twap_5min: (sol_oracle_price.agg.price * 99 / 100),
+
+                ..HistoricalOracleData::default()
+            },
+            asset_tier: AssetTier::Collateral,
+            ..SpotMarket::default()
+        };
+        create_anchor_account_info!(sol_market, SpotMarket, sol_spot_market_account_info);
+        let spot_market_account_infos = Vec::from([
+            &usdc_spot_market_account_info,
+            &sol_spot_market_account_info,
+        ]);
+        let spot_market_map =
+            SpotMarketMap::load_multiple(spot_market_account_infos, true).unwrap();
+
+        let mut spot_positions = [SpotPosition::default(); 8];
+        spot_positions[0] = SpotPosition {
+            market_index: 0,
+            balance_type: SpotBalanceType::Deposit,
+            scaled_balance: 200 * SPOT_BALANCE_PRECISION_U64,
+            ..SpotPosition::default()
+        };
+        spot_positions[1] = SpotPosition {
+            market_index: 1,
+            balance_type: SpotBalanceType::Borrow,
+            scaled_balance: SPOT_BALANCE_PRECISION_U64,
+            ..SpotPosition::default()
+        };
+        let mut user = User {
+            orders: [Order::default(); 32],
+            perp_positions: get_positions(PerpPosition {
+                market_index: 0,
+                quote_asset_amount: -100 * QUOTE_PRECISION_I64,
+                ..PerpPosition::default()
+            }),
+            spot_positions,
+            ..User::default()
+        };
+
+        let mut liquidator = User {
+            spot_positions: get_spot_positions(SpotPosition {
+                market_index: 0,
+                balance_type: SpotBalanceType::Deposit,
+                scaled_balance: 2500 * SPOT_BALANCE_PRECISION_U64,
+                ..SpotPosition::default()
+            }),
+            ..User::default()
+        };
+
+        let user_key = Pubkey::default();
+        let liquidator_key = Pubkey::default();
+
+        assert!(liquidate_perp_pnl_for_deposit(
+            0,
+            0,
+            50 * 10_u128.pow(6), // .8
+            None,
+            &mut user,
+            &user_key,
+            &mut liquidator,
+            &liquidator_key,
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            now,
+            slot,
+            10,
+            PERCENTAGE_PRECISION,
+            LIQUIDATION_PCT_PRECISION,
+            150,
+        )
+        .is_err());
+
+        let state = State {
+            liquidation_margin_buffer_ratio: MARGIN_PRECISION as u32 / 50,
+            initial_pct_to_liquidate: (PERCENTAGE_PRECISION / 10) as u16,
+            initial_liquidator_fee_pct: LIQUIDATION_PCT_PRECISION as u16,
+            liquidation_duration: 150,
+            ..Default::default()
+        };
+
+        liquidate_spot(
+            0,
+            1,
+            10_u128.pow(9),
+            None,
+            &mut user,
+            &user_key,
+            &mut liquidator,
+            &liquidator_key,
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            now,
+            slot,
+            &state,
+        )
+        .unwrap();
+
+        assert_eq!(user.spot_positions[1].scaled_balance, 0);
+
+        liquidate_perp_pnl_for_deposit(
+            0,
+            0,
+            50 * 10_u128.pow(6), // .8
+            None,
+            &mut user,
+            &user_key,
+            &mut liquidator,
+            &liquidator_key,
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            now,
+            slot,
+            10,
+            PERCENTAGE_PRECISION,
+            LIQUIDATION_PCT_PRECISION,
+            150,
+        )
+        .unwrap();
+        assert_eq!(user.perp_positions[0].quote_asset_amount, -50000000);
+        assert_eq!(user.spot_positions[0].scaled_balance, 49394850000); // <$50
+        assert_eq!(user.status, UserStatus::BeingLiquidated as u8);
+
+        liquidate_perp_pnl_for_deposit(
+            0,
+            0,
+            50 * 10_u128.pow(6), // .8
+            None,
+            &mut user,
+            &user_key,
+            &mut liquidator,
+            &liquidator_key,
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            now,
+            slot,
+            10,
+            PERCENTAGE_PRECISION,
+            LIQUIDATION_PCT_PRECISION,
+            150,
+        )
+        .unwrap();
+        assert_eq!(user.spot_positions[0].scaled_balance, 0);
+        assert_eq!(user.spot_positions[1].scaled_balance, 0);
+
+        assert_eq!(user.perp_positions[0].quote_asset_amount, -1099098);
+        assert_eq!(user.status, UserStatus::Bankrupt as u8);
+    }
+
+    #[test]
+    pub fn failure_due_to_contract_tier_violation() {
+        let now = 0_i64;
+        let slot = 0_u64;
+
+        let mut sol_oracle_price = get_pyth_price(100, 6);
+        let sol_oracle_price_key =
+            Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+        let pyth_program = crate::ids::pyth_program::id();
+        create_account_info!(
+            sol_oracle_price,
+            &sol_oracle_price_key,
+            &pyth_program,
+            oracle_account_info
+        );
+        let mut oracle_map = OracleMap::load_one(&oracle_account_info, slot, None).unwrap();
+
+        let mut market = PerpMarket {
+            amm: AMM {
+                base_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                bid_base_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+                bid_quote_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+                ask_base_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+                ask_quote_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+                sqrt_k: 100 * AMM_RESERVE_PRECISION,
+                peg_multiplier: 100 * PEG_PRECISION,
+                max_slippage_ratio: 50,
+                max_fill_reserve_fraction: 100,
+                order_step_size: 10000000,
+                quote_asset_amount: 150 * QUOTE_PRECISION_I128,
+                base_asset_amount_with_amm: BASE_PRECISION_I128,
+                oracle: sol_oracle_price_key,
+                ..AMM::default()
+            },
+            margin_ratio_initial: 1000,
+            margin_ratio_maintenance: 500,
+            unrealized_pnl_initial_asset_weight: 9000,
+            unrealized_pnl_maintenance_asset_weight: 10000,
+            number_of_users_with_base: 1,
+            status: MarketStatus::Initialized,
+            liquidator_fee: LIQUIDATION_FEE_PRECISION / 100,
+            market_index: 0,
+            contract_tier: ContractTier::A,
+            ..PerpMarket::default()
+        };
+        create_anchor_account_info!(market, PerpMarket, market_account_info);
+
+        let mut bonk_market = PerpMarket {
+            amm: AMM {
+                base_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                bid_base_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+                bid_quote_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+                ask_base_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+                ask_quote_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+                sqrt_k: 100 * AMM_RESERVE_PRECISION,
+                peg_multiplier: 8000,
+                max_slippage_ratio: 50,
+                max_fill_reserve_fraction: 100,
+                order_step_size: 10000000,
+                quote_asset_amount: 150 * QUOTE_PRECISION_I128,
+                base_asset_amount_with_amm: BASE_PRECISION_I128,
+                oracle: sol_oracle_price_key,
+                ..AMM::default()
+            },
+            margin_ratio_initial: 1000,
+            margin_ratio_maintenance: 500,
+            unrealized_pnl_initial_asset_weight: 9000,
+            unrealized_pnl_maintenance_asset_weight: 10000,
+            number_of_users_with_base: 1,
+            status: MarketStatus::Initialized,
+            liquidator_fee: LIQUIDATION_FEE_PRECISION / 100,
+            contract_tier: ContractTier::Speculative,
+            market_index: 1,
+            ..PerpMarket::default()
+        };
+        create_anchor_account_info!(bonk_market, PerpMarket, bonk_market_account_info);
+
+        let market_map = PerpMarketMap::load_multiple(
+            vec![&market_account_info, &bonk_market_account_info],
+            true,
+        )
+        .unwrap();
+
+        let mut usdc_market = SpotMarket {
+            market_index: 0,
+            oracle_source: OracleSource::QuoteAsset,
+            cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            decimals: 6,
+            initial_asset_weight: SPOT_WEIGHT_PRECISION,
+            maintenance_asset_weight: SPOT_WEIGHT_PRECISION,
+            deposit_balance: 200 * SPOT_BALANCE_PRECISION,
+            liquidator_fee: 0,
+            historical_oracle_data: HistoricalOracleData {
+                last_oracle_price_twap: QUOTE_PRECISION_I64,
+                last_oracle_price_twap_5min: QUOTE_PRECISION_I64,
+                ..HistoricalOracleData::default()
+            },
+            ..SpotMarket::default()
+        };
+        create_anchor_account_info!(usdc_market, SpotMarket, usdc_spot_market_account_info);
+        let mut sol_market = SpotMarket {
+            market_index: 1,
+            oracle_source: OracleSource::Pyth,
+            oracle: sol_oracle_price_key,
+            cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            cumulative_borrow_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            decimals: 6,
+            initial_asset_weight: 8 * SPOT_WEIGHT_PRECISION / 10,
+            maintenance_asset_weight: 9 * SPOT_WEIGHT_PRECISION / 10,
+            initial_liability_weight: 12 * SPOT_WEIGHT_PRECISION / 10,
+            maintenance_liability_weight: 11 * SPOT_WEIGHT_PRECISION / 10,
+            deposit_balance: SPOT_BALANCE_PRECISION,
+            borrow_balance: 0,
+            liquidator_fee: LIQUIDATION_FEE_PRECISION / 1000,
+            historical_oracle_data: HistoricalOracleData {
+                last_oracle_price_twap: (sol_oracle_price.agg.price * 99 / 100),
+                last_oracle_price_twap_5min: (sol_oracle_price.agg.price * 99 / 100),
+                ..HistoricalOracleData::default()
+            },
+            ..SpotMarket::default()
+        };
+        create_anchor_account_info!(sol_market, SpotMarket, sol_spot_market_account_info);
+        let spot_market_account_infos = Vec::from([
+            &usdc_spot_market_account_info,
+            &sol_spot_market_account_info,
+        ]);
+        let spot_market_map =
+            SpotMarketMap::load_multiple(spot_market_account_infos, true).unwrap();
+
+        let mut spot_positions = [SpotPosition::default(); 8];
+        spot_positions[0] = SpotPosition {
+            market_index: 0,
+            balance_type: SpotBalanceType::Deposit,
+            scaled_balance: 200 * SPOT_BALANCE_PRECISION_U64,
+            ..SpotPosition::default()
+        };
+        spot_positions[1] = SpotPosition {
+            market_index: 1,
+            balance_type: SpotBalanceType::Deposit,
+            scaled_balance: SPOT_BALANCE_PRECISION_U64 / 1000,
+            ..SpotPosition::default()
+        };
+        let mut user = User {
+            orders: [Order::default(); 32],
+            perp_positions: get_positions(PerpPosition {
+                market_index: 0,
+                quote_asset_amount: -100 * QUOTE_PRECISION_I64,
+                ..PerpPosition::default()
+            }),
+            spot_positions,
+            ..User::default()
+        };
+
+        user.perp_positions[1] = PerpPosition {
+            market_index: 1,
+            quote_asset_amount: -150 * QUOTE_PRECISION_I64,
+            ..PerpPosition::default()
+        };
+
+        let mut liquidator = User {
+            spot_positions: get_spot_positions(SpotPosition {
+                market_index: 0,
+                balance_type: SpotBalanceType::Deposit,
+                scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
+                ..SpotPosition::default()
+            }),
+            ..User::default()
+        };
+
+        let user_key = Pubkey::default();
+        let liquidator_key = Pubkey::default();
+
+        assert!(liquidate_perp_pnl_for_deposit(
+            1,
+            0,
+            50 * 10_u128.pow(6), // .8
+            None,
+            &mut user,
+            &user_key,
+            &mut liquidator,
+            &liquidator_key,
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            now,
+            slot,
+            10,
+            PERCENTAGE_PRECISION,
+            LIQUIDATION_PCT_PRECISION,
+            150,
+        )
+        .is_err());
+        assert_eq!(user.perp_positions[0].quote_asset_amount, -100000000);
+
+        liquidate_perp_pnl_for_deposit(
+            0,
+            0,
+            5000 * 10_u128.pow(6),
+            None,
+            &mut user,
+            &user_key,
+            &mut liquidator,
+            &liquidator_key,
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            now,
+            slot,
+            10,
+            PERCENTAGE_PRECISION,
+            LIQUIDATION_PCT_PRECISION,
+            150,
+        )
+        .unwrap();
+        assert_eq!(user.perp_positions[0].quote_asset_amount, 0);
+
+        liquidate_perp_pnl_for_deposit(
+            1,
+            0,
+            50 * 10_u128.pow(6), // .8
+            None,
+            &mut user,
+            &user_key,
+            &mut liquidator,
+            &liquidator_key,
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            now,
+            slot,
+            10,
+            PERCENTAGE_PRECISION,
+            LIQUIDATION_PCT_PRECISION,
+            150,
+        )
+        .unwrap();
+
+        assert_eq!(user.spot_positions[0].scaled_balance, 48484849000);
+        assert_eq!(user.perp_positions[0].quote_asset_amount, 0);
+        assert_eq!(user.perp_positions[1].quote_asset_amount, -100000000);
+
+        assert_eq!(
+            liquidator.spot_positions[1].balance_type,
+            SpotBalanceType::Deposit
+        );
+        assert_eq!(liquidator.spot_positions[1].scaled_balance, 0);
+        assert_eq!(liquidator.perp_positions[0].quote_asset_amount, -100000000);
+    }
+}
+
+pub mod resolve_perp_bankruptcy {
+    use std::str::FromStr;
+
+    use anchor_lang::Owner;
+    use solana_program::pubkey::Pubkey;
+
+    use crate::controller::funding::settle_funding_payment;
+    use crate::controller::liquidation::resolve_perp_bankruptcy;
+    use crate::controller::position::PositionDirection;
+    use crate::create_anchor_account_info;
+    use crate::math::constants::{
+        AMM_RESERVE_PRECISION, BASE_PRECISION_I128, BASE_PRECISION_I64, BASE_PRECISION_U64,
+        FUNDING_RATE_PRECISION_I128, FUNDING_RATE_PRECISION_I64, LIQUIDATION_FEE_PRECISION,
+        PEG_PRECISION, QUOTE_PRECISION_I128, QUOTE_PRECISION_I64, QUOTE_SPOT_MARKET_INDEX,
+        SPOT_BALANCE_PRECISION, SPOT_BALANCE_PRECISION_U64, SPOT_CUMULATIVE_INTEREST_PRECISION,
+        SPOT_WEIGHT_PRECISION,
+    };
+    use crate::state::oracle::{HistoricalOracleData, OracleSource};
+    use crate::state::oracle_map::OracleMap;
+    use crate::state::perp_market::{MarketStatus, PerpMarket, PoolBalance, AMM};
+    use crate::state::perp_market_map::PerpMarketMap;
+    use crate::state::spot_market::{SpotBalanceType, SpotMarket};
+    use crate::state::spot_market_map::SpotMarketMap;
+    use crate::state::user::{
+        Order, OrderStatus, OrderType, PerpPosition, SpotPosition, User, UserStatus,
+    };
+    use crate::test_utils::*;
+    use crate::test_utils::{get_orders, get_positions, get_pyth_price, get_spot_positions};
+    use crate::{create_account_info, PRICE_PRECISION_I64};
+
+    #[test]
+    pub fn successful_resolve_perp_bankruptcy() {
+        let now = 0_i64;
+        let slot = 0_u64;
+
+        let mut oracle_price = get_pyth_price(100, 6);
+        let oracle_price_key =
+            Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+        let pyth_program = crate::ids::pyth_program::id();
+        create_account_info!(
+            oracle_price,
+            &oracle_price_key,
+            &pyth_program,
+            oracle_account_info
+        );
+        let mut oracle_map = OracleMap::load_one(&oracle_account_info, slot, None).unwrap();
+
+        let mut market = PerpMarket {
+            amm: AMM {
+                base_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                bid_base_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+                bid_quote_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+                ask_base_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+                ask_quote_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+                sqrt_k: 100 * AMM_RESERVE_PRECISION,
+                peg_multiplier: 100 * PEG_PRECISION,
+                max_slippage_ratio: 50,
+                max_fill_reserve_fraction: 100,
+                order_step_size: 10000000,
+                quote_asset_amount: -150 * QUOTE_PRECISION_I128,
+                base_asset_amount_long: 5 * BASE_PRECISION_I128,
+                base_asset_amount_short: -5 * BASE_PRECISION_I128,
+                base_asset_amount_with_amm: BASE_PRECISION_I128,
+                oracle: oracle_price_key,
+                cumulative_funding_rate_long: 1000 * FUNDING_RATE_PRECISION_I128,
+                cumulative_funding_rate_short: -1000 * FUNDING_RATE_PRECISION_I128,
+                ..AMM::default()
+            },
+            margin_ratio_initial: 1000,
+            margin_ratio_maintenance: 500,
+            status: MarketStatus::Initialized,
+            liquidator_fee: LIQUIDATION_FEE_PRECISION / 100,
+            number_of_users: 1,
+            ..PerpMarket::default()
+        };
+        create_anchor_account_info!(market, PerpMarket, market_account_info);
+        let market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+
+        let mut spot_market = SpotMarket {
+            market_index: 0,
+            oracle_source: OracleSource::QuoteAsset,
+            cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            decimals: 6,
+            initial_asset_weight: SPOT_WEIGHT_PRECISION,
+            historical_oracle_data: HistoricalOracleData {
+                last_oracle_price_twap: PRICE_PRECISION_I64,
+                last_oracle_price_twap_5min: PRICE_PRECISION_I64,
+                ..HistoricalOracleData::default()
+            },
+            ..SpotMarket::default()
+        };
+        create_anchor_account_info!(spot_market, SpotMarket, spot_market_account_info);
+        let spot_market_map = SpotMarketMap::load_one(&spot_market_account_info, true).unwrap();
+
+        let mut user = User {
+            orders: get_orders(Order {
+                market_index: 0,
+                status: OrderStatus::Open,
+                order_type: OrderType::Limit,
+                direction: PositionDirection::Long,
+                base_asset_amount: BASE_PRECISION_U64,
+                slot: 0,
+                ..Order::default()
+            }),
+            perp_positions: get_positions(PerpPosition {
+                market_index: 0,
+                base_asset_amount: 0,
+                quote_asset_amount: -100 * QUOTE_PRECISION_I64,
+                quote_entry_amount: -100 * QUOTE_PRECISION_I64,
+                quote_break_even_amount: -100 * QUOTE_PRECISION_I64,
+                open_orders: 1,
+                open_bids: BASE_PRECISION_I64,
+                ..PerpPosition::default()
+            }),
+            spot_positions: [SpotPosition::default(); 8],
+            status: UserStatus::Bankrupt as u8,
+            next_liquidation_id: 2,
+            ..User::default()
+        };
+
+        let mut liquidator = User {
+            spot_positions: get_spot_positions(SpotPosition {
+                market_index: 0,
+                balance_type: SpotBalanceType::Deposit,
+                scaled_balance: 50 * SPOT_BALANCE_PRECISION_U64,
+                ..SpotPosition::default()
+            }),
+            ..User::default()
+        };
+
+        let user_key = Pubkey::default();
+        let liquidator_key = Pubkey::default();
+
+        let mut expected_user = user;
+        expected_user.status = 0;
+        expected_user.perp_positions[0].quote_asset_amount = 0;
+        expected_user.total_social_loss = 100000000;
+
+        let mut expected_market = market;
+        expected_market.amm.cumulative_funding_rate_long = 1010 * FUNDING_RATE_PRECISION_I128;
+        expected_market.amm.cumulative_funding_rate_short = -1010 * FUNDING_RATE_PRECISION_I128;
+        expected_market.amm.total_social_loss = 100000000;
+        expected_market.amm.quote_asset_amount = -50 * QUOTE_PRECISION_I128;
+        expected_market.number_of_users = 0;
+
+        resolve_perp_bankruptcy(
+            0,
+            &mut user,
+            &user_key,
+            &mut liquidator,
+            &liquidator_key,
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            now,
+            0,
+        )
+        .unwrap();
+
+        assert_eq!(expected_user, user);
+        assert_eq!(expected_market, market_map.get_ref(&0).unwrap().clone());
+
+        let mut affected_long_user = User {
+            orders: [Order::default(); 32],
+            perp_positions: get_positions(PerpPosition {
+                market_index: 0,
+                base_asset_amount: 5 * BASE_PRECISION_I64,
+                quote_asset_amount: -500 * QUOTE_PRECISION_I64,
+                quote_break_even_amount: -500 * QUOTE_PRECISION_I64,
+                quote_entry_amount: -500 * QUOTE_PRECISION_I64,
+                open_bids: BASE_PRECISION_I64,
+                last_cumulative_funding_rate: 1000 * FUNDING_RATE_PRECISION_I64,
+                ..PerpPosition::default()
+            }),
+            spot_positions: [SpotPosition::default(); 8],
+            ..User::default()
+        };
+
+        let mut expected_affected_long_user = affected_long_user;
+        expected_affected_long_user.perp_positions[0].quote_asset_amount =
+            -550 * QUOTE_PRECISION_I64; // loses $50
+        expected_affected_long_user.perp_positions[0].quote_break_even_amount =
+            -550 * QUOTE_PRECISION_I64; // loses $50
+        expected_affected_long_user.perp_positions[0].last_cumulative_funding_rate =
+            1010 * FUNDING_RATE_PRECISION_I64;
+        expected_affected_long_user.cumulative_perp_funding = -50 * QUOTE_PRECISION_I64;
+
+        {
+            let mut market = market_map.get_ref_mut(&0).unwrap();
+            settle_funding_payment(
+                &mut affected_long_user,
+                &Pubkey::default(),
+                &mut market,
+                now,
+            )
+            .unwrap()
+        }
+
+        assert_eq!(expected_affected_long_user, affected_long_user);
+
+        let mut affected_short_user = User {
+            orders: [Order::default(); 32],
+            perp_positions: get_positions(PerpPosition {
+                market_index: 0,
+                base_asset_amount: -5 * BASE_PRECISION_I64,
+                quote_asset_amount: 500 * QUOTE_PRECISION_I64,
+                quote_entry_amount: 500 * QUOTE_PRECISION_I64,
+                quote_break_even_amount: 500 * QUOTE_PRECISION_I64,
+                open_bids: BASE_PRECISION_I64,
+                last_cumulative_funding_rate: -1000 * FUNDING_RATE_PRECISION_I64,
+                ..PerpPosition::default()
+            }),
+            spot_positions: [SpotPosition::default(); 8],
+            ..User::default()
+        };
+
+        let mut expected_affected_short_user = affected_short_user;
+        expected_affected_short_user.perp_positions[0].quote_asset_amount =
+            450 * QUOTE_PRECISION_I64; // loses $50
+        expected_affected_short_user.perp_positions[0].quote_break_even_amount =
+            450 * QUOTE_PRECISION_I64; // loses $50
+        expected_affected_short_user.perp_positions[0].last_cumulative_funding_rate =
+            -1010 * FUNDING_RATE_PRECISION_I64;
+        expected_affected_short_user.cumulative_perp_funding = -50 * QUOTE_PRECISION_I64;
+
+        {
+            let mut market = market_map.get_ref_mut(&0).unwrap();
+            settle_funding_payment(
+                &mut affected_short_user,
+                &Pubkey::default(),
+                &mut market,
+                now,
+            )
+            .unwrap()
+        }
+
+        assert_eq!(expected_affected_short_user, affected_short_user);
+    }
+
+    #[test]
+    pub fn successful_resolve_perp_bankruptcy_with_fee_pool() {
+        let now = 0_i64;
+        let slot = 0_u64;
+
+        let mut oracle_price = get_pyth_price(100, 6);
+        let oracle_price_key =
+            Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+        let pyth_program = crate::ids::pyth_program::id();
+        create_account_info!(
+            oracle_price,
+            &oracle_price_key,
+            &pyth_program,
+            oracle_account_info
+        );
+        let mut oracle_map = OracleMap::load_one(&oracle_account_info, slot, None).unwrap();
+
+        let mut market = PerpMarket {
+            amm: AMM {
+                base_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                bid_base_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+                bid_quote_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+                ask_base_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+                ask_quote_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+                sqrt_k: 100 * AMM_RESERVE_PRECISION,
+                peg_multiplier: 100 * PEG_PRECISION,
+                max_slippage_ratio: 50,
+                max_fill_reserve_fraction: 100,
+                order_step_size: 10000000,
+                quote_asset_amount: -150 * QUOTE_PRECISION_I128,
+                base_asset_amount_long: 5 * BASE_PRECISION_I128,
+                base_asset_amount_short: -5 * BASE_PRECISION_I128,
+                base_asset_amount_with_amm: BASE_PRECISION_I128,
+                oracle: oracle_price_key,
+                cumulative_funding_rate_long: 1000 * FUNDING_RATE_PRECISION_I128,
+                cumulative_funding_rate_short: -1000 * FUNDING_RATE_PRECISION_I128,
+                fee_pool: PoolBalance {
+                    scaled_balance: 50 * SPOT_BALANCE_PRECISION,
+                    market_index: QUOTE_SPOT_MARKET_INDEX,
+                    ..PoolBalance::default()
+                },
+                ..AMM::default()
+            },
+            margin_ratio_initial: 1000,
+            margin_ratio_maintenance: 500,
+            status: MarketStatus::Initialized,
+            liquidator_fee: LIQUIDATION_FEE_PRECISION / 100,
+            number_of_users: 1,
+            ..PerpMarket::default()
+        };
+        create_anchor_account_info!(market, PerpMarket, market_account_info);
+        let market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+
+        let mut spot_market = SpotMarket {
+            market_index: 0,
+            deposit_balance: 500 * SPOT_BALANCE_PRECISION,
+            oracle_source: OracleSource::QuoteAsset,
+            cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            decimals: 6,
+            initial_asset_weight: SPOT_WEIGHT_PRECISION,
+            ..SpotMarket::default()
+        };
+        create_anchor_account_info!(spot_market, SpotMarket, spot_market_account_info);
+        let spot_market_map = SpotMarketMap::load_one(&spot_market_account_info, true).unwrap();
+
+        let mut user = User {
+            orders: get_orders(Order {
+                market_index: 0,
+                status: OrderStatus::Open,
+                order_type: OrderType::Limit,
+                direction: PositionDirection::Long,
+                base_asset_amount: BASE_PRECISION_U64,
+                slot: 0,
+                ..Order::default()
+            }),
+            perp_positions: get_positions(PerpPosition {
+                market_index: 0,
+                base_asset_amount: 0,
+                quote_asset_amount: -100 * QUOTE_PRECISION_I64,
+                quote_entry_amount: -100 * QUOTE_PRECISION_I64,
+                quote_break_even_amount: -100 * QUOTE_PRECISION_I64,
+                open_orders: 1,
+                open_bids: BASE_PRECISION_I64,
+                ..PerpPosition::default()
+            }),
+            spot_positions: [SpotPosition::default(); 8],
+            status: UserStatus::Bankrupt as u8,
+            next_liquidation_id: 2,
+            ..User::default()
+        };
+
+        let mut liquidator = User {
+            spot_positions: get_spot_positions(SpotPosition {
+                market_index: 0,
+                balance_type: SpotBalanceType::Deposit,
+                scaled_balance: 50 * SPOT_BALANCE_PRECISION_U64,
+                ..SpotPosition::default()
+            }),
+            ..User::default()
+        };
+
+        let user_key = Pubkey::default();
+        let liquidator_key = Pubkey::default();
+
+        let mut expected_user = user;
+        expected_user.status = 0;
+        expected_user.perp_positions[0].quote_asset_amount = 0;
+        expected_user.total_social_loss = 100000000;
+
+        let mut expected_market = market;
+        expected_market.amm.cumulative_funding_rate_long = 1005 * FUNDING_RATE_PRECISION_I128;
+        expected_market.amm.cumulative_funding_rate_short = -1005 * FUNDING_RATE_PRECISION_I128;
+        expected_market.amm.total_social_loss = 50000000;
+        expected_market.amm.quote_asset_amount = -50 * QUOTE_PRECISION_I128;
+        expected_market.number_of_users = 0;
+        expected_market.amm.fee_pool.scaled_balance = 0;
+
+        resolve_perp_bankruptcy(
+            0,
+            &mut user,
+            &user_key,
+            &mut liquidator,
+            &liquidator_key,
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            now,
+            0,
+        )
+        .unwrap();
+
+        assert_eq!(user.total_social_loss, 100000000);
+        assert_eq!(expected_user, user);
+        assert_eq!(expected_market, market_map.get_ref(&0).unwrap().clone());
+
+        let mut affected_long_user = User {
+            orders: [Order::default(); 32],
+            perp_positions: get_positions(PerpPosition {
+                market_index: 0,
+                base_asset_amount: 5 * BASE_PRECISION_I64,
+                quote_asset_amount: -500 * QUOTE_PRECISION_I64,
+                quote_break_even_amount: -500 * QUOTE_PRECISION_I64,
+                quote_entry_amount: -500 * QUOTE_PRECISION_I64,
+                open_bids: BASE_PRECISION_I64,
+                last_cumulative_funding_rate: 1000 * FUNDING_RATE_PRECISION_I64,
+                ..PerpPosition::default()
+            }),
+            spot_positions: [SpotPosition::default(); 8],
+            ..User::default()
+        };
+
+        let mut expected_affected_long_user = affected_long_user;
+        expected_affected_long_user.perp_positions[0].quote_asset_amount =
+            -525 * QUOTE_PRECISION_I64; // loses $50
+        expected_affected_long_user.perp_positions[0].quote_break_even_amount =
+            -525 * QUOTE_PRECISION_I64; // loses $50
+        expected_affected_long_user.perp_positions[0].last_cumulative_funding_rate =
+            1005 * FUNDING_RATE_PRECISION_I64;
+        expected_affected_long_user.cumulative_perp_funding = -25 * QUOTE_PRECISION_I64;
+
+        {
+            let mut market = market_map.get_ref_mut(&0).unwrap();
+            settle_funding_payment(
+                &mut affected_long_user,
+                &Pubkey::default(),
+                &mut market,
+                now,
+            )
+            .unwrap()
+        }
+
+        assert_eq!(expected_affected_long_user, affected_long_user);
+
+        let mut affected_short_user = User {
+            orders: [Order::default(); 32],
+            perp_positions: get_positions(PerpPosition {
+                market_index: 0,
+                base_asset_amount: -5 * BASE_PRECISION_I64,
+                quote_asset_amount: 500 * QUOTE_PRECISION_I64,
+                quote_entry_amount: 500 * QUOTE_PRECISION_I64,
+                quote_break_even_amount: 500 * QUOTE_PRECISION_I64,
+                open_bids: BASE_PRECISION_I64,
+                last_cumulative_funding_rate: -1000 * FUNDING_RATE_PRECISION_I64,
+                ..PerpPosition::default()
+            }),
+            spot_positions: [SpotPosition::default(); 8],
+            ..User::default()
+        };
+
+        let mut expected_affected_short_user = affected_short_user;
+        expected_affected_short_user.perp_positions[0].quote_asset_amount =
+            475 * QUOTE_PRECISION_I64; // loses $50
+        expected_affected_short_user.perp_positions[0].quote_break_even_amount =
+            475 * QUOTE_PRECISION_I64; // loses $50
+        expected_affected_short_user.perp_positions[0].last_cumulative_funding_rate =
+            -1005 * FUNDING_RATE_PRECISION_I64;
+        expected_affected_short_user.cumulative_perp_funding = -25 * QUOTE_PRECISION_I64;
+
+        {
+            let mut market = market_map.get_ref_mut(&0).unwrap();
+            settle_funding_payment(
+                &mut affected_short_user,
+                &Pubkey::default(),
+                &mut market,
+                now,
+            )
+            .unwrap()
+        }
+
+        assert_eq!(expected_affected_short_user, affected_short_user);
+    }
+}
+
+pub mod auto_deleverage_perp {
+    use std::str::FromStr;
+
+    use anchor_lang::prelude::AccountLoader;
+    use anchor_lang::Owner;
+    use solana_program::pubkey::Pubkey;
+
+    use crate::controller::liquidation::auto_deleverage_perp;
+    use crate::controller::position::PositionDirection;
+    use crate::create_anchor_account_info;
+    use crate::error::ErrorCode;
+    use crate::math::constants::{
+        AMM_RESERVE_PRECISION, BASE_PRECISION_I128, BASE_PRECISION_I64, BASE_PRECISION_U64,
+        PEG_PRECISION, PRICE_PRECISION_U64, QUOTE_PRECISION, QUOTE_PRECISION_I64,
+        SPOT_BALANCE_PRECISION, SPOT_BALANCE_PRECISION_U64, SPOT_CUMULATIVE_INTEREST_PRECISION,
+        SPOT_WEIGHT_PRECISION,
+    };
+    use crate::state::oracle::{HistoricalOracleData, OracleSource};
+    use crate::state::oracle_map::OracleMap;
+    use crate::state::perp_market::{InsuranceClaim, MarketStatus, PerpMarket, AMM};
+    use crate::state::perp_market_map::PerpMarketMap;
+    use crate::state::spot_market::{SpotBalanceType, SpotMarket};
+    use crate::state::spot_market_map::SpotMarketMap;
+    use crate::state::state::State;
+    use crate::state::user::{
+        MarketType, Order, OrderBitFlag, OrderStatus, OrderType, PerpPosition, SpotPosition, User,
+        UserStatus,
+    };
+    use crate::state::user_map::UserMap;
+    use crate::test_utils::*;
+    use crate::test_utils::{get_orders, get_positions, get_pyth_price, get_spot_positions};
+    use crate::{create_account_info, PRICE_PRECISION_I64};
+
+    // position linked take profit on a short
+    fn get_take_profit_order(base_asset_amount: u64) -> Order {
+        Order {
+            market_index: 0,
+            order_id: 1,
+            status: OrderStatus::Open,
+            order_type: OrderType::Limit,
+            market_type: MarketType::Perp,
+            direction: PositionDirection::Long,
+            base_asset_amount: base_asset_amount * BASE_PRECISION_U64,
+            price: 90 * PRICE_PRECISION_U64,
+            reduce_only: true,
+            bit_flags: OrderBitFlag::PositionLinked as u8,
+            ..Order::default()
+        }
+    }
+
+    #[test]
+    pub fn closes_against_opposite_side_at_bankruptcy_price_highest_score_first() {
+        let now = 0_i64;
+        let slot = 0_u64;
+
+        let mut oracle_price = get_pyth_price(100, 6);
+        let oracle_price_key =
+            Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+        let pyth_program = crate::ids::pyth_program::id();
+        create_account_info!(
+            oracle_price,
+            &oracle_price_key,
+            &pyth_program,
+            oracle_account_info
+        );
+        let mut oracle_map = OracleMap::load_one(&oracle_account_info, slot, None).unwrap();
+
+        let mut market = PerpMarket {
+            amm: AMM {
+                base_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                bid_base_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+                bid_quote_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+                ask_base_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+                ask_quote_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+                sqrt_k: 100 * AMM_RESERVE_PRECISION,
+                peg_multiplier: 100 * PEG_PRECISION,
+                order_step_size: 10000000,
+                base_asset_amount_long: 10 * BASE_PRECISION_I128,
+                base_asset_amount_short: -14 * BASE_PRECISION_I128,
+                oracle: oracle_price_key,
+                historical_oracle_data: HistoricalOracleData::default_price(oracle_price.agg.price),
+                ..AMM::default()
+            },
+            margin_ratio_initial: 1000,
+            margin_ratio_maintenance: 500,
+            unrealized_pnl_initial_asset_weight: 9000,
+            unrealized_pnl_maintenance_asset_weight: 10000,
+            number_of_users_with_base: 3,
+            number_of_users: 3,
+            status: MarketStatus::Initialized,
+            ..PerpMarket::default()
+        };
+        create_anchor_account_info!(market, PerpMarket, market_account_info);
+        let market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+
+        let mut spot_market = SpotMarket {
+            market_index: 0,
+            oracle_source: OracleSource::QuoteAsset,
+            cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            decimals: 6,
+            initial_asset_weight: SPOT_WEIGHT_PRECISION,
+            maintenance_asset_weight: SPOT_WEIGHT_PRECISION,
+            deposit_balance: 200 * SPOT_BALANCE_PRECISION,
+            historical_oracle_data: HistoricalOracleData {
+                last_oracle_price_twap: PRICE_PRECISION_I64,
+                last_oracle_price_twap_5min: PRICE_PRECISION_I64,
+                ..HistoricalOracleData::default()
+            },
+            ..SpotMarket::default()
+        };
+        create_anchor_account_info!(spot_market, SpotMarket, spot_market_account_info);
+        let spot_market_map = SpotMarketMap::load_one(&spot_market_account_info, true).unwrap();
+
+        // 10 long entered at $110 with no collateral, $100 underwater
+        let mut user = User {
+            perp_positions: get_positions(PerpPosition {
+                market_index: 0,
+                base_asset_amount: 10 * BASE_PRECISION_I64,
+                quote_asset_amount: -1100 * QUOTE_PRECISION_I64,
+                quote_entry_amount: -1100 * QUOTE_PRECISION_I64,
+                quote_break_even_amount: -1100 * QUOTE_PRECISION_I64,
+                ..PerpPosition::default()
+            }),
+            spot_positions: [SpotPosition::default(); 8],
+            status: UserStatus::BeingLiquidated as u8,
+            next_liquidation_id: 1,
+            ..User::default()
+        };
+        let user_key = Pubkey::default();
+
+        // 8 short entered at $105, smaller profit and lower score
+        let low_score_user_key = Pubkey::new_unique();
+        let mut low_score_user = User {
+            orders: get_orders(get_take_profit_order(8)),
+            perp_positions: get_positions(PerpPosition {
+                market_index: 0,
+                base_asset_amount: -8 * BASE_PRECISION_I64,
+                open_orders: 1,
+                open_bids: 8 * BASE_PRECISION_I64,
+                quote_asset_amount: 840 * QUOTE_PRECISION_I64,
+                quote_entry_amount: 840 * QUOTE_PRECISION_I64,
+                quote_break_even_amount: 840 * QUOTE_PRECISION_I64,
+                ..PerpPosition::default()
+            }),
+            spot_positions: get_spot_positions(SpotPosition {
+                market_index: 0,
+                balance_type: SpotBalanceType::Deposit,
+                scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
+                ..SpotPosition::default()
+            }),
+            ..User::default()
+        };
+        create_anchor_account_info!(
+            low_score_user,
+            &low_score_user_key,
+            User,
+            low_score_user_account_info
+        );
+
+        // 6 short entered at $120, comes after the other user in the map but ranks first
+        let high_score_user_key = Pubkey::new_unique();
+        let mut high_score_user = User {
+            orders: get_orders(get_take_profit_order(6)),
+            perp_positions: get_positions(PerpPosition {
+                market_index: 0,
+                base_asset_amount: -6 * BASE_PRECISION_I64,
+                open_orders: 1,
+                open_bids: 6 * BASE_PRECISION_I64,
+                quote_asset_amount: 720 * QUOTE_PRECISION_I64,
+                quote_entry_amount: 720 * QUOTE_PRECISION_I64,
+                quote_break_even_amount: 720 * QUOTE_PRECISION_I64,
+                ..PerpPosition::default()
+            }),
+            spot_positions: get_spot_positions(SpotPosition {
+                market_index: 0,
+                balance_type: SpotBalanceType::Deposit,
+                scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
+                ..SpotPosition::default()
+            }),
+            ..User::default()
+        };
+        create_anchor_account_info!(
+            high_score_user,
+            &high_score_user_key,
+            User,
+            high_score_user_account_info
+        );
+
+        let mut adl_users = UserMap::empty();
+        adl_users
+            .insert(
+                low_score_user_key,
+                AccountLoader::try_from(&low_score_user_account_info).unwrap(),
+            )
+            .unwrap();
+        adl_users
+            .insert(
+                high_score_user_key,
+                AccountLoader::try_from(&high_score_user_account_info).unwrap(),
+            )
+            .unwrap();
+
+        let state = State::default();
+
+        // oracle too far from its 5min twap
+        market_map
+            .get_ref_mut(&0)
+            .unwrap()
+            .amm
+            .historical_oracle_data
+            .last_oracle_price_twap_5min = 50 * PRICE_PRECISION_I64;
+        let result = auto_deleverage_perp(
+            0,
+            &mut user,
+            &user_key,
+            &adl_users,
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            now,
+            slot,
+            0,
+            &state,
+        );
+        assert_eq!(result, Err(ErrorCode::PriceBandsBreached));
+        market_map
+            .get_ref_mut(&0)
+            .unwrap()
+            .amm
+            .historical_oracle_data
+            .last_oracle_price_twap_5min = oracle_price.agg.price;
+
+        auto_deleverage_perp(
+            0,
+            &mut user,
+            &user_key,
+            &adl_users,
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            now,
+            slot,
+            0,
+            &state,
+        )
+        .unwrap();
+
+        // closed at the $110 bankruptcy price, nothing left to socialize
+        assert_eq!(user.perp_positions[0].base_asset_amount, 0);
+        assert_eq!(user.perp_positions[0].quote_asset_amount, 0);
+        assert!(!user.is_bankrupt());
+
+        // fully closed first, gives up $10 a contract versus the oracle
+        let high_score_user = adl_users.get_ref(&high_score_user_key).unwrap();
+        assert_eq!(high_score_user.perp_positions[0].base_asset_amount, 0);
+        assert_eq!(
+            high_score_user.perp_positions[0].quote_asset_amount,
+            60 * QUOTE_PRECISION_I64
+        );
+        assert_eq!(
+            high_score_user.total_social_loss,
+            60 * QUOTE_PRECISION as u64
+        );
+        // its take profit has nothing left to close
+        assert_eq!(high_score_user.orders[0], Order::default());
+        assert_eq!(high_score_user.perp_positions[0].open_orders, 0);
+        assert_eq!(high_score_user.perp_positions[0].open_bids, 0);
+
+        // covers the remaining 4
+        let low_score_user = adl_users.get_ref(&low_score_user_key).unwrap();
+        assert_eq!(
+            low_score_user.perp_positions[0].base_asset_amount,
+            -4 * BASE_PRECISION_I64
+        );
+        assert_eq!(
+            low_score_user.perp_positions[0].quote_asset_amount,
+            400 * QUOTE_PRECISION_I64
+        );
+        assert_eq!(
+            low_score_user.total_social_loss,
+            40 * QUOTE_PRECISION as u64
+        );
+        assert_eq!(low_score_user.orders[0].status, OrderStatus::Open);
+
+        let market = market_map.get_ref(&0).unwrap();
+        assert_eq!(market.amm.base_asset_amount_long, 0);
+        assert_eq!(market.amm.base_asset_amount_short, -4 * BASE_PRECISION_I128);
+    }
+
+    #[test]
+    pub fn rejects_same_side_users_and_losses_insurance_can_cover() {
+        let now = 0_i64;
+        let slot = 0_u64;
+
+        let mut oracle_price = get_pyth_price(100, 6);
+        let oracle_price_key =
+            Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+        let pyth_program = crate::ids::pyth_program::id();
+        create_account_info!(
+            oracle_price,
+            &oracle_price_key,
+            &pyth_program,
+            oracle_account_info
+        );
+        let mut oracle_map = OracleMap::load_one(&oracle_account_info, slot, None).unwrap();
+
+        let mut market = PerpMarket {
+            amm: AMM {
+                base_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                bid_base_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+                bid_quote_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+                ask_base_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+                ask_quote_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+                sqrt_k: 100 * AMM_RESERVE_PRECISION,
+                peg_multiplier: 100 * PEG_PRECISION,
+                order_step_size: 10000000,
+                base_asset_amount_long: 15 * BASE_PRECISION_I128,
+                base_asset_amount_short: -6 * BASE_PRECISION_I128,
+                oracle: oracle_price_key,
+                historical_oracle_data: HistoricalOracleData::default_price(oracle_price.agg.price),
+                ..AMM::default()
+            },
+            margin_ratio_initial: 1000,
+            margin_ratio_maintenance: 500,
+            unrealized_pnl_initial_asset_weight: 9000,
+            unrealized_pnl_maintenance_asset_weight: 10000,
+            number_of_users_with_base: 3,
+            number_of_users: 3,
+            status: MarketStatus::Initialized,
+            insurance_claim: InsuranceClaim {
+                quote_max_insurance: 1000 * QUOTE_PRECISION as u64,
+                ..InsuranceClaim::default()
+            },
+            ..PerpMarket::default()
+        };
+        create_anchor_account_info!(market, PerpMarket, market_account_info);
+        let market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+
+        let mut spot_market = SpotMarket {
+            market_index: 0,
+            oracle_source: OracleSource::QuoteAsset,
+            cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            decimals: 6,
+            initial_asset_weight: SPOT_WEIGHT_PRECISION,
+            maintenance_asset_weight: SPOT_WEIGHT_PRECISION,
+            deposit_balance: 200 * SPOT_BALANCE_PRECISION,
+            historical_oracle_data: HistoricalOracleData {
+                last_oracle_price_twap: PRICE_PRECISION_I64,
+                last_oracle_price_twap_5min: PRICE_PRECISION_I64,
+                ..HistoricalOracleData::default()
+            },
+            ..SpotMarket::default()
+        };
+        create_anchor_account_info!(spot_market, SpotMarket, spot_market_account_info);
+        let spot_market_map = SpotMarketMap::load_one(&spot_market_account_info, true).unwrap();
+
+        // 10 long entered at $110 with no collateral, $100 underwater
+        let mut user = User {
+            perp_positions: get_positions(PerpPosition {
+                market_index: 0,
+                base_asset_amount: 10 * BASE_PRECISION_I64,
+                quote_asset_amount: -1100 * QUOTE_PRECISION_I64,
+                quote_entry_amount: -1100 * QUOTE_PRECISION_I64,
+                quote_break_even_amount: -1100 * QUOTE_PRECISION_I64,
+                ..PerpPosition::default()
+            }),
+            spot_positions: [SpotPosition::default(); 8],
+            status: UserStatus::BeingLiquidated as u8,
+            next_liquidation_id: 1,
+            ..User::default()
+        };
+        let user_key = Pubkey::default();
+
+        // profitable, but long like the user
+        let same_side_user_key = Pubkey::new_unique();
+        let mut same_side_user = User {
+            perp_positions: get_positions(PerpPosition {
+                market_index: 0,
+                base_asset_amount: 5 * BASE_PRECISION_I64,
+                quote_asset_amount: -400 * QUOTE_PRECISION_I64,
+                quote_entry_amount: -400 * QUOTE_PRECISION_I64,
+                quote_break_even_amount: -400 * QUOTE_PRECISION_I64,
+                ..PerpPosition::default()
+            }),
+            spot_positions: get_spot_positions(SpotPosition {
+                market_index: 0,
+                balance_type: SpotBalanceType::Deposit,
+                scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
+                ..SpotPosition::default()
+            }),
+            ..User::default()
+        };
+        create_anchor_account_info!(
+            same_side_user,
+            &same_side_user_key,
+            User,
+            same_side_user_account_info
+        );
+        let same_side_users = UserMap::load_one(&same_side_user_account_info).unwrap();
+
+        let state = State::default();
+
+        let result = auto_deleverage_perp(
+            0,
+            &mut user,
+            &user_key,
+            &same_side_users,
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            now,
+            slot,
+            0,
+            &state,
+        );
+        assert_eq!(result, Err(ErrorCode::InvalidAutoDeleverage));
+
+        let opposite_side_user_key = Pubkey::new_unique();
+        let mut opposite_side_user = User {
+            perp_positions: get_positions(PerpPosition {
+                market_index: 0,
+                base_asset_amount: -6 * BASE_PRECISION_I64,
+                quote_asset_amount: 720 * QUOTE_PRECISION_I64,
+                quote_entry_amount: 720 * QUOTE_PRECISION_I64,
+                quote_break_even_amount: 720 * QUOTE_PRECISION_I64,
+                ..PerpPosition::default()
+            }),
+            spot_positions: get_spot_positions(SpotPosition {
+                market_index: 0,
+                balance_type: SpotBalanceType::Deposit,
+                scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
+                ..SpotPosition::default()
+            }),
+            ..User::default()
+        };
+        create_anchor_account_info!(
+            opposite_side_user,
+            &opposite_side_user_key,
+            User,
+            opposite_side_user_account_info
+        );
+        let opposite_side_users = UserMap::load_one(&opposite_side_user_account_info).unwrap();
+
+        // the insurance fund can cover the $100, it has to be used first
+        let result = auto_deleverage_perp(
+            0,
+            &mut user,
+            &user_key,
+            &opposite_side_users,
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            now,
+            slot,
+            1000 * QUOTE_PRECISION as u64,
+            &state,
+        );
+        assert_eq!(result, Err(ErrorCode::InvalidAutoDeleverage));
+
+        // only needs to cover what the insurance fund can't, $60 on 10 contracts
+        auto_deleverage_perp(
+            0,
+            &mut user,
+            &user_key,
+            &opposite_side_users,
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            now,
+            slot,
+            40 * QUOTE_PRECISION as u64 + 1,
+            &state,
+        )
+        .unwrap();
+
+        // 6 closed at the $106 bankruptcy price
+        assert_eq!(
+            user.perp_positions[0].base_asset_amount,
+            4 * BASE_PRECISION_I64
+        );
+        assert_eq!(
+            user.perp_positions[0].quote_asset_amount,
+            -464 * QUOTE_PRECISION_I64
+        );
+
+        let opposite_side_user = opposite_side_users
+            .get_ref(&opposite_side_user_key)
+            .unwrap();
+        assert_eq!(opposite_side_user.perp_positions[0].base_asset_amount, 0);
+        assert_eq!(
+            opposite_side_user.perp_positions[0].quote_asset_amount,
+            84 * QUOTE_PRECISION_I64
+        );
+        assert_eq!(
+            opposite_side_user.total_social_loss,
+            36 * QUOTE_PRECISION as u64
+        );
+    }
+}
+
+pub mod resolve_spot_bankruptcy {
+    use std::str::FromStr;
+
+    use anchor_lang::Owner;
+    use solana_program::pubkey::Pubkey;
+
+    use crate::controller::liquidation::resolve_spot_bankruptcy;
+    use crate::controller::position::PositionDirection;
+    use crate::create_account_info;
+    use crate::create_anchor_account_info;
+    use crate::math::constants::{
+        AMM_RESERVE_PRECISION, BASE_PRECISION_I128, BASE_PRECISION_U64,
+        FUNDING_RATE_PRECISION_I128, LIQUIDATION_FEE_PRECISION, PEG_PRECISION, QUOTE_PRECISION,
+        QUOTE_PRECISION_I128, QUOTE_PRECISION_I64, SPOT_BALANCE_PRECISION,
+        SPOT_BALANCE_PRECISION_U64, SPOT_CUMULATIVE_INTEREST_PRECISION, SPOT_WEIGHT_PRECISION,
+    };
+    use crate::math::spot_balance::get_token_amount;
+    use crate::state::oracle::{HistoricalOracleData, OracleSource};
+    use crate::state::oracle_map::OracleMap;
+    use crate::state::perp_market::{MarketStatus, PerpMarket, AMM};
+    use crate::state::perp_market_map::PerpMarketMap;
+    use crate::state::spot_market::{SpotBalanceType, SpotMarket};
+    use crate::state::spot_market_map::SpotMarketMap;
+    use crate::state::user::{
+        Order, OrderStatus, OrderType, PerpPosition, SpotPosition, User, UserStatus,
+    };
+    use crate::test_utils::*;
+    use crate::test_utils::{get_orders, get_pyth_price, get_spot_positions};
+
+    #[test]
+    pub fn successful_resolve_spot_bankruptcy() {
+        let now = 0_i64;
+        let slot = 0_u64;
+
+        let mut oracle_price = get_pyth_price(100, 6);
+        let oracle_price_key =
+            Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+        let pyth_program = crate::ids::pyth_program::id();
+        create_account_info!(
+            oracle_price,
+            &oracle_price_key,
+            &pyth_program,
+            oracle_account_info
+        );
+        let mut oracle_map = OracleMap::load_one(&oracle_account_info, slot, None).unwrap();
+
+        let mut market = PerpMarket {
+            amm: AMM {
+                base_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                bid_base_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+                bid_quote_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+                ask_base_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+                ask_quote_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+                sqrt_k: 100 * AMM_RESERVE_PRECISION,
+                peg_multiplier: 100 * PEG_PRECISION,
+                max_slippage_ratio: 50,
+                max_fill_reserve_fraction: 100,
+                order_step_size: 10000000,
+                quote_asset_amount: -150 * QUOTE_PRECISION_I128,
+                base_asset_amount_with_amm: BASE_PRECISION_I128,
+                base_asset_amount_long: 5 * BASE_PRECISION_I128,
+                base_asset_amount_short: -5 * BASE_PRECISION_I128,
+                oracle: oracle_price_key,
+                cumulative_funding_rate_long: 1000 * FUNDING_RATE_PRECISION_I128,
+                cumulative_funding_rate_short: -1000 * FUNDING_RATE_PRECISION_I128,
+                ..AMM::default()
+            },
+            margin_ratio_initial: 1000,
+            margin_ratio_maintenance: 500,
+            status: MarketStatus::Initialized,
+            liquidator_fee: LIQUIDATION_FEE_PRECISION / 100,
+            ..PerpMarket::default()
+        };
+        create_anchor_account_info!(market, PerpMarket, market_account_info);
+        let market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+
+        let mut spot_market = SpotMarket {
+            market_index: 0,
+            oracle_source: OracleSource::QuoteAsset,
+            cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            cumulative_borrow_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            decimals: 6,
+            initial_asset_weight: SPOT_WEIGHT_PRECISION,
+            deposit_balance: 1000 * SPOT_BALANCE_PRECISION,
+            borrow_balance: 100 * SPOT_BALANCE_PRECISION,
+            historical_oracle_data: HistoricalOracleData::default_price(QUOTE_PRECISION_I64),
+            ..SpotMarket::default()
+        };
+        create_anchor_account_info!(spot_market, SpotMarket, spot_market_account_info);
+        let spot_market_map = SpotMarketMap::load_one(&spot_market_account_info, true).unwrap();
+
+        let mut user = User {
+            orders: get_orders(Order {
+                market_index: 0,
+                status: OrderStatus::Open,
+                order_type: OrderType::Limit,
+                direction: PositionDirection::Long,
+                base_asset_amount: BASE_PRECISION_U64,
+                slot: 0,
+                ..Order::default()
+            }),
+            perp_positions: [PerpPosition::default(); 8],
+            spot_positions: get_spot_positions(SpotPosition {
+                market_index: 0,
+                scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
+                balance_type: SpotBalanceType::Borrow,
+                ..SpotPosition::default()
+            }),
+            status: UserStatus::Bankrupt as u8,
+            next_liquidation_id: 2,
+            ..User::default()
+        };
+
+        let mut liquidator = User {
+            spot_positions: get_spot_positions(SpotPosition {
+                market_index: 0,
+                balance_type: SpotBalanceType::Deposit,
+                scaled_balance: 50 * SPOT_BALANCE_PRECISION_U64,
+                ..SpotPosition::default()
+            }),
+            ..User::default()
+        };
+
+        let user_key = Pubkey::default();
+        let liquidator_key = Pubkey::default();
+
+        let mut expected_user = user;
+        expected_user.status = 0;
+        expected_user.spot_positions[0].scaled_balance = 0;
+        expected_user.spot_positions[0].cumulative_deposits = 100 * QUOTE_PRECISION_I64;
+        expected_user.total_social_loss = 100000000;
+
+        let mut expected_spot_market = spot_market;
+        expected_spot_market.borrow_balance = 0;
+        expected_spot_market.cumulative_deposit_interest =
+            9 * SPOT_CUMULATIVE_INTEREST_PRECISION / 10;
+        expected_spot_market.total_social_loss = 100 * QUOTE_PRECISION;
+        expected_spot_market.total_quote_social_loss = 100 * QUOTE_PRECISION;
+
+        resolve_spot_bankruptcy(
+            0,
             &mut user,
             &user_key,
             &mut liquidator,
@@ -6397,58 +8384,51 @@ pub mod liquidate_perp_pnl_for_deposit {
             &spot_market_map,
             &mut oracle_map,
             now,
-            slot,
-            10,
-            PERCENTAGE_PRECISION,
-            150,
+            0,
         )
         .unwrap();
 
-        assert_eq!(user.spot_positions[0].scaled_balance, 48484849000);
-        assert_eq!(user.perp_positions[0].quote_asset_amount, 0);
-        assert_eq!(user.perp_positions[1].quote_asset_amount, -100000000);
+        assert_eq!(expected_user, user);
+        assert_eq!(expected_spot_market, *spot_market_map.get_ref(&0).unwrap());
 
-        assert_eq!(
-            liquidator.spot_positions[1].balance_type,
-            SpotBalanceType::Deposit
-        );
-        assert_eq!(liquidator.spot_positions[1].scaled_balance, 0);
-        assert_eq!(liquidator.perp_positions[0].quote_asset_amount, -100000000);
+        let spot_market = spot_market_map.get_ref_mut(&0).unwrap();
+        let deposit_balance = spot_market.deposit_balance;
+        let deposit_token_amount =
+            get_token_amount(deposit_balance, &spot_market, &SpotBalanceType::Deposit).unwrap();
+
+        assert_eq!(deposit_token_amount, 900 * QUOTE_PRECISION);
     }
 }
 
-pub mod resolve_perp_bankruptcy {
+pub mod liquidate_user {
+    use crate::state::state::State;
     use std::str::FromStr;
 
     use anchor_lang::Owner;
     use solana_program::pubkey::Pubkey;
 
-    use crate::controller::funding::settle_funding_payment;
-    use crate::controller::liquidation::resolve_perp_bankruptcy;
-    use crate::controller::position::PositionDirection;
+    use crate::controller::liquidation::{liquidate_user, LiquidationStep};
+    use crate::create_account_info;
     use crate::create_anchor_account_info;
+    use crate::error::ErrorCode;
     use crate::math::constants::{
         AMM_RESERVE_PRECISION, BASE_PRECISION_I128, BASE_PRECISION_I64, BASE_PRECISION_U64,
-        FUNDING_RATE_PRECISION_I128, FUNDING_RATE_PRECISION_I64, LIQUIDATION_FEE_PRECISION,
-        PEG_PRECISION, QUOTE_PRECISION_I128, QUOTE_PRECISION_I64, QUOTE_SPOT_MARKET_INDEX,
-        SPOT_BALANCE_PRECISION, SPOT_BALANCE_PRECISION_U64, SPOT_CUMULATIVE_INTEREST_PRECISION,
-        SPOT_WEIGHT_PRECISION,
+        LIQUIDATION_FEE_PRECISION, LIQUIDATION_PCT_PRECISION, PEG_PRECISION, PRICE_PRECISION_U64,
+        QUOTE_PRECISION_I128, QUOTE_PRECISION_I64, SPOT_BALANCE_PRECISION,
+        SPOT_BALANCE_PRECISION_U64, SPOT_CUMULATIVE_INTEREST_PRECISION, SPOT_WEIGHT_PRECISION,
     };
     use crate::state::oracle::{HistoricalOracleData, OracleSource};
     use crate::state::oracle_map::OracleMap;
-    use crate::state::perp_market::{MarketStatus, PerpMarket, PoolBalance, AMM};
+    use crate::state::perp_market::{MarketStatus, PerpMarket, AMM};
     use crate::state::perp_market_map::PerpMarketMap;
     use crate::state::spot_market::{SpotBalanceType, SpotMarket};
     use crate::state::spot_market_map::SpotMarketMap;
-    use crate::state::user::{
-        Order, OrderStatus, OrderType, PerpPosition, SpotPosition, User, UserStatus,
-    };
+    use crate::state::user::{Order, PerpPosition, SpotPosition, User, UserStats};
     use crate::test_utils::*;
-    use crate::test_utils::{get_orders, get_positions, get_pyth_price, get_spot_positions};
-    use crate::{create_account_info, PRICE_PRECISION_I64};
+    use crate::test_utils::{get_positions, get_pyth_price, get_spot_positions};
 
     #[test]
-    pub fn successful_resolve_perp_bankruptcy() {
+    pub fn successful_liquidation_of_perp_and_pnl_for_deposit() {
         let now = 0_i64;
         let slot = 0_u64;
 
@@ -6478,23 +8458,22 @@ pub mod resolve_perp_bankruptcy {
                 max_fill_reserve_fraction: 100,
                 order_step_size: 10000000,
                 quote_asset_amount: -150 * QUOTE_PRECISION_I128,
-                base_asset_amount_long: 5 * BASE_PRECISION_I128,
-                base_asset_amount_short: -5 * BASE_PRECISION_I128,
                 base_asset_amount_with_amm: BASE_PRECISION_I128,
                 oracle: oracle_price_key,
-                cumulative_funding_rate_long: 1000 * FUNDING_RATE_PRECISION_I128,
-                cumulative_funding_rate_short: -1000 * FUNDING_RATE_PRECISION_I128,
+                historical_oracle_data: HistoricalOracleData::default_price(oracle_price.agg.price),
                 ..AMM::default()
             },
             margin_ratio_initial: 1000,
             margin_ratio_maintenance: 500,
+            unrealized_pnl_initial_asset_weight: 9000,
+            unrealized_pnl_maintenance_asset_weight: 10000,
+            number_of_users_with_base: 1,
             status: MarketStatus::Initialized,
             liquidator_fee: LIQUIDATION_FEE_PRECISION / 100,
-            number_of_users: 1,
             ..PerpMarket::default()
         };
         create_anchor_account_info!(market, PerpMarket, market_account_info);
-        let market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+        let perp_market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
 
         let mut spot_market = SpotMarket {
             market_index: 0,
@@ -6502,9 +8481,12 @@ pub mod resolve_perp_bankruptcy {
             cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
             decimals: 6,
             initial_asset_weight: SPOT_WEIGHT_PRECISION,
+            maintenance_asset_weight: SPOT_WEIGHT_PRECISION,
+            deposit_balance: 200 * SPOT_BALANCE_PRECISION,
+            liquidator_fee: 0,
             historical_oracle_data: HistoricalOracleData {
-                last_oracle_price_twap: PRICE_PRECISION_I64,
-                last_oracle_price_twap_5min: PRICE_PRECISION_I64,
+                last_oracle_price_twap: QUOTE_PRECISION_I64,
+                last_oracle_price_twap_5min: QUOTE_PRECISION_I64,
                 ..HistoricalOracleData::default()
             },
             ..SpotMarket::default()
@@ -6513,28 +8495,21 @@ pub mod resolve_perp_bankruptcy {
         let spot_market_map = SpotMarketMap::load_one(&spot_market_account_info, true).unwrap();
 
         let mut user = User {
-            orders: get_orders(Order {
-                market_index: 0,
-                status: OrderStatus::Open,
-                order_type: OrderType::Limit,
-                direction: PositionDirection::Long,
-                base_asset_amount: BASE_PRECISION_U64,
-                slot: 0,
-                ..Order::default()
-            }),
+            orders: [Order::default(); 32],
             perp_positions: get_positions(PerpPosition {
                 market_index: 0,
-                base_asset_amount: 0,
-                quote_asset_amount: -100 * QUOTE_PRECISION_I64,
-                quote_entry_amount: -100 * QUOTE_PRECISION_I64,
-                quote_break_even_amount: -100 * QUOTE_PRECISION_I64,
-                open_orders: 1,
-                open_bids: BASE_PRECISION_I64,
+                base_asset_amount: BASE_PRECISION_I64,
+                quote_asset_amount: -150 * QUOTE_PRECISION_I64,
+                quote_entry_amount: -150 * QUOTE_PRECISION_I64,
+                quote_break_even_amount: -150 * QUOTE_PRECISION_I64,
                 ..PerpPosition::default()
             }),
-            spot_positions: [SpotPosition::default(); 8],
-            status: UserStatus::Bankrupt as u8,
-            next_liquidation_id: 2,
+            spot_positions: get_spot_positions(SpotPosition {
+                market_index: 0,
+                balance_type: SpotBalanceType::Deposit,
+                scaled_balance: 40 * SPOT_BALANCE_PRECISION_U64,
+                ..SpotPosition::default()
+            }),
             ..User::default()
         };
 
@@ -6551,114 +8526,64 @@ pub mod resolve_perp_bankruptcy {
         let user_key = Pubkey::default();
         let liquidator_key = Pubkey::default();
 
-        let mut expected_user = user;
-        expected_user.status = 0;
-        expected_user.perp_positions[0].quote_asset_amount = 0;
-        expected_user.total_social_loss = 100000000;
-
-        let mut expected_market = market;
-        expected_market.amm.cumulative_funding_rate_long = 1010 * FUNDING_RATE_PRECISION_I128;
-        expected_market.amm.cumulative_funding_rate_short = -1010 * FUNDING_RATE_PRECISION_I128;
-        expected_market.amm.total_social_loss = 100000000;
-        expected_market.amm.quote_asset_amount = -50 * QUOTE_PRECISION_I128;
-        expected_market.number_of_users = 0;
+        let mut user_stats = UserStats::default();
+        let mut liquidator_stats = UserStats::default();
+        let state = State {
+            liquidation_margin_buffer_ratio: 10,
+            initial_pct_to_liquidate: LIQUIDATION_PCT_PRECISION as u16,
+            initial_liquidator_fee_pct: LIQUIDATION_PCT_PRECISION as u16,
+            liquidation_duration: 150,
+            ..Default::default()
+        };
 
-        resolve_perp_bankruptcy(
-            0,
+        // listed out of order, the perp position is still liquidated before its pnl
+        liquidate_user(
+            vec![
+                LiquidationStep::PerpPnlForDeposit {
+                    perp_market_index: 0,
+                    asset_market_index: 0,
+                    max_pnl_transfer: u128::MAX,
+                    limit_price: None,
+                },
+                LiquidationStep::Perp {
+                    market_index: 0,
+                    max_base_asset_amount: u64::MAX,
+                    limit_price: None,
+                },
+            ],
             &mut user,
             &user_key,
+            &mut user_stats,
             &mut liquidator,
             &liquidator_key,
-            &market_map,
+            &mut liquidator_stats,
+            &perp_market_map,
             &spot_market_map,
             &mut oracle_map,
+            slot,
             now,
-            0,
+            &state,
         )
         .unwrap();
 
-        assert_eq!(expected_user, user);
-        assert_eq!(expected_market, market_map.get_ref(&0).unwrap().clone());
-
-        let mut affected_long_user = User {
-            orders: [Order::default(); 32],
-            perp_positions: get_positions(PerpPosition {
-                market_index: 0,
-                base_asset_amount: 5 * BASE_PRECISION_I64,
-                quote_asset_amount: -500 * QUOTE_PRECISION_I64,
-                quote_break_even_amount: -500 * QUOTE_PRECISION_I64,
-                quote_entry_amount: -500 * QUOTE_PRECISION_I64,
-                open_bids: BASE_PRECISION_I64,
-                last_cumulative_funding_rate: 1000 * FUNDING_RATE_PRECISION_I64,
-                ..PerpPosition::default()
-            }),
-            spot_positions: [SpotPosition::default(); 8],
-            ..User::default()
-        };
-
-        let mut expected_affected_long_user = affected_long_user;
-        expected_affected_long_user.perp_positions[0].quote_asset_amount =
-            -550 * QUOTE_PRECISION_I64; // loses $50
-        expected_affected_long_user.perp_positions[0].quote_break_even_amount =
-            -550 * QUOTE_PRECISION_I64; // loses $50
-        expected_affected_long_user.perp_positions[0].last_cumulative_funding_rate =
-            1010 * FUNDING_RATE_PRECISION_I64;
-        expected_affected_long_user.cumulative_perp_funding = -50 * QUOTE_PRECISION_I64;
-
-        {
-            let mut market = market_map.get_ref_mut(&0).unwrap();
-            settle_funding_payment(
-                &mut affected_long_user,
-                &Pubkey::default(),
-                &mut market,
-                now,
-            )
-            .unwrap()
-        }
-
-        assert_eq!(expected_affected_long_user, affected_long_user);
-
-        let mut affected_short_user = User {
-            orders: [Order::default(); 32],
-            perp_positions: get_positions(PerpPosition {
-                market_index: 0,
-                base_asset_amount: -5 * BASE_PRECISION_I64,
-                quote_asset_amount: 500 * QUOTE_PRECISION_I64,
-                quote_entry_amount: 500 * QUOTE_PRECISION_I64,
-                quote_break_even_amount: 500 * QUOTE_PRECISION_I64,
-                open_bids: BASE_PRECISION_I64,
-                last_cumulative_funding_rate: -1000 * FUNDING_RATE_PRECISION_I64,
-                ..PerpPosition::default()
-            }),
-            spot_positions: [SpotPosition::default(); 8],
-            ..User::default()
-        };
-
-        let mut expected_affected_short_user = affected_short_user;
-        expected_affected_short_user.perp_positions[0].quote_asset_amount =
-            450 * QUOTE_PRECISION_I64; // loses $50
-        expected_affected_short_user.perp_positions[0].quote_break_even_amount =
-            450 * QUOTE_PRECISION_I64; // loses $50
-        expected_affected_short_user.perp_positions[0].last_cumulative_funding_rate =
-            -1010 * FUNDING_RATE_PRECISION_I64;
-        expected_affected_short_user.cumulative_perp_funding = -50 * QUOTE_PRECISION_I64;
-
-        {
-            let mut market = market_map.get_ref_mut(&0).unwrap();
-            settle_funding_payment(
-                &mut affected_short_user,
-                &Pubkey::default(),
-                &mut market,
-                now,
-            )
-            .unwrap()
-        }
+        // perp position closed, then the remaining negative pnl taken against the deposit
+        assert_eq!(user.perp_positions[0].base_asset_amount, 0);
+        assert_eq!(user.spot_positions[0].scaled_balance, 0);
+        assert!(user.perp_positions[0].quote_asset_amount < 0);
+        assert!(user.is_bankrupt());
 
-        assert_eq!(expected_affected_short_user, affected_short_user);
+        assert_eq!(
+            liquidator.perp_positions[0].base_asset_amount,
+            BASE_PRECISION_I64
+        );
+        assert_eq!(
+            liquidator.spot_positions[0].scaled_balance,
+            90 * SPOT_BALANCE_PRECISION_U64
+        );
     }
 
     #[test]
-    pub fn successful_resolve_perp_bankruptcy_with_fee_pool() {
+    pub fn skips_steps_outside_limit_price_and_respects_max_amount() {
         let now = 0_i64;
         let slot = 0_u64;
 
@@ -6687,65 +8612,78 @@ pub mod resolve_perp_bankruptcy {
                 max_slippage_ratio: 50,
                 max_fill_reserve_fraction: 100,
                 order_step_size: 10000000,
-                quote_asset_amount: -150 * QUOTE_PRECISION_I128,
-                base_asset_amount_long: 5 * BASE_PRECISION_I128,
-                base_asset_amount_short: -5 * BASE_PRECISION_I128,
-                base_asset_amount_with_amm: BASE_PRECISION_I128,
+                base_asset_amount_long: BASE_PRECISION_I128,
                 oracle: oracle_price_key,
-                cumulative_funding_rate_long: 1000 * FUNDING_RATE_PRECISION_I128,
-                cumulative_funding_rate_short: -1000 * FUNDING_RATE_PRECISION_I128,
-                fee_pool: PoolBalance {
-                    scaled_balance: 50 * SPOT_BALANCE_PRECISION,
-                    market_index: QUOTE_SPOT_MARKET_INDEX,
-                    ..PoolBalance::default()
-                },
+                historical_oracle_data: HistoricalOracleData::default_price(oracle_price.agg.price),
                 ..AMM::default()
             },
             margin_ratio_initial: 1000,
             margin_ratio_maintenance: 500,
+            unrealized_pnl_initial_asset_weight: 9000,
+            unrealized_pnl_maintenance_asset_weight: 10000,
+            number_of_users_with_base: 1,
+            number_of_users: 1,
             status: MarketStatus::Initialized,
             liquidator_fee: LIQUIDATION_FEE_PRECISION / 100,
-            number_of_users: 1,
+            market_index: 0,
             ..PerpMarket::default()
         };
+        let mut second_market = PerpMarket {
+            market_index: 1,
+            ..market
+        };
         create_anchor_account_info!(market, PerpMarket, market_account_info);
-        let market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+        create_anchor_account_info!(second_market, PerpMarket, second_market_account_info);
+
+        let perp_market_map = PerpMarketMap::load_multiple(
+            vec![&market_account_info, &second_market_account_info],
+            true,
+        )
+        .unwrap();
 
         let mut spot_market = SpotMarket {
             market_index: 0,
-            deposit_balance: 500 * SPOT_BALANCE_PRECISION,
             oracle_source: OracleSource::QuoteAsset,
             cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
             decimals: 6,
             initial_asset_weight: SPOT_WEIGHT_PRECISION,
+            maintenance_asset_weight: SPOT_WEIGHT_PRECISION,
+            deposit_balance: 200 * SPOT_BALANCE_PRECISION,
+            liquidator_fee: 0,
+            historical_oracle_data: HistoricalOracleData {
+                last_oracle_price_twap: QUOTE_PRECISION_I64,
+                last_oracle_price_twap_5min: QUOTE_PRECISION_I64,
+                ..HistoricalOracleData::default()
+            },
             ..SpotMarket::default()
         };
         create_anchor_account_info!(spot_market, SpotMarket, spot_market_account_info);
         let spot_market_map = SpotMarketMap::load_one(&spot_market_account_info, true).unwrap();
 
-        let mut user = User {
-            orders: get_orders(Order {
-                market_index: 0,
-                status: OrderStatus::Open,
-                order_type: OrderType::Limit,
-                direction: PositionDirection::Long,
-                base_asset_amount: BASE_PRECISION_U64,
-                slot: 0,
-                ..Order::default()
-            }),
-            perp_positions: get_positions(PerpPosition {
+        // $10 maintenance margin across two markets with $8 of collateral
+        let mut perp_positions = [PerpPosition::default(); 8];
+        perp_positions[0] = PerpPosition {
+            market_index: 0,
+            base_asset_amount: BASE_PRECISION_I64,
+            quote_asset_amount: -100 * QUOTE_PRECISION_I64,
+            quote_entry_amount: -100 * QUOTE_PRECISION_I64,
+            quote_break_even_amount: -100 * QUOTE_PRECISION_I64,
+            ..PerpPosition::default()
+        };
+        perp_positions[1] = PerpPosition {
+            market_index: 1,
+            ..perp_positions[0]
+        };
+
+        let mut user = User {
+            orders: [Order::default(); 32],
+            perp_positions,
+            spot_positions: get_spot_positions(SpotPosition {
                 market_index: 0,
-                base_asset_amount: 0,
-                quote_asset_amount: -100 * QUOTE_PRECISION_I64,
-                quote_entry_amount: -100 * QUOTE_PRECISION_I64,
-                quote_break_even_amount: -100 * QUOTE_PRECISION_I64,
-                open_orders: 1,
-                open_bids: BASE_PRECISION_I64,
-                ..PerpPosition::default()
+                balance_type: SpotBalanceType::Deposit,
+                scaled_balance: 8 * SPOT_BALANCE_PRECISION_U64,
+                ..SpotPosition::default()
             }),
-            spot_positions: [SpotPosition::default(); 8],
-            status: UserStatus::Bankrupt as u8,
-            next_liquidation_id: 2,
             ..User::default()
         };
 
@@ -6762,146 +8700,64 @@ pub mod resolve_perp_bankruptcy {
         let user_key = Pubkey::default();
         let liquidator_key = Pubkey::default();
 
-        let mut expected_user = user;
-        expected_user.status = 0;
-        expected_user.perp_positions[0].quote_asset_amount = 0;
-        expected_user.total_social_loss = 100000000;
-
-        let mut expected_market = market;
-        expected_market.amm.cumulative_funding_rate_long = 1005 * FUNDING_RATE_PRECISION_I128;
-        expected_market.amm.cumulative_funding_rate_short = -1005 * FUNDING_RATE_PRECISION_I128;
-        expected_market.amm.total_social_loss = 50000000;
-        expected_market.amm.quote_asset_amount = -50 * QUOTE_PRECISION_I128;
-        expected_market.number_of_users = 0;
-        expected_market.amm.fee_pool.scaled_balance = 0;
+        let mut user_stats = UserStats::default();
+        let mut liquidator_stats = UserStats::default();
+        let state = State {
+            liquidation_margin_buffer_ratio: 10,
+            initial_pct_to_liquidate: LIQUIDATION_PCT_PRECISION as u16,
+            initial_liquidator_fee_pct: LIQUIDATION_PCT_PRECISION as u16,
+            liquidation_duration: 150,
+            ..Default::default()
+        };
 
-        resolve_perp_bankruptcy(
-            0,
+        liquidate_user(
+            vec![
+                // oracle is above the liquidator's limit
+                LiquidationStep::Perp {
+                    market_index: 0,
+                    max_base_asset_amount: u64::MAX,
+                    limit_price: Some(90 * PRICE_PRECISION_U64),
+                },
+                LiquidationStep::Perp {
+                    market_index: 1,
+                    max_base_asset_amount: BASE_PRECISION_U64 / 10,
+                    limit_price: Some(110 * PRICE_PRECISION_U64),
+                },
+            ],
             &mut user,
             &user_key,
+            &mut user_stats,
             &mut liquidator,
             &liquidator_key,
-            &market_map,
+            &mut liquidator_stats,
+            &perp_market_map,
             &spot_market_map,
             &mut oracle_map,
+            slot,
             now,
-            0,
+            &state,
         )
         .unwrap();
 
-        assert_eq!(user.total_social_loss, 100000000);
-        assert_eq!(expected_user, user);
-        assert_eq!(expected_market, market_map.get_ref(&0).unwrap().clone());
-
-        let mut affected_long_user = User {
-            orders: [Order::default(); 32],
-            perp_positions: get_positions(PerpPosition {
-                market_index: 0,
-                base_asset_amount: 5 * BASE_PRECISION_I64,
-                quote_asset_amount: -500 * QUOTE_PRECISION_I64,
-                quote_break_even_amount: -500 * QUOTE_PRECISION_I64,
-                quote_entry_amount: -500 * QUOTE_PRECISION_I64,
-                open_bids: BASE_PRECISION_I64,
-                last_cumulative_funding_rate: 1000 * FUNDING_RATE_PRECISION_I64,
-                ..PerpPosition::default()
-            }),
-            spot_positions: [SpotPosition::default(); 8],
-            ..User::default()
-        };
-
-        let mut expected_affected_long_user = affected_long_user;
-        expected_affected_long_user.perp_positions[0].quote_asset_amount =
-            -525 * QUOTE_PRECISION_I64; // loses $50
-        expected_affected_long_user.perp_positions[0].quote_break_even_amount =
-            -525 * QUOTE_PRECISION_I64; // loses $50
-        expected_affected_long_user.perp_positions[0].last_cumulative_funding_rate =
-            1005 * FUNDING_RATE_PRECISION_I64;
-        expected_affected_long_user.cumulative_perp_funding = -25 * QUOTE_PRECISION_I64;
-
-        {
-            let mut market = market_map.get_ref_mut(&0).unwrap();
-            settle_funding_payment(
-                &mut affected_long_user,
-                &Pubkey::default(),
-                &mut market,
-                now,
-            )
-            .unwrap()
-        }
-
-        assert_eq!(expected_affected_long_user, affected_long_user);
-
-        let mut affected_short_user = User {
-            orders: [Order::default(); 32],
-            perp_positions: get_positions(PerpPosition {
-                market_index: 0,
-                base_asset_amount: -5 * BASE_PRECISION_I64,
-                quote_asset_amount: 500 * QUOTE_PRECISION_I64,
-                quote_entry_amount: 500 * QUOTE_PRECISION_I64,
-                quote_break_even_amount: 500 * QUOTE_PRECISION_I64,
-                open_bids: BASE_PRECISION_I64,
-                last_cumulative_funding_rate: -1000 * FUNDING_RATE_PRECISION_I64,
-                ..PerpPosition::default()
-            }),
-            spot_positions: [SpotPosition::default(); 8],
-            ..User::default()
-        };
-
-        let mut expected_affected_short_user = affected_short_user;
-        expected_affected_short_user.perp_positions[0].quote_asset_amount =
-            475 * QUOTE_PRECISION_I64; // loses $50
-        expected_affected_short_user.perp_positions[0].quote_break_even_amount =
-            475 * QUOTE_PRECISION_I64; // loses $50
-        expected_affected_short_user.perp_positions[0].last_cumulative_funding_rate =
-            -1005 * FUNDING_RATE_PRECISION_I64;
-        expected_affected_short_user.cumulative_perp_funding = -25 * QUOTE_PRECISION_I64;
-
-        {
-            let mut market = market_map.get_ref_mut(&0).unwrap();
-            settle_funding_payment(
-                &mut affected_short_user,
-                &Pubkey::default(),
-                &mut market,
-                now,
-            )
-            .unwrap()
-        }
+        assert_eq!(user.perp_positions[0].base_asset_amount, BASE_PRECISION_I64);
+        assert_eq!(
+            user.perp_positions[1].base_asset_amount,
+            BASE_PRECISION_I64 - BASE_PRECISION_I64 / 10
+        );
+        assert!(user.is_being_liquidated());
+        assert!(!user.is_bankrupt());
+        assert_eq!(user.next_liquidation_id, 1);
 
-        assert_eq!(expected_affected_short_user, affected_short_user);
+        let liquidator_position = liquidator.get_perp_position(1).unwrap();
+        assert_eq!(
+            liquidator_position.base_asset_amount,
+            BASE_PRECISION_I64 / 10
+        );
+        assert!(liquidator.get_perp_position(0).is_err());
     }
-}
-
-pub mod resolve_spot_bankruptcy {
-    use std::str::FromStr;
-
-    use anchor_lang::Owner;
-    use solana_program::pubkey::Pubkey;
-
-    use crate::controller::liquidation::resolve_spot_bankruptcy;
-    use crate::controller::position::PositionDirection;
-    use crate::create_account_info;
-    use crate::create_anchor_account_info;
-    use crate::math::constants::{
-        AMM_RESERVE_PRECISION, BASE_PRECISION_I128, BASE_PRECISION_U64,
-        FUNDING_RATE_PRECISION_I128, LIQUIDATION_FEE_PRECISION, PEG_PRECISION, QUOTE_PRECISION,
-        QUOTE_PRECISION_I128, QUOTE_PRECISION_I64, SPOT_BALANCE_PRECISION,
-        SPOT_BALANCE_PRECISION_U64, SPOT_CUMULATIVE_INTEREST_PRECISION, SPOT_WEIGHT_PRECISION,
-    };
-    use crate::math::spot_balance::get_token_amount;
-    use crate::state::oracle::{HistoricalOracleData, OracleSource};
-    use crate::state::oracle_map::OracleMap;
-    use crate::state::perp_market::{MarketStatus, PerpMarket, AMM};
-    use crate::state::perp_market_map::PerpMarketMap;
-    use crate::state::spot_market::{SpotBalanceType, SpotMarket};
-    use crate::state::spot_market_map::SpotMarketMap;
-    use crate::state::user::{
-        Order, OrderStatus, OrderType, PerpPosition, SpotPosition, User, UserStatus,
-    };
-    use crate::test_utils::*;
-    use crate::test_utils::{get_orders, get_pyth_price, get_spot_positions};
 
     #[test]
-    pub fn successful_resolve_spot_bankruptcy() {
+    pub fn fail_with_sufficient_collateral() {
         let now = 0_i64;
         let slot = 0_u64;
 
@@ -6921,118 +8777,86 @@ pub mod resolve_spot_bankruptcy {
             amm: AMM {
                 base_asset_reserve: 100 * AMM_RESERVE_PRECISION,
                 quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
-                bid_base_asset_reserve: 101 * AMM_RESERVE_PRECISION,
-                bid_quote_asset_reserve: 99 * AMM_RESERVE_PRECISION,
-                ask_base_asset_reserve: 99 * AMM_RESERVE_PRECISION,
-                ask_quote_asset_reserve: 101 * AMM_RESERVE_PRECISION,
                 sqrt_k: 100 * AMM_RESERVE_PRECISION,
                 peg_multiplier: 100 * PEG_PRECISION,
-                max_slippage_ratio: 50,
-                max_fill_reserve_fraction: 100,
                 order_step_size: 10000000,
-                quote_asset_amount: -150 * QUOTE_PRECISION_I128,
-                base_asset_amount_with_amm: BASE_PRECISION_I128,
-                base_asset_amount_long: 5 * BASE_PRECISION_I128,
-                base_asset_amount_short: -5 * BASE_PRECISION_I128,
                 oracle: oracle_price_key,
-                cumulative_funding_rate_long: 1000 * FUNDING_RATE_PRECISION_I128,
-                cumulative_funding_rate_short: -1000 * FUNDING_RATE_PRECISION_I128,
+                historical_oracle_data: HistoricalOracleData::default_price(oracle_price.agg.price),
                 ..AMM::default()
             },
             margin_ratio_initial: 1000,
             margin_ratio_maintenance: 500,
             status: MarketStatus::Initialized,
-            liquidator_fee: LIQUIDATION_FEE_PRECISION / 100,
             ..PerpMarket::default()
         };
         create_anchor_account_info!(market, PerpMarket, market_account_info);
-        let market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+        let perp_market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
 
         let mut spot_market = SpotMarket {
             market_index: 0,
             oracle_source: OracleSource::QuoteAsset,
             cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
-            cumulative_borrow_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
             decimals: 6,
             initial_asset_weight: SPOT_WEIGHT_PRECISION,
-            deposit_balance: 1000 * SPOT_BALANCE_PRECISION,
-            borrow_balance: 100 * SPOT_BALANCE_PRECISION,
-            historical_oracle_data: HistoricalOracleData::default_price(QUOTE_PRECISION_I64),
+            maintenance_asset_weight: SPOT_WEIGHT_PRECISION,
+            deposit_balance: 200 * SPOT_BALANCE_PRECISION,
+            historical_oracle_data: HistoricalOracleData {
+                last_oracle_price_twap: QUOTE_PRECISION_I64,
+                last_oracle_price_twap_5min: QUOTE_PRECISION_I64,
+                ..HistoricalOracleData::default()
+            },
             ..SpotMarket::default()
         };
         create_anchor_account_info!(spot_market, SpotMarket, spot_market_account_info);
         let spot_market_map = SpotMarketMap::load_one(&spot_market_account_info, true).unwrap();
 
         let mut user = User {
-            orders: get_orders(Order {
-                market_index: 0,
-                status: OrderStatus::Open,
-                order_type: OrderType::Limit,
-                direction: PositionDirection::Long,
-                base_asset_amount: BASE_PRECISION_U64,
-                slot: 0,
-                ..Order::default()
-            }),
-            perp_positions: [PerpPosition::default(); 8],
+            orders: [Order::default(); 32],
             spot_positions: get_spot_positions(SpotPosition {
                 market_index: 0,
+                balance_type: SpotBalanceType::Deposit,
                 scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
-                balance_type: SpotBalanceType::Borrow,
                 ..SpotPosition::default()
             }),
-            status: UserStatus::Bankrupt as u8,
-            next_liquidation_id: 2,
             ..User::default()
         };
 
-        let mut liquidator = User {
-            spot_positions: get_spot_positions(SpotPosition {
-                market_index: 0,
-                balance_type: SpotBalanceType::Deposit,
-                scaled_balance: 50 * SPOT_BALANCE_PRECISION_U64,
-                ..SpotPosition::default()
-            }),
-            ..User::default()
-        };
+        let mut liquidator = User::default();
 
         let user_key = Pubkey::default();
         let liquidator_key = Pubkey::default();
 
-        let mut expected_user = user;
-        expected_user.status = 0;
-        expected_user.spot_positions[0].scaled_balance = 0;
-        expected_user.spot_positions[0].cumulative_deposits = 100 * QUOTE_PRECISION_I64;
-        expected_user.total_social_loss = 100000000;
-
-        let mut expected_spot_market = spot_market;
-        expected_spot_market.borrow_balance = 0;
-        expected_spot_market.cumulative_deposit_interest =
-            9 * SPOT_CUMULATIVE_INTEREST_PRECISION / 10;
-        expected_spot_market.total_social_loss = 100 * QUOTE_PRECISION;
-        expected_spot_market.total_quote_social_loss = 100 * QUOTE_PRECISION;
+        let mut user_stats = UserStats::default();
+        let mut liquidator_stats = UserStats::default();
+        let state = State {
+            liquidation_margin_buffer_ratio: 10,
+            initial_pct_to_liquidate: LIQUIDATION_PCT_PRECISION as u16,
+            initial_liquidator_fee_pct: LIQUIDATION_PCT_PRECISION as u16,
+            liquidation_duration: 150,
+            ..Default::default()
+        };
 
-        resolve_spot_bankruptcy(
-            0,
+        let result = liquidate_user(
+            vec![LiquidationStep::Perp {
+                market_index: 0,
+                max_base_asset_amount: u64::MAX,
+                limit_price: None,
+            }],
             &mut user,
             &user_key,
+            &mut user_stats,
             &mut liquidator,
             &liquidator_key,
-            &market_map,
+            &mut liquidator_stats,
+            &perp_market_map,
             &spot_market_map,
             &mut oracle_map,
+            slot,
             now,
-            0,
-        )
-        .unwrap();
-
-        assert_eq!(expected_user, user);
-        assert_eq!(expected_spot_market, *spot_market_map.get_ref(&0).unwrap());
-
-        let spot_market = spot_market_map.get_ref_mut(&0).unwrap();
-        let deposit_balance = spot_market.deposit_balance;
-        let deposit_token_amount =
-            get_token_amount(deposit_balance, &spot_market, &SpotBalanceType::Deposit).unwrap();
+            &state,
+        );
 
-        assert_eq!(deposit_token_amount, 900 * QUOTE_PRECISION);
+        assert_eq!(result, Err(ErrorCode::SufficientCollateral));
+        assert!(!user.is_being_liquidated());
     }
 }
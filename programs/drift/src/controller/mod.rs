@@ -1,6 +1,8 @@
 pub mod amm;
+pub mod backstop_vault;
 pub mod funding;
 pub mod insurance;
+pub mod isolated_position;
 pub mod liquidation;
 pub mod lp;
 pub mod orders;
@@ -10,4 +12,5 @@ pub mod position;
 pub mod repeg;
 pub mod spot_balance;
 pub mod spot_position;
+pub mod sub_account_margin;
 pub mod token;
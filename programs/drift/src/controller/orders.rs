@@ -26,6 +26,7 @@ use crate::error::DriftResult;
 use crate::error::ErrorCode;
 use crate::get_struct_values;
 use crate::get_then_update_id;
+use crate::load;
 use crate::load_mut;
 use crate::math::amm_jit::calculate_amm_jit_liquidity;
 use crate::math::auction::{calculate_auction_params_for_trigger_order, calculate_auction_prices};
@@ -35,7 +36,9 @@ use crate::math::fees::{determine_user_fee_tier, ExternalFillFees, FillFees};
 use crate::math::fulfillment::{
     determine_perp_fulfillment_methods, determine_spot_fulfillment_methods,
 };
-use crate::math::liquidation::validate_user_not_being_liquidated;
+use crate::math::liquidation::{
+    calculate_base_asset_amount_to_cover_margin_shortage, validate_user_not_being_liquidated,
+};
 use crate::math::matching::{
     are_orders_same_market_but_different_sides, calculate_fill_for_matched_orders,
     calculate_filler_multiplier_for_matched_orders, do_orders_cross, is_maker_for_taker,
@@ -45,7 +48,8 @@ use crate::math::safe_math::SafeMath;
 use crate::math::spot_balance::{get_signed_token_amount, get_token_amount};
 use crate::math::{amm, fees, margin::*, orders::*};
 use crate::state::order_params::{
-    ModifyOrderParams, ModifyOrderPolicy, OrderParams, PlaceOrderOptions, PostOnlyParam,
+    ModifyOrderByIdParams, ModifyOrderParams, ModifyOrderPolicy, OrderParams, PlaceOrderOptions,
+    PostOnlyParam,
 };
 
 use crate::math::amm::calculate_amm_available_liquidity;
@@ -59,7 +63,7 @@ use crate::state::events::{
 use crate::state::events::{OrderAction, OrderActionExplanation};
 use crate::state::fill_mode::FillMode;
 use crate::state::fulfillment::{PerpFulfillmentMethod, SpotFulfillmentMethod};
-use crate::state::margin_calculation::{MarginCalculation, MarginContext};
+use crate::state::margin_calculation::{MarginCalculation, MarginContext, MarginScope};
 use crate::state::oracle::{OraclePriceData, StrictOraclePrice};
 use crate::state::oracle_map::OracleMap;
 use crate::state::paused_operations::PerpOperation;
@@ -72,7 +76,8 @@ use crate::state::state::FeeStructure;
 use crate::state::state::*;
 use crate::state::traits::Size;
 use crate::state::user::{
-    AssetType, Order, OrderStatus, OrderTriggerCondition, OrderType, UserStats,
+    AssetType, Order, OrderBitFlag, OrderStatus, OrderTriggerCondition, OrderType,
+    SelfTradePreventionMode, UserStats,
 };
 use crate::state::user::{MarketType, User};
 use crate::state::user_map::{UserMap, UserStatsMap};
@@ -99,7 +104,7 @@ pub fn place_perp_order(
     oracle_map: &mut OracleMap,
     clock: &Clock,
     mut params: OrderParams,
-    mut options: PlaceOrderOptions,
+    options: &mut PlaceOrderOptions,
 ) -> DriftResult {
     let now = clock.unix_timestamp;
     let slot = clock.slot;
@@ -153,6 +158,17 @@ pub fn place_perp_order(
     }
 
     let market_index = params.market_index;
+
+    validate!(
+        !user
+            .get_isolated_perp_position(market_index)
+            .map_or(false, |isolated_position| isolated_position
+                .is_being_liquidated()),
+        ErrorCode::UserIsBeingLiquidated,
+        "isolated perp position in market {} is being liquidated",
+        market_index
+    )?;
+
     let market = &perp_market_map.get_ref(&market_index)?;
     let force_reduce_only = market.is_reduce_only()?;
 
@@ -168,6 +184,33 @@ pub fn place_perp_order(
         "Market is in settlement mode",
     )?;
 
+    let oracle_price_data = *oracle_map.get_price_data(&market.amm.oracle)?;
+
+    // updates auction params for crossing limit orders w/out auction duration
+    params.update_perp_auction_params(market, oracle_price_data.price)?;
+
+    let (auction_start_price, auction_end_price, auction_duration) = get_auction_params(
+        &params,
+        &oracle_price_data,
+        market.amm.order_tick_size,
+        state.min_perp_auction_duration,
+    )?;
+
+    if let Some(quote_asset_amount) = params.quote_asset_amount {
+        validate!(
+            params.base_asset_amount == 0,
+            ErrorCode::InvalidOrder,
+            "base_asset_amount must be 0 for orders sized in quote"
+        )?;
+
+        params.base_asset_amount = calculate_base_asset_amount_for_quote_notional(
+            quote_asset_amount,
+            params.get_price_for_quote_notional(oracle_price_data.price, auction_end_price)?,
+            BASE_PRECISION_U64,
+            market.amm.order_step_size,
+        )?;
+    }
+
     let position_index = get_position_index(&user.perp_positions, market_index)
         .or_else(|_| add_new_position(&mut user.perp_positions, market_index))?;
 
@@ -181,7 +224,15 @@ pub fn place_perp_order(
             market.amm.order_step_size
         )?;
 
-        let base_asset_amount = if params.base_asset_amount == u64::MAX {
+        let base_asset_amount = if params.bit_flags & (OrderBitFlag::PositionLinked as u8) > 0 {
+            // sized to the current position, resized again when triggered
+            standardize_base_asset_amount(
+                user.perp_positions[position_index]
+                    .base_asset_amount
+                    .unsigned_abs(),
+                market.amm.order_step_size,
+            )?
+        } else if params.base_asset_amount == u64::MAX {
             calculate_max_perp_order_size(
                 user,
                 position_index,
@@ -204,18 +255,6 @@ pub fn place_perp_order(
         (existing_position_direction, base_asset_amount)
     };
 
-    let oracle_price_data = oracle_map.get_price_data(&market.amm.oracle)?;
-
-    // updates auction params for crossing limit orders w/out auction duration
-    params.update_perp_auction_params(market, oracle_price_data.price)?;
-
-    let (auction_start_price, auction_end_price, auction_duration) = get_auction_params(
-        &params,
-        oracle_price_data,
-        market.amm.order_tick_size,
-        state.min_perp_auction_duration,
-    )?;
-
     let max_ts = match params.max_ts {
         Some(max_ts) => max_ts,
         None => match params.order_type {
@@ -231,6 +270,21 @@ pub fn place_perp_order(
         return Ok(());
     }
 
+    let auction_start_price = if params.order_type == OrderType::Twap {
+        validate!(
+            max_ts.safe_sub(now)? >= params.twap_slices.cast()?,
+            ErrorCode::InvalidOrderMaxTs,
+            "Twap order max_ts must leave at least one second per slice (max_ts {} now {})",
+            max_ts,
+            now
+        )?;
+
+        // twap orders have no auction, the start ts anchors the slice schedule
+        now
+    } else {
+        auction_start_price
+    };
+
     validate!(
         params.market_type == MarketType::Perp,
         ErrorCode::InvalidOrderMarketType,
@@ -270,7 +324,9 @@ pub fn place_perp_order(
         auction_end_price,
         auction_duration,
         max_ts,
-        padding: [0; 3],
+        bit_flags: params.bit_flags,
+        group_id: params.group_id,
+        twap_slices: params.twap_slices,
     };
 
     let valid_oracle_price = Some(oracle_map.get_price_data(&market.amm.oracle)?.price);
@@ -292,6 +348,14 @@ pub fn place_perp_order(
         user.perp_positions[position_index].open_asks,
     )?;
 
+    if user.is_in_margin_call() {
+        validate!(
+            !risk_increasing,
+            ErrorCode::UserInMarginCall,
+            "only risk reducing orders can be placed while in margin call"
+        )?;
+    }
+
     user.increment_open_orders(new_order.has_auction());
     user.orders[new_order_index] = new_order;
     user.perp_positions[position_index].open_orders += 1;
@@ -588,7 +652,57 @@ pub fn cancel_order_by_user_order_id(
     Ok(())
 }
 
+/// Cancels the order. If it is a bracket parent that never filled, its legs are canceled too
 pub fn cancel_order(
+    order_index: usize,
+    user: &mut User,
+    user_key: &Pubkey,
+    perp_market_map: &PerpMarketMap,
+    spot_market_map: &SpotMarketMap,
+    oracle_map: &mut OracleMap,
+    now: i64,
+    slot: u64,
+    explanation: OrderActionExplanation,
+    filler_key: Option<&Pubkey>,
+    filler_reward: u64,
+    skip_log: bool,
+) -> DriftResult {
+    let canceled_order = user.orders[order_index];
+
+    cancel_single_order(
+        order_index,
+        user,
+        user_key,
+        perp_market_map,
+        spot_market_map,
+        oracle_map,
+        now,
+        slot,
+        explanation,
+        filler_key,
+        filler_reward,
+        skip_log,
+    )?;
+
+    if canceled_order.is_bracket_parent() && canceled_order.base_asset_amount_filled == 0 {
+        cancel_bracket_legs(
+            user,
+            user_key,
+            &canceled_order,
+            perp_market_map,
+            spot_market_map,
+            oracle_map,
+            now,
+            slot,
+            explanation,
+            filler_key,
+        )?;
+    }
+
+    Ok(())
+}
+
+fn cancel_single_order(
     order_index: usize,
     user: &mut User,
     user_key: &Pubkey,
@@ -686,100 +800,637 @@ pub fn cancel_order(
     Ok(())
 }
 
-pub enum ModifyOrderId {
-    UserOrderId(u8),
-    OrderId(u32),
-}
-
-pub fn modify_order(
-    order_id: ModifyOrderId,
-    modify_order_params: ModifyOrderParams,
-    user_loader: &AccountLoader<User>,
-    state: &State,
+/// Legs of a bracket whose parent never filled have no position to protect, so they leave with it
+fn cancel_bracket_legs(
+    user: &mut User,
+    user_key: &Pubkey,
+    parent_order: &Order,
     perp_market_map: &PerpMarketMap,
     spot_market_map: &SpotMarketMap,
     oracle_map: &mut OracleMap,
-    clock: &Clock,
+    now: i64,
+    slot: u64,
+    explanation: OrderActionExplanation,
+    filler_key: Option<&Pubkey>,
 ) -> DriftResult {
-    let user_key = user_loader.key();
-    let mut user = load_mut!(user_loader)?;
+    for order_index in 0..user.orders.len() {
+        if user.orders[order_index].status != OrderStatus::Open {
+            continue;
+        }
 
-    let order_index = match order_id {
-        ModifyOrderId::UserOrderId(user_order_id) => {
-            match user.get_order_index_by_user_order_id(user_order_id) {
-                Ok(order_index) => order_index,
-                Err(e) => {
-                    msg!("User order id {} not found", user_order_id);
-                    if modify_order_params.policy == Some(ModifyOrderPolicy::MustModify) {
-                        return Err(e);
-                    } else {
-                        return Ok(());
-                    }
-                }
-            }
+        if !user.orders[order_index].is_linked_to(parent_order) {
+            continue;
         }
-        ModifyOrderId::OrderId(order_id) => match user.get_order_index(order_id) {
-            Ok(order_index) => order_index,
-            Err(e) => {
-                msg!("Order id {} not found", order_id);
-                if modify_order_params.policy == Some(ModifyOrderPolicy::MustModify) {
-                    return Err(e);
-                } else {
-                    return Ok(());
-                }
-            }
-        },
-    };
 
-    let existing_order = user.orders[order_index];
+        cancel_single_order(
+            order_index,
+            user,
+            user_key,
+            perp_market_map,
+            spot_market_map,
+            oracle_map,
+            now,
+            slot,
+            explanation,
+            filler_key,
+            0,
+            false,
+        )?;
+    }
 
-    cancel_order(
-        order_index,
-        &mut user,
-        &user_key,
-        perp_market_map,
-        spot_market_map,
-        oracle_map,
-        clock.unix_timestamp,
-        clock.slot,
-        OrderActionExplanation::None,
-        None,
-        0,
-        false,
-    )?;
+    Ok(())
+}
 
-    user.update_last_active_slot(clock.slot);
+/// Cancels the open orders linked to an order that filled or triggered. Takes a copy of the order
+/// since a completely filled order is reset. Fills of a bracket parent leave its legs open
+pub fn cancel_linked_orders(
+    user: &mut User,
+    user_key: &Pubkey,
+    executed_order: &Order,
+    perp_market_map: &PerpMarketMap,
+    spot_market_map: &SpotMarketMap,
+    oracle_map: &mut OracleMap,
+    now: i64,
+    slot: u64,
+    filler_key: Option<&Pubkey>,
+) -> DriftResult<Vec<u32>> {
+    let mut canceled_order_ids: Vec<u32> = vec![];
 
-    let order_params =
-        merge_modify_order_params_with_existing_order(&existing_order, &modify_order_params)?;
+    if executed_order.group_id == 0 || executed_order.is_bracket_parent() {
+        return Ok(canceled_order_ids);
+    }
 
-    if order_params.market_type == MarketType::Perp {
-        place_perp_order(
-            state,
-            &mut user,
+    for order_index in 0..user.orders.len() {
+        if user.orders[order_index].status != OrderStatus::Open {
+            continue;
+        }
+
+        if user.orders[order_index].order_id == executed_order.order_id {
+            continue;
+        }
+
+        if !user.orders[order_index].is_linked_to(executed_order) {
+            continue;
+        }
+
+        canceled_order_ids.push(user.orders[order_index].order_id);
+        cancel_order(
+            order_index,
+            user,
             user_key,
             perp_market_map,
             spot_market_map,
             oracle_map,
-            clock,
-            order_params,
-            PlaceOrderOptions::default(),
+            now,
+            slot,
+            OrderActionExplanation::LinkedOrderExecuted,
+            filler_key,
+            0,
+            false,
         )?;
-    } else {
-        place_spot_order(
-            state,
-            &mut user,
+    }
+
+    Ok(canceled_order_ids)
+}
+
+/// Sizes a position linked order to the entire position it closes. Left unchanged if the position
+/// is on the same side as the order, so the fill cancels it as a reduce only order
+pub fn resize_position_linked_order(
+    order: &mut Order,
+    existing_base_asset_amount: i64,
+    step_size: u64,
+) -> DriftResult {
+    let closes_position = match order.direction {
+        PositionDirection::Long => existing_base_asset_amount < 0,
+        PositionDirection::Short => existing_base_asset_amount > 0,
+    };
+
+    if !closes_position {
+        return Ok(());
+    }
+
+    let base_asset_amount =
+        standardize_base_asset_amount(existing_base_asset_amount.unsigned_abs(), step_size)?;
+
+    if base_asset_amount != 0 {
+        order.base_asset_amount = order.base_asset_amount_filled.safe_add(base_asset_amount)?;
+    }
+
+    Ok(())
+}
+
+/// Cancels the take profit / stop loss orders attached to a perp position once it is closed
+pub fn cancel_position_linked_orders(
+    user: &mut User,
+    user_key: &Pubkey,
+    market_index: u16,
+    perp_market_map: &PerpMarketMap,
+    spot_market_map: &SpotMarketMap,
+    oracle_map: &mut OracleMap,
+    now: i64,
+    slot: u64,
+    filler_key: Option<&Pubkey>,
+) -> DriftResult<Vec<u32>> {
+    let mut canceled_order_ids: Vec<u32> = vec![];
+
+    if user.get_perp_position(market_index)?.base_asset_amount != 0 {
+        return Ok(canceled_order_ids);
+    }
+
+    for order_index in 0..user.orders.len() {
+        if user.orders[order_index].status != OrderStatus::Open
+            || user.orders[order_index].market_type != MarketType::Perp
+            || user.orders[order_index].market_index != market_index
+            || !user.orders[order_index].is_position_linked()
+        {
+            continue;
+        }
+
+        canceled_order_ids.push(user.orders[order_index].order_id);
+        cancel_order(
+            order_index,
+            user,
             user_key,
             perp_market_map,
             spot_market_map,
             oracle_map,
-            clock,
-            order_params,
-            PlaceOrderOptions::default(),
+            now,
+            slot,
+            OrderActionExplanation::PositionClosed,
+            filler_key,
+            0,
+            false,
         )?;
     }
 
-    Ok(())
+    Ok(canceled_order_ids)
+}
+
+/// Applies the taker order's self trade prevention mode when the maker order belongs to the same
+/// authority. Returns true if the match must be skipped
+pub fn prevent_perp_self_trade(
+    taker: &mut User,
+    taker_order_index: usize,
+    taker_key: &Pubkey,
+    maker: &mut User,
+    maker_order_index: usize,
+    maker_key: &Pubkey,
+    perp_market_map: &PerpMarketMap,
+    spot_market_map: &SpotMarketMap,
+    oracle_map: &mut OracleMap,
+    now: i64,
+    slot: u64,
+    filler_key: &Pubkey,
+) -> DriftResult<bool> {
+    if taker.authority != maker.authority {
+        return Ok(false);
+    }
+
+    let explanation = OrderActionExplanation::SelfTradePrevented;
+    match taker.orders[taker_order_index].get_self_trade_prevention_mode() {
+        SelfTradePreventionMode::None => return Ok(false),
+        SelfTradePreventionMode::ExpireMaker => {
+            cancel_order(
+                maker_order_index,
+                maker,
+                maker_key,
+                perp_market_map,
+                spot_market_map,
+                oracle_map,
+                now,
+                slot,
+                explanation,
+                Some(filler_key),
+                0,
+                false,
+            )?;
+        }
+        SelfTradePreventionMode::ExpireTaker => {
+            cancel_order(
+                taker_order_index,
+                taker,
+                taker_key,
+                perp_market_map,
+                spot_market_map,
+                oracle_map,
+                now,
+                slot,
+                explanation,
+                Some(filler_key),
+                0,
+                false,
+            )?;
+        }
+        SelfTradePreventionMode::DecrementBoth => {
+            let base_asset_amount = taker.orders[taker_order_index]
+                .get_base_asset_amount_unfilled(None)?
+                .min(maker.orders[maker_order_index].get_base_asset_amount_unfilled(None)?);
+
+            decrement_perp_order_for_self_trade(taker, taker_order_index, base_asset_amount)?;
+            decrement_perp_order_for_self_trade(maker, maker_order_index, base_asset_amount)?;
+
+            let market_index = taker.orders[taker_order_index].market_index;
+            let oracle = perp_market_map.get_ref(&market_index)?.amm.oracle;
+            let order_action_record = get_order_action_record(
+                now,
+                OrderAction::Cancel,
+                explanation,
+                market_index,
+                Some(*filler_key),
+                None,
+                None,
+                Some(base_asset_amount),
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                Some(*taker_key),
+                Some(taker.orders[taker_order_index]),
+                Some(*maker_key),
+                Some(maker.orders[maker_order_index]),
+                oracle_map.get_price_data(&oracle)?.price,
+            )?;
+            emit_stack::<_, { OrderActionRecord::SIZE }>(order_action_record)?;
+
+            for (user, user_key, order_index) in [
+                (taker, taker_key, taker_order_index),
+                (maker, maker_key, maker_order_index),
+            ] {
+                if user.orders[order_index].get_base_asset_amount_unfilled(None)? == 0 {
+                    cancel_order(
+                        order_index,
+                        user,
+                        user_key,
+                        perp_market_map,
+                        spot_market_map,
+                        oracle_map,
+                        now,
+                        slot,
+                        explanation,
+                        Some(filler_key),
+                        0,
+                        true,
+                    )?;
+                }
+            }
+        }
+    }
+
+    Ok(true)
+}
+
+fn decrement_perp_order_for_self_trade(
+    user: &mut User,
+    order_index: usize,
+    base_asset_amount: u64,
+) -> DriftResult {
+    let (market_index, direction) =
+        get_struct_values!(user.orders[order_index], market_index, direction);
+
+    user.orders[order_index].base_asset_amount = user.orders[order_index]
+        .base_asset_amount
+        .safe_sub(base_asset_amount)?;
+
+    let position_index = get_position_index(&user.perp_positions, market_index)?;
+    decrease_open_bids_and_asks(
+        &mut user.perp_positions[position_index],
+        &direction,
+        base_asset_amount,
+    )?;
+
+    Ok(())
+}
+
+pub enum ModifyOrderId {
+    UserOrderId(u8),
+    OrderId(u32),
+}
+
+pub fn modify_order(
+    order_id: ModifyOrderId,
+    modify_order_params: ModifyOrderParams,
+    user_loader: &AccountLoader<User>,
+    state: &State,
+    perp_market_map: &PerpMarketMap,
+    spot_market_map: &SpotMarketMap,
+    oracle_map: &mut OracleMap,
+    clock: &Clock,
+) -> DriftResult {
+    let user_key = user_loader.key();
+    let mut user = load_mut!(user_loader)?;
+
+    let order_index =
+        match get_order_index_to_modify(&user, order_id, modify_order_params.policy.clone())? {
+            Some(order_index) => order_index,
+            None => return Ok(()),
+        };
+
+    modify_order_at_index(
+        order_index,
+        modify_order_params,
+        &mut user,
+        user_key,
+        state,
+        perp_market_map,
+        spot_market_map,
+        oracle_map,
+        clock,
+        &mut PlaceOrderOptions::default(),
+    )
+}
+
+/// Modifies many orders at once. Margin is checked once after every order is modified, against
+/// initial margin if any of the modified orders increased risk
+pub fn modify_orders(
+    modify_orders_params: Vec<ModifyOrderByIdParams>,
+    user_loader: &AccountLoader<User>,
+    state: &State,
+    perp_market_map: &PerpMarketMap,
+    spot_market_map: &SpotMarketMap,
+    oracle_map: &mut OracleMap,
+    clock: &Clock,
+) -> DriftResult {
+    let user_key = user_loader.key();
+    let mut user = load_mut!(user_loader)?;
+
+    // resolve every order up front. placing the modified orders only uses empty slots and doesnt
+    // expire orders, so the indexes of the orders still to be modified dont change
+    let mut orders_to_modify: Vec<(usize, ModifyOrderByIdParams)> =
+        Vec::with_capacity(modify_orders_params.len());
+    for params in modify_orders_params {
+        if let Some(order_index) = get_order_index_to_modify(
+            &user,
+            ModifyOrderId::OrderId(params.order_id),
+            params.modify_order_params.policy.clone(),
+        )? {
+            validate!(
+                !orders_to_modify
+                    .iter()
+                    .any(|(existing_order_index, _)| *existing_order_index == order_index),
+                ErrorCode::InvalidOrder,
+                "order id {} modified more than once",
+                params.order_id
+            )?;
+
+            orders_to_modify.push((order_index, params));
+        }
+    }
+
+    if orders_to_modify.is_empty() {
+        return Ok(());
+    }
+
+    // shared across the batch so the margin check sees if any modified order increased risk
+    let mut options = PlaceOrderOptions {
+        enforce_margin_check: false,
+        try_expire_orders: false,
+        risk_increasing: false,
+        explanation: OrderActionExplanation::None,
+    };
+
+    for (order_index, params) in orders_to_modify {
+        modify_order_at_index(
+            order_index,
+            params.modify_order_params,
+            &mut user,
+            user_key,
+            state,
+            perp_market_map,
+            spot_market_map,
+            oracle_map,
+            clock,
+            &mut options,
+        )?;
+    }
+
+    // checked after the loop since placing the last order can be skipped (e.g. expired max_ts)
+    meets_place_order_margin_requirement(
+        &user,
+        perp_market_map,
+        spot_market_map,
+        oracle_map,
+        options.risk_increasing,
+    )
+}
+
+/// Places many orders at once. Margin is checked once after every order is placed, against
+/// initial margin if any of the placed orders increased risk
+pub fn place_orders(
+    state: &State,
+    user: &mut User,
+    user_key: Pubkey,
+    perp_market_map: &PerpMarketMap,
+    spot_market_map: &SpotMarketMap,
+    oracle_map: &mut OracleMap,
+    clock: &Clock,
+    params: &[OrderParams],
+) -> DriftResult {
+    if params.is_empty() {
+        return Ok(());
+    }
+
+    // shared across the batch so the margin check sees if any placed order increased risk
+    let mut options = PlaceOrderOptions {
+        enforce_margin_check: false,
+        try_expire_orders: true,
+        risk_increasing: false,
+        explanation: OrderActionExplanation::None,
+    };
+
+    for (i, params) in params.iter().enumerate() {
+        validate!(
+            !params.immediate_or_cancel,
+            ErrorCode::InvalidOrderIOC,
+            "immediate_or_cancel order must be in place_and_make or place_and_take"
+        )?;
+
+        validate!(
+            !params.has_min_fill(),
+            ErrorCode::InvalidOrderMinFill,
+            "fill_or_kill and min_fill_base_asset_amount must be in place_and_take"
+        )?;
+
+        // only try to expire on first order
+        options.try_expire_orders = i == 0;
+
+        if params.market_type == MarketType::Perp {
+            place_perp_order(
+                state,
+                user,
+                user_key,
+                perp_market_map,
+                spot_market_map,
+                oracle_map,
+                clock,
+                *params,
+                &mut options,
+            )?;
+        } else {
+            place_spot_order(
+                state,
+                user,
+                user_key,
+                perp_market_map,
+                spot_market_map,
+                oracle_map,
+                clock,
+                *params,
+                &mut options,
+            )?;
+        }
+    }
+
+    // checked after the loop since placing the last order can be skipped (e.g. expired max_ts)
+    meets_place_order_margin_requirement(
+        user,
+        perp_market_map,
+        spot_market_map,
+        oracle_map,
+        options.risk_increasing,
+    )
+}
+
+/// Cancels the user's orders matching the market and direction filters then places new orders,
+/// so a maker can requote atomically
+pub fn cancel_and_place_orders(
+    state: &State,
+    user: &mut User,
+    user_key: Pubkey,
+    perp_market_map: &PerpMarketMap,
+    spot_market_map: &SpotMarketMap,
+    oracle_map: &mut OracleMap,
+    clock: &Clock,
+    market_type: MarketType,
+    market_index: u16,
+    direction: Option<PositionDirection>,
+    params: &[OrderParams],
+) -> DriftResult {
+    cancel_orders(
+        user,
+        &user_key,
+        None,
+        perp_market_map,
+        spot_market_map,
+        oracle_map,
+        clock.unix_timestamp,
+        clock.slot,
+        OrderActionExplanation::None,
+        Some(market_type),
+        Some(market_index),
+        direction,
+    )?;
+
+    place_orders(
+        state,
+        user,
+        user_key,
+        perp_market_map,
+        spot_market_map,
+        oracle_map,
+        clock,
+        params,
+    )
+}
+
+fn get_order_index_to_modify(
+    user: &User,
+    order_id: ModifyOrderId,
+    policy: Option<ModifyOrderPolicy>,
+) -> DriftResult<Option<usize>> {
+    let order_index = match order_id {
+        ModifyOrderId::UserOrderId(user_order_id) => {
+            match user.get_order_index_by_user_order_id(user_order_id) {
+                Ok(order_index) => order_index,
+                Err(e) => {
+                    msg!("User order id {} not found", user_order_id);
+                    if policy == Some(ModifyOrderPolicy::MustModify) {
+                        return Err(e);
+                    } else {
+                        return Ok(None);
+                    }
+                }
+            }
+        }
+        ModifyOrderId::OrderId(order_id) => match user.get_order_index(order_id) {
+            Ok(order_index) => order_index,
+            Err(e) => {
+                msg!("Order id {} not found", order_id);
+                if policy == Some(ModifyOrderPolicy::MustModify) {
+                    return Err(e);
+                } else {
+                    return Ok(None);
+                }
+            }
+        },
+    };
+
+    Ok(Some(order_index))
+}
+
+fn modify_order_at_index(
+    order_index: usize,
+    modify_order_params: ModifyOrderParams,
+    user: &mut User,
+    user_key: Pubkey,
+    state: &State,
+    perp_market_map: &PerpMarketMap,
+    spot_market_map: &SpotMarketMap,
+    oracle_map: &mut OracleMap,
+    clock: &Clock,
+    options: &mut PlaceOrderOptions,
+) -> DriftResult {
+    let existing_order = user.orders[order_index];
+
+    // the modified order is placed again with the same group, so bracket legs stay open
+    cancel_single_order(
+        order_index,
+        user,
+        &user_key,
+        perp_market_map,
+        spot_market_map,
+        oracle_map,
+        clock.unix_timestamp,
+        clock.slot,
+        OrderActionExplanation::None,
+        None,
+        0,
+        false,
+    )?;
+
+    user.update_last_active_slot(clock.slot);
+
+    let order_params =
+        merge_modify_order_params_with_existing_order(&existing_order, &modify_order_params)?;
+
+    if order_params.market_type == MarketType::Perp {
+        place_perp_order(
+            state,
+            user,
+            user_key,
+            perp_market_map,
+            spot_market_map,
+            oracle_map,
+            clock,
+            order_params,
+            options,
+        )?;
+    } else {
+        place_spot_order(
+            state,
+            user,
+            user_key,
+            perp_market_map,
+            spot_market_map,
+            oracle_map,
+            clock,
+            order_params,
+            options,
+        )?;
+    }
+
+    Ok(())
 }
 
 fn merge_modify_order_params_with_existing_order(
@@ -826,6 +1477,9 @@ fn merge_modify_order_params_with_existing_order(
     let oracle_price_offset = modify_order_params
         .oracle_price_offset
         .or(Some(existing_order.oracle_price_offset));
+    let bit_flags = existing_order.bit_flags;
+    let group_id = existing_order.group_id;
+    let twap_slices = existing_order.twap_slices;
     let (auction_duration, auction_start_price, auction_end_price) =
         if modify_order_params.auction_duration.is_some()
             && modify_order_params.auction_start_price.is_some()
@@ -858,9 +1512,106 @@ fn merge_modify_order_params_with_existing_order(
         auction_duration,
         auction_start_price,
         auction_end_price,
+        bit_flags,
+        group_id,
+        twap_slices,
+        fill_or_kill: false,
+        min_fill_base_asset_amount: None,
+        quote_asset_amount: None,
     })
 }
 
+/// Places a perp order and fills it against the makers and amm in the same instruction. Errors if
+/// less than the order's min fill is filled so the whole instruction reverts
+pub fn place_and_take_perp_order(
+    state: &State,
+    user: &AccountLoader<User>,
+    user_stats: &AccountLoader<UserStats>,
+    perp_market_map: &PerpMarketMap,
+    spot_market_map: &SpotMarketMap,
+    oracle_map: &mut OracleMap,
+    makers_and_referrer: &UserMap,
+    makers_and_referrer_stats: &UserStatsMap,
+    clock: &Clock,
+    params: OrderParams,
+) -> DriftResult<u64> {
+    let is_immediate_or_cancel = params.immediate_or_cancel || params.fill_or_kill;
+
+    let user_key = user.key();
+    let order_id = {
+        let mut user = load_mut!(user)?;
+
+        place_perp_order(
+            state,
+            &mut user,
+            user_key,
+            perp_market_map,
+            spot_market_map,
+            oracle_map,
+            clock,
+            params,
+            &mut PlaceOrderOptions::default(),
+        )?;
+
+        user.get_last_order_id()
+    };
+
+    let min_fill_base_asset_amount = if params.has_min_fill() {
+        let user = load!(user)?;
+        let order = user
+            .get_order(order_id)
+            .ok_or_else(print_error!(ErrorCode::OrderDoesNotExist))?;
+        params.get_min_fill_base_asset_amount(order.base_asset_amount)
+    } else {
+        None
+    };
+
+    let base_asset_amount_filled = fill_perp_order(
+        order_id,
+        state,
+        user,
+        user_stats,
+        spot_market_map,
+        perp_market_map,
+        oracle_map,
+        user,
+        user_stats,
+        makers_and_referrer,
+        makers_and_referrer_stats,
+        None,
+        clock,
+        FillMode::PlaceAndTake,
+    )?;
+
+    if let Some(min_fill_base_asset_amount) = min_fill_base_asset_amount {
+        validate!(
+            base_asset_amount_filled >= min_fill_base_asset_amount,
+            ErrorCode::OrderFillBelowMinimum,
+            "base_asset_amount_filled {} < min_fill_base_asset_amount {}",
+            base_asset_amount_filled,
+            min_fill_base_asset_amount
+        )?;
+    }
+
+    let order_exists = load!(user)?
+        .orders
+        .iter()
+        .any(|order| order.order_id == order_id);
+
+    if is_immediate_or_cancel && order_exists {
+        cancel_order_by_order_id(
+            order_id,
+            user,
+            perp_market_map,
+            spot_market_map,
+            oracle_map,
+            clock,
+        )?;
+    }
+
+    Ok(base_asset_amount_filled)
+}
+
 pub fn fill_perp_order(
     order_id: u32,
     state: &State,
@@ -938,6 +1689,12 @@ pub fn fill_perp_order(
         "Order must be triggered first"
     )?;
 
+    validate!(
+        !user.has_unfilled_bracket_parent(&user.orders[order_index]),
+        ErrorCode::BracketParentOrderNotFilled,
+        "Bracket parent order must be filled first"
+    )?;
+
     if user.is_bankrupt() {
         msg!("user is bankrupt");
         return Ok(0);
@@ -1002,6 +1759,17 @@ pub fn fill_perp_order(
             None
         };
 
+    if let Some(oracle_price) = valid_oracle_price {
+        let tick_size = perp_market_map.get_ref(&market_index)?.amm.order_tick_size;
+        update_trailing_stop_trigger_prices(
+            user,
+            MarketType::Perp,
+            market_index,
+            oracle_price,
+            tick_size,
+        )?;
+    }
+
     let is_filler_taker = user_key == filler_key;
     let is_filler_maker = makers_and_referrer.0.contains_key(&filler_key);
     let (mut filler, mut filler_stats) = if !is_filler_maker && !is_filler_taker {
@@ -1107,6 +1875,19 @@ pub fn fill_perp_order(
         return Ok(0);
     }
 
+    if user.orders[order_index].is_twap() {
+        let step_size = perp_market_map.get_ref(&market_index)?.amm.order_step_size;
+        let base_asset_amount_fillable =
+            user.orders[order_index].get_base_asset_amount_fillable(now, step_size)?;
+
+        if base_asset_amount_fillable == 0 {
+            msg!("twap order has no released slice to fill");
+            return Ok(0);
+        }
+    }
+
+    let order_before_fill = user.orders[order_index];
+
     let (base_asset_amount, quote_asset_amount) = fulfill_perp_order(
         user,
         order_index,
@@ -1192,6 +1973,30 @@ pub fn fill_perp_order(
         return Ok(0);
     }
 
+    cancel_linked_orders(
+        user,
+        &user_key,
+        &order_before_fill,
+        perp_market_map,
+        spot_market_map,
+        oracle_map,
+        now,
+        slot,
+        Some(&filler_key),
+    )?;
+
+    cancel_position_linked_orders(
+        user,
+        &user_key,
+        market_index,
+        perp_market_map,
+        spot_market_map,
+        oracle_map,
+        now,
+        slot,
+        Some(&filler_key),
+    )?;
+
     {
         let market = perp_market_map.get_ref(&market_index)?;
 
@@ -1225,6 +2030,47 @@ pub fn fill_perp_order(
         )?;
     }
 
+    // margin called users can only reduce risk, the fill may have brought them back above the buffer
+    if user.is_in_margin_call() {
+        update_user_margin_call(
+            state,
+            user,
+            &user_key,
+            perp_market_map,
+            spot_market_map,
+            oracle_map,
+            now,
+            slot,
+        )?;
+    }
+
+    for (maker_key, _, _) in maker_orders_info.iter() {
+        let mut maker = makers_and_referrer.get_ref_mut(maker_key)?;
+        if let Some(oracle_price) = valid_oracle_price {
+            let tick_size = perp_market_map.get_ref(&market_index)?.amm.order_tick_size;
+            update_trailing_stop_trigger_prices(
+                &mut maker,
+                MarketType::Perp,
+                market_index,
+                oracle_price,
+                tick_size,
+            )?;
+        }
+
+        if maker.is_in_margin_call() {
+            update_user_margin_call(
+                state,
+                &mut maker,
+                maker_key,
+                perp_market_map,
+                spot_market_map,
+                oracle_map,
+                now,
+                slot,
+            )?;
+        }
+    }
+
     user.update_last_active_slot(slot);
 
     Ok(base_asset_amount)
@@ -1356,6 +2202,11 @@ fn get_maker_orders_info(
                 continue;
             }
 
+            // bracket legs only rest on the book once their entry order has filled
+            if maker.has_unfilled_bracket_parent(maker_order) {
+                continue;
+            }
+
             if !are_orders_same_market_but_different_sides(maker_order, taker_order) {
                 continue;
             }
@@ -1565,11 +2416,33 @@ fn fulfill_perp_order(
     let mut base_asset_amount = 0_u64;
     let mut quote_asset_amount = 0_u64;
     let mut maker_fills: BTreeMap<Pubkey, i64> = BTreeMap::new();
+    let mut linked_maker_orders: Vec<(Pubkey, Order)> = vec![];
     let maker_direction = user.orders[user_order_index].direction.opposite();
     for fulfillment_method in fulfillment_methods.iter() {
         if user.orders[user_order_index].status != OrderStatus::Open {
             break;
         }
+
+        if let PerpFulfillmentMethod::Match(maker_key, maker_order_index) = fulfillment_method {
+            let mut maker = makers_and_referrer.get_ref_mut(maker_key)?;
+            if prevent_perp_self_trade(
+                user,
+                user_order_index,
+                user_key,
+                &mut maker,
+                *maker_order_index as usize,
+                maker_key,
+                perp_market_map,
+                spot_market_map,
+                oracle_map,
+                now,
+                slot,
+                filler_key,
+            )? {
+                continue;
+            }
+        }
+
         let mut market = perp_market_map.get_ref_mut(&market_index)?;
         let user_order_direction = user.orders[user_order_index].direction;
 
@@ -1622,6 +2495,8 @@ fn fulfill_perp_order(
                     Some(&maker),
                 )?;
 
+                let maker_order_before_fill = maker.orders[*maker_order_index as usize];
+
                 let (fill_base_asset_amount, fill_quote_asset_amount, maker_fill_base_asset_amount) =
                     fulfill_perp_order_with_match(
                         market.deref_mut(),
@@ -1654,6 +2529,10 @@ fn fulfill_perp_order(
                         maker_direction,
                         maker_fill_base_asset_amount,
                     )?;
+
+                    if maker_order_before_fill.group_id != 0 {
+                        linked_maker_orders.push((*maker_key, maker_order_before_fill));
+                    }
                 }
 
                 (fill_base_asset_amount, fill_quote_asset_amount)
@@ -1695,7 +2574,8 @@ fn fulfill_perp_order(
                 MarginRequirementType::Maintenance
             } else {
                 MarginRequirementType::Fill
-            }),
+            })
+            .scope(user.get_perp_margin_scope(market_index)),
         )?;
 
     if !taker_margin_calculation.meets_margin_requirement() {
@@ -1707,12 +2587,12 @@ fn fulfill_perp_order(
         return Err(ErrorCode::InsufficientCollateral);
     }
 
-    for (maker_key, maker_base_asset_amount_filled) in maker_fills {
-        let maker = makers_and_referrer.get_ref(&maker_key)?;
+    for (maker_key, maker_base_asset_amount_filled) in maker_fills.iter() {
+        let maker = makers_and_referrer.get_ref(maker_key)?;
 
         let margin_type = select_margin_type_for_perp_maker(
             &maker,
-            maker_base_asset_amount_filled,
+            *maker_base_asset_amount_filled,
             market_index,
         )?;
 
@@ -1722,7 +2602,8 @@ fn fulfill_perp_order(
                 perp_market_map,
                 spot_market_map,
                 oracle_map,
-                MarginContext::standard(margin_type),
+                MarginContext::standard(margin_type)
+                    .scope(maker.get_perp_margin_scope(market_index)),
             )?;
 
         if !maker_margin_calculation.meets_margin_requirement() {
@@ -1736,6 +2617,36 @@ fn fulfill_perp_order(
         }
     }
 
+    for (maker_key, maker_order) in linked_maker_orders.iter() {
+        let mut maker = makers_and_referrer.get_ref_mut(maker_key)?;
+        cancel_linked_orders(
+            &mut maker,
+            maker_key,
+            maker_order,
+            perp_market_map,
+            spot_market_map,
+            oracle_map,
+            now,
+            slot,
+            Some(filler_key),
+        )?;
+    }
+
+    for maker_key in maker_fills.keys() {
+        let mut maker = makers_and_referrer.get_ref_mut(maker_key)?;
+        cancel_position_linked_orders(
+            &mut maker,
+            maker_key,
+            market_index,
+            perp_market_map,
+            spot_market_map,
+            oracle_map,
+            now,
+            slot,
+            Some(filler_key),
+        )?;
+    }
+
     Ok((base_asset_amount, quote_asset_amount))
 }
 
@@ -1840,6 +2751,12 @@ pub fn fulfill_perp_order_with_amm(
                 fee_tier,
             )?;
 
+            // twap orders can only fill the slices released so far
+            let base_asset_amount = base_asset_amount.min(
+                user.orders[order_index]
+                    .get_base_asset_amount_fillable(now, market.amm.order_step_size)?,
+            );
+
             let fill_price = if user.orders[order_index].post_only {
                 limit_price
             } else {
@@ -2143,7 +3060,11 @@ pub fn fulfill_perp_order_with_match(
         .get_perp_position(market.market_index)?
         .base_asset_amount;
     let taker_base_asset_amount = taker.orders[taker_order_index]
-        .get_base_asset_amount_unfilled(Some(taker_existing_position))?;
+        .get_base_asset_amount_unfilled(Some(taker_existing_position))?
+        .min(
+            taker.orders[taker_order_index]
+                .get_base_asset_amount_fillable(now, market.amm.order_step_size)?,
+        );
 
     let maker_price = maker.orders[maker_order_index].force_get_limit_price(
         Some(oracle_price),
@@ -2237,7 +3158,11 @@ pub fn fulfill_perp_order_with_match(
         .base_asset_amount;
 
     let taker_base_asset_amount = taker.orders[taker_order_index]
-        .get_base_asset_amount_unfilled(Some(taker_existing_position))?;
+        .get_base_asset_amount_unfilled(Some(taker_existing_position))?
+        .min(
+            taker.orders[taker_order_index]
+                .get_base_asset_amount_fillable(now, market.amm.order_step_size)?,
+        );
 
     let (base_asset_amount_fulfilled_by_maker, quote_asset_amount) =
         calculate_fill_for_matched_orders(
@@ -2558,6 +3483,12 @@ pub fn trigger_order(
         "Order is already triggered"
     )?;
 
+    validate!(
+        !user.has_unfilled_bracket_parent(&user.orders[order_index]),
+        ErrorCode::OrderNotTriggerable,
+        "Bracket parent order must be filled first"
+    )?;
+
     validate!(
         market_type == MarketType::Perp,
         ErrorCode::InvalidOrderMarketType,
@@ -2593,16 +3524,57 @@ pub fn trigger_order(
 
     let oracle_price = oracle_price_data.price;
 
+    let trigger_price_updated = if user.orders[order_index].is_trailing_stop() {
+        update_trailing_stop_trigger_price(
+            &mut user.orders[order_index],
+            oracle_price.unsigned_abs().cast()?,
+            perp_market.amm.order_tick_size,
+        )?
+    } else {
+        false
+    };
+
     let can_trigger = order_satisfies_trigger_condition(
         &user.orders[order_index],
         oracle_price.unsigned_abs().cast()?,
     )?;
+
+    // trailing stop only ratcheted its trigger price, the keeper is still paid for keeping it current
+    if !can_trigger && trigger_price_updated {
+        let mut filler = if user_key != filler_key {
+            Some(load_mut!(filler)?)
+        } else {
+            None
+        };
+
+        pay_keeper_flat_reward_for_perps(
+            user,
+            filler.as_deref_mut(),
+            &mut perp_market,
+            state.perp_fee_structure.flat_filler_fee,
+            slot,
+        )?;
+
+        user.update_last_active_slot(slot);
+
+        return Ok(());
+    }
+
     validate!(can_trigger, ErrorCode::OrderDidNotSatisfyTriggerCondition)?;
 
     let worst_case_base_asset_amount_before = user
         .get_perp_position(market_index)?
         .worst_case_base_asset_amount()?;
 
+    if user.orders[order_index].is_position_linked() {
+        let existing_base_asset_amount = user.get_perp_position(market_index)?.base_asset_amount;
+        resize_position_linked_order(
+            &mut user.orders[order_index],
+            existing_base_asset_amount,
+            perp_market.amm.order_step_size,
+        )?;
+    }
+
     {
         update_trigger_order_params(
             &mut user.orders[order_index],
@@ -2672,8 +3644,13 @@ pub fn trigger_order(
 
     // If order increases risk and user is below initial margin, cancel it
     if is_risk_increasing && !user.orders[order_index].reduce_only {
-        let meets_initial_margin_requirement =
-            meets_initial_margin_requirement(user, perp_market_map, spot_market_map, oracle_map)?;
+        let meets_initial_margin_requirement = meets_initial_margin_requirement(
+            user,
+            perp_market_map,
+            spot_market_map,
+            oracle_map,
+            user.get_perp_margin_scope(market_index),
+        )?;
 
         if !meets_initial_margin_requirement {
             cancel_order(
@@ -2693,6 +3670,21 @@ pub fn trigger_order(
         }
     }
 
+    if user.orders[order_index].status == OrderStatus::Open {
+        let triggered_order = user.orders[order_index];
+        cancel_linked_orders(
+            user,
+            &user_key,
+            &triggered_order,
+            perp_market_map,
+            spot_market_map,
+            oracle_map,
+            now,
+            slot,
+            Some(&filler_key),
+        )?;
+    }
+
     user.update_last_active_slot(slot);
 
     Ok(())
@@ -2715,6 +3707,11 @@ fn update_trigger_order_params(
 
     order.slot = slot;
 
+    // trailing stop fires as a trigger market order, the offset was only the trailing distance
+    if order.is_trailing_stop() {
+        order.oracle_price_offset = 0;
+    }
+
     let (auction_duration, auction_start_price, auction_end_price) =
         calculate_auction_params_for_trigger_order(
             order,
@@ -2737,6 +3734,52 @@ fn update_trigger_order_params(
     Ok(())
 }
 
+/// Ratchets every untriggered trailing stop the user has in the market, so stops keep trailing
+/// the oracle whenever the user is touched by a fill and not only when a keeper triggers them
+pub fn update_trailing_stop_trigger_prices(
+    user: &mut User,
+    market_type: MarketType,
+    market_index: u16,
+    oracle_price: i64,
+    tick_size: u64,
+) -> DriftResult {
+    let oracle_price: u64 = oracle_price.unsigned_abs().cast()?;
+    for order in user.orders.iter_mut() {
+        if order.status == OrderStatus::Open
+            && order.is_trailing_stop()
+            && !order.triggered()
+            && order.market_type == market_type
+            && order.market_index == market_index
+        {
+            update_trailing_stop_trigger_price(order, oracle_price, tick_size)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn update_trailing_stop_trigger_price(
+    order: &mut Order,
+    oracle_price: u64,
+    tick_size: u64,
+) -> DriftResult<bool> {
+    let new_trigger_price = calculate_trailing_stop_trigger_price(order, oracle_price, tick_size)?;
+
+    if new_trigger_price == order.trigger_price {
+        return Ok(false);
+    }
+
+    msg!(
+        "trailing stop trigger price updated from {} to {}",
+        order.trigger_price,
+        new_trigger_price
+    );
+
+    order.trigger_price = new_trigger_price;
+
+    Ok(true)
+}
+
 pub fn force_cancel_orders(
     state: &State,
     user_account_loader: &AccountLoader<User>,
@@ -2851,6 +3894,92 @@ pub fn force_cancel_orders(
     Ok(())
 }
 
+pub fn cancel_all_orders_after_ts(
+    state: &State,
+    user_account_loader: &AccountLoader<User>,
+    spot_market_map: &SpotMarketMap,
+    perp_market_map: &PerpMarketMap,
+    oracle_map: &mut OracleMap,
+    filler: &AccountLoader<User>,
+    clock: &Clock,
+) -> DriftResult {
+    let now = clock.unix_timestamp;
+    let slot = clock.slot;
+
+    let filler_key = filler.key();
+    let user_key = user_account_loader.key();
+    let user = &mut load_mut!(user_account_loader)?;
+    let filler = &mut load_mut!(filler)?;
+
+    validate!(
+        !user.is_being_liquidated(),
+        ErrorCode::UserIsBeingLiquidated
+    )?;
+
+    validate!(!user.is_bankrupt(), ErrorCode::UserBankrupt)?;
+
+    validate!(
+        user.can_cancel_all_orders_after_ts(now),
+        ErrorCode::CancelAllAfterTsNotReached,
+        "cancel_all_after_ts {} not reached (now {})",
+        user.cancel_all_after_ts,
+        now
+    )?;
+
+    // the keeper is paid from the user's quote deposit, never creating a borrow
+    let mut fee_remaining = {
+        let quote_spot_market = spot_market_map.get_quote_spot_market()?;
+        user.get_quote_spot_position()
+            .get_signed_token_amount(&quote_spot_market)?
+            .max(0)
+            .cast::<u64>()?
+    };
+    let mut total_fee = 0_u64;
+
+    for order_index in 0..user.orders.len() {
+        if user.orders[order_index].status != OrderStatus::Open {
+            continue;
+        }
+
+        let fee = match user.orders[order_index].market_type {
+            MarketType::Perp => state.perp_fee_structure.flat_filler_fee,
+            MarketType::Spot => state.spot_fee_structure.flat_filler_fee,
+        }
+        .min(fee_remaining);
+
+        fee_remaining = fee_remaining.safe_sub(fee)?;
+        total_fee = total_fee.safe_add(fee)?;
+
+        cancel_order(
+            order_index,
+            user,
+            &user_key,
+            perp_market_map,
+            spot_market_map,
+            oracle_map,
+            now,
+            slot,
+            OrderActionExplanation::CancelAllAfterTs,
+            Some(&filler_key),
+            fee,
+            false,
+        )?;
+    }
+
+    pay_keeper_flat_reward_for_spot(
+        user,
+        Some(filler),
+        spot_market_map.get_quote_spot_market_mut()?.deref_mut(),
+        total_fee,
+        slot,
+    )?;
+
+    // switch has fired, the authority must set it again
+    user.cancel_all_after_ts = 0;
+
+    Ok(())
+}
+
 pub fn can_reward_user_with_perp_pnl(user: &mut Option<&mut User>, market_index: u16) -> bool {
     match user.as_mut() {
         Some(user) => user.force_get_perp_position_mut(market_index).is_ok(),
@@ -2866,94 +3995,345 @@ pub fn attempt_burn_user_lp_shares_for_risk_reduction(
     perp_market_map: &PerpMarketMap,
     spot_market_map: &SpotMarketMap,
     oracle_map: &mut OracleMap,
-    clock: &Clock,
-    market_index: u16,
-) -> DriftResult {
-    let now = clock.unix_timestamp;
-    let time_since_last_liquidity_change: i64 = now.safe_sub(user.last_add_perp_lp_shares_ts)?;
-    // avoid spamming update if orders have already been set
-    if time_since_last_liquidity_change >= state.lp_cooldown_time.cast()? {
-        burn_user_lp_shares_for_risk_reduction(
-            state,
+    clock: &Clock,
+    market_index: u16,
+) -> DriftResult {
+    let now = clock.unix_timestamp;
+    let time_since_last_liquidity_change: i64 = now.safe_sub(user.last_add_perp_lp_shares_ts)?;
+    // avoid spamming update if orders have already been set
+    if time_since_last_liquidity_change >= state.lp_cooldown_time.cast()? {
+        burn_user_lp_shares_for_risk_reduction(
+            state,
+            user,
+            user_key,
+            market_index,
+            margin_calc,
+            perp_market_map,
+            spot_market_map,
+            oracle_map,
+            clock,
+        )?;
+        user.last_add_perp_lp_shares_ts = now;
+    }
+
+    Ok(())
+}
+
+pub fn burn_user_lp_shares_for_risk_reduction(
+    state: &State,
+    user: &mut User,
+    user_key: Pubkey,
+    market_index: u16,
+    margin_calc: MarginCalculation,
+    perp_market_map: &PerpMarketMap,
+    spot_market_map: &SpotMarketMap,
+    oracle_map: &mut OracleMap,
+    clock: &Clock,
+) -> DriftResult {
+    let position_index = get_position_index(&user.perp_positions, market_index)?;
+    let is_lp = user.perp_positions[position_index].is_lp();
+    if !is_lp {
+        return Ok(());
+    }
+
+    let mut market = perp_market_map.get_ref_mut(&market_index)?;
+
+    let quote_oracle = spot_market_map
+        .get_ref(&market.quote_spot_market_index)?
+        .oracle;
+    let quote_oracle_price = oracle_map.get_price_data(&quote_oracle)?.price;
+
+    let oracle_price_data = oracle_map.get_price_data(&market.amm.oracle)?;
+
+    let oracle_price = if market.status == MarketStatus::Settlement {
+        market.expiry_price
+    } else {
+        oracle_price_data.price
+    };
+
+    let user_custom_margin_ratio = user.max_margin_ratio;
+    let (lp_shares_to_burn, base_asset_amount_to_close) =
+        calculate_lp_shares_to_burn_for_risk_reduction(
+            &user.perp_positions[position_index],
+            &market,
+            oracle_price,
+            quote_oracle_price,
+            margin_calc.margin_shortage()?,
+            user_custom_margin_ratio,
+        )?;
+
+    let (position_delta, pnl) = burn_lp_shares(
+        &mut user.perp_positions[position_index],
+        &mut market,
+        lp_shares_to_burn,
+        oracle_price,
+    )?;
+
+    // emit LP record for shares removed
+    emit_stack::<_, { LPRecord::SIZE }>(LPRecord {
+        ts: clock.unix_timestamp,
+        action: LPAction::RemoveLiquidityDerisk,
+        user: user_key,
+        n_shares: lp_shares_to_burn,
+        market_index,
+        delta_base_asset_amount: position_delta.base_asset_amount,
+        delta_quote_asset_amount: position_delta.quote_asset_amount,
+        pnl,
+    })?;
+
+    let direction_to_close = user.perp_positions[position_index].get_direction_to_close();
+
+    let params = OrderParams::get_close_perp_params(
+        &market,
+        direction_to_close,
+        base_asset_amount_to_close,
+    )?;
+
+    drop(market);
+
+    if user.has_room_for_new_order() {
+        controller::orders::place_perp_order(
+            state,
+            user,
+            user_key,
+            perp_market_map,
+            spot_market_map,
+            oracle_map,
+            clock,
+            params,
+            &mut PlaceOrderOptions::default().explanation(OrderActionExplanation::DeriskLp),
+        )?;
+    }
+
+    Ok(())
+}
+
+pub fn update_user_margin_call(
+    state: &State,
+    user: &mut User,
+    user_key: &Pubkey,
+    perp_market_map: &PerpMarketMap,
+    spot_market_map: &SpotMarketMap,
+    oracle_map: &mut OracleMap,
+    now: i64,
+    slot: u64,
+) -> DriftResult {
+    validate!(
+        !user.is_being_liquidated(),
+        ErrorCode::UserIsBeingLiquidated,
+        "user is being liquidated"
+    )?;
+
+    if state.margin_call_buffer_ratio == 0 {
+        user.exit_margin_call();
+        return Ok(());
+    }
+
+    let margin_calculation = calculate_margin_requirement_and_total_collateral_and_liability_info(
+        user,
+        perp_market_map,
+        spot_market_map,
+        oracle_map,
+        MarginContext::liquidation(state.margin_call_buffer_ratio),
+    )?;
+
+    let meets_initial_margin_requirement = meets_initial_margin_requirement(
+        user,
+        perp_market_map,
+        spot_market_map,
+        oracle_map,
+        MarginScope::Cross,
+    )?;
+
+    // margin call sits between maintenance and the lower of initial and maintenance plus the margin call buffer
+    if margin_calculation.can_exit_liquidation()? || meets_initial_margin_requirement {
+        user.exit_margin_call();
+    } else if margin_calculation.meets_margin_requirement() {
+        if !user.is_in_margin_call() {
+            user.enter_margin_call(slot);
+
+            cancel_risk_increasing_orders(
+                user,
+                user_key,
+                perp_market_map,
+                spot_market_map,
+                oracle_map,
+                now,
+                slot,
+                OrderActionExplanation::MarginCall,
+            )?;
+        }
+    } else {
+        msg!("user below maintenance margin, must be liquidated");
+    }
+
+    Ok(())
+}
+
+/// Cancels every open order that could add to a position. Reduce only and position reducing orders stay open
+fn cancel_risk_increasing_orders(
+    user: &mut User,
+    user_key: &Pubkey,
+    perp_market_map: &PerpMarketMap,
+    spot_market_map: &SpotMarketMap,
+    oracle_map: &mut OracleMap,
+    now: i64,
+    slot: u64,
+    explanation: OrderActionExplanation,
+) -> DriftResult<Vec<u32>> {
+    let mut canceled_order_ids: Vec<u32> = vec![];
+    for order_index in 0..user.orders.len() {
+        let order = user.orders[order_index];
+        if order.status != OrderStatus::Open || order.reduce_only {
+            continue;
+        }
+
+        let position_base_asset_amount = match order.market_type {
+            MarketType::Perp => user
+                .get_perp_position(order.market_index)
+                .map_or(0, |position| position.base_asset_amount),
+            MarketType::Spot => match user.get_spot_position(order.market_index) {
+                Ok(position) => position
+                    .get_signed_token_amount(&spot_market_map.get_ref(&order.market_index)?)?
+                    .cast()?,
+                Err(_) => 0,
+            },
+        };
+
+        if is_order_position_reducing(
+            &order.direction,
+            order.get_base_asset_amount_unfilled(None)?,
+            position_base_asset_amount,
+        )? {
+            continue;
+        }
+
+        canceled_order_ids.push(order.order_id);
+        cancel_order(
+            order_index,
             user,
             user_key,
-            market_index,
-            margin_calc,
             perp_market_map,
             spot_market_map,
             oracle_map,
-            clock,
+            now,
+            slot,
+            explanation,
+            None,
+            0,
+            false,
         )?;
-        user.last_add_perp_lp_shares_ts = now;
     }
 
-    Ok(())
+    Ok(canceled_order_ids)
 }
 
-pub fn burn_user_lp_shares_for_risk_reduction(
+pub fn place_margin_call_order(
     state: &State,
     user: &mut User,
     user_key: Pubkey,
     market_index: u16,
-    margin_calc: MarginCalculation,
     perp_market_map: &PerpMarketMap,
     spot_market_map: &SpotMarketMap,
     oracle_map: &mut OracleMap,
     clock: &Clock,
 ) -> DriftResult {
-    let position_index = get_position_index(&user.perp_positions, market_index)?;
-    let is_lp = user.perp_positions[position_index].is_lp();
-    if !is_lp {
+    let now = clock.unix_timestamp;
+    let slot = clock.slot;
+
+    validate!(
+        user.is_in_margin_call(),
+        ErrorCode::UserNotInMarginCall,
+        "user not in margin call"
+    )?;
+
+    let slots_in_margin_call = slot.safe_sub(user.last_active_slot)?;
+    validate!(
+        slots_in_margin_call >= state.margin_call_grace_period.cast::<u64>()?,
+        ErrorCode::MarginCallGracePeriodNotOver,
+        "slots in margin call {} < grace period {}",
+        slots_in_margin_call,
+        state.margin_call_grace_period
+    )?;
+
+    let margin_calculation = calculate_margin_requirement_and_total_collateral_and_liability_info(
+        user,
+        perp_market_map,
+        spot_market_map,
+        oracle_map,
+        MarginContext::liquidation(state.margin_call_buffer_ratio),
+    )?;
+
+    let meets_initial_margin_requirement = meets_initial_margin_requirement(
+        user,
+        perp_market_map,
+        spot_market_map,
+        oracle_map,
+        MarginScope::Cross,
+    )?;
+
+    if margin_calculation.can_exit_liquidation()? || meets_initial_margin_requirement {
+        msg!("user no longer below margin call requirement");
+        user.exit_margin_call();
         return Ok(());
     }
 
-    let mut market = perp_market_map.get_ref_mut(&market_index)?;
+    let base_asset_amount = user.get_perp_position(market_index)?.base_asset_amount;
+    validate!(
+        base_asset_amount != 0,
+        ErrorCode::UserHasNoPositionInMarket,
+        "user has no position in market {}",
+        market_index
+    )?;
+
+    // free up the user's orders in the market so the close isn't blocked or counted as risk increasing
+    cancel_orders(
+        user,
+        &user_key,
+        None,
+        perp_market_map,
+        spot_market_map,
+        oracle_map,
+        now,
+        slot,
+        OrderActionExplanation::MarginCall,
+        Some(MarketType::Perp),
+        Some(market_index),
+        None,
+    )?;
 
+    let market = perp_market_map.get_ref(&market_index)?;
+    let oracle_price = oracle_map.get_price_data(&market.amm.oracle)?.price;
     let quote_oracle = spot_market_map
         .get_ref(&market.quote_spot_market_index)?
         .oracle;
     let quote_oracle_price = oracle_map.get_price_data(&quote_oracle)?.price;
 
-    let oracle_price_data = oracle_map.get_price_data(&market.amm.oracle)?;
-
-    let oracle_price = if market.status == MarketStatus::Settlement {
-        market.expiry_price
-    } else {
-        oracle_price_data.price
-    };
+    let margin_ratio_with_buffer = market
+        .get_margin_ratio(
+            base_asset_amount.unsigned_abs().cast()?,
+            MarginRequirementType::Maintenance,
+        )?
+        .safe_add(state.margin_call_buffer_ratio)?;
 
-    let user_custom_margin_ratio = user.max_margin_ratio;
-    let (lp_shares_to_burn, base_asset_amount_to_close) =
-        calculate_lp_shares_to_burn_for_risk_reduction(
-            &user.perp_positions[position_index],
-            &market,
+    let base_asset_amount_to_close = standardize_base_asset_amount_ceil(
+        calculate_base_asset_amount_to_cover_margin_shortage(
+            margin_calculation.margin_shortage()?,
+            margin_ratio_with_buffer,
+            0,
+            0,
             oracle_price,
             quote_oracle_price,
-            margin_calc.margin_shortage()?,
-            user_custom_margin_ratio,
-        )?;
-
-    let (position_delta, pnl) = burn_lp_shares(
-        &mut user.perp_positions[position_index],
-        &mut market,
-        lp_shares_to_burn,
-        oracle_price,
-    )?;
-
-    // emit LP record for shares removed
-    emit_stack::<_, { LPRecord::SIZE }>(LPRecord {
-        ts: clock.unix_timestamp,
-        action: LPAction::RemoveLiquidityDerisk,
-        user: user_key,
-        n_shares: lp_shares_to_burn,
-        market_index,
-        delta_base_asset_amount: position_delta.base_asset_amount,
-        delta_quote_asset_amount: position_delta.quote_asset_amount,
-        pnl,
-    })?;
+        )?,
+        market.amm.order_step_size,
+    )?
+    .min(base_asset_amount.unsigned_abs());
 
-    let direction_to_close = user.perp_positions[position_index].get_direction_to_close();
+    let direction_to_close = user
+        .get_perp_position(market_index)?
+        .get_direction_to_close();
 
+    // oracle auction bounded by the market's baseline spread
     let params = OrderParams::get_close_perp_params(
         &market,
         direction_to_close,
@@ -2962,19 +4342,20 @@ pub fn burn_user_lp_shares_for_risk_reduction(
 
     drop(market);
 
-    if user.has_room_for_new_order() {
-        controller::orders::place_perp_order(
-            state,
-            user,
-            user_key,
-            perp_market_map,
-            spot_market_map,
-            oracle_map,
-            clock,
-            params,
-            PlaceOrderOptions::default().explanation(OrderActionExplanation::DeriskLp),
-        )?;
-    }
+    place_perp_order(
+        state,
+        user,
+        user_key,
+        perp_market_map,
+        spot_market_map,
+        oracle_map,
+        clock,
+        params,
+        &mut PlaceOrderOptions::default().explanation(OrderActionExplanation::MarginCall),
+    )?;
+
+    // restart the grace period so the auction can run before the next forced order
+    user.last_active_slot = slot;
 
     Ok(())
 }
@@ -3060,8 +4441,8 @@ pub fn place_spot_order(
     spot_market_map: &SpotMarketMap,
     oracle_map: &mut OracleMap,
     clock: &Clock,
-    params: OrderParams,
-    mut options: PlaceOrderOptions,
+    mut params: OrderParams,
+    options: &mut PlaceOrderOptions,
 ) -> DriftResult {
     let now = clock.unix_timestamp;
     let slot = clock.slot;
@@ -3138,6 +4519,30 @@ pub fn place_spot_order(
         "Market is being initialized"
     )?;
 
+    let oracle_price_data = *oracle_map.get_price_data(&spot_market.oracle)?;
+
+    let (auction_start_price, auction_end_price, auction_duration) = get_auction_params(
+        &params,
+        &oracle_price_data,
+        spot_market.order_tick_size,
+        state.default_spot_auction_duration,
+    )?;
+
+    if let Some(quote_asset_amount) = params.quote_asset_amount {
+        validate!(
+            params.base_asset_amount == 0,
+            ErrorCode::InvalidOrder,
+            "base_asset_amount must be 0 for orders sized in quote"
+        )?;
+
+        params.base_asset_amount = calculate_base_asset_amount_for_quote_notional(
+            quote_asset_amount,
+            params.get_price_for_quote_notional(oracle_price_data.price, auction_end_price)?,
+            spot_market.get_precision(),
+            step_size,
+        )?;
+    }
+
     let spot_position_index = user
         .get_spot_position_index(market_index)
         .or_else(|_| user.add_spot_position(market_index, SpotBalanceType::Deposit))?;
@@ -3146,8 +4551,6 @@ pub fn place_spot_order(
     let token_amount = user.spot_positions[spot_position_index].get_token_amount(spot_market)?;
     let signed_token_amount = get_signed_token_amount(token_amount, &balance_type)?;
 
-    let oracle_price_data = *oracle_map.get_price_data(&spot_market.oracle)?;
-
     // Increment open orders for existing position
     let (existing_position_direction, order_base_asset_amount) = {
         validate!(
@@ -3190,13 +4593,6 @@ pub fn place_spot_order(
         )
     };
 
-    let (auction_start_price, auction_end_price, auction_duration) = get_auction_params(
-        &params,
-        &oracle_price_data,
-        spot_market.order_tick_size,
-        state.default_spot_auction_duration,
-    )?;
-
     validate!(spot_market.orders_enabled, ErrorCode::SpotOrdersDisabled)?;
 
     validate!(
@@ -3239,7 +4635,9 @@ pub fn place_spot_order(
         auction_end_price,
         auction_duration,
         max_ts,
-        padding: [0; 3],
+        bit_flags: params.bit_flags,
+        group_id: params.group_id,
+        twap_slices: params.twap_slices,
     };
 
     validate_spot_order(
@@ -3255,6 +4653,14 @@ pub fn place_spot_order(
         user.spot_positions[spot_position_index].open_asks,
     )?;
 
+    if user.is_in_margin_call() {
+        validate!(
+            !risk_increasing,
+            ErrorCode::UserInMarginCall,
+            "only risk reducing orders can be placed while in margin call"
+        )?;
+    }
+
     user.increment_open_orders(new_order.has_auction());
     user.orders[new_order_index] = new_order;
     user.spot_positions[spot_position_index].open_orders += 1;
@@ -3390,6 +4796,12 @@ pub fn fill_spot_order(
         "Order must be triggered first"
     )?;
 
+    validate!(
+        !user.has_unfilled_bracket_parent(&user.orders[order_index]),
+        ErrorCode::BracketParentOrderNotFilled,
+        "Bracket parent order must be filled first"
+    )?;
+
     if user.is_bankrupt() {
         msg!("User is bankrupt");
         return Ok(0);
@@ -3471,6 +4883,19 @@ pub fn fill_spot_order(
         }
     }
 
+    {
+        let tick_size = spot_market_map
+            .get_ref(&order_market_index)?
+            .order_tick_size;
+        update_trailing_stop_trigger_prices(
+            user,
+            MarketType::Spot,
+            order_market_index,
+            oracle_price,
+            tick_size,
+        )?;
+    }
+
     let should_expire_order = should_expire_order_before_fill(user, order_index, now)?;
 
     let should_cancel_reduce_only = if user.orders[order_index].reduce_only {
@@ -3523,6 +4948,8 @@ pub fn fill_spot_order(
         return Ok(0);
     }
 
+    let order_before_fill = user.orders[order_index];
+
     let (base_asset_amount, quote_asset_amount) = fulfill_spot_order(
         user,
         order_index,
@@ -3629,6 +5056,35 @@ pub fn fill_spot_order(
         )?
     }
 
+    if base_asset_amount != 0 {
+        cancel_linked_orders(
+            user,
+            &user_key,
+            &order_before_fill,
+            perp_market_map,
+            spot_market_map,
+            oracle_map,
+            now,
+            slot,
+            Some(&filler_key),
+        )?;
+    }
+
+    if base_asset_amount != 0 {
+        let tick_size = spot_market_map
+            .get_ref(&order_market_index)?
+            .order_tick_size;
+        for (maker_key, _, _) in maker_order_info.iter() {
+            update_trailing_stop_trigger_prices(
+                &mut makers_and_referrer.get_ref_mut(maker_key)?,
+                MarketType::Spot,
+                order_market_index,
+                oracle_price,
+                tick_size,
+            )?;
+        }
+    }
+
     spot_market_map
         .get_ref(&order_market_index)?
         .validate_max_token_deposits()?;
@@ -3704,6 +5160,11 @@ fn get_spot_maker_orders_info(
                 continue;
             }
 
+            // bracket legs only rest on the book once their entry order has filled
+            if maker.has_unfilled_bracket_parent(maker_order) {
+                continue;
+            }
+
             if !are_orders_same_market_but_different_sides(maker_order, taker_order) {
                 continue;
             }
@@ -3851,6 +5312,7 @@ fn fulfill_spot_order(
     let mut base_asset_amount = 0_u64;
     let mut quote_asset_amount = 0_u64;
     let mut maker_fills: BTreeMap<Pubkey, i64> = BTreeMap::new();
+    let mut linked_maker_orders: Vec<(Pubkey, Order)> = vec![];
     let maker_direction = user.orders[user_order_index].direction.opposite();
     for fulfillment_method in fulfillment_methods.iter() {
         if user.orders[user_order_index].status != OrderStatus::Open {
@@ -3866,6 +5328,8 @@ fn fulfill_spot_order(
                     Some(makers_and_referrer_stats.get_ref_mut(&maker.authority)?)
                 };
 
+                let maker_order_before_fill = maker.orders[*maker_order_index as usize];
+
                 let (base_filled, quote_filled) = fulfill_spot_order_with_match(
                     &mut base_market,
                     &mut quote_market,
@@ -3893,6 +5357,10 @@ fn fulfill_spot_order(
                         maker_direction,
                         base_filled,
                     )?;
+
+                    if maker_order_before_fill.group_id != 0 {
+                        linked_maker_orders.push((*maker_key, maker_order_before_fill));
+                    }
                 }
 
                 (base_filled, quote_filled)
@@ -4068,6 +5536,21 @@ fn fulfill_spot_order(
         }
     }
 
+    for (maker_key, maker_order) in linked_maker_orders.iter() {
+        let mut maker = makers_and_referrer.get_ref_mut(maker_key)?;
+        cancel_linked_orders(
+            &mut maker,
+            maker_key,
+            maker_order,
+            perp_market_map,
+            spot_market_map,
+            oracle_map,
+            now,
+            slot,
+            Some(filler_key),
+        )?;
+    }
+
     Ok((base_asset_amount, quote_asset_amount))
 }
 
@@ -4707,6 +6190,12 @@ pub fn trigger_spot_order(
         "Order is already triggered"
     )?;
 
+    validate!(
+        !user.has_unfilled_bracket_parent(&user.orders[order_index]),
+        ErrorCode::OrderNotTriggerable,
+        "Bracket parent order must be filled first"
+    )?;
+
     validate!(
         market_type == MarketType::Spot,
         ErrorCode::InvalidOrderMarketType,
@@ -4749,10 +6238,45 @@ pub fn trigger_spot_order(
 
     let oracle_price = oracle_price_data.price;
 
+    let trigger_price_updated = if user.orders[order_index].is_trailing_stop() {
+        update_trailing_stop_trigger_price(
+            &mut user.orders[order_index],
+            oracle_price.unsigned_abs().cast()?,
+            spot_market.order_tick_size,
+        )?
+    } else {
+        false
+    };
+
     let can_trigger = order_satisfies_trigger_condition(
         &user.orders[order_index],
         oracle_price.unsigned_abs().cast()?,
     )?;
+
+    // trailing stop only ratcheted its trigger price, the keeper is still paid for keeping it current
+    if !can_trigger && trigger_price_updated {
+        drop(spot_market);
+
+        let mut filler = if user_key != filler_key {
+            Some(load_mut!(filler)?)
+        } else {
+            None
+        };
+
+        let mut quote_market = spot_market_map.get_quote_spot_market_mut()?;
+        pay_keeper_flat_reward_for_spot(
+            user,
+            filler.as_deref_mut(),
+            &mut quote_market,
+            state.spot_fee_structure.flat_filler_fee,
+            slot,
+        )?;
+
+        user.update_last_active_slot(slot);
+
+        return Ok(());
+    }
+
     validate!(can_trigger, ErrorCode::OrderDidNotSatisfyTriggerCondition)?;
 
     let position_index = user.get_spot_position_index(market_index)?;
@@ -4844,8 +6368,13 @@ pub fn trigger_spot_order(
 
     // If order is risk increasing and user is below initial margin, cancel it
     if is_risk_increasing && !user.orders[order_index].reduce_only {
-        let meets_initial_margin_requirement =
-            meets_initial_margin_requirement(user, perp_market_map, spot_market_map, oracle_map)?;
+        let meets_initial_margin_requirement = meets_initial_margin_requirement(
+            user,
+            perp_market_map,
+            spot_market_map,
+            oracle_map,
+            MarginScope::Cross,
+        )?;
 
         if !meets_initial_margin_requirement {
             cancel_order(
@@ -4865,6 +6394,21 @@ pub fn trigger_spot_order(
         }
     }
 
+    if user.orders[order_index].status == OrderStatus::Open {
+        let triggered_order = user.orders[order_index];
+        cancel_linked_orders(
+            user,
+            &user_key,
+            &triggered_order,
+            perp_market_map,
+            spot_market_map,
+            oracle_map,
+            now,
+            slot,
+            Some(&filler_key),
+        )?;
+    }
+
     user.update_last_active_slot(slot);
 
     Ok(())
@@ -9439,96 +9439,46 @@ pub mod force_cancel_orders {
     }
 }
 
-pub mod insert_maker_order_info {
-    use crate::controller::orders::insert_maker_order_info;
-    use crate::controller::position::PositionDirection;
-    use solana_program::pubkey::Pubkey;
-
-    #[test]
-    fn bids() {
-        let mut bids = Vec::with_capacity(3);
-        bids.push((Pubkey::default(), 1, 10));
-        bids.push((Pubkey::default(), 0, 1));
-        let maker_direction = PositionDirection::Long;
-
-        insert_maker_order_info(&mut bids, (Pubkey::default(), 2, 100), maker_direction);
-
-        assert_eq!(
-            bids,
-            vec![
-                (Pubkey::default(), 2, 100),
-                (Pubkey::default(), 1, 10),
-                (Pubkey::default(), 0, 1),
-            ]
-        );
-    }
-
-    #[test]
-    fn asks() {
-        let mut asks = Vec::with_capacity(3);
-        asks.push((Pubkey::default(), 0, 1));
-        asks.push((Pubkey::default(), 1, 10));
-        let maker_direction = PositionDirection::Short;
-
-        insert_maker_order_info(&mut asks, (Pubkey::default(), 2, 100), maker_direction);
-
-        assert_eq!(
-            asks,
-            vec![
-                (Pubkey::default(), 0, 1),
-                (Pubkey::default(), 1, 10),
-                (Pubkey::default(), 2, 100)
-            ]
-        );
-    }
-}
-
-pub mod get_maker_orders_info {
+pub mod cancel_linked_orders {
     use std::str::FromStr;
 
-    use anchor_lang::prelude::{AccountLoader, Clock};
+    use anchor_lang::prelude::Clock;
 
-    use crate::controller::orders::get_maker_orders_info;
+    use crate::controller::orders::cancel_linked_orders;
     use crate::controller::position::PositionDirection;
+    use crate::create_account_info;
+    use crate::create_anchor_account_info;
     use crate::math::constants::{
         AMM_RESERVE_PRECISION, BASE_PRECISION_I64, BASE_PRECISION_U64, PEG_PRECISION,
-        PRICE_PRECISION_I64, PRICE_PRECISION_U64, SPOT_BALANCE_PRECISION_U64,
-        SPOT_CUMULATIVE_INTEREST_PRECISION, SPOT_WEIGHT_PRECISION,
+        PRICE_PRECISION_U64, SPOT_CUMULATIVE_INTEREST_PRECISION, SPOT_WEIGHT_PRECISION,
     };
-    use crate::state::oracle::HistoricalOracleData;
-    use crate::state::oracle::OracleSource;
+    use crate::state::oracle::{HistoricalOracleData, OracleSource};
     use crate::state::perp_market::{PerpMarket, AMM};
     use crate::state::perp_market_map::PerpMarketMap;
-    use crate::state::spot_market::{SpotBalanceType, SpotMarket};
+    use crate::state::spot_market::SpotMarket;
     use crate::state::spot_market_map::SpotMarketMap;
-    use crate::state::user::{OrderStatus, OrderType, SpotPosition, User};
-    use crate::state::user_map::UserMap;
+    use crate::state::user::{OrderBitFlag, OrderStatus, OrderTriggerCondition, OrderType, User};
     use crate::test_utils::*;
-    use crate::test_utils::{
-        create_account_info, get_orders, get_positions, get_pyth_price, get_spot_positions,
-    };
-    use crate::{create_account_info, get_orders};
-    use crate::{create_anchor_account_info, QUOTE_PRECISION_I64};
+    use crate::test_utils::{create_account_info, get_positions, get_pyth_price};
 
     use super::*;
 
     #[test]
-    fn one_maker_order_canceled_for_breaching_oracle_price_band() {
+    fn leg_cancels_siblings() {
         let clock = Clock {
-            slot: 56,
+            slot: 6,
             epoch_start_timestamp: 0,
             epoch: 0,
             leader_schedule_epoch: 0,
             unix_timestamp: 0,
         };
 
-        let mut pyth_price = get_pyth_price(100, 6);
-        let oracle_price = 100 * PRICE_PRECISION_I64;
+        let mut oracle_price = get_pyth_price(100, 6);
         let oracle_price_key =
             Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
         let pyth_program = crate::ids::pyth_program::id();
         create_account_info!(
-            pyth_price,
+            oracle_price,
             &oracle_price_key,
             &pyth_program,
             oracle_account_info
@@ -9539,50 +9489,24 @@ pub mod get_maker_orders_info {
             amm: AMM {
                 base_asset_reserve: 100 * AMM_RESERVE_PRECISION,
                 quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
-                terminal_quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
                 sqrt_k: 100 * AMM_RESERVE_PRECISION,
                 peg_multiplier: 100 * PEG_PRECISION,
-                max_slippage_ratio: 100,
-                max_fill_reserve_fraction: 100,
                 order_step_size: 1000,
                 order_tick_size: 1,
                 oracle: oracle_price_key,
-                max_spread: 1000,
-                base_spread: 0,
-                long_spread: 0,
-                short_spread: 0,
                 historical_oracle_data: HistoricalOracleData {
-                    last_oracle_price_twap: pyth_price.twap as i64,
-                    last_oracle_price_twap_5min: pyth_price.twap as i64,
-                    last_oracle_price: pyth_price.agg.price as i64,
+                    last_oracle_price_twap: oracle_price.twap as i64,
+                    last_oracle_price_twap_5min: oracle_price.twap as i64,
+                    last_oracle_price: oracle_price.agg.price as i64,
                     ..HistoricalOracleData::default()
                 },
                 ..AMM::default()
             },
             margin_ratio_initial: 1000,
             margin_ratio_maintenance: 500,
-            status: MarketStatus::Initialized,
+            status: MarketStatus::Active,
             ..PerpMarket::default()
         };
-        market.status = MarketStatus::Active;
-        market.amm.max_base_asset_reserve = u128::MAX;
-        market.amm.min_base_asset_reserve = 0;
-        let (new_ask_base_asset_reserve, new_ask_quote_asset_reserve) =
-            crate::math::amm_spread::calculate_spread_reserves(
-                &market.amm,
-                PositionDirection::Long,
-            )
-            .unwrap();
-        let (new_bid_base_asset_reserve, new_bid_quote_asset_reserve) =
-            crate::math::amm_spread::calculate_spread_reserves(
-                &market.amm,
-                PositionDirection::Short,
-            )
-            .unwrap();
-        market.amm.ask_base_asset_reserve = new_ask_base_asset_reserve;
-        market.amm.bid_base_asset_reserve = new_bid_base_asset_reserve;
-        market.amm.ask_quote_asset_reserve = new_ask_quote_asset_reserve;
-        market.amm.bid_quote_asset_reserve = new_bid_quote_asset_reserve;
         create_anchor_account_info!(market, PerpMarket, market_account_info);
         let market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
 
@@ -9593,316 +9517,199 @@ pub mod get_maker_orders_info {
             decimals: 6,
             initial_asset_weight: SPOT_WEIGHT_PRECISION,
             maintenance_asset_weight: SPOT_WEIGHT_PRECISION,
-            historical_oracle_data: HistoricalOracleData::default_price(QUOTE_PRECISION_I64),
             ..SpotMarket::default()
         };
         create_anchor_account_info!(spot_market, SpotMarket, spot_market_account_info);
         let spot_market_map = SpotMarketMap::load_one(&spot_market_account_info, true).unwrap();
 
-        let taker_key = Pubkey::default();
-        let taker_authority =
-            Pubkey::from_str("My11111111111111111111111111111111111111111").unwrap();
-        let user = User {
-            authority: taker_authority,
-            orders: get_orders(Order {
-                market_index: 0,
-                order_id: 1,
-                status: OrderStatus::Open,
-                order_type: OrderType::Market,
-                direction: PositionDirection::Long,
-                base_asset_amount: BASE_PRECISION_U64,
-                slot: 0,
-                auction_start_price: 0,
-                auction_end_price: 50 * PRICE_PRECISION_I64,
-                auction_duration: 5,
-                price: 50 * PRICE_PRECISION_U64,
-                ..Order::default()
-            }),
-            perp_positions: get_positions(PerpPosition {
-                market_index: 0,
-                open_orders: 1,
-                open_bids: BASE_PRECISION_I64,
-                ..PerpPosition::default()
-            }),
-            spot_positions: get_spot_positions(SpotPosition {
-                market_index: 0,
-                balance_type: SpotBalanceType::Deposit,
-                scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
-                ..SpotPosition::default()
-            }),
-            ..User::default()
-        };
-
-        let mut maker_orders = [Order::default(); 32];
-        maker_orders[0] = Order {
+        let mut orders = [Order::default(); 32];
+        // entry
+        orders[0] = Order {
             market_index: 0,
             order_id: 1,
             status: OrderStatus::Open,
             order_type: OrderType::Limit,
-            direction: PositionDirection::Short,
-            base_asset_amount: BASE_PRECISION_U64,
-            slot: 0,
-            price: 50 * PRICE_PRECISION_U64,
-            post_only: true,
+            market_type: MarketType::Perp,
+            direction: PositionDirection::Long,
+            base_asset_amount: 2 * BASE_PRECISION_U64,
+            base_asset_amount_filled: BASE_PRECISION_U64,
+            price: 100 * PRICE_PRECISION_U64,
+            group_id: 1,
+            bit_flags: OrderBitFlag::BracketParent as u8,
             ..Order::default()
         };
-        maker_orders[1] = Order {
+        // take profit
+        orders[1] = Order {
             market_index: 0,
             order_id: 2,
             status: OrderStatus::Open,
-            order_type: OrderType::Limit,
+            order_type: OrderType::TriggerMarket,
+            market_type: MarketType::Perp,
+            direction: PositionDirection::Short,
+            base_asset_amount: 2 * BASE_PRECISION_U64,
+            trigger_price: 110 * PRICE_PRECISION_U64,
+            trigger_condition: OrderTriggerCondition::Above,
+            reduce_only: true,
+            group_id: 1,
+            ..Order::default()
+        };
+        // stop loss
+        orders[2] = Order {
+            market_index: 0,
+            order_id: 3,
+            status: OrderStatus::Open,
+            order_type: OrderType::TriggerMarket,
+            market_type: MarketType::Perp,
             direction: PositionDirection::Short,
+            base_asset_amount: 2 * BASE_PRECISION_U64,
+            trigger_price: 90 * PRICE_PRECISION_U64,
+            trigger_condition: OrderTriggerCondition::Below,
+            reduce_only: true,
+            group_id: 1,
+            ..Order::default()
+        };
+        // unlinked
+        orders[3] = Order {
+            market_index: 0,
+            order_id: 4,
+            status: OrderStatus::Open,
+            order_type: OrderType::Limit,
+            market_type: MarketType::Perp,
+            direction: PositionDirection::Long,
             base_asset_amount: BASE_PRECISION_U64,
-            slot: 0,
-            price: 100 * PRICE_PRECISION_U64,
-            post_only: true,
+            price: 95 * PRICE_PRECISION_U64,
             ..Order::default()
         };
 
-        let mut maker = User {
-            orders: maker_orders,
+        let mut user = User {
+            orders,
             perp_positions: get_positions(PerpPosition {
                 market_index: 0,
-                open_orders: 2,
-                open_asks: -2 * BASE_PRECISION_I64,
+                base_asset_amount: BASE_PRECISION_I64,
+                open_orders: 4,
+                open_bids: 2 * BASE_PRECISION_I64,
                 ..PerpPosition::default()
             }),
-            spot_positions: get_spot_positions(SpotPosition {
-                market_index: 0,
-                balance_type: SpotBalanceType::Deposit,
-                scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
-                ..SpotPosition::default()
-            }),
             ..User::default()
         };
-        let maker_key = Pubkey::from_str("My11111111111111111111111111111111111111113").unwrap();
-        create_anchor_account_info!(maker, &maker_key, User, maker_account_info);
-
-        let makers_and_referrers = UserMap::load_one(&maker_account_info).unwrap();
 
-        let filler_key = Pubkey::from_str("My11111111111111111111111111111111111111111").unwrap();
-        let mut filler = User::default();
+        assert!(!user.has_unfilled_bracket_parent(&user.orders[1]));
 
-        let maker_order_price_and_indexes = get_maker_orders_info(
+        // parent fills do not cancel the legs
+        let parent = user.orders[0];
+        let canceled_order_ids = cancel_linked_orders(
+            &mut user,
+            &Pubkey::default(),
+            &parent,
             &market_map,
             &spot_market_map,
             &mut oracle_map,
-            &makers_and_referrers,
-            &taker_key,
-            &user.orders[0],
-            &mut Some(&mut filler),
-            &filler_key,
-            0,
-            oracle_price,
+            clock.unix_timestamp,
+            clock.slot,
             None,
+        )
+        .unwrap();
+        assert!(canceled_order_ids.is_empty());
+
+        let take_profit = user.orders[1];
+        let canceled_order_ids = cancel_linked_orders(
+            &mut user,
+            &Pubkey::default(),
+            &take_profit,
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
             clock.unix_timestamp,
             clock.slot,
+            None,
         )
         .unwrap();
 
-        assert_eq!(
-            maker_order_price_and_indexes,
-            vec![(maker_key, 1, 100 * PRICE_PRECISION_U64)]
-        );
+        assert_eq!(canceled_order_ids, vec![1, 3]);
+        assert_eq!(user.orders[0], Order::default());
+        assert_eq!(user.orders[1], take_profit);
+        assert_eq!(user.orders[2], Order::default());
+        assert_eq!(user.orders[3].status, OrderStatus::Open);
+        assert_eq!(user.perp_positions[0].open_orders, 2);
+        assert_eq!(user.perp_positions[0].open_bids, BASE_PRECISION_I64);
     }
+}
 
-    #[test]
-    fn one_maker_order_canceled_for_being_expired() {
-        let clock = Clock {
-            slot: 56,
-            epoch_start_timestamp: 0,
-            epoch: 0,
-            leader_schedule_epoch: 0,
-            unix_timestamp: 6,
-        };
+pub mod prevent_perp_self_trade {
+    use std::str::FromStr;
 
-        let mut pyth_price = get_pyth_price(100, 6);
-        let oracle_price = 100 * PRICE_PRECISION_I64;
-        let oracle_price_key =
-            Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
-        let pyth_program = crate::ids::pyth_program::id();
-        create_account_info!(
-            pyth_price,
-            &oracle_price_key,
-            &pyth_program,
-            oracle_account_info
-        );
-        let mut oracle_map = OracleMap::load_one(&oracle_account_info, clock.slot, None).unwrap();
+    use anchor_lang::prelude::Clock;
 
-        let mut market = PerpMarket {
-            amm: AMM {
-                base_asset_reserve: 100 * AMM_RESERVE_PRECISION,
-                quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
-                terminal_quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
-                sqrt_k: 100 * AMM_RESERVE_PRECISION,
-                peg_multiplier: 100 * PEG_PRECISION,
-                max_slippage_ratio: 100,
-                max_fill_reserve_fraction: 100,
-                order_step_size: 1000,
-                order_tick_size: 1,
-                oracle: oracle_price_key,
-                max_spread: 1000,
-                base_spread: 0,
-                long_spread: 0,
-                short_spread: 0,
-                historical_oracle_data: HistoricalOracleData {
-                    last_oracle_price_twap: pyth_price.twap as i64,
-                    last_oracle_price_twap_5min: pyth_price.twap as i64,
-                    last_oracle_price: pyth_price.agg.price as i64,
-                    ..HistoricalOracleData::default()
-                },
-                ..AMM::default()
-            },
-            margin_ratio_initial: 1000,
-            margin_ratio_maintenance: 500,
-            status: MarketStatus::Initialized,
-            ..PerpMarket::default()
-        };
-        market.status = MarketStatus::Active;
-        market.amm.max_base_asset_reserve = u128::MAX;
-        market.amm.min_base_asset_reserve = 0;
-        let (new_ask_base_asset_reserve, new_ask_quote_asset_reserve) =
-            crate::math::amm_spread::calculate_spread_reserves(
-                &market.amm,
-                PositionDirection::Long,
-            )
-            .unwrap();
-        let (new_bid_base_asset_reserve, new_bid_quote_asset_reserve) =
-            crate::math::amm_spread::calculate_spread_reserves(
-                &market.amm,
-                PositionDirection::Short,
-            )
-            .unwrap();
-        market.amm.ask_base_asset_reserve = new_ask_base_asset_reserve;
-        market.amm.bid_base_asset_reserve = new_bid_base_asset_reserve;
-        market.amm.ask_quote_asset_reserve = new_ask_quote_asset_reserve;
-        market.amm.bid_quote_asset_reserve = new_bid_quote_asset_reserve;
-        create_anchor_account_info!(market, PerpMarket, market_account_info);
-        let market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+    use crate::controller::orders::prevent_perp_self_trade;
+    use crate::controller::position::PositionDirection;
+    use crate::create_account_info;
+    use crate::create_anchor_account_info;
+    use crate::math::constants::{
+        AMM_RESERVE_PRECISION, BASE_PRECISION_I64, BASE_PRECISION_U64, PEG_PRECISION,
+        PRICE_PRECISION_U64, SPOT_CUMULATIVE_INTEREST_PRECISION, SPOT_WEIGHT_PRECISION,
+    };
+    use crate::state::oracle::{HistoricalOracleData, OracleSource};
+    use crate::state::perp_market::{PerpMarket, AMM};
+    use crate::state::perp_market_map::PerpMarketMap;
+    use crate::state::spot_market::SpotMarket;
+    use crate::state::spot_market_map::SpotMarketMap;
+    use crate::state::user::{OrderBitFlag, OrderStatus, OrderType, User};
+    use crate::test_utils::*;
+    use crate::test_utils::{create_account_info, get_orders, get_positions, get_pyth_price};
 
-        let mut spot_market = SpotMarket {
-            market_index: 0,
-            oracle_source: OracleSource::QuoteAsset,
-            cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
-            decimals: 6,
-            initial_asset_weight: SPOT_WEIGHT_PRECISION,
-            maintenance_asset_weight: SPOT_WEIGHT_PRECISION,
-            historical_oracle_data: HistoricalOracleData::default_price(QUOTE_PRECISION_I64),
-            ..SpotMarket::default()
-        };
-        create_anchor_account_info!(spot_market, SpotMarket, spot_market_account_info);
-        let spot_market_map = SpotMarketMap::load_one(&spot_market_account_info, true).unwrap();
+    use super::*;
 
-        let taker_key = Pubkey::default();
-        let taker_authority =
-            Pubkey::from_str("My11111111111111111111111111111111111111111").unwrap();
-        let user = User {
-            authority: taker_authority,
+    fn get_users(taker_bit_flags: u8, maker_authority: Pubkey) -> (User, User) {
+        let taker = User {
+            authority: Pubkey::default(),
             orders: get_orders(Order {
                 market_index: 0,
                 order_id: 1,
                 status: OrderStatus::Open,
                 order_type: OrderType::Market,
+                market_type: MarketType::Perp,
                 direction: PositionDirection::Long,
-                base_asset_amount: BASE_PRECISION_U64,
-                slot: 0,
-                auction_start_price: 0,
-                auction_end_price: 50 * PRICE_PRECISION_I64,
-                auction_duration: 5,
-                price: 100 * PRICE_PRECISION_U64,
+                base_asset_amount: 2 * BASE_PRECISION_U64,
+                bit_flags: taker_bit_flags,
                 ..Order::default()
             }),
             perp_positions: get_positions(PerpPosition {
                 market_index: 0,
                 open_orders: 1,
-                open_bids: BASE_PRECISION_I64,
+                open_bids: 2 * BASE_PRECISION_I64,
                 ..PerpPosition::default()
             }),
-            spot_positions: get_spot_positions(SpotPosition {
-                market_index: 0,
-                balance_type: SpotBalanceType::Deposit,
-                scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
-                ..SpotPosition::default()
-            }),
             ..User::default()
         };
 
-        let mut maker_orders = [Order::default(); 32];
-        maker_orders[0] = Order {
-            market_index: 0,
-            order_id: 1,
-            status: OrderStatus::Open,
-            order_type: OrderType::Limit,
-            direction: PositionDirection::Short,
-            base_asset_amount: BASE_PRECISION_U64,
-            slot: 0,
-            price: 100 * PRICE_PRECISION_U64,
-            max_ts: 1,
-            post_only: true,
-            ..Order::default()
-        };
-        maker_orders[1] = Order {
-            market_index: 0,
-            order_id: 2,
-            status: OrderStatus::Open,
-            order_type: OrderType::Limit,
-            direction: PositionDirection::Short,
-            base_asset_amount: BASE_PRECISION_U64,
-            slot: 0,
-            price: 100 * PRICE_PRECISION_U64,
-            post_only: true,
-            ..Order::default()
-        };
-
-        let mut maker = User {
-            orders: maker_orders,
-            perp_positions: get_positions(PerpPosition {
+        let maker = User {
+            authority: maker_authority,
+            orders: get_orders(Order {
                 market_index: 0,
-                open_orders: 2,
-                open_asks: -2 * BASE_PRECISION_I64,
-                ..PerpPosition::default()
+                order_id: 1,
+                status: OrderStatus::Open,
+                order_type: OrderType::Limit,
+                market_type: MarketType::Perp,
+                direction: PositionDirection::Short,
+                base_asset_amount: BASE_PRECISION_U64,
+                price: 100 * PRICE_PRECISION_U64,
+                post_only: true,
+                ..Order::default()
             }),
-            spot_positions: get_spot_positions(SpotPosition {
+            perp_positions: get_positions(PerpPosition {
                 market_index: 0,
-                balance_type: SpotBalanceType::Deposit,
-                scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
-                ..SpotPosition::default()
+                open_orders: 1,
+                open_asks: -BASE_PRECISION_I64,
+                ..PerpPosition::default()
             }),
             ..User::default()
         };
-        let maker_key = Pubkey::from_str("My11111111111111111111111111111111111111113").unwrap();
-        create_anchor_account_info!(maker, &maker_key, User, maker_account_info);
-
-        let makers_and_referrers = UserMap::load_one(&maker_account_info).unwrap();
-
-        let filler_key = Pubkey::from_str("My11111111111111111111111111111111111111111").unwrap();
-        let mut filler = User::default();
-
-        let maker_order_price_and_indexes = get_maker_orders_info(
-            &market_map,
-            &spot_market_map,
-            &mut oracle_map,
-            &makers_and_referrers,
-            &taker_key,
-            &user.orders[0],
-            &mut Some(&mut filler),
-            &filler_key,
-            0,
-            oracle_price,
-            None,
-            clock.unix_timestamp,
-            clock.slot,
-        )
-        .unwrap();
 
-        assert_eq!(
-            maker_order_price_and_indexes,
-            vec![(maker_key, 1, 100 * PRICE_PRECISION_U64)]
-        );
+        (taker, maker)
     }
 
     #[test]
-    fn one_maker_order_canceled_for_being_reduce_only() {
+    fn modes() {
         let clock = Clock {
             slot: 6,
             epoch_start_timestamp: 0,
@@ -9911,13 +9718,12 @@ pub mod get_maker_orders_info {
             unix_timestamp: 0,
         };
 
-        let mut pyth_price = get_pyth_price(100, 6);
-        let oracle_price = 100 * PRICE_PRECISION_I64;
+        let mut oracle_price = get_pyth_price(100, 6);
         let oracle_price_key =
             Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
         let pyth_program = crate::ids::pyth_program::id();
         create_account_info!(
-            pyth_price,
+            oracle_price,
             &oracle_price_key,
             &pyth_program,
             oracle_account_info
@@ -9928,50 +9734,24 @@ pub mod get_maker_orders_info {
             amm: AMM {
                 base_asset_reserve: 100 * AMM_RESERVE_PRECISION,
                 quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
-                terminal_quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
                 sqrt_k: 100 * AMM_RESERVE_PRECISION,
                 peg_multiplier: 100 * PEG_PRECISION,
-                max_slippage_ratio: 100,
-                max_fill_reserve_fraction: 100,
                 order_step_size: 1000,
                 order_tick_size: 1,
                 oracle: oracle_price_key,
-                max_spread: 1000,
-                base_spread: 0,
-                long_spread: 0,
-                short_spread: 0,
                 historical_oracle_data: HistoricalOracleData {
-                    last_oracle_price_twap: pyth_price.twap as i64,
-                    last_oracle_price_twap_5min: pyth_price.twap as i64,
-                    last_oracle_price: pyth_price.agg.price as i64,
+                    last_oracle_price_twap: oracle_price.twap as i64,
+                    last_oracle_price_twap_5min: oracle_price.twap as i64,
+                    last_oracle_price: oracle_price.agg.price as i64,
                     ..HistoricalOracleData::default()
                 },
                 ..AMM::default()
             },
             margin_ratio_initial: 1000,
             margin_ratio_maintenance: 500,
-            status: MarketStatus::Initialized,
+            status: MarketStatus::Active,
             ..PerpMarket::default()
         };
-        market.status = MarketStatus::Active;
-        market.amm.max_base_asset_reserve = u128::MAX;
-        market.amm.min_base_asset_reserve = 0;
-        let (new_ask_base_asset_reserve, new_ask_quote_asset_reserve) =
-            crate::math::amm_spread::calculate_spread_reserves(
-                &market.amm,
-                PositionDirection::Long,
-            )
-            .unwrap();
-        let (new_bid_base_asset_reserve, new_bid_quote_asset_reserve) =
-            crate::math::amm_spread::calculate_spread_reserves(
-                &market.amm,
-                PositionDirection::Short,
-            )
-            .unwrap();
-        market.amm.ask_base_asset_reserve = new_ask_base_asset_reserve;
-        market.amm.bid_base_asset_reserve = new_bid_base_asset_reserve;
-        market.amm.ask_quote_asset_reserve = new_ask_quote_asset_reserve;
-        market.amm.bid_quote_asset_reserve = new_bid_quote_asset_reserve;
         create_anchor_account_info!(market, PerpMarket, market_account_info);
         let market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
 
@@ -9982,110 +9762,250 @@ pub mod get_maker_orders_info {
             decimals: 6,
             initial_asset_weight: SPOT_WEIGHT_PRECISION,
             maintenance_asset_weight: SPOT_WEIGHT_PRECISION,
-            historical_oracle_data: HistoricalOracleData::default_price(QUOTE_PRECISION_I64),
             ..SpotMarket::default()
         };
         create_anchor_account_info!(spot_market, SpotMarket, spot_market_account_info);
         let spot_market_map = SpotMarketMap::load_one(&spot_market_account_info, true).unwrap();
 
-        let taker_key = Pubkey::default();
-        let taker_authority =
-            Pubkey::from_str("My11111111111111111111111111111111111111111").unwrap();
-        let user = User {
-            authority: taker_authority,
-            orders: get_orders(Order {
-                market_index: 0,
-                order_id: 1,
-                status: OrderStatus::Open,
-                order_type: OrderType::Market,
-                direction: PositionDirection::Long,
-                base_asset_amount: BASE_PRECISION_U64,
-                slot: 0,
-                auction_start_price: 0,
-                auction_end_price: 50 * PRICE_PRECISION_I64,
-                auction_duration: 5,
-                price: 100 * PRICE_PRECISION_U64,
-                max_ts: 1,
-                ..Order::default()
-            }),
-            perp_positions: get_positions(PerpPosition {
-                market_index: 0,
-                open_orders: 1,
-                open_bids: BASE_PRECISION_I64,
-                ..PerpPosition::default()
-            }),
-            spot_positions: get_spot_positions(SpotPosition {
-                market_index: 0,
-                balance_type: SpotBalanceType::Deposit,
-                scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
-                ..SpotPosition::default()
-            }),
-            ..User::default()
-        };
-
-        let mut maker_orders = [Order::default(); 32];
-        maker_orders[0] = Order {
-            market_index: 0,
-            order_id: 1,
-            status: OrderStatus::Open,
-            order_type: OrderType::Limit,
-            direction: PositionDirection::Short,
-            base_asset_amount: BASE_PRECISION_U64,
-            slot: 0,
-            price: 100 * PRICE_PRECISION_U64,
-            reduce_only: true,
-            ..Order::default()
-        };
+        let taker_key = Pubkey::new_unique();
+        let maker_key = Pubkey::new_unique();
+        let filler_key = Pubkey::default();
 
-        let mut maker = User {
-            orders: maker_orders,
-            perp_positions: get_positions(PerpPosition {
-                market_index: 0,
-                base_asset_amount: -BASE_PRECISION_I64,
-                open_orders: 1,
-                open_asks: -BASE_PRECISION_I64,
-                ..PerpPosition::default()
-            }),
-            spot_positions: get_spot_positions(SpotPosition {
-                market_index: 0,
-                balance_type: SpotBalanceType::Deposit,
-                scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
-                ..SpotPosition::default()
-            }),
-            ..User::default()
-        };
-        let maker_key = Pubkey::from_str("My11111111111111111111111111111111111111113").unwrap();
-        create_anchor_account_info!(maker, &maker_key, User, maker_account_info);
-
-        let makers_and_referrers = UserMap::load_one(&maker_account_info).unwrap();
+        // different authority always matches
+        let (mut taker, mut maker) =
+            get_users(OrderBitFlag::StpExpireMaker as u8, Pubkey::new_unique());
+        let skip = prevent_perp_self_trade(
+            &mut taker,
+            0,
+            &taker_key,
+            &mut maker,
+            0,
+            &maker_key,
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            clock.unix_timestamp,
+            clock.slot,
+            &filler_key,
+        )
+        .unwrap();
+        assert!(!skip);
 
-        let filler_key = Pubkey::from_str("My11111111111111111111111111111111111111111").unwrap();
-        let mut filler = User::default();
+        // no mode set allows self trade
+        let (mut taker, mut maker) = get_users(0, Pubkey::default());
+        let skip = prevent_perp_self_trade(
+            &mut taker,
+            0,
+            &taker_key,
+            &mut maker,
+            0,
+            &maker_key,
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            clock.unix_timestamp,
+            clock.slot,
+            &filler_key,
+        )
+        .unwrap();
+        assert!(!skip);
 
-        let maker_order_price_and_indexes = get_maker_orders_info(
+        let (mut taker, mut maker) =
+            get_users(OrderBitFlag::StpExpireMaker as u8, Pubkey::default());
+        let skip = prevent_perp_self_trade(
+            &mut taker,
+            0,
+            &taker_key,
+            &mut maker,
+            0,
+            &maker_key,
             &market_map,
             &spot_market_map,
             &mut oracle_map,
-            &makers_and_referrers,
+            clock.unix_timestamp,
+            clock.slot,
+            &filler_key,
+        )
+        .unwrap();
+        assert!(skip);
+        assert_eq!(taker.orders[0].status, OrderStatus::Open);
+        assert_eq!(maker.orders[0], Order::default());
+        assert_eq!(maker.perp_positions[0].open_asks, 0);
+
+        let (mut taker, mut maker) =
+            get_users(OrderBitFlag::StpExpireTaker as u8, Pubkey::default());
+        let skip = prevent_perp_self_trade(
+            &mut taker,
+            0,
             &taker_key,
-            &user.orders[0],
-            &mut Some(&mut filler),
+            &mut maker,
+            0,
+            &maker_key,
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            clock.unix_timestamp,
+            clock.slot,
             &filler_key,
+        )
+        .unwrap();
+        assert!(skip);
+        assert_eq!(taker.orders[0], Order::default());
+        assert_eq!(taker.perp_positions[0].open_bids, 0);
+        assert_eq!(maker.orders[0].status, OrderStatus::Open);
+
+        let (mut taker, mut maker) =
+            get_users(OrderBitFlag::StpDecrementBoth as u8, Pubkey::default());
+        let skip = prevent_perp_self_trade(
+            &mut taker,
             0,
-            oracle_price,
-            None,
+            &taker_key,
+            &mut maker,
+            0,
+            &maker_key,
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
             clock.unix_timestamp,
             clock.slot,
+            &filler_key,
         )
         .unwrap();
+        assert!(skip);
+        assert_eq!(taker.orders[0].status, OrderStatus::Open);
+        assert_eq!(taker.orders[0].base_asset_amount, BASE_PRECISION_U64);
+        assert_eq!(taker.perp_positions[0].open_bids, BASE_PRECISION_I64);
+        assert_eq!(taker.perp_positions[0].open_orders, 1);
+        assert_eq!(maker.orders[0], Order::default());
+        assert_eq!(maker.perp_positions[0].open_asks, 0);
+        assert_eq!(maker.perp_positions[0].open_orders, 0);
+    }
+}
 
-        assert_eq!(maker_order_price_and_indexes, vec![],);
+pub mod resize_position_linked_order {
+    use crate::controller::orders::resize_position_linked_order;
+    use crate::controller::position::PositionDirection;
+    use crate::math::constants::{BASE_PRECISION_I64, BASE_PRECISION_U64};
+    use crate::state::user::{Order, OrderBitFlag, OrderStatus, OrderType};
+
+    fn get_order(direction: PositionDirection) -> Order {
+        Order {
+            status: OrderStatus::Open,
+            order_type: OrderType::TriggerMarket,
+            direction,
+            base_asset_amount: BASE_PRECISION_U64,
+            reduce_only: true,
+            bit_flags: OrderBitFlag::PositionLinked as u8,
+            ..Order::default()
+        }
     }
 
     #[test]
-    fn two_makers() {
+    fn position_grew() {
+        let mut order = get_order(PositionDirection::Short);
+        resize_position_linked_order(&mut order, 3 * BASE_PRECISION_I64, 1000).unwrap();
+        assert_eq!(order.base_asset_amount, 3 * BASE_PRECISION_U64);
+
+        let mut order = get_order(PositionDirection::Long);
+        resize_position_linked_order(&mut order, -3 * BASE_PRECISION_I64, 1000).unwrap();
+        assert_eq!(order.base_asset_amount, 3 * BASE_PRECISION_U64);
+    }
+
+    #[test]
+    fn position_shrank() {
+        let mut order = get_order(PositionDirection::Short);
+        resize_position_linked_order(&mut order, BASE_PRECISION_I64 / 2, 1000).unwrap();
+        assert_eq!(order.base_asset_amount, BASE_PRECISION_U64 / 2);
+    }
+
+    #[test]
+    fn position_same_side_or_closed() {
+        let mut order = get_order(PositionDirection::Short);
+        resize_position_linked_order(&mut order, -3 * BASE_PRECISION_I64, 1000).unwrap();
+        assert_eq!(order.base_asset_amount, BASE_PRECISION_U64);
+
+        resize_position_linked_order(&mut order, 0, 1000).unwrap();
+        assert_eq!(order.base_asset_amount, BASE_PRECISION_U64);
+    }
+}
+
+pub mod insert_maker_order_info {
+    use crate::controller::orders::insert_maker_order_info;
+    use crate::controller::position::PositionDirection;
+    use solana_program::pubkey::Pubkey;
+
+    #[test]
+    fn bids() {
+        let mut bids = Vec::with_capacity(3);
+        bids.push((Pubkey::default(), 1, 10));
+        bids.push((Pubkey::default(), 0, 1));
+        let maker_direction = PositionDirection::Long;
+
+        insert_maker_order_info(&mut bids, (Pubkey::default(), 2, 100), maker_direction);
+
+        assert_eq!(
+            bids,
+            vec![
+                (Pubkey::default(), 2, 100),
+                (Pubkey::default(), 1, 10),
+                (Pubkey::default(), 0, 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn asks() {
+        let mut asks = Vec::with_capacity(3);
+        asks.push((Pubkey::default(), 0, 1));
+        asks.push((Pubkey::default(), 1, 10));
+        let maker_direction = PositionDirection::Short;
+
+        insert_maker_order_info(&mut asks, (Pubkey::default(), 2, 100), maker_direction);
+
+        assert_eq!(
+            asks,
+            vec![
+                (Pubkey::default(), 0, 1),
+                (Pubkey::default(), 1, 10),
+                (Pubkey::default(), 2, 100)
+            ]
+        );
+    }
+}
+
+pub mod get_maker_orders_info {
+    use std::str::FromStr;
+
+    use anchor_lang::prelude::{AccountLoader, Clock};
+
+    use crate::controller::orders::get_maker_orders_info;
+    use crate::controller::position::PositionDirection;
+    use crate::math::constants::{
+        AMM_RESERVE_PRECISION, BASE_PRECISION_I64, BASE_PRECISION_U64, PEG_PRECISION,
+        PRICE_PRECISION_I64, PRICE_PRECISION_U64, SPOT_BALANCE_PRECISION_U64,
+        SPOT_CUMULATIVE_INTEREST_PRECISION, SPOT_WEIGHT_PRECISION,
+    };
+    use crate::state::oracle::HistoricalOracleData;
+    use crate::state::oracle::OracleSource;
+    use crate::state::perp_market::{PerpMarket, AMM};
+    use crate::state::perp_market_map::PerpMarketMap;
+    use crate::state::spot_market::{SpotBalanceType, SpotMarket};
+    use crate::state::spot_market_map::SpotMarketMap;
+    use crate::state::user::{OrderStatus, OrderType, SpotPosition, User};
+    use crate::state::user_map::UserMap;
+    use crate::test_utils::*;
+    use crate::test_utils::{
+        create_account_info, get_orders, get_positions, get_pyth_price, get_spot_positions,
+    };
+    use crate::{create_account_info, get_orders};
+    use crate::{create_anchor_account_info, QUOTE_PRECISION_I64};
+
+    use super::*;
+
+    #[test]
+    fn one_maker_order_canceled_for_breaching_oracle_price_band() {
         let clock = Clock {
-            slot: 6,
+            slot: 56,
             epoch_start_timestamp: 0,
             epoch: 0,
             leader_schedule_epoch: 0,
@@ -10185,8 +10105,7 @@ pub mod get_maker_orders_info {
                 auction_start_price: 0,
                 auction_end_price: 50 * PRICE_PRECISION_I64,
                 auction_duration: 5,
-                price: 100 * PRICE_PRECISION_U64,
-                max_ts: 1,
+                price: 50 * PRICE_PRECISION_U64,
                 ..Order::default()
             }),
             perp_positions: get_positions(PerpPosition {
@@ -10204,34 +10123,38 @@ pub mod get_maker_orders_info {
             ..User::default()
         };
 
-        let mut first_maker = User {
-            orders: get_orders!(
-                Order {
-                    market_index: 0,
-                    order_id: 1,
-                    status: OrderStatus::Open,
-                    order_type: OrderType::Limit,
-                    direction: PositionDirection::Short,
-                    base_asset_amount: BASE_PRECISION_U64,
-                    slot: 0,
-                    price: 100 * PRICE_PRECISION_U64,
-                    ..Order::default()
-                },
-                Order {
-                    market_index: 0,
-                    order_id: 1,
-                    status: OrderStatus::Open,
-                    order_type: OrderType::Limit,
-                    direction: PositionDirection::Short,
-                    base_asset_amount: BASE_PRECISION_U64,
-                    slot: 0,
-                    price: 102 * PRICE_PRECISION_U64,
-                    ..Order::default()
-                }
-            ),
+        let mut maker_orders = [Order::default(); 32];
+        maker_orders[0] = Order {
+            market_index: 0,
+            order_id: 1,
+            status: OrderStatus::Open,
+            order_type: OrderType::Limit,
+            direction: PositionDirection::Short,
+            base_asset_amount: BASE_PRECISION_U64,
+            slot: 0,
+            price: 50 * PRICE_PRECISION_U64,
+            post_only: true,
+            ..Order::default()
+        };
+        maker_orders[1] = Order {
+            market_index: 0,
+            order_id: 2,
+            status: OrderStatus::Open,
+            order_type: OrderType::Limit,
+            direction: PositionDirection::Short,
+            base_asset_amount: BASE_PRECISION_U64,
+            slot: 0,
+            price: 100 * PRICE_PRECISION_U64,
+            post_only: true,
+            ..Order::default()
+        };
+
+        let mut maker = User {
+            orders: maker_orders,
             perp_positions: get_positions(PerpPosition {
                 market_index: 0,
                 open_orders: 2,
+                open_asks: -2 * BASE_PRECISION_I64,
                 ..PerpPosition::default()
             }),
             spot_positions: get_spot_positions(SpotPosition {
@@ -10242,69 +10165,10 @@ pub mod get_maker_orders_info {
             }),
             ..User::default()
         };
-        let first_maker_key =
-            Pubkey::from_str("My11111111111111111111111111111111111111113").unwrap();
-        create_anchor_account_info!(
-            first_maker,
-            &first_maker_key,
-            User,
-            first_maker_account_info
-        );
+        let maker_key = Pubkey::from_str("My11111111111111111111111111111111111111113").unwrap();
+        create_anchor_account_info!(maker, &maker_key, User, maker_account_info);
 
-        let mut second_maker = User {
-            orders: get_orders!(
-                Order {
-                    market_index: 0,
-                    order_id: 1,
-                    status: OrderStatus::Open,
-                    order_type: OrderType::Limit,
-                    direction: PositionDirection::Short,
-                    base_asset_amount: BASE_PRECISION_U64,
-                    slot: 0,
-                    price: 101 * PRICE_PRECISION_U64,
-                    ..Order::default()
-                },
-                Order {
-                    market_index: 0,
-                    order_id: 1,
-                    status: OrderStatus::Open,
-                    order_type: OrderType::Limit,
-                    direction: PositionDirection::Short,
-                    base_asset_amount: BASE_PRECISION_U64,
-                    slot: 0,
-                    price: 103 * PRICE_PRECISION_U64,
-                    ..Order::default()
-                }
-            ),
-            perp_positions: get_positions(PerpPosition {
-                market_index: 0,
-                open_orders: 2,
-                ..PerpPosition::default()
-            }),
-            spot_positions: get_spot_positions(SpotPosition {
-                market_index: 0,
-                balance_type: SpotBalanceType::Deposit,
-                scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
-                ..SpotPosition::default()
-            }),
-            ..User::default()
-        };
-        let second_maker_key =
-            Pubkey::from_str("My11111111111111111111111111111111111111112").unwrap();
-        create_anchor_account_info!(
-            second_maker,
-            &second_maker_key,
-            User,
-            second_maker_account_info
-        );
-
-        let mut makers_and_referrers = UserMap::load_one(&first_maker_account_info).unwrap();
-        makers_and_referrers
-            .insert(
-                second_maker_key,
-                AccountLoader::try_from(&second_maker_account_info).unwrap(),
-            )
-            .unwrap();
+        let makers_and_referrers = UserMap::load_one(&maker_account_info).unwrap();
 
         let filler_key = Pubkey::from_str("My11111111111111111111111111111111111111111").unwrap();
         let mut filler = User::default();
@@ -10328,23 +10192,18 @@ pub mod get_maker_orders_info {
 
         assert_eq!(
             maker_order_price_and_indexes,
-            vec![
-                (first_maker_key, 0, 100000000),
-                (second_maker_key, 0, 101000000),
-                (first_maker_key, 1, 102000000),
-                (second_maker_key, 1, 103000000),
-            ],
+            vec![(maker_key, 1, 100 * PRICE_PRECISION_U64)]
         );
     }
 
     #[test]
-    fn jit_maker_order_id() {
+    fn one_maker_order_canceled_for_being_expired() {
         let clock = Clock {
-            slot: 6,
+            slot: 56,
             epoch_start_timestamp: 0,
             epoch: 0,
             leader_schedule_epoch: 0,
-            unix_timestamp: 0,
+            unix_timestamp: 6,
         };
 
         let mut pyth_price = get_pyth_price(100, 6);
@@ -10441,7 +10300,6 @@ pub mod get_maker_orders_info {
                 auction_end_price: 50 * PRICE_PRECISION_I64,
                 auction_duration: 5,
                 price: 100 * PRICE_PRECISION_U64,
-                max_ts: 1,
                 ..Order::default()
             }),
             perp_positions: get_positions(PerpPosition {
@@ -10459,34 +10317,39 @@ pub mod get_maker_orders_info {
             ..User::default()
         };
 
-        let mut first_maker = User {
-            orders: get_orders!(
-                Order {
-                    market_index: 0,
-                    order_id: 1,
-                    status: OrderStatus::Open,
-                    order_type: OrderType::Limit,
-                    direction: PositionDirection::Short,
-                    base_asset_amount: BASE_PRECISION_U64,
-                    slot: 0,
-                    price: 100 * PRICE_PRECISION_U64,
-                    ..Order::default()
-                },
-                Order {
-                    market_index: 0,
-                    order_id: 2,
-                    status: OrderStatus::Open,
-                    order_type: OrderType::Limit,
-                    direction: PositionDirection::Short,
-                    base_asset_amount: BASE_PRECISION_U64,
-                    slot: 0,
-                    price: 102 * PRICE_PRECISION_U64,
-                    ..Order::default()
-                }
-            ),
+        let mut maker_orders = [Order::default(); 32];
+        maker_orders[0] = Order {
+            market_index: 0,
+            order_id: 1,
+            status: OrderStatus::Open,
+            order_type: OrderType::Limit,
+            direction: PositionDirection::Short,
+            base_asset_amount: BASE_PRECISION_U64,
+            slot: 0,
+            price: 100 * PRICE_PRECISION_U64,
+            max_ts: 1,
+            post_only: true,
+            ..Order::default()
+        };
+        maker_orders[1] = Order {
+            market_index: 0,
+            order_id: 2,
+            status: OrderStatus::Open,
+            order_type: OrderType::Limit,
+            direction: PositionDirection::Short,
+            base_asset_amount: BASE_PRECISION_U64,
+            slot: 0,
+            price: 100 * PRICE_PRECISION_U64,
+            post_only: true,
+            ..Order::default()
+        };
+
+        let mut maker = User {
+            orders: maker_orders,
             perp_positions: get_positions(PerpPosition {
                 market_index: 0,
                 open_orders: 2,
+                open_asks: -2 * BASE_PRECISION_I64,
                 ..PerpPosition::default()
             }),
             spot_positions: get_spot_positions(SpotPosition {
@@ -10497,16 +10360,10 @@ pub mod get_maker_orders_info {
             }),
             ..User::default()
         };
-        let first_maker_key =
-            Pubkey::from_str("My11111111111111111111111111111111111111113").unwrap();
-        create_anchor_account_info!(
-            first_maker,
-            &first_maker_key,
-            User,
-            first_maker_account_info
-        );
+        let maker_key = Pubkey::from_str("My11111111111111111111111111111111111111113").unwrap();
+        create_anchor_account_info!(maker, &maker_key, User, maker_account_info);
 
-        let makers_and_referrers = UserMap::load_one(&first_maker_account_info).unwrap();
+        let makers_and_referrers = UserMap::load_one(&maker_account_info).unwrap();
 
         let filler_key = Pubkey::from_str("My11111111111111111111111111111111111111111").unwrap();
         let mut filler = User::default();
@@ -10522,7 +10379,7 @@ pub mod get_maker_orders_info {
             &filler_key,
             0,
             oracle_price,
-            Some(2),
+            None,
             clock.unix_timestamp,
             clock.slot,
         )
@@ -10530,12 +10387,12 @@ pub mod get_maker_orders_info {
 
         assert_eq!(
             maker_order_price_and_indexes,
-            vec![(first_maker_key, 1, 102000000),],
+            vec![(maker_key, 1, 100 * PRICE_PRECISION_U64)]
         );
     }
 
     #[test]
-    fn two_makers_with_max_orders() {
+    fn one_maker_order_canceled_for_being_reduce_only() {
         let clock = Clock {
             slot: 6,
             epoch_start_timestamp: 0,
@@ -10656,55 +10513,27 @@ pub mod get_maker_orders_info {
             ..User::default()
         };
 
-        let mut first_maker = User {
-            orders: [Order {
-                market_index: 0,
-                order_id: 1,
-                status: OrderStatus::Open,
-                order_type: OrderType::Limit,
-                direction: PositionDirection::Short,
-                base_asset_amount: BASE_PRECISION_U64,
-                slot: 0,
-                price: 100 * PRICE_PRECISION_U64,
-                ..Order::default()
-            }; 32],
-            perp_positions: get_positions(PerpPosition {
-                market_index: 0,
-                open_orders: 2,
-                ..PerpPosition::default()
-            }),
-            spot_positions: get_spot_positions(SpotPosition {
-                market_index: 0,
-                balance_type: SpotBalanceType::Deposit,
-                scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
-                ..SpotPosition::default()
-            }),
-            ..User::default()
+        let mut maker_orders = [Order::default(); 32];
+        maker_orders[0] = Order {
+            market_index: 0,
+            order_id: 1,
+            status: OrderStatus::Open,
+            order_type: OrderType::Limit,
+            direction: PositionDirection::Short,
+            base_asset_amount: BASE_PRECISION_U64,
+            slot: 0,
+            price: 100 * PRICE_PRECISION_U64,
+            reduce_only: true,
+            ..Order::default()
         };
-        let first_maker_key =
-            Pubkey::from_str("My11111111111111111111111111111111111111113").unwrap();
-        create_anchor_account_info!(
-            first_maker,
-            &first_maker_key,
-            User,
-            first_maker_account_info
-        );
 
-        let mut second_maker = User {
-            orders: [Order {
-                market_index: 0,
-                order_id: 1,
-                status: OrderStatus::Open,
-                order_type: OrderType::Limit,
-                direction: PositionDirection::Short,
-                base_asset_amount: BASE_PRECISION_U64,
-                slot: 0,
-                price: 101 * PRICE_PRECISION_U64,
-                ..Order::default()
-            }; 32],
+        let mut maker = User {
+            orders: maker_orders,
             perp_positions: get_positions(PerpPosition {
                 market_index: 0,
-                open_orders: 2,
+                base_asset_amount: -BASE_PRECISION_I64,
+                open_orders: 1,
+                open_asks: -BASE_PRECISION_I64,
                 ..PerpPosition::default()
             }),
             spot_positions: get_spot_positions(SpotPosition {
@@ -10715,22 +10544,10 @@ pub mod get_maker_orders_info {
             }),
             ..User::default()
         };
-        let second_maker_key =
-            Pubkey::from_str("My11111111111111111111111111111111111111112").unwrap();
-        create_anchor_account_info!(
-            second_maker,
-            &second_maker_key,
-            User,
-            second_maker_account_info
-        );
+        let maker_key = Pubkey::from_str("My11111111111111111111111111111111111111113").unwrap();
+        create_anchor_account_info!(maker, &maker_key, User, maker_account_info);
 
-        let mut makers_and_referrers = UserMap::load_one(&first_maker_account_info).unwrap();
-        makers_and_referrers
-            .insert(
-                second_maker_key,
-                AccountLoader::try_from(&second_maker_account_info).unwrap(),
-            )
-            .unwrap();
+        let makers_and_referrers = UserMap::load_one(&maker_account_info).unwrap();
 
         let filler_key = Pubkey::from_str("My11111111111111111111111111111111111111111").unwrap();
         let mut filler = User::default();
@@ -10752,42 +10569,13 @@ pub mod get_maker_orders_info {
         )
         .unwrap();
 
-        assert_eq!(maker_order_price_and_indexes.len(), 64);
+        assert_eq!(maker_order_price_and_indexes, vec![],);
     }
-}
-
-pub mod get_spot_maker_orders_info {
-    use std::str::FromStr;
-
-    use anchor_lang::prelude::{AccountLoader, Clock};
-
-    use crate::controller::orders::get_spot_maker_orders_info;
-    use crate::controller::position::PositionDirection;
-    use crate::math::constants::{
-        BASE_PRECISION_U64, PRICE_PRECISION_I64, PRICE_PRECISION_U64, SPOT_BALANCE_PRECISION_U64,
-        SPOT_CUMULATIVE_INTEREST_PRECISION, SPOT_WEIGHT_PRECISION,
-    };
-    use crate::state::oracle::HistoricalOracleData;
-    use crate::state::oracle::OracleSource;
-    use crate::state::perp_market_map::PerpMarketMap;
-    use crate::state::spot_market::{SpotBalanceType, SpotMarket};
-    use crate::state::spot_market_map::SpotMarketMap;
-    use crate::state::user::{OrderStatus, OrderType, SpotPosition, User};
-    use crate::state::user_map::UserMap;
-    use crate::test_utils::*;
-    use crate::test_utils::{create_account_info, get_orders, get_pyth_price, get_spot_positions};
-    use crate::{
-        create_account_info, get_orders, LAMPORTS_PER_SOL_I64, QUOTE_PRECISION_U64,
-        SPOT_BALANCE_PRECISION,
-    };
-    use crate::{create_anchor_account_info, QUOTE_PRECISION_I64};
-
-    use super::*;
 
     #[test]
-    fn one_maker_order_canceled_for_breaching_oracle_price_band() {
+    fn two_makers() {
         let clock = Clock {
-            slot: 56,
+            slot: 6,
             epoch_start_timestamp: 0,
             epoch: 0,
             leader_schedule_epoch: 0,
@@ -10807,8 +10595,57 @@ pub mod get_spot_maker_orders_info {
         );
         let mut oracle_map = OracleMap::load_one(&oracle_account_info, clock.slot, None).unwrap();
 
-        let market_map = PerpMarketMap::empty();
-
+        let mut market = PerpMarket {
+            amm: AMM {
+                base_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                terminal_quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                sqrt_k: 100 * AMM_RESERVE_PRECISION,
+                peg_multiplier: 100 * PEG_PRECISION,
+                max_slippage_ratio: 100,
+                max_fill_reserve_fraction: 100,
+                order_step_size: 1000,
+                order_tick_size: 1,
+                oracle: oracle_price_key,
+                max_spread: 1000,
+                base_spread: 0,
+                long_spread: 0,
+                short_spread: 0,
+                historical_oracle_data: HistoricalOracleData {
+                    last_oracle_price_twap: pyth_price.twap as i64,
+                    last_oracle_price_twap_5min: pyth_price.twap as i64,
+                    last_oracle_price: pyth_price.agg.price as i64,
+                    ..HistoricalOracleData::default()
+                },
+                ..AMM::default()
+            },
+            margin_ratio_initial: 1000,
+            margin_ratio_maintenance: 500,
+            status: MarketStatus::Initialized,
+            ..PerpMarket::default()
+        };
+        market.status = MarketStatus::Active;
+        market.amm.max_base_asset_reserve = u128::MAX;
+        market.amm.min_base_asset_reserve = 0;
+        let (new_ask_base_asset_reserve, new_ask_quote_asset_reserve) =
+            crate::math::amm_spread::calculate_spread_reserves(
+                &market.amm,
+                PositionDirection::Long,
+            )
+            .unwrap();
+        let (new_bid_base_asset_reserve, new_bid_quote_asset_reserve) =
+            crate::math::amm_spread::calculate_spread_reserves(
+                &market.amm,
+                PositionDirection::Short,
+            )
+            .unwrap();
+        market.amm.ask_base_asset_reserve = new_ask_base_asset_reserve;
+        market.amm.bid_base_asset_reserve = new_bid_base_asset_reserve;
+        market.amm.ask_quote_asset_reserve = new_ask_quote_asset_reserve;
+        market.amm.bid_quote_asset_reserve = new_bid_quote_asset_reserve;
+        create_anchor_account_info!(market, PerpMarket, market_account_info);
+        let market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+
         let mut spot_market = SpotMarket {
             market_index: 0,
             oracle_source: OracleSource::QuoteAsset,
@@ -10820,20 +10657,7 @@ pub mod get_spot_maker_orders_info {
             ..SpotMarket::default()
         };
         create_anchor_account_info!(spot_market, SpotMarket, spot_market_account_info);
-
-        let mut base_market = SpotMarket {
-            deposit_balance: 100 * SPOT_BALANCE_PRECISION,
-            oracle: oracle_price_key,
-            ..SpotMarket::default_base_market()
-        };
-
-        create_anchor_account_info!(base_market, SpotMarket, base_spot_market_account_info);
-
-        let spot_market_map = SpotMarketMap::load_multiple(
-            vec![&spot_market_account_info, &base_spot_market_account_info],
-            true,
-        )
-        .unwrap();
+        let spot_market_map = SpotMarketMap::load_one(&spot_market_account_info, true).unwrap();
 
         let taker_key = Pubkey::default();
         let taker_authority =
@@ -10841,20 +10665,26 @@ pub mod get_spot_maker_orders_info {
         let user = User {
             authority: taker_authority,
             orders: get_orders(Order {
-                market_index: 1,
+                market_index: 0,
                 order_id: 1,
                 status: OrderStatus::Open,
                 order_type: OrderType::Market,
-                market_type: MarketType::Spot,
                 direction: PositionDirection::Long,
                 base_asset_amount: BASE_PRECISION_U64,
                 slot: 0,
                 auction_start_price: 0,
                 auction_end_price: 50 * PRICE_PRECISION_I64,
                 auction_duration: 5,
-                price: 50 * PRICE_PRECISION_U64,
+                price: 100 * PRICE_PRECISION_U64,
+                max_ts: 1,
                 ..Order::default()
             }),
+            perp_positions: get_positions(PerpPosition {
+                market_index: 0,
+                open_orders: 1,
+                open_bids: BASE_PRECISION_I64,
+                ..PerpPosition::default()
+            }),
             spot_positions: get_spot_positions(SpotPosition {
                 market_index: 0,
                 balance_type: SpotBalanceType::Deposit,
@@ -10864,61 +10694,112 @@ pub mod get_spot_maker_orders_info {
             ..User::default()
         };
 
-        let mut maker_orders = [Order::default(); 32];
-        maker_orders[0] = Order {
-            market_index: 1,
-            order_id: 1,
-            status: OrderStatus::Open,
-            order_type: OrderType::Limit,
-            market_type: MarketType::Spot,
-            direction: PositionDirection::Short,
-            base_asset_amount: BASE_PRECISION_U64,
-            slot: 0,
-            price: 50 * PRICE_PRECISION_U64,
-            post_only: true,
-            ..Order::default()
-        };
-        maker_orders[1] = Order {
-            market_index: 1,
-            order_id: 2,
-            status: OrderStatus::Open,
-            order_type: OrderType::Limit,
-            direction: PositionDirection::Short,
-            market_type: MarketType::Spot,
-            base_asset_amount: BASE_PRECISION_U64,
-            slot: 0,
-            price: 100 * PRICE_PRECISION_U64,
-            post_only: true,
-            ..Order::default()
+        let mut first_maker = User {
+            orders: get_orders!(
+                Order {
+                    market_index: 0,
+                    order_id: 1,
+                    status: OrderStatus::Open,
+                    order_type: OrderType::Limit,
+                    direction: PositionDirection::Short,
+                    base_asset_amount: BASE_PRECISION_U64,
+                    slot: 0,
+                    price: 100 * PRICE_PRECISION_U64,
+                    ..Order::default()
+                },
+                Order {
+                    market_index: 0,
+                    order_id: 1,
+                    status: OrderStatus::Open,
+                    order_type: OrderType::Limit,
+                    direction: PositionDirection::Short,
+                    base_asset_amount: BASE_PRECISION_U64,
+                    slot: 0,
+                    price: 102 * PRICE_PRECISION_U64,
+                    ..Order::default()
+                }
+            ),
+            perp_positions: get_positions(PerpPosition {
+                market_index: 0,
+                open_orders: 2,
+                ..PerpPosition::default()
+            }),
+            spot_positions: get_spot_positions(SpotPosition {
+                market_index: 0,
+                balance_type: SpotBalanceType::Deposit,
+                scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
+                ..SpotPosition::default()
+            }),
+            ..User::default()
         };
+        let first_maker_key =
+            Pubkey::from_str("My11111111111111111111111111111111111111113").unwrap();
+        create_anchor_account_info!(
+            first_maker,
+            &first_maker_key,
+            User,
+            first_maker_account_info
+        );
 
-        let mut maker_spot_positions = get_spot_positions(SpotPosition {
-            market_index: 0,
-            balance_type: SpotBalanceType::Deposit,
-            scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
-            ..SpotPosition::default()
-        });
-        maker_spot_positions[1] = SpotPosition {
-            market_index: 1,
-            balance_type: SpotBalanceType::Deposit,
-            open_orders: 2,
-            open_asks: -2 * LAMPORTS_PER_SOL_I64,
-            ..SpotPosition::default()
-        };
-        let mut maker = User {
-            orders: maker_orders,
-            spot_positions: maker_spot_positions,
+        let mut second_maker = User {
+            orders: get_orders!(
+                Order {
+                    market_index: 0,
+                    order_id: 1,
+                    status: OrderStatus::Open,
+                    order_type: OrderType::Limit,
+                    direction: PositionDirection::Short,
+                    base_asset_amount: BASE_PRECISION_U64,
+                    slot: 0,
+                    price: 101 * PRICE_PRECISION_U64,
+                    ..Order::default()
+                },
+                Order {
+                    market_index: 0,
+                    order_id: 1,
+                    status: OrderStatus::Open,
+                    order_type: OrderType::Limit,
+                    direction: PositionDirection::Short,
+                    base_asset_amount: BASE_PRECISION_U64,
+                    slot: 0,
+                    price: 103 * PRICE_PRECISION_U64,
+                    ..Order::default()
+                }
+            ),
+            perp_positions: get_positions(PerpPosition {
+                market_index: 0,
+                open_orders: 2,
+                ..PerpPosition::default()
+            }),
+            spot_positions: get_spot_positions(SpotPosition {
+                market_index: 0,
+                balance_type: SpotBalanceType::Deposit,
+                scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
+                ..SpotPosition::default()
+            }),
             ..User::default()
         };
-        let maker_key = Pubkey::from_str("My11111111111111111111111111111111111111113").unwrap();
-        create_anchor_account_info!(maker, &maker_key, User, maker_account_info);
+        let second_maker_key =
+            Pubkey::from_str("My11111111111111111111111111111111111111112").unwrap();
+        create_anchor_account_info!(
+            second_maker,
+            &second_maker_key,
+            User,
+            second_maker_account_info
+        );
 
-        let makers_and_referrers = UserMap::load_one(&maker_account_info).unwrap();
+        let mut makers_and_referrers = UserMap::load_one(&first_maker_account_info).unwrap();
+        makers_and_referrers
+            .insert(
+                second_maker_key,
+                AccountLoader::try_from(&second_maker_account_info).unwrap(),
+            )
+            .unwrap();
 
         let filler_key = Pubkey::from_str("My11111111111111111111111111111111111111111").unwrap();
         let mut filler = User::default();
 
-        let maker_order_price_and_indexes = get_spot_maker_orders_info(
+        let maker_order_price_and_indexes = get_maker_orders_info(
             &market_map,
             &spot_market_map,
             &mut oracle_map,
@@ -10927,7 +10808,7 @@ pub mod get_spot_maker_orders_info {
             &user.orders[0],
             &mut Some(&mut filler),
             &filler_key,
-            QUOTE_PRECISION_U64 / 100,
+            0,
             oracle_price,
             None,
             clock.unix_timestamp,
@@ -10937,18 +10818,23 @@ pub mod get_spot_maker_orders_info {
 
         assert_eq!(
             maker_order_price_and_indexes,
-            vec![(maker_key, 1, 100 * PRICE_PRECISION_U64)]
+            vec![
+                (first_maker_key, 0, 100000000),
+                (second_maker_key, 0, 101000000),
+                (first_maker_key, 1, 102000000),
+                (second_maker_key, 1, 103000000),
+            ],
         );
     }
 
     #[test]
-    fn one_maker_order_canceled_for_being_expired() {
+    fn jit_maker_order_id() {
         let clock = Clock {
-            slot: 56,
+            slot: 6,
             epoch_start_timestamp: 0,
             epoch: 0,
             leader_schedule_epoch: 0,
-            unix_timestamp: 6,
+            unix_timestamp: 0,
         };
 
         let mut pyth_price = get_pyth_price(100, 6);
@@ -10964,33 +10850,69 @@ pub mod get_spot_maker_orders_info {
         );
         let mut oracle_map = OracleMap::load_one(&oracle_account_info, clock.slot, None).unwrap();
 
-        let market_map = PerpMarketMap::empty();
-
-        let mut spot_market = SpotMarket {
-            market_index: 0,
-            oracle_source: OracleSource::QuoteAsset,
-            cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
-            decimals: 6,
-            initial_asset_weight: SPOT_WEIGHT_PRECISION,
-            maintenance_asset_weight: SPOT_WEIGHT_PRECISION,
-            historical_oracle_data: HistoricalOracleData::default_price(QUOTE_PRECISION_I64),
-            ..SpotMarket::default()
-        };
-        create_anchor_account_info!(spot_market, SpotMarket, spot_market_account_info);
-
-        let mut base_market = SpotMarket {
-            deposit_balance: 100 * SPOT_BALANCE_PRECISION,
-            oracle: oracle_price_key,
-            ..SpotMarket::default_base_market()
-        };
-
-        create_anchor_account_info!(base_market, SpotMarket, base_spot_market_account_info);
+        let mut market = PerpMarket {
+            amm: AMM {
+                base_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                terminal_quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                sqrt_k: 100 * AMM_RESERVE_PRECISION,
+                peg_multiplier: 100 * PEG_PRECISION,
+                max_slippage_ratio: 100,
+                max_fill_reserve_fraction: 100,
+                order_step_size: 1000,
+                order_tick_size: 1,
+                oracle: oracle_price_key,
+                max_spread: 1000,
+                base_spread: 0,
+                long_spread: 0,
+                short_spread: 0,
+                historical_oracle_data: HistoricalOracleData {
+                    last_oracle_price_twap: pyth_price.twap as i64,
+                    last_oracle_price_twap_5min: pyth_price.twap as i64,
+                    last_oracle_price: pyth_price.agg.price as i64,
+                    ..HistoricalOracleData::default()
+                },
+                ..AMM::default()
+            },
+            margin_ratio_initial: 1000,
+            margin_ratio_maintenance: 500,
+            status: MarketStatus::Initialized,
+            ..PerpMarket::default()
+        };
+        market.status = MarketStatus::Active;
+        market.amm.max_base_asset_reserve = u128::MAX;
+        market.amm.min_base_asset_reserve = 0;
+        let (new_ask_base_asset_reserve, new_ask_quote_asset_reserve) =
+            crate::math::amm_spread::calculate_spread_reserves(
+                &market.amm,
+                PositionDirection::Long,
+            )
+            .unwrap();
+        let (new_bid_base_asset_reserve, new_bid_quote_asset_reserve) =
+            crate::math::amm_spread::calculate_spread_reserves(
+                &market.amm,
+                PositionDirection::Short,
+            )
+            .unwrap();
+        market.amm.ask_base_asset_reserve = new_ask_base_asset_reserve;
+        market.amm.bid_base_asset_reserve = new_bid_base_asset_reserve;
+        market.amm.ask_quote_asset_reserve = new_ask_quote_asset_reserve;
+        market.amm.bid_quote_asset_reserve = new_bid_quote_asset_reserve;
+        create_anchor_account_info!(market, PerpMarket, market_account_info);
+        let market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
 
-        let spot_market_map = SpotMarketMap::load_multiple(
-            vec![&spot_market_account_info, &base_spot_market_account_info],
-            true,
-        )
-        .unwrap();
+        let mut spot_market = SpotMarket {
+            market_index: 0,
+            oracle_source: OracleSource::QuoteAsset,
+            cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            decimals: 6,
+            initial_asset_weight: SPOT_WEIGHT_PRECISION,
+            maintenance_asset_weight: SPOT_WEIGHT_PRECISION,
+            historical_oracle_data: HistoricalOracleData::default_price(QUOTE_PRECISION_I64),
+            ..SpotMarket::default()
+        };
+        create_anchor_account_info!(spot_market, SpotMarket, spot_market_account_info);
+        let spot_market_map = SpotMarketMap::load_one(&spot_market_account_info, true).unwrap();
 
         let taker_key = Pubkey::default();
         let taker_authority =
@@ -10998,11 +10920,10 @@ pub mod get_spot_maker_orders_info {
         let user = User {
             authority: taker_authority,
             orders: get_orders(Order {
-                market_index: 1,
+                market_index: 0,
                 order_id: 1,
                 status: OrderStatus::Open,
                 order_type: OrderType::Market,
-                market_type: MarketType::Spot,
                 direction: PositionDirection::Long,
                 base_asset_amount: BASE_PRECISION_U64,
                 slot: 0,
@@ -11010,8 +10931,15 @@ pub mod get_spot_maker_orders_info {
                 auction_end_price: 50 * PRICE_PRECISION_I64,
                 auction_duration: 5,
                 price: 100 * PRICE_PRECISION_U64,
+                max_ts: 1,
                 ..Order::default()
             }),
+            perp_positions: get_positions(PerpPosition {
+                market_index: 0,
+                open_orders: 1,
+                open_bids: BASE_PRECISION_I64,
+                ..PerpPosition::default()
+            }),
             spot_positions: get_spot_positions(SpotPosition {
                 market_index: 0,
                 balance_type: SpotBalanceType::Deposit,
@@ -11021,63 +10949,59 @@ pub mod get_spot_maker_orders_info {
             ..User::default()
         };
 
-        let mut maker_orders = [Order::default(); 32];
-        maker_orders[0] = Order {
-            market_index: 1,
-            order_id: 1,
-            status: OrderStatus::Open,
-            order_type: OrderType::Limit,
-            direction: PositionDirection::Short,
-            market_type: MarketType::Spot,
-            base_asset_amount: BASE_PRECISION_U64,
-            slot: 0,
-            price: 100 * PRICE_PRECISION_U64,
-            max_ts: 1,
-            post_only: true,
-            ..Order::default()
-        };
-        maker_orders[1] = Order {
-            market_index: 1,
-            order_id: 2,
-            status: OrderStatus::Open,
-            order_type: OrderType::Limit,
-            direction: PositionDirection::Short,
-            market_type: MarketType::Spot,
-            base_asset_amount: BASE_PRECISION_U64,
-            slot: 0,
-            price: 100 * PRICE_PRECISION_U64,
-            post_only: true,
-            ..Order::default()
-        };
-
-        let mut maker_spot_positions = get_spot_positions(SpotPosition {
-            market_index: 0,
-            balance_type: SpotBalanceType::Deposit,
-            scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
-            ..SpotPosition::default()
-        });
-        maker_spot_positions[1] = SpotPosition {
-            market_index: 1,
-            balance_type: SpotBalanceType::Deposit,
-            open_orders: 2,
-            open_asks: -2 * LAMPORTS_PER_SOL_I64,
-            ..SpotPosition::default()
-        };
-
-        let mut maker = User {
-            orders: maker_orders,
-            spot_positions: maker_spot_positions,
+        let mut first_maker = User {
+            orders: get_orders!(
+                Order {
+                    market_index: 0,
+                    order_id: 1,
+                    status: OrderStatus::Open,
+                    order_type: OrderType::Limit,
+                    direction: PositionDirection::Short,
+                    base_asset_amount: BASE_PRECISION_U64,
+                    slot: 0,
+                    price: 100 * PRICE_PRECISION_U64,
+                    ..Order::default()
+                },
+                Order {
+                    market_index: 0,
+                    order_id: 2,
+                    status: OrderStatus::Open,
+                    order_type: OrderType::Limit,
+                    direction: PositionDirection::Short,
+                    base_asset_amount: BASE_PRECISION_U64,
+                    slot: 0,
+                    price: 102 * PRICE_PRECISION_U64,
+                    ..Order::default()
+                }
+            ),
+            perp_positions: get_positions(PerpPosition {
+                market_index: 0,
+                open_orders: 2,
+                ..PerpPosition::default()
+            }),
+            spot_positions: get_spot_positions(SpotPosition {
+                market_index: 0,
+                balance_type: SpotBalanceType::Deposit,
+                scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
+                ..SpotPosition::default()
+            }),
             ..User::default()
         };
-        let maker_key = Pubkey::from_str("My11111111111111111111111111111111111111113").unwrap();
-        create_anchor_account_info!(maker, &maker_key, User, maker_account_info);
+        let first_maker_key =
+            Pubkey::from_str("My11111111111111111111111111111111111111113").unwrap();
+        create_anchor_account_info!(
+            first_maker,
+            &first_maker_key,
+            User,
+            first_maker_account_info
+        );
 
-        let makers_and_referrers = UserMap::load_one(&maker_account_info).unwrap();
+        let makers_and_referrers = UserMap::load_one(&first_maker_account_info).unwrap();
 
         let filler_key = Pubkey::from_str("My11111111111111111111111111111111111111111").unwrap();
         let mut filler = User::default();
 
-        let maker_order_price_and_indexes = get_spot_maker_orders_info(
+        let maker_order_price_and_indexes = get_maker_orders_info(
             &market_map,
             &spot_market_map,
             &mut oracle_map,
@@ -11088,7 +11012,7 @@ pub mod get_spot_maker_orders_info {
             &filler_key,
             0,
             oracle_price,
-            None,
+            Some(2),
             clock.unix_timestamp,
             clock.slot,
         )
@@ -11096,12 +11020,12 @@ pub mod get_spot_maker_orders_info {
 
         assert_eq!(
             maker_order_price_and_indexes,
-            vec![(maker_key, 1, 100 * PRICE_PRECISION_U64)]
+            vec![(first_maker_key, 1, 102000000),],
         );
     }
 
     #[test]
-    fn one_maker_order_canceled_for_being_reduce_only() {
+    fn two_makers_with_max_orders() {
         let clock = Clock {
             slot: 6,
             epoch_start_timestamp: 0,
@@ -11123,7 +11047,56 @@ pub mod get_spot_maker_orders_info {
         );
         let mut oracle_map = OracleMap::load_one(&oracle_account_info, clock.slot, None).unwrap();
 
-        let market_map = PerpMarketMap::empty();
+        let mut market = PerpMarket {
+            amm: AMM {
+                base_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                terminal_quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                sqrt_k: 100 * AMM_RESERVE_PRECISION,
+                peg_multiplier: 100 * PEG_PRECISION,
+                max_slippage_ratio: 100,
+                max_fill_reserve_fraction: 100,
+                order_step_size: 1000,
+                order_tick_size: 1,
+                oracle: oracle_price_key,
+                max_spread: 1000,
+                base_spread: 0,
+                long_spread: 0,
+                short_spread: 0,
+                historical_oracle_data: HistoricalOracleData {
+                    last_oracle_price_twap: pyth_price.twap as i64,
+                    last_oracle_price_twap_5min: pyth_price.twap as i64,
+                    last_oracle_price: pyth_price.agg.price as i64,
+                    ..HistoricalOracleData::default()
+                },
+                ..AMM::default()
+            },
+            margin_ratio_initial: 1000,
+            margin_ratio_maintenance: 500,
+            status: MarketStatus::Initialized,
+            ..PerpMarket::default()
+        };
+        market.status = MarketStatus::Active;
+        market.amm.max_base_asset_reserve = u128::MAX;
+        market.amm.min_base_asset_reserve = 0;
+        let (new_ask_base_asset_reserve, new_ask_quote_asset_reserve) =
+            crate::math::amm_spread::calculate_spread_reserves(
+                &market.amm,
+                PositionDirection::Long,
+            )
+            .unwrap();
+        let (new_bid_base_asset_reserve, new_bid_quote_asset_reserve) =
+            crate::math::amm_spread::calculate_spread_reserves(
+                &market.amm,
+                PositionDirection::Short,
+            )
+            .unwrap();
+        market.amm.ask_base_asset_reserve = new_ask_base_asset_reserve;
+        market.amm.bid_base_asset_reserve = new_bid_base_asset_reserve;
+        market.amm.ask_quote_asset_reserve = new_ask_quote_asset_reserve;
+        market.amm.bid_quote_asset_reserve = new_bid_quote_asset_reserve;
+        create_anchor_account_info!(market, PerpMarket, market_account_info);
+        let market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
 
         let mut spot_market = SpotMarket {
             market_index: 0,
@@ -11136,20 +11109,7 @@ pub mod get_spot_maker_orders_info {
             ..SpotMarket::default()
         };
         create_anchor_account_info!(spot_market, SpotMarket, spot_market_account_info);
-
-        let mut base_market = SpotMarket {
-            deposit_balance: 100 * SPOT_BALANCE_PRECISION,
-            oracle: oracle_price_key,
-            ..SpotMarket::default_base_market()
-        };
-
-        create_anchor_account_info!(base_market, SpotMarket, base_spot_market_account_info);
-
-        let spot_market_map = SpotMarketMap::load_multiple(
-            vec![&spot_market_account_info, &base_spot_market_account_info],
-            true,
-        )
-        .unwrap();
+        let spot_market_map = SpotMarketMap::load_one(&spot_market_account_info, true).unwrap();
 
         let taker_key = Pubkey::default();
         let taker_authority =
@@ -11157,12 +11117,11 @@ pub mod get_spot_maker_orders_info {
         let user = User {
             authority: taker_authority,
             orders: get_orders(Order {
-                market_index: 1,
+                market_index: 0,
                 order_id: 1,
                 status: OrderStatus::Open,
                 order_type: OrderType::Market,
                 direction: PositionDirection::Long,
-                market_type: MarketType::Spot,
                 base_asset_amount: BASE_PRECISION_U64,
                 slot: 0,
                 auction_start_price: 0,
@@ -11172,6 +11131,12 @@ pub mod get_spot_maker_orders_info {
                 max_ts: 1,
                 ..Order::default()
             }),
+            perp_positions: get_positions(PerpPosition {
+                market_index: 0,
+                open_orders: 1,
+                open_bids: BASE_PRECISION_I64,
+                ..PerpPosition::default()
+            }),
             spot_positions: get_spot_positions(SpotPosition {
                 market_index: 0,
                 balance_type: SpotBalanceType::Deposit,
@@ -11181,18 +11146,239 @@ pub mod get_spot_maker_orders_info {
             ..User::default()
         };
 
-        let mut maker_orders = [Order::default(); 32];
+        let mut first_maker = User {
+            orders: [Order {
+                market_index: 0,
+                order_id: 1,
+                status: OrderStatus::Open,
+                order_type: OrderType::Limit,
+                direction: PositionDirection::Short,
+                base_asset_amount: BASE_PRECISION_U64,
+                slot: 0,
+                price: 100 * PRICE_PRECISION_U64,
+                ..Order::default()
+            }; 32],
+            perp_positions: get_positions(PerpPosition {
+                market_index: 0,
+                open_orders: 2,
+                ..PerpPosition::default()
+            }),
+            spot_positions: get_spot_positions(SpotPosition {
+                market_index: 0,
+                balance_type: SpotBalanceType::Deposit,
+                scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
+                ..SpotPosition::default()
+            }),
+            ..User::default()
+        };
+        let first_maker_key =
+            Pubkey::from_str("My11111111111111111111111111111111111111113").unwrap();
+        create_anchor_account_info!(
+            first_maker,
+            &first_maker_key,
+            User,
+            first_maker_account_info
+        );
+
+        let mut second_maker = User {
+            orders: [Order {
+                market_index: 0,
+                order_id: 1,
+                status: OrderStatus::Open,
+                order_type: OrderType::Limit,
+                direction: PositionDirection::Short,
+                base_asset_amount: BASE_PRECISION_U64,
+                slot: 0,
+                price: 101 * PRICE_PRECISION_U64,
+                ..Order::default()
+            }; 32],
+            perp_positions: get_positions(PerpPosition {
+                market_index: 0,
+                open_orders: 2,
+                ..PerpPosition::default()
+            }),
+            spot_positions: get_spot_positions(SpotPosition {
+                market_index: 0,
+                balance_type: SpotBalanceType::Deposit,
+                scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
+                ..SpotPosition::default()
+            }),
+            ..User::default()
+        };
+        let second_maker_key =
+            Pubkey::from_str("My11111111111111111111111111111111111111112").unwrap();
+        create_anchor_account_info!(
+            second_maker,
+            &second_maker_key,
+            User,
+            second_maker_account_info
+        );
+
+        let mut makers_and_referrers = UserMap::load_one(&first_maker_account_info).unwrap();
+        makers_and_referrers
+            .insert(
+                second_maker_key,
+                AccountLoader::try_from(&second_maker_account_info).unwrap(),
+            )
+            .unwrap();
+
+        let filler_key = Pubkey::from_str("My11111111111111111111111111111111111111111").unwrap();
+        let mut filler = User::default();
+
+        let maker_order_price_and_indexes = get_maker_orders_info(
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            &makers_and_referrers,
+            &taker_key,
+            &user.orders[0],
+            &mut Some(&mut filler),
+            &filler_key,
+            0,
+            oracle_price,
+            None,
+            clock.unix_timestamp,
+            clock.slot,
+        )
+        .unwrap();
+
+        assert_eq!(maker_order_price_and_indexes.len(), 64);
+    }
+}
+
+pub mod get_spot_maker_orders_info {
+    use std::str::FromStr;
+
+    use anchor_lang::prelude::{AccountLoader, Clock};
+
+    use crate::controller::orders::get_spot_maker_orders_info;
+    use crate::controller::position::PositionDirection;
+    use crate::math::constants::{
+        BASE_PRECISION_U64, PRICE_PRECISION_I64, PRICE_PRECISION_U64, SPOT_BALANCE_PRECISION_U64,
+        SPOT_CUMULATIVE_INTEREST_PRECISION, SPOT_WEIGHT_PRECISION,
+    };
+    use crate::state::oracle::HistoricalOracleData;
+    use crate::state::oracle::OracleSource;
+    use crate::state::perp_market_map::PerpMarketMap;
+    use crate::state::spot_market::{SpotBalanceType, SpotMarket};
+    use crate::state::spot_market_map::SpotMarketMap;
+    use crate::state::user::{OrderStatus, OrderType, SpotPosition, User};
+    use crate::state::user_map::UserMap;
+    use crate::test_utils::*;
+    use crate::test_utils::{create_account_info, get_orders, get_pyth_price, get_spot_positions};
+    use crate::{
+        create_account_info, get_orders, LAMPORTS_PER_SOL_I64, QUOTE_PRECISION_U64,
+        SPOT_BALANCE_PRECISION,
+    };
+    use crate::{create_anchor_account_info, QUOTE_PRECISION_I64};
+
+    use super::*;
+
+    #[test]
+    fn one_maker_order_canceled_for_breaching_oracle_price_band() {
+        let clock = Clock {
+            slot: 56,
+            epoch_start_timestamp: 0,
+            epoch: 0,
+            leader_schedule_epoch: 0,
+            unix_timestamp: 0,
+        };
+
+        let mut pyth_price = get_pyth_price(100, 6);
+        let oracle_price = 100 * PRICE_PRECISION_I64;
+        let oracle_price_key =
+            Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+        let pyth_program = crate::ids::pyth_program::id();
+        create_account_info!(
+            pyth_price,
+            &oracle_price_key,
+            &pyth_program,
+            oracle_account_info
+        );
+        let mut oracle_map = OracleMap::load_one(&oracle_account_info, clock.slot, None).unwrap();
+
+        let market_map = PerpMarketMap::empty();
+
+        let mut spot_market = SpotMarket {
+            market_index: 0,
+            oracle_source: OracleSource::QuoteAsset,
+            cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            decimals: 6,
+            initial_asset_weight: SPOT_WEIGHT_PRECISION,
+            maintenance_asset_weight: SPOT_WEIGHT_PRECISION,
+            historical_oracle_data: HistoricalOracleData::default_price(QUOTE_PRECISION_I64),
+            ..SpotMarket::default()
+        };
+        create_anchor_account_info!(spot_market, SpotMarket, spot_market_account_info);
+
+        let mut base_market = SpotMarket {
+            deposit_balance: 100 * SPOT_BALANCE_PRECISION,
+            oracle: oracle_price_key,
+            ..SpotMarket::default_base_market()
+        };
+
+        create_anchor_account_info!(base_market, SpotMarket, base_spot_market_account_info);
+
+        let spot_market_map = SpotMarketMap::load_multiple(
+            vec![&spot_market_account_info, &base_spot_market_account_info],
+            true,
+        )
+        .unwrap();
+
+        let taker_key = Pubkey::default();
+        let taker_authority =
+            Pubkey::from_str("My11111111111111111111111111111111111111111").unwrap();
+        let user = User {
+            authority: taker_authority,
+            orders: get_orders(Order {
+                market_index: 1,
+                order_id: 1,
+                status: OrderStatus::Open,
+                order_type: OrderType::Market,
+                market_type: MarketType::Spot,
+                direction: PositionDirection::Long,
+                base_asset_amount: BASE_PRECISION_U64,
+                slot: 0,
+                auction_start_price: 0,
+                auction_end_price: 50 * PRICE_PRECISION_I64,
+                auction_duration: 5,
+                price: 50 * PRICE_PRECISION_U64,
+                ..Order::default()
+            }),
+            spot_positions: get_spot_positions(SpotPosition {
+                market_index: 0,
+                balance_type: SpotBalanceType::Deposit,
+                scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
+                ..SpotPosition::default()
+            }),
+            ..User::default()
+        };
+
+        let mut maker_orders = [Order::default(); 32];
         maker_orders[0] = Order {
             market_index: 1,
             order_id: 1,
             status: OrderStatus::Open,
             order_type: OrderType::Limit,
+            market_type: MarketType::Spot,
+            direction: PositionDirection::Short,
+            base_asset_amount: BASE_PRECISION_U64,
+            slot: 0,
+            price: 50 * PRICE_PRECISION_U64,
+            post_only: true,
+            ..Order::default()
+        };
+        maker_orders[1] = Order {
+            market_index: 1,
+            order_id: 2,
+            status: OrderStatus::Open,
+            order_type: OrderType::Limit,
             direction: PositionDirection::Short,
             market_type: MarketType::Spot,
             base_asset_amount: BASE_PRECISION_U64,
             slot: 0,
             price: 100 * PRICE_PRECISION_U64,
-            reduce_only: true,
+            post_only: true,
             ..Order::default()
         };
 
@@ -11205,12 +11391,10 @@ pub mod get_spot_maker_orders_info {
         maker_spot_positions[1] = SpotPosition {
             market_index: 1,
             balance_type: SpotBalanceType::Deposit,
-            open_orders: 1,
-            open_asks: -LAMPORTS_PER_SOL_I64,
-            scaled_balance: 0,
+            open_orders: 2,
+            open_asks: -2 * LAMPORTS_PER_SOL_I64,
             ..SpotPosition::default()
         };
-
         let mut maker = User {
             orders: maker_orders,
             spot_positions: maker_spot_positions,
@@ -11233,7 +11417,7 @@ pub mod get_spot_maker_orders_info {
             &user.orders[0],
             &mut Some(&mut filler),
             &filler_key,
-            0,
+            QUOTE_PRECISION_U64 / 100,
             oracle_price,
             None,
             clock.unix_timestamp,
@@ -11241,17 +11425,20 @@ pub mod get_spot_maker_orders_info {
         )
         .unwrap();
 
-        assert_eq!(maker_order_price_and_indexes, vec![],);
+        assert_eq!(
+            maker_order_price_and_indexes,
+            vec![(maker_key, 1, 100 * PRICE_PRECISION_U64)]
+        );
     }
 
     #[test]
-    fn two_makers() {
+    fn one_maker_order_canceled_for_being_expired() {
         let clock = Clock {
-            slot: 6,
+            slot: 56,
             epoch_start_timestamp: 0,
             epoch: 0,
             leader_schedule_epoch: 0,
-            unix_timestamp: 0,
+            unix_timestamp: 6,
         };
 
         let mut pyth_price = get_pyth_price(100, 6);
@@ -11313,7 +11500,6 @@ pub mod get_spot_maker_orders_info {
                 auction_end_price: 50 * PRICE_PRECISION_I64,
                 auction_duration: 5,
                 price: 100 * PRICE_PRECISION_U64,
-                max_ts: 1,
                 ..Order::default()
             }),
             spot_positions: get_spot_positions(SpotPosition {
@@ -11325,115 +11511,58 @@ pub mod get_spot_maker_orders_info {
             ..User::default()
         };
 
-        let mut maker_spot_positions = get_spot_positions(SpotPosition {
-            market_index: 0,
-            balance_type: SpotBalanceType::Deposit,
-            scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
-            ..SpotPosition::default()
-        });
-        maker_spot_positions[1] = SpotPosition {
+        let mut maker_orders = [Order::default(); 32];
+        maker_orders[0] = Order {
             market_index: 1,
-            balance_type: SpotBalanceType::Deposit,
-            open_orders: 2,
-            ..SpotPosition::default()
+            order_id: 1,
+            status: OrderStatus::Open,
+            order_type: OrderType::Limit,
+            direction: PositionDirection::Short,
+            market_type: MarketType::Spot,
+            base_asset_amount: BASE_PRECISION_U64,
+            slot: 0,
+            price: 100 * PRICE_PRECISION_U64,
+            max_ts: 1,
+            post_only: true,
+            ..Order::default()
         };
-        let mut first_maker = User {
-            orders: get_orders!(
-                Order {
-                    market_index: 1,
-                    order_id: 1,
-                    status: OrderStatus::Open,
-                    order_type: OrderType::Limit,
-                    market_type: MarketType::Spot,
-                    direction: PositionDirection::Short,
-                    base_asset_amount: BASE_PRECISION_U64,
-                    slot: 0,
-                    price: 100 * PRICE_PRECISION_U64,
-                    ..Order::default()
-                },
-                Order {
-                    market_index: 1,
-                    order_id: 1,
-                    market_type: MarketType::Spot,
-                    status: OrderStatus::Open,
-                    order_type: OrderType::Limit,
-                    direction: PositionDirection::Short,
-                    base_asset_amount: BASE_PRECISION_U64,
-                    slot: 0,
-                    price: 102 * PRICE_PRECISION_U64,
-                    ..Order::default()
-                }
-            ),
-            spot_positions: maker_spot_positions,
-            ..User::default()
+        maker_orders[1] = Order {
+            market_index: 1,
+            order_id: 2,
+            status: OrderStatus::Open,
+            order_type: OrderType::Limit,
+            direction: PositionDirection::Short,
+            market_type: MarketType::Spot,
+            base_asset_amount: BASE_PRECISION_U64,
+            slot: 0,
+            price: 100 * PRICE_PRECISION_U64,
+            post_only: true,
+            ..Order::default()
         };
-        let first_maker_key =
-            Pubkey::from_str("My11111111111111111111111111111111111111113").unwrap();
-        create_anchor_account_info!(
-            first_maker,
-            &first_maker_key,
-            User,
-            first_maker_account_info
-        );
 
-        let mut second_maker_spot_positions = get_spot_positions(SpotPosition {
+        let mut maker_spot_positions = get_spot_positions(SpotPosition {
             market_index: 0,
             balance_type: SpotBalanceType::Deposit,
             scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
             ..SpotPosition::default()
         });
-        second_maker_spot_positions[1] = SpotPosition {
+        maker_spot_positions[1] = SpotPosition {
             market_index: 1,
             balance_type: SpotBalanceType::Deposit,
             open_orders: 2,
+            open_asks: -2 * LAMPORTS_PER_SOL_I64,
             ..SpotPosition::default()
         };
-        let mut second_maker = User {
-            orders: get_orders!(
-                Order {
-                    market_index: 1,
-                    order_id: 1,
-                    status: OrderStatus::Open,
-                    order_type: OrderType::Limit,
-                    market_type: MarketType::Spot,
-                    direction: PositionDirection::Short,
-                    base_asset_amount: BASE_PRECISION_U64,
-                    slot: 0,
-                    price: 101 * PRICE_PRECISION_U64,
-                    ..Order::default()
-                },
-                Order {
-                    market_index: 1,
-                    order_id: 1,
-                    status: OrderStatus::Open,
-                    order_type: OrderType::Limit,
-                    market_type: MarketType::Spot,
-                    direction: PositionDirection::Short,
-                    base_asset_amount: BASE_PRECISION_U64,
-                    slot: 0,
-                    price: 103 * PRICE_PRECISION_U64,
-                    ..Order::default()
-                }
-            ),
-            spot_positions: second_maker_spot_positions,
+
+        let mut maker = User {
+            orders: maker_orders,
+            spot_positions: maker_spot_positions,
             ..User::default()
         };
-        let second_maker_key =
-            Pubkey::from_str("My11111111111111111111111111111111111111112").unwrap();
-        create_anchor_account_info!(
-            second_maker,
-            &second_maker_key,
-            User,
-            second_maker_account_info
-        );
+        let maker_key = Pubkey::from_str("My11111111111111111111111111111111111111113").unwrap();
+        create_anchor_account_info!(maker, &maker_key, User, maker_account_info);
 
-        let mut makers_and_referrers = UserMap::load_one(&first_maker_account_info).unwrap();
-        makers_and_referrers
-            .insert(
-                second_maker_key,
-                AccountLoader::try_from(&second_maker_account_info).unwrap(),
-            )
-            .unwrap();
+        let makers_and_referrers = UserMap::load_one(&maker_account_info).unwrap();
 
         let filler_key = Pubkey::from_str("My11111111111111111111111111111111111111111").unwrap();
         let mut filler = User::default();
@@ -11457,17 +11586,12 @@ pub mod get_spot_maker_orders_info {
 
         assert_eq!(
             maker_order_price_and_indexes,
-            vec![
-                (first_maker_key, 0, 100000000),
-                (second_maker_key, 0, 101000000),
-                (first_maker_key, 1, 102000000),
-                (second_maker_key, 1, 103000000),
-            ],
+            vec![(maker_key, 1, 100 * PRICE_PRECISION_U64)]
         );
     }
 
     #[test]
-    fn jit_maker_order_id() {
+    fn one_maker_order_canceled_for_being_reduce_only() {
         let clock = Clock {
             slot: 6,
             epoch_start_timestamp: 0,
@@ -11527,8 +11651,8 @@ pub mod get_spot_maker_orders_info {
                 order_id: 1,
                 status: OrderStatus::Open,
                 order_type: OrderType::Market,
-                market_type: MarketType::Spot,
                 direction: PositionDirection::Long,
+                market_type: MarketType::Spot,
                 base_asset_amount: BASE_PRECISION_U64,
                 slot: 0,
                 auction_start_price: 0,
@@ -11547,6 +11671,21 @@ pub mod get_spot_maker_orders_info {
             ..User::default()
         };
 
+        let mut maker_orders = [Order::default(); 32];
+        maker_orders[0] = Order {
+            market_index: 1,
+            order_id: 1,
+            status: OrderStatus::Open,
+            order_type: OrderType::Limit,
+            direction: PositionDirection::Short,
+            market_type: MarketType::Spot,
+            base_asset_amount: BASE_PRECISION_U64,
+            slot: 0,
+            price: 100 * PRICE_PRECISION_U64,
+            reduce_only: true,
+            ..Order::default()
+        };
+
         let mut maker_spot_positions = get_spot_positions(SpotPosition {
             market_index: 0,
             balance_type: SpotBalanceType::Deposit,
@@ -11556,50 +11695,21 @@ pub mod get_spot_maker_orders_info {
         maker_spot_positions[1] = SpotPosition {
             market_index: 1,
             balance_type: SpotBalanceType::Deposit,
-            open_orders: 2,
-            open_asks: -2 * LAMPORTS_PER_SOL_I64,
+            open_orders: 1,
+            open_asks: -LAMPORTS_PER_SOL_I64,
+            scaled_balance: 0,
             ..SpotPosition::default()
         };
-        let mut first_maker = User {
-            orders: get_orders!(
-                Order {
-                    market_index: 1,
-                    order_id: 1,
-                    status: OrderStatus::Open,
-                    order_type: OrderType::Limit,
-                    market_type: MarketType::Spot,
-                    direction: PositionDirection::Short,
-                    base_asset_amount: BASE_PRECISION_U64,
-                    slot: 0,
-                    price: 100 * PRICE_PRECISION_U64,
-                    ..Order::default()
-                },
-                Order {
-                    market_index: 1,
-                    order_id: 2,
-                    status: OrderStatus::Open,
-                    market_type: MarketType::Spot,
-                    order_type: OrderType::Limit,
-                    direction: PositionDirection::Short,
-                    base_asset_amount: BASE_PRECISION_U64,
-                    slot: 0,
-                    price: 102 * PRICE_PRECISION_U64,
-                    ..Order::default()
-                }
-            ),
+
+        let mut maker = User {
+            orders: maker_orders,
             spot_positions: maker_spot_positions,
             ..User::default()
         };
-        let first_maker_key =
-            Pubkey::from_str("My11111111111111111111111111111111111111113").unwrap();
-        create_anchor_account_info!(
-            first_maker,
-            &first_maker_key,
-            User,
-            first_maker_account_info
-        );
+        let maker_key = Pubkey::from_str("My11111111111111111111111111111111111111113").unwrap();
+        create_anchor_account_info!(maker, &maker_key, User, maker_account_info);
 
-        let makers_and_referrers = UserMap::load_one(&first_maker_account_info).unwrap();
+        let makers_and_referrers = UserMap::load_one(&maker_account_info).unwrap();
 
         let filler_key = Pubkey::from_str("My11111111111111111111111111111111111111111").unwrap();
         let mut filler = User::default();
@@ -11615,20 +11725,17 @@ pub mod get_spot_maker_orders_info {
             &filler_key,
             0,
             oracle_price,
-            Some(2),
+            None,
             clock.unix_timestamp,
             clock.slot,
         )
         .unwrap();
 
-        assert_eq!(
-            maker_order_price_and_indexes,
-            vec![(first_maker_key, 1, 102000000),],
-        );
+        assert_eq!(maker_order_price_and_indexes, vec![],);
     }
 
     #[test]
-    fn two_makers_with_max_orders() {
+    fn two_makers() {
         let clock = Clock {
             slot: 6,
             epoch_start_timestamp: 0,
@@ -11708,33 +11815,46 @@ pub mod get_spot_maker_orders_info {
             ..User::default()
         };
 
-        let mut first_maker_spot_positions = get_spot_positions(SpotPosition {
+        let mut maker_spot_positions = get_spot_positions(SpotPosition {
             market_index: 0,
             balance_type: SpotBalanceType::Deposit,
             scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
             ..SpotPosition::default()
         });
-        first_maker_spot_positions[1] = SpotPosition {
+        maker_spot_positions[1] = SpotPosition {
             market_index: 1,
             balance_type: SpotBalanceType::Deposit,
-            open_orders: 32,
-            open_asks: -32 * LAMPORTS_PER_SOL_I64,
+            open_orders: 2,
             ..SpotPosition::default()
         };
         let mut first_maker = User {
-            orders: [Order {
-                market_index: 1,
-                order_id: 1,
-                status: OrderStatus::Open,
-                market_type: MarketType::Spot,
-                order_type: OrderType::Limit,
-                direction: PositionDirection::Short,
-                base_asset_amount: BASE_PRECISION_U64,
-                slot: 0,
-                price: 100 * PRICE_PRECISION_U64,
-                ..Order::default()
-            }; 32],
-            spot_positions: first_maker_spot_positions,
+            orders: get_orders!(
+                Order {
+                    market_index: 1,
+                    order_id: 1,
+                    status: OrderStatus::Open,
+                    order_type: OrderType::Limit,
+                    market_type: MarketType::Spot,
+                    direction: PositionDirection::Short,
+                    base_asset_amount: BASE_PRECISION_U64,
+                    slot: 0,
+                    price: 100 * PRICE_PRECISION_U64,
+                    ..Order::default()
+                },
+                Order {
+                    market_index: 1,
+                    order_id: 1,
+                    market_type: MarketType::Spot,
+                    status: OrderStatus::Open,
+                    order_type: OrderType::Limit,
+                    direction: PositionDirection::Short,
+                    base_asset_amount: BASE_PRECISION_U64,
+                    slot: 0,
+                    price: 102 * PRICE_PRECISION_U64,
+                    ..Order::default()
+                }
+            ),
+            spot_positions: maker_spot_positions,
             ..User::default()
         };
         let first_maker_key =
@@ -11755,23 +11875,36 @@ pub mod get_spot_maker_orders_info {
         second_maker_spot_positions[1] = SpotPosition {
             market_index: 1,
             balance_type: SpotBalanceType::Deposit,
-            open_orders: 32,
-            open_asks: -32 * LAMPORTS_PER_SOL_I64,
+            open_orders: 2,
             ..SpotPosition::default()
         };
         let mut second_maker = User {
-            orders: [Order {
-                market_index: 1,
-                order_id: 1,
-                status: OrderStatus::Open,
-                order_type: OrderType::Limit,
-                market_type: MarketType::Spot,
-                direction: PositionDirection::Short,
-                base_asset_amount: BASE_PRECISION_U64,
-                slot: 0,
-                price: 101 * PRICE_PRECISION_U64,
-                ..Order::default()
-            }; 32],
+            orders: get_orders!(
+                Order {
+                    market_index: 1,
+                    order_id: 1,
+                    status: OrderStatus::Open,
+                    order_type: OrderType::Limit,
+                    market_type: MarketType::Spot,
+                    direction: PositionDirection::Short,
+                    base_asset_amount: BASE_PRECISION_U64,
+                    slot: 0,
+                    price: 101 * PRICE_PRECISION_U64,
+                    ..Order::default()
+                },
+                Order {
+                    market_index: 1,
+                    order_id: 1,
+                    status: OrderStatus::Open,
+                    order_type: OrderType::Limit,
+                    market_type: MarketType::Spot,
+                    direction: PositionDirection::Short,
+                    base_asset_amount: BASE_PRECISION_U64,
+                    slot: 0,
+                    price: 103 * PRICE_PRECISION_U64,
+                    ..Order::default()
+                }
+            ),
             spot_positions: second_maker_spot_positions,
             ..User::default()
         };
@@ -11812,127 +11945,484 @@ pub mod get_spot_maker_orders_info {
         )
         .unwrap();
 
-        assert_eq!(maker_order_price_and_indexes.len(), 64);
+        assert_eq!(
+            maker_order_price_and_indexes,
+            vec![
+                (first_maker_key, 0, 100000000),
+                (second_maker_key, 0, 101000000),
+                (first_maker_key, 1, 102000000),
+                (second_maker_key, 1, 103000000),
+            ],
+        );
     }
-}
-
-pub mod update_trigger_order_params {
-    use crate::controller::orders::update_trigger_order_params;
-    use crate::state::oracle::OraclePriceData;
-    use crate::state::user::{Order, OrderTriggerCondition, OrderType};
-    use crate::{PositionDirection, PRICE_PRECISION_I64, PRICE_PRECISION_U64};
 
     #[test]
-    fn test() {
-        let mut order = Order {
-            order_type: OrderType::TriggerMarket,
-            direction: PositionDirection::Long,
-            trigger_condition: OrderTriggerCondition::Above,
-            ..Order::default()
+    fn jit_maker_order_id() {
+        let clock = Clock {
+            slot: 6,
+            epoch_start_timestamp: 0,
+            epoch: 0,
+            leader_schedule_epoch: 0,
+            unix_timestamp: 0,
         };
-        let oracle_price_data = OraclePriceData {
-            price: 100 * PRICE_PRECISION_I64,
-            confidence: 100 * PRICE_PRECISION_U64,
-            ..OraclePriceData::default()
+
+        let mut pyth_price = get_pyth_price(100, 6);
+        let oracle_price = 100 * PRICE_PRECISION_I64;
+        let oracle_price_key =
+            Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+        let pyth_program = crate::ids::pyth_program::id();
+        create_account_info!(
+            pyth_price,
+            &oracle_price_key,
+            &pyth_program,
+            oracle_account_info
+        );
+        let mut oracle_map = OracleMap::load_one(&oracle_account_info, clock.slot, None).unwrap();
+
+        let market_map = PerpMarketMap::empty();
+
+        let mut spot_market = SpotMarket {
+            market_index: 0,
+            oracle_source: OracleSource::QuoteAsset,
+            cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            decimals: 6,
+            initial_asset_weight: SPOT_WEIGHT_PRECISION,
+            maintenance_asset_weight: SPOT_WEIGHT_PRECISION,
+            historical_oracle_data: HistoricalOracleData::default_price(QUOTE_PRECISION_I64),
+            ..SpotMarket::default()
         };
-        let slot = 10;
-        let min_auction_duration = 10;
+        create_anchor_account_info!(spot_market, SpotMarket, spot_market_account_info);
 
-        update_trigger_order_params(
-            &mut order,
-            &oracle_price_data,
-            slot,
-            min_auction_duration,
-            None,
+        let mut base_market = SpotMarket {
+            deposit_balance: 100 * SPOT_BALANCE_PRECISION,
+            oracle: oracle_price_key,
+            ..SpotMarket::default_base_market()
+        };
+
+        create_anchor_account_info!(base_market, SpotMarket, base_spot_market_account_info);
+
+        let spot_market_map = SpotMarketMap::load_multiple(
+            vec![&spot_market_account_info, &base_spot_market_account_info],
+            true,
         )
         .unwrap();
 
-        assert_eq!(order.slot, slot);
-        assert_eq!(order.auction_duration, min_auction_duration);
-        assert_eq!(
-            order.trigger_condition,
-            OrderTriggerCondition::TriggeredAbove
-        );
-        assert_eq!(order.auction_start_price, 100000000);
-        assert_eq!(order.auction_end_price, 100500000);
+        let taker_key = Pubkey::default();
+        let taker_authority =
+            Pubkey::from_str("My11111111111111111111111111111111111111111").unwrap();
+        let user = User {
+            authority: taker_authority,
+            orders: get_orders(Order {
+                market_index: 1,
+                order_id: 1,
+                status: OrderStatus::Open,
+                order_type: OrderType::Market,
+                market_type: MarketType::Spot,
+                direction: PositionDirection::Long,
+                base_asset_amount: BASE_PRECISION_U64,
+                slot: 0,
+                auction_start_price: 0,
+                auction_end_price: 50 * PRICE_PRECISION_I64,
+                auction_duration: 5,
+                price: 100 * PRICE_PRECISION_U64,
+                max_ts: 1,
+                ..Order::default()
+            }),
+            spot_positions: get_spot_positions(SpotPosition {
+                market_index: 0,
+                balance_type: SpotBalanceType::Deposit,
+                scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
+                ..SpotPosition::default()
+            }),
+            ..User::default()
+        };
 
-        let mut order = Order {
-            order_type: OrderType::TriggerMarket,
-            direction: PositionDirection::Short,
-            trigger_condition: OrderTriggerCondition::Below,
-            ..Order::default()
+        let mut maker_spot_positions = get_spot_positions(SpotPosition {
+            market_index: 0,
+            balance_type: SpotBalanceType::Deposit,
+            scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
+            ..SpotPosition::default()
+        });
+        maker_spot_positions[1] = SpotPosition {
+            market_index: 1,
+            balance_type: SpotBalanceType::Deposit,
+            open_orders: 2,
+            open_asks: -2 * LAMPORTS_PER_SOL_I64,
+            ..SpotPosition::default()
+        };
+        let mut first_maker = User {
+            orders: get_orders!(
+                Order {
+                    market_index: 1,
+                    order_id: 1,
+                    status: OrderStatus::Open,
+                    order_type: OrderType::Limit,
+                    market_type: MarketType::Spot,
+                    direction: PositionDirection::Short,
+                    base_asset_amount: BASE_PRECISION_U64,
+                    slot: 0,
+                    price: 100 * PRICE_PRECISION_U64,
+                    ..Order::default()
+                },
+                Order {
+                    market_index: 1,
+                    order_id: 2,
+                    status: OrderStatus::Open,
+                    market_type: MarketType::Spot,
+                    order_type: OrderType::Limit,
+                    direction: PositionDirection::Short,
+                    base_asset_amount: BASE_PRECISION_U64,
+                    slot: 0,
+                    price: 102 * PRICE_PRECISION_U64,
+                    ..Order::default()
+                }
+            ),
+            spot_positions: maker_spot_positions,
+            ..User::default()
         };
+        let first_maker_key =
+            Pubkey::from_str("My11111111111111111111111111111111111111113").unwrap();
+        create_anchor_account_info!(
+            first_maker,
+            &first_maker_key,
+            User,
+            first_maker_account_info
+        );
 
-        update_trigger_order_params(
-            &mut order,
-            &oracle_price_data,
-            slot,
-            min_auction_duration,
-            None,
+        let makers_and_referrers = UserMap::load_one(&first_maker_account_info).unwrap();
+
+        let filler_key = Pubkey::from_str("My11111111111111111111111111111111111111111").unwrap();
+        let mut filler = User::default();
+
+        let maker_order_price_and_indexes = get_spot_maker_orders_info(
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            &makers_and_referrers,
+            &taker_key,
+            &user.orders[0],
+            &mut Some(&mut filler),
+            &filler_key,
+            0,
+            oracle_price,
+            Some(2),
+            clock.unix_timestamp,
+            clock.slot,
         )
         .unwrap();
 
-        assert_eq!(order.slot, slot);
-        assert_eq!(order.auction_duration, min_auction_duration);
         assert_eq!(
-            order.trigger_condition,
-            OrderTriggerCondition::TriggeredBelow
+            maker_order_price_and_indexes,
+            vec![(first_maker_key, 1, 102000000),],
         );
-        assert_eq!(order.auction_start_price, 100000000);
-        assert_eq!(order.auction_end_price, 99500000);
+    }
 
-        let mut order = Order {
-            order_type: OrderType::TriggerMarket,
-            direction: PositionDirection::Short,
-            trigger_condition: OrderTriggerCondition::TriggeredAbove,
-            ..Order::default()
+    #[test]
+    fn two_makers_with_max_orders() {
+        let clock = Clock {
+            slot: 6,
+            epoch_start_timestamp: 0,
+            epoch: 0,
+            leader_schedule_epoch: 0,
+            unix_timestamp: 0,
         };
 
-        let err = update_trigger_order_params(
-            &mut order,
-            &oracle_price_data,
-            slot,
-            min_auction_duration,
-            None,
-        );
-        assert!(err.is_err());
-
+        let mut pyth_price = get_pyth_price(100, 6);
+        let oracle_price = 100 * PRICE_PRECISION_I64;
+        let oracle_price_key =
+            Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+        let pyth_program = crate::ids::pyth_program::id();
+        create_account_info!(
+            pyth_price,
+            &oracle_price_key,
+            &pyth_program,
+            oracle_account_info
+        );
+        let mut oracle_map = OracleMap::load_one(&oracle_account_info, clock.slot, None).unwrap();
+
+        let market_map = PerpMarketMap::empty();
+
+        let mut spot_market = SpotMarket {
+            market_index: 0,
+            oracle_source: OracleSource::QuoteAsset,
+            cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            decimals: 6,
+            initial_asset_weight: SPOT_WEIGHT_PRECISION,
+            maintenance_asset_weight: SPOT_WEIGHT_PRECISION,
+            historical_oracle_data: HistoricalOracleData::default_price(QUOTE_PRECISION_I64),
+            ..SpotMarket::default()
+        };
+        create_anchor_account_info!(spot_market, SpotMarket, spot_market_account_info);
+
+        let mut base_market = SpotMarket {
+            deposit_balance: 100 * SPOT_BALANCE_PRECISION,
+            oracle: oracle_price_key,
+            ..SpotMarket::default_base_market()
+        };
+
+        create_anchor_account_info!(base_market, SpotMarket, base_spot_market_account_info);
+
+        let spot_market_map = SpotMarketMap::load_multiple(
+            vec![&spot_market_account_info, &base_spot_market_account_info],
+            true,
+        )
+        .unwrap();
+
+        let taker_key = Pubkey::default();
+        let taker_authority =
+            Pubkey::from_str("My11111111111111111111111111111111111111111").unwrap();
+        let user = User {
+            authority: taker_authority,
+            orders: get_orders(Order {
+                market_index: 1,
+                order_id: 1,
+                status: OrderStatus::Open,
+                order_type: OrderType::Market,
+                market_type: MarketType::Spot,
+                direction: PositionDirection::Long,
+                base_asset_amount: BASE_PRECISION_U64,
+                slot: 0,
+                auction_start_price: 0,
+                auction_end_price: 50 * PRICE_PRECISION_I64,
+                auction_duration: 5,
+                price: 100 * PRICE_PRECISION_U64,
+                max_ts: 1,
+                ..Order::default()
+            }),
+            spot_positions: get_spot_positions(SpotPosition {
+                market_index: 0,
+                balance_type: SpotBalanceType::Deposit,
+                scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
+                ..SpotPosition::default()
+            }),
+            ..User::default()
+        };
+
+        let mut first_maker_spot_positions = get_spot_positions(SpotPosition {
+            market_index: 0,
+            balance_type: SpotBalanceType::Deposit,
+            scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
+            ..SpotPosition::default()
+        });
+        first_maker_spot_positions[1] = SpotPosition {
+            market_index: 1,
+            balance_type: SpotBalanceType::Deposit,
+            open_orders: 32,
+            open_asks: -32 * LAMPORTS_PER_SOL_I64,
+            ..SpotPosition::default()
+        };
+        let mut first_maker = User {
+            orders: [Order {
+                market_index: 1,
+                order_id: 1,
+                status: OrderStatus::Open,
+                market_type: MarketType::Spot,
+                order_type: OrderType::Limit,
+                direction: PositionDirection::Short,
+                base_asset_amount: BASE_PRECISION_U64,
+                slot: 0,
+                price: 100 * PRICE_PRECISION_U64,
+                ..Order::default()
+            }; 32],
+            spot_positions: first_maker_spot_positions,
+            ..User::default()
+        };
+        let first_maker_key =
+            Pubkey::from_str("My11111111111111111111111111111111111111113").unwrap();
+        create_anchor_account_info!(
+            first_maker,
+            &first_maker_key,
+            User,
+            first_maker_account_info
+        );
+
+        let mut second_maker_spot_positions = get_spot_positions(SpotPosition {
+            market_index: 0,
+            balance_type: SpotBalanceType::Deposit,
+            scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
+            ..SpotPosition::default()
+        });
+        second_maker_spot_positions[1] = SpotPosition {
+            market_index: 1,
+            balance_type: SpotBalanceType::Deposit,
+            open_orders: 32,
+            open_asks: -32 * LAMPORTS_PER_SOL_I64,
+            ..SpotPosition::default()
+        };
+        let mut second_maker = User {
+            orders: [Order {
+                market_index: 1,
+                order_id: 1,
+                status: OrderStatus::Open,
+                order_type: OrderType::Limit,
+                market_type: MarketType::Spot,
+                direction: PositionDirection::Short,
+                base_asset_amount: BASE_PRECISION_U64,
+                slot: 0,
+                price: 101 * PRICE_PRECISION_U64,
+                ..Order::default()
+            }; 32],
+            spot_positions: second_maker_spot_positions,
+            ..User::default()
+        };
+        let second_maker_key =
+            Pubkey::from_str("My11111111111111111111111111111111111111112").unwrap();
+        create_anchor_account_info!(
+            second_maker,
+            &second_maker_key,
+            User,
+            second_maker_account_info
+        );
+
+        let mut makers_and_referrers = UserMap::load_one(&first_maker_account_info).unwrap();
+        makers_and_referrers
+            .insert(
+                second_maker_key,
+                AccountLoader::try_from(&second_maker_account_info).unwrap(),
+            )
+            .unwrap();
+
+        let filler_key = Pubkey::from_str("My11111111111111111111111111111111111111111").unwrap();
+        let mut filler = User::default();
+
+        let maker_order_price_and_indexes = get_spot_maker_orders_info(
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            &makers_and_referrers,
+            &taker_key,
+            &user.orders[0],
+            &mut Some(&mut filler),
+            &filler_key,
+            0,
+            oracle_price,
+            None,
+            clock.unix_timestamp,
+            clock.slot,
+        )
+        .unwrap();
+
+        assert_eq!(maker_order_price_and_indexes.len(), 64);
+    }
+}
+
+pub mod update_trigger_order_params {
+    use crate::controller::orders::update_trigger_order_params;
+    use crate::state::oracle::OraclePriceData;
+    use crate::state::user::{Order, OrderTriggerCondition, OrderType};
+    use crate::{PositionDirection, PRICE_PRECISION_I64, PRICE_PRECISION_U64};
+
+    #[test]
+    fn test() {
         let mut order = Order {
             order_type: OrderType::TriggerMarket,
-            direction: PositionDirection::Short,
-            trigger_condition: OrderTriggerCondition::TriggeredBelow,
+            direction: PositionDirection::Long,
+            trigger_condition: OrderTriggerCondition::Above,
             ..Order::default()
         };
+        let oracle_price_data = OraclePriceData {
+            price: 100 * PRICE_PRECISION_I64,
+            confidence: 100 * PRICE_PRECISION_U64,
+            ..OraclePriceData::default()
+        };
+        let slot = 10;
+        let min_auction_duration = 10;
 
-        let err = update_trigger_order_params(
+        update_trigger_order_params(
             &mut order,
             &oracle_price_data,
             slot,
             min_auction_duration,
             None,
-        );
-        assert!(err.is_err());
-    }
-}
+        )
+        .unwrap();
 
-mod update_maker_fills_map {
-    use crate::controller::orders::update_maker_fills_map;
-    use crate::PositionDirection;
-    use solana_program::pubkey::Pubkey;
-    use std::collections::BTreeMap;
+        assert_eq!(order.slot, slot);
+        assert_eq!(order.auction_duration, min_auction_duration);
+        assert_eq!(
+            order.trigger_condition,
+            OrderTriggerCondition::TriggeredAbove
+        );
+        assert_eq!(order.auction_start_price, 100000000);
+        assert_eq!(order.auction_end_price, 100500000);
 
-    #[test]
-    fn test() {
-        let mut map: BTreeMap<Pubkey, i64> = BTreeMap::new();
+        let mut order = Order {
+            order_type: OrderType::TriggerMarket,
+            direction: PositionDirection::Short,
+            trigger_condition: OrderTriggerCondition::Below,
+            ..Order::default()
+        };
 
-        let maker_key = Pubkey::new_unique();
-        let fill = 100;
-        let direction = PositionDirection::Long;
-        update_maker_fills_map(&mut map, &maker_key, direction, fill).unwrap();
+        update_trigger_order_params(
+            &mut order,
+            &oracle_price_data,
+            slot,
+            min_auction_duration,
+            None,
+        )
+        .unwrap();
 
-        assert_eq!(*map.get(&maker_key).unwrap(), fill as i64);
+        assert_eq!(order.slot, slot);
+        assert_eq!(order.auction_duration, min_auction_duration);
+        assert_eq!(
+            order.trigger_condition,
+            OrderTriggerCondition::TriggeredBelow
+        );
+        assert_eq!(order.auction_start_price, 100000000);
+        assert_eq!(order.auction_end_price, 99500000);
 
-        update_maker_fills_map(&mut map, &maker_key, direction, fill).unwrap();
+        let mut order = Order {
+            order_type: OrderType::TriggerMarket,
+            direction: PositionDirection::Short,
+            trigger_condition: OrderTriggerCondition::TriggeredAbove,
+            ..Order::default()
+        };
+
+        let err = update_trigger_order_params(
+            &mut order,
+            &oracle_price_data,
+            slot,
+            min_auction_duration,
+            None,
+        );
+        assert!(err.is_err());
+
+        let mut order = Order {
+            order_type: OrderType::TriggerMarket,
+            direction: PositionDirection::Short,
+            trigger_condition: OrderTriggerCondition::TriggeredBelow,
+            ..Order::default()
+        };
+
+        let err = update_trigger_order_params(
+            &mut order,
+            &oracle_price_data,
+            slot,
+            min_auction_duration,
+            None,
+        );
+        assert!(err.is_err());
+    }
+}
+
+mod update_maker_fills_map {
+    use crate::controller::orders::update_maker_fills_map;
+    use crate::PositionDirection;
+    use solana_program::pubkey::Pubkey;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test() {
+        let mut map: BTreeMap<Pubkey, i64> = BTreeMap::new();
+
+        let maker_key = Pubkey::new_unique();
+        let fill = 100;
+        let direction = PositionDirection::Long;
+        update_maker_fills_map(&mut map, &maker_key, direction, fill).unwrap();
+
+        assert_eq!(*map.get(&maker_key).unwrap(), fill as i64);
+
+        update_maker_fills_map(&mut map, &maker_key, direction, fill).unwrap();
 
         assert_eq!(*map.get(&maker_key).unwrap(), 2 * fill as i64);
 
@@ -11947,3 +12437,2968 @@ mod update_maker_fills_map {
         assert_eq!(*map.get(&maker_key).unwrap(), -2 * fill as i64);
     }
 }
+
+pub mod modify_orders {
+    use std::str::FromStr;
+
+    use anchor_lang::prelude::{AccountLoader, Clock};
+
+    use crate::controller::orders::modify_orders;
+    use crate::controller::position::PositionDirection;
+    use crate::create_account_info;
+    use crate::create_anchor_account_info;
+    use crate::error::ErrorCode;
+    use crate::math::constants::{
+        AMM_RESERVE_PRECISION, BASE_PRECISION_I64, BASE_PRECISION_U64, PEG_PRECISION,
+        PRICE_PRECISION_I64, PRICE_PRECISION_U64, QUOTE_PRECISION_I64, SPOT_BALANCE_PRECISION_U64,
+        SPOT_CUMULATIVE_INTEREST_PRECISION, SPOT_WEIGHT_PRECISION,
+    };
+    use crate::state::oracle::{HistoricalOracleData, OracleSource};
+    use crate::state::order_params::{ModifyOrderByIdParams, ModifyOrderParams, ModifyOrderPolicy};
+    use crate::state::perp_market::{PerpMarket, AMM};
+    use crate::state::perp_market_map::PerpMarketMap;
+    use crate::state::spot_market::{SpotBalanceType, SpotMarket};
+    use crate::state::spot_market_map::SpotMarketMap;
+    use crate::state::state::State;
+    use crate::state::user::{OrderStatus, OrderType, SpotPosition, User};
+    use crate::test_utils::*;
+    use crate::test_utils::{
+        create_account_info, get_positions, get_pyth_price, get_spot_positions,
+    };
+
+    use super::*;
+
+    pub fn get_market(oracle_price_key: Pubkey) -> PerpMarket {
+        PerpMarket {
+            amm: AMM {
+                base_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                terminal_quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                sqrt_k: 100 * AMM_RESERVE_PRECISION,
+                peg_multiplier: 100 * PEG_PRECISION,
+                max_slippage_ratio: 100,
+                max_fill_reserve_fraction: 100,
+                order_step_size: 1000,
+                order_tick_size: 1,
+                oracle: oracle_price_key,
+                max_spread: 1000,
+                historical_oracle_data: HistoricalOracleData {
+                    last_oracle_price_twap: 100 * PRICE_PRECISION_I64,
+                    last_oracle_price_twap_5min: 100 * PRICE_PRECISION_I64,
+                    last_oracle_price: 100 * PRICE_PRECISION_I64,
+                    ..HistoricalOracleData::default()
+                },
+                max_base_asset_reserve: u128::MAX,
+                ..AMM::default()
+            },
+            margin_ratio_initial: 1000,
+            margin_ratio_maintenance: 500,
+            status: MarketStatus::Active,
+            ..PerpMarket::default()
+        }
+    }
+
+    pub fn get_spot_market() -> SpotMarket {
+        SpotMarket {
+            market_index: 0,
+            oracle_source: OracleSource::QuoteAsset,
+            cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            decimals: 6,
+            initial_asset_weight: SPOT_WEIGHT_PRECISION,
+            maintenance_asset_weight: SPOT_WEIGHT_PRECISION,
+            historical_oracle_data: HistoricalOracleData::default_price(QUOTE_PRECISION_I64),
+            ..SpotMarket::default()
+        }
+    }
+
+    // $100 of collateral, long 1 at $100 with a bid at $90 and an ask at $110
+    pub fn get_user() -> User {
+        let mut orders = [Order::default(); 32];
+        orders[0] = Order {
+            market_index: 0,
+            order_id: 1,
+            status: OrderStatus::Open,
+            order_type: OrderType::Limit,
+            direction: PositionDirection::Long,
+            base_asset_amount: BASE_PRECISION_U64,
+            price: 90 * PRICE_PRECISION_U64,
+            ..Order::default()
+        };
+        orders[1] = Order {
+            market_index: 0,
+            order_id: 2,
+            status: OrderStatus::Open,
+            order_type: OrderType::Limit,
+            direction: PositionDirection::Short,
+            base_asset_amount: BASE_PRECISION_U64,
+            price: 110 * PRICE_PRECISION_U64,
+            ..Order::default()
+        };
+
+        User {
+            orders,
+            next_order_id: 3,
+            perp_positions: get_positions(PerpPosition {
+                market_index: 0,
+                base_asset_amount: BASE_PRECISION_I64,
+                quote_asset_amount: -100 * QUOTE_PRECISION_I64,
+                quote_entry_amount: -100 * QUOTE_PRECISION_I64,
+                quote_break_even_amount: -100 * QUOTE_PRECISION_I64,
+                open_orders: 2,
+                open_bids: BASE_PRECISION_I64,
+                open_asks: -BASE_PRECISION_I64,
+                ..PerpPosition::default()
+            }),
+            spot_positions: get_spot_positions(SpotPosition {
+                market_index: 0,
+                balance_type: SpotBalanceType::Deposit,
+                scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
+                ..SpotPosition::default()
+            }),
+            ..User::default()
+        }
+    }
+
+    fn modify_params(
+        order_id: u32,
+        modify_order_params: ModifyOrderParams,
+    ) -> ModifyOrderByIdParams {
+        ModifyOrderByIdParams {
+            order_id,
+            modify_order_params,
+        }
+    }
+
+    #[test]
+    fn initial_margin_if_any_modification_increases_risk() {
+        let clock = Clock {
+            slot: 56,
+            epoch_start_timestamp: 0,
+            epoch: 0,
+            leader_schedule_epoch: 0,
+            unix_timestamp: 0,
+        };
+
+        let mut oracle_price = get_pyth_price(100, 6);
+        let oracle_price_key =
+            Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+        let pyth_program = crate::ids::pyth_program::id();
+        create_account_info!(
+            oracle_price,
+            &oracle_price_key,
+            &pyth_program,
+            oracle_account_info
+        );
+        let mut oracle_map = OracleMap::load_one(&oracle_account_info, clock.slot, None).unwrap();
+
+        let mut market = get_market(oracle_price_key);
+        create_anchor_account_info!(market, PerpMarket, market_account_info);
+        let market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+
+        let mut spot_market = get_spot_market();
+        create_anchor_account_info!(spot_market, SpotMarket, spot_market_account_info);
+        let spot_market_map = SpotMarketMap::load_one(&spot_market_account_info, true).unwrap();
+
+        let state = State::default();
+
+        // bid grows to 15 so worst case long is 16: $160 initial, $80 maintenance vs $100 collateral.
+        // the ask modified last is risk reducing, but the batch must still be checked against initial
+        let mut user = get_user();
+        create_anchor_account_info!(user, User, user_account_info);
+        let user_account_loader: AccountLoader<User> =
+            AccountLoader::try_from(&user_account_info).unwrap();
+
+        let result = modify_orders(
+            vec![
+                modify_params(
+                    1,
+                    ModifyOrderParams {
+                        base_asset_amount: Some(15 * BASE_PRECISION_U64),
+                        ..ModifyOrderParams::default()
+                    },
+                ),
+                modify_params(
+                    2,
+                    ModifyOrderParams {
+                        price: Some(111 * PRICE_PRECISION_U64),
+                        ..ModifyOrderParams::default()
+                    },
+                ),
+            ],
+            &user_account_loader,
+            &state,
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            &clock,
+        );
+        assert_eq!(result, Err(ErrorCode::InsufficientCollateral));
+
+        // bid grows to 5 so worst case long is 6: $60 initial vs $100 collateral
+        let mut user = get_user();
+        create_anchor_account_info!(user, User, user_account_info);
+        let user_account_loader: AccountLoader<User> =
+            AccountLoader::try_from(&user_account_info).unwrap();
+
+        modify_orders(
+            vec![
+                modify_params(
+                    1,
+                    ModifyOrderParams {
+                        base_asset_amount: Some(5 * BASE_PRECISION_U64),
+                        ..ModifyOrderParams::default()
+                    },
+                ),
+                modify_params(
+                    2,
+                    ModifyOrderParams {
+                        price: Some(111 * PRICE_PRECISION_U64),
+                        ..ModifyOrderParams::default()
+                    },
+                ),
+            ],
+            &user_account_loader,
+            &state,
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            &clock,
+        )
+        .unwrap();
+
+        let user = user_account_loader.load().unwrap();
+        assert_eq!(user.orders[0].order_id, 3);
+        assert_eq!(user.orders[0].base_asset_amount, 5 * BASE_PRECISION_U64);
+        assert_eq!(user.orders[1].order_id, 4);
+        assert_eq!(user.orders[1].price, 111 * PRICE_PRECISION_U64);
+        assert_eq!(user.perp_positions[0].open_orders, 2);
+        assert_eq!(user.perp_positions[0].open_bids, 5 * BASE_PRECISION_I64);
+        assert_eq!(user.perp_positions[0].open_asks, -BASE_PRECISION_I64);
+    }
+
+    #[test]
+    fn missing_or_duplicate_orders() {
+        let clock = Clock {
+            slot: 56,
+            epoch_start_timestamp: 0,
+            epoch: 0,
+            leader_schedule_epoch: 0,
+            unix_timestamp: 0,
+        };
+
+        let mut oracle_price = get_pyth_price(100, 6);
+        let oracle_price_key =
+            Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+        let pyth_program = crate::ids::pyth_program::id();
+        create_account_info!(
+            oracle_price,
+            &oracle_price_key,
+            &pyth_program,
+            oracle_account_info
+        );
+        let mut oracle_map = OracleMap::load_one(&oracle_account_info, clock.slot, None).unwrap();
+
+        let mut market = get_market(oracle_price_key);
+        create_anchor_account_info!(market, PerpMarket, market_account_info);
+        let market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+
+        let mut spot_market = get_spot_market();
+        create_anchor_account_info!(spot_market, SpotMarket, spot_market_account_info);
+        let spot_market_map = SpotMarketMap::load_one(&spot_market_account_info, true).unwrap();
+
+        let state = State::default();
+
+        let mut user = get_user();
+        create_anchor_account_info!(user, User, user_account_info);
+        let user_account_loader: AccountLoader<User> =
+            AccountLoader::try_from(&user_account_info).unwrap();
+
+        // must modify order that doesnt exist fails the whole batch before anything is modified
+        let result = modify_orders(
+            vec![
+                modify_params(
+                    1,
+                    ModifyOrderParams {
+                        base_asset_amount: Some(5 * BASE_PRECISION_U64),
+                        ..ModifyOrderParams::default()
+                    },
+                ),
+                modify_params(
+                    5,
+                    ModifyOrderParams {
+                        price: Some(111 * PRICE_PRECISION_U64),
+                        policy: Some(ModifyOrderPolicy::MustModify),
+                        ..ModifyOrderParams::default()
+                    },
+                ),
+            ],
+            &user_account_loader,
+            &state,
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            &clock,
+        );
+        assert_eq!(result, Err(ErrorCode::OrderDoesNotExist));
+        assert_eq!(
+            user_account_loader.load().unwrap().orders[0].base_asset_amount,
+            BASE_PRECISION_U64
+        );
+
+        // same order twice fails the whole batch
+        let result = modify_orders(
+            vec![
+                modify_params(
+                    1,
+                    ModifyOrderParams {
+                        base_asset_amount: Some(5 * BASE_PRECISION_U64),
+                        ..ModifyOrderParams::default()
+                    },
+                ),
+                modify_params(
+                    1,
+                    ModifyOrderParams {
+                        price: Some(91 * PRICE_PRECISION_U64),
+                        ..ModifyOrderParams::default()
+                    },
+                ),
+            ],
+            &user_account_loader,
+            &state,
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            &clock,
+        );
+        assert_eq!(result, Err(ErrorCode::InvalidOrder));
+        assert_eq!(
+            user_account_loader.load().unwrap().orders[0].base_asset_amount,
+            BASE_PRECISION_U64
+        );
+
+        // try modify order that doesnt exist is skipped, the rest of the batch is modified
+        modify_orders(
+            vec![
+                modify_params(
+                    1,
+                    ModifyOrderParams {
+                        base_asset_amount: Some(5 * BASE_PRECISION_U64),
+                        ..ModifyOrderParams::default()
+                    },
+                ),
+                modify_params(
+                    5,
+                    ModifyOrderParams {
+                        price: Some(111 * PRICE_PRECISION_U64),
+                        policy: Some(ModifyOrderPolicy::TryModify),
+                        ..ModifyOrderParams::default()
+                    },
+                ),
+            ],
+            &user_account_loader,
+            &state,
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            &clock,
+        )
+        .unwrap();
+
+        let user = user_account_loader.load().unwrap();
+        assert_eq!(user.orders[0].order_id, 3);
+        assert_eq!(user.orders[0].base_asset_amount, 5 * BASE_PRECISION_U64);
+        assert_eq!(user.orders[1].order_id, 2);
+        assert_eq!(user.orders[1].price, 110 * PRICE_PRECISION_U64);
+    }
+}
+
+pub mod place_orders {
+    use std::str::FromStr;
+
+    use anchor_lang::prelude::Clock;
+
+    use super::modify_orders::{get_market, get_spot_market, get_user};
+    use crate::controller::orders::{cancel_and_place_orders, place_orders};
+    use crate::controller::position::PositionDirection;
+    use crate::create_account_info;
+    use crate::create_anchor_account_info;
+    use crate::error::ErrorCode;
+    use crate::math::constants::{BASE_PRECISION_I64, BASE_PRECISION_U64, PRICE_PRECISION_U64};
+    use crate::state::order_params::OrderParams;
+    use crate::state::perp_market::PerpMarket;
+    use crate::state::perp_market_map::PerpMarketMap;
+    use crate::state::spot_market::SpotMarket;
+    use crate::state::spot_market_map::SpotMarketMap;
+    use crate::state::state::State;
+    use crate::state::user::{OrderStatus, OrderType};
+    use crate::test_utils::*;
+    use crate::test_utils::{create_account_info, get_pyth_price};
+
+    use super::*;
+
+    fn limit_order_params(
+        direction: PositionDirection,
+        base_asset_amount: u64,
+        price: u64,
+    ) -> OrderParams {
+        OrderParams {
+            order_type: OrderType::Limit,
+            market_type: MarketType::Perp,
+            direction,
+            base_asset_amount,
+            price,
+            market_index: 0,
+            ..OrderParams::default()
+        }
+    }
+
+    #[test]
+    fn initial_margin_if_any_order_increases_risk() {
+        let clock = Clock {
+            slot: 56,
+            epoch_start_timestamp: 0,
+            epoch: 0,
+            leader_schedule_epoch: 0,
+            unix_timestamp: 0,
+        };
+
+        let mut oracle_price = get_pyth_price(100, 6);
+        let oracle_price_key =
+            Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+        let pyth_program = crate::ids::pyth_program::id();
+        create_account_info!(
+            oracle_price,
+            &oracle_price_key,
+            &pyth_program,
+            oracle_account_info
+        );
+        let mut oracle_map = OracleMap::load_one(&oracle_account_info, clock.slot, None).unwrap();
+
+        let mut market = get_market(oracle_price_key);
+        create_anchor_account_info!(market, PerpMarket, market_account_info);
+        let market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+
+        let mut spot_market = get_spot_market();
+        create_anchor_account_info!(spot_market, SpotMarket, spot_market_account_info);
+        let spot_market_map = SpotMarketMap::load_one(&spot_market_account_info, true).unwrap();
+
+        let state = State::default();
+
+        // worst case long is 1 + 1 + 14 = 16: $160 initial, $80 maintenance vs $100 collateral.
+        // the reduce only ask placed last is risk reducing, but the batch must still be checked
+        // against initial
+        let mut user = get_user();
+        let result = place_orders(
+            &state,
+            &mut user,
+            Pubkey::default(),
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            &clock,
+            &[
+                limit_order_params(
+                    PositionDirection::Long,
+                    14 * BASE_PRECISION_U64,
+                    90 * PRICE_PRECISION_U64,
+                ),
+                OrderParams {
+                    reduce_only: true,
+                    ..limit_order_params(
+                        PositionDirection::Short,
+                        BASE_PRECISION_U64,
+                        111 * PRICE_PRECISION_U64,
+                    )
+                },
+            ],
+        );
+        assert_eq!(result, Err(ErrorCode::InsufficientCollateral));
+
+        // worst case long is 1 + 1 + 4 = 6: $60 initial vs $100 collateral
+        let mut user = get_user();
+        place_orders(
+            &state,
+            &mut user,
+            Pubkey::default(),
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            &clock,
+            &[
+                limit_order_params(
+                    PositionDirection::Long,
+                    4 * BASE_PRECISION_U64,
+                    90 * PRICE_PRECISION_U64,
+                ),
+                limit_order_params(
+                    PositionDirection::Short,
+                    BASE_PRECISION_U64,
+                    111 * PRICE_PRECISION_U64,
+                ),
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(user.perp_positions[0].open_orders, 4);
+        assert_eq!(user.perp_positions[0].open_bids, 5 * BASE_PRECISION_I64);
+        assert_eq!(user.perp_positions[0].open_asks, -2 * BASE_PRECISION_I64);
+    }
+
+    #[test]
+    fn cancel_then_place() {
+        let clock = Clock {
+            slot: 56,
+            epoch_start_timestamp: 0,
+            epoch: 0,
+            leader_schedule_epoch: 0,
+            unix_timestamp: 0,
+        };
+
+        let mut oracle_price = get_pyth_price(100, 6);
+        let oracle_price_key =
+            Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+        let pyth_program = crate::ids::pyth_program::id();
+        create_account_info!(
+            oracle_price,
+            &oracle_price_key,
+            &pyth_program,
+            oracle_account_info
+        );
+        let mut oracle_map = OracleMap::load_one(&oracle_account_info, clock.slot, None).unwrap();
+
+        let mut market = get_market(oracle_price_key);
+        create_anchor_account_info!(market, PerpMarket, market_account_info);
+        let market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+
+        let mut spot_market = get_spot_market();
+        create_anchor_account_info!(spot_market, SpotMarket, spot_market_account_info);
+        let spot_market_map = SpotMarketMap::load_one(&spot_market_account_info, true).unwrap();
+
+        let state = State::default();
+
+        // bid of 8 only fits because the existing bid of 1 is canceled first: worst case long is
+        // 1 + 8 = 9, $90 initial vs $100 collateral. the ask is kept by the direction filter
+        let mut user = get_user();
+
+        cancel_and_place_orders(
+            &state,
+            &mut user,
+            Pubkey::default(),
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            &clock,
+            MarketType::Perp,
+            0,
+            Some(PositionDirection::Long),
+            &[limit_order_params(
+                PositionDirection::Long,
+                8 * BASE_PRECISION_U64,
+                91 * PRICE_PRECISION_U64,
+            )],
+        )
+        .unwrap();
+
+        assert_eq!(user.orders[0].order_id, 3);
+        assert_eq!(user.orders[0].status, OrderStatus::Open);
+        assert_eq!(user.orders[0].base_asset_amount, 8 * BASE_PRECISION_U64);
+        assert_eq!(user.orders[0].price, 91 * PRICE_PRECISION_U64);
+        assert_eq!(user.orders[1].order_id, 2);
+        assert_eq!(user.orders[1].status, OrderStatus::Open);
+        assert_eq!(user.perp_positions[0].open_orders, 2);
+        assert_eq!(user.perp_positions[0].open_bids, 8 * BASE_PRECISION_I64);
+        assert_eq!(user.perp_positions[0].open_asks, -BASE_PRECISION_I64);
+
+        // requote where a risk increasing bid is followed by a risk reducing ask: worst case long is
+        // 1 + 14 = 15, $150 initial vs $100 collateral
+        let result = cancel_and_place_orders(
+            &state,
+            &mut user,
+            Pubkey::default(),
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            &clock,
+            MarketType::Perp,
+            0,
+            None,
+            &[
+                limit_order_params(
+                    PositionDirection::Long,
+                    14 * BASE_PRECISION_U64,
+                    91 * PRICE_PRECISION_U64,
+                ),
+                limit_order_params(
+                    PositionDirection::Short,
+                    BASE_PRECISION_U64,
+                    111 * PRICE_PRECISION_U64,
+                ),
+            ],
+        );
+        assert_eq!(result, Err(ErrorCode::InsufficientCollateral));
+    }
+}
+
+pub mod margin_call {
+    use std::str::FromStr;
+
+    use anchor_lang::prelude::Clock;
+
+    use super::modify_orders::{get_market, get_spot_market};
+    use crate::controller::orders::{
+        place_margin_call_order, place_perp_order, update_user_margin_call,
+    };
+    use crate::controller::position::PositionDirection;
+    use crate::create_account_info;
+    use crate::create_anchor_account_info;
+    use crate::error::ErrorCode;
+    use crate::math::constants::{
+        BASE_PRECISION_I64, BASE_PRECISION_U64, PRICE_PRECISION_U64, QUOTE_PRECISION_I64,
+        SPOT_BALANCE_PRECISION_U64,
+    };
+    use crate::state::order_params::{OrderParams, PlaceOrderOptions};
+    use crate::state::perp_market::PerpMarket;
+    use crate::state::perp_market_map::PerpMarketMap;
+    use crate::state::spot_market::{SpotBalanceType, SpotMarket};
+    use crate::state::spot_market_map::SpotMarketMap;
+    use crate::state::state::State;
+    use crate::state::user::{Order, OrderStatus, OrderType, SpotPosition, User, UserStatus};
+    use crate::test_utils::*;
+    use crate::test_utils::{
+        create_account_info, get_positions, get_pyth_price, get_spot_positions,
+    };
+
+    use super::*;
+
+    fn get_clock(slot: u64) -> Clock {
+        Clock {
+            slot,
+            epoch_start_timestamp: 0,
+            epoch: 0,
+            leader_schedule_epoch: 0,
+            unix_timestamp: 0,
+        }
+    }
+
+    fn get_state() -> State {
+        State {
+            margin_call_buffer_ratio: 500,
+            margin_call_grace_period: 10,
+            ..State::default()
+        }
+    }
+
+    // long 10 at $100: $50 maintenance, $100 maintenance plus the 5% margin call buffer
+    fn get_user(collateral: u64) -> User {
+        User {
+            perp_positions: get_positions(PerpPosition {
+                market_index: 0,
+                base_asset_amount: 10 * BASE_PRECISION_I64,
+                quote_asset_amount: -1000 * QUOTE_PRECISION_I64,
+                quote_entry_amount: -1000 * QUOTE_PRECISION_I64,
+                quote_break_even_amount: -1000 * QUOTE_PRECISION_I64,
+                ..PerpPosition::default()
+            }),
+            spot_positions: get_spot_positions(SpotPosition {
+                market_index: 0,
+                balance_type: SpotBalanceType::Deposit,
+                scaled_balance: collateral * SPOT_BALANCE_PRECISION_U64,
+                ..SpotPosition::default()
+            }),
+            ..User::default()
+        }
+    }
+
+    #[test]
+    fn enter_stay_and_exit() {
+        let slot = 5_u64;
+
+        let mut oracle_price = get_pyth_price(100, 6);
+        let oracle_price_key =
+            Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+        let pyth_program = crate::ids::pyth_program::id();
+        create_account_info!(
+            oracle_price,
+            &oracle_price_key,
+            &pyth_program,
+            oracle_account_info
+        );
+        let mut oracle_map = OracleMap::load_one(&oracle_account_info, slot, None).unwrap();
+
+        let mut market = get_market(oracle_price_key);
+        create_anchor_account_info!(market, PerpMarket, market_account_info);
+        let market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+
+        let mut spot_market = get_spot_market();
+        create_anchor_account_info!(spot_market, SpotMarket, spot_market_account_info);
+        let spot_market_map = SpotMarketMap::load_one(&spot_market_account_info, true).unwrap();
+
+        let state = get_state();
+
+        // $60 is above maintenance but below the buffer
+        let mut user = get_user(60);
+        update_user_margin_call(
+            &state,
+            &mut user,
+            &Pubkey::default(),
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            0,
+            slot,
+        )
+        .unwrap();
+        assert!(user.is_in_margin_call());
+        assert_eq!(user.last_active_slot, slot);
+
+        // staying in margin call keeps the slot it was entered at
+        update_user_margin_call(
+            &state,
+            &mut user,
+            &Pubkey::default(),
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            0,
+            slot + 20,
+        )
+        .unwrap();
+        assert!(user.is_in_margin_call());
+        assert_eq!(user.last_active_slot, slot);
+
+        // back above the buffer
+        user.spot_positions[0].scaled_balance = 200 * SPOT_BALANCE_PRECISION_U64;
+        update_user_margin_call(
+            &state,
+            &mut user,
+            &Pubkey::default(),
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            0,
+            slot + 20,
+        )
+        .unwrap();
+        assert!(!user.is_in_margin_call());
+
+        // below maintenance has to be liquidated instead
+        let mut user = get_user(40);
+        update_user_margin_call(
+            &state,
+            &mut user,
+            &Pubkey::default(),
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            0,
+            slot,
+        )
+        .unwrap();
+        assert!(!user.is_in_margin_call());
+
+        let mut user = User {
+            status: UserStatus::BeingLiquidated as u8,
+            ..get_user(40)
+        };
+        let result = update_user_margin_call(
+            &state,
+            &mut user,
+            &Pubkey::default(),
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            0,
+            slot,
+        );
+        assert_eq!(result, Err(ErrorCode::UserIsBeingLiquidated));
+
+        // margin calls turned off clears the flag
+        let mut user = get_user(60);
+        user.enter_margin_call(slot);
+        update_user_margin_call(
+            &State::default(),
+            &mut user,
+            &Pubkey::default(),
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            0,
+            slot,
+        )
+        .unwrap();
+        assert!(!user.is_in_margin_call());
+    }
+
+    #[test]
+    fn band_capped_at_initial_margin() {
+        let slot = 5_u64;
+
+        let mut oracle_price = get_pyth_price(100, 6);
+        let oracle_price_key =
+            Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+        let pyth_program = crate::ids::pyth_program::id();
+        create_account_info!(
+            oracle_price,
+            &oracle_price_key,
+            &pyth_program,
+            oracle_account_info
+        );
+        let mut oracle_map = OracleMap::load_one(&oracle_account_info, slot, None).unwrap();
+
+        let mut market = get_market(oracle_price_key);
+        create_anchor_account_info!(market, PerpMarket, market_account_info);
+        let market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+
+        let mut spot_market = get_spot_market();
+        create_anchor_account_info!(spot_market, SpotMarket, spot_market_account_info);
+        let spot_market_map = SpotMarketMap::load_one(&spot_market_account_info, true).unwrap();
+
+        // maintenance plus a 10% buffer is $150, above the $100 initial margin
+        let state = State {
+            margin_call_buffer_ratio: 1000,
+            ..get_state()
+        };
+
+        // $120 is below maintenance plus buffer but meets initial
+        let mut user = get_user(120);
+        update_user_margin_call(
+            &state,
+            &mut user,
+            &Pubkey::default(),
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            0,
+            slot,
+        )
+        .unwrap();
+        assert!(!user.is_in_margin_call());
+
+        // $80 is below initial
+        let mut user = get_user(80);
+        update_user_margin_call(
+            &state,
+            &mut user,
+            &Pubkey::default(),
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            0,
+            slot,
+        )
+        .unwrap();
+        assert!(user.is_in_margin_call());
+    }
+
+    #[test]
+    fn entering_cancels_risk_increasing_orders() {
+        let slot = 5_u64;
+
+        let mut oracle_price = get_pyth_price(100, 6);
+        let oracle_price_key =
+            Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+        let pyth_program = crate::ids::pyth_program::id();
+        create_account_info!(
+            oracle_price,
+            &oracle_price_key,
+            &pyth_program,
+            oracle_account_info
+        );
+        let mut oracle_map = OracleMap::load_one(&oracle_account_info, slot, None).unwrap();
+
+        let mut market = get_market(oracle_price_key);
+        create_anchor_account_info!(market, PerpMarket, market_account_info);
+        let market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+
+        let mut spot_market = get_spot_market();
+        create_anchor_account_info!(spot_market, SpotMarket, spot_market_account_info);
+        let spot_market_map = SpotMarketMap::load_one(&spot_market_account_info, true).unwrap();
+
+        let state = get_state();
+
+        // a bid adds to the long, an ask reduces it
+        let mut user = get_user(70);
+        user.orders = get_orders!(
+            Order {
+                order_id: 1,
+                status: OrderStatus::Open,
+                order_type: OrderType::Limit,
+                market_type: MarketType::Perp,
+                direction: PositionDirection::Long,
+                base_asset_amount: BASE_PRECISION_U64,
+                price: 90 * PRICE_PRECISION_U64,
+                ..Order::default()
+            },
+            Order {
+                order_id: 2,
+                status: OrderStatus::Open,
+                order_type: OrderType::Limit,
+                market_type: MarketType::Perp,
+                direction: PositionDirection::Short,
+                base_asset_amount: BASE_PRECISION_U64,
+                price: 110 * PRICE_PRECISION_U64,
+                ..Order::default()
+            }
+        );
+        user.open_orders = 2;
+        user.perp_positions[0].open_orders = 2;
+        user.perp_positions[0].open_bids = BASE_PRECISION_I64;
+        user.perp_positions[0].open_asks = -BASE_PRECISION_I64;
+
+        update_user_margin_call(
+            &state,
+            &mut user,
+            &Pubkey::default(),
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            0,
+            slot,
+        )
+        .unwrap();
+        assert!(user.is_in_margin_call());
+
+        assert_eq!(user.orders[0].status, OrderStatus::Init);
+        assert_eq!(user.orders[1].status, OrderStatus::Open);
+        assert_eq!(user.open_orders, 1);
+        assert_eq!(user.perp_positions[0].open_orders, 1);
+        assert_eq!(user.perp_positions[0].open_bids, 0);
+        assert_eq!(user.perp_positions[0].open_asks, -BASE_PRECISION_I64);
+    }
+
+    #[test]
+    fn only_risk_reducing_orders() {
+        let clock = get_clock(5);
+
+        let mut oracle_price = get_pyth_price(100, 6);
+        let oracle_price_key =
+            Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+        let pyth_program = crate::ids::pyth_program::id();
+        create_account_info!(
+            oracle_price,
+            &oracle_price_key,
+            &pyth_program,
+            oracle_account_info
+        );
+        let mut oracle_map = OracleMap::load_one(&oracle_account_info, clock.slot, None).unwrap();
+
+        let mut market = get_market(oracle_price_key);
+        create_anchor_account_info!(market, PerpMarket, market_account_info);
+        let market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+
+        let mut spot_market = get_spot_market();
+        create_anchor_account_info!(spot_market, SpotMarket, spot_market_account_info);
+        let spot_market_map = SpotMarketMap::load_one(&spot_market_account_info, true).unwrap();
+
+        let state = get_state();
+
+        let mut user = get_user(60);
+        user.enter_margin_call(clock.slot);
+
+        let limit_order_params = |direction: PositionDirection, price: u64| OrderParams {
+            order_type: OrderType::Limit,
+            market_type: MarketType::Perp,
+            direction,
+            base_asset_amount: BASE_PRECISION_U64,
+            price,
+            market_index: 0,
+            ..OrderParams::default()
+        };
+
+        let result = place_perp_order(
+            &state,
+            &mut user,
+            Pubkey::default(),
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            &clock,
+            limit_order_params(PositionDirection::Long, 90 * PRICE_PRECISION_U64),
+            &mut PlaceOrderOptions::default(),
+        );
+        assert_eq!(result, Err(ErrorCode::UserInMarginCall));
+
+        place_perp_order(
+            &state,
+            &mut user,
+            Pubkey::default(),
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            &clock,
+            limit_order_params(PositionDirection::Short, 110 * PRICE_PRECISION_U64),
+            &mut PlaceOrderOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(user.orders[0].status, OrderStatus::Open);
+        assert_eq!(user.orders[0].direction, PositionDirection::Short);
+    }
+
+    #[test]
+    fn place_order_after_grace_period() {
+        let mut oracle_price = get_pyth_price(100, 6);
+        let oracle_price_key =
+            Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+        let pyth_program = crate::ids::pyth_program::id();
+        create_account_info!(
+            oracle_price,
+            &oracle_price_key,
+            &pyth_program,
+            oracle_account_info
+        );
+        let mut oracle_map = OracleMap::load_one(&oracle_account_info, 5, None).unwrap();
+
+        let mut market = get_market(oracle_price_key);
+        market.amm.last_bid_price_twap = 100 * PRICE_PRECISION_U64;
+        market.amm.last_ask_price_twap = 100 * PRICE_PRECISION_U64;
+        market.amm.last_mark_price_twap = 100 * PRICE_PRECISION_U64;
+        market.amm.last_mark_price_twap_5min = 100 * PRICE_PRECISION_U64;
+        create_anchor_account_info!(market, PerpMarket, market_account_info);
+        let market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+
+        let mut spot_market = get_spot_market();
+        create_anchor_account_info!(spot_market, SpotMarket, spot_market_account_info);
+        let spot_market_map = SpotMarketMap::load_one(&spot_market_account_info, true).unwrap();
+
+        let state = get_state();
+
+        let result = place_margin_call_order(
+            &state,
+            &mut get_user(60),
+            Pubkey::default(),
+            0,
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            &get_clock(5),
+        );
+        assert_eq!(result, Err(ErrorCode::UserNotInMarginCall));
+
+        let mut user = get_user(60);
+        user.enter_margin_call(5);
+
+        let result = place_margin_call_order(
+            &state,
+            &mut user,
+            Pubkey::default(),
+            0,
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            &get_clock(14),
+        );
+        assert_eq!(result, Err(ErrorCode::MarginCallGracePeriodNotOver));
+
+        place_margin_call_order(
+            &state,
+            &mut user,
+            Pubkey::default(),
+            0,
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            &get_clock(15),
+        )
+        .unwrap();
+
+        // $40 short of the buffer at a 10% margin ratio with buffer, closes 4 of the 10
+        let order = user.orders[0];
+        assert_eq!(order.status, OrderStatus::Open);
+        assert_eq!(order.order_type, OrderType::Oracle);
+        assert_eq!(order.direction, PositionDirection::Short);
+        assert_eq!(order.base_asset_amount, 4 * BASE_PRECISION_U64);
+        assert!(order.reduce_only);
+        assert!(order.has_auction());
+
+        // grace period restarts for the auction
+        assert!(user.is_in_margin_call());
+        assert_eq!(user.last_active_slot, 15);
+
+        let result = place_margin_call_order(
+            &state,
+            &mut user,
+            Pubkey::default(),
+            0,
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            &get_clock(20),
+        );
+        assert_eq!(result, Err(ErrorCode::MarginCallGracePeriodNotOver));
+
+        // a deposit brings the user back above the buffer, no order is placed
+        user.spot_positions[0].scaled_balance = 200 * SPOT_BALANCE_PRECISION_U64;
+        place_margin_call_order(
+            &state,
+            &mut user,
+            Pubkey::default(),
+            0,
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            &get_clock(25),
+        )
+        .unwrap();
+        assert!(!user.is_in_margin_call());
+        assert_eq!(user.orders[1].status, OrderStatus::Init);
+    }
+}
+
+pub mod trailing_stop {
+    use std::str::FromStr;
+
+    use anchor_lang::prelude::{AccountLoader, Clock};
+
+    use super::modify_orders::{get_market, get_spot_market};
+    use crate::controller::orders::{
+        fill_perp_order, trigger_order, update_trailing_stop_trigger_prices,
+    };
+    use crate::controller::position::PositionDirection;
+    use crate::create_account_info;
+    use crate::create_anchor_account_info;
+    use crate::error::ErrorCode;
+    use crate::math::constants::{
+        BASE_PRECISION_I64, BASE_PRECISION_U64, PRICE_PRECISION_I64, PRICE_PRECISION_U64,
+        QUOTE_PRECISION_I64, SPOT_BALANCE_PRECISION_U64,
+    };
+    use crate::state::fill_mode::FillMode;
+    use crate::state::perp_market::PerpMarket;
+    use crate::state::perp_market_map::PerpMarketMap;
+    use crate::state::spot_market::{SpotBalanceType, SpotMarket};
+    use crate::state::spot_market_map::SpotMarketMap;
+    use crate::state::state::State;
+    use crate::state::user::{
+        OrderStatus, OrderTriggerCondition, OrderType, SpotPosition, User, UserStats,
+    };
+    use crate::state::user_map::{UserMap, UserStatsMap};
+    use crate::test_utils::*;
+    use crate::test_utils::{
+        create_account_info, get_orders, get_positions, get_pyth_price, get_spot_positions,
+    };
+
+    use super::*;
+
+    // market with spread reserves set so the amm can quote alongside makers
+    pub fn get_fillable_market(oracle_price_key: Pubkey) -> PerpMarket {
+        let mut market = get_market(oracle_price_key);
+        let (new_ask_base_asset_reserve, new_ask_quote_asset_reserve) =
+            crate::math::amm_spread::calculate_spread_reserves(
+                &market.amm,
+                PositionDirection::Long,
+            )
+            .unwrap();
+        let (new_bid_base_asset_reserve, new_bid_quote_asset_reserve) =
+            crate::math::amm_spread::calculate_spread_reserves(
+                &market.amm,
+                PositionDirection::Short,
+            )
+            .unwrap();
+        market.amm.ask_base_asset_reserve = new_ask_base_asset_reserve;
+        market.amm.bid_base_asset_reserve = new_bid_base_asset_reserve;
+        market.amm.ask_quote_asset_reserve = new_ask_quote_asset_reserve;
+        market.amm.bid_quote_asset_reserve = new_bid_quote_asset_reserve;
+        market
+    }
+
+    // sell stop trailing $5 below the oracle
+    fn get_trailing_stop(order_id: u32, trigger_price: u64) -> Order {
+        Order {
+            market_index: 0,
+            order_id,
+            status: OrderStatus::Open,
+            order_type: OrderType::TrailingStop,
+            market_type: MarketType::Perp,
+            direction: PositionDirection::Short,
+            base_asset_amount: BASE_PRECISION_U64,
+            trigger_price,
+            trigger_condition: OrderTriggerCondition::Below,
+            oracle_price_offset: 5 * PRICE_PRECISION_I64 as i32,
+            ..Order::default()
+        }
+    }
+
+    #[test]
+    fn ratchet_only_trigger_pays_filler() {
+        let clock = Clock {
+            slot: 56,
+            epoch_start_timestamp: 0,
+            epoch: 0,
+            leader_schedule_epoch: 0,
+            unix_timestamp: 0,
+        };
+
+        let mut oracle_price = get_pyth_price(100, 6);
+        let oracle_price_key =
+            Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+        let pyth_program = crate::ids::pyth_program::id();
+        create_account_info!(
+            oracle_price,
+            &oracle_price_key,
+            &pyth_program,
+            oracle_account_info
+        );
+        let mut oracle_map = OracleMap::load_one(&oracle_account_info, clock.slot, None).unwrap();
+
+        let mut market = get_market(oracle_price_key);
+        create_anchor_account_info!(market, PerpMarket, market_account_info);
+        let market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+
+        let mut spot_market = get_spot_market();
+        create_anchor_account_info!(spot_market, SpotMarket, spot_market_account_info);
+        let spot_market_map = SpotMarketMap::load_one(&spot_market_account_info, true).unwrap();
+
+        let mut user = User {
+            orders: get_orders(get_trailing_stop(1, 90 * PRICE_PRECISION_U64)),
+            perp_positions: get_positions(PerpPosition {
+                market_index: 0,
+                base_asset_amount: BASE_PRECISION_I64,
+                quote_asset_amount: -100 * QUOTE_PRECISION_I64,
+                open_orders: 1,
+                ..PerpPosition::default()
+            }),
+            spot_positions: get_spot_positions(SpotPosition {
+                market_index: 0,
+                balance_type: SpotBalanceType::Deposit,
+                scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
+                ..SpotPosition::default()
+            }),
+            ..User::default()
+        };
+        create_anchor_account_info!(user, User, user_account_info);
+        let user_account_loader: AccountLoader<User> =
+            AccountLoader::try_from(&user_account_info).unwrap();
+
+        let filler_key = Pubkey::from_str("My11111111111111111111111111111111111111111").unwrap();
+        create_anchor_account_info!(User::default(), &filler_key, User, filler_account_info);
+        let filler_account_loader: AccountLoader<User> =
+            AccountLoader::try_from(&filler_account_info).unwrap();
+
+        let state = State::default();
+
+        trigger_order(
+            1,
+            &state,
+            &user_account_loader,
+            &spot_market_map,
+            &market_map,
+            &mut oracle_map,
+            &filler_account_loader,
+            &clock,
+        )
+        .unwrap();
+
+        let user = user_account_loader.load().unwrap();
+        assert_eq!(user.orders[0].trigger_price, 95 * PRICE_PRECISION_U64);
+        assert!(!user.orders[0].triggered());
+        assert_eq!(
+            user.perp_positions[0].quote_asset_amount,
+            -100 * QUOTE_PRECISION_I64 - 10_000
+        );
+
+        let filler = filler_account_loader.load().unwrap();
+        assert_eq!(filler.perp_positions[0].quote_asset_amount, 10_000);
+        drop(user);
+
+        // nothing left to ratchet, the oracle has not moved
+        let result = trigger_order(
+            1,
+            &state,
+            &user_account_loader,
+            &spot_market_map,
+            &market_map,
+            &mut oracle_map,
+            &filler_account_loader,
+            &clock,
+        );
+        assert_eq!(result, Err(ErrorCode::OrderDidNotSatisfyTriggerCondition));
+    }
+
+    #[test]
+    fn fill_ratchets_taker_and_maker_trailing_stops() {
+        let clock = Clock {
+            slot: 56,
+            epoch_start_timestamp: 0,
+            epoch: 0,
+            leader_schedule_epoch: 0,
+            unix_timestamp: 0,
+        };
+
+        let mut oracle_price = get_pyth_price(100, 6);
+        let oracle_price_key =
+            Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+        let pyth_program = crate::ids::pyth_program::id();
+        create_account_info!(
+            oracle_price,
+            &oracle_price_key,
+            &pyth_program,
+            oracle_account_info
+        );
+        let mut oracle_map = OracleMap::load_one(&oracle_account_info, clock.slot, None).unwrap();
+
+        let mut market = get_fillable_market(oracle_price_key);
+        create_anchor_account_info!(market, PerpMarket, market_account_info);
+        let market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+
+        let mut spot_market = get_spot_market();
+        create_anchor_account_info!(spot_market, SpotMarket, spot_market_account_info);
+        let spot_market_map = SpotMarketMap::load_one(&spot_market_account_info, true).unwrap();
+
+        let mut orders = [Order::default(); 32];
+        orders[0] = Order {
+            market_index: 0,
+            order_id: 1,
+            status: OrderStatus::Open,
+            order_type: OrderType::Market,
+            market_type: MarketType::Perp,
+            direction: PositionDirection::Long,
+            base_asset_amount: BASE_PRECISION_U64,
+            auction_start_price: 0,
+            auction_end_price: 100 * PRICE_PRECISION_I64,
+            auction_duration: 5,
+            price: 100 * PRICE_PRECISION_U64,
+            ..Order::default()
+        };
+        orders[1] = get_trailing_stop(2, 90 * PRICE_PRECISION_U64);
+        let mut user = User {
+            authority: Pubkey::from_str("My11111111111111111111111111111111111111111").unwrap(), // different authority than filler
+            orders,
+            perp_positions: get_positions(PerpPosition {
+                market_index: 0,
+                open_orders: 2,
+                open_bids: BASE_PRECISION_I64,
+                open_asks: -BASE_PRECISION_I64,
+                ..PerpPosition::default()
+            }),
+            spot_positions: get_spot_positions(SpotPosition {
+                market_index: 0,
+                balance_type: SpotBalanceType::Deposit,
+                scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
+                ..SpotPosition::default()
+            }),
+            ..User::default()
+        };
+        create_anchor_account_info!(user, User, user_account_info);
+        let user_account_loader: AccountLoader<User> =
+            AccountLoader::try_from(&user_account_info).unwrap();
+
+        create_anchor_account_info!(UserStats::default(), UserStats, user_stats_account_info);
+        let user_stats_account_loader: AccountLoader<UserStats> =
+            AccountLoader::try_from(&user_stats_account_info).unwrap();
+
+        let maker_key = Pubkey::from_str("My11111111111111111111111111111111111111113").unwrap();
+        let maker_authority =
+            Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+        let mut maker_orders = [Order::default(); 32];
+        maker_orders[0] = Order {
+            market_index: 0,
+            order_id: 1,
+            status: OrderStatus::Open,
+            order_type: OrderType::Limit,
+            market_type: MarketType::Perp,
+            direction: PositionDirection::Short,
+            base_asset_amount: BASE_PRECISION_U64,
+            price: 100 * PRICE_PRECISION_U64,
+            post_only: true,
+            ..Order::default()
+        };
+        // buy stop trailing $5 above the oracle
+        maker_orders[1] = Order {
+            direction: PositionDirection::Long,
+            trigger_condition: OrderTriggerCondition::Above,
+            ..get_trailing_stop(2, 110 * PRICE_PRECISION_U64)
+        };
+        let mut maker = User {
+            authority: maker_authority,
+            orders: maker_orders,
+            perp_positions: get_positions(PerpPosition {
+                market_index: 0,
+                open_orders: 2,
+                open_bids: BASE_PRECISION_I64,
+                open_asks: -BASE_PRECISION_I64,
+                ..PerpPosition::default()
+            }),
+            spot_positions: get_spot_positions(SpotPosition {
+                market_index: 0,
+                balance_type: SpotBalanceType::Deposit,
+                scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
+                ..SpotPosition::default()
+            }),
+            ..User::default()
+        };
+        create_anchor_account_info!(maker, &maker_key, User, maker_account_info);
+        let makers_and_referrers = UserMap::load_one(&maker_account_info).unwrap();
+
+        let mut maker_stats = UserStats {
+            authority: maker_authority,
+            ..UserStats::default()
+        };
+        create_anchor_account_info!(maker_stats, UserStats, maker_stats_account_info);
+        let maker_and_referrer_stats = UserStatsMap::load_one(&maker_stats_account_info).unwrap();
+
+        let filler_key = Pubkey::from_str("My11111111111111111111111111111111111111111").unwrap();
+        create_anchor_account_info!(User::default(), &filler_key, User, filler_account_info);
+        let filler_account_loader: AccountLoader<User> =
+            AccountLoader::try_from(&filler_account_info).unwrap();
+
+        create_anchor_account_info!(UserStats::default(), UserStats, filler_stats_account_info);
+        let filler_stats_account_loader: AccountLoader<UserStats> =
+            AccountLoader::try_from(&filler_stats_account_info).unwrap();
+
+        let state = State {
+            min_perp_auction_duration: 1,
+            default_market_order_time_in_force: 10,
+            ..State::default()
+        };
+
+        let base_asset_amount = fill_perp_order(
+            1,
+            &state,
+            &user_account_loader,
+            &user_stats_account_loader,
+            &spot_market_map,
+            &market_map,
+            &mut oracle_map,
+            &filler_account_loader,
+            &filler_stats_account_loader,
+            &makers_and_referrers,
+            &maker_and_referrer_stats,
+            None,
+            &clock,
+            FillMode::Fill,
+        )
+        .unwrap();
+
+        assert_eq!(base_asset_amount, BASE_PRECISION_U64);
+
+        let user = user_account_loader.load().unwrap();
+        assert_eq!(user.orders[1].trigger_price, 95 * PRICE_PRECISION_U64);
+
+        let maker = makers_and_referrers.get_ref(&maker_key).unwrap();
+        assert_eq!(maker.orders[1].trigger_price, 105 * PRICE_PRECISION_U64);
+    }
+
+    #[test]
+    fn only_ratchets_untriggered_stops_in_market() {
+        let mut orders = [Order::default(); 32];
+        orders[0] = get_trailing_stop(1, 90 * PRICE_PRECISION_U64);
+        orders[1] = Order {
+            market_index: 1,
+            ..get_trailing_stop(2, 90 * PRICE_PRECISION_U64)
+        };
+        orders[2] = Order {
+            market_type: MarketType::Spot,
+            ..get_trailing_stop(3, 90 * PRICE_PRECISION_U64)
+        };
+        orders[3] = Order {
+            trigger_condition: OrderTriggerCondition::TriggeredBelow,
+            ..get_trailing_stop(4, 90 * PRICE_PRECISION_U64)
+        };
+        let mut user = User {
+            orders,
+            ..User::default()
+        };
+
+        update_trailing_stop_trigger_prices(
+            &mut user,
+            MarketType::Perp,
+            0,
+            100 * PRICE_PRECISION_I64,
+            1,
+        )
+        .unwrap();
+
+        assert_eq!(user.orders[0].trigger_price, 95 * PRICE_PRECISION_U64);
+        assert_eq!(user.orders[1].trigger_price, 90 * PRICE_PRECISION_U64);
+        assert_eq!(user.orders[2].trigger_price, 90 * PRICE_PRECISION_U64);
+        assert_eq!(user.orders[3].trigger_price, 90 * PRICE_PRECISION_U64);
+
+        // never loosens the stop
+        update_trailing_stop_trigger_prices(
+            &mut user,
+            MarketType::Perp,
+            0,
+            90 * PRICE_PRECISION_I64,
+            1,
+        )
+        .unwrap();
+        assert_eq!(user.orders[0].trigger_price, 95 * PRICE_PRECISION_U64);
+    }
+}
+
+pub mod bracket_orders {
+    use std::str::FromStr;
+
+    use anchor_lang::prelude::{AccountLoader, Clock};
+
+    use super::modify_orders::{get_market, get_spot_market};
+    use crate::controller::orders::{cancel_order, expire_orders, fill_perp_order};
+    use crate::controller::position::PositionDirection;
+    use crate::create_account_info;
+    use crate::create_anchor_account_info;
+    use crate::error::ErrorCode;
+    use crate::math::constants::{
+        BASE_PRECISION_I64, BASE_PRECISION_U64, PRICE_PRECISION_U64, SPOT_BALANCE_PRECISION_U64,
+    };
+    use crate::state::events::OrderActionExplanation;
+    use crate::state::fill_mode::FillMode;
+    use crate::state::perp_market::PerpMarket;
+    use crate::state::perp_market_map::PerpMarketMap;
+    use crate::state::spot_market::{SpotBalanceType, SpotMarket};
+    use crate::state::spot_market_map::SpotMarketMap;
+    use crate::state::state::{ExchangeStatus, State};
+    use crate::state::user::{
+        OrderBitFlag, OrderStatus, OrderTriggerCondition, OrderType, SpotPosition, User, UserStats,
+    };
+    use crate::state::user_map::{UserMap, UserStatsMap};
+    use crate::test_utils::*;
+    use crate::test_utils::{
+        create_account_info, get_orders, get_positions, get_pyth_price, get_spot_positions,
+    };
+
+    use super::*;
+
+    fn get_clock() -> Clock {
+        Clock {
+            slot: 56,
+            epoch_start_timestamp: 0,
+            epoch: 0,
+            leader_schedule_epoch: 0,
+            unix_timestamp: 10,
+        }
+    }
+
+    // long entry at $100 with a $110 take profit limit leg and a $90 stop loss leg
+    fn get_bracket_user(parent_filled: u64) -> User {
+        let mut orders = [Order::default(); 32];
+        orders[0] = Order {
+            market_index: 0,
+            order_id: 1,
+            status: OrderStatus::Open,
+            order_type: OrderType::Limit,
+            market_type: MarketType::Perp,
+            direction: PositionDirection::Long,
+            base_asset_amount: 2 * BASE_PRECISION_U64,
+            base_asset_amount_filled: parent_filled,
+            price: 100 * PRICE_PRECISION_U64,
+            group_id: 1,
+            bit_flags: OrderBitFlag::BracketParent as u8,
+            ..Order::default()
+        };
+        orders[1] = Order {
+            market_index: 0,
+            order_id: 2,
+            status: OrderStatus::Open,
+            order_type: OrderType::Limit,
+            market_type: MarketType::Perp,
+            direction: PositionDirection::Short,
+            base_asset_amount: 2 * BASE_PRECISION_U64,
+            price: 110 * PRICE_PRECISION_U64,
+            reduce_only: true,
+            group_id: 1,
+            ..Order::default()
+        };
+        orders[2] = Order {
+            market_index: 0,
+            order_id: 3,
+            status: OrderStatus::Open,
+            order_type: OrderType::TriggerMarket,
+            market_type: MarketType::Perp,
+            direction: PositionDirection::Short,
+            base_asset_amount: 2 * BASE_PRECISION_U64,
+            trigger_price: 90 * PRICE_PRECISION_U64,
+            trigger_condition: OrderTriggerCondition::Below,
+            reduce_only: true,
+            group_id: 1,
+            ..Order::default()
+        };
+        // unlinked
+        orders[3] = Order {
+            market_index: 0,
+            order_id: 4,
+            status: OrderStatus::Open,
+            order_type: OrderType::Limit,
+            market_type: MarketType::Perp,
+            direction: PositionDirection::Long,
+            base_asset_amount: BASE_PRECISION_U64,
+            price: 95 * PRICE_PRECISION_U64,
+            ..Order::default()
+        };
+
+        let base_asset_amount = parent_filled as i64;
+        User {
+            orders,
+            next_order_id: 5,
+            perp_positions: get_positions(PerpPosition {
+                market_index: 0,
+                base_asset_amount,
+                quote_asset_amount: -100 * base_asset_amount / 1000,
+                open_orders: 4,
+                open_bids: 3 * BASE_PRECISION_I64 - base_asset_amount,
+                open_asks: -2 * BASE_PRECISION_I64,
+                ..PerpPosition::default()
+            }),
+            spot_positions: get_spot_positions(SpotPosition {
+                market_index: 0,
+                balance_type: SpotBalanceType::Deposit,
+                scaled_balance: 1000 * SPOT_BALANCE_PRECISION_U64,
+                ..SpotPosition::default()
+            }),
+            ..User::default()
+        }
+    }
+
+    #[test]
+    fn canceling_unfilled_parent_cancels_legs() {
+        let clock = get_clock();
+
+        let mut oracle_price = get_pyth_price(100, 6);
+        let oracle_price_key =
+            Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+        let pyth_program = crate::ids::pyth_program::id();
+        create_account_info!(
+            oracle_price,
+            &oracle_price_key,
+            &pyth_program,
+            oracle_account_info
+        );
+        let mut oracle_map = OracleMap::load_one(&oracle_account_info, clock.slot, None).unwrap();
+
+        let mut market = get_market(oracle_price_key);
+        create_anchor_account_info!(market, PerpMarket, market_account_info);
+        let market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+
+        let mut spot_market = get_spot_market();
+        create_anchor_account_info!(spot_market, SpotMarket, spot_market_account_info);
+        let spot_market_map = SpotMarketMap::load_one(&spot_market_account_info, true).unwrap();
+
+        let mut user = get_bracket_user(0);
+
+        cancel_order(
+            0,
+            &mut user,
+            &Pubkey::default(),
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            clock.unix_timestamp,
+            clock.slot,
+            OrderActionExplanation::None,
+            None,
+            0,
+            false,
+        )
+        .unwrap();
+
+        assert_eq!(user.orders[0], Order::default());
+        assert_eq!(user.orders[1], Order::default());
+        assert_eq!(user.orders[2], Order::default());
+        assert_eq!(user.orders[3].status, OrderStatus::Open);
+        assert_eq!(user.perp_positions[0].open_orders, 1);
+        assert_eq!(user.perp_positions[0].open_bids, BASE_PRECISION_I64);
+        // the untriggered stop loss never added to open asks
+        assert_eq!(user.perp_positions[0].open_asks, 0);
+
+        // legs protect the filled part of a partially filled parent
+        let mut user = get_bracket_user(BASE_PRECISION_U64);
+
+        cancel_order(
+            0,
+            &mut user,
+            &Pubkey::default(),
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            clock.unix_timestamp,
+            clock.slot,
+            OrderActionExplanation::None,
+            None,
+            0,
+            false,
+        )
+        .unwrap();
+
+        assert_eq!(user.orders[0], Order::default());
+        assert_eq!(user.orders[1].status, OrderStatus::Open);
+        assert_eq!(user.orders[2].status, OrderStatus::Open);
+        assert_eq!(user.perp_positions[0].open_orders, 3);
+    }
+
+    #[test]
+    fn expiring_unfilled_parent_cancels_legs() {
+        let clock = get_clock();
+
+        let mut oracle_price = get_pyth_price(100, 6);
+        let oracle_price_key =
+            Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+        let pyth_program = crate::ids::pyth_program::id();
+        create_account_info!(
+            oracle_price,
+            &oracle_price_key,
+            &pyth_program,
+            oracle_account_info
+        );
+        let mut oracle_map = OracleMap::load_one(&oracle_account_info, clock.slot, None).unwrap();
+
+        let mut market = get_market(oracle_price_key);
+        create_anchor_account_info!(market, PerpMarket, market_account_info);
+        let market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+
+        let mut spot_market = get_spot_market();
+        create_anchor_account_info!(spot_market, SpotMarket, spot_market_account_info);
+        let spot_market_map = SpotMarketMap::load_one(&spot_market_account_info, true).unwrap();
+
+        let mut user = get_bracket_user(0);
+        user.orders[0].max_ts = clock.unix_timestamp - 1;
+
+        expire_orders(
+            &mut user,
+            &Pubkey::default(),
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            clock.unix_timestamp,
+            clock.slot,
+        )
+        .unwrap();
+
+        assert_eq!(user.orders[0], Order::default());
+        assert_eq!(user.orders[1], Order::default());
+        assert_eq!(user.orders[2], Order::default());
+        assert_eq!(user.orders[3].status, OrderStatus::Open);
+        assert_eq!(user.perp_positions[0].open_orders, 1);
+    }
+
+    #[test]
+    fn leg_can_not_fill_before_parent() {
+        let clock = get_clock();
+
+        let mut oracle_price = get_pyth_price(100, 6);
+        let oracle_price_key =
+            Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+        let pyth_program = crate::ids::pyth_program::id();
+        create_account_info!(
+            oracle_price,
+            &oracle_price_key,
+            &pyth_program,
+            oracle_account_info
+        );
+        let mut oracle_map = OracleMap::load_one(&oracle_account_info, clock.slot, None).unwrap();
+
+        let mut market = get_market(oracle_price_key);
+        create_anchor_account_info!(market, PerpMarket, market_account_info);
+        let market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+
+        let mut spot_market = get_spot_market();
+        create_anchor_account_info!(spot_market, SpotMarket, spot_market_account_info);
+        let spot_market_map = SpotMarketMap::load_one(&spot_market_account_info, true).unwrap();
+
+        let mut user = get_bracket_user(0);
+        create_anchor_account_info!(user, User, user_account_info);
+        let user_account_loader: AccountLoader<User> =
+            AccountLoader::try_from(&user_account_info).unwrap();
+
+        create_anchor_account_info!(UserStats::default(), UserStats, user_stats_account_info);
+        let user_stats_account_loader: AccountLoader<UserStats> =
+            AccountLoader::try_from(&user_stats_account_info).unwrap();
+
+        let filler_key = Pubkey::from_str("My11111111111111111111111111111111111111111").unwrap();
+        create_anchor_account_info!(User::default(), &filler_key, User, filler_account_info);
+        let filler_account_loader: AccountLoader<User> =
+            AccountLoader::try_from(&filler_account_info).unwrap();
+
+        create_anchor_account_info!(UserStats::default(), UserStats, filler_stats_account_info);
+        let filler_stats_account_loader: AccountLoader<UserStats> =
+            AccountLoader::try_from(&filler_stats_account_info).unwrap();
+
+        let state = State::default();
+
+        let result = fill_perp_order(
+            2,
+            &state,
+            &user_account_loader,
+            &user_stats_account_loader,
+            &spot_market_map,
+            &market_map,
+            &mut oracle_map,
+            &filler_account_loader,
+            &filler_stats_account_loader,
+            &UserMap::empty(),
+            &UserStatsMap::empty(),
+            None,
+            &clock,
+            FillMode::Fill,
+        );
+
+        assert_eq!(result, Err(ErrorCode::BracketParentOrderNotFilled));
+
+        let user = user_account_loader.load().unwrap();
+        assert_eq!(user.orders[0].status, OrderStatus::Open);
+        assert_eq!(user.orders[1].status, OrderStatus::Open);
+    }
+
+    #[test]
+    fn maker_leg_skipped_until_parent_fills() {
+        for parent_filled in [0, BASE_PRECISION_U64] {
+            let clock = get_clock();
+
+            let mut oracle_price = get_pyth_price(100, 6);
+            let oracle_price_key =
+                Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+            let pyth_program = crate::ids::pyth_program::id();
+            create_account_info!(
+                oracle_price,
+                &oracle_price_key,
+                &pyth_program,
+                oracle_account_info
+            );
+            let mut oracle_map =
+                OracleMap::load_one(&oracle_account_info, clock.slot, None).unwrap();
+
+            let mut market = get_market(oracle_price_key);
+            create_anchor_account_info!(market, PerpMarket, market_account_info);
+            let market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+
+            let mut spot_market = get_spot_market();
+            create_anchor_account_info!(spot_market, SpotMarket, spot_market_account_info);
+            let spot_market_map = SpotMarketMap::load_one(&spot_market_account_info, true).unwrap();
+
+            let mut user = User {
+                authority: Pubkey::from_str("My11111111111111111111111111111111111111112").unwrap(),
+                orders: get_orders(Order {
+                    market_index: 0,
+                    order_id: 1,
+                    status: OrderStatus::Open,
+                    order_type: OrderType::Limit,
+                    market_type: MarketType::Perp,
+                    direction: PositionDirection::Long,
+                    base_asset_amount: BASE_PRECISION_U64,
+                    price: 105 * PRICE_PRECISION_U64,
+                    slot: clock.slot,
+                    ..Order::default()
+                }),
+                perp_positions: get_positions(PerpPosition {
+                    market_index: 0,
+                    open_orders: 1,
+                    open_bids: BASE_PRECISION_I64,
+                    ..PerpPosition::default()
+                }),
+                spot_positions: get_spot_positions(SpotPosition {
+                    market_index: 0,
+                    balance_type: SpotBalanceType::Deposit,
+                    scaled_balance: 1000 * SPOT_BALANCE_PRECISION_U64,
+                    ..SpotPosition::default()
+                }),
+                ..User::default()
+            };
+            create_anchor_account_info!(user, User, user_account_info);
+            let user_account_loader: AccountLoader<User> =
+                AccountLoader::try_from(&user_account_info).unwrap();
+
+            create_anchor_account_info!(UserStats::default(), UserStats, user_stats_account_info);
+            let user_stats_account_loader: AccountLoader<UserStats> =
+                AccountLoader::try_from(&user_stats_account_info).unwrap();
+
+            let maker_key =
+                Pubkey::from_str("My11111111111111111111111111111111111111113").unwrap();
+            let maker_authority =
+                Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+            let mut maker = get_bracket_user(parent_filled);
+            maker.authority = maker_authority;
+            maker.orders[1].post_only = true;
+            maker.orders[1].price = 105 * PRICE_PRECISION_U64;
+            maker.orders[1].base_asset_amount = BASE_PRECISION_U64;
+            maker.perp_positions[0].open_asks = -BASE_PRECISION_I64;
+            create_anchor_account_info!(maker, &maker_key, User, maker_account_info);
+            let makers_and_referrers = UserMap::load_one(&maker_account_info).unwrap();
+
+            let mut maker_stats = UserStats {
+                authority: maker_authority,
+                ..UserStats::default()
+            };
+            create_anchor_account_info!(maker_stats, UserStats, maker_stats_account_info);
+            let maker_and_referrer_stats =
+                UserStatsMap::load_one(&maker_stats_account_info).unwrap();
+
+            let filler_key =
+                Pubkey::from_str("My11111111111111111111111111111111111111111").unwrap();
+            create_anchor_account_info!(User::default(), &filler_key, User, filler_account_info);
+            let filler_account_loader: AccountLoader<User> =
+                AccountLoader::try_from(&filler_account_info).unwrap();
+
+            create_anchor_account_info!(UserStats::default(), UserStats, filler_stats_account_info);
+            let filler_stats_account_loader: AccountLoader<UserStats> =
+                AccountLoader::try_from(&filler_stats_account_info).unwrap();
+
+            // only the maker can fill the taker
+            let state = State {
+                exchange_status: ExchangeStatus::AmmPaused as u8,
+                ..State::default()
+            };
+
+            let base_asset_amount = fill_perp_order(
+                1,
+                &state,
+                &user_account_loader,
+                &user_stats_account_loader,
+                &spot_market_map,
+                &market_map,
+                &mut oracle_map,
+                &filler_account_loader,
+                &filler_stats_account_loader,
+                &makers_and_referrers,
+                &maker_and_referrer_stats,
+                None,
+                &clock,
+                FillMode::Fill,
+            )
+            .unwrap();
+
+            let maker = makers_and_referrers.get_ref(&maker_key).unwrap();
+            if parent_filled == 0 {
+                assert_eq!(base_asset_amount, 0);
+                assert_eq!(maker.orders[1].status, OrderStatus::Open);
+                assert_eq!(maker.orders[1].base_asset_amount_filled, 0);
+            } else {
+                assert_eq!(base_asset_amount, BASE_PRECISION_U64);
+                assert_eq!(maker.perp_positions[0].base_asset_amount, 0);
+                assert_eq!(maker.orders[1], Order::default());
+            }
+        }
+    }
+}
+
+pub mod twap_orders {
+    use std::str::FromStr;
+
+    use anchor_lang::prelude::{AccountLoader, Clock};
+
+    use super::modify_orders::{get_market, get_spot_market};
+    use crate::controller::orders::fill_perp_order;
+    use crate::controller::position::PositionDirection;
+    use crate::create_account_info;
+    use crate::create_anchor_account_info;
+    use crate::math::constants::{
+        BASE_PRECISION_I64, BASE_PRECISION_U64, PRICE_PRECISION_U64, SPOT_BALANCE_PRECISION_U64,
+    };
+    use crate::state::fill_mode::FillMode;
+    use crate::state::perp_market::PerpMarket;
+    use crate::state::perp_market_map::PerpMarketMap;
+    use crate::state::spot_market::{SpotBalanceType, SpotMarket};
+    use crate::state::spot_market_map::SpotMarketMap;
+    use crate::state::state::{ExchangeStatus, State};
+    use crate::state::user::{OrderStatus, OrderType, SpotPosition, User, UserStats};
+    use crate::state::user_map::{UserMap, UserStatsMap};
+    use crate::test_utils::*;
+    use crate::test_utils::{
+        create_account_info, get_orders, get_positions, get_pyth_price, get_spot_positions,
+    };
+
+    use super::*;
+
+    fn get_clock(unix_timestamp: i64) -> Clock {
+        Clock {
+            slot: 56,
+            epoch_start_timestamp: 0,
+            epoch: 0,
+            leader_schedule_epoch: 0,
+            unix_timestamp,
+        }
+    }
+
+    // 4 slice twap long started at ts 0 and ending at ts 100 with 1% max slippage
+    fn get_twap_user() -> User {
+        User {
+            authority: Pubkey::from_str("My11111111111111111111111111111111111111112").unwrap(),
+            orders: get_orders(Order {
+                market_index: 0,
+                order_id: 1,
+                status: OrderStatus::Open,
+                order_type: OrderType::Twap,
+                market_type: MarketType::Perp,
+                direction: PositionDirection::Long,
+                base_asset_amount: 4 * BASE_PRECISION_U64,
+                auction_start_price: 0,
+                max_ts: 100,
+                oracle_price_offset: 10000,
+                twap_slices: 4,
+                slot: 56,
+                ..Order::default()
+            }),
+            perp_positions: get_positions(PerpPosition {
+                market_index: 0,
+                open_orders: 1,
+                open_bids: 4 * BASE_PRECISION_I64,
+                ..PerpPosition::default()
+            }),
+            spot_positions: get_spot_positions(SpotPosition {
+                market_index: 0,
+                balance_type: SpotBalanceType::Deposit,
+                scaled_balance: 1000 * SPOT_BALANCE_PRECISION_U64,
+                ..SpotPosition::default()
+            }),
+            ..User::default()
+        }
+    }
+
+    fn get_maker(price: u64) -> User {
+        User {
+            authority: Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap(),
+            orders: get_orders(Order {
+                market_index: 0,
+                order_id: 1,
+                status: OrderStatus::Open,
+                order_type: OrderType::Limit,
+                market_type: MarketType::Perp,
+                direction: PositionDirection::Short,
+                base_asset_amount: 4 * BASE_PRECISION_U64,
+                price,
+                post_only: true,
+                ..Order::default()
+            }),
+            perp_positions: get_positions(PerpPosition {
+                market_index: 0,
+                open_orders: 1,
+                open_asks: -4 * BASE_PRECISION_I64,
+                ..PerpPosition::default()
+            }),
+            spot_positions: get_spot_positions(SpotPosition {
+                market_index: 0,
+                balance_type: SpotBalanceType::Deposit,
+                scaled_balance: 1000 * SPOT_BALANCE_PRECISION_U64,
+                ..SpotPosition::default()
+            }),
+            ..User::default()
+        }
+    }
+
+    #[test]
+    fn fills_released_slices() {
+        let clock = get_clock(10);
+
+        let mut oracle_price = get_pyth_price(100, 6);
+        let oracle_price_key =
+            Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+        let pyth_program = crate::ids::pyth_program::id();
+        create_account_info!(
+            oracle_price,
+            &oracle_price_key,
+            &pyth_program,
+            oracle_account_info
+        );
+        let mut oracle_map = OracleMap::load_one(&oracle_account_info, clock.slot, None).unwrap();
+
+        let mut market = get_market(oracle_price_key);
+        create_anchor_account_info!(market, PerpMarket, market_account_info);
+        let market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+
+        let mut spot_market = get_spot_market();
+        create_anchor_account_info!(spot_market, SpotMarket, spot_market_account_info);
+        let spot_market_map = SpotMarketMap::load_one(&spot_market_account_info, true).unwrap();
+
+        let mut user = get_twap_user();
+        create_anchor_account_info!(user, User, user_account_info);
+        let user_account_loader: AccountLoader<User> =
+            AccountLoader::try_from(&user_account_info).unwrap();
+
+        create_anchor_account_info!(UserStats::default(), UserStats, user_stats_account_info);
+        let user_stats_account_loader: AccountLoader<UserStats> =
+            AccountLoader::try_from(&user_stats_account_info).unwrap();
+
+        let maker_key = Pubkey::from_str("My11111111111111111111111111111111111111113").unwrap();
+        let mut maker = get_maker(100 * PRICE_PRECISION_U64);
+        create_anchor_account_info!(maker, &maker_key, User, maker_account_info);
+        let makers_and_referrers = UserMap::load_one(&maker_account_info).unwrap();
+
+        let mut maker_stats = UserStats {
+            authority: maker.authority,
+            ..UserStats::default()
+        };
+        create_anchor_account_info!(maker_stats, UserStats, maker_stats_account_info);
+        let maker_and_referrer_stats = UserStatsMap::load_one(&maker_stats_account_info).unwrap();
+
+        let filler_key = Pubkey::from_str("My11111111111111111111111111111111111111111").unwrap();
+        create_anchor_account_info!(User::default(), &filler_key, User, filler_account_info);
+        let filler_account_loader: AccountLoader<User> =
+            AccountLoader::try_from(&filler_account_info).unwrap();
+
+        create_anchor_account_info!(UserStats::default(), UserStats, filler_stats_account_info);
+        let filler_stats_account_loader: AccountLoader<UserStats> =
+            AccountLoader::try_from(&filler_stats_account_info).unwrap();
+
+        // only the maker can fill the twap
+        let state = State {
+            exchange_status: ExchangeStatus::AmmPaused as u8,
+            ..State::default()
+        };
+
+        // (ts, base filled by the fill, total base filled)
+        let expected = [
+            (10, BASE_PRECISION_U64, BASE_PRECISION_U64),
+            (20, 0, BASE_PRECISION_U64),
+            (50, 2 * BASE_PRECISION_U64, 3 * BASE_PRECISION_U64),
+            (100, BASE_PRECISION_U64, 4 * BASE_PRECISION_U64),
+        ];
+
+        for (now, expected_base_asset_amount, expected_total) in expected {
+            let base_asset_amount = fill_perp_order(
+                1,
+                &state,
+                &user_account_loader,
+                &user_stats_account_loader,
+                &spot_market_map,
+                &market_map,
+                &mut oracle_map,
+                &filler_account_loader,
+                &filler_stats_account_loader,
+                &makers_and_referrers,
+                &maker_and_referrer_stats,
+                None,
+                &get_clock(now),
+                FillMode::Fill,
+            )
+            .unwrap();
+
+            assert_eq!(base_asset_amount, expected_base_asset_amount);
+
+            let user = user_account_loader.load().unwrap();
+            assert_eq!(
+                user.perp_positions[0].base_asset_amount,
+                expected_total as i64
+            );
+        }
+
+        let user = user_account_loader.load().unwrap();
+        assert_eq!(user.orders[0], Order::default());
+    }
+
+    #[test]
+    fn slice_bounded_by_max_slippage() {
+        // 1% max slippage from the $100 oracle bounds the slice at $101
+        for (maker_price, expected_base_asset_amount) in [
+            (101 * PRICE_PRECISION_U64, BASE_PRECISION_U64),
+            (102 * PRICE_PRECISION_U64, 0),
+        ] {
+            let clock = get_clock(10);
+
+            let mut oracle_price = get_pyth_price(100, 6);
+            let oracle_price_key =
+                Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+            let pyth_program = crate::ids::pyth_program::id();
+            create_account_info!(
+                oracle_price,
+                &oracle_price_key,
+                &pyth_program,
+                oracle_account_info
+            );
+            let mut oracle_map =
+                OracleMap::load_one(&oracle_account_info, clock.slot, None).unwrap();
+
+            let mut market = get_market(oracle_price_key);
+            create_anchor_account_info!(market, PerpMarket, market_account_info);
+            let market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+
+            let mut spot_market = get_spot_market();
+            create_anchor_account_info!(spot_market, SpotMarket, spot_market_account_info);
+            let spot_market_map = SpotMarketMap::load_one(&spot_market_account_info, true).unwrap();
+
+            let mut user = get_twap_user();
+            create_anchor_account_info!(user, User, user_account_info);
+            let user_account_loader: AccountLoader<User> =
+                AccountLoader::try_from(&user_account_info).unwrap();
+
+            create_anchor_account_info!(UserStats::default(), UserStats, user_stats_account_info);
+            let user_stats_account_loader: AccountLoader<UserStats> =
+                AccountLoader::try_from(&user_stats_account_info).unwrap();
+
+            let maker_key =
+                Pubkey::from_str("My11111111111111111111111111111111111111113").unwrap();
+            let mut maker = get_maker(maker_price);
+            create_anchor_account_info!(maker, &maker_key, User, maker_account_info);
+            let makers_and_referrers = UserMap::load_one(&maker_account_info).unwrap();
+
+            let mut maker_stats = UserStats {
+                authority: maker.authority,
+                ..UserStats::default()
+            };
+            create_anchor_account_info!(maker_stats, UserStats, maker_stats_account_info);
+            let maker_and_referrer_stats =
+                UserStatsMap::load_one(&maker_stats_account_info).unwrap();
+
+            let filler_key =
+                Pubkey::from_str("My11111111111111111111111111111111111111111").unwrap();
+            create_anchor_account_info!(User::default(), &filler_key, User, filler_account_info);
+            let filler_account_loader: AccountLoader<User> =
+                AccountLoader::try_from(&filler_account_info).unwrap();
+
+            create_anchor_account_info!(UserStats::default(), UserStats, filler_stats_account_info);
+            let filler_stats_account_loader: AccountLoader<UserStats> =
+                AccountLoader::try_from(&filler_stats_account_info).unwrap();
+
+            let state = State {
+                exchange_status: ExchangeStatus::AmmPaused as u8,
+                ..State::default()
+            };
+
+            let base_asset_amount = fill_perp_order(
+                1,
+                &state,
+                &user_account_loader,
+                &user_stats_account_loader,
+                &spot_market_map,
+                &market_map,
+                &mut oracle_map,
+                &filler_account_loader,
+                &filler_stats_account_loader,
+                &makers_and_referrers,
+                &maker_and_referrer_stats,
+                None,
+                &clock,
+                FillMode::Fill,
+            )
+            .unwrap();
+
+            assert_eq!(base_asset_amount, expected_base_asset_amount);
+
+            let maker = makers_and_referrers.get_ref(&maker_key).unwrap();
+            assert_eq!(
+                maker.orders[0].base_asset_amount_filled,
+                expected_base_asset_amount
+            );
+        }
+    }
+}
+
+pub mod place_and_take_perp_order {
+    use std::str::FromStr;
+
+    use anchor_lang::prelude::{AccountLoader, Clock};
+
+    use super::modify_orders::{get_market, get_spot_market};
+    use crate::controller::orders::place_and_take_perp_order;
+    use crate::controller::position::PositionDirection;
+    use crate::create_account_info;
+    use crate::create_anchor_account_info;
+    use crate::error::ErrorCode;
+    use crate::math::constants::{
+        BASE_PRECISION_U64, PRICE_PRECISION_U64, SPOT_BALANCE_PRECISION_U64,
+    };
+    use crate::state::order_params::OrderParams;
+    use crate::state::perp_market::PerpMarket;
+    use crate::state::perp_market_map::PerpMarketMap;
+    use crate::state::spot_market::{SpotBalanceType, SpotMarket};
+    use crate::state::spot_market_map::SpotMarketMap;
+    use crate::state::state::{ExchangeStatus, State};
+    use crate::state::user::{OrderBitFlag, OrderStatus, OrderType, SpotPosition, User, UserStats};
+    use crate::state::user_map::{UserMap, UserStatsMap};
+    use crate::test_utils::*;
+    use crate::test_utils::{
+        create_account_info, get_orders, get_positions, get_pyth_price, get_spot_positions,
+    };
+
+    use super::*;
+
+    fn get_clock() -> Clock {
+        Clock {
+            slot: 56,
+            epoch_start_timestamp: 0,
+            epoch: 0,
+            leader_schedule_epoch: 0,
+            unix_timestamp: 10,
+        }
+    }
+
+    fn get_taker() -> User {
+        User {
+            authority: Pubkey::from_str("My11111111111111111111111111111111111111112").unwrap(),
+            next_order_id: 1,
+            spot_positions: get_spot_positions(SpotPosition {
+                market_index: 0,
+                balance_type: SpotBalanceType::Deposit,
+                scaled_balance: 1000 * SPOT_BALANCE_PRECISION_U64,
+                ..SpotPosition::default()
+            }),
+            ..User::default()
+        }
+    }
+
+    // resting post only ask at $100
+    pub fn get_maker(authority: Pubkey, base_asset_amount: u64) -> User {
+        User {
+            authority,
+            orders: get_orders(Order {
+                market_index: 0,
+                order_id: 1,
+                status: OrderStatus::Open,
+                order_type: OrderType::Limit,
+                market_type: MarketType::Perp,
+                direction: PositionDirection::Short,
+                base_asset_amount,
+                price: 100 * PRICE_PRECISION_U64,
+                post_only: true,
+                ..Order::default()
+            }),
+            perp_positions: get_positions(PerpPosition {
+                market_index: 0,
+                open_orders: 1,
+                open_asks: -(base_asset_amount as i64),
+                ..PerpPosition::default()
+            }),
+            spot_positions: get_spot_positions(SpotPosition {
+                market_index: 0,
+                balance_type: SpotBalanceType::Deposit,
+                scaled_balance: 1000 * SPOT_BALANCE_PRECISION_U64,
+                ..SpotPosition::default()
+            }),
+            next_order_id: 2,
+            ..User::default()
+        }
+    }
+
+    // ioc long limit for 2 at $100
+    pub fn get_params() -> OrderParams {
+        OrderParams {
+            order_type: OrderType::Limit,
+            market_type: MarketType::Perp,
+            direction: PositionDirection::Long,
+            base_asset_amount: 2 * BASE_PRECISION_U64,
+            price: 100 * PRICE_PRECISION_U64,
+            market_index: 0,
+            immediate_or_cancel: true,
+            ..OrderParams::default()
+        }
+    }
+
+    #[test]
+    fn min_fill() {
+        let maker_authority =
+            Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+
+        // (params, maker size, expected result)
+        let cases = [
+            // fill or kill reverts on a partial fill
+            (
+                OrderParams {
+                    fill_or_kill: true,
+                    ..get_params()
+                },
+                BASE_PRECISION_U64,
+                Err(ErrorCode::OrderFillBelowMinimum),
+            ),
+            (
+                OrderParams {
+                    fill_or_kill: true,
+                    ..get_params()
+                },
+                2 * BASE_PRECISION_U64,
+                Ok(2 * BASE_PRECISION_U64),
+            ),
+            // min fill reverts below the min, ioc cancels the rest above it
+            (
+                OrderParams {
+                    min_fill_base_asset_amount: Some(3 * BASE_PRECISION_U64 / 2),
+                    ..get_params()
+                },
+                BASE_PRECISION_U64,
+                Err(ErrorCode::OrderFillBelowMinimum),
+            ),
+            (
+                OrderParams {
+                    min_fill_base_asset_amount: Some(BASE_PRECISION_U64),
+                    ..get_params()
+                },
+                BASE_PRECISION_U64,
+                Ok(BASE_PRECISION_U64),
+            ),
+        ];
+
+        for (params, maker_base_asset_amount, expected) in cases {
+            let clock = get_clock();
+
+            let mut oracle_price = get_pyth_price(100, 6);
+            let oracle_price_key =
+                Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+            let pyth_program = crate::ids::pyth_program::id();
+            create_account_info!(
+                oracle_price,
+                &oracle_price_key,
+                &pyth_program,
+                oracle_account_info
+            );
+            let mut oracle_map =
+                OracleMap::load_one(&oracle_account_info, clock.slot, None).unwrap();
+
+            let mut market = get_market(oracle_price_key);
+            create_anchor_account_info!(market, PerpMarket, market_account_info);
+            let market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+
+            let mut spot_market = get_spot_market();
+            create_anchor_account_info!(spot_market, SpotMarket, spot_market_account_info);
+            let spot_market_map = SpotMarketMap::load_one(&spot_market_account_info, true).unwrap();
+
+            let mut user = get_taker();
+            create_anchor_account_info!(user, User, user_account_info);
+            let user_account_loader: AccountLoader<User> =
+                AccountLoader::try_from(&user_account_info).unwrap();
+
+            create_anchor_account_info!(UserStats::default(), UserStats, user_stats_account_info);
+            let user_stats_account_loader: AccountLoader<UserStats> =
+                AccountLoader::try_from(&user_stats_account_info).unwrap();
+
+            let maker_key =
+                Pubkey::from_str("My11111111111111111111111111111111111111113").unwrap();
+            let mut maker = get_maker(maker_authority, maker_base_asset_amount);
+            create_anchor_account_info!(maker, &maker_key, User, maker_account_info);
+            let makers_and_referrers = UserMap::load_one(&maker_account_info).unwrap();
+
+            let mut maker_stats = UserStats {
+                authority: maker_authority,
+                ..UserStats::default()
+            };
+            create_anchor_account_info!(maker_stats, UserStats, maker_stats_account_info);
+            let maker_and_referrer_stats =
+                UserStatsMap::load_one(&maker_stats_account_info).unwrap();
+
+            // only the maker can fill the taker
+            let state = State {
+                exchange_status: ExchangeStatus::AmmPaused as u8,
+                ..State::default()
+            };
+
+            let result = place_and_take_perp_order(
+                &state,
+                &user_account_loader,
+                &user_stats_account_loader,
+                &market_map,
+                &spot_market_map,
+                &mut oracle_map,
+                &makers_and_referrers,
+                &maker_and_referrer_stats,
+                &clock,
+                params,
+            );
+
+            assert_eq!(result, expected);
+
+            if expected.is_ok() {
+                // nothing is left resting
+                let user = user_account_loader.load().unwrap();
+                assert_eq!(user.orders[0], Order::default());
+                assert_eq!(user.perp_positions[0].open_orders, 0);
+                assert_eq!(user.perp_positions[0].open_bids, 0);
+                assert_eq!(
+                    user.perp_positions[0].base_asset_amount,
+                    expected.unwrap() as i64
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn self_trade_prevention() {
+        // taker and maker are sub accounts of the same authority
+        let authority = Pubkey::from_str("My11111111111111111111111111111111111111112").unwrap();
+
+        // (stp mode, maker size, expected fill, maker base left open)
+        let cases = [
+            (0, BASE_PRECISION_U64, BASE_PRECISION_U64, None),
+            (
+                OrderBitFlag::StpExpireMaker as u8,
+                BASE_PRECISION_U64,
+                0,
+                None,
+            ),
+            (
+                OrderBitFlag::StpExpireTaker as u8,
+                BASE_PRECISION_U64,
+                0,
+                Some(BASE_PRECISION_U64),
+            ),
+            // both orders are decremented by the taker's 2, the taker is canceled
+            (
+                OrderBitFlag::StpDecrementBoth as u8,
+                3 * BASE_PRECISION_U64,
+                0,
+                Some(BASE_PRECISION_U64),
+            ),
+        ];
+
+        for (bit_flags, maker_base_asset_amount, expected_fill, expected_maker_open) in cases {
+            let clock = get_clock();
+
+            let mut oracle_price = get_pyth_price(100, 6);
+            let oracle_price_key =
+                Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+            let pyth_program = crate::ids::pyth_program::id();
+            create_account_info!(
+                oracle_price,
+                &oracle_price_key,
+                &pyth_program,
+                oracle_account_info
+            );
+            let mut oracle_map =
+                OracleMap::load_one(&oracle_account_info, clock.slot, None).unwrap();
+
+            let mut market = get_market(oracle_price_key);
+            create_anchor_account_info!(market, PerpMarket, market_account_info);
+            let market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+
+            let mut spot_market = get_spot_market();
+            create_anchor_account_info!(spot_market, SpotMarket, spot_market_account_info);
+            let spot_market_map = SpotMarketMap::load_one(&spot_market_account_info, true).unwrap();
+
+            let mut user = User {
+                authority,
+                ..get_taker()
+            };
+            create_anchor_account_info!(user, User, user_account_info);
+            let user_account_loader: AccountLoader<User> =
+                AccountLoader::try_from(&user_account_info).unwrap();
+
+            create_anchor_account_info!(UserStats::default(), UserStats, user_stats_account_info);
+            let user_stats_account_loader: AccountLoader<UserStats> =
+                AccountLoader::try_from(&user_stats_account_info).unwrap();
+
+            let maker_key =
+                Pubkey::from_str("My11111111111111111111111111111111111111113").unwrap();
+            let mut maker = get_maker(authority, maker_base_asset_amount);
+            create_anchor_account_info!(maker, &maker_key, User, maker_account_info);
+            let makers_and_referrers = UserMap::load_one(&maker_account_info).unwrap();
+
+            let mut maker_stats = UserStats {
+                authority,
+                ..UserStats::default()
+            };
+            create_anchor_account_info!(maker_stats, UserStats, maker_stats_account_info);
+            let maker_and_referrer_stats =
+                UserStatsMap::load_one(&maker_stats_account_info).unwrap();
+
+            let state = State {
+                exchange_status: ExchangeStatus::AmmPaused as u8,
+                ..State::default()
+            };
+
+            let base_asset_amount = place_and_take_perp_order(
+                &state,
+                &user_account_loader,
+                &user_stats_account_loader,
+                &market_map,
+                &spot_market_map,
+                &mut oracle_map,
+                &makers_and_referrers,
+                &maker_and_referrer_stats,
+                &clock,
+                OrderParams {
+                    bit_flags,
+                    ..get_params()
+                },
+            )
+            .unwrap();
+
+            assert_eq!(base_asset_amount, expected_fill);
+
+            let user = user_account_loader.load().unwrap();
+            assert_eq!(
+                user.perp_positions[0].base_asset_amount,
+                expected_fill as i64
+            );
+            // ioc leaves nothing resting on the taker
+            assert_eq!(user.orders[0], Order::default());
+            assert_eq!(user.perp_positions[0].open_bids, 0);
+
+            let maker = makers_and_referrers.get_ref(&maker_key).unwrap();
+            assert_eq!(
+                maker.perp_positions[0].base_asset_amount,
+                -(expected_fill as i64)
+            );
+            match expected_maker_open {
+                Some(base_asset_amount) => {
+                    assert_eq!(maker.orders[0].status, OrderStatus::Open);
+                    assert_eq!(
+                        maker.orders[0]
+                            .get_base_asset_amount_unfilled(None)
+                            .unwrap(),
+                        base_asset_amount
+                    );
+                    assert_eq!(
+                        maker.perp_positions[0].open_asks,
+                        -(base_asset_amount as i64)
+                    );
+                }
+                None => {
+                    assert_eq!(maker.orders[0], Order::default());
+                    assert_eq!(maker.perp_positions[0].open_orders, 0);
+                    assert_eq!(maker.perp_positions[0].open_asks, 0);
+                }
+            }
+        }
+    }
+}
+
+pub mod cancel_all_orders_after_ts {
+    use std::str::FromStr;
+
+    use anchor_lang::prelude::{AccountLoader, Clock};
+
+    use super::modify_orders::{get_market, get_spot_market, get_user};
+    use crate::controller::orders::cancel_all_orders_after_ts;
+    use crate::create_account_info;
+    use crate::create_anchor_account_info;
+    use crate::error::ErrorCode;
+    use crate::math::constants::{SPOT_BALANCE_PRECISION, SPOT_BALANCE_PRECISION_U64};
+    use crate::state::perp_market::PerpMarket;
+    use crate::state::perp_market_map::PerpMarketMap;
+    use crate::state::spot_market::{SpotBalanceType, SpotMarket};
+    use crate::state::spot_market_map::SpotMarketMap;
+    use crate::state::state::State;
+    use crate::state::user::{User, UserStatus};
+    use crate::test_utils::*;
+    use crate::test_utils::{create_account_info, get_pyth_price};
+
+    use super::*;
+
+    fn get_clock() -> Clock {
+        Clock {
+            slot: 56,
+            epoch_start_timestamp: 0,
+            epoch: 0,
+            leader_schedule_epoch: 0,
+            unix_timestamp: 10,
+        }
+    }
+
+    #[test]
+    fn cancels_orders_and_caps_fee_at_deposit() {
+        // (user status, cancel_all_after_ts, expected result)
+        let cases = [
+            (0, 20, Err(ErrorCode::CancelAllAfterTsNotReached)),
+            (
+                UserStatus::BeingLiquidated as u8,
+                5,
+                Err(ErrorCode::UserIsBeingLiquidated),
+            ),
+            (UserStatus::Bankrupt as u8, 5, Err(ErrorCode::UserBankrupt)),
+            (0, 5, Ok(())),
+        ];
+
+        for (status, cancel_all_after_ts, expected) in cases {
+            let clock = get_clock();
+
+            let mut oracle_price = get_pyth_price(100, 6);
+            let oracle_price_key =
+                Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+            let pyth_program = crate::ids::pyth_program::id();
+            create_account_info!(
+                oracle_price,
+                &oracle_price_key,
+                &pyth_program,
+                oracle_account_info
+            );
+            let mut oracle_map =
+                OracleMap::load_one(&oracle_account_info, clock.slot, None).unwrap();
+
+            let mut market = get_market(oracle_price_key);
+            create_anchor_account_info!(market, PerpMarket, market_account_info);
+            let market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+
+            let mut spot_market = SpotMarket {
+                deposit_balance: 100 * SPOT_BALANCE_PRECISION,
+                ..get_spot_market()
+            };
+            create_anchor_account_info!(spot_market, SpotMarket, spot_market_account_info);
+            let spot_market_map = SpotMarketMap::load_one(&spot_market_account_info, true).unwrap();
+
+            // two open perp orders and only $.015 deposited to pay the $.01 per order fee
+            let mut user = get_user();
+            user.status = status;
+            user.cancel_all_after_ts = cancel_all_after_ts;
+            user.spot_positions[0].scaled_balance = SPOT_BALANCE_PRECISION_U64 / 1000 * 15;
+            create_anchor_account_info!(user, User, user_account_info);
+            let user_account_loader: AccountLoader<User> =
+                AccountLoader::try_from(&user_account_info).unwrap();
+
+            let filler_key =
+                Pubkey::from_str("My11111111111111111111111111111111111111111").unwrap();
+            create_anchor_account_info!(User::default(), &filler_key, User, filler_account_info);
+            let filler_account_loader: AccountLoader<User> =
+                AccountLoader::try_from(&filler_account_info).unwrap();
+
+            let result = cancel_all_orders_after_ts(
+                &State::default(),
+                &user_account_loader,
+                &spot_market_map,
+                &market_map,
+                &mut oracle_map,
+                &filler_account_loader,
+                &clock,
+            );
+
+            assert_eq!(result, expected);
+
+            if expected.is_err() {
+                continue;
+            }
+
+            let user = user_account_loader.load().unwrap();
+            assert_eq!(user.orders[0], Order::default());
+            assert_eq!(user.orders[1], Order::default());
+            assert_eq!(user.perp_positions[0].open_orders, 0);
+            assert_eq!(user.cancel_all_after_ts, 0);
+            // the fee is capped at the deposit, the user is left without a borrow
+            assert_eq!(
+                user.spot_positions[0].balance_type,
+                SpotBalanceType::Deposit
+            );
+            assert_eq!(user.spot_positions[0].scaled_balance, 0);
+            assert_eq!(user.cumulative_spot_fees, -15000);
+
+            let filler = filler_account_loader.load().unwrap();
+            assert_eq!(
+                filler.spot_positions[0].balance_type,
+                SpotBalanceType::Deposit
+            );
+            assert_eq!(
+                filler.spot_positions[0].scaled_balance,
+                SPOT_BALANCE_PRECISION_U64 / 1000 * 15
+            );
+            assert_eq!(filler.cumulative_spot_fees, 15000);
+        }
+    }
+}
+
+pub mod quote_sized_orders {
+    use std::str::FromStr;
+
+    use anchor_lang::prelude::Clock;
+
+    use super::modify_orders::{get_market, get_spot_market, get_user};
+    use crate::controller::orders::{place_perp_order, place_spot_order};
+    use crate::controller::position::PositionDirection;
+    use crate::create_account_info;
+    use crate::create_anchor_account_info;
+    use crate::math::constants::{
+        PRICE_PRECISION_I64, PRICE_PRECISION_U64, QUOTE_PRECISION_U64, SPOT_BALANCE_PRECISION,
+        SPOT_BALANCE_PRECISION_U64,
+    };
+    use crate::state::oracle::{HistoricalOracleData, OracleSource};
+    use crate::state::order_params::{OrderParams, PlaceOrderOptions};
+    use crate::state::perp_market::PerpMarket;
+    use crate::state::perp_market_map::PerpMarketMap;
+    use crate::state::spot_market::{SpotBalanceType, SpotMarket};
+    use crate::state::spot_market_map::SpotMarketMap;
+    use crate::state::state::State;
+    use crate::state::user::{OrderType, SpotPosition, User};
+    use crate::test_utils::*;
+    use crate::test_utils::{create_account_info, get_pyth_price};
+
+    use super::*;
+
+    fn get_clock() -> Clock {
+        Clock {
+            slot: 56,
+            epoch_start_timestamp: 0,
+            epoch: 0,
+            leader_schedule_epoch: 0,
+            unix_timestamp: 0,
+        }
+    }
+
+    #[test]
+    fn perp_orders_sized_at_worst_price() {
+        let clock = get_clock();
+
+        let mut oracle_price = get_pyth_price(100, 6);
+        let oracle_price_key =
+            Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+        let pyth_program = crate::ids::pyth_program::id();
+        create_account_info!(
+            oracle_price,
+            &oracle_price_key,
+            &pyth_program,
+            oracle_account_info
+        );
+        let mut oracle_map = OracleMap::load_one(&oracle_account_info, clock.slot, None).unwrap();
+
+        let mut market = get_market(oracle_price_key);
+        create_anchor_account_info!(market, PerpMarket, market_account_info);
+        let market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+
+        let mut spot_market = get_spot_market();
+        create_anchor_account_info!(spot_market, SpotMarket, spot_market_account_info);
+        let spot_market_map = SpotMarketMap::load_one(&spot_market_account_info, true).unwrap();
+
+        let state = State::default();
+
+        // (params, expected base asset amount)
+        let cases = [
+            // $50 at the $102 auction end, not the $100 oracle: 0.490196 rounded to the step
+            (
+                OrderParams {
+                    order_type: OrderType::Market,
+                    direction: PositionDirection::Long,
+                    auction_start_price: Some(100 * PRICE_PRECISION_I64),
+                    auction_end_price: Some(102 * PRICE_PRECISION_I64),
+                    auction_duration: Some(10),
+                    quote_asset_amount: Some(50 * QUOTE_PRECISION_U64),
+                    ..OrderParams::default()
+                },
+                490196000,
+            ),
+            // $49 at oracle - $2, the limit after the auction ends at oracle - $1
+            (
+                OrderParams {
+                    order_type: OrderType::Oracle,
+                    direction: PositionDirection::Short,
+                    oracle_price_offset: Some(-2 * PRICE_PRECISION_I64 as i32),
+                    auction_start_price: Some(0),
+                    auction_end_price: Some(-PRICE_PRECISION_I64),
+                    auction_duration: Some(10),
+                    quote_asset_amount: Some(49 * QUOTE_PRECISION_U64),
+                    ..OrderParams::default()
+                },
+                500000000,
+            ),
+            // $40 at the $80 limit price
+            (
+                OrderParams {
+                    order_type: OrderType::Limit,
+                    direction: PositionDirection::Long,
+                    price: 80 * PRICE_PRECISION_U64,
+                    quote_asset_amount: Some(40 * QUOTE_PRECISION_U64),
+                    ..OrderParams::default()
+                },
+                500000000,
+            ),
+        ];
+
+        for (params, expected_base_asset_amount) in cases {
+            let mut user = get_user();
+
+            place_perp_order(
+                &state,
+                &mut user,
+                Pubkey::default(),
+                &market_map,
+                &spot_market_map,
+                &mut oracle_map,
+                &clock,
+                OrderParams {
+                    market_type: MarketType::Perp,
+                    market_index: 0,
+                    ..params
+                },
+                &mut PlaceOrderOptions::default(),
+            )
+            .unwrap();
+
+            let order = user
+                .orders
+                .iter()
+                .find(|order| order.order_id == 3)
+                .unwrap();
+            assert_eq!(order.base_asset_amount, expected_base_asset_amount);
+        }
+    }
+
+    #[test]
+    fn spot_orders_sized_with_mint_decimals() {
+        let clock = get_clock();
+
+        let mut oracle_price = get_pyth_price(100, 6);
+        let oracle_price_key =
+            Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+        let pyth_program = crate::ids::pyth_program::id();
+        create_account_info!(
+            oracle_price,
+            &oracle_price_key,
+            &pyth_program,
+            oracle_account_info
+        );
+        let mut oracle_map = OracleMap::load_one(&oracle_account_info, clock.slot, None).unwrap();
+
+        let perp_market_map = PerpMarketMap::empty();
+
+        // mint with 5 decimals and a 0.01 step
+        let mut base_market = SpotMarket {
+            market_index: 1,
+            oracle: oracle_price_key,
+            oracle_source: OracleSource::Pyth,
+            decimals: 5,
+            order_step_size: 1000,
+            orders_enabled: true,
+            historical_oracle_data: HistoricalOracleData::default_price(100 * PRICE_PRECISION_I64),
+            ..SpotMarket::default_base_market()
+        };
+        create_anchor_account_info!(base_market, SpotMarket, base_market_account_info);
+        let mut quote_market = SpotMarket {
+            market_index: 0,
+            deposit_balance: 100 * SPOT_BALANCE_PRECISION,
+            ..SpotMarket::default_quote_market()
+        };
+        create_anchor_account_info!(quote_market, SpotMarket, quote_market_account_info);
+        let spot_market_map = SpotMarketMap::load_multiple(
+            vec![&base_market_account_info, &quote_market_account_info],
+            true,
+        )
+        .unwrap();
+
+        let state = State::default();
+
+        // (params, expected base asset amount)
+        let cases = [
+            // $50 at the $100 limit price is 0.5 of the mint
+            (
+                OrderParams {
+                    order_type: OrderType::Limit,
+                    price: 100 * PRICE_PRECISION_U64,
+                    quote_asset_amount: Some(50 * QUOTE_PRECISION_U64),
+                    ..OrderParams::default()
+                },
+                50000,
+            ),
+            // $50 at the $104 auction end is 0.48076 of the mint, rounded to the step
+            (
+                OrderParams {
+                    order_type: OrderType::Market,
+                    auction_start_price: Some(100 * PRICE_PRECISION_I64),
+                    auction_end_price: Some(104 * PRICE_PRECISION_I64),
+                    auction_duration: Some(10),
+                    quote_asset_amount: Some(50 * QUOTE_PRECISION_U64),
+                    ..OrderParams::default()
+                },
+                48000,
+            ),
+        ];
+
+        for (params, expected_base_asset_amount) in cases {
+            let mut user = User {
+                spot_positions: get_spot_positions(SpotPosition {
+                    market_index: 0,
+                    balance_type: SpotBalanceType::Deposit,
+                    scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
+                    ..SpotPosition::default()
+                }),
+                ..User::default()
+            };
+
+            place_spot_order(
+                &state,
+                &mut user,
+                Pubkey::default(),
+                &perp_market_map,
+                &spot_market_map,
+                &mut oracle_map,
+                &clock,
+                OrderParams {
+                    market_type: MarketType::Spot,
+                    market_index: 1,
+                    direction: PositionDirection::Long,
+                    ..params
+                },
+                &mut PlaceOrderOptions::default(),
+            )
+            .unwrap();
+
+            assert_eq!(user.orders[0].base_asset_amount, expected_base_asset_amount);
+        }
+    }
+}
@@ -1,5 +1,6 @@
 use crate::controller::amm::{update_pnl_pool_and_user_balance, update_pool_balances};
 use crate::controller::funding::settle_funding_payment;
+use crate::controller::isolated_position::release_isolated_perp_position;
 use crate::controller::orders::{
     attempt_burn_user_lp_shares_for_risk_reduction, cancel_orders,
     validate_market_within_price_band,
@@ -75,6 +76,7 @@ pub fn settle_pnl(
 
     let position_index = get_position_index(&user.perp_positions, market_index)?;
     let unrealized_pnl = user.perp_positions[position_index].get_unrealized_pnl(oracle_price)?;
+    let margin_scope = user.get_perp_margin_scope(market_index);
 
     // cannot settle negative pnl this way on a user who is in liquidation territory
     if user.perp_positions[position_index].is_lp() && !user.is_advanced_lp() {
@@ -84,7 +86,8 @@ pub fn settle_pnl(
             spot_market_map,
             oracle_map,
             MarginContext::standard(MarginRequirementType::Initial)
-                .margin_buffer(state.liquidation_margin_buffer_ratio),
+                .margin_buffer(state.liquidation_margin_buffer_ratio)
+                .scope(margin_scope),
         )?;
 
         if !margin_calc.meets_margin_requirement() {
@@ -109,6 +112,7 @@ pub fn settle_pnl(
                     perp_market_map,
                     spot_market_map,
                     oracle_map,
+                    margin_scope,
                 )?)
             {
                 msg!(
@@ -118,13 +122,15 @@ pub fn settle_pnl(
                 return Ok(());
             }
         }
-    } else if unrealized_pnl < 0 {
+    } else if unrealized_pnl < 0 && !margin_scope.is_isolated() {
         // cannot settle pnl this way on a user who is in liquidation territory
+        // (an isolated position's losses only come out of its own deposit, which is its collateral)
         if !(meets_maintenance_margin_requirement(
             user,
             perp_market_map,
             spot_market_map,
             oracle_map,
+            margin_scope,
         )?) {
             return Err(ErrorCode::InsufficientCollateralForSettlingPNL);
         }
@@ -230,10 +236,24 @@ pub fn settle_pnl(
     let user_unsettled_pnl: i128 =
         user.perp_positions[position_index].get_claimable_pnl(oracle_price, max_pnl_pool_excess)?;
 
+    // an isolated position's losses can only be settled out of its own deposit
+    let (user_unsettled_pnl, user_quote_position) =
+        match user.get_isolated_perp_position(market_index) {
+            Some(isolated_position) => (
+                user_unsettled_pnl.max(
+                    -isolated_position
+                        .get_token_amount(spot_market)?
+                        .cast::<i128>()?,
+                ),
+                isolated_position.get_quote_spot_position(),
+            ),
+            None => (user_unsettled_pnl, *user.get_quote_spot_position()),
+        };
+
     let pnl_to_settle_with_user = update_pool_balances(
         perp_market,
         spot_market,
-        user.get_quote_spot_position(),
+        &user_quote_position,
         user_unsettled_pnl,
         now,
     )?;
@@ -251,12 +271,18 @@ pub fn settle_pnl(
     validate!(
         pnl_to_settle_with_user < 0
             || max_pnl_pool_excess > 0
-            || (pnl_to_settle_with_user > 0 && user.is_being_liquidated())
+            || (pnl_to_settle_with_user > 0 && user.is_margin_scope_being_liquidated(margin_scope))
             || (user.authority.eq(authority) || user.delegate.eq(authority)),
         ErrorCode::UserMustSettleTheirOwnPositiveUnsettledPNL,
         "User must settle their own unsettled pnl when its positive and pnl pool not in excess"
     )?;
 
+    let user_quote_balance: &mut dyn SpotBalance = if margin_scope.is_isolated() {
+        user.get_isolated_perp_position_mut(market_index)?
+    } else {
+        user.get_quote_spot_position_mut()
+    };
+
     update_spot_balances(
         pnl_to_settle_with_user.unsigned_abs(),
         if pnl_to_settle_with_user > 0 {
@@ -265,7 +291,7 @@ pub fn settle_pnl(
             &SpotBalanceType::Borrow
         },
         spot_market,
-        user.get_quote_spot_position_mut(),
+        user_quote_balance,
         false,
     )?;
 
@@ -315,8 +341,13 @@ pub fn settle_expired_position(
     validate!(!user.is_bankrupt(), ErrorCode::UserBankrupt)?;
 
     // cannot settle pnl this way on a user who is in liquidation territory
-    if !(meets_maintenance_margin_requirement(user, perp_market_map, spot_market_map, oracle_map)?)
-    {
+    if !(meets_maintenance_margin_requirement(
+        user,
+        perp_market_map,
+        spot_market_map,
+        oracle_map,
+        user.get_perp_margin_scope(perp_market_index),
+    )?) {
         return Err(ErrorCode::InsufficientCollateralForSettlingPNL);
     }
 
@@ -351,6 +382,13 @@ pub fn settle_expired_position(
         None,
     )?;
 
+    // an isolated position is settled at the expiry price like any other, so its deposit
+    // moves back to cross margin first
+    if user.is_perp_position_isolated(perp_market_index) {
+        let quote_spot_market = &mut spot_market_map.get_quote_spot_market_mut()?;
+        release_isolated_perp_position(user, perp_market_index, quote_spot_market)?;
+    }
+
     let position_index = match get_position_index(&user.perp_positions, perp_market_index) {
         Ok(index) => index,
         Err(_) => {
@@ -7,6 +7,7 @@ use crate::controller::pnl::settle_pnl;
 use crate::create_account_info;
 use crate::create_anchor_account_info;
 use crate::error::ErrorCode;
+use crate::math::bankruptcy::is_isolated_perp_position_bankrupt;
 use crate::math::casting::Cast;
 use crate::math::constants::{
     AMM_RESERVE_PRECISION, BASE_PRECISION_I128, BASE_PRECISION_I64, LIQUIDATION_FEE_PRECISION,
@@ -21,7 +22,9 @@ use crate::state::perp_market_map::PerpMarketMap;
 use crate::state::spot_market::{SpotBalanceType, SpotMarket};
 use crate::state::spot_market_map::SpotMarketMap;
 use crate::state::state::{OracleGuardRails, State, ValidityGuardRails};
-use crate::state::user::{PerpPosition, SpotPosition, User};
+use crate::state::user::{
+    IsolatedPerpPosition, IsolatedPerpPositionStatus, PerpPosition, SpotPosition, User,
+};
 use crate::test_utils::*;
 use crate::test_utils::{get_positions, get_pyth_price, get_spot_positions};
 use anchor_lang::prelude::Clock;
@@ -1931,3 +1934,153 @@ pub fn is_price_divergence_ok_on_invalid_oracle() {
         .is_price_divergence_ok_for_settle_pnl(oracle_price.agg.price)
         .unwrap());
 }
+
+#[test]
+pub fn isolated_negative_pnl_capped_at_isolated_deposit() {
+    let clock = Clock {
+        slot: 0,
+        epoch_start_timestamp: 0,
+        epoch: 0,
+        leader_schedule_epoch: 0,
+        unix_timestamp: 0,
+    };
+
+    let state = State {
+        oracle_guard_rails: OracleGuardRails {
+            validity: ValidityGuardRails {
+                slots_before_stale_for_amm: 10,     // 5s
+                slots_before_stale_for_margin: 120, // 60s
+                confidence_interval_max_size: 1000,
+                too_volatile_ratio: 5,
+            },
+            ..OracleGuardRails::default()
+        },
+        ..State::default()
+    };
+
+    let mut oracle_price = get_pyth_price(100, 6);
+    let oracle_price_key =
+        Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+    let pyth_program = crate::ids::pyth_program::id();
+    create_account_info!(
+        oracle_price,
+        &oracle_price_key,
+        &pyth_program,
+        oracle_account_info
+    );
+    let mut oracle_map = OracleMap::load_one(&oracle_account_info, clock.slot, None).unwrap();
+
+    let mut market = PerpMarket {
+        amm: AMM {
+            base_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+            quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+            bid_base_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+            bid_quote_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+            ask_base_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+            ask_quote_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+            sqrt_k: 100 * AMM_RESERVE_PRECISION,
+            peg_multiplier: 100 * PEG_PRECISION,
+            max_slippage_ratio: 50,
+            max_fill_reserve_fraction: 100,
+            order_step_size: 10000000,
+            quote_asset_amount: -150 * QUOTE_PRECISION_I128,
+            base_asset_amount_with_amm: BASE_PRECISION_I128,
+            base_asset_amount_long: BASE_PRECISION_I128,
+            oracle: oracle_price_key,
+            historical_oracle_data: HistoricalOracleData {
+                last_oracle_price: oracle_price.agg.price,
+                last_oracle_price_twap_5min: oracle_price.agg.price,
+                last_oracle_price_twap: oracle_price.agg.price,
+                ..HistoricalOracleData::default()
+            },
+            ..AMM::default()
+        },
+        margin_ratio_initial: 1000,
+        margin_ratio_maintenance: 500,
+        number_of_users_with_base: 1,
+        status: MarketStatus::Active,
+        liquidator_fee: LIQUIDATION_FEE_PRECISION / 100,
+        pnl_pool: PoolBalance {
+            scaled_balance: (50 * SPOT_BALANCE_PRECISION) as u128,
+            market_index: QUOTE_SPOT_MARKET_INDEX,
+            ..PoolBalance::default()
+        },
+        unrealized_pnl_maintenance_asset_weight: SPOT_WEIGHT_PRECISION.cast().unwrap(),
+        ..PerpMarket::default()
+    };
+    create_anchor_account_info!(market, PerpMarket, market_account_info);
+    let market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+
+    let mut spot_market = SpotMarket {
+        market_index: 0,
+        oracle_source: OracleSource::QuoteAsset,
+        cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+        decimals: 6,
+        initial_asset_weight: SPOT_WEIGHT_PRECISION,
+        maintenance_asset_weight: SPOT_WEIGHT_PRECISION,
+        deposit_balance: 140 * SPOT_BALANCE_PRECISION,
+        historical_oracle_data: HistoricalOracleData::default_price(QUOTE_PRECISION_I64),
+        ..SpotMarket::default()
+    };
+    create_anchor_account_info!(spot_market, SpotMarket, spot_market_account_info);
+    let spot_market_map = SpotMarketMap::load_one(&spot_market_account_info, true).unwrap();
+
+    let mut isolated_perp_positions = [IsolatedPerpPosition::default(); 8];
+    isolated_perp_positions[0] = IsolatedPerpPosition {
+        scaled_balance: 40 * SPOT_BALANCE_PRECISION_U64,
+        perp_market_index: 0,
+        status: IsolatedPerpPositionStatus::Active as u8,
+        ..IsolatedPerpPosition::default()
+    };
+
+    let mut user = User {
+        perp_positions: get_positions(PerpPosition {
+            market_index: 0,
+            quote_asset_amount: -120 * QUOTE_PRECISION_I64,
+            ..PerpPosition::default()
+        }),
+        spot_positions: get_spot_positions(SpotPosition {
+            market_index: 0,
+            balance_type: SpotBalanceType::Deposit,
+            scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
+            ..SpotPosition::default()
+        }),
+        isolated_perp_positions,
+        ..User::default()
+    };
+
+    let user_key = Pubkey::default();
+    let authority = Pubkey::default();
+
+    settle_pnl(
+        0,
+        &mut user,
+        &authority,
+        &user_key,
+        &market_map,
+        &spot_market_map,
+        &mut oracle_map,
+        &clock,
+        &state,
+    )
+    .unwrap();
+
+    // only the isolated deposit pays, the cross deposit is untouched
+    assert_eq!(
+        user.perp_positions[0].quote_asset_amount,
+        -80 * QUOTE_PRECISION_I64
+    );
+    assert_eq!(
+        user.get_isolated_perp_position(0).unwrap().scaled_balance,
+        0
+    );
+    assert_eq!(
+        user.spot_positions[0].scaled_balance,
+        100 * SPOT_BALANCE_PRECISION_U64
+    );
+    assert_eq!(
+        user.spot_positions[0].balance_type,
+        SpotBalanceType::Deposit
+    );
+    assert!(is_isolated_perp_position_bankrupt(&user, 0));
+}
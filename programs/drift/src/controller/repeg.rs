@@ -167,7 +167,19 @@ pub fn _update_amm(
         let curve_update_intensity =
             min(market.amm.curve_update_intensity, 100_u8).cast::<i128>()?;
 
-        if curve_update_intensity > 0 {
+        if market.amm.oracle_anchored {
+            // the repeg cost is the amm's pnl on its inventory, so it is applied even past the fee budget
+            let oracle_peg = repeg::calculate_peg_from_target_price(
+                market.amm.quote_asset_reserve,
+                market.amm.base_asset_reserve,
+                oracle_price_data.price.cast()?,
+            )?;
+            let repegged_cost = repeg::calculate_repeg_cost(&market.amm, oracle_peg)?;
+
+            apply_cost_to_market(market, repegged_cost, false)?;
+            market.amm.peg_multiplier = oracle_peg;
+            amm_update_cost = repegged_cost;
+        } else if curve_update_intensity > 0 {
             let (optimal_peg, fee_budget, check_lower_bound) =
                 repeg::calculate_optimal_peg_and_budget(market, oracle_price_data)?;
 
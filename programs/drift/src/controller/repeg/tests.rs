@@ -564,3 +564,99 @@ pub fn update_amm_larg_conf_w_neg_tfmd_test() {
     assert_eq!((oracle_price_data.price as u64) > bid, true);
     assert_eq!((oracle_price_data.price as u64) < ask, true);
 }
+
+#[test]
+pub fn update_amm_oracle_anchored_test() {
+    let mut market = PerpMarket {
+        amm: AMM {
+            base_asset_reserve: 65 * AMM_RESERVE_PRECISION,
+            quote_asset_reserve: 63015384615,
+            terminal_quote_asset_reserve: 64 * AMM_RESERVE_PRECISION,
+            sqrt_k: 64 * AMM_RESERVE_PRECISION,
+            peg_multiplier: 19_400 * PEG_PRECISION,
+            base_asset_amount_with_amm: -(AMM_RESERVE_PRECISION as i128),
+            mark_std: PRICE_PRECISION as u64,
+            last_mark_price_twap_ts: 0,
+            historical_oracle_data: HistoricalOracleData {
+                last_oracle_price_twap: 19_400 * PRICE_PRECISION_I64,
+                ..HistoricalOracleData::default()
+            },
+            base_spread: 250,
+            curve_update_intensity: 100,
+            max_spread: 55500,
+            concentration_coef: 31020710, //unrealistic but for poc
+            oracle_anchored: true,
+            ..AMM::default()
+        },
+        status: MarketStatus::Initialized,
+        contract_tier: ContractTier::B,
+        margin_ratio_initial: 555, // max 1/.0555 = 18.018018018x leverage
+        ..PerpMarket::default()
+    };
+    let (_, new_terminal_base_reserve) = amm::calculate_terminal_reserves(&market.amm).unwrap();
+    let (min_base_asset_reserve, max_base_asset_reserve) =
+        amm::calculate_bid_ask_bounds(market.amm.concentration_coef, new_terminal_base_reserve)
+            .unwrap();
+    market.amm.min_base_asset_reserve = min_base_asset_reserve;
+    market.amm.max_base_asset_reserve = max_base_asset_reserve;
+
+    let state = State {
+        oracle_guard_rails: OracleGuardRails {
+            price_divergence: PriceDivergenceGuardRails {
+                mark_oracle_percent_divergence: 1,
+                oracle_twap_5min_percent_divergence: 10,
+            },
+            validity: ValidityGuardRails {
+                slots_before_stale_for_amm: 10,     // 5s
+                slots_before_stale_for_margin: 120, // 60s
+                confidence_interval_max_size: 1000,
+                too_volatile_ratio: 5,
+            },
+        },
+        ..State::default()
+    };
+
+    let now = 10000;
+    let slot = 81680085;
+    let oracle_price_data = OraclePriceData {
+        price: (12_400 * PRICE_PRECISION) as i64,
+        confidence: 0,
+        delay: 2,
+        has_sufficient_number_of_data_points: true,
+    };
+
+    // the fee pool is empty, so a budgeted repeg could not move the peg to the oracle
+    assert_eq!(calculate_fee_pool(&market).unwrap(), 0);
+
+    let oracle_peg = calculate_peg_from_target_price(
+        market.amm.quote_asset_reserve,
+        market.amm.base_asset_reserve,
+        12_400 * PRICE_PRECISION_U64,
+    )
+    .unwrap();
+    let expected_cost = calculate_repeg_cost(&market.amm, oracle_peg).unwrap();
+    // amm is long against the users' short, so the price drop is a loss
+    assert!(expected_cost > 0);
+
+    let cost_of_update = _update_amm(&mut market, &oracle_price_data, &state, now, slot).unwrap();
+    assert_eq!(cost_of_update, expected_cost);
+
+    // reserve price follows the oracle and k is left alone
+    assert_eq!(market.amm.peg_multiplier, oracle_peg);
+    assert_eq!(market.amm.sqrt_k, 64 * AMM_RESERVE_PRECISION);
+    let reserve_price = market.amm.reserve_price().unwrap();
+    assert!(reserve_price.abs_diff(12_400 * PRICE_PRECISION_U64) < PRICE_PRECISION_U64 / 100);
+
+    // the inventory loss is still booked
+    assert_eq!(market.amm.total_fee_minus_distributions, -expected_cost);
+    assert_eq!(
+        market.amm.net_revenue_since_last_funding,
+        -(expected_cost as i64)
+    );
+
+    // spreads still apply around the anchored reserve price
+    assert!(market.amm.long_spread > 0);
+    assert!(market.amm.short_spread > 0);
+    assert!(market.amm.ask_price(reserve_price).unwrap() > reserve_price);
+    assert!(market.amm.bid_price(reserve_price).unwrap() < reserve_price);
+}
@@ -0,0 +1,260 @@
+use anchor_lang::prelude::*;
+use solana_program::msg;
+
+use crate::controller::spot_balance::update_spot_market_cumulative_interest;
+use crate::controller::spot_position::update_spot_balances_and_cumulative_deposits;
+use crate::error::{DriftResult, ErrorCode};
+use crate::get_then_update_id;
+use crate::math::casting::Cast;
+use crate::math::constants::{PRICE_PRECISION, QUOTE_SPOT_MARKET_INDEX};
+use crate::math::margin::{
+    calculate_margin_requirement_and_total_collateral_and_liability_info,
+    meets_initial_margin_requirement, MarginRequirementType,
+};
+use crate::math::safe_math::SafeMath;
+use crate::state::events::{DepositDirection, DepositExplanation, DepositRecord};
+use crate::state::margin_calculation::{MarginContext, MarginScope};
+use crate::state::oracle_map::OracleMap;
+use crate::state::perp_market_map::PerpMarketMap;
+use crate::state::spot_market::SpotBalanceType;
+use crate::state::spot_market_map::SpotMarketMap;
+use crate::state::user::User;
+use crate::state::user_map::UserMap;
+use crate::validate;
+
+#[cfg(test)]
+mod tests;
+
+/// For authorities that share collateral across sub accounts. Before a sub account is liquidated, free quote
+/// collateral is moved into it from the authority's other sub accounts, which must all be passed in.
+/// A sub account only gives up collateral it doesn't need to meet its own initial margin requirement
+///
+/// Returns true if the sub account was topped up enough that it no longer needs to be liquidated
+#[allow(clippy::too_many_arguments)]
+pub fn cover_margin_shortage_from_sub_accounts(
+    user: &mut User,
+    user_key: &Pubkey,
+    number_of_sub_accounts: u16,
+    sub_accounts: &UserMap,
+    perp_market_map: &PerpMarketMap,
+    spot_market_map: &SpotMarketMap,
+    oracle_map: &mut OracleMap,
+    liquidation_margin_buffer_ratio: u32,
+    now: i64,
+) -> DriftResult<bool> {
+    validate!(
+        sub_accounts.0.len() == number_of_sub_accounts.saturating_sub(1).cast()?,
+        ErrorCode::InvalidSubAccountCrossMargin,
+        "expected {} other sub accounts, got {}",
+        number_of_sub_accounts.saturating_sub(1),
+        sub_accounts.0.len()
+    )?;
+
+    for sub_account_key in sub_accounts.0.keys() {
+        validate!(
+            sub_account_key != user_key,
+            ErrorCode::InvalidSubAccountCrossMargin,
+            "sub account {} passed twice",
+            sub_account_key
+        )?;
+
+        validate!(
+            sub_accounts.get_ref(sub_account_key)?.authority == user.authority,
+            ErrorCode::InvalidSubAccountCrossMargin,
+            "sub account {} has a different authority",
+            sub_account_key
+        )?;
+    }
+
+    if user.is_bankrupt() {
+        return Ok(false);
+    }
+
+    let margin_context = MarginContext::liquidation(liquidation_margin_buffer_ratio);
+
+    let margin_calculation = calculate_margin_requirement_and_total_collateral_and_liability_info(
+        user,
+        perp_market_map,
+        spot_market_map,
+        oracle_map,
+        margin_context,
+    )?;
+
+    if margin_calculation.can_exit_liquidation()? {
+        return Ok(false);
+    }
+
+    let (quote_oracle_price, quote_oracle_price_twap) = {
+        let quote_spot_market = &mut spot_market_map.get_quote_spot_market_mut()?;
+        let oracle_price_data = oracle_map.get_price_data(&quote_spot_market.oracle)?;
+        update_spot_market_cumulative_interest(quote_spot_market, Some(oracle_price_data), now)?;
+
+        (
+            oracle_price_data.price,
+            quote_spot_market
+                .historical_oracle_data
+                .last_oracle_price_twap_5min,
+        )
+    };
+
+    let mut remaining_token_amount = margin_calculation
+        .margin_shortage()?
+        .safe_mul(PRICE_PRECISION)?
+        .safe_div_ceil(quote_oracle_price.cast()?)?;
+
+    for sub_account_key in sub_accounts.0.keys() {
+        if remaining_token_amount == 0 {
+            break;
+        }
+
+        let sub_account = &mut sub_accounts.get_ref_mut(sub_account_key)?;
+
+        if sub_account.is_being_liquidated() || sub_account.is_bankrupt() {
+            continue;
+        }
+
+        let free_collateral = calculate_margin_requirement_and_total_collateral_and_liability_info(
+            sub_account,
+            perp_market_map,
+            spot_market_map,
+            oracle_map,
+            MarginContext::standard(MarginRequirementType::Initial).strict(true),
+        )?
+        .get_free_collateral()?;
+
+        let mut quote_spot_market = spot_market_map.get_quote_spot_market_mut()?;
+
+        let quote_deposit_token_amount = sub_account
+            .get_spot_position(QUOTE_SPOT_MARKET_INDEX)
+            .map_or(Ok(0), |spot_position| {
+                if spot_position.balance_type == SpotBalanceType::Deposit {
+                    spot_position.get_token_amount(&quote_spot_market)
+                } else {
+                    Ok(0)
+                }
+            })?;
+
+        // value the transfer at the higher of the oracle and twap so the sub account keeps its initial margin
+        let free_token_amount = free_collateral
+            .safe_mul(PRICE_PRECISION)?
+            .safe_div(quote_oracle_price.max(quote_oracle_price_twap).cast()?)?;
+
+        let token_amount = remaining_token_amount
+            .min(free_token_amount)
+            .min(quote_deposit_token_amount);
+
+        if token_amount == 0 {
+            continue;
+        }
+
+        let amount: u64 = token_amount.cast()?;
+
+        sub_account.increment_total_withdraws(
+            amount,
+            quote_oracle_price,
+            quote_spot_market.get_precision().cast()?,
+        )?;
+
+        update_spot_balances_and_cumulative_deposits(
+            token_amount,
+            &SpotBalanceType::Borrow,
+            &mut quote_spot_market,
+            sub_account.get_quote_spot_position_mut(),
+            false,
+            None,
+        )?;
+
+        emit!(DepositRecord {
+            ts: now,
+            deposit_record_id: get_then_update_id!(quote_spot_market, next_deposit_record_id),
+            user_authority: sub_account.authority,
+            user: *sub_account_key,
+            direction: DepositDirection::Withdraw,
+            amount,
+            oracle_price: quote_oracle_price,
+            market_index: QUOTE_SPOT_MARKET_INDEX,
+            market_deposit_balance: quote_spot_market.deposit_balance,
+            market_withdraw_balance: quote_spot_market.borrow_balance,
+            market_cumulative_deposit_interest: quote_spot_market.cumulative_deposit_interest,
+            market_cumulative_borrow_interest: quote_spot_market.cumulative_borrow_interest,
+            total_deposits_after: sub_account.total_deposits,
+            total_withdraws_after: sub_account.total_withdraws,
+            explanation: DepositExplanation::Transfer,
+            transfer_user: Some(*user_key),
+        });
+
+        user.increment_total_deposits(
+            amount,
+            quote_oracle_price,
+            quote_spot_market.get_precision().cast()?,
+        )?;
+
+        update_spot_balances_and_cumulative_deposits(
+            token_amount,
+            &SpotBalanceType::Deposit,
+            &mut quote_spot_market,
+            user.force_get_spot_position_mut(QUOTE_SPOT_MARKET_INDEX)?,
+            false,
+            None,
+        )?;
+
+        emit!(DepositRecord {
+            ts: now,
+            deposit_record_id: get_then_update_id!(quote_spot_market, next_deposit_record_id),
+            user_authority: user.authority,
+            user: *user_key,
+            direction: DepositDirection::Deposit,
+            amount,
+            oracle_price: quote_oracle_price,
+            market_index: QUOTE_SPOT_MARKET_INDEX,
+            market_deposit_balance: quote_spot_market.deposit_balance,
+            market_withdraw_balance: quote_spot_market.borrow_balance,
+            market_cumulative_deposit_interest: quote_spot_market.cumulative_deposit_interest,
+            market_cumulative_borrow_interest: quote_spot_market.cumulative_borrow_interest,
+            total_deposits_after: user.total_deposits,
+            total_withdraws_after: user.total_withdraws,
+            explanation: DepositExplanation::Transfer,
+            transfer_user: Some(*sub_account_key),
+        });
+
+        drop(quote_spot_market);
+
+        validate!(
+            meets_initial_margin_requirement(
+                sub_account,
+                perp_market_map,
+                spot_market_map,
+                oracle_map,
+                MarginScope::Cross,
+            )?,
+            ErrorCode::InsufficientCollateral,
+            "sub account {} doesnt meet initial margin after covering {}",
+            sub_account_key,
+            user_key
+        )?;
+
+        msg!(
+            "sub account {} covered {} of {} margin shortage",
+            sub_account_key,
+            amount,
+            user_key
+        );
+
+        remaining_token_amount = remaining_token_amount.safe_sub(token_amount)?;
+    }
+
+    let margin_calculation = calculate_margin_requirement_and_total_collateral_and_liability_info(
+        user,
+        perp_market_map,
+        spot_market_map,
+        oracle_map,
+        margin_context,
+    )?;
+
+    let is_covered = margin_calculation.can_exit_liquidation()?;
+    if is_covered && user.is_being_liquidated() {
+        user.exit_liquidation();
+    }
+
+    Ok(is_covered)
+}
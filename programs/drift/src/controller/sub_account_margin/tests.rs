@@ -0,0 +1,275 @@
+pub mod cover_margin_shortage_from_sub_accounts {
+    use std::str::FromStr;
+
+    use anchor_lang::Owner;
+    use solana_program::pubkey::Pubkey;
+
+    use crate::controller::sub_account_margin::cover_margin_shortage_from_sub_accounts;
+    use crate::create_account_info;
+    use crate::create_anchor_account_info;
+    use crate::error::ErrorCode;
+    use crate::math::constants::{
+        AMM_RESERVE_PRECISION, BASE_PRECISION_I64, LIQUIDATION_FEE_PRECISION, MARGIN_PRECISION,
+        PEG_PRECISION, PRICE_PRECISION_I64, QUOTE_PRECISION_I64, SPOT_BALANCE_PRECISION,
+        SPOT_BALANCE_PRECISION_U64, SPOT_CUMULATIVE_INTEREST_PRECISION, SPOT_WEIGHT_PRECISION,
+    };
+    use crate::state::oracle::{HistoricalOracleData, OracleSource};
+    use crate::state::oracle_map::OracleMap;
+    use crate::state::perp_market::{MarketStatus, PerpMarket, AMM};
+    use crate::state::perp_market_map::PerpMarketMap;
+    use crate::state::spot_market::{SpotBalanceType, SpotMarket};
+    use crate::state::spot_market_map::SpotMarketMap;
+    use crate::state::user::{PerpPosition, SpotPosition, User, UserStatus};
+    use crate::state::user_map::UserMap;
+    use crate::test_utils::*;
+    use crate::test_utils::{get_positions, get_pyth_price, get_spot_positions};
+
+    #[test]
+    pub fn sibling_covers_shortage() {
+        let slot = 0_u64;
+        let now = 0_i64;
+
+        let mut oracle_price = get_pyth_price(100, 6);
+        let oracle_price_key =
+            Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+        let pyth_program = crate::ids::pyth_program::id();
+        create_account_info!(
+            oracle_price,
+            &oracle_price_key,
+            &pyth_program,
+            oracle_account_info
+        );
+        let mut oracle_map = OracleMap::load_one(&oracle_account_info, slot, None).unwrap();
+
+        let mut market = PerpMarket {
+            amm: AMM {
+                base_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                sqrt_k: 100 * AMM_RESERVE_PRECISION,
+                peg_multiplier: 100 * PEG_PRECISION,
+                order_step_size: 10000000,
+                oracle: oracle_price_key,
+                historical_oracle_data: HistoricalOracleData::default_price(oracle_price.agg.price),
+                ..AMM::default()
+            },
+            margin_ratio_initial: 1000,
+            margin_ratio_maintenance: 500,
+            status: MarketStatus::Active,
+            liquidator_fee: LIQUIDATION_FEE_PRECISION / 100,
+            ..PerpMarket::default()
+        };
+        create_anchor_account_info!(market, PerpMarket, market_account_info);
+        let perp_market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+
+        let mut spot_market = SpotMarket {
+            market_index: 0,
+            oracle_source: OracleSource::QuoteAsset,
+            cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            decimals: 6,
+            initial_asset_weight: SPOT_WEIGHT_PRECISION,
+            maintenance_asset_weight: SPOT_WEIGHT_PRECISION,
+            deposit_balance: 140 * SPOT_BALANCE_PRECISION,
+            historical_oracle_data: HistoricalOracleData::default_price(PRICE_PRECISION_I64),
+            ..SpotMarket::default()
+        };
+        create_anchor_account_info!(spot_market, SpotMarket, spot_market_account_info);
+        let spot_market_map = SpotMarketMap::load_one(&spot_market_account_info, true).unwrap();
+
+        // 10 sol long with $40 of collateral, $70 needed with the 2% liquidation buffer
+        let user_key = Pubkey::new_unique();
+        let mut user = User {
+            sub_account_id: 1,
+            spot_positions: get_spot_positions(SpotPosition {
+                market_index: 0,
+                balance_type: SpotBalanceType::Deposit,
+                scaled_balance: 40 * SPOT_BALANCE_PRECISION_U64,
+                ..SpotPosition::default()
+            }),
+            perp_positions: get_positions(PerpPosition {
+                market_index: 0,
+                base_asset_amount: 10 * BASE_PRECISION_I64,
+                quote_asset_amount: -1000 * QUOTE_PRECISION_I64,
+                ..PerpPosition::default()
+            }),
+            status: UserStatus::BeingLiquidated as u8,
+            ..User::default()
+        };
+
+        let sub_account_key = Pubkey::new_unique();
+        let mut sub_account = User {
+            spot_positions: get_spot_positions(SpotPosition {
+                market_index: 0,
+                balance_type: SpotBalanceType::Deposit,
+                scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
+                ..SpotPosition::default()
+            }),
+            ..User::default()
+        };
+        create_anchor_account_info!(sub_account, &sub_account_key, User, sub_account_info);
+        let sub_accounts = UserMap::load_one(&sub_account_info).unwrap();
+
+        let is_covered = cover_margin_shortage_from_sub_accounts(
+            &mut user,
+            &user_key,
+            2,
+            &sub_accounts,
+            &perp_market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            MARGIN_PRECISION / 50,
+            now,
+        )
+        .unwrap();
+
+        assert!(is_covered);
+        assert!(!user.is_being_liquidated());
+        assert_eq!(
+            user.spot_positions[0].scaled_balance,
+            70 * SPOT_BALANCE_PRECISION_U64
+        );
+        assert_eq!(
+            sub_accounts
+                .get_ref(&sub_account_key)
+                .unwrap()
+                .spot_positions[0]
+                .scaled_balance,
+            70 * SPOT_BALANCE_PRECISION_U64
+        );
+        assert_eq!(
+            spot_market_map.get_ref(&0).unwrap().deposit_balance,
+            140 * SPOT_BALANCE_PRECISION
+        );
+    }
+
+    #[test]
+    pub fn sibling_keeps_initial_margin() {
+        let slot = 0_u64;
+        let now = 0_i64;
+
+        let mut oracle_price = get_pyth_price(100, 6);
+        let oracle_price_key =
+            Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+        let pyth_program = crate::ids::pyth_program::id();
+        create_account_info!(
+            oracle_price,
+            &oracle_price_key,
+            &pyth_program,
+            oracle_account_info
+        );
+        let mut oracle_map = OracleMap::load_one(&oracle_account_info, slot, None).unwrap();
+
+        let mut market = PerpMarket {
+            amm: AMM {
+                base_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                sqrt_k: 100 * AMM_RESERVE_PRECISION,
+                peg_multiplier: 100 * PEG_PRECISION,
+                order_step_size: 10000000,
+                oracle: oracle_price_key,
+                historical_oracle_data: HistoricalOracleData::default_price(oracle_price.agg.price),
+                ..AMM::default()
+            },
+            margin_ratio_initial: 1000,
+            margin_ratio_maintenance: 500,
+            status: MarketStatus::Active,
+            liquidator_fee: LIQUIDATION_FEE_PRECISION / 100,
+            ..PerpMarket::default()
+        };
+        create_anchor_account_info!(market, PerpMarket, market_account_info);
+        let perp_market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+
+        let mut spot_market = SpotMarket {
+            market_index: 0,
+            oracle_source: OracleSource::QuoteAsset,
+            cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            decimals: 6,
+            initial_asset_weight: SPOT_WEIGHT_PRECISION,
+            maintenance_asset_weight: SPOT_WEIGHT_PRECISION,
+            deposit_balance: 140 * SPOT_BALANCE_PRECISION,
+            historical_oracle_data: HistoricalOracleData::default_price(PRICE_PRECISION_I64),
+            ..SpotMarket::default()
+        };
+        create_anchor_account_info!(spot_market, SpotMarket, spot_market_account_info);
+        let spot_market_map = SpotMarketMap::load_one(&spot_market_account_info, true).unwrap();
+
+        let user_key = Pubkey::new_unique();
+        let mut user = User {
+            sub_account_id: 1,
+            spot_positions: get_spot_positions(SpotPosition {
+                market_index: 0,
+                balance_type: SpotBalanceType::Deposit,
+                scaled_balance: 40 * SPOT_BALANCE_PRECISION_U64,
+                ..SpotPosition::default()
+            }),
+            perp_positions: get_positions(PerpPosition {
+                market_index: 0,
+                base_asset_amount: 10 * BASE_PRECISION_I64,
+                quote_asset_amount: -1000 * QUOTE_PRECISION_I64,
+                ..PerpPosition::default()
+            }),
+            ..User::default()
+        };
+
+        // every sub account has to be passed in
+        let result = cover_margin_shortage_from_sub_accounts(
+            &mut user,
+            &user_key,
+            2,
+            &UserMap::empty(),
+            &perp_market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            MARGIN_PRECISION / 50,
+            now,
+        );
+
+        assert_eq!(result, Err(ErrorCode::InvalidSubAccountCrossMargin));
+
+        // 9 sol long needs $90 of its $100 for initial margin
+        let sub_account_key = Pubkey::new_unique();
+        let mut sub_account = User {
+            spot_positions: get_spot_positions(SpotPosition {
+                market_index: 0,
+                balance_type: SpotBalanceType::Deposit,
+                scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
+                ..SpotPosition::default()
+            }),
+            perp_positions: get_positions(PerpPosition {
+                market_index: 0,
+                base_asset_amount: 9 * BASE_PRECISION_I64,
+                quote_asset_amount: -900 * QUOTE_PRECISION_I64,
+                ..PerpPosition::default()
+            }),
+            ..User::default()
+        };
+        create_anchor_account_info!(sub_account, &sub_account_key, User, sub_account_info);
+        let sub_accounts = UserMap::load_one(&sub_account_info).unwrap();
+
+        let is_covered = cover_margin_shortage_from_sub_accounts(
+            &mut user,
+            &user_key,
+            2,
+            &sub_accounts,
+            &perp_market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            MARGIN_PRECISION / 50,
+            now,
+        )
+        .unwrap();
+
+        assert!(!is_covered);
+        assert_eq!(
+            user.spot_positions[0].scaled_balance,
+            50 * SPOT_BALANCE_PRECISION_U64
+        );
+        assert_eq!(
+            sub_accounts
+                .get_ref(&sub_account_key)
+                .unwrap()
+                .spot_positions[0]
+                .scaled_balance,
+            90 * SPOT_BALANCE_PRECISION_U64
+        );
+    }
+}
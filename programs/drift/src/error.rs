@@ -523,6 +523,36 @@ pub enum ErrorCode {
     CantReclaimRent,
     #[msg("InsuranceFundOperationPaused")]
     InsuranceFundOperationPaused,
+    #[msg("InvalidOrderMinFill")]
+    InvalidOrderMinFill,
+    #[msg("OrderFillBelowMinimum")]
+    OrderFillBelowMinimum,
+    #[msg("CancelAllAfterTsNotReached")]
+    CancelAllAfterTsNotReached,
+    #[msg("UserInMarginCall")]
+    UserInMarginCall,
+    #[msg("UserNotInMarginCall")]
+    UserNotInMarginCall,
+    #[msg("MarginCallGracePeriodNotOver")]
+    MarginCallGracePeriodNotOver,
+    #[msg("InvalidAutoDeleverage")]
+    InvalidAutoDeleverage,
+    #[msg("BracketParentOrderNotFilled")]
+    BracketParentOrderNotFilled,
+    #[msg("InvalidIsolatedPerpPosition")]
+    InvalidIsolatedPerpPosition,
+    #[msg("InvalidSubAccountCrossMargin")]
+    InvalidSubAccountCrossMargin,
+    #[msg("BackstopVaultLiquidationDelayNotOver")]
+    BackstopVaultLiquidationDelayNotOver,
+    #[msg("BackstopVaultWithdrawRequestInProgress")]
+    BackstopVaultWithdrawRequestInProgress,
+    #[msg("NoBackstopVaultWithdrawRequestInProgress")]
+    NoBackstopVaultWithdrawRequestInProgress,
+    #[msg("InsufficientBackstopVaultShares")]
+    InsufficientBackstopVaultShares,
+    #[msg("InvalidBackstopVaultEquity")]
+    InvalidBackstopVaultEquity,
 }
 
 #[macro_export]
@@ -15,10 +15,10 @@ use crate::math::casting::Cast;
 use crate::math::constants::{
     DEFAULT_LIQUIDATION_MARGIN_BUFFER_RATIO, FEE_POOL_TO_REVENUE_POOL_THRESHOLD,
     IF_FACTOR_PRECISION, INSURANCE_A_MAX, INSURANCE_B_MAX, INSURANCE_C_MAX,
-    INSURANCE_SPECULATIVE_MAX, LIQUIDATION_FEE_PRECISION, MAX_CONCENTRATION_COEFFICIENT,
-    MAX_SQRT_K, MAX_UPDATE_K_PRICE_CHANGE, QUOTE_SPOT_MARKET_INDEX,
-    SPOT_CUMULATIVE_INTEREST_PRECISION, SPOT_IMF_PRECISION, SPOT_WEIGHT_PRECISION, THIRTEEN_DAY,
-    TWENTY_FOUR_HOUR,
+    INSURANCE_SPECULATIVE_MAX, LIQUIDATION_FEE_PRECISION, LIQUIDATION_PCT_PRECISION,
+    MARGIN_PRECISION, MAX_CONCENTRATION_COEFFICIENT, MAX_SQRT_K, MAX_UPDATE_K_PRICE_CHANGE,
+    QUOTE_SPOT_MARKET_INDEX, SPOT_CUMULATIVE_INTEREST_PRECISION, SPOT_IMF_PRECISION,
+    SPOT_WEIGHT_PRECISION, THIRTEEN_DAY, TWENTY_FOUR_HOUR,
 };
 use crate::math::cp_curve::get_update_k_result;
 use crate::math::orders::is_multiple_of_step_size;
@@ -27,6 +27,7 @@ use crate::math::safe_math::SafeMath;
 use crate::math::spot_balance::get_token_amount;
 use crate::math::{amm, bn};
 use crate::math_error;
+use crate::state::backstop_vault::BackstopVault;
 use crate::state::events::CurveRecord;
 use crate::state::fulfillment_params::phoenix::PhoenixMarketContext;
 use crate::state::fulfillment_params::phoenix::PhoenixV1FulfillmentConfig;
@@ -51,7 +52,9 @@ use crate::state::user::User;
 use crate::state::user::UserStats;
 use crate::validate;
 use crate::validation::fee_structure::validate_fee_structure;
-use crate::validation::margin::{validate_margin, validate_margin_weights};
+use crate::validation::margin::{
+    validate_margin, validate_margin_ratio_volatility_scaling, validate_margin_weights,
+};
 use crate::validation::perp_market::validate_perp_market;
 use crate::validation::spot_market::validate_borrow_rate;
 use crate::{controller, QUOTE_PRECISION_I64};
@@ -90,7 +93,10 @@ pub fn handle_initialize(ctx: Context<Initialize>) -> Result<()> {
         initial_pct_to_liquidate: 0,
         max_number_of_sub_accounts: 0,
         max_initialize_user_fee: 0,
-        padding: [0; 10],
+        initial_liquidator_fee_pct: 0,
+        margin_call_buffer_ratio: 0,
+        margin_call_grace_period: 0,
+        padding: [0; 2],
     };
 
     Ok(())
@@ -281,7 +287,8 @@ pub fn handle_initialize_spot_market(
         flash_loan_initial_token_amount: 0,
         total_swap_fee: 0,
         scale_initial_asset_weight_start,
-        padding: [0; 48],
+        stress_oracle_shock: 0,
+        padding: [0; 46],
         insurance_fund: InsuranceFund {
             vault: *ctx.accounts.insurance_fund_vault.to_account_info().key,
             unstaking_period: THIRTEEN_DAY,
@@ -678,7 +685,12 @@ pub fn handle_initialize_perp_market(
         paused_operations: 0,
         quote_spot_market_index: QUOTE_SPOT_MARKET_INDEX,
         fee_adjustment: 0,
-        padding: [0; 46],
+        hedge_spot_market_index: 0,
+        stress_oracle_shock: 0,
+        margin_ratio_volatility_reference: 0,
+        margin_ratio_volatility_scale_min: 0,
+        margin_ratio_volatility_scale_max: 0,
+        padding: [0; 36],
         amm: AMM {
             oracle: *ctx.accounts.oracle.key,
             oracle_source,
@@ -767,7 +779,7 @@ pub fn handle_initialize_perp_market(
             last_oracle_valid: false,
             target_base_asset_amount_per_lp: 0,
             per_lp_base: 0,
-            padding1: 0,
+            oracle_anchored: false,
             padding2: 0,
             total_fee_earned_per_lp: 0,
             net_unsettled_funding_pnl: 0,
@@ -1558,6 +1570,17 @@ pub fn handle_update_perp_market_margin_ratio(
         perp_market.amm.max_spread,
     )?;
 
+    if perp_market.margin_ratio_volatility_reference != 0 {
+        validate_margin_ratio_volatility_scaling(
+            margin_ratio_initial,
+            margin_ratio_maintenance,
+            perp_market.liquidator_fee,
+            perp_market.amm.max_spread,
+            perp_market.margin_ratio_volatility_scale_min,
+            perp_market.margin_ratio_volatility_scale_max,
+        )?;
+    }
+
     perp_market.margin_ratio_initial = margin_ratio_initial;
     perp_market.margin_ratio_maintenance = margin_ratio_maintenance;
     Ok(())
@@ -1667,6 +1690,34 @@ pub fn handle_update_spot_market_name(
     Ok(())
 }
 
+#[access_control(
+    perp_market_valid(&ctx.accounts.perp_market)
+)]
+pub fn handle_update_perp_market_margin_ratio_volatility_scaling(
+    ctx: Context<AdminUpdatePerpMarket>,
+    margin_ratio_volatility_reference: u16,
+    margin_ratio_volatility_scale_min: u16,
+    margin_ratio_volatility_scale_max: u16,
+) -> Result<()> {
+    let perp_market = &mut load_mut!(ctx.accounts.perp_market)?;
+
+    if margin_ratio_volatility_reference != 0 {
+        validate_margin_ratio_volatility_scaling(
+            perp_market.margin_ratio_initial,
+            perp_market.margin_ratio_maintenance,
+            perp_market.liquidator_fee,
+            perp_market.amm.max_spread,
+            margin_ratio_volatility_scale_min,
+            margin_ratio_volatility_scale_max,
+        )?;
+    }
+
+    perp_market.margin_ratio_volatility_reference = margin_ratio_volatility_reference;
+    perp_market.margin_ratio_volatility_scale_min = margin_ratio_volatility_scale_min;
+    perp_market.margin_ratio_volatility_scale_max = margin_ratio_volatility_scale_max;
+    Ok(())
+}
+
 #[access_control(
     perp_market_valid(&ctx.accounts.perp_market)
 )]
@@ -1695,6 +1746,17 @@ pub fn handle_update_perp_liquidation_fee(
         perp_market.amm.max_spread,
     )?;
 
+    if perp_market.margin_ratio_volatility_reference != 0 {
+        validate_margin_ratio_volatility_scaling(
+            perp_market.margin_ratio_initial,
+            perp_market.margin_ratio_maintenance,
+            liquidator_fee,
+            perp_market.amm.max_spread,
+            perp_market.margin_ratio_volatility_scale_min,
+            perp_market.margin_ratio_volatility_scale_max,
+        )?;
+    }
+
     perp_market.liquidator_fee = liquidator_fee;
     perp_market.if_liquidation_fee = if_liquidation_fee;
     Ok(())
@@ -2112,6 +2174,24 @@ pub fn handle_update_perp_market_curve_update_intensity(
     Ok(())
 }
 
+#[access_control(
+    perp_market_valid(&ctx.accounts.perp_market)
+)]
+pub fn handle_update_perp_market_oracle_anchored(
+    ctx: Context<AdminUpdatePerpMarket>,
+    oracle_anchored: bool,
+) -> Result<()> {
+    let perp_market = &mut load_mut!(ctx.accounts.perp_market)?;
+    msg!(
+        "perp market {} oracle_anchored {} -> {}",
+        perp_market.market_index,
+        perp_market.amm.oracle_anchored,
+        oracle_anchored
+    );
+    perp_market.amm.oracle_anchored = oracle_anchored;
+    Ok(())
+}
+
 #[access_control(
     perp_market_valid(&ctx.accounts.perp_market)
 )]
@@ -2191,6 +2271,54 @@ pub fn handle_update_initial_pct_to_liquidate(
     Ok(())
 }
 
+pub fn handle_update_initial_liquidator_fee_pct(
+    ctx: Context<AdminUpdateState>,
+    initial_liquidator_fee_pct: u16,
+) -> Result<()> {
+    validate!(
+        initial_liquidator_fee_pct.cast::<u128>()? <= LIQUIDATION_PCT_PRECISION,
+        ErrorCode::DefaultError,
+        "initial_liquidator_fee_pct must be <= LIQUIDATION_PCT_PRECISION"
+    )?;
+
+    msg!(
+        "initial_liquidator_fee_pct {} -> {}",
+        ctx.accounts.state.initial_liquidator_fee_pct,
+        initial_liquidator_fee_pct
+    );
+
+    ctx.accounts.state.initial_liquidator_fee_pct = initial_liquidator_fee_pct;
+    Ok(())
+}
+
+pub fn handle_update_margin_call_params(
+    ctx: Context<AdminUpdateState>,
+    margin_call_buffer_ratio: u32,
+    margin_call_grace_period: u16,
+) -> Result<()> {
+    validate!(
+        margin_call_buffer_ratio <= MARGIN_PRECISION,
+        ErrorCode::DefaultError,
+        "margin_call_buffer_ratio must be <= MARGIN_PRECISION"
+    )?;
+
+    msg!(
+        "margin_call_buffer_ratio {} -> {}",
+        ctx.accounts.state.margin_call_buffer_ratio,
+        margin_call_buffer_ratio
+    );
+
+    msg!(
+        "margin_call_grace_period {} -> {}",
+        ctx.accounts.state.margin_call_grace_period,
+        margin_call_grace_period
+    );
+
+    ctx.accounts.state.margin_call_buffer_ratio = margin_call_buffer_ratio;
+    ctx.accounts.state.margin_call_grace_period = margin_call_grace_period;
+    Ok(())
+}
+
 pub fn handle_update_liquidation_duration(
     ctx: Context<AdminUpdateState>,
     liquidation_duration: u8,
@@ -2454,6 +2582,60 @@ pub fn handle_update_perp_market_fee_adjustment(
     Ok(())
 }
 
+pub fn handle_update_perp_market_hedge_spot_market(
+    ctx: Context<AdminUpdatePerpMarketHedgeSpotMarket>,
+    hedge_spot_market_index: u16,
+) -> Result<()> {
+    let perp_market = &mut load_mut!(ctx.accounts.perp_market)?;
+
+    if hedge_spot_market_index != QUOTE_SPOT_MARKET_INDEX {
+        let spot_market = load!(&ctx.accounts.spot_market)?;
+
+        validate!(
+            spot_market.oracle == perp_market.amm.oracle,
+            ErrorCode::InvalidSpotMarketAccount,
+            "spot market {} oracle {} does not match perp market {} oracle {}",
+            spot_market.market_index,
+            spot_market.oracle,
+            perp_market.market_index,
+            perp_market.amm.oracle
+        )?;
+    }
+
+    msg!(
+        "hedge_spot_market_index {} -> {}",
+        perp_market.hedge_spot_market_index,
+        hedge_spot_market_index
+    );
+
+    perp_market.hedge_spot_market_index = hedge_spot_market_index;
+    Ok(())
+}
+
+pub fn handle_update_perp_market_stress_oracle_shock(
+    ctx: Context<AdminUpdatePerpMarket>,
+    stress_oracle_shock: u16,
+) -> Result<()> {
+    let perp_market = &mut load_mut!(ctx.accounts.perp_market)?;
+
+    validate!(
+        stress_oracle_shock.cast::<u32>()? <= MARGIN_PRECISION,
+        ErrorCode::DefaultError,
+        "stress oracle shock {} greater than max {}",
+        stress_oracle_shock,
+        MARGIN_PRECISION
+    )?;
+
+    msg!(
+        "stress_oracle_shock {} -> {}",
+        perp_market.stress_oracle_shock,
+        stress_oracle_shock
+    );
+
+    perp_market.stress_oracle_shock = stress_oracle_shock;
+    Ok(())
+}
+
 pub fn handle_update_perp_market_number_of_users(
     ctx: Context<AdminUpdatePerpMarket>,
     number_of_users: Option<u32>,
@@ -2499,6 +2681,30 @@ pub fn handle_update_spot_market_fee_adjustment(
     Ok(())
 }
 
+pub fn handle_update_spot_market_stress_oracle_shock(
+    ctx: Context<AdminUpdateSpotMarket>,
+    stress_oracle_shock: u16,
+) -> Result<()> {
+    let spot_market = &mut load_mut!(ctx.accounts.spot_market)?;
+
+    validate!(
+        stress_oracle_shock.cast::<u32>()? <= MARGIN_PRECISION,
+        ErrorCode::DefaultError,
+        "stress oracle shock {} greater than max {}",
+        stress_oracle_shock,
+        MARGIN_PRECISION
+    )?;
+
+    msg!(
+        "stress_oracle_shock {} -> {}",
+        spot_market.stress_oracle_shock,
+        stress_oracle_shock
+    );
+
+    spot_market.stress_oracle_shock = stress_oracle_shock;
+    Ok(())
+}
+
 pub fn handle_update_admin(ctx: Context<AdminUpdateState>, admin: Pubkey) -> Result<()> {
     ctx.accounts.state.admin = admin;
     Ok(())
@@ -2584,6 +2790,99 @@ pub fn handle_update_protocol_if_shares_transfer_config(
     Ok(())
 }
 
+pub fn handle_initialize_backstop_vault(
+    ctx: Context<InitializeBackstopVault>,
+    unstaking_period: i64,
+    liquidation_delay: u64,
+) -> Result<()> {
+    let clock = Clock::get()?;
+    let backstop_vault_key = ctx.accounts.backstop_vault.key();
+    let user_key = ctx.accounts.user.key();
+
+    let mut backstop_vault = ctx
+        .accounts
+        .backstop_vault
+        .load_init()
+        .or(Err(ErrorCode::UnableToLoadAccountLoader))?;
+
+    *backstop_vault = BackstopVault {
+        pubkey: backstop_vault_key,
+        user: user_key,
+        unstaking_period,
+        liquidation_delay,
+        ..BackstopVault::default()
+    };
+
+    // the vault liquidates through its own user, which only the vault can withdraw from
+    let mut user = ctx
+        .accounts
+        .user
+        .load_init()
+        .or(Err(ErrorCode::UnableToLoadAccountLoader))?;
+    user.authority = backstop_vault_key;
+    user.next_order_id = 1;
+    user.next_liquidation_id = 1;
+
+    let mut user_stats = ctx
+        .accounts
+        .user_stats
+        .load_init()
+        .or(Err(ErrorCode::UnableToLoadAccountLoader))?;
+
+    *user_stats = UserStats {
+        authority: backstop_vault_key,
+        number_of_sub_accounts: 1,
+        number_of_sub_accounts_created: 1,
+        last_taker_volume_30d_ts: clock.unix_timestamp,
+        last_maker_volume_30d_ts: clock.unix_timestamp,
+        last_filler_volume_30d_ts: clock.unix_timestamp,
+        ..UserStats::default()
+    };
+
+    let state = &mut ctx.accounts.state;
+    safe_increment!(state.number_of_authorities, 1);
+    safe_increment!(state.number_of_sub_accounts, 1);
+
+    Ok(())
+}
+
+pub fn handle_update_backstop_vault_params(
+    ctx: Context<AdminUpdateBackstopVault>,
+    unstaking_period: i64,
+    liquidation_delay: u64,
+) -> Result<()> {
+    let backstop_vault = &mut load_mut!(ctx.accounts.backstop_vault)?;
+
+    msg!(
+        "unstaking_period {} -> {}",
+        backstop_vault.unstaking_period,
+        unstaking_period
+    );
+
+    msg!(
+        "liquidation_delay {} -> {}",
+        backstop_vault.liquidation_delay,
+        liquidation_delay
+    );
+
+    backstop_vault.unstaking_period = unstaking_period;
+    backstop_vault.liquidation_delay = liquidation_delay;
+    Ok(())
+}
+
+/// The delegate trades the vault's user, e.g. to unwind positions taken over in liquidations
+pub fn handle_update_backstop_vault_delegate(
+    ctx: Context<AdminUpdateBackstopVault>,
+    delegate: Pubkey,
+) -> Result<()> {
+    let user = &mut load_mut!(ctx.accounts.user)?;
+
+    msg!("delegate {} -> {}", user.delegate, delegate);
+
+    user.delegate = delegate;
+    Ok(())
+}
+
 pub fn handle_initialize_prelaunch_oracle<'info>(
     ctx: Context<InitializePrelaunchOracle<'info>>,
     params: PrelaunchOracleParams,
@@ -2920,6 +3219,23 @@ pub struct AdminUpdatePerpMarket<'info> {
     pub perp_market: AccountLoader<'info, PerpMarket>,
 }
 
+#[derive(Accounts)]
+#[instruction(hedge_spot_market_index: u16)]
+pub struct AdminUpdatePerpMarketHedgeSpotMarket<'info> {
+    pub admin: Signer<'info>,
+    #[account(
+        has_one = admin
+    )]
+    pub state: Box<Account<'info, State>>,
+    #[account(mut)]
+    pub perp_market: AccountLoader<'info, PerpMarket>,
+    #[account(
+        seeds = [b"spot_market", hedge_spot_market_index.to_le_bytes().as_ref()],
+        bump,
+    )]
+    pub spot_market: AccountLoader<'info, SpotMarket>,
+}
+
 #[derive(Accounts)]
 pub struct AdminUpdatePerpMarketAmmSummaryStats<'info> {
     pub admin: Signer<'info>,
@@ -3097,6 +3413,61 @@ pub struct UpdateProtocolIfSharesTransferConfig<'info> {
     pub state: Box<Account<'info, State>>,
 }
 
+#[derive(Accounts)]
+pub struct InitializeBackstopVault<'info> {
+    #[account(mut)]
+    pub admin: Signer<'info>,
+    #[account(
+        mut,
+        has_one = admin
+    )]
+    pub state: Box<Account<'info, State>>,
+    #[account(
+        init,
+        seeds = [b"backstop_vault".as_ref()],
+        space = BackstopVault::SIZE,
+        bump,
+        payer = admin
+    )]
+    pub backstop_vault: AccountLoader<'info, BackstopVault>,
+    #[account(
+        init,
+        seeds = [b"user", backstop_vault.key().as_ref(), 0_u16.to_le_bytes().as_ref()],
+        space = User::SIZE,
+        bump,
+        payer = admin
+    )]
+    pub user: AccountLoader<'info, User>,
+    #[account(
+        init,
+        seeds = [b"user_stats", backstop_vault.key().as_ref()],
+        space = UserStats::SIZE,
+        bump,
+        payer = admin
+    )]
+    pub user_stats: AccountLoader<'info, UserStats>,
+    pub rent: Sysvar<'info, Rent>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct AdminUpdateBackstopVault<'info> {
+    pub admin: Signer<'info>,
+    #[account(
+        has_one = admin
+    )]
+    pub state: Box<Account<'info, State>>,
+    #[account(
+        mut,
+        seeds = [b"backstop_vault".as_ref()],
+        bump,
+        has_one = user
+    )]
+    pub backstop_vault: AccountLoader<'info, BackstopVault>,
+    #[account(mut)]
+    pub user: AccountLoader<'info, User>,
+}
+
 #[derive(Accounts)]
 #[instruction(params: PrelaunchOracleParams,)]
 pub struct InitializePrelaunchOracle<'info> {
@@ -0,0 +1,411 @@
+use anchor_lang::prelude::*;
+use anchor_spl::token::{Token, TokenAccount};
+
+use crate::controller::backstop_vault::calculate_backstop_vault_equity;
+use crate::controller::spot_balance::update_spot_market_cumulative_interest;
+use crate::controller::spot_position::{
+    update_spot_balances_and_cumulative_deposits,
+    update_spot_balances_and_cumulative_deposits_with_limits,
+};
+use crate::error::ErrorCode;
+use crate::instructions::constraints::*;
+use crate::instructions::optional_accounts::{load_maps, AccountMaps};
+use crate::math::casting::Cast;
+use crate::math::margin::{
+    meets_withdraw_margin_requirement, validate_spot_margin_trading, MarginRequirementType,
+};
+use crate::state::backstop_vault::{BackstopVault, BackstopVaultDepositor};
+use crate::state::events::{DepositDirection, DepositExplanation, DepositRecord};
+use crate::state::perp_market_map::MarketSet;
+use crate::state::spot_market::SpotBalanceType;
+use crate::state::spot_market_map::get_writable_spot_market_set;
+use crate::state::state::State;
+use crate::state::traits::Size;
+use crate::state::user::User;
+use crate::validate;
+use crate::{controller, math};
+use crate::{get_then_update_id, load, load_mut, QUOTE_SPOT_MARKET_INDEX};
+
+pub fn handle_initialize_backstop_vault_depositor(
+    ctx: Context<InitializeBackstopVaultDepositor>,
+) -> Result<()> {
+    let mut depositor = ctx
+        .accounts
+        .backstop_vault_depositor
+        .load_init()
+        .or(Err(ErrorCode::UnableToLoadAccountLoader))?;
+
+    *depositor = BackstopVaultDepositor::new(*ctx.accounts.authority.key);
+
+    Ok(())
+}
+
+#[access_control(
+    deposit_not_paused(&ctx.accounts.state)
+)]
+pub fn handle_deposit_into_backstop_vault(
+    ctx: Context<DepositIntoBackstopVault>,
+    amount: u64,
+) -> Result<()> {
+    if amount == 0 {
+        return Err(ErrorCode::InsufficientDeposit.into());
+    }
+
+    let clock = Clock::get()?;
+    let now = clock.unix_timestamp;
+    let state = &ctx.accounts.state;
+
+    let user_key = ctx.accounts.user.key();
+    let user = &mut load_mut!(ctx.accounts.user)?;
+    let backstop_vault = &mut load_mut!(ctx.accounts.backstop_vault)?;
+    let depositor = &mut load_mut!(ctx.accounts.backstop_vault_depositor)?;
+
+    let AccountMaps {
+        perp_market_map,
+        spot_market_map,
+        mut oracle_map,
+    } = load_maps(
+        &mut ctx.remaining_accounts.iter().peekable(),
+        &MarketSet::new(),
+        &get_writable_spot_market_set(QUOTE_SPOT_MARKET_INDEX),
+        clock.slot,
+        Some(state.oracle_guard_rails),
+    )?;
+
+    validate!(!user.is_bankrupt(), ErrorCode::UserBankrupt)?;
+
+    {
+        let quote_spot_market = &mut spot_market_map.get_quote_spot_market_mut()?;
+        let oracle_price_data = oracle_map.get_price_data(&quote_spot_market.oracle)?;
+        update_spot_market_cumulative_interest(quote_spot_market, Some(oracle_price_data), now)?;
+    }
+
+    // shares are minted against the equity before the deposit lands
+    let vault_equity =
+        calculate_backstop_vault_equity(user, &perp_market_map, &spot_market_map, &mut oracle_map)?;
+
+    controller::backstop_vault::deposit_into_backstop_vault(
+        amount,
+        vault_equity,
+        backstop_vault,
+        depositor,
+        now,
+    )?;
+
+    let mut quote_spot_market = spot_market_map.get_quote_spot_market_mut()?;
+    let oracle_price = oracle_map.get_price_data(&quote_spot_market.oracle)?.price;
+
+    user.increment_total_deposits(
+        amount,
+        oracle_price,
+        quote_spot_market.get_precision().cast()?,
+    )?;
+
+    update_spot_balances_and_cumulative_deposits(
+        amount.cast()?,
+        &SpotBalanceType::Deposit,
+        &mut quote_spot_market,
+        user.force_get_spot_position_mut(QUOTE_SPOT_MARKET_INDEX)?,
+        false,
+        None,
+    )?;
+
+    controller::token::receive(
+        &ctx.accounts.token_program,
+        &ctx.accounts.user_token_account,
+        &ctx.accounts.spot_market_vault,
+        &ctx.accounts.authority,
+        amount,
+    )?;
+    ctx.accounts.spot_market_vault.reload()?;
+
+    let deposit_record = DepositRecord {
+        ts: now,
+        deposit_record_id: get_then_update_id!(quote_spot_market, next_deposit_record_id),
+        user_authority: user.authority,
+        user: user_key,
+        direction: DepositDirection::Deposit,
+        amount,
+        oracle_price,
+        market_deposit_balance: quote_spot_market.deposit_balance,
+        market_withdraw_balance: quote_spot_market.borrow_balance,
+        market_cumulative_deposit_interest: quote_spot_market.cumulative_deposit_interest,
+        market_cumulative_borrow_interest: quote_spot_market.cumulative_borrow_interest,
+        total_deposits_after: user.total_deposits,
+        total_withdraws_after: user.total_withdraws,
+        market_index: QUOTE_SPOT_MARKET_INDEX,
+        explanation: DepositExplanation::None,
+        transfer_user: None,
+    };
+    emit!(deposit_record);
+
+    quote_spot_market.validate_max_token_deposits()?;
+
+    Ok(())
+}
+
+pub fn handle_request_withdraw_from_backstop_vault(
+    ctx: Context<RequestWithdrawFromBackstopVault>,
+    shares: u128,
+) -> Result<()> {
+    let clock = Clock::get()?;
+    let now = clock.unix_timestamp;
+    let state = &ctx.accounts.state;
+
+    let user = load!(ctx.accounts.user)?;
+    let backstop_vault = load!(ctx.accounts.backstop_vault)?;
+    let depositor = &mut load_mut!(ctx.accounts.backstop_vault_depositor)?;
+
+    let AccountMaps {
+        perp_market_map,
+        spot_market_map,
+        mut oracle_map,
+    } = load_maps(
+        &mut ctx.remaining_accounts.iter().peekable(),
+        &MarketSet::new(),
+        &MarketSet::new(),
+        clock.slot,
+        Some(state.oracle_guard_rails),
+    )?;
+
+    let vault_equity = calculate_backstop_vault_equity(
+        &user,
+        &perp_market_map,
+        &spot_market_map,
+        &mut oracle_map,
+    )?;
+
+    controller::backstop_vault::request_withdraw_from_backstop_vault(
+        shares,
+        vault_equity,
+        &backstop_vault,
+        depositor,
+        now,
+    )?;
+
+    Ok(())
+}
+
+#[access_control(
+    withdraw_not_paused(&ctx.accounts.state)
+)]
+pub fn handle_withdraw_from_backstop_vault(ctx: Context<WithdrawFromBackstopVault>) -> Result<()> {
+    let clock = Clock::get()?;
+    let now = clock.unix_timestamp;
+    let state = &ctx.accounts.state;
+
+    let user_key = ctx.accounts.user.key();
+    let user = &mut load_mut!(ctx.accounts.user)?;
+    let backstop_vault = &mut load_mut!(ctx.accounts.backstop_vault)?;
+    let depositor = &mut load_mut!(ctx.accounts.backstop_vault_depositor)?;
+
+    let AccountMaps {
+        perp_market_map,
+        spot_market_map,
+        mut oracle_map,
+    } = load_maps(
+        &mut ctx.remaining_accounts.iter().peekable(),
+        &MarketSet::new(),
+        &get_writable_spot_market_set(QUOTE_SPOT_MARKET_INDEX),
+        clock.slot,
+        Some(state.oracle_guard_rails),
+    )?;
+
+    validate!(!user.is_bankrupt(), ErrorCode::UserBankrupt)?;
+    validate!(
+        !user.is_being_liquidated(),
+        ErrorCode::UserIsBeingLiquidated
+    )?;
+
+    {
+        let quote_spot_market = &mut spot_market_map.get_quote_spot_market_mut()?;
+        let oracle_price_data = oracle_map.get_price_data(&quote_spot_market.oracle)?;
+        update_spot_market_cumulative_interest(quote_spot_market, Some(oracle_price_data), now)?;
+    }
+
+    let vault_equity =
+        calculate_backstop_vault_equity(user, &perp_market_map, &spot_market_map, &mut oracle_map)?;
+
+    let amount = controller::backstop_vault::withdraw_from_backstop_vault(
+        vault_equity,
+        backstop_vault,
+        depositor,
+        now,
+    )?;
+
+    {
+        let quote_spot_market = &mut spot_market_map.get_quote_spot_market_mut()?;
+        let oracle_price = oracle_map.get_price_data(&quote_spot_market.oracle)?.price;
+
+        user.increment_total_withdraws(
+            amount,
+            oracle_price,
+            quote_spot_market.get_precision().cast()?,
+        )?;
+
+        // prevents withdraw when limits hit
+        update_spot_balances_and_cumulative_deposits_with_limits(
+            amount.cast()?,
+            &SpotBalanceType::Borrow,
+            quote_spot_market,
+            user,
+        )?;
+    }
+
+    // collateral backing positions the vault took over stays in the vault
+    meets_withdraw_margin_requirement(
+        user,
+        &perp_market_map,
+        &spot_market_map,
+        &mut oracle_map,
+        MarginRequirementType::Initial,
+    )?;
+
+    validate_spot_margin_trading(user, &perp_market_map, &spot_market_map, &mut oracle_map)?;
+
+    let mut quote_spot_market = spot_market_map.get_quote_spot_market_mut()?;
+    let oracle_price = oracle_map.get_price_data(&quote_spot_market.oracle)?.price;
+
+    let deposit_record = DepositRecord {
+        ts: now,
+        deposit_record_id: get_then_update_id!(quote_spot_market, next_deposit_record_id),
+        user_authority: user.authority,
+        user: user_key,
+        direction: DepositDirection::Withdraw,
+        oracle_price,
+        amount,
+        market_index: QUOTE_SPOT_MARKET_INDEX,
+        market_deposit_balance: quote_spot_market.deposit_balance,
+        market_withdraw_balance: quote_spot_market.borrow_balance,
+        market_cumulative_deposit_interest: quote_spot_market.cumulative_deposit_interest,
+        market_cumulative_borrow_interest: quote_spot_market.cumulative_borrow_interest,
+        total_deposits_after: user.total_deposits,
+        total_withdraws_after: user.total_withdraws,
+        explanation: DepositExplanation::None,
+        transfer_user: None,
+    };
+    emit!(deposit_record);
+
+    controller::token::send_from_program_vault(
+        &ctx.accounts.token_program,
+        &ctx.accounts.spot_market_vault,
+        &ctx.accounts.user_token_account,
+        &ctx.accounts.drift_signer,
+        state.signer_nonce,
+        amount,
+    )?;
+
+    // reload the spot market vault balance so it's up-to-date
+    ctx.accounts.spot_market_vault.reload()?;
+    math::spot_withdraw::validate_spot_market_vault_amount(
+        &quote_spot_market,
+        ctx.accounts.spot_market_vault.amount,
+    )?;
+
+    Ok(())
+}
+
+#[derive(Accounts)]
+pub struct InitializeBackstopVaultDepositor<'info> {
+    #[account(
+        init,
+        seeds = [b"backstop_vault_depositor", authority.key.as_ref()],
+        space = BackstopVaultDepositor::SIZE,
+        bump,
+        payer = payer
+    )]
+    pub backstop_vault_depositor: AccountLoader<'info, BackstopVaultDepositor>,
+    pub authority: Signer<'info>,
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    pub rent: Sysvar<'info, Rent>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct DepositIntoBackstopVault<'info> {
+    pub state: Box<Account<'info, State>>,
+    #[account(
+        mut,
+        seeds = [b"backstop_vault".as_ref()],
+        bump,
+        has_one = user
+    )]
+    pub backstop_vault: AccountLoader<'info, BackstopVault>,
+    #[account(
+        mut,
+        has_one = authority
+    )]
+    pub backstop_vault_depositor: AccountLoader<'info, BackstopVaultDepositor>,
+    #[account(mut)]
+    pub user: AccountLoader<'info, User>,
+    pub authority: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [b"spot_market_vault".as_ref(), QUOTE_SPOT_MARKET_INDEX.to_le_bytes().as_ref()],
+        bump,
+    )]
+    pub spot_market_vault: Box<Account<'info, TokenAccount>>,
+    #[account(
+        mut,
+        constraint = &spot_market_vault.mint.eq(&user_token_account.mint),
+        token::authority = authority
+    )]
+    pub user_token_account: Box<Account<'info, TokenAccount>>,
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct RequestWithdrawFromBackstopVault<'info> {
+    pub state: Box<Account<'info, State>>,
+    #[account(
+        seeds = [b"backstop_vault".as_ref()],
+        bump,
+        has_one = user
+    )]
+    pub backstop_vault: AccountLoader<'info, BackstopVault>,
+    #[account(
+        mut,
+        has_one = authority
+    )]
+    pub backstop_vault_depositor: AccountLoader<'info, BackstopVaultDepositor>,
+    pub user: AccountLoader<'info, User>,
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct WithdrawFromBackstopVault<'info> {
+    pub state: Box<Account<'info, State>>,
+    #[account(
+        mut,
+        seeds = [b"backstop_vault".as_ref()],
+        bump,
+        has_one = user
+    )]
+    pub backstop_vault: AccountLoader<'info, BackstopVault>,
+    #[account(
+        mut,
+        has_one = authority
+    )]
+    pub backstop_vault_depositor: AccountLoader<'info, BackstopVaultDepositor>,
+    #[account(mut)]
+    pub user: AccountLoader<'info, User>,
+    pub authority: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [b"spot_market_vault".as_ref(), QUOTE_SPOT_MARKET_INDEX.to_le_bytes().as_ref()],
+        bump,
+    )]
+    pub spot_market_vault: Box<Account<'info, TokenAccount>>,
+    #[account(
+        constraint = state.signer.eq(&drift_signer.key())
+    )]
+    /// CHECK: forced drift_signer
+    pub drift_signer: AccountInfo<'info>,
+    #[account(
+        mut,
+        constraint = &spot_market_vault.mint.eq(&user_token_account.mint)
+    )]
+    pub user_token_account: Box<Account<'info, TokenAccount>>,
+    pub token_program: Program<'info, Token>,
+}
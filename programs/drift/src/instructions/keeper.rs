@@ -1,20 +1,25 @@
 use anchor_lang::prelude::*;
 use anchor_spl::token::{Token, TokenAccount};
 
+use crate::controller::liquidation::LiquidationStep;
 use crate::error::ErrorCode;
 use crate::instructions::constraints::*;
 use crate::instructions::optional_accounts::{load_maps, AccountMaps};
 use crate::math::constants::QUOTE_SPOT_MARKET_INDEX;
 use crate::math::insurance::if_shares_to_vault_amount;
-use crate::math::margin::calculate_user_equity;
+use crate::math::margin::{
+    calculate_margin_requirement_and_total_collateral_and_liability_info, calculate_user_equity,
+};
 use crate::math::orders::{estimate_price_from_side, find_bids_and_asks_from_users};
 use crate::math::spot_withdraw::validate_spot_market_vault_amount;
 use crate::optional_accounts::update_prelaunch_oracle;
+use crate::state::backstop_vault::BackstopVault;
 use crate::state::fill_mode::FillMode;
 use crate::state::fulfillment_params::drift::MatchFulfillmentParams;
 use crate::state::fulfillment_params::phoenix::PhoenixFulfillmentParams;
 use crate::state::fulfillment_params::serum::SerumFulfillmentParams;
 use crate::state::insurance_fund_stake::InsuranceFundStake;
+use crate::state::margin_calculation::{MarginContext, MarginScope};
 use crate::state::oracle_map::OracleMap;
 use crate::state::paused_operations::PerpOperation;
 use crate::state::perp_market::{MarketStatus, PerpMarket};
@@ -29,7 +34,7 @@ use crate::state::spot_market_map::{
 };
 use crate::state::state::State;
 use crate::state::user::{MarketType, OrderStatus, User, UserStats};
-use crate::state::user_map::{load_user_maps, UserMap, UserStatsMap};
+use crate::state::user_map::{load_user_map, load_user_maps, UserMap, UserStatsMap};
 use crate::validation::user::validate_user_is_idle;
 use crate::{controller, load, math, OracleSource};
 use crate::{load_mut, QUOTE_PRECISION_U64};
@@ -344,6 +349,35 @@ pub fn handle_force_cancel_orders<'info>(ctx: Context<ForceCancelOrder>) -> Resu
     Ok(())
 }
 
+#[access_control(
+    exchange_not_paused(&ctx.accounts.state)
+)]
+pub fn handle_cancel_all_orders_after_ts<'info>(ctx: Context<ForceCancelOrder>) -> Result<()> {
+    let AccountMaps {
+        perp_market_map,
+        spot_market_map,
+        mut oracle_map,
+    } = load_maps(
+        &mut ctx.remaining_accounts.iter().peekable(),
+        &MarketSet::new(),
+        &MarketSet::new(),
+        Clock::get()?.slot,
+        None,
+    )?;
+
+    controller::orders::cancel_all_orders_after_ts(
+        &ctx.accounts.state,
+        &ctx.accounts.user,
+        &spot_market_map,
+        &perp_market_map,
+        &mut oracle_map,
+        &ctx.accounts.filler,
+        &Clock::get()?,
+    )?;
+
+    Ok(())
+}
+
 #[access_control(
     exchange_not_paused(&ctx.accounts.state)
 )]
@@ -376,6 +410,115 @@ pub fn handle_update_user_idle<'info>(ctx: Context<UpdateUserIdle>) -> Result<()
     Ok(())
 }
 
+#[access_control(
+    exchange_not_paused(&ctx.accounts.state)
+)]
+pub fn handle_update_user_margin_call<'info>(ctx: Context<UpdateUserIdle>) -> Result<()> {
+    let state = &ctx.accounts.state;
+    let user_key = ctx.accounts.user.key();
+    let mut user = load_mut!(ctx.accounts.user)?;
+    let clock = Clock::get()?;
+
+    let AccountMaps {
+        perp_market_map,
+        spot_market_map,
+        mut oracle_map,
+    } = load_maps(
+        &mut ctx.remaining_accounts.iter().peekable(),
+        &MarketSet::new(),
+        &MarketSet::new(),
+        clock.slot,
+        Some(state.oracle_guard_rails),
+    )?;
+
+    controller::orders::update_user_margin_call(
+        state,
+        &mut user,
+        &user_key,
+        &perp_market_map,
+        &spot_market_map,
+        &mut oracle_map,
+        clock.unix_timestamp,
+        clock.slot,
+    )?;
+
+    Ok(())
+}
+
+#[access_control(
+    exchange_not_paused(&ctx.accounts.state)
+)]
+pub fn handle_place_margin_call_order<'info>(
+    ctx: Context<ForceCancelOrder>,
+    market_index: u16,
+) -> Result<()> {
+    let state = &ctx.accounts.state;
+    let user_key = ctx.accounts.user.key();
+    let mut user = load_mut!(ctx.accounts.user)?;
+    let clock = Clock::get()?;
+
+    let AccountMaps {
+        perp_market_map,
+        spot_market_map,
+        mut oracle_map,
+    } = load_maps(
+        &mut ctx.remaining_accounts.iter().peekable(),
+        &get_writable_perp_market_set(market_index),
+        &MarketSet::new(),
+        clock.slot,
+        Some(state.oracle_guard_rails),
+    )?;
+
+    controller::orders::place_margin_call_order(
+        state,
+        &mut user,
+        user_key,
+        market_index,
+        &perp_market_map,
+        &spot_market_map,
+        &mut oracle_map,
+        &clock,
+    )?;
+
+    Ok(())
+}
+
+pub fn handle_log_user_stress_margin<'info>(ctx: Context<LogUserStressMargin>) -> Result<()> {
+    let user = load!(ctx.accounts.user)?;
+
+    let AccountMaps {
+        perp_market_map,
+        spot_market_map,
+        mut oracle_map,
+    } = load_maps(
+        &mut ctx.remaining_accounts.iter().peekable(),
+        &MarketSet::new(),
+        &MarketSet::new(),
+        Clock::get()?.slot,
+        None,
+    )?;
+
+    for shock_up in [false, true] {
+        let calculation = calculate_margin_requirement_and_total_collateral_and_liability_info(
+            &user,
+            &perp_market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            MarginContext::stress(shock_up),
+        )?;
+
+        msg!(
+            "stress shock_up={} total_collateral={} margin_requirement={} meets_margin_requirement={}",
+            shock_up,
+            calculation.total_collateral,
+            calculation.margin_requirement,
+            calculation.meets_margin_requirement()
+        );
+    }
+
+    Ok(())
+}
+
 #[access_control(
     exchange_not_paused(&ctx.accounts.state)
 )]
@@ -595,18 +738,36 @@ pub fn handle_liquidate_perp(
     let liquidator = &mut load_mut!(ctx.accounts.liquidator)?;
     let liquidator_stats = &mut load_mut!(ctx.accounts.liquidator_stats)?;
 
+    let remaining_accounts_iter = &mut ctx.remaining_accounts.iter().peekable();
     let AccountMaps {
         perp_market_map,
         spot_market_map,
         mut oracle_map,
     } = load_maps(
-        &mut ctx.remaining_accounts.iter().peekable(),
+        remaining_accounts_iter,
         &get_writable_perp_market_set(market_index),
         &MarketSet::new(),
         clock.slot,
         Some(state.oracle_guard_rails),
     )?;
 
+    if user_stats.is_sub_account_cross_margin_enabled {
+        let sub_accounts = load_user_map(remaining_accounts_iter, true)?;
+        if controller::sub_account_margin::cover_margin_shortage_from_sub_accounts(
+            user,
+            &user_key,
+            user_stats.number_of_sub_accounts,
+            &sub_accounts,
+            &perp_market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            state.liquidation_margin_buffer_ratio,
+            now,
+        )? {
+            return Ok(());
+        }
+    }
+
     controller::liquidation::liquidate_perp(
         market_index,
         liquidator_max_base_asset_amount,
@@ -651,20 +812,226 @@ pub fn handle_liquidate_spot(
     )?;
 
     let user = &mut load_mut!(ctx.accounts.user)?;
+    let user_stats = load!(ctx.accounts.user_stats)?;
     let liquidator = &mut load_mut!(ctx.accounts.liquidator)?;
 
+    let remaining_accounts_iter = &mut ctx.remaining_accounts.iter().peekable();
     let AccountMaps {
         perp_market_map,
         spot_market_map,
         mut oracle_map,
     } = load_maps(
-        &mut ctx.remaining_accounts.iter().peekable(),
+        remaining_accounts_iter,
         &MarketSet::new(),
         &get_writable_spot_market_set_from_many(vec![asset_market_index, liability_market_index]),
         clock.slot,
         Some(state.oracle_guard_rails),
     )?;
 
+    if user_stats.is_sub_account_cross_margin_enabled {
+        let sub_accounts = load_user_map(remaining_accounts_iter, true)?;
+        if controller::sub_account_margin::cover_margin_shortage_from_sub_accounts(
+            user,
+            &user_key,
+            user_stats.number_of_sub_accounts,
+            &sub_accounts,
+            &perp_market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            state.liquidation_margin_buffer_ratio,
+            now,
+        )? {
+            return Ok(());
+        }
+    }
+
+    controller::liquidation::liquidate_spot(
+        asset_market_index,
+        liability_market_index,
+        liquidator_max_liability_transfer,
+        limit_price,
+        user,
+        &user_key,
+        liquidator,
+        &liquidator_key,
+        &perp_market_map,
+        &spot_market_map,
+        &mut oracle_map,
+        now,
+        clock.slot,
+        state,
+    )?;
+
+    Ok(())
+}
+
+/// Liquidates a perp position with the backstop vault as the liquidator. Anyone can crank it once the
+/// user has been left in liquidation for the vault's liquidation delay
+#[access_control(
+    liq_not_paused(&ctx.accounts.state)
+)]
+pub fn handle_liquidate_perp_with_backstop_vault(
+    ctx: Context<LiquidatePerpWithBackstopVault>,
+    market_index: u16,
+    liquidator_max_base_asset_amount: u64,
+    limit_price: Option<u64>,
+) -> Result<()> {
+    let clock = Clock::get()?;
+    let now = clock.unix_timestamp;
+    let slot = clock.slot;
+    let state = &ctx.accounts.state;
+
+    let user_key = ctx.accounts.user.key();
+    let liquidator_key = ctx.accounts.liquidator.key();
+
+    validate!(
+        user_key != liquidator_key,
+        ErrorCode::UserCantLiquidateThemself
+    )?;
+
+    let backstop_vault = load!(ctx.accounts.backstop_vault)?;
+    let user = &mut load_mut!(ctx.accounts.user)?;
+    let user_stats = &mut load_mut!(ctx.accounts.user_stats)?;
+    let liquidator = &mut load_mut!(ctx.accounts.liquidator)?;
+    let liquidator_stats = &mut load_mut!(ctx.accounts.liquidator_stats)?;
+
+    let remaining_accounts_iter = &mut ctx.remaining_accounts.iter().peekable();
+    let AccountMaps {
+        perp_market_map,
+        spot_market_map,
+        mut oracle_map,
+    } = load_maps(
+        remaining_accounts_iter,
+        &get_writable_perp_market_set(market_index),
+        &MarketSet::new(),
+        clock.slot,
+        Some(state.oracle_guard_rails),
+    )?;
+
+    if user_stats.is_sub_account_cross_margin_enabled {
+        let sub_accounts = load_user_map(remaining_accounts_iter, true)?;
+        if controller::sub_account_margin::cover_margin_shortage_from_sub_accounts(
+            user,
+            &user_key,
+            user_stats.number_of_sub_accounts,
+            &sub_accounts,
+            &perp_market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            state.liquidation_margin_buffer_ratio,
+            now,
+        )? {
+            return Ok(());
+        }
+    }
+
+    let margin_scope = user.get_perp_margin_scope(market_index);
+    if !controller::backstop_vault::can_backstop_vault_liquidate(
+        user,
+        margin_scope,
+        &backstop_vault,
+        &perp_market_map,
+        &spot_market_map,
+        &mut oracle_map,
+        state.liquidation_margin_buffer_ratio,
+        slot,
+    )? {
+        return Ok(());
+    }
+
+    controller::liquidation::liquidate_perp(
+        market_index,
+        liquidator_max_base_asset_amount,
+        limit_price,
+        user,
+        &user_key,
+        user_stats,
+        liquidator,
+        &liquidator_key,
+        liquidator_stats,
+        &perp_market_map,
+        &spot_market_map,
+        &mut oracle_map,
+        slot,
+        now,
+        state,
+    )?;
+
+    Ok(())
+}
+
+/// Liquidates a spot borrow with the backstop vault as the liquidator, once the user has been left in
+/// liquidation for the vault's liquidation delay
+#[access_control(
+    liq_not_paused(&ctx.accounts.state)
+)]
+pub fn handle_liquidate_spot_with_backstop_vault(
+    ctx: Context<LiquidateSpotWithBackstopVault>,
+    asset_market_index: u16,
+    liability_market_index: u16,
+    liquidator_max_liability_transfer: u128,
+    limit_price: Option<u64>,
+) -> Result<()> {
+    let clock = Clock::get()?;
+    let now = clock.unix_timestamp;
+    let state = &ctx.accounts.state;
+
+    let user_key = ctx.accounts.user.key();
+    let liquidator_key = ctx.accounts.liquidator.key();
+
+    validate!(
+        user_key != liquidator_key,
+        ErrorCode::UserCantLiquidateThemself
+    )?;
+
+    let backstop_vault = load!(ctx.accounts.backstop_vault)?;
+    let user = &mut load_mut!(ctx.accounts.user)?;
+    let user_stats = load!(ctx.accounts.user_stats)?;
+    let liquidator = &mut load_mut!(ctx.accounts.liquidator)?;
+
+    let remaining_accounts_iter = &mut ctx.remaining_accounts.iter().peekable();
+    let AccountMaps {
+        perp_market_map,
+        spot_market_map,
+        mut oracle_map,
+    } = load_maps(
+        remaining_accounts_iter,
+        &MarketSet::new(),
+        &get_writable_spot_market_set_from_many(vec![asset_market_index, liability_market_index]),
+        clock.slot,
+        Some(state.oracle_guard_rails),
+    )?;
+
+    if user_stats.is_sub_account_cross_margin_enabled {
+        let sub_accounts = load_user_map(remaining_accounts_iter, true)?;
+        if controller::sub_account_margin::cover_margin_shortage_from_sub_accounts(
+            user,
+            &user_key,
+            user_stats.number_of_sub_accounts,
+            &sub_accounts,
+            &perp_market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            state.liquidation_margin_buffer_ratio,
+            now,
+        )? {
+            return Ok(());
+        }
+    }
+
+    if !controller::backstop_vault::can_backstop_vault_liquidate(
+        user,
+        MarginScope::Cross,
+        &backstop_vault,
+        &perp_market_map,
+        &spot_market_map,
+        &mut oracle_map,
+        state.liquidation_margin_buffer_ratio,
+        clock.slot,
+    )? {
+        return Ok(());
+    }
+
     controller::liquidation::liquidate_spot(
         asset_market_index,
         liability_market_index,
@@ -708,20 +1075,39 @@ pub fn handle_liquidate_borrow_for_perp_pnl(
     )?;
 
     let user = &mut load_mut!(ctx.accounts.user)?;
+    let user_stats = load!(ctx.accounts.user_stats)?;
     let liquidator = &mut load_mut!(ctx.accounts.liquidator)?;
 
+    let remaining_accounts_iter = &mut ctx.remaining_accounts.iter().peekable();
     let AccountMaps {
         perp_market_map,
         spot_market_map,
         mut oracle_map,
     } = load_maps(
-        &mut ctx.remaining_accounts.iter().peekable(),
+        remaining_accounts_iter,
         &MarketSet::new(),
         &get_writable_spot_market_set(spot_market_index),
         clock.slot,
         Some(state.oracle_guard_rails),
     )?;
 
+    if user_stats.is_sub_account_cross_margin_enabled {
+        let sub_accounts = load_user_map(remaining_accounts_iter, true)?;
+        if controller::sub_account_margin::cover_margin_shortage_from_sub_accounts(
+            user,
+            &user_key,
+            user_stats.number_of_sub_accounts,
+            &sub_accounts,
+            &perp_market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            state.liquidation_margin_buffer_ratio,
+            now,
+        )? {
+            return Ok(());
+        }
+    }
+
     controller::liquidation::liquidate_borrow_for_perp_pnl(
         perp_market_index,
         spot_market_index,
@@ -738,6 +1124,7 @@ pub fn handle_liquidate_borrow_for_perp_pnl(
         clock.slot,
         state.liquidation_margin_buffer_ratio,
         state.initial_pct_to_liquidate as u128,
+        state.initial_liquidator_fee_pct as u128,
         state.liquidation_duration as u128,
     )?;
 
@@ -767,20 +1154,39 @@ pub fn handle_liquidate_perp_pnl_for_deposit(
     )?;
 
     let user = &mut load_mut!(ctx.accounts.user)?;
+    let user_stats = load!(ctx.accounts.user_stats)?;
     let liquidator = &mut load_mut!(ctx.accounts.liquidator)?;
 
+    let remaining_accounts_iter = &mut ctx.remaining_accounts.iter().peekable();
     let AccountMaps {
         perp_market_map,
         spot_market_map,
         mut oracle_map,
     } = load_maps(
-        &mut ctx.remaining_accounts.iter().peekable(),
+        remaining_accounts_iter,
         &MarketSet::new(),
         &get_writable_spot_market_set(spot_market_index),
         clock.slot,
         Some(state.oracle_guard_rails),
     )?;
 
+    if user_stats.is_sub_account_cross_margin_enabled {
+        let sub_accounts = load_user_map(remaining_accounts_iter, true)?;
+        if controller::sub_account_margin::cover_margin_shortage_from_sub_accounts(
+            user,
+            &user_key,
+            user_stats.number_of_sub_accounts,
+            &sub_accounts,
+            &perp_market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            state.liquidation_margin_buffer_ratio,
+            now,
+        )? {
+            return Ok(());
+        }
+    }
+
     controller::liquidation::liquidate_perp_pnl_for_deposit(
         perp_market_index,
         spot_market_index,
@@ -797,12 +1203,102 @@ pub fn handle_liquidate_perp_pnl_for_deposit(
         clock.slot,
         state.liquidation_margin_buffer_ratio,
         state.initial_pct_to_liquidate as u128,
+        state.initial_liquidator_fee_pct as u128,
         state.liquidation_duration as u128,
     )?;
 
     Ok(())
 }
 
+#[access_control(
+    liq_not_paused(&ctx.accounts.state)
+)]
+pub fn handle_liquidate_user(
+    ctx: Context<LiquidatePerp>,
+    steps: Vec<LiquidationStep>,
+) -> Result<()> {
+    let clock = Clock::get()?;
+    let now = clock.unix_timestamp;
+    let slot = clock.slot;
+    let state = &ctx.accounts.state;
+
+    let user_key = ctx.accounts.user.key();
+    let liquidator_key = ctx.accounts.liquidator.key();
+
+    validate!(
+        user_key != liquidator_key,
+        ErrorCode::UserCantLiquidateThemself
+    )?;
+
+    let user = &mut load_mut!(ctx.accounts.user)?;
+    let user_stats = &mut load_mut!(ctx.accounts.user_stats)?;
+    let liquidator = &mut load_mut!(ctx.accounts.liquidator)?;
+    let liquidator_stats = &mut load_mut!(ctx.accounts.liquidator_stats)?;
+
+    let writable_perp_markets: MarketSet = user
+        .perp_positions
+        .iter()
+        .filter(|position| !position.is_available())
+        .map(|position| position.market_index)
+        .collect();
+
+    let writable_spot_markets = get_writable_spot_market_set_from_many(
+        user.spot_positions
+            .iter()
+            .filter(|position| !position.is_available())
+            .map(|position| position.market_index)
+            .collect(),
+    );
+
+    let remaining_accounts_iter = &mut ctx.remaining_accounts.iter().peekable();
+    let AccountMaps {
+        perp_market_map,
+        spot_market_map,
+        mut oracle_map,
+    } = load_maps(
+        remaining_accounts_iter,
+        &writable_perp_markets,
+        &writable_spot_markets,
+        slot,
+        Some(state.oracle_guard_rails),
+    )?;
+
+    if user_stats.is_sub_account_cross_margin_enabled {
+        let sub_accounts = load_user_map(remaining_accounts_iter, true)?;
+        if controller::sub_account_margin::cover_margin_shortage_from_sub_accounts(
+            user,
+            &user_key,
+            user_stats.number_of_sub_accounts,
+            &sub_accounts,
+            &perp_market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            state.liquidation_margin_buffer_ratio,
+            now,
+        )? {
+            return Ok(());
+        }
+    }
+
+    controller::liquidation::liquidate_user(
+        steps,
+        user,
+        &user_key,
+        user_stats,
+        liquidator,
+        &liquidator_key,
+        liquidator_stats,
+        &perp_market_map,
+        &spot_market_map,
+        &mut oracle_map,
+        slot,
+        now,
+        state,
+    )?;
+
+    Ok(())
+}
+
 #[access_control(
     withdraw_not_paused(&ctx.accounts.state)
 )]
@@ -997,6 +1493,7 @@ pub fn handle_resolve_perp_bankruptcy(
         &spot_market_map,
         &mut oracle_map,
         now,
+        clock.slot,
         ctx.accounts.insurance_fund_vault.amount,
     )?;
 
@@ -1038,6 +1535,53 @@ pub fn handle_resolve_perp_bankruptcy(
     Ok(())
 }
 
+#[access_control(
+    liq_not_paused(&ctx.accounts.state)
+)]
+pub fn handle_auto_deleverage_perp(
+    ctx: Context<AutoDeleveragePerp>,
+    market_index: u16,
+) -> Result<()> {
+    let clock = Clock::get()?;
+    let now = clock.unix_timestamp;
+    let state = &ctx.accounts.state;
+
+    let user_key = ctx.accounts.user.key();
+    let user = &mut load_mut!(ctx.accounts.user)?;
+
+    let remaining_accounts_iter = &mut ctx.remaining_accounts.iter().peekable();
+    let AccountMaps {
+        perp_market_map,
+        spot_market_map,
+        mut oracle_map,
+    } = load_maps(
+        remaining_accounts_iter,
+        &get_writable_perp_market_set(market_index),
+        &get_writable_spot_market_set(QUOTE_SPOT_MARKET_INDEX),
+        clock.slot,
+        Some(state.oracle_guard_rails),
+    )?;
+
+    // profitable users on the other side of the position
+    let adl_users = load_user_map(remaining_accounts_iter, true)?;
+
+    controller::liquidation::auto_deleverage_perp(
+        market_index,
+        user,
+        &user_key,
+        &adl_users,
+        &perp_market_map,
+        &spot_market_map,
+        &mut oracle_map,
+        now,
+        clock.slot,
+        ctx.accounts.insurance_fund_vault.amount,
+        state,
+    )?;
+
+    Ok(())
+}
+
 #[access_control(
     withdraw_not_paused(&ctx.accounts.state)
 )]
@@ -1103,6 +1647,7 @@ pub fn handle_resolve_spot_bankruptcy(
         &spot_market_map,
         &mut oracle_map,
         now,
+        clock.slot,
         ctx.accounts.insurance_fund_vault.amount,
     )?;
 
@@ -1546,6 +2091,12 @@ pub struct UpdateUserIdle<'info> {
     pub user: AccountLoader<'info, User>,
 }
 
+#[derive(Accounts)]
+pub struct LogUserStressMargin<'info> {
+    pub state: Box<Account<'info, State>>,
+    pub user: AccountLoader<'info, User>,
+}
+
 #[derive(Accounts)]
 pub struct SettlePNL<'info> {
     pub state: Box<Account<'info, State>>,
@@ -1619,6 +2170,58 @@ pub struct LiquidateSpot<'info> {
     pub user_stats: AccountLoader<'info, UserStats>,
 }
 
+#[derive(Accounts)]
+pub struct LiquidatePerpWithBackstopVault<'info> {
+    pub state: Box<Account<'info, State>>,
+    pub authority: Signer<'info>,
+    #[account(
+        seeds = [b"backstop_vault".as_ref()],
+        bump,
+        constraint = backstop_vault.load()?.user.eq(&liquidator.key())
+    )]
+    pub backstop_vault: AccountLoader<'info, BackstopVault>,
+    #[account(mut)]
+    pub liquidator: AccountLoader<'info, User>,
+    #[account(
+        mut,
+        constraint = is_stats_for_user(&liquidator, &liquidator_stats)?
+    )]
+    pub liquidator_stats: AccountLoader<'info, UserStats>,
+    #[account(mut)]
+    pub user: AccountLoader<'info, User>,
+    #[account(
+        mut,
+        constraint = is_stats_for_user(&user, &user_stats)?
+    )]
+    pub user_stats: AccountLoader<'info, UserStats>,
+}
+
+#[derive(Accounts)]
+pub struct LiquidateSpotWithBackstopVault<'info> {
+    pub state: Box<Account<'info, State>>,
+    pub authority: Signer<'info>,
+    #[account(
+        seeds = [b"backstop_vault".as_ref()],
+        bump,
+        constraint = backstop_vault.load()?.user.eq(&liquidator.key())
+    )]
+    pub backstop_vault: AccountLoader<'info, BackstopVault>,
+    #[account(mut)]
+    pub liquidator: AccountLoader<'info, User>,
+    #[account(
+        mut,
+        constraint = is_stats_for_user(&liquidator, &liquidator_stats)?
+    )]
+    pub liquidator_stats: AccountLoader<'info, UserStats>,
+    #[account(mut)]
+    pub user: AccountLoader<'info, User>,
+    #[account(
+        mut,
+        constraint = is_stats_for_user(&user, &user_stats)?
+    )]
+    pub user_stats: AccountLoader<'info, UserStats>,
+}
+
 #[derive(Accounts)]
 pub struct LiquidateBorrowForPerpPnl<'info> {
     pub state: Box<Account<'info, State>>,
@@ -1707,6 +2310,22 @@ pub struct ResolveBankruptcy<'info> {
     pub token_program: Program<'info, Token>,
 }
 
+#[derive(Accounts)]
+pub struct AutoDeleveragePerp<'info> {
+    #[account(
+        has_one = admin
+    )]
+    pub state: Box<Account<'info, State>>,
+    pub admin: Signer<'info>,
+    #[account(mut)]
+    pub user: AccountLoader<'info, User>,
+    #[account(
+        seeds = [b"insurance_fund_vault".as_ref(), QUOTE_SPOT_MARKET_INDEX.to_le_bytes().as_ref()],
+        bump,
+    )]
+    pub insurance_fund_vault: Box<Account<'info, TokenAccount>>,
+}
+
 #[derive(Accounts)]
 #[instruction(spot_market_index: u16,)]
 pub struct ResolvePerpPnlDeficit<'info> {
@@ -11,7 +11,7 @@ use crate::controller::spot_position::{
     charge_withdraw_fee, update_spot_balances_and_cumulative_deposits,
     update_spot_balances_and_cumulative_deposits_with_limits,
 };
-use crate::error::ErrorCode;
+use crate::error::{DriftResult, ErrorCode};
 use crate::ids::{
     jupiter_mainnet_3, jupiter_mainnet_4, jupiter_mainnet_6, marinade_mainnet, serum_program,
 };
@@ -45,7 +45,7 @@ use crate::state::fulfillment_params::phoenix::PhoenixFulfillmentParams;
 use crate::state::fulfillment_params::serum::SerumFulfillmentParams;
 use crate::state::oracle::StrictOraclePrice;
 use crate::state::order_params::{
-    ModifyOrderParams, OrderParams, PlaceOrderOptions, PostOnlyParam,
+    ModifyOrderByIdParams, ModifyOrderParams, OrderParams, PlaceOrderOptions, PostOnlyParam,
 };
 use crate::state::paused_operations::PerpOperation;
 use crate::state::perp_market::MarketStatus;
@@ -59,7 +59,7 @@ use crate::state::spot_market_map::{
 use crate::state::state::State;
 use crate::state::traits::Size;
 use crate::state::user::{MarketType, OrderType, ReferrerName, User, UserStats};
-use crate::state::user_map::{load_user_maps, UserMap, UserStatsMap};
+use crate::state::user_map::{load_user_map, load_user_maps, UserMap, UserStatsMap};
 use crate::validate;
 use crate::validation::user::validate_user_deletion;
 use crate::validation::whitelist::validate_whitelist_token;
@@ -778,6 +778,51 @@ pub fn handle_transfer_deposit(
 #[access_control(
     exchange_not_paused(&ctx.accounts.state)
 )]
+pub fn handle_transfer_isolated_perp_position_deposit(
+    ctx: Context<TransferIsolatedPerpPositionDeposit>,
+    perp_market_index: u16,
+    amount: i64,
+) -> Result<()> {
+    let state = &ctx.accounts.state;
+    let clock = Clock::get()?;
+    let user = &mut load_mut!(ctx.accounts.user)?;
+
+    let AccountMaps {
+        perp_market_map,
+        spot_market_map,
+        mut oracle_map,
+    } = load_maps(
+        &mut ctx.remaining_accounts.iter().peekable(),
+        &MarketSet::new(),
+        &get_writable_spot_market_set(QUOTE_SPOT_MARKET_INDEX),
+        clock.slot,
+        Some(state.oracle_guard_rails),
+    )?;
+
+    {
+        let quote_spot_market = &mut spot_market_map.get_quote_spot_market_mut()?;
+        let oracle_price_data = oracle_map.get_price_data(&quote_spot_market.oracle)?;
+        controller::spot_balance::update_spot_market_cumulative_interest(
+            quote_spot_market,
+            Some(oracle_price_data),
+            clock.unix_timestamp,
+        )?;
+    }
+
+    controller::isolated_position::transfer_isolated_perp_position_deposit(
+        user,
+        perp_market_index,
+        amount,
+        &perp_market_map,
+        &spot_market_map,
+        &mut oracle_map,
+    )?;
+
+    user.update_last_active_slot(clock.slot);
+
+    Ok(())
+}
+
 pub fn handle_place_perp_order(ctx: Context<PlaceOrder>, params: OrderParams) -> Result<()> {
     let clock = &Clock::get()?;
     let state = &ctx.accounts.state;
@@ -799,6 +844,11 @@ pub fn handle_place_perp_order(ctx: Context<PlaceOrder>, params: OrderParams) ->
         return Err(print_error!(ErrorCode::InvalidOrderIOC)().into());
     }
 
+    if params.has_min_fill() {
+        msg!("fill_or_kill and min_fill_base_asset_amount must be in place_and_take");
+        return Err(print_error!(ErrorCode::InvalidOrderMinFill)().into());
+    }
+
     let user_key = ctx.accounts.user.key();
     let mut user = load_mut!(ctx.accounts.user)?;
 
@@ -811,7 +861,7 @@ pub fn handle_place_perp_order(ctx: Context<PlaceOrder>, params: OrderParams) ->
         &mut oracle_map,
         clock,
         params,
-        PlaceOrderOptions::default(),
+        &mut PlaceOrderOptions::default(),
     )?;
 
     Ok(())
@@ -1041,6 +1091,47 @@ pub fn handle_modify_order_by_user_order_id(
     Ok(())
 }
 
+#[access_control(
+    exchange_not_paused(&ctx.accounts.state)
+)]
+pub fn handle_modify_orders(
+    ctx: Context<CancelOrder>,
+    modify_orders_params: Vec<ModifyOrderByIdParams>,
+) -> Result<()> {
+    let clock = &Clock::get()?;
+    let state = &ctx.accounts.state;
+
+    let AccountMaps {
+        perp_market_map,
+        spot_market_map,
+        mut oracle_map,
+    } = load_maps(
+        &mut ctx.remaining_accounts.iter().peekable(),
+        &MarketSet::new(),
+        &MarketSet::new(),
+        clock.slot,
+        Some(state.oracle_guard_rails),
+    )?;
+
+    validate!(
+        modify_orders_params.len() <= 32,
+        ErrorCode::DefaultError,
+        "max 32 modify order params"
+    )?;
+
+    controller::orders::modify_orders(
+        modify_orders_params,
+        &ctx.accounts.user,
+        state,
+        &perp_market_map,
+        &spot_market_map,
+        &mut oracle_map,
+        clock,
+    )?;
+
+    Ok(())
+}
+
 #[access_control(
     exchange_not_paused(&ctx.accounts.state)
 )]
@@ -1069,48 +1160,67 @@ pub fn handle_place_orders(ctx: Context<PlaceOrder>, params: Vec<OrderParams>) -
     let user_key = ctx.accounts.user.key();
     let mut user = load_mut!(ctx.accounts.user)?;
 
-    let num_orders = params.len();
-    for (i, params) in params.iter().enumerate() {
-        validate!(
-            !params.immediate_or_cancel,
-            ErrorCode::InvalidOrderIOC,
-            "immediate_or_cancel order must be in place_and_make or place_and_take"
-        )?;
+    controller::orders::place_orders(
+        state,
+        &mut user,
+        user_key,
+        &perp_market_map,
+        &spot_market_map,
+        &mut oracle_map,
+        clock,
+        &params,
+    )?;
 
-        // only enforce margin on last order and only try to expire on first order
-        let options = PlaceOrderOptions {
-            enforce_margin_check: i == num_orders - 1,
-            try_expire_orders: i == 0,
-            risk_increasing: false,
-            explanation: OrderActionExplanation::None,
-        };
+    Ok(())
+}
 
-        if params.market_type == MarketType::Perp {
-            controller::orders::place_perp_order(
-                &ctx.accounts.state,
-                &mut user,
-                user_key,
-                &perp_market_map,
-                &spot_market_map,
-                &mut oracle_map,
-                clock,
-                *params,
-                options,
-            )?;
-        } else {
-            controller::orders::place_spot_order(
-                &ctx.accounts.state,
-                &mut user,
-                user_key,
-                &perp_market_map,
-                &spot_market_map,
-                &mut oracle_map,
-                clock,
-                *params,
-                options,
-            )?;
-        }
-    }
+#[access_control(
+    exchange_not_paused(&ctx.accounts.state)
+)]
+pub fn handle_cancel_and_place_orders(
+    ctx: Context<PlaceOrder>,
+    market_type: MarketType,
+    market_index: u16,
+    direction: Option<PositionDirection>,
+    params: Vec<OrderParams>,
+) -> Result<()> {
+    let clock = &Clock::get()?;
+    let state = &ctx.accounts.state;
+
+    let AccountMaps {
+        perp_market_map,
+        spot_market_map,
+        mut oracle_map,
+    } = load_maps(
+        &mut ctx.remaining_accounts.iter().peekable(),
+        &MarketSet::new(),
+        &MarketSet::new(),
+        clock.slot,
+        Some(state.oracle_guard_rails),
+    )?;
+
+    validate!(
+        params.len() <= 32,
+        ErrorCode::DefaultError,
+        "max 32 order params"
+    )?;
+
+    let user_key = ctx.accounts.user.key();
+    let mut user = load_mut!(ctx.accounts.user)?;
+
+    controller::orders::cancel_and_place_orders(
+        state,
+        &mut user,
+        user_key,
+        &perp_market_map,
+        &spot_market_map,
+        &mut oracle_map,
+        clock,
+        market_type,
+        market_index,
+        direction,
+        &params,
+    )?;
 
     Ok(())
 }
@@ -1147,7 +1257,10 @@ pub fn handle_place_and_take_perp_order<'info>(
     let (makers_and_referrer, makers_and_referrer_stats) =
         load_user_maps(remaining_accounts_iter, true)?;
 
-    let is_immediate_or_cancel = params.immediate_or_cancel;
+    if params.min_fill_base_asset_amount.is_some() && !params.immediate_or_cancel {
+        msg!("min_fill_base_asset_amount must be used with immediate_or_cancel");
+        return Err(print_error!(ErrorCode::InvalidOrderMinFill)().into());
+    }
 
     controller::repeg::update_amm(
         params.market_index,
@@ -1157,59 +1270,102 @@ pub fn handle_place_and_take_perp_order<'info>(
         &Clock::get()?,
     )?;
 
-    let user_key = ctx.accounts.user.key();
-    let mut user = load_mut!(ctx.accounts.user)?;
-
-    controller::orders::place_perp_order(
+    controller::orders::place_and_take_perp_order(
         &ctx.accounts.state,
-        &mut user,
-        user_key,
+        &ctx.accounts.user,
+        &ctx.accounts.user_stats,
         &perp_market_map,
         &spot_market_map,
         &mut oracle_map,
+        &makers_and_referrer,
+        &makers_and_referrer_stats,
         &Clock::get()?,
         params,
-        PlaceOrderOptions::default(),
     )?;
 
-    drop(user);
+    Ok(())
+}
 
-    let user = &mut ctx.accounts.user;
-    let order_id = load!(user)?.get_last_order_id();
+fn get_min_fill_base_asset_amount(
+    user: &AccountLoader<User>,
+    order_id: u32,
+    params: &OrderParams,
+) -> DriftResult<Option<u64>> {
+    if !params.has_min_fill() {
+        return Ok(None);
+    }
 
-    controller::orders::fill_perp_order(
-        order_id,
-        &ctx.accounts.state,
-        user,
-        &ctx.accounts.user_stats,
-        &spot_market_map,
-        &perp_market_map,
-        &mut oracle_map,
-        &user.clone(),
-        &ctx.accounts.user_stats.clone(),
-        &makers_and_referrer,
-        &makers_and_referrer_stats,
-        None,
-        &Clock::get()?,
-        FillMode::PlaceAndTake,
-    )?;
+    let user = load!(user)?;
+    let order = user
+        .get_order(order_id)
+        .ok_or_else(print_error!(ErrorCode::OrderDoesNotExist))?;
 
-    let order_exists = load!(ctx.accounts.user)?
-        .orders
-        .iter()
-        .any(|order| order.order_id == order_id);
+    Ok(params.get_min_fill_base_asset_amount(order.base_asset_amount))
+}
 
-    if is_immediate_or_cancel && order_exists {
-        controller::orders::cancel_order_by_order_id(
-            order_id,
-            &ctx.accounts.user,
+#[access_control(
+    liq_not_paused(&ctx.accounts.state)
+)]
+pub fn handle_self_liquidate_perp(
+    ctx: Context<PlaceAndTake>,
+    market_index: u16,
+    max_base_asset_amount: u64,
+    limit_price: Option<u64>,
+) -> Result<()> {
+    let clock = Clock::get()?;
+    let now = clock.unix_timestamp;
+    let slot = clock.slot;
+    let state = &ctx.accounts.state;
+
+    let user_key = ctx.accounts.user.key();
+    let user = &mut load_mut!(ctx.accounts.user)?;
+    let user_stats = &mut load_mut!(ctx.accounts.user_stats)?;
+
+    let remaining_accounts_iter = &mut ctx.remaining_accounts.iter().peekable();
+    let AccountMaps {
+        perp_market_map,
+        spot_market_map,
+        mut oracle_map,
+    } = load_maps(
+        remaining_accounts_iter,
+        &get_writable_perp_market_set(market_index),
+        &MarketSet::new(),
+        slot,
+        Some(state.oracle_guard_rails),
+    )?;
+
+    if user_stats.is_sub_account_cross_margin_enabled {
+        let sub_accounts = load_user_map(remaining_accounts_iter, true)?;
+        if controller::sub_account_margin::cover_margin_shortage_from_sub_accounts(
+            user,
+            &user_key,
+            user_stats.number_of_sub_accounts,
+            &sub_accounts,
             &perp_market_map,
             &spot_market_map,
             &mut oracle_map,
-            &Clock::get()?,
-        )?;
+            state.liquidation_margin_buffer_ratio,
+            now,
+        )? {
+            return Ok(());
+        }
     }
 
+    controller::liquidation::self_liquidate_perp(
+        market_index,
+        max_base_asset_amount,
+        limit_price,
+        user,
+        &user_key,
+        user_stats,
+        &perp_market_map,
+        &spot_market_map,
+        &mut oracle_map,
+        slot,
+        now,
+        state,
+    )?;
+
     Ok(())
 }
 
@@ -1245,6 +1401,11 @@ pub fn handle_place_and_make_perp_order<'a, 'b, 'c, 'info>(
         return Err(print_error!(ErrorCode::InvalidOrderIOCPostOnly)().into());
     }
 
+    if params.has_min_fill() {
+        msg!("fill_or_kill and min_fill_base_asset_amount must be in place_and_take");
+        return Err(print_error!(ErrorCode::InvalidOrderMinFill)().into());
+    }
+
     controller::repeg::update_amm(
         params.market_index,
         &perp_market_map,
@@ -1265,7 +1426,7 @@ pub fn handle_place_and_make_perp_order<'a, 'b, 'c, 'info>(
         &mut oracle_map,
         clock,
         params,
-        PlaceOrderOptions::default(),
+        &mut PlaceOrderOptions::default(),
     )?;
 
     let (order_id, authority) = (user.get_last_order_id(), user.authority);
@@ -1331,6 +1492,11 @@ pub fn handle_place_spot_order(ctx: Context<PlaceOrder>, params: OrderParams) ->
         return Err(print_error!(ErrorCode::InvalidOrderIOC)().into());
     }
 
+    if params.has_min_fill() {
+        msg!("fill_or_kill and min_fill_base_asset_amount must be in place_and_take");
+        return Err(print_error!(ErrorCode::InvalidOrderMinFill)().into());
+    }
+
     let user_key = ctx.accounts.user.key();
     let mut user = load_mut!(ctx.accounts.user)?;
 
@@ -1343,7 +1509,7 @@ pub fn handle_place_spot_order(ctx: Context<PlaceOrder>, params: OrderParams) ->
         &mut oracle_map,
         &Clock::get()?,
         params,
-        PlaceOrderOptions::default(),
+        &mut PlaceOrderOptions::default(),
     )?;
 
     Ok(())
@@ -1384,7 +1550,12 @@ pub fn handle_place_and_take_spot_order<'info>(
         _ => (UserMap::empty(), UserStatsMap::empty()),
     };
 
-    let is_immediate_or_cancel = params.immediate_or_cancel;
+    if params.min_fill_base_asset_amount.is_some() && !params.immediate_or_cancel {
+        msg!("min_fill_base_asset_amount must be used with immediate_or_cancel");
+        return Err(print_error!(ErrorCode::InvalidOrderMinFill)().into());
+    }
+
+    let is_immediate_or_cancel = params.immediate_or_cancel || params.fill_or_kill;
 
     let mut fulfillment_params: Box<dyn SpotFulfillmentParams> = match fulfillment_type {
         SpotFulfillmentType::SerumV3 => {
@@ -1431,15 +1602,16 @@ pub fn handle_place_and_take_spot_order<'info>(
         &mut oracle_map,
         &clock,
         params,
-        PlaceOrderOptions::default(),
+        &mut PlaceOrderOptions::default(),
     )?;
 
     drop(user);
 
     let user = &mut ctx.accounts.user;
     let order_id = load!(user)?.get_last_order_id();
+    let min_fill_base_asset_amount = get_min_fill_base_asset_amount(user, order_id, &params)?;
 
-    controller::orders::fill_spot_order(
+    let base_asset_amount_filled = controller::orders::fill_spot_order(
         order_id,
         &ctx.accounts.state,
         user,
@@ -1456,6 +1628,16 @@ pub fn handle_place_and_take_spot_order<'info>(
         fulfillment_params.as_mut(),
     )?;
 
+    if let Some(min_fill_base_asset_amount) = min_fill_base_asset_amount {
+        validate!(
+            base_asset_amount_filled >= min_fill_base_asset_amount,
+            ErrorCode::OrderFillBelowMinimum,
+            "base_asset_amount_filled {} < min_fill_base_asset_amount {}",
+            base_asset_amount_filled,
+            min_fill_base_asset_amount
+        )?;
+    }
+
     let order_exists = load!(ctx.accounts.user)?
         .orders
         .iter()
@@ -1514,6 +1696,11 @@ pub fn handle_place_and_make_spot_order<'info>(
         return Err(print_error!(ErrorCode::InvalidOrderIOCPostOnly)().into());
     }
 
+    if params.has_min_fill() {
+        msg!("fill_or_kill and min_fill_base_asset_amount must be in place_and_take");
+        return Err(print_error!(ErrorCode::InvalidOrderMinFill)().into());
+    }
+
     let market_index = params.market_index;
 
     let mut fulfillment_params: Box<dyn SpotFulfillmentParams> = match fulfillment_type {
@@ -1562,7 +1749,7 @@ pub fn handle_place_and_make_spot_order<'info>(
         &mut oracle_map,
         clock,
         params,
-        PlaceOrderOptions::default(),
+        &mut PlaceOrderOptions::default(),
     )?;
 
     drop(user);
@@ -1826,6 +2013,34 @@ pub fn handle_update_user_custom_margin_ratio(
     Ok(())
 }
 
+pub fn handle_update_user_market_max_leverage(
+    ctx: Context<UpdateUser>,
+    _sub_account_id: u16,
+    market_type: MarketType,
+    market_index: u16,
+    max_leverage: u8,
+) -> Result<()> {
+    let mut user = load_mut!(ctx.accounts.user)?;
+
+    validate!(
+        market_type != MarketType::Spot || market_index != QUOTE_SPOT_MARKET_INDEX,
+        ErrorCode::InvalidSpotPosition,
+        "cant set max leverage for quote spot market"
+    )?;
+
+    msg!(
+        "{} market {} max leverage {} -> {}",
+        market_type,
+        market_index,
+        user.get_market_max_leverage(market_type, market_index),
+        max_leverage
+    );
+
+    user.update_market_max_leverage(market_type, market_index, max_leverage)?;
+
+    Ok(())
+}
+
 pub fn handle_update_user_margin_trading_enabled(
     ctx: Context<UpdateUser>,
     _sub_account_id: u16,
@@ -1890,6 +2105,48 @@ pub fn handle_update_user_advanced_lp(
     Ok(())
 }
 
+pub fn handle_update_user_cancel_all_after_ts(
+    ctx: Context<UpdateUser>,
+    _sub_account_id: u16,
+    cancel_all_after_ts: i64,
+) -> Result<()> {
+    let mut user = load_mut!(ctx.accounts.user)?;
+    let now = Clock::get()?.unix_timestamp;
+
+    validate!(
+        cancel_all_after_ts == 0 || cancel_all_after_ts > now,
+        ErrorCode::DefaultError,
+        "cancel_all_after_ts {} must be 0 or in the future (now {})",
+        cancel_all_after_ts,
+        now
+    )?;
+
+    user.cancel_all_after_ts = cancel_all_after_ts;
+    Ok(())
+}
+
+pub fn handle_update_user_stats_sub_account_cross_margin(
+    ctx: Context<UpdateUserStats>,
+    enabled: bool,
+) -> Result<()> {
+    let mut user_stats = load_mut!(ctx.accounts.user_stats)?;
+
+    msg!(
+        "sub account cross margin {} -> {}",
+        user_stats.is_sub_account_cross_margin_enabled,
+        enabled
+    );
+
+    user_stats.is_sub_account_cross_margin_enabled = enabled;
+    Ok(())
+}
+
+pub fn handle_resize_user(ctx: Context<ResizeUser>) -> Result<()> {
+    // the realloc constraint grew the account, make sure it loads at the new size
+    load!(ctx.accounts.user)?;
+    Ok(())
+}
+
 pub fn handle_delete_user(ctx: Context<DeleteUser>) -> Result<()> {
     let user = &load!(ctx.accounts.user)?;
     let user_stats = &mut load_mut!(ctx.accounts.user_stats)?;
@@ -2183,6 +2440,17 @@ pub struct TransferDeposit<'info> {
     pub spot_market_vault: Box<Account<'info, TokenAccount>>,
 }
 
+#[derive(Accounts)]
+pub struct TransferIsolatedPerpPositionDeposit<'info> {
+    #[account(
+        mut,
+        has_one = authority,
+    )]
+    pub user: AccountLoader<'info, User>,
+    pub authority: Signer<'info>,
+    pub state: Box<Account<'info, State>>,
+}
+
 #[derive(Accounts)]
 pub struct PlaceOrder<'info> {
     pub state: Box<Account<'info, State>>,
@@ -2276,6 +2544,16 @@ pub struct UpdateUser<'info> {
     pub authority: Signer<'info>,
 }
 
+#[derive(Accounts)]
+pub struct UpdateUserStats<'info> {
+    #[account(
+        mut,
+        has_one = authority
+    )]
+    pub user_stats: AccountLoader<'info, UserStats>,
+    pub authority: Signer<'info>,
+}
+
 #[derive(Accounts)]
 pub struct DeleteUser<'info> {
     #[account(
@@ -2294,6 +2572,21 @@ pub struct DeleteUser<'info> {
     pub authority: Signer<'info>,
 }
 
+#[derive(Accounts)]
+pub struct ResizeUser<'info> {
+    /// Accounts created before the user account grew can't be loaded until they're resized
+    #[account(
+        mut,
+        realloc = User::SIZE,
+        realloc::payer = payer,
+        realloc::zero = true,
+    )]
+    pub user: AccountLoader<'info, User>,
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
 #[derive(Accounts)]
 pub struct ReclaimRent<'info> {
     #[account(
@@ -10,9 +10,10 @@ use math::amm;
 use math::{bn, constants::*};
 use state::oracle::OracleSource;
 
+use crate::controller::liquidation::LiquidationStep;
 use crate::controller::position::PositionDirection;
 use crate::state::oracle::PrelaunchOracleParams;
-use crate::state::order_params::{ModifyOrderParams, OrderParams};
+use crate::state::order_params::{ModifyOrderByIdParams, ModifyOrderParams, OrderParams};
 use crate::state::perp_market::{ContractTier, MarketStatus};
 use crate::state::spot_market::AssetTier;
 use crate::state::spot_market::SpotFulfillmentConfigStatus;
@@ -89,6 +90,14 @@ pub mod drift {
         handle_transfer_deposit(ctx, market_index, amount)
     }
 
+    pub fn transfer_isolated_perp_position_deposit(
+        ctx: Context<TransferIsolatedPerpPositionDeposit>,
+        perp_market_index: u16,
+        amount: i64,
+    ) -> Result<()> {
+        handle_transfer_isolated_perp_position_deposit(ctx, perp_market_index, amount)
+    }
+
     pub fn place_perp_order(ctx: Context<PlaceOrder>, params: OrderParams) -> Result<()> {
         handle_place_perp_order(ctx, params)
     }
@@ -130,6 +139,13 @@ pub mod drift {
         handle_modify_order_by_user_order_id(ctx, user_order_id, modify_order_params)
     }
 
+    pub fn modify_orders(
+        ctx: Context<CancelOrder>,
+        modify_orders_params: Vec<ModifyOrderByIdParams>,
+    ) -> Result<()> {
+        handle_modify_orders(ctx, modify_orders_params)
+    }
+
     pub fn place_and_take_perp_order(
         ctx: Context<PlaceAndTake>,
         params: OrderParams,
@@ -138,6 +154,15 @@ pub mod drift {
         handle_place_and_take_perp_order(ctx, params, maker_order_id)
     }
 
+    pub fn self_liquidate_perp(
+        ctx: Context<PlaceAndTake>,
+        market_index: u16,
+        max_base_asset_amount: u64,
+        limit_price: Option<u64>,
+    ) -> Result<()> {
+        handle_self_liquidate_perp(ctx, market_index, max_base_asset_amount, limit_price)
+    }
+
     pub fn place_and_make_perp_order<'info>(
         ctx: Context<'_, '_, '_, 'info, PlaceAndMake<'info>>,
         params: OrderParams,
@@ -182,6 +207,16 @@ pub mod drift {
         handle_place_orders(ctx, params)
     }
 
+    pub fn cancel_and_place_orders(
+        ctx: Context<PlaceOrder>,
+        market_type: MarketType,
+        market_index: u16,
+        direction: Option<PositionDirection>,
+        params: Vec<OrderParams>,
+    ) -> Result<()> {
+        handle_cancel_and_place_orders(ctx, market_type, market_index, direction, params)
+    }
+
     pub fn begin_swap(
         ctx: Context<Swap>,
         in_market_index: u16,
@@ -247,6 +282,22 @@ pub mod drift {
         handle_update_user_custom_margin_ratio(ctx, _sub_account_id, margin_ratio)
     }
 
+    pub fn update_user_market_max_leverage(
+        ctx: Context<UpdateUser>,
+        _sub_account_id: u16,
+        market_type: MarketType,
+        market_index: u16,
+        max_leverage: u8,
+    ) -> Result<()> {
+        handle_update_user_market_max_leverage(
+            ctx,
+            _sub_account_id,
+            market_type,
+            market_index,
+            max_leverage,
+        )
+    }
+
     pub fn update_user_margin_trading_enabled(
         ctx: Context<UpdateUser>,
         _sub_account_id: u16,
@@ -279,6 +330,25 @@ pub mod drift {
         handle_update_user_advanced_lp(ctx, _sub_account_id, advanced_lp)
     }
 
+    pub fn update_user_cancel_all_after_ts(
+        ctx: Context<UpdateUser>,
+        _sub_account_id: u16,
+        cancel_all_after_ts: i64,
+    ) -> Result<()> {
+        handle_update_user_cancel_all_after_ts(ctx, _sub_account_id, cancel_all_after_ts)
+    }
+
+    pub fn update_user_stats_sub_account_cross_margin(
+        ctx: Context<UpdateUserStats>,
+        enabled: bool,
+    ) -> Result<()> {
+        handle_update_user_stats_sub_account_cross_margin(ctx, enabled)
+    }
+
+    pub fn resize_user(ctx: Context<ResizeUser>) -> Result<()> {
+        handle_resize_user(ctx)
+    }
+
     pub fn delete_user(ctx: Context<DeleteUser>) -> Result<()> {
         handle_delete_user(ctx)
     }
@@ -318,10 +388,29 @@ pub mod drift {
         handle_force_cancel_orders(ctx)
     }
 
+    pub fn cancel_all_orders_after_ts(ctx: Context<ForceCancelOrder>) -> Result<()> {
+        handle_cancel_all_orders_after_ts(ctx)
+    }
+
     pub fn update_user_idle(ctx: Context<UpdateUserIdle>) -> Result<()> {
         handle_update_user_idle(ctx)
     }
 
+    pub fn update_user_margin_call(ctx: Context<UpdateUserIdle>) -> Result<()> {
+        handle_update_user_margin_call(ctx)
+    }
+
+    pub fn place_margin_call_order(
+        ctx: Context<ForceCancelOrder>,
+        market_index: u16,
+    ) -> Result<()> {
+        handle_place_margin_call_order(ctx, market_index)
+    }
+
+    pub fn log_user_stress_margin(ctx: Context<LogUserStressMargin>) -> Result<()> {
+        handle_log_user_stress_margin(ctx)
+    }
+
     pub fn update_user_open_orders_count(ctx: Context<UpdateUserIdle>) -> Result<()> {
         handle_update_user_open_orders_count(ctx)
     }
@@ -379,6 +468,36 @@ pub mod drift {
         )
     }
 
+    pub fn liquidate_perp_with_backstop_vault(
+        ctx: Context<LiquidatePerpWithBackstopVault>,
+        market_index: u16,
+        liquidator_max_base_asset_amount: u64,
+        limit_price: Option<u64>,
+    ) -> Result<()> {
+        handle_liquidate_perp_with_backstop_vault(
+            ctx,
+            market_index,
+            liquidator_max_base_asset_amount,
+            limit_price,
+        )
+    }
+
+    pub fn liquidate_spot_with_backstop_vault(
+        ctx: Context<LiquidateSpotWithBackstopVault>,
+        asset_market_index: u16,
+        liability_market_index: u16,
+        liquidator_max_liability_transfer: u128,
+        limit_price: Option<u64>,
+    ) -> Result<()> {
+        handle_liquidate_spot_with_backstop_vault(
+            ctx,
+            asset_market_index,
+            liability_market_index,
+            liquidator_max_liability_transfer,
+            limit_price,
+        )
+    }
+
     pub fn liquidate_borrow_for_perp_pnl(
         ctx: Context<LiquidateBorrowForPerpPnl>,
         perp_market_index: u16,
@@ -411,6 +530,10 @@ pub mod drift {
         )
     }
 
+    pub fn liquidate_user(ctx: Context<LiquidatePerp>, steps: Vec<LiquidationStep>) -> Result<()> {
+        handle_liquidate_user(ctx, steps)
+    }
+
     pub fn resolve_perp_pnl_deficit(
         ctx: Context<ResolvePerpPnlDeficit>,
         spot_market_index: u16,
@@ -427,6 +550,10 @@ pub mod drift {
         handle_resolve_perp_bankruptcy(ctx, quote_spot_market_index, market_index)
     }
 
+    pub fn auto_deleverage_perp(ctx: Context<AutoDeleveragePerp>, market_index: u16) -> Result<()> {
+        handle_auto_deleverage_perp(ctx, market_index)
+    }
+
     pub fn resolve_spot_bankruptcy(
         ctx: Context<ResolveBankruptcy>,
         market_index: u16,
@@ -523,6 +650,30 @@ pub mod drift {
         handle_transfer_protocol_if_shares(ctx, market_index, shares)
     }
 
+    pub fn initialize_backstop_vault_depositor(
+        ctx: Context<InitializeBackstopVaultDepositor>,
+    ) -> Result<()> {
+        handle_initialize_backstop_vault_depositor(ctx)
+    }
+
+    pub fn deposit_into_backstop_vault(
+        ctx: Context<DepositIntoBackstopVault>,
+        amount: u64,
+    ) -> Result<()> {
+        handle_deposit_into_backstop_vault(ctx, amount)
+    }
+
+    pub fn request_withdraw_from_backstop_vault(
+        ctx: Context<RequestWithdrawFromBackstopVault>,
+        shares: u128,
+    ) -> Result<()> {
+        handle_request_withdraw_from_backstop_vault(ctx, shares)
+    }
+
+    pub fn withdraw_from_backstop_vault(ctx: Context<WithdrawFromBackstopVault>) -> Result<()> {
+        handle_withdraw_from_backstop_vault(ctx)
+    }
+
     // Admin Instructions
 
     pub fn initialize(ctx: Context<Initialize>) -> Result<()> {
@@ -754,6 +905,20 @@ pub mod drift {
         handle_update_perp_market_margin_ratio(ctx, margin_ratio_initial, margin_ratio_maintenance)
     }
 
+    pub fn update_perp_market_margin_ratio_volatility_scaling(
+        ctx: Context<AdminUpdatePerpMarket>,
+        margin_ratio_volatility_reference: u16,
+        margin_ratio_volatility_scale_min: u16,
+        margin_ratio_volatility_scale_max: u16,
+    ) -> Result<()> {
+        handle_update_perp_market_margin_ratio_volatility_scaling(
+            ctx,
+            margin_ratio_volatility_reference,
+            margin_ratio_volatility_scale_min,
+            margin_ratio_volatility_scale_max,
+        )
+    }
+
     pub fn update_perp_market_funding_period(
         ctx: Context<AdminUpdatePerpMarket>,
         funding_period: i64,
@@ -990,6 +1155,13 @@ pub mod drift {
         handle_update_perp_market_curve_update_intensity(ctx, curve_update_intensity)
     }
 
+    pub fn update_perp_market_oracle_anchored(
+        ctx: Context<AdminUpdatePerpMarket>,
+        oracle_anchored: bool,
+    ) -> Result<()> {
+        handle_update_perp_market_oracle_anchored(ctx, oracle_anchored)
+    }
+
     pub fn update_perp_market_target_base_asset_amount_per_lp(
         ctx: Context<AdminUpdatePerpMarket>,
         target_base_asset_amount_per_lp: i32,
@@ -1035,6 +1207,21 @@ pub mod drift {
         handle_update_initial_pct_to_liquidate(ctx, initial_pct_to_liquidate)
     }
 
+    pub fn update_initial_liquidator_fee_pct(
+        ctx: Context<AdminUpdateState>,
+        initial_liquidator_fee_pct: u16,
+    ) -> Result<()> {
+        handle_update_initial_liquidator_fee_pct(ctx, initial_liquidator_fee_pct)
+    }
+
+    pub fn update_margin_call_params(
+        ctx: Context<AdminUpdateState>,
+        margin_call_buffer_ratio: u32,
+        margin_call_grace_period: u16,
+    ) -> Result<()> {
+        handle_update_margin_call_params(ctx, margin_call_buffer_ratio, margin_call_grace_period)
+    }
+
     pub fn update_liquidation_duration(
         ctx: Context<AdminUpdateState>,
         liquidation_duration: u8,
@@ -1164,6 +1351,27 @@ pub mod drift {
         handle_update_perp_market_fee_adjustment(ctx, fee_adjustment)
     }
 
+    pub fn update_perp_market_stress_oracle_shock(
+        ctx: Context<AdminUpdatePerpMarket>,
+        stress_oracle_shock: u16,
+    ) -> Result<()> {
+        handle_update_perp_market_stress_oracle_shock(ctx, stress_oracle_shock)
+    }
+
+    pub fn update_spot_market_stress_oracle_shock(
+        ctx: Context<AdminUpdateSpotMarket>,
+        stress_oracle_shock: u16,
+    ) -> Result<()> {
+        handle_update_spot_market_stress_oracle_shock(ctx, stress_oracle_shock)
+    }
+
+    pub fn update_perp_market_hedge_spot_market(
+        ctx: Context<AdminUpdatePerpMarketHedgeSpotMarket>,
+        hedge_spot_market_index: u16,
+    ) -> Result<()> {
+        handle_update_perp_market_hedge_spot_market(ctx, hedge_spot_market_index)
+    }
+
     pub fn update_spot_market_fee_adjustment(
         ctx: Context<AdminUpdateSpotMarket>,
         fee_adjustment: i16,
@@ -1228,6 +1436,29 @@ pub mod drift {
         )
     }
 
+    pub fn initialize_backstop_vault(
+        ctx: Context<InitializeBackstopVault>,
+        unstaking_period: i64,
+        liquidation_delay: u64,
+    ) -> Result<()> {
+        handle_initialize_backstop_vault(ctx, unstaking_period, liquidation_delay)
+    }
+
+    pub fn update_backstop_vault_params(
+        ctx: Context<AdminUpdateBackstopVault>,
+        unstaking_period: i64,
+        liquidation_delay: u64,
+    ) -> Result<()> {
+        handle_update_backstop_vault_params(ctx, unstaking_period, liquidation_delay)
+    }
+
+    pub fn update_backstop_vault_delegate(
+        ctx: Context<AdminUpdateBackstopVault>,
+        delegate: Pubkey,
+    ) -> Result<()> {
+        handle_update_backstop_vault_delegate(ctx, delegate)
+    }
+
     pub fn initialize_prelaunch_oracle(
         ctx: Context<InitializePrelaunchOracle>,
         params: PrelaunchOracleParams,
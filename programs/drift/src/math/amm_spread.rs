@@ -512,6 +512,17 @@ pub fn calculate_spread_reserves(
     Ok((base_asset_reserve, quote_asset_reserve))
 }
 
+pub fn calculate_inventory_price_offset_pct(
+    liquidity_fraction: i128,
+    max_offset_pct: i64,
+) -> DriftResult<i64> {
+    Ok(liquidity_fraction
+        .cast::<i64>()?
+        .safe_mul(max_offset_pct)?
+        .safe_div(PERCENTAGE_PRECISION.cast::<i64>()?)?
+        .clamp(-max_offset_pct, max_offset_pct))
+}
+
 #[allow(clippy::comparison_chain)]
 pub fn calculate_reference_price_offset(
     reserve_price: u64,
@@ -557,11 +568,7 @@ pub fn calculate_reference_price_offset(
         .safe_mul(PRICE_PRECISION_I64)?
         .safe_div(reserve_price.cast()?)?;
 
-    let inventory_pct = liquidity_fraction
-        .cast::<i64>()?
-        .safe_mul(max_offset_pct)?
-        .safe_div(PERCENTAGE_PRECISION.cast::<i64>()?)?
-        .clamp(-max_offset_pct, max_offset_pct);
+    let inventory_pct = calculate_inventory_price_offset_pct(liquidity_fraction, max_offset_pct)?;
 
     // only apply when inventory is consistent with recent and 24h market premium
     let offset_pct = if (mark_premium_avg_pct >= 0 && inventory_pct >= 0)
@@ -189,6 +189,24 @@ mod test {
         assert_eq!(res, 0);
     }
 
+    #[test]
+    fn calculate_inventory_price_offset_pct_tests() {
+        let max_offset: i64 = 2500; // 25 bps
+
+        let res = calculate_inventory_price_offset_pct(0, max_offset).unwrap();
+        assert_eq!(res, 0);
+
+        // half the inventory liquidity used is half the max offset
+        let res = calculate_inventory_price_offset_pct(500_000, max_offset).unwrap();
+        assert_eq!(res, 1250);
+
+        let res = calculate_inventory_price_offset_pct(-500_000, max_offset).unwrap();
+        assert_eq!(res, -1250);
+
+        let res = calculate_inventory_price_offset_pct(2_000_000, max_offset).unwrap();
+        assert_eq!(res, max_offset);
+    }
+
     #[test]
     fn calculate_spread_tests() {
         let base_spread = 1000; // .1%
@@ -89,6 +89,8 @@ pub fn calculate_auction_price(
     match order.order_type {
         OrderType::Market
         | OrderType::TriggerMarket
+        | OrderType::TrailingStop
+        | OrderType::Twap
         | OrderType::Limit
         | OrderType::TriggerLimit => {
             calculate_auction_price_for_fixed_auction(order, slot, tick_size)
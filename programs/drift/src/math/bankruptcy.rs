@@ -19,6 +19,11 @@ pub fn is_user_bankrupt(user: &User) -> bool {
     }
 
     for perp_position in user.perp_positions.iter() {
+        // isolated positions go bankrupt on their own
+        if user.is_perp_position_isolated(perp_position.market_index) {
+            continue;
+        }
+
         if perp_position.base_asset_amount != 0
             || perp_position.quote_asset_amount > 0
             || perp_position.has_open_order()
@@ -34,3 +39,25 @@ pub fn is_user_bankrupt(user: &User) -> bool {
 
     has_liability
 }
+
+pub fn is_isolated_perp_position_bankrupt(user: &User, market_index: u16) -> bool {
+    // an isolated position is bankrupt iff its deposit is gone and only negative pnl is left
+
+    let has_deposit = user
+        .get_isolated_perp_position(market_index)
+        .map_or(false, |isolated_position| {
+            isolated_position.scaled_balance > 0
+        });
+
+    if has_deposit {
+        return false;
+    }
+
+    user.get_perp_position(market_index)
+        .map_or(false, |perp_position| {
+            perp_position.base_asset_amount == 0
+                && !perp_position.has_open_order()
+                && !perp_position.is_lp()
+                && perp_position.quote_asset_amount < 0
+        })
+}
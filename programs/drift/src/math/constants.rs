@@ -37,6 +37,7 @@ pub const FUNDING_RATE_BUFFER_I128: i128 = FUNDING_RATE_BUFFER as i128; // expo
 
 pub const MARGIN_PRECISION: u32 = 10_000; // expo = -4
 pub const MARGIN_PRECISION_U128: u128 = 10_000; // expo = -4
+pub const MARGIN_PRECISION_I128: i128 = MARGIN_PRECISION as i128; // expo = -4
 pub const SPOT_WEIGHT_PRECISION: u32 = MARGIN_PRECISION; // expo = -4
 pub const SPOT_WEIGHT_PRECISION_U128: u128 = SPOT_WEIGHT_PRECISION as u128; // expo = -4
 pub const SPOT_WEIGHT_PRECISION_I128: i128 = SPOT_WEIGHT_PRECISION as i128; // expo = -4
@@ -3,8 +3,9 @@ use crate::math::casting::Cast;
 use crate::math::constants::{
     AMM_RESERVE_PRECISION_I128, FUNDING_RATE_TO_QUOTE_PRECISION_PRECISION_RATIO,
     LIQUIDATION_FEE_PRECISION, LIQUIDATION_FEE_PRECISION_U128,
-    LIQUIDATION_FEE_TO_MARGIN_PRECISION_RATIO, LIQUIDATION_PCT_PRECISION, PRICE_PRECISION,
-    PRICE_TIMES_AMM_TO_QUOTE_PRECISION_RATIO, QUOTE_PRECISION, SPOT_WEIGHT_PRECISION_U128,
+    LIQUIDATION_FEE_TO_MARGIN_PRECISION_RATIO, LIQUIDATION_PCT_PRECISION, PERCENTAGE_PRECISION,
+    PRICE_PRECISION, PRICE_TIMES_AMM_TO_QUOTE_PRECISION_RATIO, QUOTE_PRECISION,
+    SPOT_WEIGHT_PRECISION_U128,
 };
 use crate::math::margin::calculate_margin_requirement_and_total_collateral_and_liability_info;
 use crate::math::safe_math::SafeMath;
@@ -275,6 +276,31 @@ pub fn calculate_funding_rate_deltas_to_resolve_bankruptcy(
         .safe_mul(FUNDING_RATE_TO_QUOTE_PRECISION_PRECISION_RATIO.cast()?)
 }
 
+/// Ranks positions for auto-deleveraging: unrealized pnl as a percentage of entry times the
+/// position's leverage on the user's total collateral
+/// precision: PERCENTAGE_PRECISION
+pub fn calculate_adl_score(
+    unrealized_pnl: i128,
+    quote_entry_amount: i64,
+    base_asset_value: u128,
+    total_collateral: i128,
+) -> DriftResult<u128> {
+    if unrealized_pnl <= 0 || quote_entry_amount == 0 || total_collateral <= 0 {
+        return Ok(0);
+    }
+
+    let pnl_pct = unrealized_pnl
+        .unsigned_abs()
+        .safe_mul(PERCENTAGE_PRECISION)?
+        .safe_div(quote_entry_amount.unsigned_abs().cast()?)?;
+
+    let leverage = base_asset_value
+        .safe_mul(PERCENTAGE_PRECISION)?
+        .safe_div(total_collateral.unsigned_abs())?;
+
+    pnl_pct.safe_mul(leverage)?.safe_div(PERCENTAGE_PRECISION)
+}
+
 pub fn calculate_cumulative_deposit_interest_delta_to_resolve_bankruptcy(
     borrow: u128,
     spot_market: &SpotMarket,
@@ -353,6 +379,40 @@ pub fn calculate_max_pct_to_liquidate(
         .safe_div(margin_shortage)
 }
 
+/// Liquidator fee is auctioned: it starts at initial_liquidator_fee_pct of the market's fee when the user
+/// enters liquidation and increases linearly from there to the full fee over liquidation_duration slots
+pub fn calculate_liquidator_fee(
+    user: &User,
+    max_liquidator_fee: u32,
+    slot: u64,
+    initial_liquidator_fee_pct: u128,
+    liquidation_duration: u128,
+) -> DriftResult<u32> {
+    // auction begins once the user is flagged as being liquidated
+    let slots_elapsed = if user.is_being_liquidated() {
+        slot.safe_sub(user.last_active_slot)?
+    } else {
+        0
+    };
+
+    let initial_liquidator_fee_pct = initial_liquidator_fee_pct.min(LIQUIDATION_PCT_PRECISION);
+
+    // initial pct + (100% - initial pct) * elapsed / duration
+    let fee_pct = LIQUIDATION_PCT_PRECISION
+        .safe_sub(initial_liquidator_fee_pct)?
+        .safe_mul(slots_elapsed.cast()?)?
+        .safe_div(liquidation_duration)
+        .unwrap_or(LIQUIDATION_PCT_PRECISION) // if divide by zero, default to 100%
+        .safe_add(initial_liquidator_fee_pct)?
+        .min(LIQUIDATION_PCT_PRECISION);
+
+    max_liquidator_fee
+        .cast::<u128>()?
+        .safe_mul(fee_pct)?
+        .safe_div(LIQUIDATION_PCT_PRECISION)?
+        .cast()
+}
+
 pub fn calculate_perp_if_fee(
     margin_shortage: u128,
     user_base_asset_amount: u64,
@@ -826,3 +826,122 @@ mod calculate_max_pct_to_liquidate {
         assert_eq!(pct, LIQUIDATION_PCT_PRECISION);
     }
 }
+
+mod calculate_liquidator_fee {
+    use crate::math::liquidation::calculate_liquidator_fee;
+    use crate::state::user::{User, UserStatus};
+    use crate::{LIQUIDATION_FEE_PRECISION, LIQUIDATION_PCT_PRECISION};
+
+    #[test]
+    fn test() {
+        let max_liquidator_fee = LIQUIDATION_FEE_PRECISION / 100; // 1%
+
+        // not being liquidated yet, auction starts now
+        let user = User {
+            last_active_slot: 0,
+            ..User::default()
+        };
+        let fee = calculate_liquidator_fee(&user, max_liquidator_fee, 100, 0, 150).unwrap();
+        assert_eq!(fee, 0);
+
+        let user = User {
+            status: UserStatus::BeingLiquidated as u8,
+            last_active_slot: 100,
+            ..User::default()
+        };
+
+        let fee = calculate_liquidator_fee(&user, max_liquidator_fee, 100, 0, 150).unwrap();
+        assert_eq!(fee, 0);
+
+        let fee = calculate_liquidator_fee(
+            &user,
+            max_liquidator_fee,
+            100,
+            LIQUIDATION_PCT_PRECISION / 10,
+            150,
+        )
+        .unwrap();
+        assert_eq!(fee, 1000); // 10% of max
+
+        let fee = calculate_liquidator_fee(&user, max_liquidator_fee, 175, 0, 150).unwrap();
+        assert_eq!(fee, 5000); // halfway
+
+        // ramps from the initial pct, not on top of it
+        let fee = calculate_liquidator_fee(
+            &user,
+            max_liquidator_fee,
+            175,
+            LIQUIDATION_PCT_PRECISION / 10,
+            150,
+        )
+        .unwrap();
+        assert_eq!(fee, 5500); // 10% + 90% * .5
+
+        let fee = calculate_liquidator_fee(
+            &user,
+            max_liquidator_fee,
+            240,
+            LIQUIDATION_PCT_PRECISION / 10,
+            150,
+        )
+        .unwrap();
+        assert_eq!(fee, 9400); // 10% + 90% * 140 / 150
+
+        let fee = calculate_liquidator_fee(&user, max_liquidator_fee, 250, 0, 150).unwrap();
+        assert_eq!(fee, max_liquidator_fee);
+
+        let fee = calculate_liquidator_fee(&user, max_liquidator_fee, 1000, 0, 150).unwrap();
+        assert_eq!(fee, max_liquidator_fee);
+
+        // no duration means full fee
+        let fee = calculate_liquidator_fee(&user, max_liquidator_fee, 100, 0, 0).unwrap();
+        assert_eq!(fee, max_liquidator_fee);
+    }
+}
+
+mod calculate_adl_score {
+    use crate::math::liquidation::calculate_adl_score;
+    use crate::{QUOTE_PRECISION, QUOTE_PRECISION_I128, QUOTE_PRECISION_I64};
+
+    #[test]
+    fn test() {
+        // $100 profit on $600 entry at 2x leverage
+        let score = calculate_adl_score(
+            100 * QUOTE_PRECISION_I128,
+            600 * QUOTE_PRECISION_I64,
+            500 * QUOTE_PRECISION,
+            250 * QUOTE_PRECISION_I128,
+        )
+        .unwrap();
+        assert_eq!(score, 333332);
+
+        // same profit at 4x leverage ranks higher
+        let more_leveraged_score = calculate_adl_score(
+            100 * QUOTE_PRECISION_I128,
+            600 * QUOTE_PRECISION_I64,
+            500 * QUOTE_PRECISION,
+            125 * QUOTE_PRECISION_I128,
+        )
+        .unwrap();
+        assert_eq!(more_leveraged_score, 666664);
+
+        // losing positions and users without collateral aren't ranked
+        let score = calculate_adl_score(
+            -100 * QUOTE_PRECISION_I128,
+            600 * QUOTE_PRECISION_I64,
+            500 * QUOTE_PRECISION,
+            250 * QUOTE_PRECISION_I128,
+        )
+        .unwrap();
+        assert_eq!(score, 0);
+
+        let score = calculate_adl_score(
+            100 * QUOTE_PRECISION_I128,
+            600 * QUOTE_PRECISION_I64,
+            500 * QUOTE_PRECISION,
+            0,
+        )
+        .unwrap();
+        assert_eq!(score, 0);
+    }
+}
@@ -0,0 +1,171 @@
+use crate::error::DriftResult;
+use crate::math::casting::Cast;
+use crate::math::margin::{
+    calculate_margin_requirement_and_total_collateral_and_liability_info, MarginRequirementType,
+};
+use crate::math::safe_math::SafeMath;
+use crate::state::margin_calculation::MarginContext;
+use crate::state::oracle::OraclePriceData;
+use crate::state::oracle_map::OracleMap;
+use crate::state::perp_market_map::PerpMarketMap;
+use crate::state::spot_market_map::SpotMarketMap;
+use crate::state::user::{MarketType, User};
+use anchor_lang::prelude::Pubkey;
+
+#[cfg(test)]
+mod tests;
+
+pub const LIQUIDATION_PRICE_SEARCH_MAX_MULTIPLE: i64 = 100;
+
+/// Finds the oracle price at which the user would fall below maintenance margin, holding every other price constant.
+/// Every position on the same oracle (e.g. SOL deposits and SOL-PERP) is revalued together.
+/// Collateral isn't linear in price once imf size premiums and unrealized pnl weights apply, so the price is
+/// found by stepping geometrically away from the current price until margin is breached and then bisecting
+/// between the last safe step and the breaching step
+///
+/// Excess collateral isn't guaranteed to be monotonic in price (e.g. a deposit and a short on the same oracle),
+/// so this finds a breach price, not necessarily the closest one: a breach that starts and ends between two
+/// steps is missed, and if a bracket crosses zero more than once bisection settles on one of the crossings
+///
+/// Returns None if maintenance margin isn't breached anywhere between 0 and 100x the current price
+pub fn calculate_liquidation_price(
+    user: &User,
+    perp_market_map: &PerpMarketMap,
+    spot_market_map: &SpotMarketMap,
+    oracle_map: &mut OracleMap,
+    market_type: MarketType,
+    market_index: u16,
+) -> DriftResult<Option<i64>> {
+    let oracle = match market_type {
+        MarketType::Perp => perp_market_map.get_ref(&market_index)?.amm.oracle,
+        MarketType::Spot => spot_market_map.get_ref(&market_index)?.oracle,
+    };
+
+    // quote asset price is fixed
+    if oracle == Pubkey::default() {
+        return Ok(None);
+    }
+
+    let current_excess_collateral = calculate_maintenance_excess_collateral(
+        user,
+        perp_market_map,
+        spot_market_map,
+        oracle_map,
+    )?;
+
+    let original_price_data = *oracle_map.get_price_data(&oracle)?;
+    let current_price = original_price_data.price;
+
+    if current_excess_collateral < 0 {
+        return Ok(Some(current_price));
+    }
+
+    let liquidation_price = find_liquidation_price(
+        user,
+        perp_market_map,
+        spot_market_map,
+        oracle_map,
+        &oracle,
+        &original_price_data,
+    );
+
+    oracle_map.set_price_data(&oracle, original_price_data)?;
+
+    liquidation_price
+}
+
+fn find_liquidation_price(
+    user: &User,
+    perp_market_map: &PerpMarketMap,
+    spot_market_map: &SpotMarketMap,
+    oracle_map: &mut OracleMap,
+    oracle: &Pubkey,
+    original_price_data: &OraclePriceData,
+) -> DriftResult<Option<i64>> {
+    let current_price = original_price_data.price;
+    let max_price = current_price.safe_mul(LIQUIDATION_PRICE_SEARCH_MAX_MULTIPLE)?;
+
+    let mut excess_collateral_at_price = |price: i64| -> DriftResult<i128> {
+        oracle_map.set_price_data(
+            oracle,
+            OraclePriceData {
+                price,
+                ..*original_price_data
+            },
+        )?;
+
+        calculate_maintenance_excess_collateral(user, perp_market_map, spot_market_map, oracle_map)
+    };
+
+    let mut liquidation_price: Option<i64> = None;
+    for search_down in [true, false] {
+        // bracket the breach by halving/doubling the distance from the current price
+        let mut safe_price = current_price;
+        let mut breach_price: Option<i64> = None;
+        loop {
+            let price = if search_down {
+                (safe_price / 2).max(1)
+            } else {
+                safe_price.safe_mul(2)?.min(max_price)
+            };
+
+            if price == safe_price {
+                break;
+            }
+
+            if excess_collateral_at_price(price)? < 0 {
+                breach_price = Some(price);
+                break;
+            }
+
+            safe_price = price;
+        }
+
+        let mut breach_price = match breach_price {
+            Some(breach_price) => breach_price,
+            None => continue,
+        };
+
+        // invariant: excess collateral is non-negative at safe_price and negative at breach_price
+        while breach_price.safe_sub(safe_price)?.abs() > 1 {
+            let mid_price = safe_price.safe_add(breach_price.safe_sub(safe_price)? / 2)?;
+            if excess_collateral_at_price(mid_price)? < 0 {
+                breach_price = mid_price;
+            } else {
+                safe_price = mid_price;
+            }
+        }
+
+        // if it breaches on both sides, the closest price is liquidated first
+        liquidation_price = match liquidation_price {
+            Some(price)
+                if price.safe_sub(current_price)?.abs()
+                    <= breach_price.safe_sub(current_price)?.abs() =>
+            {
+                Some(price)
+            }
+            _ => Some(breach_price),
+        };
+    }
+
+    Ok(liquidation_price)
+}
+
+fn calculate_maintenance_excess_collateral(
+    user: &User,
+    perp_market_map: &PerpMarketMap,
+    spot_market_map: &SpotMarketMap,
+    oracle_map: &mut OracleMap,
+) -> DriftResult<i128> {
+    let calculation = calculate_margin_requirement_and_total_collateral_and_liability_info(
+        user,
+        perp_market_map,
+        spot_market_map,
+        oracle_map,
+        MarginContext::standard(MarginRequirementType::Maintenance),
+    )?;
+
+    calculation
+        .total_collateral
+        .safe_sub(calculation.margin_requirement.cast()?)
+}
@@ -1,6 +1,7 @@
 use crate::error::DriftResult;
 use crate::error::ErrorCode;
 use crate::math::constants::{
+    BASE_PRECISION_I128, LIQUIDATION_FEE_PRECISION_U128, MARGIN_PRECISION, MARGIN_PRECISION_I128,
     MARGIN_PRECISION_U128, MAX_POSITIVE_UPNL_FOR_INITIAL_MARGIN, PRICE_PRECISION,
     SPOT_IMF_PRECISION_U128, SPOT_WEIGHT_PRECISION, SPOT_WEIGHT_PRECISION_U128,
 };
@@ -16,17 +17,19 @@ use crate::math::casting::Cast;
 use crate::math::funding::calculate_funding_payment;
 use crate::math::oracle::{is_oracle_valid_for_action, DriftAction};
 
-use crate::math::spot_balance::{get_strict_token_value, get_token_value};
+use crate::math::spot_balance::{get_spot_balance, get_strict_token_value, get_token_value};
 
 use crate::math::safe_math::SafeMath;
-use crate::state::margin_calculation::{MarginCalculation, MarginContext, MarketIdentifier};
+use crate::state::margin_calculation::{
+    MarginCalculation, MarginContext, MarginScope, MarketIdentifier,
+};
 use crate::state::oracle::{OraclePriceData, StrictOraclePrice};
 use crate::state::oracle_map::OracleMap;
 use crate::state::perp_market::{ContractTier, MarketStatus, PerpMarket};
 use crate::state::perp_market_map::PerpMarketMap;
-use crate::state::spot_market::{AssetTier, SpotBalanceType};
+use crate::state::spot_market::{AssetTier, SpotBalanceType, SpotMarket};
 use crate::state::spot_market_map::SpotMarketMap;
-use crate::state::user::{MarketType, OrderFillSimulation, PerpPosition, User};
+use crate::state::user::{MarketType, OrderFillSimulation, PerpPosition, SpotPosition, User};
 use num_integer::Roots;
 use solana_program::msg;
 use std::cmp::{max, min, Ordering};
@@ -209,6 +212,220 @@ pub fn calculate_perp_position_value_and_pnl(
     ))
 }
 
+/// Perp base that remains if every open order reducing the position fills, signed like the position.
+/// lp positions are never treated as a hedge
+pub fn get_perp_hedgeable_base_asset_amount(market_position: &PerpPosition) -> DriftResult<i128> {
+    if market_position.is_lp() {
+        return Ok(0);
+    }
+
+    let base_asset_amount = market_position.base_asset_amount.cast::<i128>()?;
+    if base_asset_amount < 0 {
+        let base_asset_amount_all_bids_fill =
+            base_asset_amount.safe_add(market_position.open_bids.cast()?)?;
+        Ok(base_asset_amount_all_bids_fill.min(0))
+    } else {
+        let base_asset_amount_all_asks_fill =
+            base_asset_amount.safe_add(market_position.open_asks.cast()?)?;
+        Ok(base_asset_amount_all_asks_fill.max(0))
+    }
+}
+
+/// Spot balance that remains if every open order reducing it fills, converted to BASE_PRECISION and
+/// signed like the balance (deposits positive, borrows negative)
+pub fn get_spot_hedgeable_base_asset_amount(
+    spot_position: &SpotPosition,
+    spot_market: &SpotMarket,
+) -> DriftResult<i128> {
+    let token_amount = spot_position.get_signed_token_amount(spot_market)?;
+    let token_amount_all_reducing_fill = if token_amount > 0 {
+        token_amount
+            .safe_add(spot_position.open_asks.cast()?)?
+            .max(0)
+    } else {
+        token_amount
+            .safe_add(spot_position.open_bids.cast()?)?
+            .min(0)
+    };
+
+    token_amount_all_reducing_fill
+        .safe_mul(BASE_PRECISION_I128)?
+        .safe_div(spot_market.get_precision().cast()?)
+}
+
+/// For the hedged amount, the perp margin requirement and the spot weight haircut (1 - asset weight for
+/// a deposit, liability weight - 1 for a borrow) both cover the same price move, so part of the smaller
+/// one is given back. The offset is capped at half of each leg's requirement net of that leg's
+/// liquidation fees, so liquidating either leg always frees more margin than it pays in fees
+pub fn calculate_perp_hedge_offset(
+    hedged_base_asset_amount: u128,
+    market: &PerpMarket,
+    spot_market: &SpotMarket,
+    spot_token_amount: u128,
+    spot_balance_type: &SpotBalanceType,
+    oracle_price: i64,
+    worst_case_base_asset_amount: u128,
+    margin_requirement_type: MarginRequirementType,
+    user_custom_margin_ratio: u32,
+) -> DriftResult<u128> {
+    if hedged_base_asset_amount == 0 {
+        return Ok(0);
+    }
+
+    let hedged_value = calculate_base_asset_value_with_oracle_price(
+        hedged_base_asset_amount.cast()?,
+        oracle_price,
+    )?;
+
+    let perp_margin_ratio = user_custom_margin_ratio
+        .max(market.get_margin_ratio(worst_case_base_asset_amount, margin_requirement_type)?);
+
+    let perp_margin_requirement = hedged_value
+        .safe_mul(perp_margin_ratio.cast()?)?
+        .safe_div(MARGIN_PRECISION_U128)?;
+
+    let spot_haircut_weight = match spot_balance_type {
+        SpotBalanceType::Deposit => {
+            SPOT_WEIGHT_PRECISION.saturating_sub(spot_market.get_asset_weight(
+                spot_token_amount,
+                oracle_price,
+                &margin_requirement_type,
+            )?)
+        }
+        SpotBalanceType::Borrow => spot_market
+            .get_liability_weight(spot_token_amount, &margin_requirement_type)?
+            .saturating_sub(SPOT_WEIGHT_PRECISION),
+    };
+
+    let spot_haircut = hedged_value
+        .safe_mul(spot_haircut_weight.cast()?)?
+        .safe_div(SPOT_WEIGHT_PRECISION_U128)?;
+
+    let perp_liquidation_fees = hedged_value
+        .safe_mul(
+            market
+                .liquidator_fee
+                .safe_add(market.if_liquidation_fee)?
+                .cast()?,
+        )?
+        .safe_div(LIQUIDATION_FEE_PRECISION_U128)?;
+
+    let spot_liquidation_fees = hedged_value
+        .safe_mul(
+            spot_market
+                .liquidator_fee
+                .safe_add(spot_market.if_liquidation_fee)?
+                .cast()?,
+        )?
+        .safe_div(LIQUIDATION_FEE_PRECISION_U128)?;
+
+    perp_margin_requirement
+        .saturating_sub(perp_liquidation_fees)
+        .min(spot_haircut.saturating_sub(spot_liquidation_fees))
+        .safe_div(2)
+}
+
+fn calculate_perp_hedge_offset_for_user(
+    user: &User,
+    market_position: &PerpPosition,
+    market: &PerpMarket,
+    spot_market_map: &SpotMarketMap,
+    oracle_price: i64,
+    margin_requirement_type: MarginRequirementType,
+    user_custom_margin_ratio: u32,
+    hedged_spot_base_asset_amounts: &mut [u128; 8],
+) -> DriftResult<u128> {
+    if market.hedge_spot_market_index == 0 || market.status == MarketStatus::Settlement {
+        return Ok(0);
+    }
+
+    let spot_position_index = match user.get_spot_position_index(market.hedge_spot_market_index) {
+        Ok(spot_position_index) => spot_position_index,
+        Err(_) => return Ok(0),
+    };
+
+    let spot_market = spot_market_map.get_ref(&market.hedge_spot_market_index)?;
+    if spot_market.oracle != market.amm.oracle {
+        return Ok(0);
+    }
+
+    let spot_position = &user.spot_positions[spot_position_index];
+
+    let perp_hedgeable_base_asset_amount = get_perp_hedgeable_base_asset_amount(market_position)?;
+    let spot_hedgeable_base_asset_amount =
+        get_spot_hedgeable_base_asset_amount(spot_position, &spot_market)?;
+
+    // a deposit hedges a short and a borrow hedges a long
+    if perp_hedgeable_base_asset_amount == 0
+        || spot_hedgeable_base_asset_amount == 0
+        || perp_hedgeable_base_asset_amount.signum() == spot_hedgeable_base_asset_amount.signum()
+    {
+        return Ok(0);
+    }
+
+    // a spot position can only offset one perp position
+    let spot_hedgeable_base_asset_amount = spot_hedgeable_base_asset_amount
+        .unsigned_abs()
+        .saturating_sub(hedged_spot_base_asset_amounts[spot_position_index]);
+
+    let hedged_base_asset_amount = perp_hedgeable_base_asset_amount
+        .unsigned_abs()
+        .min(spot_hedgeable_base_asset_amount);
+
+    let hedge_offset = calculate_perp_hedge_offset(
+        hedged_base_asset_amount,
+        market,
+        &spot_market,
+        spot_position.get_token_amount(&spot_market)?,
+        &spot_position.balance_type,
+        oracle_price,
+        market_position
+            .worst_case_base_asset_amount()?
+            .unsigned_abs(),
+        margin_requirement_type,
+        user_custom_margin_ratio,
+    )?;
+
+    if hedge_offset > 0 {
+        hedged_spot_base_asset_amounts[spot_position_index] = hedged_spot_base_asset_amounts
+            [spot_position_index]
+            .safe_add(hedged_base_asset_amount)?;
+    }
+
+    Ok(hedge_offset)
+}
+
+/// Moves the oracle price by the market's stress shock and widens it by the confidence interval in the
+/// same direction. The widened price is the twap side, so assets take the lower price when shocked down
+/// and liabilities take the higher price when shocked up
+pub fn get_stressed_strict_oracle_price(
+    oracle_price_data: &OraclePriceData,
+    oracle_shock: u16,
+    shock_up: bool,
+) -> DriftResult<StrictOraclePrice> {
+    let price = oracle_price_data.price.cast::<i128>()?;
+    let oracle_shock = oracle_shock.cast::<i128>()?;
+    let confidence = oracle_price_data.confidence.cast::<i128>()?;
+
+    let (shocked_price, widened_price) = if shock_up {
+        let shocked_price = price
+            .safe_mul(MARGIN_PRECISION_I128.safe_add(oracle_shock)?)?
+            .safe_div(MARGIN_PRECISION_I128)?;
+        (shocked_price, shocked_price.safe_add(confidence)?)
+    } else {
+        let shocked_price = price
+            .safe_mul(MARGIN_PRECISION_I128.safe_sub(oracle_shock)?)?
+            .safe_div(MARGIN_PRECISION_I128)?
+            .max(1);
+        (shocked_price, shocked_price.safe_sub(confidence)?.max(1))
+    };
+
+    Ok(StrictOraclePrice {
+        current: shocked_price.cast()?,
+        twap_5min: Some(widened_price.cast()?),
+    })
+}
+
 pub fn calculate_user_safest_position_tiers(
     user: &User,
     perp_market_map: &PerpMarketMap,
@@ -236,6 +453,15 @@ pub fn calculate_user_safest_position_tiers(
     Ok((safest_tier_spot_liablity, safest_tier_perp_liablity))
 }
 
+/// Converts a user set max leverage (e.g. 3 for 3x) into an initial margin ratio. 0 means no cap
+pub fn calculate_max_leverage_margin_ratio(max_leverage: u8) -> DriftResult<u32> {
+    if max_leverage == 0 {
+        return Ok(0);
+    }
+
+    MARGIN_PRECISION.safe_div(max_leverage.cast()?)
+}
+
 pub fn calculate_margin_requirement_and_total_collateral_and_liability_info(
     user: &User,
     perp_market_map: &PerpMarketMap,
@@ -251,7 +477,20 @@ pub fn calculate_margin_requirement_and_total_collateral_and_liability_info(
         0_u32
     };
 
-    for spot_position in user.spot_positions.iter() {
+    let isolated_quote_spot_position;
+    let spot_positions: &[SpotPosition] = match context.scope {
+        MarginScope::Cross => &user.spot_positions,
+        MarginScope::IsolatedPerp { market_index } => {
+            // an isolated perp position's only collateral is its own quote deposit
+            isolated_quote_spot_position = [user
+                .get_isolated_perp_position(market_index)
+                .ok_or(ErrorCode::InvalidIsolatedPerpPosition)?
+                .get_quote_spot_position()];
+            &isolated_quote_spot_position
+        }
+    };
+
+    for spot_position in spot_positions.iter() {
         validation::position::validate_spot_position(spot_position)?;
 
         if spot_position.is_available() {
@@ -272,13 +511,20 @@ pub fn calculate_margin_requirement_and_total_collateral_and_liability_info(
             Some(DriftAction::MarginCalc),
         )?);
 
-        let strict_oracle_price = StrictOraclePrice::new(
-            oracle_price_data.price,
-            spot_market
-                .historical_oracle_data
-                .last_oracle_price_twap_5min,
-            calculation.context.strict,
-        );
+        let strict_oracle_price = match calculation.context.stress_shock_up() {
+            Some(shock_up) => get_stressed_strict_oracle_price(
+                oracle_price_data,
+                spot_market.stress_oracle_shock,
+                shock_up,
+            )?,
+            None => StrictOraclePrice::new(
+                oracle_price_data.price,
+                spot_market
+                    .historical_oracle_data
+                    .last_oracle_price_twap_5min,
+                calculation.context.strict,
+            ),
+        };
         strict_oracle_price.validate()?;
 
         if spot_market.market_index == 0 {
@@ -300,7 +546,6 @@ pub fn calculate_margin_requirement_and_total_collateral_and_liability_info(
                 SpotBalanceType::Deposit => {
                     calculation.add_total_collateral(token_value)?;
 
-                    #[cfg(feature = "drift-rs")]
                     calculation.add_spot_asset_value(token_value)?;
                 }
                 SpotBalanceType::Borrow => {
@@ -322,13 +567,21 @@ pub fn calculate_margin_requirement_and_total_collateral_and_liability_info(
 
                     calculation.add_spot_liability()?;
 
-                    #[cfg(feature = "drift-rs")]
                     calculation.add_spot_liability_value(token_value)?;
                 }
             }
         } else {
             let signed_token_amount = spot_position.get_signed_token_amount(&spot_market)?;
 
+            let spot_custom_margin_ratio = if context.margin_type == MarginRequirementType::Initial
+            {
+                user_custom_margin_ratio.max(calculate_max_leverage_margin_ratio(
+                    user.get_market_max_leverage(MarketType::Spot, spot_position.market_index),
+                )?)
+            } else {
+                user_custom_margin_ratio
+            };
+
             let OrderFillSimulation {
                 token_amount: worst_case_token_amount,
                 orders_value: worst_case_orders_value,
@@ -345,7 +598,7 @@ pub fn calculate_margin_requirement_and_total_collateral_and_liability_info(
                 .apply_user_custom_margin_ratio(
                     &spot_market,
                     strict_oracle_price.current,
-                    user_custom_margin_ratio,
+                    spot_custom_margin_ratio,
                 )?;
 
             if worst_case_token_amount == 0 {
@@ -369,7 +622,6 @@ pub fn calculate_margin_requirement_and_total_collateral_and_liability_info(
                     calculation
                         .add_total_collateral(worst_case_weighted_token_value.cast::<i128>()?)?;
 
-                    #[cfg(feature = "drift-rs")]
                     calculation.add_spot_asset_value(worst_case_token_value)?;
                 }
                 Ordering::Less => {
@@ -398,8 +650,11 @@ pub fn calculate_margin_requirement_and_total_collateral_and_liability_info(
                     calculation.update_with_spot_isolated_liability(
                         spot_market.asset_tier == AssetTier::Isolated,
                     );
+                    calculation.update_stress_check_required(
+                        spot_market.asset_tier == AssetTier::Isolated
+                            && spot_market.stress_oracle_shock > 0,
+                    );
 
-                    #[cfg(feature = "drift-rs")]
                     calculation.add_spot_liability_value(worst_case_token_value.unsigned_abs())?;
                 }
                 Ordering::Equal => {
@@ -408,6 +663,10 @@ pub fn calculate_margin_requirement_and_total_collateral_and_liability_info(
                         calculation.update_with_spot_isolated_liability(
                             spot_market.asset_tier == AssetTier::Isolated,
                         );
+                        calculation.update_stress_check_required(
+                            spot_market.asset_tier == AssetTier::Isolated
+                                && spot_market.stress_oracle_shock > 0,
+                        );
                     }
                 }
             }
@@ -416,7 +675,6 @@ pub fn calculate_margin_requirement_and_total_collateral_and_liability_info(
                 Ordering::Greater => {
                     calculation.add_total_collateral(worst_case_orders_value.cast::<i128>()?)?;
 
-                    #[cfg(feature = "drift-rs")]
                     calculation.add_spot_asset_value(worst_case_orders_value)?;
                 }
                 Ordering::Less => {
@@ -426,7 +684,6 @@ pub fn calculate_margin_requirement_and_total_collateral_and_liability_info(
                         MarketIdentifier::spot(0),
                     )?;
 
-                    #[cfg(feature = "drift-rs")]
                     calculation.add_spot_liability_value(worst_case_orders_value.unsigned_abs())?;
                 }
                 Ordering::Equal => {}
@@ -434,11 +691,29 @@ pub fn calculate_margin_requirement_and_total_collateral_and_liability_info(
         }
     }
 
+    let mut hedged_spot_base_asset_amounts = [0_u128; 8];
+
     for market_position in user.perp_positions.iter() {
         if market_position.is_available() {
             continue;
         }
 
+        let is_isolated = user.is_perp_position_isolated(market_position.market_index);
+        if !context
+            .scope
+            .includes_perp_position(market_position.market_index, is_isolated)
+        {
+            continue;
+        }
+
+        let perp_custom_margin_ratio = if context.margin_type == MarginRequirementType::Initial {
+            user_custom_margin_ratio.max(calculate_max_leverage_margin_ratio(
+                user.get_market_max_leverage(MarketType::Perp, market_position.market_index),
+            )?)
+        } else {
+            user_custom_margin_ratio
+        };
+
         let market = &perp_market_map.get_ref(&market_position.market_index)?;
 
         let quote_spot_market = spot_market_map.get_ref(&market.quote_spot_market_index)?;
@@ -458,13 +733,20 @@ pub fn calculate_margin_requirement_and_total_collateral_and_liability_info(
             Some(DriftAction::MarginCalc),
         )?);
 
-        let strict_quote_price = StrictOraclePrice::new(
-            quote_oracle_price_data.price,
-            quote_spot_market
-                .historical_oracle_data
-                .last_oracle_price_twap_5min,
-            calculation.context.strict,
-        );
+        let strict_quote_price = match calculation.context.stress_shock_up() {
+            Some(shock_up) => get_stressed_strict_oracle_price(
+                quote_oracle_price_data,
+                quote_spot_market.stress_oracle_shock,
+                shock_up,
+            )?,
+            None => StrictOraclePrice::new(
+                quote_oracle_price_data.price,
+                quote_spot_market
+                    .historical_oracle_data
+                    .last_oracle_price_twap_5min,
+                calculation.context.strict,
+            ),
+        };
         drop(quote_spot_market);
 
         let (oracle_price_data, oracle_validity) = oracle_map.get_price_data_and_validity(
@@ -475,6 +757,28 @@ pub fn calculate_margin_requirement_and_total_collateral_and_liability_info(
             market.get_max_confidence_interval_multiplier()?,
         )?;
 
+        let stressed_oracle_price_data;
+        let oracle_price_data = match calculation.context.stress_shock_up() {
+            Some(shock_up) => {
+                // perp positions are valued at the widened price in the direction of the shock
+                let stressed_price = get_stressed_strict_oracle_price(
+                    oracle_price_data,
+                    market.stress_oracle_shock,
+                    shock_up,
+                )?;
+                stressed_oracle_price_data = OraclePriceData {
+                    price: if shock_up {
+                        stressed_price.max()
+                    } else {
+                        stressed_price.min()
+                    },
+                    ..*oracle_price_data
+                };
+                &stressed_oracle_price_data
+            }
+            None => oracle_price_data,
+        };
+
         let (
             perp_margin_requirement,
             weighted_pnl,
@@ -486,7 +790,7 @@ pub fn calculate_margin_requirement_and_total_collateral_and_liability_info(
             oracle_price_data,
             &strict_quote_price,
             context.margin_type,
-            user_custom_margin_ratio,
+            perp_custom_margin_ratio,
             calculation.track_open_orders_fraction(),
         )?;
 
@@ -496,15 +800,35 @@ pub fn calculate_margin_requirement_and_total_collateral_and_liability_info(
             MarketIdentifier::perp(market.market_index),
         )?;
 
+        // cross margin spot positions cant offset an isolated perp position
+        let hedge_offset = if is_isolated {
+            0
+        } else {
+            calculate_perp_hedge_offset_for_user(
+                user,
+                market_position,
+                market,
+                spot_market_map,
+                oracle_price_data.price,
+                context.margin_type,
+                perp_custom_margin_ratio,
+                &mut hedged_spot_base_asset_amounts,
+            )?
+            .min(perp_margin_requirement)
+        };
+
+        if hedge_offset > 0 {
+            calculation
+                .add_hedge_offset(hedge_offset, MarketIdentifier::perp(market.market_index))?;
+        }
+
         if calculation.track_open_orders_fraction() {
             calculation.add_open_orders_margin_requirement(open_order_margin_requirement)?;
         }
 
         calculation.add_total_collateral(weighted_pnl)?;
 
-        #[cfg(feature = "drift-rs")]
         calculation.add_perp_liability_value(worst_case_base_asset_value)?;
-        #[cfg(feature = "drift-rs")]
         calculation.add_perp_pnl(weighted_pnl)?;
 
         let has_perp_liability = market_position.base_asset_amount != 0
@@ -517,6 +841,9 @@ pub fn calculate_margin_requirement_and_total_collateral_and_liability_info(
             calculation.update_with_perp_isolated_liability(
                 market.contract_tier == ContractTier::Isolated,
             );
+            calculation.update_stress_check_required(
+                market.contract_tier == ContractTier::Isolated && market.stress_oracle_shock > 0,
+            );
         }
 
         if has_perp_liability || calculation.context.margin_type != MarginRequirementType::Initial {
@@ -532,9 +859,90 @@ pub fn calculate_margin_requirement_and_total_collateral_and_liability_info(
     Ok(calculation)
 }
 
+#[derive(Clone, Copy, PartialEq, Debug, Eq)]
+pub enum SimulatedAction {
+    /// signed base and quote deltas, e.g. a 1 SOL long filled at $100 is base 1e9, quote -100e6
+    /// fees can be included in the quote delta
+    PerpFill {
+        market_index: u16,
+        base_asset_amount: i64,
+        quote_asset_amount: i64,
+    },
+    /// signed token delta, positive for a deposit and negative for a withdraw or borrow
+    SpotTokenDelta {
+        market_index: u16,
+        token_amount: i128,
+    },
+}
+
+/// Applies hypothetical fills and deposits to a copy of the user and returns the resulting margin calculation,
+/// with per market margin requirements and leverage tracked. Markets and oracles for any new positions must be in the maps
+pub fn simulate_margin_calculation(
+    user: &User,
+    perp_market_map: &PerpMarketMap,
+    spot_market_map: &SpotMarketMap,
+    oracle_map: &mut OracleMap,
+    actions: &[SimulatedAction],
+    context: MarginContext,
+) -> DriftResult<MarginCalculation> {
+    let mut simulated_user = Box::new(*user);
+
+    for action in actions.iter() {
+        match *action {
+            SimulatedAction::PerpFill {
+                market_index,
+                base_asset_amount,
+                quote_asset_amount,
+            } => {
+                let perp_position = simulated_user.force_get_perp_position_mut(market_index)?;
+                perp_position.base_asset_amount = perp_position
+                    .base_asset_amount
+                    .safe_add(base_asset_amount)?;
+                perp_position.quote_asset_amount = perp_position
+                    .quote_asset_amount
+                    .safe_add(quote_asset_amount)?;
+            }
+            SimulatedAction::SpotTokenDelta {
+                market_index,
+                token_amount,
+            } => {
+                let spot_market = spot_market_map.get_ref(&market_index)?;
+                let spot_position = simulated_user.force_get_spot_position_mut(market_index)?;
+
+                let new_token_amount = spot_position
+                    .get_signed_token_amount(&spot_market)?
+                    .safe_add(token_amount)?;
+
+                let balance_type = if new_token_amount >= 0 {
+                    SpotBalanceType::Deposit
+                } else {
+                    SpotBalanceType::Borrow
+                };
+
+                spot_position.balance_type = balance_type;
+                spot_position.scaled_balance = get_spot_balance(
+                    new_token_amount.unsigned_abs(),
+                    &spot_market,
+                    &balance_type,
+                    balance_type == SpotBalanceType::Borrow,
+                )?
+                .cast()?;
+            }
+        }
+    }
+
+    calculate_margin_requirement_and_total_collateral_and_liability_info(
+        &simulated_user,
+        perp_market_map,
+        spot_market_map,
+        oracle_map,
+        context.track_details(),
+    )
+}
+
 pub fn validate_any_isolated_tier_requirements(
     user: &User,
-    calculation: MarginCalculation,
+    calculation: &MarginCalculation,
 ) -> DriftResult {
     if calculation.with_perp_isolated_liability && !user.is_reduce_only() {
         validate!(
@@ -597,7 +1005,7 @@ pub fn meets_withdraw_margin_requirement(
         )?;
     }
 
-    validate_any_isolated_tier_requirements(user, calculation)?;
+    validate_any_isolated_tier_requirements(user, &calculation)?;
 
     validate!(
         calculation.meets_margin_requirement(),
@@ -607,6 +1015,16 @@ pub fn meets_withdraw_margin_requirement(
         calculation.margin_requirement
     )?;
 
+    if margin_requirement_type == MarginRequirementType::Initial
+        && calculation.stress_check_required
+    {
+        validate!(
+            meets_stress_margin_requirement(user, perp_market_map, spot_market_map, oracle_map)?,
+            ErrorCode::InsufficientCollateral,
+            "User with isolated tier liability attempting to withdraw below stress margin requirement"
+        )?;
+    }
+
     Ok(true)
 }
 
@@ -642,7 +1060,55 @@ pub fn meets_place_order_margin_requirement(
         return Err(ErrorCode::InsufficientCollateral);
     }
 
-    validate_any_isolated_tier_requirements(user, calculation)?;
+    validate_any_isolated_tier_requirements(user, &calculation)?;
+
+    if risk_increasing && calculation.stress_check_required {
+        validate!(
+            meets_stress_margin_requirement(user, perp_market_map, spot_market_map, oracle_map)?,
+            ErrorCode::InsufficientCollateral,
+            "User with isolated tier liability does not meet stress margin requirement"
+        )?;
+    }
+
+    validate_isolated_perp_positions_margin_requirement(
+        user,
+        perp_market_map,
+        spot_market_map,
+        oracle_map,
+        context,
+    )
+}
+
+/// Each isolated perp position must meet the requirement with its own deposit
+pub fn validate_isolated_perp_positions_margin_requirement(
+    user: &User,
+    perp_market_map: &PerpMarketMap,
+    spot_market_map: &SpotMarketMap,
+    oracle_map: &mut OracleMap,
+    context: MarginContext,
+) -> DriftResult {
+    for margin_scope in user.get_margin_scopes() {
+        if !margin_scope.is_isolated() {
+            continue;
+        }
+
+        let calculation = calculate_margin_requirement_and_total_collateral_and_liability_info(
+            user,
+            perp_market_map,
+            spot_market_map,
+            oracle_map,
+            context.scope(margin_scope),
+        )?;
+
+        validate!(
+            calculation.meets_margin_requirement(),
+            ErrorCode::InsufficientCollateral,
+            "{:?} total_collateral={} is below margin_requirement={}",
+            margin_scope,
+            calculation.total_collateral,
+            calculation.margin_requirement
+        )?;
+    }
 
     Ok(())
 }
@@ -652,13 +1118,14 @@ pub fn meets_initial_margin_requirement(
     perp_market_map: &PerpMarketMap,
     spot_market_map: &SpotMarketMap,
     oracle_map: &mut OracleMap,
+    margin_scope: MarginScope,
 ) -> DriftResult<bool> {
     calculate_margin_requirement_and_total_collateral_and_liability_info(
         user,
         perp_market_map,
         spot_market_map,
         oracle_map,
-        MarginContext::standard(MarginRequirementType::Initial),
+        MarginContext::standard(MarginRequirementType::Initial).scope(margin_scope),
     )
     .map(|calc| calc.meets_margin_requirement())
 }
@@ -668,17 +1135,48 @@ pub fn meets_maintenance_margin_requirement(
     perp_market_map: &PerpMarketMap,
     spot_market_map: &SpotMarketMap,
     oracle_map: &mut OracleMap,
+    margin_scope: MarginScope,
 ) -> DriftResult<bool> {
     calculate_margin_requirement_and_total_collateral_and_liability_info(
         user,
         perp_market_map,
         spot_market_map,
         oracle_map,
-        MarginContext::standard(MarginRequirementType::Maintenance),
+        MarginContext::standard(MarginRequirementType::Maintenance).scope(margin_scope),
     )
     .map(|calc| calc.meets_margin_requirement())
 }
 
+/// Checks maintenance margin with every oracle shocked down and then up
+pub fn meets_stress_margin_requirement(
+    user: &User,
+    perp_market_map: &PerpMarketMap,
+    spot_market_map: &SpotMarketMap,
+    oracle_map: &mut OracleMap,
+) -> DriftResult<bool> {
+    for shock_up in [false, true] {
+        let calculation = calculate_margin_requirement_and_total_collateral_and_liability_info(
+            user,
+            perp_market_map,
+            spot_market_map,
+            oracle_map,
+            MarginContext::stress(shock_up),
+        )?;
+
+        if !calculation.meets_margin_requirement() {
+            msg!(
+                "stress shock_up={} total_collateral={}, margin_requirement={}",
+                shock_up,
+                calculation.total_collateral,
+                calculation.margin_requirement
+            );
+            return Ok(false);
+        }
+    }
+
+    Ok(true)
+}
+
 pub fn calculate_max_withdrawable_amount(
     market_index: u16,
     user: &User,
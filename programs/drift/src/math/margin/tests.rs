@@ -549,8 +549,8 @@ mod calculate_margin_requirement_and_total_collateral {
     use crate::create_anchor_account_info;
     use crate::math::constants::{
         AMM_RESERVE_PRECISION, BASE_PRECISION_I64, LIQUIDATION_FEE_PRECISION, MARGIN_PRECISION,
-        PEG_PRECISION, SPOT_BALANCE_PRECISION, SPOT_BALANCE_PRECISION_U64,
-        SPOT_CUMULATIVE_INTEREST_PRECISION, SPOT_WEIGHT_PRECISION,
+        PEG_PRECISION, QUOTE_PRECISION, QUOTE_PRECISION_I64, SPOT_BALANCE_PRECISION,
+        SPOT_BALANCE_PRECISION_U64, SPOT_CUMULATIVE_INTEREST_PRECISION, SPOT_WEIGHT_PRECISION,
     };
     use crate::math::margin::{
         calculate_margin_requirement_and_total_collateral_and_liability_info, MarginRequirementType,
@@ -562,7 +562,7 @@ mod calculate_margin_requirement_and_total_collateral {
     use crate::state::perp_market_map::PerpMarketMap;
     use crate::state::spot_market::{SpotBalanceType, SpotMarket};
     use crate::state::spot_market_map::SpotMarketMap;
-    use crate::state::user::{Order, PerpPosition, SpotPosition, User};
+    use crate::state::user::{MarketType, Order, PerpPosition, SpotPosition, User};
     use crate::test_utils::*;
     use crate::test_utils::{get_positions, get_pyth_price};
 
@@ -1040,7 +1040,7 @@ mod calculate_margin_requirement_and_total_collateral {
     }
 
     #[test]
-    pub fn user_dust_deposit() {
+    pub fn user_market_max_leverage() {
         let slot = 0_u64;
 
         let mut sol_oracle_price = get_pyth_price(100, 6);
@@ -1094,7 +1094,7 @@ mod calculate_margin_requirement_and_total_collateral {
             market_index: 1,
             oracle_source: OracleSource::Pyth,
             oracle: sol_oracle_price_key,
-            cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION / 99, // big loss
+            cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
             cumulative_borrow_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
             decimals: 9,
             initial_asset_weight: 8 * SPOT_WEIGHT_PRECISION / 10,
@@ -1113,25 +1113,32 @@ mod calculate_margin_requirement_and_total_collateral {
             SpotMarketMap::load_multiple(spot_market_account_infos, true).unwrap();
 
         let mut spot_positions = [SpotPosition::default(); 8];
+        spot_positions[0] = SpotPosition {
+            market_index: 0,
+            balance_type: SpotBalanceType::Deposit,
+            scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
+            ..SpotPosition::default()
+        };
         spot_positions[1] = SpotPosition {
             market_index: 1,
-            balance_type: SpotBalanceType::Deposit,
-            scaled_balance: 1040,
+            balance_type: SpotBalanceType::Borrow,
+            scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
             ..SpotPosition::default()
         };
 
-        let user = User {
+        let mut user = User {
             orders: [Order::default(); 32],
+            perp_positions: get_positions(PerpPosition {
+                market_index: 0,
+                base_asset_amount: 100 * BASE_PRECISION_I64,
+                ..PerpPosition::default()
+            }),
             spot_positions,
-            max_margin_ratio: 2 * MARGIN_PRECISION as u32, // .5x leverage
             ..User::default()
         };
 
         let MarginCalculation {
-            total_collateral,
-            margin_requirement,
-            all_oracles_valid: oracles_valid,
-            ..
+            margin_requirement, ..
         } = calculate_margin_requirement_and_total_collateral_and_liability_info(
             &user,
             &perp_market_map,
@@ -1141,27 +1148,76 @@ mod calculate_margin_requirement_and_total_collateral {
         )
         .unwrap();
 
-        assert_eq!(
-            user.spot_positions[0]
-                .get_token_amount(&usdc_spot_market)
-                .unwrap(),
-            0
-        );
+        assert_eq!(margin_requirement, 13000000000); // 100 * $100 * .1 + 100 * $100 * 1.2
 
-        assert_eq!(
-            user.spot_positions[1]
-                .get_token_amount(&sol_spot_market)
-                .unwrap(),
-            10
-        );
+        // cap perp at 4x
+        user.update_market_max_leverage(MarketType::Perp, 0, 4)
+            .unwrap();
 
-        assert_eq!(oracles_valid, false);
-        assert_eq!(total_collateral, 0); // todo not 0
-        assert_eq!(margin_requirement, 0);
+        let MarginCalculation {
+            margin_requirement, ..
+        } = calculate_margin_requirement_and_total_collateral_and_liability_info(
+            &user,
+            &perp_market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            MarginContext::standard(MarginRequirementType::Initial),
+        )
+        .unwrap();
+
+        assert_eq!(margin_requirement, 14500000000); // 100 * $100 * .25 + 100 * $100 * 1.2
+
+        // cap sol borrow at 2x
+        user.update_market_max_leverage(MarketType::Spot, 1, 2)
+            .unwrap();
+
+        let MarginCalculation {
+            margin_requirement, ..
+        } = calculate_margin_requirement_and_total_collateral_and_liability_info(
+            &user,
+            &perp_market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            MarginContext::standard(MarginRequirementType::Initial),
+        )
+        .unwrap();
+
+        assert_eq!(margin_requirement, 17500000000); // 100 * $100 * .25 + 100 * $100 * 1.5
+
+        // global custom margin ratio still applies when larger
+        user.max_margin_ratio = MARGIN_PRECISION as u32 / 2; // 2x leverage
+
+        let MarginCalculation {
+            margin_requirement, ..
+        } = calculate_margin_requirement_and_total_collateral_and_liability_info(
+            &user,
+            &perp_market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            MarginContext::standard(MarginRequirementType::Initial),
+        )
+        .unwrap();
+
+        assert_eq!(margin_requirement, 20000000000); // 100 * $100 * .5 + 100 * $100 * 1.5
+
+        let MarginCalculation {
+            margin_requirement: maintenance_margin_requirement,
+            ..
+        } = calculate_margin_requirement_and_total_collateral_and_liability_info(
+            &user,
+            &perp_market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            MarginContext::standard(MarginRequirementType::Maintenance),
+        )
+        .unwrap();
+
+        // doesnt affect maintenance margin requirement
+        assert_eq!(maintenance_margin_requirement, 11500000000); // 100 * 100 * .05 + 100 * $100 * 1.1
     }
 
     #[test]
-    pub fn user_dust_borrow() {
+    pub fn user_market_max_leverage_only_affects_initial_margin() {
         let slot = 0_u64;
 
         let mut sol_oracle_price = get_pyth_price(100, 6);
@@ -1234,130 +1290,99 @@ mod calculate_margin_requirement_and_total_collateral {
             SpotMarketMap::load_multiple(spot_market_account_infos, true).unwrap();
 
         let mut spot_positions = [SpotPosition::default(); 8];
+        spot_positions[0] = SpotPosition {
+            market_index: 0,
+            balance_type: SpotBalanceType::Deposit,
+            scaled_balance: 3000 * SPOT_BALANCE_PRECISION_U64,
+            ..SpotPosition::default()
+        };
         spot_positions[1] = SpotPosition {
             market_index: 1,
             balance_type: SpotBalanceType::Borrow,
-            scaled_balance: 1,
+            scaled_balance: 10 * SPOT_BALANCE_PRECISION_U64,
             ..SpotPosition::default()
         };
 
-        let user = User {
+        // 100 sol long entered at $100, no pnl
+        let mut user = User {
             orders: [Order::default(); 32],
+            perp_positions: get_positions(PerpPosition {
+                market_index: 0,
+                base_asset_amount: 100 * BASE_PRECISION_I64,
+                quote_asset_amount: -10000 * QUOTE_PRECISION_I64,
+                ..PerpPosition::default()
+            }),
             spot_positions,
-            max_margin_ratio: 2 * MARGIN_PRECISION as u32, // .5x leverage
             ..User::default()
         };
 
-        let MarginCalculation {
-            total_collateral,
-            margin_requirement,
-            all_oracles_valid: oracles_valid,
-            ..
-        } = calculate_margin_requirement_and_total_collateral_and_liability_info(
+        let mut calculate = |user: &User, context: MarginContext| {
+            calculate_margin_requirement_and_total_collateral_and_liability_info(
+                user,
+                &perp_market_map,
+                &spot_market_map,
+                &mut oracle_map,
+                context,
+            )
+            .unwrap()
+        };
+
+        let initial = calculate(
             &user,
-            &perp_market_map,
-            &spot_market_map,
-            &mut oracle_map,
             MarginContext::standard(MarginRequirementType::Initial),
-        )
-        .unwrap();
-
-        assert_eq!(
-            user.spot_positions[0]
-                .get_token_amount(&usdc_spot_market)
-                .unwrap(),
-            0
         );
+        assert_eq!(initial.margin_requirement, 2200 * QUOTE_PRECISION); // 100 * $100 * .1 + 10 * $100 * 1.2
+        assert!(initial.meets_margin_requirement());
 
-        assert_eq!(
-            user.spot_positions[1]
-                .get_token_amount(&sol_spot_market)
-                .unwrap(),
-            1
+        let maintenance = calculate(
+            &user,
+            MarginContext::standard(MarginRequirementType::Maintenance),
         );
+        let liquidation = calculate(&user, MarginContext::liquidation(0));
 
-        assert_eq!(oracles_valid, false);
-        assert_eq!(total_collateral, 0); // todo not 0
-        assert_eq!(margin_requirement, 3);
-
-        let mut sol_oracle_price = get_pyth_price(1, 6);
-        sol_oracle_price.agg.price /= 10000; // < 1 penny
+        // cap perp at 2x and sol borrow at 2x
+        user.update_market_max_leverage(MarketType::Perp, 0, 2)
+            .unwrap();
+        user.update_market_max_leverage(MarketType::Spot, 1, 2)
+            .unwrap();
 
-        let sol_oracle_price_key =
-            Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
-        let pyth_program = crate::ids::pyth_program::id();
-        create_account_info!(
-            sol_oracle_price,
-            &sol_oracle_price_key,
-            &pyth_program,
-            oracle_account_info
-        );
-        let mut oracle_map = OracleMap::load_one(&oracle_account_info, slot, None).unwrap();
-        let MarginCalculation {
-            total_collateral,
-            margin_requirement,
-            all_oracles_valid: oracles_valid,
-            ..
-        } = calculate_margin_requirement_and_total_collateral_and_liability_info(
+        let capped_initial = calculate(
             &user,
-            &perp_market_map,
-            &spot_market_map,
-            &mut oracle_map,
             MarginContext::standard(MarginRequirementType::Initial),
-        )
-        .unwrap();
+        );
+        assert_eq!(capped_initial.margin_requirement, 6500 * QUOTE_PRECISION); // 100 * $100 * .5 + 10 * $100 * 1.5
+        assert!(!capped_initial.meets_margin_requirement());
 
+        // maintenance margin is unchanged
+        let capped_maintenance = calculate(
+            &user,
+            MarginContext::standard(MarginRequirementType::Maintenance),
+        );
         assert_eq!(
-            user.spot_positions[0]
-                .get_token_amount(&usdc_spot_market)
-                .unwrap(),
-            0
+            capped_maintenance.margin_requirement,
+            1600 * QUOTE_PRECISION
+        ); // 100 * $100 * .05 + 10 * $100 * 1.1
+        assert_eq!(
+            capped_maintenance.margin_requirement,
+            maintenance.margin_requirement
         );
-
         assert_eq!(
-            user.spot_positions[1]
-                .get_token_amount(&sol_spot_market)
-                .unwrap(),
-            1
+            capped_maintenance.total_collateral,
+            maintenance.total_collateral
         );
+        assert!(capped_maintenance.meets_margin_requirement());
 
-        assert_eq!(oracles_valid, false);
-        assert_eq!(total_collateral, 0); // todo not 0
-        assert_eq!(margin_requirement, 3);
+        // so the cap can't make the user liquidatable
+        let capped_liquidation = calculate(&user, MarginContext::liquidation(0));
+        assert_eq!(
+            capped_liquidation.margin_requirement,
+            liquidation.margin_requirement
+        );
+        assert!(capped_liquidation.meets_margin_requirement());
     }
-}
-
-#[cfg(test)]
-mod calculate_margin_requirement_and_total_collateral_and_liability_info {
-    use std::str::FromStr;
-
-    use anchor_lang::Owner;
-    use solana_program::pubkey::Pubkey;
-
-    use crate::controller::position::PositionDirection;
-    use crate::math::constants::{
-        AMM_RESERVE_PRECISION, LIQUIDATION_FEE_PRECISION, PEG_PRECISION, QUOTE_PRECISION,
-        SPOT_BALANCE_PRECISION, SPOT_BALANCE_PRECISION_U64, SPOT_CUMULATIVE_INTEREST_PRECISION,
-        SPOT_WEIGHT_PRECISION,
-    };
-    use crate::math::margin::{
-        calculate_margin_requirement_and_total_collateral_and_liability_info, MarginRequirementType,
-    };
-    use crate::state::margin_calculation::{MarginCalculation, MarginContext};
-    use crate::state::oracle::{HistoricalOracleData, OracleSource};
-    use crate::state::oracle_map::OracleMap;
-    use crate::state::perp_market::{ContractTier, MarketStatus, PerpMarket, AMM};
-    use crate::state::perp_market_map::PerpMarketMap;
-    use crate::state::spot_market::{SpotBalanceType, SpotMarket};
-    use crate::state::spot_market_map::SpotMarketMap;
-    use crate::state::user::{Order, OrderType, PerpPosition, SpotPosition, User};
-    use crate::test_utils::*;
-    use crate::test_utils::{get_positions, get_pyth_price};
-    use crate::{create_account_info, PRICE_PRECISION_I64};
-    use crate::{create_anchor_account_info, BASE_PRECISION_I64};
 
     #[test]
-    fn no_perp_position_but_trigger_order() {
+    pub fn user_dust_deposit() {
         let slot = 0_u64;
 
         let mut sol_oracle_price = get_pyth_price(100, 6);
@@ -1411,7 +1436,7 @@ mod calculate_margin_requirement_and_total_collateral_and_liability_info {
             market_index: 1,
             oracle_source: OracleSource::Pyth,
             oracle: sol_oracle_price_key,
-            cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION / 99, // big loss
             cumulative_borrow_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
             decimals: 9,
             initial_asset_weight: 8 * SPOT_WEIGHT_PRECISION / 10,
@@ -1430,29 +1455,26 @@ mod calculate_margin_requirement_and_total_collateral_and_liability_info {
             SpotMarketMap::load_multiple(spot_market_account_infos, true).unwrap();
 
         let mut spot_positions = [SpotPosition::default(); 8];
-        spot_positions[0] = SpotPosition {
-            market_index: 0,
+        spot_positions[1] = SpotPosition {
+            market_index: 1,
             balance_type: SpotBalanceType::Deposit,
-            scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
+            scaled_balance: 1040,
             ..SpotPosition::default()
         };
 
         let user = User {
-            orders: get_orders(Order {
-                order_type: OrderType::TriggerMarket,
-                ..Order::default()
-            }),
-            perp_positions: get_positions(PerpPosition {
-                market_index: 0,
-                base_asset_amount: 0,
-                open_orders: 1,
-                ..PerpPosition::default()
-            }),
+            orders: [Order::default(); 32],
             spot_positions,
+            max_margin_ratio: 2 * MARGIN_PRECISION as u32, // .5x leverage
             ..User::default()
         };
 
-        let calculation = calculate_margin_requirement_and_total_collateral_and_liability_info(
+        let MarginCalculation {
+            total_collateral,
+            margin_requirement,
+            all_oracles_valid: oracles_valid,
+            ..
+        } = calculate_margin_requirement_and_total_collateral_and_liability_info(
             &user,
             &perp_market_map,
             &spot_market_map,
@@ -1461,14 +1483,27 @@ mod calculate_margin_requirement_and_total_collateral_and_liability_info {
         )
         .unwrap();
 
-        assert_eq!(calculation.margin_requirement, QUOTE_PRECISION / 100);
-        assert_eq!(calculation.get_num_of_liabilities().unwrap(), 1);
-        assert_eq!(calculation.with_perp_isolated_liability, false);
-        assert_eq!(calculation.with_spot_isolated_liability, false);
-    }
-
-    #[test]
-    fn isolated_contract_tier_count_and_check() {
+        assert_eq!(
+            user.spot_positions[0]
+                .get_token_amount(&usdc_spot_market)
+                .unwrap(),
+            0
+        );
+
+        assert_eq!(
+            user.spot_positions[1]
+                .get_token_amount(&sol_spot_market)
+                .unwrap(),
+            10
+        );
+
+        assert_eq!(oracles_valid, false);
+        assert_eq!(total_collateral, 0); // todo not 0
+        assert_eq!(margin_requirement, 0);
+    }
+
+    #[test]
+    pub fn user_dust_borrow() {
         let slot = 0_u64;
 
         let mut sol_oracle_price = get_pyth_price(100, 6);
@@ -1500,7 +1535,6 @@ mod calculate_margin_requirement_and_total_collateral_and_liability_info {
             margin_ratio_initial: 1000,
             margin_ratio_maintenance: 500,
             status: MarketStatus::Initialized,
-            contract_tier: ContractTier::Isolated,
             ..PerpMarket::default()
         };
         create_anchor_account_info!(market, PerpMarket, market_account_info);
@@ -1541,91 +1575,132 @@ mod calculate_margin_requirement_and_total_collateral_and_liability_info {
         let spot_market_map =
             SpotMarketMap::load_multiple(spot_market_account_infos, true).unwrap();
 
-        // just usdc and resting limit order
         let mut spot_positions = [SpotPosition::default(); 8];
-        spot_positions[0] = SpotPosition {
-            market_index: 0,
-            balance_type: SpotBalanceType::Deposit,
-            scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
+        spot_positions[1] = SpotPosition {
+            market_index: 1,
+            balance_type: SpotBalanceType::Borrow,
+            scaled_balance: 1,
             ..SpotPosition::default()
         };
 
         let user = User {
-            orders: get_orders(Order {
-                order_type: OrderType::Limit,
-                base_asset_amount: 1,
-                base_asset_amount_filled: 0,
-                ..Order::default()
-            }),
-            perp_positions: get_positions(PerpPosition {
-                market_index: 0,
-                base_asset_amount: 0,
-                open_orders: 1,
-                ..PerpPosition::default()
-            }),
+            orders: [Order::default(); 32],
             spot_positions,
+            max_margin_ratio: 2 * MARGIN_PRECISION as u32, // .5x leverage
             ..User::default()
         };
 
-        let calculation: MarginCalculation =
-            calculate_margin_requirement_and_total_collateral_and_liability_info(
-                &user,
-                &perp_market_map,
-                &spot_market_map,
-                &mut oracle_map,
-                MarginContext::standard(MarginRequirementType::Initial),
-            )
-            .unwrap();
+        let MarginCalculation {
+            total_collateral,
+            margin_requirement,
+            all_oracles_valid: oracles_valid,
+            ..
+        } = calculate_margin_requirement_and_total_collateral_and_liability_info(
+            &user,
+            &perp_market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            MarginContext::standard(MarginRequirementType::Initial),
+        )
+        .unwrap();
 
-        assert_eq!(calculation.margin_requirement, QUOTE_PRECISION / 100);
-        assert_eq!(calculation.get_num_of_liabilities().unwrap(), 1);
-        assert_eq!(calculation.with_spot_isolated_liability, false);
-        assert_eq!(calculation.with_perp_isolated_liability, true);
+        assert_eq!(
+            user.spot_positions[0]
+                .get_token_amount(&usdc_spot_market)
+                .unwrap(),
+            0
+        );
 
-        // just usdc, long iso perp, resting limit order to close
-        let mut spot_positions = [SpotPosition::default(); 8];
-        spot_positions[0] = SpotPosition {
-            market_index: 0,
-            balance_type: SpotBalanceType::Deposit,
-            scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
-            ..SpotPosition::default()
-        };
+        assert_eq!(
+            user.spot_positions[1]
+                .get_token_amount(&sol_spot_market)
+                .unwrap(),
+            1
+        );
 
-        let user = User {
-            orders: get_orders(Order {
-                order_type: OrderType::Limit,
-                base_asset_amount: market.amm.order_step_size,
-                base_asset_amount_filled: 0,
-                direction: PositionDirection::Short,
-                ..Order::default()
-            }),
-            perp_positions: get_positions(PerpPosition {
-                market_index: 0,
-                base_asset_amount: market.amm.order_step_size as i64,
-                open_orders: 1,
-                ..PerpPosition::default()
-            }),
-            spot_positions,
-            ..User::default()
-        };
+        assert_eq!(oracles_valid, false);
+        assert_eq!(total_collateral, 0); // todo not 0
+        assert_eq!(margin_requirement, 3);
 
-        let calculation: MarginCalculation =
-            calculate_margin_requirement_and_total_collateral_and_liability_info(
-                &user,
-                &perp_market_map,
-                &spot_market_map,
-                &mut oracle_map,
-                MarginContext::standard(MarginRequirementType::Initial),
-            )
-            .unwrap();
+        let mut sol_oracle_price = get_pyth_price(1, 6);
+        sol_oracle_price.agg.price /= 10000; // < 1 penny
 
-        assert_eq!(calculation.margin_requirement, 110000);
-        assert_eq!(calculation.get_num_of_liabilities().unwrap(), 1);
-        assert_eq!(calculation.with_perp_isolated_liability, true);
+        let sol_oracle_price_key =
+            Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+        let pyth_program = crate::ids::pyth_program::id();
+        create_account_info!(
+            sol_oracle_price,
+            &sol_oracle_price_key,
+            &pyth_program,
+            oracle_account_info
+        );
+        let mut oracle_map = OracleMap::load_one(&oracle_account_info, slot, None).unwrap();
+        let MarginCalculation {
+            total_collateral,
+            margin_requirement,
+            all_oracles_valid: oracles_valid,
+            ..
+        } = calculate_margin_requirement_and_total_collateral_and_liability_info(
+            &user,
+            &perp_market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            MarginContext::standard(MarginRequirementType::Initial),
+        )
+        .unwrap();
+
+        assert_eq!(
+            user.spot_positions[0]
+                .get_token_amount(&usdc_spot_market)
+                .unwrap(),
+            0
+        );
+
+        assert_eq!(
+            user.spot_positions[1]
+                .get_token_amount(&sol_spot_market)
+                .unwrap(),
+            1
+        );
+
+        assert_eq!(oracles_valid, false);
+        assert_eq!(total_collateral, 0); // todo not 0
+        assert_eq!(margin_requirement, 3);
     }
+}
+
+#[cfg(test)]
+mod calculate_margin_requirement_and_total_collateral_and_liability_info {
+    use std::str::FromStr;
+
+    use anchor_lang::Owner;
+    use solana_program::pubkey::Pubkey;
+
+    use crate::controller::position::PositionDirection;
+    use crate::math::constants::{
+        AMM_RESERVE_PRECISION, LIQUIDATION_FEE_PRECISION, PEG_PRECISION, QUOTE_PRECISION,
+        QUOTE_PRECISION_I128, QUOTE_PRECISION_I64, SPOT_BALANCE_PRECISION,
+        SPOT_BALANCE_PRECISION_U64, SPOT_CUMULATIVE_INTEREST_PRECISION, SPOT_WEIGHT_PRECISION,
+    };
+    use crate::math::margin::{
+        calculate_margin_requirement_and_total_collateral_and_liability_info,
+        meets_stress_margin_requirement, MarginRequirementType,
+    };
+    use crate::state::margin_calculation::{MarginCalculation, MarginContext};
+    use crate::state::oracle::{HistoricalOracleData, OracleSource};
+    use crate::state::oracle_map::OracleMap;
+    use crate::state::perp_market::{ContractTier, MarketStatus, PerpMarket, AMM};
+    use crate::state::perp_market_map::PerpMarketMap;
+    use crate::state::spot_market::{SpotBalanceType, SpotMarket};
+    use crate::state::spot_market_map::SpotMarketMap;
+    use crate::state::user::{Order, OrderType, PerpPosition, SpotPosition, User};
+    use crate::test_utils::*;
+    use crate::test_utils::{get_positions, get_pyth_price};
+    use crate::{create_account_info, PRICE_PRECISION_I64};
+    use crate::{create_anchor_account_info, BASE_PRECISION_I64};
 
     #[test]
-    fn no_spot_position_but_trigger_order() {
+    fn no_perp_position_but_trigger_order() {
         let slot = 0_u64;
 
         let mut sol_oracle_price = get_pyth_price(100, 6);
@@ -1704,19 +1779,18 @@ mod calculate_margin_requirement_and_total_collateral_and_liability_info {
             scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
             ..SpotPosition::default()
         };
-        spot_positions[1] = SpotPosition {
-            market_index: 1,
-            balance_type: SpotBalanceType::Deposit,
-            scaled_balance: 0,
-            open_orders: 1,
-            ..SpotPosition::default()
-        };
 
         let user = User {
             orders: get_orders(Order {
                 order_type: OrderType::TriggerMarket,
                 ..Order::default()
             }),
+            perp_positions: get_positions(PerpPosition {
+                market_index: 0,
+                base_asset_amount: 0,
+                open_orders: 1,
+                ..PerpPosition::default()
+            }),
             spot_positions,
             ..User::default()
         };
@@ -1732,10 +1806,12 @@ mod calculate_margin_requirement_and_total_collateral_and_liability_info {
 
         assert_eq!(calculation.margin_requirement, QUOTE_PRECISION / 100);
         assert_eq!(calculation.get_num_of_liabilities().unwrap(), 1);
+        assert_eq!(calculation.with_perp_isolated_liability, false);
+        assert_eq!(calculation.with_spot_isolated_liability, false);
     }
 
     #[test]
-    pub fn usdc_less_than_1_with_deposit() {
+    fn isolated_contract_tier_count_and_check() {
         let slot = 0_u64;
 
         let mut sol_oracle_price = get_pyth_price(100, 6);
@@ -1746,24 +1822,32 @@ mod calculate_margin_requirement_and_total_collateral_and_liability_info {
             sol_oracle_price,
             &sol_oracle_price_key,
             &pyth_program,
-            sol_oracle_account_info
-        );
-
-        let mut usdc_oracle_price = get_hardcoded_pyth_price(99 * 10000, 6); // $.99
-        let usdc_oracle_price_key =
-            Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
-        let pyth_program = crate::ids::pyth_program::id();
-        create_account_info!(
-            usdc_oracle_price,
-            &usdc_oracle_price_key,
-            &pyth_program,
-            usdc_oracle_account_info
+            oracle_account_info
         );
-        let oracle_account_infos = Vec::from([sol_oracle_account_info, usdc_oracle_account_info]);
-        let mut oracle_map =
-            OracleMap::load(&mut oracle_account_infos.iter().peekable(), slot, None).unwrap();
+        let mut oracle_map = OracleMap::load_one(&oracle_account_info, slot, None).unwrap();
 
-        let market_map = PerpMarketMap::empty();
+        let mut market = PerpMarket {
+            amm: AMM {
+                base_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                bid_base_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+                bid_quote_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+                ask_base_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+                ask_quote_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+                sqrt_k: 100 * AMM_RESERVE_PRECISION,
+                peg_multiplier: 100 * PEG_PRECISION,
+                order_step_size: 10000000,
+                oracle: sol_oracle_price_key,
+                ..AMM::default()
+            },
+            margin_ratio_initial: 1000,
+            margin_ratio_maintenance: 500,
+            status: MarketStatus::Initialized,
+            contract_tier: ContractTier::Isolated,
+            ..PerpMarket::default()
+        };
+        create_anchor_account_info!(market, PerpMarket, market_account_info);
+        let perp_market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
 
         let mut usdc_spot_market = SpotMarket {
             market_index: 0,
@@ -1775,14 +1859,13 @@ mod calculate_margin_requirement_and_total_collateral_and_liability_info {
             deposit_balance: 10000 * SPOT_BALANCE_PRECISION,
             liquidator_fee: 0,
             historical_oracle_data: HistoricalOracleData::default_quote_oracle(),
-            oracle: usdc_oracle_price_key,
             ..SpotMarket::default()
         };
         create_anchor_account_info!(usdc_spot_market, SpotMarket, usdc_spot_market_account_info);
         let mut sol_spot_market = SpotMarket {
             market_index: 1,
             oracle_source: OracleSource::Pyth,
-            oracle: usdc_oracle_price_key,
+            oracle: sol_oracle_price_key,
             cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
             cumulative_borrow_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
             decimals: 9,
@@ -1801,86 +1884,201 @@ mod calculate_margin_requirement_and_total_collateral_and_liability_info {
         let spot_market_map =
             SpotMarketMap::load_multiple(spot_market_account_infos, true).unwrap();
 
+        // just usdc and resting limit order
         let mut spot_positions = [SpotPosition::default(); 8];
         spot_positions[0] = SpotPosition {
             market_index: 0,
             balance_type: SpotBalanceType::Deposit,
-            scaled_balance: SPOT_BALANCE_PRECISION_U64,
+            scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
             ..SpotPosition::default()
         };
+
         let user = User {
-            orders: [Order::default(); 32],
-            perp_positions: [PerpPosition::default(); 8],
+            orders: get_orders(Order {
+                order_type: OrderType::Limit,
+                base_asset_amount: 1,
+                base_asset_amount_filled: 0,
+                ..Order::default()
+            }),
+            perp_positions: get_positions(PerpPosition {
+                market_index: 0,
+                base_asset_amount: 0,
+                open_orders: 1,
+                ..PerpPosition::default()
+            }),
             spot_positions,
             ..User::default()
         };
 
-        let MarginCalculation {
-            total_collateral,
-            margin_requirement,
-            ..
-        } = calculate_margin_requirement_and_total_collateral_and_liability_info(
-            &user,
-            &market_map,
-            &spot_market_map,
-            &mut oracle_map,
-            MarginContext::standard(MarginRequirementType::Initial).strict(true),
-        )
-        .unwrap();
+        let calculation: MarginCalculation =
+            calculate_margin_requirement_and_total_collateral_and_liability_info(
+                &user,
+                &perp_market_map,
+                &spot_market_map,
+                &mut oracle_map,
+                MarginContext::standard(MarginRequirementType::Initial),
+            )
+            .unwrap();
 
-        assert_eq!(margin_requirement, 0);
-        assert_eq!(total_collateral, 990000);
+        assert_eq!(calculation.margin_requirement, QUOTE_PRECISION / 100);
+        assert_eq!(calculation.get_num_of_liabilities().unwrap(), 1);
+        assert_eq!(calculation.with_spot_isolated_liability, false);
+        assert_eq!(calculation.with_perp_isolated_liability, true);
 
-        let mut spot_market = spot_market_map.get_ref_mut(&0).unwrap();
-        spot_market.historical_oracle_data = HistoricalOracleData {
-            last_oracle_price_twap_5min: 95 * PRICE_PRECISION_I64 / 100,
-            ..HistoricalOracleData::default()
+        // just usdc, long iso perp, resting limit order to close
+        let mut spot_positions = [SpotPosition::default(); 8];
+        spot_positions[0] = SpotPosition {
+            market_index: 0,
+            balance_type: SpotBalanceType::Deposit,
+            scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
+            ..SpotPosition::default()
         };
-        drop(spot_market);
 
-        let MarginCalculation {
-            total_collateral,
-            margin_requirement,
-            ..
-        } = calculate_margin_requirement_and_total_collateral_and_liability_info(
-            &user,
-            &market_map,
-            &spot_market_map,
-            &mut oracle_map,
-            MarginContext::standard(MarginRequirementType::Initial).strict(true),
-        )
-        .unwrap();
+        let user = User {
+            orders: get_orders(Order {
+                order_type: OrderType::Limit,
+                base_asset_amount: market.amm.order_step_size,
+                base_asset_amount_filled: 0,
+                direction: PositionDirection::Short,
+                ..Order::default()
+            }),
+            perp_positions: get_positions(PerpPosition {
+                market_index: 0,
+                base_asset_amount: market.amm.order_step_size as i64,
+                open_orders: 1,
+                ..PerpPosition::default()
+            }),
+            spot_positions,
+            ..User::default()
+        };
 
-        assert_eq!(margin_requirement, 0);
-        assert_eq!(total_collateral, 950000);
+        let calculation: MarginCalculation =
+            calculate_margin_requirement_and_total_collateral_and_liability_info(
+                &user,
+                &perp_market_map,
+                &spot_market_map,
+                &mut oracle_map,
+                MarginContext::standard(MarginRequirementType::Initial),
+            )
+            .unwrap();
 
-        let mut spot_market = spot_market_map.get_ref_mut(&0).unwrap();
-        spot_market.historical_oracle_data = HistoricalOracleData {
-            last_oracle_price_twap_5min: 101 * PRICE_PRECISION_I64 / 100,
-            ..HistoricalOracleData::default()
+        assert_eq!(calculation.margin_requirement, 110000);
+        assert_eq!(calculation.get_num_of_liabilities().unwrap(), 1);
+        assert_eq!(calculation.with_perp_isolated_liability, true);
+    }
+
+    #[test]
+    fn no_spot_position_but_trigger_order() {
+        let slot = 0_u64;
+
+        let mut sol_oracle_price = get_pyth_price(100, 6);
+        let sol_oracle_price_key =
+            Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+        let pyth_program = crate::ids::pyth_program::id();
+        create_account_info!(
+            sol_oracle_price,
+            &sol_oracle_price_key,
+            &pyth_program,
+            oracle_account_info
+        );
+        let mut oracle_map = OracleMap::load_one(&oracle_account_info, slot, None).unwrap();
+
+        let mut market = PerpMarket {
+            amm: AMM {
+                base_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                bid_base_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+                bid_quote_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+                ask_base_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+                ask_quote_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+                sqrt_k: 100 * AMM_RESERVE_PRECISION,
+                peg_multiplier: 100 * PEG_PRECISION,
+                order_step_size: 10000000,
+                oracle: sol_oracle_price_key,
+                ..AMM::default()
+            },
+            margin_ratio_initial: 1000,
+            margin_ratio_maintenance: 500,
+            status: MarketStatus::Initialized,
+            ..PerpMarket::default()
         };
-        drop(spot_market);
+        create_anchor_account_info!(market, PerpMarket, market_account_info);
+        let perp_market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
 
-        let MarginCalculation {
-            total_collateral,
-            margin_requirement,
-            ..
-        } = calculate_margin_requirement_and_total_collateral_and_liability_info(
+        let mut usdc_spot_market = SpotMarket {
+            market_index: 0,
+            oracle_source: OracleSource::QuoteAsset,
+            cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            decimals: 6,
+            initial_asset_weight: SPOT_WEIGHT_PRECISION,
+            maintenance_asset_weight: SPOT_WEIGHT_PRECISION,
+            deposit_balance: 10000 * SPOT_BALANCE_PRECISION,
+            liquidator_fee: 0,
+            historical_oracle_data: HistoricalOracleData::default_quote_oracle(),
+            ..SpotMarket::default()
+        };
+        create_anchor_account_info!(usdc_spot_market, SpotMarket, usdc_spot_market_account_info);
+        let mut sol_spot_market = SpotMarket {
+            market_index: 1,
+            oracle_source: OracleSource::Pyth,
+            oracle: sol_oracle_price_key,
+            cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            cumulative_borrow_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            decimals: 9,
+            initial_asset_weight: 8 * SPOT_WEIGHT_PRECISION / 10,
+            maintenance_asset_weight: 9 * SPOT_WEIGHT_PRECISION / 10,
+            initial_liability_weight: 12 * SPOT_WEIGHT_PRECISION / 10,
+            maintenance_liability_weight: 11 * SPOT_WEIGHT_PRECISION / 10,
+            liquidator_fee: LIQUIDATION_FEE_PRECISION / 1000,
+            ..SpotMarket::default()
+        };
+        create_anchor_account_info!(sol_spot_market, SpotMarket, sol_spot_market_account_info);
+        let spot_market_account_infos = Vec::from([
+            &usdc_spot_market_account_info,
+            &sol_spot_market_account_info,
+        ]);
+        let spot_market_map =
+            SpotMarketMap::load_multiple(spot_market_account_infos, true).unwrap();
+
+        let mut spot_positions = [SpotPosition::default(); 8];
+        spot_positions[0] = SpotPosition {
+            market_index: 0,
+            balance_type: SpotBalanceType::Deposit,
+            scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
+            ..SpotPosition::default()
+        };
+        spot_positions[1] = SpotPosition {
+            market_index: 1,
+            balance_type: SpotBalanceType::Deposit,
+            scaled_balance: 0,
+            open_orders: 1,
+            ..SpotPosition::default()
+        };
+
+        let user = User {
+            orders: get_orders(Order {
+                order_type: OrderType::TriggerMarket,
+                ..Order::default()
+            }),
+            spot_positions,
+            ..User::default()
+        };
+
+        let calculation = calculate_margin_requirement_and_total_collateral_and_liability_info(
             &user,
-            &market_map,
+            &perp_market_map,
             &spot_market_map,
             &mut oracle_map,
-            MarginContext::standard(MarginRequirementType::Initial).strict(true),
+            MarginContext::standard(MarginRequirementType::Initial),
         )
         .unwrap();
 
-        assert_eq!(margin_requirement, 0);
-        // twap is 1.01, but oracle is .99, so we use oracle
-        assert_eq!(total_collateral, 990000);
+        assert_eq!(calculation.margin_requirement, QUOTE_PRECISION / 100);
+        assert_eq!(calculation.get_num_of_liabilities().unwrap(), 1);
     }
 
     #[test]
-    pub fn usdc_more_than_1_with_borrow() {
+    pub fn usdc_less_than_1_with_deposit() {
         let slot = 0_u64;
 
         let mut sol_oracle_price = get_pyth_price(100, 6);
@@ -1894,7 +2092,7 @@ mod calculate_margin_requirement_and_total_collateral_and_liability_info {
             sol_oracle_account_info
         );
 
-        let mut usdc_oracle_price = get_hardcoded_pyth_price(101 * 10000, 6); // $1.01
+        let mut usdc_oracle_price = get_hardcoded_pyth_price(99 * 10000, 6); // $.99
         let usdc_oracle_price_key =
             Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
         let pyth_program = crate::ids::pyth_program::id();
@@ -1914,12 +2112,10 @@ mod calculate_margin_requirement_and_total_collateral_and_liability_info {
             market_index: 0,
             oracle_source: OracleSource::QuoteAsset,
             cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
-            cumulative_borrow_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
             decimals: 6,
             initial_asset_weight: SPOT_WEIGHT_PRECISION,
             maintenance_asset_weight: SPOT_WEIGHT_PRECISION,
             deposit_balance: 10000 * SPOT_BALANCE_PRECISION,
-            borrow_balance: 1000 * SPOT_BALANCE_PRECISION,
             liquidator_fee: 0,
             historical_oracle_data: HistoricalOracleData::default_quote_oracle(),
             oracle: usdc_oracle_price_key,
@@ -1951,7 +2147,7 @@ mod calculate_margin_requirement_and_total_collateral_and_liability_info {
         let mut spot_positions = [SpotPosition::default(); 8];
         spot_positions[0] = SpotPosition {
             market_index: 0,
-            balance_type: SpotBalanceType::Borrow,
+            balance_type: SpotBalanceType::Deposit,
             scaled_balance: SPOT_BALANCE_PRECISION_U64,
             ..SpotPosition::default()
         };
@@ -1975,12 +2171,12 @@ mod calculate_margin_requirement_and_total_collateral_and_liability_info {
         )
         .unwrap();
 
-        assert_eq!(margin_requirement, 1010000);
-        assert_eq!(total_collateral, 0);
+        assert_eq!(margin_requirement, 0);
+        assert_eq!(total_collateral, 990000);
 
         let mut spot_market = spot_market_map.get_ref_mut(&0).unwrap();
         spot_market.historical_oracle_data = HistoricalOracleData {
-            last_oracle_price_twap_5min: 102 * PRICE_PRECISION_I64 / 100,
+            last_oracle_price_twap_5min: 95 * PRICE_PRECISION_I64 / 100,
             ..HistoricalOracleData::default()
         };
         drop(spot_market);
@@ -1998,12 +2194,12 @@ mod calculate_margin_requirement_and_total_collateral_and_liability_info {
         )
         .unwrap();
 
-        assert_eq!(margin_requirement, 1020000);
-        assert_eq!(total_collateral, 0);
+        assert_eq!(margin_requirement, 0);
+        assert_eq!(total_collateral, 950000);
 
         let mut spot_market = spot_market_map.get_ref_mut(&0).unwrap();
         spot_market.historical_oracle_data = HistoricalOracleData {
-            last_oracle_price_twap_5min: 99 * PRICE_PRECISION_I64 / 100,
+            last_oracle_price_twap_5min: 101 * PRICE_PRECISION_I64 / 100,
             ..HistoricalOracleData::default()
         };
         drop(spot_market);
@@ -2021,13 +2217,13 @@ mod calculate_margin_requirement_and_total_collateral_and_liability_info {
         )
         .unwrap();
 
-        assert_eq!(total_collateral, 0);
-        // twap is .99, but oracle is 1.01, so we use oracle
-        assert_eq!(margin_requirement, 1010000);
+        assert_eq!(margin_requirement, 0);
+        // twap is 1.01, but oracle is .99, so we use oracle
+        assert_eq!(total_collateral, 990000);
     }
 
     #[test]
-    pub fn usdc_not_1_with_perp_position() {
+    pub fn usdc_more_than_1_with_borrow() {
         let slot = 0_u64;
 
         let mut sol_oracle_price = get_pyth_price(100, 6);
@@ -2041,10 +2237,9 @@ mod calculate_margin_requirement_and_total_collateral_and_liability_info {
             sol_oracle_account_info
         );
 
-        let usdc_price = 101 * 10000; // $1.01
-        let mut usdc_oracle_price = get_hardcoded_pyth_price(usdc_price, 6);
+        let mut usdc_oracle_price = get_hardcoded_pyth_price(101 * 10000, 6); // $1.01
         let usdc_oracle_price_key =
-            Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkiF").unwrap();
+            Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
         let pyth_program = crate::ids::pyth_program::id();
         create_account_info!(
             usdc_oracle_price,
@@ -2056,16 +2251,164 @@ mod calculate_margin_requirement_and_total_collateral_and_liability_info {
         let mut oracle_map =
             OracleMap::load(&mut oracle_account_infos.iter().peekable(), slot, None).unwrap();
 
+        let market_map = PerpMarketMap::empty();
+
         let mut usdc_spot_market = SpotMarket {
             market_index: 0,
             oracle_source: OracleSource::QuoteAsset,
             cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            cumulative_borrow_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
             decimals: 6,
             initial_asset_weight: SPOT_WEIGHT_PRECISION,
             maintenance_asset_weight: SPOT_WEIGHT_PRECISION,
             deposit_balance: 10000 * SPOT_BALANCE_PRECISION,
+            borrow_balance: 1000 * SPOT_BALANCE_PRECISION,
             liquidator_fee: 0,
-            historical_oracle_data: HistoricalOracleData::default_price(usdc_price),
+            historical_oracle_data: HistoricalOracleData::default_quote_oracle(),
+            oracle: usdc_oracle_price_key,
+            ..SpotMarket::default()
+        };
+        create_anchor_account_info!(usdc_spot_market, SpotMarket, usdc_spot_market_account_info);
+        let mut sol_spot_market = SpotMarket {
+            market_index: 1,
+            oracle_source: OracleSource::Pyth,
+            oracle: usdc_oracle_price_key,
+            cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            cumulative_borrow_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            decimals: 9,
+            initial_asset_weight: 8 * SPOT_WEIGHT_PRECISION / 10,
+            maintenance_asset_weight: 9 * SPOT_WEIGHT_PRECISION / 10,
+            initial_liability_weight: 12 * SPOT_WEIGHT_PRECISION / 10,
+            maintenance_liability_weight: 11 * SPOT_WEIGHT_PRECISION / 10,
+            liquidator_fee: LIQUIDATION_FEE_PRECISION / 1000,
+            ..SpotMarket::default()
+        };
+        create_anchor_account_info!(sol_spot_market, SpotMarket, sol_spot_market_account_info);
+        let spot_market_account_infos = Vec::from([
+            &usdc_spot_market_account_info,
+            &sol_spot_market_account_info,
+        ]);
+        let spot_market_map =
+            SpotMarketMap::load_multiple(spot_market_account_infos, true).unwrap();
+
+        let mut spot_positions = [SpotPosition::default(); 8];
+        spot_positions[0] = SpotPosition {
+            market_index: 0,
+            balance_type: SpotBalanceType::Borrow,
+            scaled_balance: SPOT_BALANCE_PRECISION_U64,
+            ..SpotPosition::default()
+        };
+        let user = User {
+            orders: [Order::default(); 32],
+            perp_positions: [PerpPosition::default(); 8],
+            spot_positions,
+            ..User::default()
+        };
+
+        let MarginCalculation {
+            total_collateral,
+            margin_requirement,
+            ..
+        } = calculate_margin_requirement_and_total_collateral_and_liability_info(
+            &user,
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            MarginContext::standard(MarginRequirementType::Initial).strict(true),
+        )
+        .unwrap();
+
+        assert_eq!(margin_requirement, 1010000);
+        assert_eq!(total_collateral, 0);
+
+        let mut spot_market = spot_market_map.get_ref_mut(&0).unwrap();
+        spot_market.historical_oracle_data = HistoricalOracleData {
+            last_oracle_price_twap_5min: 102 * PRICE_PRECISION_I64 / 100,
+            ..HistoricalOracleData::default()
+        };
+        drop(spot_market);
+
+        let MarginCalculation {
+            total_collateral,
+            margin_requirement,
+            ..
+        } = calculate_margin_requirement_and_total_collateral_and_liability_info(
+            &user,
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            MarginContext::standard(MarginRequirementType::Initial).strict(true),
+        )
+        .unwrap();
+
+        assert_eq!(margin_requirement, 1020000);
+        assert_eq!(total_collateral, 0);
+
+        let mut spot_market = spot_market_map.get_ref_mut(&0).unwrap();
+        spot_market.historical_oracle_data = HistoricalOracleData {
+            last_oracle_price_twap_5min: 99 * PRICE_PRECISION_I64 / 100,
+            ..HistoricalOracleData::default()
+        };
+        drop(spot_market);
+
+        let MarginCalculation {
+            total_collateral,
+            margin_requirement,
+            ..
+        } = calculate_margin_requirement_and_total_collateral_and_liability_info(
+            &user,
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            MarginContext::standard(MarginRequirementType::Initial).strict(true),
+        )
+        .unwrap();
+
+        assert_eq!(total_collateral, 0);
+        // twap is .99, but oracle is 1.01, so we use oracle
+        assert_eq!(margin_requirement, 1010000);
+    }
+
+    #[test]
+    pub fn usdc_not_1_with_perp_position() {
+        let slot = 0_u64;
+
+        let mut sol_oracle_price = get_pyth_price(100, 6);
+        let sol_oracle_price_key =
+            Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+        let pyth_program = crate::ids::pyth_program::id();
+        create_account_info!(
+            sol_oracle_price,
+            &sol_oracle_price_key,
+            &pyth_program,
+            sol_oracle_account_info
+        );
+
+        let usdc_price = 101 * 10000; // $1.01
+        let mut usdc_oracle_price = get_hardcoded_pyth_price(usdc_price, 6);
+        let usdc_oracle_price_key =
+            Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkiF").unwrap();
+        let pyth_program = crate::ids::pyth_program::id();
+        create_account_info!(
+            usdc_oracle_price,
+            &usdc_oracle_price_key,
+            &pyth_program,
+            usdc_oracle_account_info
+        );
+        let oracle_account_infos = Vec::from([sol_oracle_account_info, usdc_oracle_account_info]);
+        let mut oracle_map =
+            OracleMap::load(&mut oracle_account_infos.iter().peekable(), slot, None).unwrap();
+
+        let mut usdc_spot_market = SpotMarket {
+            market_index: 0,
+            oracle_source: OracleSource::QuoteAsset,
+            cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            decimals: 6,
+            initial_asset_weight: SPOT_WEIGHT_PRECISION,
+            maintenance_asset_weight: SPOT_WEIGHT_PRECISION,
+            deposit_balance: 10000 * SPOT_BALANCE_PRECISION,
+            liquidator_fee: 0,
+            historical_oracle_data: HistoricalOracleData::default_price(usdc_price),
             oracle: usdc_oracle_price_key,
             ..SpotMarket::default()
         };
@@ -2194,6 +2537,1044 @@ mod calculate_margin_requirement_and_total_collateral_and_liability_info {
         assert_eq!(margin_requirement, 10100000);
         assert_eq!(total_collateral, 9500000);
     }
+
+    #[test]
+    fn spot_deposit_hedging_perp_short() {
+        let slot = 0_u64;
+
+        let mut sol_oracle_price = get_pyth_price(100, 6);
+        let sol_oracle_price_key =
+            Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+        let pyth_program = crate::ids::pyth_program::id();
+        create_account_info!(
+            sol_oracle_price,
+            &sol_oracle_price_key,
+            &pyth_program,
+            oracle_account_info
+        );
+        let mut oracle_map = OracleMap::load_one(&oracle_account_info, slot, None).unwrap();
+
+        let mut market = PerpMarket {
+            amm: AMM {
+                base_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                bid_base_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+                bid_quote_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+                ask_base_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+                ask_quote_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+                sqrt_k: 100 * AMM_RESERVE_PRECISION,
+                peg_multiplier: 100 * PEG_PRECISION,
+                order_step_size: 10000000,
+                oracle: sol_oracle_price_key,
+                ..AMM::default()
+            },
+            margin_ratio_initial: 1000,
+            margin_ratio_maintenance: 500,
+            status: MarketStatus::Initialized,
+            ..PerpMarket::default()
+        };
+        create_anchor_account_info!(market, PerpMarket, market_account_info);
+        let perp_market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+
+        let mut usdc_spot_market = SpotMarket {
+            market_index: 0,
+            oracle_source: OracleSource::QuoteAsset,
+            cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            decimals: 6,
+            initial_asset_weight: SPOT_WEIGHT_PRECISION,
+            maintenance_asset_weight: SPOT_WEIGHT_PRECISION,
+            deposit_balance: 10000 * SPOT_BALANCE_PRECISION,
+            liquidator_fee: 0,
+            historical_oracle_data: HistoricalOracleData::default_quote_oracle(),
+            ..SpotMarket::default()
+        };
+        create_anchor_account_info!(usdc_spot_market, SpotMarket, usdc_spot_market_account_info);
+        let mut sol_spot_market = SpotMarket {
+            market_index: 1,
+            oracle_source: OracleSource::Pyth,
+            oracle: sol_oracle_price_key,
+            cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            cumulative_borrow_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            decimals: 9,
+            initial_asset_weight: 8 * SPOT_WEIGHT_PRECISION / 10,
+            maintenance_asset_weight: 9 * SPOT_WEIGHT_PRECISION / 10,
+            initial_liability_weight: 12 * SPOT_WEIGHT_PRECISION / 10,
+            maintenance_liability_weight: 11 * SPOT_WEIGHT_PRECISION / 10,
+            liquidator_fee: LIQUIDATION_FEE_PRECISION / 1000,
+            ..SpotMarket::default()
+        };
+        create_anchor_account_info!(sol_spot_market, SpotMarket, sol_spot_market_account_info);
+        let spot_market_account_infos = Vec::from([
+            &usdc_spot_market_account_info,
+            &sol_spot_market_account_info,
+        ]);
+        let spot_market_map =
+            SpotMarketMap::load_multiple(spot_market_account_infos, true).unwrap();
+
+        // 5 sol deposit against a 10 sol short
+        let mut spot_positions = [SpotPosition::default(); 8];
+        spot_positions[0] = SpotPosition {
+            market_index: 0,
+            balance_type: SpotBalanceType::Deposit,
+            scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
+            ..SpotPosition::default()
+        };
+        spot_positions[1] = SpotPosition {
+            market_index: 1,
+            balance_type: SpotBalanceType::Deposit,
+            scaled_balance: 5 * SPOT_BALANCE_PRECISION_U64,
+            ..SpotPosition::default()
+        };
+
+        let user = User {
+            orders: [Order::default(); 32],
+            perp_positions: get_positions(PerpPosition {
+                market_index: 0,
+                base_asset_amount: -10 * BASE_PRECISION_I64,
+                quote_asset_amount: 1000 * QUOTE_PRECISION_I64,
+                ..PerpPosition::default()
+            }),
+            spot_positions,
+            ..User::default()
+        };
+
+        let MarginCalculation {
+            total_collateral,
+            margin_requirement,
+            total_hedge_offset,
+            ..
+        } = calculate_margin_requirement_and_total_collateral_and_liability_info(
+            &user,
+            &perp_market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            MarginContext::standard(MarginRequirementType::Initial),
+        )
+        .unwrap();
+
+        assert_eq!(margin_requirement, 100 * QUOTE_PRECISION);
+        assert_eq!(total_collateral, 500 * QUOTE_PRECISION_I128);
+        assert_eq!(total_hedge_offset, 0);
+
+        let mut market = perp_market_map.get_ref_mut(&0).unwrap();
+        market.hedge_spot_market_index = 1;
+        drop(market);
+
+        // 5 sol hedged, half of the perp requirement (.1) since it is smaller than the spot haircut (.2)
+        let MarginCalculation {
+            total_collateral,
+            margin_requirement,
+            total_hedge_offset,
+            ..
+        } = calculate_margin_requirement_and_total_collateral_and_liability_info(
+            &user,
+            &perp_market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            MarginContext::standard(MarginRequirementType::Initial),
+        )
+        .unwrap();
+
+        assert_eq!(margin_requirement, 75 * QUOTE_PRECISION);
+        assert_eq!(total_collateral, 500 * QUOTE_PRECISION_I128);
+        assert_eq!(total_hedge_offset, 25 * QUOTE_PRECISION);
+
+        let MarginCalculation {
+            margin_requirement,
+            total_hedge_offset,
+            ..
+        } = calculate_margin_requirement_and_total_collateral_and_liability_info(
+            &user,
+            &perp_market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            MarginContext::liquidation(0),
+        )
+        .unwrap();
+
+        assert_eq!(margin_requirement, 37_500_000);
+        assert_eq!(total_hedge_offset, 12_500_000);
+
+        // open bids that could close the short reduce the hedge
+        let mut user = user;
+        user.perp_positions[0].open_bids = 8 * BASE_PRECISION_I64;
+        user.perp_positions[0].open_orders = 1;
+
+        let MarginCalculation {
+            total_hedge_offset, ..
+        } = calculate_margin_requirement_and_total_collateral_and_liability_info(
+            &user,
+            &perp_market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            MarginContext::standard(MarginRequirementType::Initial),
+        )
+        .unwrap();
+
+        assert_eq!(total_hedge_offset, 10 * QUOTE_PRECISION);
+    }
+
+    #[test]
+    fn spot_borrow_hedging_perp_long() {
+        let slot = 0_u64;
+
+        let mut sol_oracle_price = get_pyth_price(100, 6);
+        let sol_oracle_price_key =
+            Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+        let pyth_program = crate::ids::pyth_program::id();
+        create_account_info!(
+            sol_oracle_price,
+            &sol_oracle_price_key,
+            &pyth_program,
+            oracle_account_info
+        );
+        let mut oracle_map = OracleMap::load_one(&oracle_account_info, slot, None).unwrap();
+
+        let mut market = PerpMarket {
+            amm: AMM {
+                base_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                bid_base_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+                bid_quote_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+                ask_base_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+                ask_quote_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+                sqrt_k: 100 * AMM_RESERVE_PRECISION,
+                peg_multiplier: 100 * PEG_PRECISION,
+                order_step_size: 10000000,
+                oracle: sol_oracle_price_key,
+                ..AMM::default()
+            },
+            margin_ratio_initial: 1000,
+            margin_ratio_maintenance: 500,
+            status: MarketStatus::Initialized,
+            hedge_spot_market_index: 1,
+            ..PerpMarket::default()
+        };
+        create_anchor_account_info!(market, PerpMarket, market_account_info);
+        let perp_market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+
+        let mut usdc_spot_market = SpotMarket {
+            market_index: 0,
+            oracle_source: OracleSource::QuoteAsset,
+            cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            decimals: 6,
+            initial_asset_weight: SPOT_WEIGHT_PRECISION,
+            maintenance_asset_weight: SPOT_WEIGHT_PRECISION,
+            deposit_balance: 10000 * SPOT_BALANCE_PRECISION,
+            liquidator_fee: 0,
+            historical_oracle_data: HistoricalOracleData::default_quote_oracle(),
+            ..SpotMarket::default()
+        };
+        create_anchor_account_info!(usdc_spot_market, SpotMarket, usdc_spot_market_account_info);
+        let mut sol_spot_market = SpotMarket {
+            market_index: 1,
+            oracle_source: OracleSource::Pyth,
+            oracle: sol_oracle_price_key,
+            cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            cumulative_borrow_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            decimals: 9,
+            initial_asset_weight: 8 * SPOT_WEIGHT_PRECISION / 10,
+            maintenance_asset_weight: 9 * SPOT_WEIGHT_PRECISION / 10,
+            initial_liability_weight: 12 * SPOT_WEIGHT_PRECISION / 10,
+            maintenance_liability_weight: 11 * SPOT_WEIGHT_PRECISION / 10,
+            liquidator_fee: LIQUIDATION_FEE_PRECISION / 1000,
+            ..SpotMarket::default()
+        };
+        create_anchor_account_info!(sol_spot_market, SpotMarket, sol_spot_market_account_info);
+        let spot_market_account_infos = Vec::from([
+            &usdc_spot_market_account_info,
+            &sol_spot_market_account_info,
+        ]);
+        let spot_market_map =
+            SpotMarketMap::load_multiple(spot_market_account_infos, true).unwrap();
+
+        // 5 sol borrow against a 10 sol long
+        let mut spot_positions = [SpotPosition::default(); 8];
+        spot_positions[0] = SpotPosition {
+            market_index: 0,
+            balance_type: SpotBalanceType::Deposit,
+            scaled_balance: 1000 * SPOT_BALANCE_PRECISION_U64,
+            ..SpotPosition::default()
+        };
+        spot_positions[1] = SpotPosition {
+            market_index: 1,
+            balance_type: SpotBalanceType::Borrow,
+            scaled_balance: 5 * SPOT_BALANCE_PRECISION_U64,
+            ..SpotPosition::default()
+        };
+
+        let user = User {
+            orders: [Order::default(); 32],
+            perp_positions: get_positions(PerpPosition {
+                market_index: 0,
+                base_asset_amount: 10 * BASE_PRECISION_I64,
+                quote_asset_amount: -1000 * QUOTE_PRECISION_I64,
+                ..PerpPosition::default()
+            }),
+            spot_positions,
+            ..User::default()
+        };
+
+        // perp requirement of $100 and borrow requirement of $600. 5 sol hedged, half of the perp
+        // requirement (.1) since it is smaller than the liability weight excess (.2) net of fees
+        let MarginCalculation {
+            total_collateral,
+            margin_requirement,
+            total_hedge_offset,
+            ..
+        } = calculate_margin_requirement_and_total_collateral_and_liability_info(
+            &user,
+            &perp_market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            MarginContext::standard(MarginRequirementType::Initial),
+        )
+        .unwrap();
+
+        assert_eq!(margin_requirement, 675 * QUOTE_PRECISION);
+        assert_eq!(total_collateral, 1000 * QUOTE_PRECISION_I128);
+        assert_eq!(total_hedge_offset, 25 * QUOTE_PRECISION);
+
+        // open asks that could close the long reduce the hedge
+        let mut user = user;
+        user.perp_positions[0].open_asks = -8 * BASE_PRECISION_I64;
+        user.perp_positions[0].open_orders = 1;
+
+        let MarginCalculation {
+            total_hedge_offset, ..
+        } = calculate_margin_requirement_and_total_collateral_and_liability_info(
+            &user,
+            &perp_market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            MarginContext::standard(MarginRequirementType::Initial),
+        )
+        .unwrap();
+
+        assert_eq!(total_hedge_offset, 10 * QUOTE_PRECISION);
+
+        // a borrow doesnt hedge a short
+        user.perp_positions[0].open_asks = 0;
+        user.perp_positions[0].open_orders = 0;
+        user.perp_positions[0].base_asset_amount = -10 * BASE_PRECISION_I64;
+        user.perp_positions[0].quote_asset_amount = 1000 * QUOTE_PRECISION_I64;
+
+        let MarginCalculation {
+            margin_requirement,
+            total_hedge_offset,
+            ..
+        } = calculate_margin_requirement_and_total_collateral_and_liability_info(
+            &user,
+            &perp_market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            MarginContext::standard(MarginRequirementType::Initial),
+        )
+        .unwrap();
+
+        assert_eq!(margin_requirement, 700 * QUOTE_PRECISION);
+        assert_eq!(total_hedge_offset, 0);
+    }
+
+    #[test]
+    fn liquidating_either_hedged_leg_frees_margin() {
+        let slot = 0_u64;
+
+        let mut sol_oracle_price = get_pyth_price(100, 6);
+        let sol_oracle_price_key =
+            Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+        let pyth_program = crate::ids::pyth_program::id();
+        create_account_info!(
+            sol_oracle_price,
+            &sol_oracle_price_key,
+            &pyth_program,
+            oracle_account_info
+        );
+        let mut oracle_map = OracleMap::load_one(&oracle_account_info, slot, None).unwrap();
+
+        // perp maintenance requirement (.1) equal to the spot haircut (.1), 1% liquidation fees
+        let mut market = PerpMarket {
+            amm: AMM {
+                base_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                bid_base_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+                bid_quote_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+                ask_base_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+                ask_quote_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+                sqrt_k: 100 * AMM_RESERVE_PRECISION,
+                peg_multiplier: 100 * PEG_PRECISION,
+                order_step_size: 10000000,
+                oracle: sol_oracle_price_key,
+                ..AMM::default()
+            },
+            margin_ratio_initial: 2000,
+            margin_ratio_maintenance: 1000,
+            liquidator_fee: LIQUIDATION_FEE_PRECISION / 100,
+            status: MarketStatus::Initialized,
+            hedge_spot_market_index: 1,
+            ..PerpMarket::default()
+        };
+        create_anchor_account_info!(market, PerpMarket, market_account_info);
+        let perp_market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+
+        let mut usdc_spot_market = SpotMarket {
+            market_index: 0,
+            oracle_source: OracleSource::QuoteAsset,
+            cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            decimals: 6,
+            initial_asset_weight: SPOT_WEIGHT_PRECISION,
+            maintenance_asset_weight: SPOT_WEIGHT_PRECISION,
+            deposit_balance: 10000 * SPOT_BALANCE_PRECISION,
+            liquidator_fee: 0,
+            historical_oracle_data: HistoricalOracleData::default_quote_oracle(),
+            ..SpotMarket::default()
+        };
+        create_anchor_account_info!(usdc_spot_market, SpotMarket, usdc_spot_market_account_info);
+        let mut sol_spot_market = SpotMarket {
+            market_index: 1,
+            oracle_source: OracleSource::Pyth,
+            oracle: sol_oracle_price_key,
+            cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            cumulative_borrow_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            decimals: 9,
+            initial_asset_weight: 8 * SPOT_WEIGHT_PRECISION / 10,
+            maintenance_asset_weight: 9 * SPOT_WEIGHT_PRECISION / 10,
+            initial_liability_weight: 12 * SPOT_WEIGHT_PRECISION / 10,
+            maintenance_liability_weight: 11 * SPOT_WEIGHT_PRECISION / 10,
+            liquidator_fee: LIQUIDATION_FEE_PRECISION / 100,
+            ..SpotMarket::default()
+        };
+        create_anchor_account_info!(sol_spot_market, SpotMarket, sol_spot_market_account_info);
+        let spot_market_account_infos = Vec::from([
+            &usdc_spot_market_account_info,
+            &sol_spot_market_account_info,
+        ]);
+        let spot_market_map =
+            SpotMarketMap::load_multiple(spot_market_account_infos, true).unwrap();
+
+        let mut free_collateral = |user: &User| -> i128 {
+            let calculation = calculate_margin_requirement_and_total_collateral_and_liability_info(
+                user,
+                &perp_market_map,
+                &spot_market_map,
+                &mut oracle_map,
+                MarginContext::liquidation(0),
+            )
+            .unwrap();
+
+            calculation.total_collateral - calculation.margin_requirement as i128
+        };
+
+        // perp leg: 10 sol deposit against a 10 sol short
+        let mut spot_positions = [SpotPosition::default(); 8];
+        spot_positions[0] = SpotPosition {
+            market_index: 1,
+            balance_type: SpotBalanceType::Deposit,
+            scaled_balance: 10 * SPOT_BALANCE_PRECISION_U64,
+            ..SpotPosition::default()
+        };
+
+        let mut user = User {
+            orders: [Order::default(); 32],
+            perp_positions: get_positions(PerpPosition {
+                market_index: 0,
+                base_asset_amount: -10 * BASE_PRECISION_I64,
+                quote_asset_amount: 1000 * QUOTE_PRECISION_I64,
+                ..PerpPosition::default()
+            }),
+            spot_positions,
+            ..User::default()
+        };
+
+        // $900 collateral, $100 perp requirement less a $45 offset
+        let free_collateral_before = free_collateral(&user);
+        assert_eq!(free_collateral_before, 845 * QUOTE_PRECISION_I128);
+
+        // liquidate 1 sol of the short at the oracle price, paying the 1% liquidator fee
+        user.perp_positions[0].base_asset_amount = -9 * BASE_PRECISION_I64;
+        user.perp_positions[0].quote_asset_amount = 899 * QUOTE_PRECISION_I64;
+
+        // $899 collateral, $90 perp requirement less a $40.5 offset
+        let free_collateral_after = free_collateral(&user);
+        assert_eq!(free_collateral_after, 849_500_000);
+        assert!(free_collateral_after > free_collateral_before);
+
+        // spot leg: 10 sol borrow against a 10 sol long
+        let mut spot_positions = [SpotPosition::default(); 8];
+        spot_positions[0] = SpotPosition {
+            market_index: 0,
+            balance_type: SpotBalanceType::Deposit,
+            scaled_balance: 2000 * SPOT_BALANCE_PRECISION_U64,
+            ..SpotPosition::default()
+        };
+        spot_positions[1] = SpotPosition {
+            market_index: 1,
+            balance_type: SpotBalanceType::Borrow,
+            scaled_balance: 10 * SPOT_BALANCE_PRECISION_U64,
+            ..SpotPosition::default()
+        };
+
+        let mut user = User {
+            orders: [Order::default(); 32],
+            perp_positions: get_positions(PerpPosition {
+                market_index: 0,
+                base_asset_amount: 10 * BASE_PRECISION_I64,
+                quote_asset_amount: -1000 * QUOTE_PRECISION_I64,
+                ..PerpPosition::default()
+            }),
+            spot_positions,
+            ..User::default()
+        };
+
+        // $2000 collateral, $100 perp requirement and $1100 borrow requirement less a $45 offset
+        let free_collateral_before = free_collateral(&user);
+        assert_eq!(free_collateral_before, 845 * QUOTE_PRECISION_I128);
+
+        // liquidate 1 sol of the borrow with usdc, paying the 1% liquidator fee
+        user.spot_positions[0].scaled_balance = 1899 * SPOT_BALANCE_PRECISION_U64;
+        user.spot_positions[1].scaled_balance = 9 * SPOT_BALANCE_PRECISION_U64;
+
+        // $1899 collateral, $100 perp requirement and $990 borrow requirement less a $40.5 offset
+        let free_collateral_after = free_collateral(&user);
+        assert_eq!(free_collateral_after, 849_500_000);
+        assert!(free_collateral_after > free_collateral_before);
+    }
+
+    #[test]
+    fn stress_mode() {
+        let slot = 0_u64;
+
+        let mut sol_oracle_price = get_pyth_price(100, 6);
+        sol_oracle_price.agg.conf = 1_000_000;
+        let sol_oracle_price_key =
+            Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+        let pyth_program = crate::ids::pyth_program::id();
+        create_account_info!(
+            sol_oracle_price,
+            &sol_oracle_price_key,
+            &pyth_program,
+            oracle_account_info
+        );
+        let mut oracle_map = OracleMap::load_one(&oracle_account_info, slot, None).unwrap();
+
+        let mut market = PerpMarket {
+            amm: AMM {
+                base_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                bid_base_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+                bid_quote_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+                ask_base_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+                ask_quote_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+                sqrt_k: 100 * AMM_RESERVE_PRECISION,
+                peg_multiplier: 100 * PEG_PRECISION,
+                order_step_size: 10000000,
+                oracle: sol_oracle_price_key,
+                ..AMM::default()
+            },
+            margin_ratio_initial: 1000,
+            margin_ratio_maintenance: 500,
+            status: MarketStatus::Initialized,
+            contract_tier: ContractTier::Isolated,
+            stress_oracle_shock: 2000, // 20%
+            ..PerpMarket::default()
+        };
+        create_anchor_account_info!(market, PerpMarket, market_account_info);
+        let perp_market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+
+        let mut usdc_spot_market = SpotMarket {
+            market_index: 0,
+            oracle_source: OracleSource::QuoteAsset,
+            cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            decimals: 6,
+            initial_asset_weight: SPOT_WEIGHT_PRECISION,
+            maintenance_asset_weight: SPOT_WEIGHT_PRECISION,
+            deposit_balance: 10000 * SPOT_BALANCE_PRECISION,
+            liquidator_fee: 0,
+            historical_oracle_data: HistoricalOracleData::default_quote_oracle(),
+            ..SpotMarket::default()
+        };
+        create_anchor_account_info!(usdc_spot_market, SpotMarket, usdc_spot_market_account_info);
+        let spot_market_map =
+            SpotMarketMap::load_one(&usdc_spot_market_account_info, true).unwrap();
+
+        let mut spot_positions = [SpotPosition::default(); 8];
+        spot_positions[0] = SpotPosition {
+            market_index: 0,
+            balance_type: SpotBalanceType::Deposit,
+            scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
+            ..SpotPosition::default()
+        };
+
+        // 10 sol long at $100 with $100 of collateral
+        let user = User {
+            orders: [Order::default(); 32],
+            perp_positions: get_positions(PerpPosition {
+                market_index: 0,
+                base_asset_amount: 10 * BASE_PRECISION_I64,
+                quote_asset_amount: -1000 * QUOTE_PRECISION_I64,
+                ..PerpPosition::default()
+            }),
+            spot_positions,
+            ..User::default()
+        };
+
+        let MarginCalculation {
+            total_collateral,
+            margin_requirement,
+            stress_check_required,
+            ..
+        } = calculate_margin_requirement_and_total_collateral_and_liability_info(
+            &user,
+            &perp_market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            MarginContext::standard(MarginRequirementType::Maintenance),
+        )
+        .unwrap();
+
+        assert_eq!(margin_requirement, 50 * QUOTE_PRECISION);
+        assert_eq!(total_collateral, 100 * QUOTE_PRECISION_I128);
+        assert!(stress_check_required);
+
+        // sol at 80 - 1 conf, usdc deposit at 1 - 1 conf
+        let MarginCalculation {
+            total_collateral,
+            margin_requirement,
+            ..
+        } = calculate_margin_requirement_and_total_collateral_and_liability_info(
+            &user,
+            &perp_market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            MarginContext::stress(false),
+        )
+        .unwrap();
+
+        assert_eq!(margin_requirement, 39_500_000);
+        assert_eq!(total_collateral, -110_000_100);
+
+        // sol at 120 + 1 conf, liability valued with usdc at 1 + 1 conf
+        // positive pnl has no maintenance asset weight
+        let MarginCalculation {
+            total_collateral,
+            margin_requirement,
+            ..
+        } = calculate_margin_requirement_and_total_collateral_and_liability_info(
+            &user,
+            &perp_market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            MarginContext::stress(true),
+        )
+        .unwrap();
+
+        assert_eq!(margin_requirement, 60_500_060);
+        assert_eq!(total_collateral, 100 * QUOTE_PRECISION_I128);
+
+        let meets_stress_margin_requirement = meets_stress_margin_requirement(
+            &user,
+            &perp_market_map,
+            &spot_market_map,
+            &mut oracle_map,
+        )
+        .unwrap();
+
+        assert!(!meets_stress_margin_requirement);
+    }
+}
+
+#[cfg(test)]
+mod simulate_margin_calculation {
+    use std::str::FromStr;
+
+    use anchor_lang::Owner;
+    use solana_program::pubkey::Pubkey;
+
+    use crate::math::constants::{
+        AMM_RESERVE_PRECISION, PEG_PRECISION, QUOTE_PRECISION, QUOTE_PRECISION_I128,
+        QUOTE_PRECISION_I64, SPOT_BALANCE_PRECISION, SPOT_BALANCE_PRECISION_U64,
+        SPOT_CUMULATIVE_INTEREST_PRECISION, SPOT_WEIGHT_PRECISION,
+    };
+    use crate::math::margin::{
+        calculate_margin_requirement_and_total_collateral_and_liability_info,
+        simulate_margin_calculation, MarginRequirementType, SimulatedAction,
+    };
+    use crate::state::margin_calculation::{MarginCalculation, MarginContext, MarketIdentifier};
+    use crate::state::oracle::{HistoricalOracleData, OracleSource};
+    use crate::state::oracle_map::OracleMap;
+    use crate::state::perp_market::{MarketStatus, PerpMarket, AMM};
+    use crate::state::perp_market_map::PerpMarketMap;
+    use crate::state::spot_market::{SpotBalanceType, SpotMarket};
+    use crate::state::spot_market_map::SpotMarketMap;
+    use crate::state::user::{PerpPosition, SpotPosition, User};
+    use crate::test_utils::*;
+    use crate::test_utils::{get_positions, get_pyth_price};
+    use crate::{create_account_info, create_anchor_account_info, BASE_PRECISION_I64};
+
+    #[test]
+    fn perp_fill_and_spot_deposit() {
+        let slot = 0_u64;
+
+        let mut sol_oracle_price = get_pyth_price(100, 6);
+        let sol_oracle_price_key =
+            Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+        let pyth_program = crate::ids::pyth_program::id();
+        create_account_info!(
+            sol_oracle_price,
+            &sol_oracle_price_key,
+            &pyth_program,
+            oracle_account_info
+        );
+        let mut oracle_map = OracleMap::load_one(&oracle_account_info, slot, None).unwrap();
+
+        let mut market = PerpMarket {
+            amm: AMM {
+                base_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                bid_base_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+                bid_quote_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+                ask_base_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+                ask_quote_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+                sqrt_k: 100 * AMM_RESERVE_PRECISION,
+                peg_multiplier: 100 * PEG_PRECISION,
+                order_step_size: 10000000,
+                oracle: sol_oracle_price_key,
+                ..AMM::default()
+            },
+            margin_ratio_initial: 1000,
+            margin_ratio_maintenance: 500,
+            status: MarketStatus::Initialized,
+            ..PerpMarket::default()
+        };
+        create_anchor_account_info!(market, PerpMarket, market_account_info);
+        let perp_market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+
+        let mut usdc_spot_market = SpotMarket {
+            market_index: 0,
+            oracle_source: OracleSource::QuoteAsset,
+            cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            cumulative_borrow_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            decimals: 6,
+            initial_asset_weight: SPOT_WEIGHT_PRECISION,
+            maintenance_asset_weight: SPOT_WEIGHT_PRECISION,
+            deposit_balance: 10000 * SPOT_BALANCE_PRECISION,
+            historical_oracle_data: HistoricalOracleData::default_quote_oracle(),
+            ..SpotMarket::default()
+        };
+        create_anchor_account_info!(usdc_spot_market, SpotMarket, usdc_spot_market_account_info);
+        let mut sol_spot_market = SpotMarket {
+            market_index: 1,
+            oracle_source: OracleSource::Pyth,
+            oracle: sol_oracle_price_key,
+            cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            cumulative_borrow_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            decimals: 9,
+            initial_asset_weight: 8 * SPOT_WEIGHT_PRECISION / 10,
+            maintenance_asset_weight: 9 * SPOT_WEIGHT_PRECISION / 10,
+            initial_liability_weight: 12 * SPOT_WEIGHT_PRECISION / 10,
+            maintenance_liability_weight: 11 * SPOT_WEIGHT_PRECISION / 10,
+            ..SpotMarket::default()
+        };
+        create_anchor_account_info!(sol_spot_market, SpotMarket, sol_spot_market_account_info);
+        let spot_market_account_infos = Vec::from([
+            &usdc_spot_market_account_info,
+            &sol_spot_market_account_info,
+        ]);
+        let spot_market_map =
+            SpotMarketMap::load_multiple(spot_market_account_infos, true).unwrap();
+
+        let mut spot_positions = [SpotPosition::default(); 8];
+        spot_positions[0] = SpotPosition {
+            market_index: 0,
+            balance_type: SpotBalanceType::Deposit,
+            scaled_balance: 1000 * SPOT_BALANCE_PRECISION_U64,
+            ..SpotPosition::default()
+        };
+
+        let user = User {
+            spot_positions,
+            ..User::default()
+        };
+
+        let MarginCalculation {
+            total_collateral,
+            margin_requirement,
+            ..
+        } = simulate_margin_calculation(
+            &user,
+            &perp_market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            &[SimulatedAction::PerpFill {
+                market_index: 0,
+                base_asset_amount: -10 * BASE_PRECISION_I64,
+                quote_asset_amount: 1000 * QUOTE_PRECISION_I64,
+            }],
+            MarginContext::standard(MarginRequirementType::Initial),
+        )
+        .unwrap();
+
+        assert_eq!(margin_requirement, 100 * QUOTE_PRECISION);
+        assert_eq!(total_collateral, 1000 * QUOTE_PRECISION_I128);
+
+        // swap usdc for sol and borrow usdc
+        let MarginCalculation {
+            total_collateral,
+            margin_requirement,
+            ..
+        } = simulate_margin_calculation(
+            &user,
+            &perp_market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            &[
+                SimulatedAction::PerpFill {
+                    market_index: 0,
+                    base_asset_amount: -10 * BASE_PRECISION_I64,
+                    quote_asset_amount: 1000 * QUOTE_PRECISION_I64,
+                },
+                SimulatedAction::SpotTokenDelta {
+                    market_index: 1,
+                    token_amount: 10 * AMM_RESERVE_PRECISION as i128,
+                },
+                SimulatedAction::SpotTokenDelta {
+                    market_index: 0,
+                    token_amount: -1100 * QUOTE_PRECISION_I128,
+                },
+            ],
+            MarginContext::standard(MarginRequirementType::Initial),
+        )
+        .unwrap();
+
+        // 100 perp + 100 usdc borrow, borrows round up
+        assert_eq!(margin_requirement, 200 * QUOTE_PRECISION + 1);
+        assert_eq!(total_collateral, 800 * QUOTE_PRECISION_I128);
+
+        // user is untouched
+        let MarginCalculation {
+            total_collateral,
+            margin_requirement,
+            ..
+        } = calculate_margin_requirement_and_total_collateral_and_liability_info(
+            &user,
+            &perp_market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            MarginContext::standard(MarginRequirementType::Initial),
+        )
+        .unwrap();
+
+        assert_eq!(margin_requirement, 0);
+        assert_eq!(total_collateral, 1000 * QUOTE_PRECISION_I128);
+    }
+
+    #[test]
+    fn matches_calculation_after_actions_are_applied() {
+        let slot = 0_u64;
+
+        let mut sol_oracle_price = get_pyth_price(100, 6);
+        let sol_oracle_price_key =
+            Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+        let pyth_program = crate::ids::pyth_program::id();
+        create_account_info!(
+            sol_oracle_price,
+            &sol_oracle_price_key,
+            &pyth_program,
+            oracle_account_info
+        );
+        let mut oracle_map = OracleMap::load_one(&oracle_account_info, slot, None).unwrap();
+
+        let mut market = PerpMarket {
+            amm: AMM {
+                base_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                bid_base_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+                bid_quote_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+                ask_base_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+                ask_quote_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+                sqrt_k: 100 * AMM_RESERVE_PRECISION,
+                peg_multiplier: 100 * PEG_PRECISION,
+                order_step_size: 10000000,
+                oracle: sol_oracle_price_key,
+                ..AMM::default()
+            },
+            margin_ratio_initial: 1000,
+            margin_ratio_maintenance: 500,
+            status: MarketStatus::Initialized,
+            ..PerpMarket::default()
+        };
+        create_anchor_account_info!(market, PerpMarket, market_account_info);
+        let perp_market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+
+        let mut usdc_spot_market = SpotMarket {
+            market_index: 0,
+            oracle_source: OracleSource::QuoteAsset,
+            cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            cumulative_borrow_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            decimals: 6,
+            initial_asset_weight: SPOT_WEIGHT_PRECISION,
+            maintenance_asset_weight: SPOT_WEIGHT_PRECISION,
+            initial_liability_weight: SPOT_WEIGHT_PRECISION,
+            maintenance_liability_weight: SPOT_WEIGHT_PRECISION,
+            deposit_balance: 10000 * SPOT_BALANCE_PRECISION,
+            historical_oracle_data: HistoricalOracleData::default_quote_oracle(),
+            ..SpotMarket::default()
+        };
+        create_anchor_account_info!(usdc_spot_market, SpotMarket, usdc_spot_market_account_info);
+        let mut sol_spot_market = SpotMarket {
+            market_index: 1,
+            oracle_source: OracleSource::Pyth,
+            oracle: sol_oracle_price_key,
+            cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            cumulative_borrow_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            decimals: 9,
+            initial_asset_weight: 8 * SPOT_WEIGHT_PRECISION / 10,
+            maintenance_asset_weight: 9 * SPOT_WEIGHT_PRECISION / 10,
+            initial_liability_weight: 12 * SPOT_WEIGHT_PRECISION / 10,
+            maintenance_liability_weight: 11 * SPOT_WEIGHT_PRECISION / 10,
+            ..SpotMarket::default()
+        };
+        create_anchor_account_info!(sol_spot_market, SpotMarket, sol_spot_market_account_info);
+        let spot_market_account_infos = Vec::from([
+            &usdc_spot_market_account_info,
+            &sol_spot_market_account_info,
+        ]);
+        let spot_market_map =
+            SpotMarketMap::load_multiple(spot_market_account_infos, true).unwrap();
+
+        // $1000 deposit and a 2 sol short
+        let mut spot_positions = [SpotPosition::default(); 8];
+        spot_positions[0] = SpotPosition {
+            market_index: 0,
+            balance_type: SpotBalanceType::Deposit,
+            scaled_balance: 1000 * SPOT_BALANCE_PRECISION_U64,
+            ..SpotPosition::default()
+        };
+
+        let user = User {
+            perp_positions: get_positions(PerpPosition {
+                market_index: 0,
+                base_asset_amount: -2 * BASE_PRECISION_I64,
+                quote_asset_amount: 200 * QUOTE_PRECISION_I64,
+                ..PerpPosition::default()
+            }),
+            spot_positions,
+            ..User::default()
+        };
+
+        // buy 5 sol perp, then swap $1100 for 1 sol borrowing $100
+        let actions = [
+            SimulatedAction::PerpFill {
+                market_index: 0,
+                base_asset_amount: 5 * BASE_PRECISION_I64,
+                quote_asset_amount: -500 * QUOTE_PRECISION_I64,
+            },
+            SimulatedAction::SpotTokenDelta {
+                market_index: 1,
+                token_amount: AMM_RESERVE_PRECISION as i128,
+            },
+            SimulatedAction::SpotTokenDelta {
+                market_index: 0,
+                token_amount: -1100 * QUOTE_PRECISION_I128,
+            },
+        ];
+
+        // the same user with the actions applied
+        let mut spot_positions = [SpotPosition::default(); 8];
+        spot_positions[0] = SpotPosition {
+            market_index: 0,
+            balance_type: SpotBalanceType::Borrow,
+            scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
+            ..SpotPosition::default()
+        };
+        spot_positions[1] = SpotPosition {
+            market_index: 1,
+            balance_type: SpotBalanceType::Deposit,
+            scaled_balance: SPOT_BALANCE_PRECISION_U64,
+            ..SpotPosition::default()
+        };
+
+        let applied_user = User {
+            perp_positions: get_positions(PerpPosition {
+                market_index: 0,
+                base_asset_amount: 3 * BASE_PRECISION_I64,
+                quote_asset_amount: -300 * QUOTE_PRECISION_I64,
+                ..PerpPosition::default()
+            }),
+            spot_positions,
+            ..User::default()
+        };
+
+        for context in [
+            MarginContext::standard(MarginRequirementType::Initial),
+            MarginContext::standard(MarginRequirementType::Maintenance),
+            MarginContext::liquidation(0),
+        ] {
+            let simulated = simulate_margin_calculation(
+                &user,
+                &perp_market_map,
+                &spot_market_map,
+                &mut oracle_map,
+                &actions,
+                context,
+            )
+            .unwrap();
+
+            let applied = calculate_margin_requirement_and_total_collateral_and_liability_info(
+                &applied_user,
+                &perp_market_map,
+                &spot_market_map,
+                &mut oracle_map,
+                context.track_details(),
+            )
+            .unwrap();
+
+            assert_eq!(simulated.total_collateral, applied.total_collateral);
+            assert_eq!(simulated.margin_requirement, applied.margin_requirement);
+            assert_eq!(
+                simulated.get_free_collateral().unwrap(),
+                applied.get_free_collateral().unwrap()
+            );
+            assert_eq!(
+                simulated.get_leverage().unwrap(),
+                applied.get_leverage().unwrap()
+            );
+            assert_eq!(simulated.num_spot_liabilities, applied.num_spot_liabilities);
+            assert_eq!(simulated.num_perp_liabilities, applied.num_perp_liabilities);
+            for market in [
+                MarketIdentifier::perp(0),
+                MarketIdentifier::spot(0),
+                MarketIdentifier::spot(1),
+            ] {
+                assert_eq!(
+                    simulated.get_market_margin_requirement(market),
+                    applied.get_market_margin_requirement(market)
+                );
+            }
+        }
+
+        let simulated = simulate_margin_calculation(
+            &user,
+            &perp_market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            &actions,
+            MarginContext::standard(MarginRequirementType::Initial),
+        )
+        .unwrap();
+
+        // 3 sol long at 10% and $100 borrow at 100%
+        assert_eq!(
+            simulated.get_market_margin_requirement(MarketIdentifier::perp(0)),
+            30 * QUOTE_PRECISION
+        );
+        assert_eq!(
+            simulated.get_market_margin_requirement(MarketIdentifier::spot(0)),
+            100 * QUOTE_PRECISION
+        );
+        assert_eq!(simulated.margin_requirement, 130 * QUOTE_PRECISION);
+        assert_ne!(simulated.get_leverage().unwrap(), 0);
+    }
 }
 
 #[cfg(test)]
@@ -15,6 +15,7 @@ pub mod funding;
 pub mod helpers;
 pub mod insurance;
 pub mod liquidation;
+pub mod liquidation_price;
 pub mod lp;
 pub mod margin;
 pub mod matching;
@@ -33,7 +33,8 @@ use crate::state::perp_market_map::PerpMarketMap;
 use crate::state::spot_market::SpotMarket;
 use crate::state::spot_market_map::SpotMarketMap;
 use crate::state::user::{
-    MarketType, Order, OrderFillSimulation, OrderStatus, OrderTriggerCondition, PerpPosition, User,
+    MarketType, Order, OrderBitFlag, OrderFillSimulation, OrderStatus, OrderTriggerCondition,
+    PerpPosition, User,
 };
 use crate::state::user_map::UserMap;
 use crate::validate;
@@ -201,6 +202,28 @@ pub fn standardize_base_asset_amount(base_asset_amount: u64, step_size: u64) ->
     base_asset_amount.safe_sub(remainder)
 }
 
+/// Converts an order size in quote notional to a base asset amount at price, rounded down to the step size
+pub fn calculate_base_asset_amount_for_quote_notional(
+    quote_asset_amount: u64,
+    price: u64,
+    base_precision: u64,
+    step_size: u64,
+) -> DriftResult<u64> {
+    validate!(
+        price > 0,
+        ErrorCode::InvalidOrder,
+        "price must be greater than 0 to size order in quote"
+    )?;
+
+    let base_asset_amount = quote_asset_amount
+        .cast::<u128>()?
+        .safe_mul(base_precision.cast()?)?
+        .safe_div(price.cast()?)?
+        .cast::<u64>()?;
+
+    standardize_base_asset_amount(base_asset_amount, step_size)
+}
+
 pub fn standardize_base_asset_amount_ceil(
     base_asset_amount: u64,
     step_size: u64,
@@ -582,6 +605,47 @@ pub fn order_satisfies_trigger_condition(order: &Order, oracle_price: u64) -> Dr
     }
 }
 
+/// Trails the oracle price by the order's oracle_price_offset. The trigger price only ratchets
+/// up for Below (sell) stops and down for Above (buy) stops
+pub fn calculate_trailing_stop_trigger_price(
+    order: &Order,
+    oracle_price: u64,
+    tick_size: u64,
+) -> DriftResult<u64> {
+    let trailing_offset = order.oracle_price_offset.unsigned_abs().cast::<u64>()?;
+    let trailing_distance = if order.is_bit_flag_set(OrderBitFlag::TrailingStopPercentage) {
+        oracle_price
+            .cast::<u128>()?
+            .safe_mul(trailing_offset.cast()?)?
+            .safe_div(PERCENTAGE_PRECISION)?
+            .cast::<u64>()?
+    } else {
+        trailing_offset
+    };
+
+    match order.trigger_condition {
+        OrderTriggerCondition::Below => {
+            let trailing_trigger_price = standardize_price(
+                oracle_price.saturating_sub(trailing_distance),
+                tick_size,
+                PositionDirection::Long,
+            )?;
+
+            Ok(order.trigger_price.max(trailing_trigger_price))
+        }
+        OrderTriggerCondition::Above => {
+            let trailing_trigger_price = standardize_price(
+                oracle_price.safe_add(trailing_distance)?,
+                tick_size,
+                PositionDirection::Short,
+            )?;
+
+            Ok(order.trigger_price.min(trailing_trigger_price))
+        }
+        _ => Err(print_error!(ErrorCode::InvalidTriggerOrderCondition)()),
+    }
+}
+
 pub fn is_new_order_risk_increasing(
     order: &Order,
     position_base_asset_amount: i64,
@@ -109,6 +109,68 @@ pub mod standardize_base_asset_amount {
     }
 }
 
+pub mod calculate_base_asset_amount_for_quote_notional {
+    use crate::math::constants::{BASE_PRECISION_U64, PRICE_PRECISION_U64, QUOTE_PRECISION_U64};
+    use crate::math::orders::calculate_base_asset_amount_for_quote_notional;
+
+    #[test]
+    fn perp() {
+        // $1000 at $100 = 10 base
+        let base_asset_amount = calculate_base_asset_amount_for_quote_notional(
+            1000 * QUOTE_PRECISION_U64,
+            100 * PRICE_PRECISION_U64,
+            BASE_PRECISION_U64,
+            BASE_PRECISION_U64 / 1000,
+        )
+        .unwrap();
+        assert_eq!(base_asset_amount, 10 * BASE_PRECISION_U64);
+
+        // $1000 at $30 = 33.333... base, rounded down to step size
+        let base_asset_amount = calculate_base_asset_amount_for_quote_notional(
+            1000 * QUOTE_PRECISION_U64,
+            30 * PRICE_PRECISION_U64,
+            BASE_PRECISION_U64,
+            BASE_PRECISION_U64 / 1000,
+        )
+        .unwrap();
+        assert_eq!(base_asset_amount, 33333 * BASE_PRECISION_U64 / 1000);
+    }
+
+    #[test]
+    fn spot_decimals() {
+        // $50 at $25 with 6 decimal mint = 2 tokens
+        let base_asset_amount = calculate_base_asset_amount_for_quote_notional(
+            50 * QUOTE_PRECISION_U64,
+            25 * PRICE_PRECISION_U64,
+            1_000_000,
+            1000,
+        )
+        .unwrap();
+        assert_eq!(base_asset_amount, 2_000_000);
+
+        // $50 at $25 with 8 decimal mint = 2 tokens
+        let base_asset_amount = calculate_base_asset_amount_for_quote_notional(
+            50 * QUOTE_PRECISION_U64,
+            25 * PRICE_PRECISION_U64,
+            100_000_000,
+            1000,
+        )
+        .unwrap();
+        assert_eq!(base_asset_amount, 200_000_000);
+    }
+
+    #[test]
+    fn zero_price() {
+        assert!(calculate_base_asset_amount_for_quote_notional(
+            50 * QUOTE_PRECISION_U64,
+            0,
+            BASE_PRECISION_U64,
+            1000,
+        )
+        .is_err());
+    }
+}
+
 pub mod is_multiple_of_step_size {
     use crate::math::orders::is_multiple_of_step_size;
 
@@ -3861,3 +3923,91 @@ mod fallback_price_logic {
         assert_eq!(result, 99500000);
     }
 }
+
+mod calculate_trailing_stop_trigger_price {
+    use crate::controller::position::PositionDirection;
+    use crate::math::constants::{PERCENTAGE_PRECISION_U64, PRICE_PRECISION_U64};
+    use crate::math::orders::calculate_trailing_stop_trigger_price;
+    use crate::state::user::{Order, OrderBitFlag, OrderTriggerCondition, OrderType};
+
+    #[test]
+    fn sell_stop_ratchets_up() {
+        let order = Order {
+            order_type: OrderType::TrailingStop,
+            direction: PositionDirection::Short,
+            trigger_condition: OrderTriggerCondition::Below,
+            trigger_price: 95 * PRICE_PRECISION_U64,
+            oracle_price_offset: 5 * PRICE_PRECISION_U64 as i32,
+            ..Order::default()
+        };
+
+        // oracle moves up, trigger follows
+        let trigger_price =
+            calculate_trailing_stop_trigger_price(&order, 110 * PRICE_PRECISION_U64, 1).unwrap();
+        assert_eq!(trigger_price, 105 * PRICE_PRECISION_U64);
+
+        // oracle moves down, trigger stays
+        let trigger_price =
+            calculate_trailing_stop_trigger_price(&order, 90 * PRICE_PRECISION_U64, 1).unwrap();
+        assert_eq!(trigger_price, 95 * PRICE_PRECISION_U64);
+    }
+
+    #[test]
+    fn buy_stop_ratchets_down() {
+        let order = Order {
+            order_type: OrderType::TrailingStop,
+            direction: PositionDirection::Long,
+            trigger_condition: OrderTriggerCondition::Above,
+            trigger_price: 105 * PRICE_PRECISION_U64,
+            oracle_price_offset: 5 * PRICE_PRECISION_U64 as i32,
+            ..Order::default()
+        };
+
+        // oracle moves down, trigger follows
+        let trigger_price =
+            calculate_trailing_stop_trigger_price(&order, 90 * PRICE_PRECISION_U64, 1).unwrap();
+        assert_eq!(trigger_price, 95 * PRICE_PRECISION_U64);
+
+        // oracle moves up, trigger stays
+        let trigger_price =
+            calculate_trailing_stop_trigger_price(&order, 110 * PRICE_PRECISION_U64, 1).unwrap();
+        assert_eq!(trigger_price, 105 * PRICE_PRECISION_U64);
+    }
+
+    #[test]
+    fn percentage_offset() {
+        let order = Order {
+            order_type: OrderType::TrailingStop,
+            direction: PositionDirection::Short,
+            trigger_condition: OrderTriggerCondition::Below,
+            trigger_price: 90 * PRICE_PRECISION_U64,
+            oracle_price_offset: (PERCENTAGE_PRECISION_U64 / 20) as i32, // 5%
+            bit_flags: OrderBitFlag::TrailingStopPercentage as u8,
+            ..Order::default()
+        };
+
+        let trigger_price =
+            calculate_trailing_stop_trigger_price(&order, 200 * PRICE_PRECISION_U64, 1).unwrap();
+        assert_eq!(trigger_price, 190 * PRICE_PRECISION_U64);
+    }
+
+    #[test]
+    fn rounds_to_tick_size() {
+        let order = Order {
+            order_type: OrderType::TrailingStop,
+            direction: PositionDirection::Short,
+            trigger_condition: OrderTriggerCondition::Below,
+            trigger_price: 90 * PRICE_PRECISION_U64,
+            oracle_price_offset: 5 * PRICE_PRECISION_U64 as i32,
+            ..Order::default()
+        };
+
+        let trigger_price = calculate_trailing_stop_trigger_price(
+            &order,
+            100 * PRICE_PRECISION_U64 + 123,
+            PRICE_PRECISION_U64 / 100,
+        )
+        .unwrap();
+        assert_eq!(trigger_price, 95 * PRICE_PRECISION_U64);
+    }
+}
@@ -0,0 +1,90 @@
+use crate::error::{DriftResult, ErrorCode};
+use crate::math::safe_math::SafeMath;
+use crate::math_error;
+use crate::safe_decrement;
+use crate::safe_increment;
+use crate::state::traits::Size;
+use crate::validate;
+use anchor_lang::prelude::*;
+
+/// Pool of quote deposits that takes over liquidations no keeper picks up. The pool trades through a
+/// User whose authority is the vault, and depositors own shares of that User's equity
+#[account(zero_copy(unsafe))]
+#[derive(Default, Eq, PartialEq, Debug)]
+#[repr(C)]
+pub struct BackstopVault {
+    pub pubkey: Pubkey,
+    /// the User the vault liquidates through
+    pub user: Pubkey,
+    pub total_shares: u128,
+    /// seconds between a depositor requesting a withdraw and being able to complete it
+    pub unstaking_period: i64,
+    /// slots a user has to be in liquidation before the vault can liquidate them
+    pub liquidation_delay: u64,
+    pub padding: [u8; 16],
+}
+
+// implement SIZE const for BackstopVault
+impl Size for BackstopVault {
+    const SIZE: usize = 120;
+}
+
+impl BackstopVault {
+    pub fn can_liquidate(&self, liquidation_start_slot: u64, slot: u64) -> DriftResult<bool> {
+        Ok(slot.safe_sub(liquidation_start_slot)? >= self.liquidation_delay)
+    }
+}
+
+#[account(zero_copy(unsafe))]
+#[derive(Default, Eq, PartialEq, Debug)]
+#[repr(C)]
+pub struct BackstopVaultDepositor {
+    pub authority: Pubkey,
+    shares: u128,
+    pub last_withdraw_request_shares: u128,
+    /// precision: QUOTE_PRECISION
+    pub last_withdraw_request_value: u64,
+    pub last_withdraw_request_ts: i64,
+    /// precision: QUOTE_PRECISION
+    pub cost_basis: i64,
+    pub padding: [u8; 8],
+}
+
+// implement SIZE const for BackstopVaultDepositor
+impl Size for BackstopVaultDepositor {
+    const SIZE: usize = 104;
+}
+
+impl BackstopVaultDepositor {
+    pub fn new(authority: Pubkey) -> Self {
+        BackstopVaultDepositor {
+            authority,
+            ..BackstopVaultDepositor::default()
+        }
+    }
+
+    pub fn shares(&self) -> u128 {
+        self.shares
+    }
+
+    pub fn increase_shares(&mut self, delta: u128) -> DriftResult {
+        safe_increment!(self.shares, delta);
+        Ok(())
+    }
+
+    pub fn decrease_shares(&mut self, delta: u128) -> DriftResult {
+        validate!(
+            self.shares >= delta,
+            ErrorCode::InsufficientBackstopVaultShares,
+            "shares {} < {}",
+            self.shares,
+            delta
+        )?;
+        safe_decrement!(self.shares, delta);
+        Ok(())
+    }
+
+    pub fn has_withdraw_request(&self) -> bool {
+        self.last_withdraw_request_shares != 0
+    }
+}
@@ -343,6 +343,11 @@ pub enum OrderActionExplanation {
     OrderFilledWithAMMJitLPSplit,
     OrderFilledWithLPJit,
     DeriskLp,
+    LinkedOrderExecuted,
+    SelfTradePrevented,
+    CancelAllAfterTs,
+    PositionClosed,
+    MarginCall,
 }
 
 impl Default for OrderAction {
@@ -494,6 +499,31 @@ pub struct SpotBankruptcyRecord {
     pub cumulative_deposit_interest_delta: u128,
 }
 
+#[event]
+#[derive(Default)]
+pub struct AdlRecord {
+    pub ts: i64,
+    pub liquidation_id: u16,
+    pub market_index: u16,
+    /// the underwater user whose position was closed
+    pub bankrupt_user: Pubkey,
+    /// the counterparty that was deleveraged
+    pub user: Pubkey,
+    /// precision: BASE_PRECISION
+    pub base_asset_amount: u64,
+    /// precision: QUOTE_PRECISION
+    pub quote_asset_amount: u64,
+    /// precision: PRICE_PRECISION
+    pub bankruptcy_price: i64,
+    /// precision: PRICE_PRECISION
+    pub oracle_price: i64,
+    /// profit the counterparty gave up versus closing at the oracle price
+    /// precision: QUOTE_PRECISION
+    pub pnl_haircut: u128,
+    /// precision: PERCENTAGE_PRECISION
+    pub adl_score: u128,
+}
+
 #[event]
 #[derive(Default)]
 pub struct SettlePnlRecord {
@@ -561,6 +591,22 @@ pub struct InsuranceFundStakeRecord {
     pub total_if_shares_after: u128,
 }
 
+#[event]
+#[derive(Default)]
+pub struct BackstopVaultRecord {
+    pub ts: i64,
+    pub user_authority: Pubkey,
+    pub action: StakeAction,
+    /// precision: QUOTE_PRECISION
+    pub amount: u64,
+    /// precision: QUOTE_PRECISION
+    pub vault_equity_before: u64,
+    pub shares_before: u128,
+    pub total_shares_before: u128,
+    pub shares_after: u128,
+    pub total_shares_after: u128,
+}
+
 #[derive(Clone, Copy, BorshSerialize, BorshDeserialize, PartialEq, Eq)]
 pub enum StakeAction {
     Stake,
@@ -13,14 +13,46 @@ pub enum MarginCalculationMode {
     Liquidation {
         market_to_track_margin_requirement: Option<MarketIdentifier>,
     },
+    /// every oracle is moved by its market's stress_oracle_shock and widened by its confidence interval
+    Stress {
+        shock_up: bool,
+    },
+}
+
+/// Which of the user's positions a margin calculation covers
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MarginScope {
+    /// every position except isolated perp positions
+    Cross,
+    /// a single isolated perp position backed only by its own deposit
+    IsolatedPerp { market_index: u16 },
+}
+
+impl MarginScope {
+    pub fn includes_perp_position(&self, market_index: u16, is_isolated: bool) -> bool {
+        match self {
+            MarginScope::Cross => !is_isolated,
+            MarginScope::IsolatedPerp {
+                market_index: isolated_market_index,
+            } => is_isolated && *isolated_market_index == market_index,
+        }
+    }
+
+    pub fn is_isolated(&self) -> bool {
+        matches!(self, MarginScope::IsolatedPerp { .. })
+    }
 }
 
 #[derive(Clone, Copy, Debug)]
 pub struct MarginContext {
     pub margin_type: MarginRequirementType,
     pub mode: MarginCalculationMode,
+    pub scope: MarginScope,
     pub strict: bool,
     pub margin_buffer: u128,
+    /// Also track each market's margin requirement and the asset and liability totals behind
+    /// get_leverage. Always on with drift-rs, otherwise off since it costs compute on chain
+    pub track_details: bool,
 }
 
 #[derive(PartialEq, Eq, Copy, Clone, Debug, AnchorSerialize, AnchorDeserialize)]
@@ -52,11 +84,18 @@ impl MarginContext {
             mode: MarginCalculationMode::Standard {
                 track_open_orders_fraction: false,
             },
+            scope: MarginScope::Cross,
             strict: false,
             margin_buffer: 0,
+            track_details: false,
         }
     }
 
+    pub fn scope(mut self, scope: MarginScope) -> Self {
+        self.scope = scope;
+        self
+    }
+
     pub fn strict(mut self, strict: bool) -> Self {
         self.strict = strict;
         self
@@ -67,6 +106,11 @@ impl MarginContext {
         self
     }
 
+    pub fn track_details(mut self) -> Self {
+        self.track_details = true;
+        self
+    }
+
     pub fn track_open_orders_fraction(mut self) -> DriftResult<Self> {
         match self.mode {
             MarginCalculationMode::Standard {
@@ -88,8 +132,28 @@ impl MarginContext {
             mode: MarginCalculationMode::Liquidation {
                 market_to_track_margin_requirement: None,
             },
+            scope: MarginScope::Cross,
             margin_buffer: margin_buffer as u128,
             strict: false,
+            track_details: false,
+        }
+    }
+
+    pub fn stress(shock_up: bool) -> Self {
+        Self {
+            margin_type: MarginRequirementType::Maintenance,
+            mode: MarginCalculationMode::Stress { shock_up },
+            scope: MarginScope::Cross,
+            strict: false,
+            margin_buffer: 0,
+            track_details: false,
+        }
+    }
+
+    pub fn stress_shock_up(&self) -> Option<bool> {
+        match self.mode {
+            MarginCalculationMode::Stress { shock_up } => Some(shock_up),
+            _ => None,
         }
     }
 
@@ -113,7 +177,7 @@ impl MarginContext {
     }
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Debug)]
 pub struct MarginCalculation {
     pub context: MarginContext,
     pub total_collateral: i128,
@@ -127,16 +191,26 @@ pub struct MarginCalculation {
     pub all_oracles_valid: bool,
     pub with_perp_isolated_liability: bool,
     pub with_spot_isolated_liability: bool,
+    pub stress_check_required: bool,
     pub total_spot_asset_value: i128,
     pub total_spot_liability_value: u128,
     pub total_perp_liability_value: u128,
     pub total_perp_pnl: i128,
     pub open_orders_margin_requirement: u128,
     tracked_market_margin_requirement: u128,
+    pub total_hedge_offset: u128,
+    /// Only allocated when details are tracked, to keep the calculation small on the stack
+    pub market_margin_requirements: Option<Box<[Option<(MarketIdentifier, u128)>; 16]>>,
 }
 
 impl MarginCalculation {
     pub fn new(context: MarginContext) -> Self {
+        let market_margin_requirements = if cfg!(feature = "drift-rs") || context.track_details {
+            Some(Box::new([None; 16]))
+        } else {
+            None
+        };
+
         Self {
             context,
             total_collateral: 0,
@@ -147,12 +221,15 @@ impl MarginCalculation {
             all_oracles_valid: true,
             with_perp_isolated_liability: false,
             with_spot_isolated_liability: false,
+            stress_check_required: false,
             total_spot_asset_value: 0,
             total_spot_liability_value: 0,
             total_perp_liability_value: 0,
             total_perp_pnl: 0,
             open_orders_margin_requirement: 0,
             tracked_market_margin_requirement: 0,
+            total_hedge_offset: 0,
+            market_margin_requirements,
         }
     }
 
@@ -186,6 +263,38 @@ impl MarginCalculation {
             }
         }
 
+        self.update_market_margin_requirement(market_identifier, margin_requirement.cast()?)?;
+
+        Ok(())
+    }
+
+    pub fn add_hedge_offset(
+        &mut self,
+        hedge_offset: u128,
+        market_identifier: MarketIdentifier,
+    ) -> DriftResult {
+        self.margin_requirement = self.margin_requirement.safe_sub(hedge_offset)?;
+
+        if self.context.margin_buffer > 0 {
+            self.margin_requirement_plus_buffer =
+                self.margin_requirement_plus_buffer.safe_sub(hedge_offset)?;
+        }
+
+        if let Some(market_to_track) = self.market_to_track_margin_requirement() {
+            if market_to_track == market_identifier {
+                self.tracked_market_margin_requirement = self
+                    .tracked_market_margin_requirement
+                    .safe_sub(hedge_offset)?;
+            }
+        }
+
+        self.total_hedge_offset = self.total_hedge_offset.safe_add(hedge_offset)?;
+
+        self.update_market_margin_requirement(
+            market_identifier,
+            hedge_offset.cast::<i128>()?.safe_mul(-1)?,
+        )?;
+
         Ok(())
     }
 
@@ -206,34 +315,142 @@ impl MarginCalculation {
         Ok(())
     }
 
-    #[cfg(feature = "drift-rs")]
     pub fn add_spot_asset_value(&mut self, spot_asset_value: i128) -> DriftResult {
+        if !self.tracks_details() {
+            return Ok(());
+        }
+
         self.total_spot_asset_value = self.total_spot_asset_value.safe_add(spot_asset_value)?;
         Ok(())
     }
 
-    #[cfg(feature = "drift-rs")]
     pub fn add_spot_liability_value(&mut self, spot_liability_value: u128) -> DriftResult {
+        if !self.tracks_details() {
+            return Ok(());
+        }
+
         self.total_spot_liability_value = self
             .total_spot_liability_value
             .safe_add(spot_liability_value)?;
         Ok(())
     }
 
-    #[cfg(feature = "drift-rs")]
     pub fn add_perp_liability_value(&mut self, perp_liability_value: u128) -> DriftResult {
+        if !self.tracks_details() {
+            return Ok(());
+        }
+
         self.total_perp_liability_value = self
             .total_perp_liability_value
             .safe_add(perp_liability_value)?;
         Ok(())
     }
 
-    #[cfg(feature = "drift-rs")]
     pub fn add_perp_pnl(&mut self, perp_pnl: i128) -> DriftResult {
+        if !self.tracks_details() {
+            return Ok(());
+        }
+
         self.total_perp_pnl = self.total_perp_pnl.safe_add(perp_pnl)?;
         Ok(())
     }
 
+    fn update_market_margin_requirement(
+        &mut self,
+        market_identifier: MarketIdentifier,
+        delta: i128,
+    ) -> DriftResult {
+        if delta == 0 {
+            return Ok(());
+        }
+
+        let market_margin_requirements = match self.market_margin_requirements.as_mut() {
+            Some(market_margin_requirements) => market_margin_requirements,
+            None => return Ok(()),
+        };
+
+        for market_margin_requirement in market_margin_requirements.iter_mut() {
+            match market_margin_requirement {
+                Some((market, margin_requirement)) if *market == market_identifier => {
+                    *margin_requirement =
+                        margin_requirement.cast::<i128>()?.safe_add(delta)?.cast()?;
+                    return Ok(());
+                }
+                None => {
+                    *market_margin_requirement = Some((market_identifier, delta.cast()?));
+                    return Ok(());
+                }
+                _ => {}
+            }
+        }
+
+        msg!(
+            "no space to track margin requirement for {:?}",
+            market_identifier
+        );
+        Err(ErrorCode::InvalidMarginCalculation)
+    }
+
+    pub fn tracks_details(&self) -> bool {
+        self.market_margin_requirements.is_some()
+    }
+
+    pub fn get_market_margin_requirement(&self, market_identifier: MarketIdentifier) -> u128 {
+        self.market_margin_requirements
+            .iter()
+            .flat_map(|market_margin_requirements| market_margin_requirements.iter())
+            .flatten()
+            .find(|(market, _)| *market == market_identifier)
+            .map(|(_, margin_requirement)| *margin_requirement)
+            .unwrap_or(0)
+    }
+
+    /// Switches the tracked market using the per market margin requirements, so one calculation
+    /// can be carried across the steps of a liquidation
+    pub fn track_market_margin_requirement(
+        mut self,
+        market_identifier: MarketIdentifier,
+    ) -> DriftResult<Self> {
+        validate!(
+            self.tracks_details(),
+            ErrorCode::InvalidMarginCalculation,
+            "market margin requirements not tracked"
+        )?;
+
+        self.context = self
+            .context
+            .track_market_margin_requirement(market_identifier)?;
+        self.tracked_market_margin_requirement =
+            self.get_market_margin_requirement(market_identifier);
+
+        Ok(self)
+    }
+
+    /// total liability value divided by net asset value
+    /// precision: MARGIN_PRECISION
+    pub fn get_leverage(&self) -> DriftResult<u128> {
+        let total_liability_value = self
+            .total_perp_liability_value
+            .safe_add(self.total_spot_liability_value)?;
+
+        if total_liability_value == 0 {
+            return Ok(0);
+        }
+
+        let net_asset_value = self
+            .total_spot_asset_value
+            .safe_sub(self.total_spot_liability_value.cast()?)?
+            .safe_add(self.total_perp_pnl)?;
+
+        if net_asset_value <= 0 {
+            return Ok(u128::MAX);
+        }
+
+        total_liability_value
+            .safe_mul(MARGIN_PRECISION_U128)?
+            .safe_div(net_asset_value.unsigned_abs())
+    }
+
     pub fn update_all_oracles_valid(&mut self, valid: bool) {
         self.all_oracles_valid &= valid;
     }
@@ -246,6 +463,10 @@ impl MarginCalculation {
         self.with_perp_isolated_liability |= isolated;
     }
 
+    pub fn update_stress_check_required(&mut self, required: bool) {
+        self.stress_check_required |= required;
+    }
+
     pub fn validate_num_spot_liabilities(&self) -> DriftResult {
         if self.num_spot_liabilities > 0 {
             validate!(
@@ -1,3 +1,4 @@
+pub mod backstop_vault;
 pub mod events;
 pub mod fill_mode;
 pub mod fulfillment;
@@ -81,6 +81,18 @@ impl<'a> OracleMap<'a> {
         self.price_data.get(pubkey).safe_unwrap()
     }
 
+    /// Overrides the cached price for an already loaded oracle, e.g. to value a user at a hypothetical price
+    pub fn set_price_data(&mut self, pubkey: &Pubkey, price_data: OraclePriceData) -> DriftResult {
+        if !self.price_data.contains_key(pubkey) {
+            msg!("oracle pubkey not loaded in oracle_map: {}", pubkey);
+            return Err(ErrorCode::OracleNotFound);
+        }
+
+        self.price_data.insert(*pubkey, price_data);
+
+        Ok(())
+    }
+
     pub fn get_price_data_and_validity(
         &mut self,
         market_type: MarketType,
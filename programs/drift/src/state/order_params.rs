@@ -31,13 +31,82 @@ pub struct OrderParams {
     pub max_ts: Option<i64>,
     pub trigger_price: Option<u64>,
     pub trigger_condition: OrderTriggerCondition,
-    pub oracle_price_offset: Option<i32>, // price offset from oracle for order (~ +/- 2147 max)
+    pub oracle_price_offset: Option<i32>, // price offset from oracle for order (~ +/- 2147 max), max slippage pct for twap
     pub auction_duration: Option<u8>,     // specified in slots
     pub auction_start_price: Option<i64>, // specified in price or oracle_price_offset
     pub auction_end_price: Option<i64>,   // specified in price or oracle_price_offset
+    pub bit_flags: u8,                    // see OrderBitFlag
+    pub group_id: u8,                     // links orders for oco/bracket, 0 for none
+    pub twap_slices: u8,                  // number of slices for twap orders
+    pub fill_or_kill: bool,
+    pub min_fill_base_asset_amount: Option<u64>, // place_and_take reverts if less is filled
+    pub quote_asset_amount: Option<u64>, // size in quote, converted to base_asset_amount when placed
 }
 
 impl OrderParams {
+    pub fn has_min_fill(&self) -> bool {
+        self.fill_or_kill || self.min_fill_base_asset_amount.is_some()
+    }
+
+    /// Price a quote sized order is converted at. Market and oracle orders use the worst price
+    /// they can fill at, the auction end price or the limit price after the auction, so the
+    /// filled notional never exceeds the quote amount. Other orders use the limit price, the
+    /// trigger price for trigger orders, otherwise the oracle price plus the order's offset
+    pub fn get_price_for_quote_notional(
+        &self,
+        oracle_price: i64,
+        auction_end_price: i64,
+    ) -> DriftResult<u64> {
+        if matches!(self.order_type, OrderType::Market | OrderType::Oracle) {
+            let (auction_end_price, limit_price) = if self.order_type == OrderType::Oracle {
+                (
+                    oracle_price.safe_add(auction_end_price)?,
+                    oracle_price.safe_add(self.oracle_price_offset.unwrap_or(0).cast()?)?,
+                )
+            } else {
+                (auction_end_price, self.price.cast::<i64>()?)
+            };
+
+            let worst_price = if limit_price == 0 {
+                auction_end_price
+            } else {
+                match self.direction {
+                    PositionDirection::Long => auction_end_price.max(limit_price),
+                    PositionDirection::Short => auction_end_price.min(limit_price),
+                }
+            };
+
+            return worst_price.cast();
+        }
+
+        if self.price > 0 {
+            return Ok(self.price);
+        }
+
+        if let Some(trigger_price) = self.trigger_price {
+            let is_trigger_order = matches!(
+                self.order_type,
+                OrderType::TriggerMarket | OrderType::TriggerLimit | OrderType::TrailingStop
+            );
+            if trigger_price > 0 && is_trigger_order {
+                return Ok(trigger_price);
+            }
+        }
+
+        oracle_price
+            .safe_add(self.oracle_price_offset.unwrap_or(0).cast()?)?
+            .cast()
+    }
+
+    /// For fill or kill orders, the entire placed order must fill
+    pub fn get_min_fill_base_asset_amount(&self, order_base_asset_amount: u64) -> Option<u64> {
+        if self.fill_or_kill {
+            Some(order_base_asset_amount)
+        } else {
+            self.min_fill_base_asset_amount
+        }
+    }
+
     pub fn update_perp_auction_params_limit_orders(
         &mut self,
         perp_market: &PerpMarket,
@@ -636,6 +705,12 @@ pub struct ModifyOrderParams {
     pub policy: Option<ModifyOrderPolicy>,
 }
 
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default)]
+pub struct ModifyOrderByIdParams {
+    pub order_id: u32,
+    pub modify_order_params: ModifyOrderParams,
+}
+
 #[derive(AnchorSerialize, AnchorDeserialize, Clone, Eq, PartialEq)]
 pub enum ModifyOrderPolicy {
     TryModify,
@@ -1047,7 +1047,9 @@ mod get_close_perp_params {
             auction_end_price: params.auction_end_price.unwrap_or(0),
             auction_duration: params.auction_duration.unwrap_or(0),
             max_ts: 100,
-            padding: [0; 3],
+            bit_flags: 0,
+            group_id: 0,
+            twap_slices: 0,
         }
     }
 
@@ -1112,3 +1114,55 @@ mod get_close_perp_params {
         validate_order(&order, &perp_market, Some(oracle_price), slot).unwrap();
     }
 }
+
+mod get_min_fill_base_asset_amount {
+    use crate::{OrderParams, BASE_PRECISION_U64};
+
+    #[test]
+    fn no_min_fill() {
+        let params = OrderParams {
+            base_asset_amount: BASE_PRECISION_U64,
+            immediate_or_cancel: true,
+            ..OrderParams::default()
+        };
+
+        assert!(!params.has_min_fill());
+        assert_eq!(
+            params.get_min_fill_base_asset_amount(BASE_PRECISION_U64),
+            None
+        );
+    }
+
+    #[test]
+    fn fill_or_kill() {
+        let params = OrderParams {
+            base_asset_amount: BASE_PRECISION_U64,
+            fill_or_kill: true,
+            min_fill_base_asset_amount: Some(BASE_PRECISION_U64 / 2),
+            ..OrderParams::default()
+        };
+
+        assert!(params.has_min_fill());
+        // fill or kill takes the placed order size, which may have been standardized
+        assert_eq!(
+            params.get_min_fill_base_asset_amount(BASE_PRECISION_U64 / 10 * 9),
+            Some(BASE_PRECISION_U64 / 10 * 9)
+        );
+    }
+
+    #[test]
+    fn immediate_or_cancel_with_min_fill() {
+        let params = OrderParams {
+            base_asset_amount: BASE_PRECISION_U64,
+            immediate_or_cancel: true,
+            min_fill_base_asset_amount: Some(BASE_PRECISION_U64 / 2),
+            ..OrderParams::default()
+        };
+
+        assert!(params.has_min_fill());
+        assert_eq!(
+            params.get_min_fill_base_asset_amount(BASE_PRECISION_U64),
+            Some(BASE_PRECISION_U64 / 2)
+        );
+    }
+}
@@ -13,9 +13,9 @@ use crate::math::constants::{
 use crate::math::constants::{
     AMM_RESERVE_PRECISION_I128, AMM_TO_QUOTE_PRECISION_RATIO, BID_ASK_SPREAD_PRECISION,
     BID_ASK_SPREAD_PRECISION_U128, DEFAULT_REVENUE_SINCE_LAST_FUNDING_SPREAD_RETREAT,
-    LP_FEE_SLICE_DENOMINATOR, LP_FEE_SLICE_NUMERATOR, MARGIN_PRECISION_U128, PERCENTAGE_PRECISION,
-    PERCENTAGE_PRECISION_I128, PERCENTAGE_PRECISION_I64, PERCENTAGE_PRECISION_U64, PRICE_PRECISION,
-    SPOT_WEIGHT_PRECISION, TWENTY_FOUR_HOUR,
+    LP_FEE_SLICE_DENOMINATOR, LP_FEE_SLICE_NUMERATOR, MARGIN_PRECISION, MARGIN_PRECISION_U128,
+    MAX_MARGIN_RATIO, PERCENTAGE_PRECISION, PERCENTAGE_PRECISION_I128, PERCENTAGE_PRECISION_I64,
+    PERCENTAGE_PRECISION_U64, PRICE_PRECISION, SPOT_WEIGHT_PRECISION, TWENTY_FOUR_HOUR,
 };
 use crate::math::helpers::get_proportion_i128;
 
@@ -232,7 +232,25 @@ pub struct PerpMarket {
     /// E.g. if this is -50 and the fee is 5bps, the new fee will be 2.5bps
     /// if this is 50 and the fee is 5bps, the new fee will be 7.5bps
     pub fee_adjustment: i16,
-    pub padding: [u8; 46],
+    /// The spot market whose deposits can offset a short position in this market and whose borrows
+    /// can offset a long, e.g. SOL deposits offsetting a SOL-PERP short. 0 means no offset
+    pub hedge_spot_market_index: u16,
+    /// The oracle move applied to this market in stress margin calculations
+    /// Setting it on an isolated tier market requires users with a liability there to pass the stress check
+    /// precision: MARGIN_PRECISION
+    pub stress_oracle_shock: u16,
+    /// The oracle std (as a pct of the oracle twap) at which the static initial margin ratio applies
+    /// the initial margin ratio is scaled by realized std / this value. 0 disables scaling
+    /// maintenance margin is never scaled
+    /// precision: MARGIN_PRECISION
+    pub margin_ratio_volatility_reference: u16,
+    /// The smallest multiple of the initial margin ratio that volatility scaling can apply
+    /// precision: MARGIN_PRECISION
+    pub margin_ratio_volatility_scale_min: u16,
+    /// The largest multiple of the initial margin ratio that volatility scaling can apply
+    /// precision: MARGIN_PRECISION
+    pub margin_ratio_volatility_scale_max: u16,
+    pub padding: [u8; 36],
 }
 
 impl Default for PerpMarket {
@@ -266,7 +284,12 @@ impl Default for PerpMarket {
             paused_operations: 0,
             quote_spot_market_index: 0,
             fee_adjustment: 0,
-            padding: [0; 46],
+            hedge_spot_market_index: 0,
+            stress_oracle_shock: 0,
+            margin_ratio_volatility_reference: 0,
+            margin_ratio_volatility_scale_min: 0,
+            margin_ratio_volatility_scale_max: 0,
+            padding: [0; 36],
         }
     }
 }
@@ -394,9 +417,9 @@ impl PerpMarket {
         }
 
         let default_margin_ratio = match margin_type {
-            MarginRequirementType::Initial => self.margin_ratio_initial,
+            MarginRequirementType::Initial => self.get_volatility_scaled_margin_ratio_initial()?,
             MarginRequirementType::Fill => {
-                self.margin_ratio_initial
+                self.get_volatility_scaled_margin_ratio_initial()?
                     .safe_add(self.margin_ratio_maintenance)?
                     / 2
             }
@@ -415,6 +438,48 @@ impl PerpMarket {
         Ok(margin_ratio)
     }
 
+    /// Initial margin ratio scaled by realized volatility. Maintenance is never scaled so a
+    /// volatility spike can't push existing positions into liquidation
+    pub fn get_volatility_scaled_margin_ratio_initial(&self) -> DriftResult<u32> {
+        self.margin_ratio_initial
+            .cast::<u128>()?
+            .safe_mul(self.get_volatility_margin_scale()?.cast()?)?
+            .safe_div(MARGIN_PRECISION_U128)?
+            .min(MAX_MARGIN_RATIO.cast()?)
+            .cast()
+    }
+
+    /// Multiple of the static initial margin ratio based on the market's realized oracle volatility
+    /// mark_std is left out since trading against the amm moves it
+    /// precision: MARGIN_PRECISION
+    pub fn get_volatility_margin_scale(&self) -> DriftResult<u32> {
+        if self.margin_ratio_volatility_reference == 0 {
+            return Ok(MARGIN_PRECISION);
+        }
+
+        let oracle_price_twap = self.amm.historical_oracle_data.last_oracle_price_twap;
+        if oracle_price_twap <= 0 {
+            return Ok(MARGIN_PRECISION);
+        }
+
+        let std_pct = self
+            .amm
+            .oracle_std
+            .cast::<u128>()?
+            .safe_mul(MARGIN_PRECISION_U128)?
+            .safe_div(oracle_price_twap.unsigned_abs().cast()?)?;
+
+        let scale = std_pct
+            .safe_mul(MARGIN_PRECISION_U128)?
+            .safe_div(self.margin_ratio_volatility_reference.cast()?)?
+            .clamp(
+                self.margin_ratio_volatility_scale_min.cast()?,
+                self.margin_ratio_volatility_scale_max.cast()?,
+            );
+
+        scale.cast()
+    }
+
     pub fn get_unrealized_asset_weight(
         &self,
         unrealized_pnl: i128,
@@ -877,7 +942,9 @@ pub struct AMM {
     pub target_base_asset_amount_per_lp: i32,
     /// expo for unit of per_lp, base 10 (if per_lp_base=X, then per_lp unit is 10^X)
     pub per_lp_base: i8,
-    pub padding1: u8,
+    /// the reserve price is repegged to the oracle on every amm update instead of within the fee budget
+    /// the repeg cost is still applied to total_fee_minus_distributions
+    pub oracle_anchored: bool,
     pub padding2: u16,
     pub total_fee_earned_per_lp: u64,
     pub net_unsettled_funding_pnl: i64,
@@ -966,7 +1033,7 @@ impl Default for AMM {
             last_oracle_valid: false,
             target_base_asset_amount_per_lp: 0,
             per_lp_base: 0,
-            padding1: 0,
+            oracle_anchored: false,
             padding2: 0,
             total_fee_earned_per_lp: 0,
             net_unsettled_funding_pnl: 0,
@@ -40,3 +40,93 @@ mod amm {
         assert_eq!(discount, 10000000); // $1
     }
 }
+
+mod get_margin_ratio {
+    use crate::math::margin::MarginRequirementType;
+    use crate::state::oracle::HistoricalOracleData;
+    use crate::state::perp_market::{MarketStatus, PerpMarket, AMM};
+    use crate::{MARGIN_PRECISION, PRICE_PRECISION_I64, PRICE_PRECISION_U64};
+
+    #[test]
+    fn volatility_scaling() {
+        let mut market = PerpMarket {
+            amm: AMM {
+                oracle_std: PRICE_PRECISION_U64,
+                mark_std: PRICE_PRECISION_U64,
+                historical_oracle_data: HistoricalOracleData {
+                    last_oracle_price_twap: 100 * PRICE_PRECISION_I64,
+                    ..HistoricalOracleData::default()
+                },
+                ..AMM::default()
+            },
+            margin_ratio_initial: 1000,
+            margin_ratio_maintenance: 500,
+            status: MarketStatus::Active,
+            ..PerpMarket::default()
+        };
+
+        // disabled
+        assert_eq!(
+            market
+                .get_margin_ratio(0, MarginRequirementType::Initial)
+                .unwrap(),
+            1000
+        );
+
+        // 1% std vs .5% reference doubles the initial margin ratio
+        market.margin_ratio_volatility_reference = 50;
+        market.margin_ratio_volatility_scale_min = 3 * MARGIN_PRECISION as u16 / 4;
+        market.margin_ratio_volatility_scale_max = 3 * MARGIN_PRECISION as u16;
+
+        assert_eq!(market.get_volatility_margin_scale().unwrap(), 20000);
+        assert_eq!(
+            market
+                .get_margin_ratio(0, MarginRequirementType::Initial)
+                .unwrap(),
+            2000
+        );
+        assert_eq!(
+            market
+                .get_margin_ratio(0, MarginRequirementType::Fill)
+                .unwrap(),
+            1250
+        );
+        // maintenance is never scaled
+        assert_eq!(
+            market
+                .get_margin_ratio(0, MarginRequirementType::Maintenance)
+                .unwrap(),
+            500
+        );
+
+        // mark std is ignored
+        market.amm.mark_std = 10 * PRICE_PRECISION_U64;
+        assert_eq!(market.get_volatility_margin_scale().unwrap(), 20000);
+
+        // calm market bounded by min
+        market.amm.oracle_std = 0;
+        assert_eq!(market.get_volatility_margin_scale().unwrap(), 7500);
+        assert_eq!(
+            market
+                .get_margin_ratio(0, MarginRequirementType::Initial)
+                .unwrap(),
+            750
+        );
+
+        // turbulent market bounded by max
+        market.amm.oracle_std = 10 * PRICE_PRECISION_U64;
+        assert_eq!(market.get_volatility_margin_scale().unwrap(), 30000);
+        assert_eq!(
+            market
+                .get_margin_ratio(0, MarginRequirementType::Initial)
+                .unwrap(),
+            3000
+        );
+        assert_eq!(
+            market
+                .get_margin_ratio(0, MarginRequirementType::Maintenance)
+                .unwrap(),
+            500
+        );
+    }
+}
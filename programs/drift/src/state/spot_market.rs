@@ -178,7 +178,11 @@ pub struct SpotMarket {
     /// disabled when 0
     /// precision: QUOTE_PRECISION
     pub scale_initial_asset_weight_start: u64,
-    pub padding: [u8; 48],
+    /// The oracle move applied to this market in stress margin calculations
+    /// Setting it on an isolated tier market requires users with a liability there to pass the stress check
+    /// precision: MARGIN_PRECISION
+    pub stress_oracle_shock: u16,
+    pub padding: [u8; 46],
 }
 
 impl Default for SpotMarket {
@@ -239,7 +243,8 @@ impl Default for SpotMarket {
             flash_loan_initial_token_amount: 0,
             total_swap_fee: 0,
             scale_initial_asset_weight_start: 0,
-            padding: [0; 48],
+            stress_oracle_shock: 0,
+            padding: [0; 46],
         }
     }
 }
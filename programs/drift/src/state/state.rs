@@ -41,7 +41,15 @@ pub struct State {
     pub initial_pct_to_liquidate: u16,
     pub max_number_of_sub_accounts: u16,
     pub max_initialize_user_fee: u16,
-    pub padding: [u8; 10],
+    /// Share of a market's liquidator fee paid when a liquidation starts. It scales up to the full fee over liquidation_duration
+    /// precision: LIQUIDATION_PCT_PRECISION
+    pub initial_liquidator_fee_pct: u16,
+    /// Buffer above maintenance margin. Users below maintenance plus this buffer, and below initial margin, can be put in margin call
+    /// 0 disables margin calls. precision: MARGIN_PRECISION
+    pub margin_call_buffer_ratio: u32,
+    /// Slots a user in margin call has to de-risk before a keeper can place orders on their behalf
+    pub margin_call_grace_period: u16,
+    pub padding: [u8; 2],
 }
 
 #[derive(BitFlags, Clone, Copy, PartialEq, Debug, Eq)]
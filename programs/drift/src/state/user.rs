@@ -4,8 +4,8 @@ use crate::error::{DriftResult, ErrorCode};
 use crate::math::auction::{calculate_auction_price, is_auction_complete};
 use crate::math::casting::Cast;
 use crate::math::constants::{
-    EPOCH_DURATION, OPEN_ORDER_MARGIN_REQUIREMENT, PRICE_TIMES_AMM_TO_QUOTE_PRECISION_RATIO,
-    QUOTE_PRECISION, QUOTE_SPOT_MARKET_INDEX, THIRTY_DAY,
+    EPOCH_DURATION, OPEN_ORDER_MARGIN_REQUIREMENT, PERCENTAGE_PRECISION,
+    PRICE_TIMES_AMM_TO_QUOTE_PRECISION_RATIO, QUOTE_PRECISION, QUOTE_SPOT_MARKET_INDEX, THIRTY_DAY,
 };
 use crate::math::lp::{calculate_lp_open_bids_asks, calculate_settle_lp_metrics};
 use crate::math::margin::MarginRequirementType;
@@ -19,6 +19,7 @@ use crate::math::spot_balance::{
     get_signed_token_amount, get_strict_token_value, get_token_amount, get_token_value,
 };
 use crate::math::stats::calculate_rolling_sum;
+use crate::state::margin_calculation::MarginScope;
 use crate::state::oracle::StrictOraclePrice;
 use crate::state::perp_market::PerpMarket;
 use crate::state::spot_market::{SpotBalance, SpotBalanceType, SpotMarket};
@@ -45,11 +46,12 @@ pub enum UserStatus {
     Bankrupt = 0b00000010,
     ReduceOnly = 0b00000100,
     AdvancedLp = 0b00001000,
+    MarginCall = 0b00010000,
 }
 
 // implement SIZE const for User
 impl Size for User {
-    const SIZE: usize = 4376;
+    const SIZE: usize = 4528;
 }
 
 #[account(zero_copy(unsafe))]
@@ -102,7 +104,7 @@ pub struct User {
     pub next_liquidation_id: u16,
     /// The sub account id for this user
     pub sub_account_id: u16,
-    /// Whether the user is active, being liquidated or bankrupt
+    /// Whether the user is active, being liquidated, bankrupt or in margin call
     pub status: u8,
     /// Whether the user has enabled margin trading
     pub is_margin_trading_enabled: bool,
@@ -117,7 +119,15 @@ pub struct User {
     pub open_auctions: u8,
     /// Whether or not user has open order with auction
     pub has_open_auction: bool,
-    pub padding: [u8; 21],
+    pub padding1: [u8; 5],
+    /// Unix timestamp after which any keeper can cancel all of the user's open orders
+    /// Refreshed periodically by the authority. 0 means disabled
+    pub cancel_all_after_ts: i64,
+    /// Max leverage the authority allows per perp and spot market, keyed by market so a cap outlives
+    /// the position it was set on. Only raises the initial margin requirement
+    pub market_max_leverages: [MarketMaxLeverage; 8],
+    /// Quote deposits backing the perp positions the authority margins in isolation
+    pub isolated_perp_positions: [IsolatedPerpPosition; 8],
 }
 
 impl User {
@@ -137,6 +147,61 @@ impl User {
         self.status & (UserStatus::AdvancedLp as u8) > 0
     }
 
+    pub fn is_in_margin_call(&self) -> bool {
+        self.status & (UserStatus::MarginCall as u8) > 0
+    }
+
+    pub fn can_cancel_all_orders_after_ts(&self, now: i64) -> bool {
+        self.cancel_all_after_ts != 0 && now >= self.cancel_all_after_ts
+    }
+
+    pub fn get_market_max_leverage(&self, market_type: MarketType, market_index: u16) -> u8 {
+        self.market_max_leverages
+            .iter()
+            .find(|market_max_leverage| market_max_leverage.is_for(market_type, market_index))
+            .map_or(0, |market_max_leverage| market_max_leverage.max_leverage)
+    }
+
+    pub fn update_market_max_leverage(
+        &mut self,
+        market_type: MarketType,
+        market_index: u16,
+        max_leverage: u8,
+    ) -> DriftResult {
+        let entry_index = self
+            .market_max_leverages
+            .iter()
+            .position(|market_max_leverage| market_max_leverage.is_for(market_type, market_index));
+
+        let entry_index = match entry_index {
+            Some(entry_index) => entry_index,
+            None if max_leverage == 0 => return Ok(()),
+            None => self
+                .market_max_leverages
+                .iter()
+                .position(|market_max_leverage| market_max_leverage.is_available())
+                .ok_or_else(|| {
+                    msg!(
+                        "cant cap leverage in more than {} markets",
+                        self.market_max_leverages.len()
+                    );
+                    ErrorCode::MaxNumberOfPositions
+                })?,
+        };
+
+        self.market_max_leverages[entry_index] = if max_leverage == 0 {
+            MarketMaxLeverage::default()
+        } else {
+            MarketMaxLeverage {
+                market_index,
+                market_type,
+                max_leverage,
+            }
+        };
+
+        Ok(())
+    }
+
     pub fn add_user_status(&mut self, status: UserStatus) {
         self.status |= status as u8;
     }
@@ -240,6 +305,71 @@ impl User {
         Ok(&mut self.perp_positions[position_index])
     }
 
+    pub fn get_isolated_perp_position(
+        &self,
+        perp_market_index: u16,
+    ) -> Option<&IsolatedPerpPosition> {
+        self.isolated_perp_positions
+            .iter()
+            .find(|isolated_position| isolated_position.is_for(perp_market_index))
+    }
+
+    pub fn get_isolated_perp_position_mut(
+        &mut self,
+        perp_market_index: u16,
+    ) -> DriftResult<&mut IsolatedPerpPosition> {
+        self.isolated_perp_positions
+            .iter_mut()
+            .find(|isolated_position| isolated_position.is_for(perp_market_index))
+            .ok_or(ErrorCode::InvalidIsolatedPerpPosition)
+    }
+
+    pub fn add_isolated_perp_position(
+        &mut self,
+        perp_market_index: u16,
+    ) -> DriftResult<&mut IsolatedPerpPosition> {
+        let isolated_position = self
+            .isolated_perp_positions
+            .iter_mut()
+            .find(|isolated_position| isolated_position.is_available())
+            .ok_or(ErrorCode::MaxNumberOfPositions)?;
+
+        *isolated_position = IsolatedPerpPosition {
+            perp_market_index,
+            status: IsolatedPerpPositionStatus::Active as u8,
+            ..IsolatedPerpPosition::default()
+        };
+
+        Ok(isolated_position)
+    }
+
+    pub fn is_perp_position_isolated(&self, perp_market_index: u16) -> bool {
+        self.get_isolated_perp_position(perp_market_index).is_some()
+    }
+
+    /// The margin the perp position in the market is evaluated with
+    pub fn get_perp_margin_scope(&self, perp_market_index: u16) -> MarginScope {
+        if self.is_perp_position_isolated(perp_market_index) {
+            MarginScope::IsolatedPerp {
+                market_index: perp_market_index,
+            }
+        } else {
+            MarginScope::Cross
+        }
+    }
+
+    /// Cross margin followed by each isolated perp position
+    pub fn get_margin_scopes(&self) -> impl Iterator<Item = MarginScope> + '_ {
+        std::iter::once(MarginScope::Cross).chain(
+            self.isolated_perp_positions
+                .iter()
+                .filter(|isolated_position| isolated_position.is_active())
+                .map(|isolated_position| MarginScope::IsolatedPerp {
+                    market_index: isolated_position.perp_market_index,
+                }),
+        )
+    }
+
     pub fn get_order_index(&self, order_id: u32) -> DriftResult<usize> {
         self.orders
             .iter()
@@ -260,6 +390,20 @@ impl User {
         self.orders.iter().find(|order| order.order_id == order_id)
     }
 
+    /// Bracket legs can not be triggered or filled until the parent entry order has started filling
+    pub fn has_unfilled_bracket_parent(&self, order: &Order) -> bool {
+        if order.group_id == 0 || order.is_bracket_parent() {
+            return false;
+        }
+
+        self.orders.iter().any(|parent| {
+            parent.status == OrderStatus::Open
+                && parent.is_bracket_parent()
+                && parent.is_linked_to(order)
+                && parent.base_asset_amount_filled == 0
+        })
+    }
+
     pub fn get_last_order_id(&self) -> u32 {
         if self.next_order_id == 1 {
             u32::MAX
@@ -321,6 +465,7 @@ impl User {
             return self.next_liquidation_id.safe_sub(1);
         }
 
+        self.remove_user_status(UserStatus::MarginCall);
         self.add_user_status(UserStatus::BeingLiquidated);
         self.liquidation_margin_freed = 0;
         self.last_active_slot = slot;
@@ -333,6 +478,57 @@ impl User {
         self.liquidation_margin_freed = 0;
     }
 
+    pub fn is_margin_scope_being_liquidated(&self, margin_scope: MarginScope) -> bool {
+        match margin_scope {
+            MarginScope::Cross => self.is_being_liquidated(),
+            MarginScope::IsolatedPerp { market_index } => self
+                .get_isolated_perp_position(market_index)
+                .map_or(false, |isolated_position| {
+                    isolated_position.is_being_liquidated()
+                }),
+        }
+    }
+
+    /// Isolated perp positions are liquidated without changing the user's status, so the rest of
+    /// the account keeps trading
+    pub fn enter_margin_scope_liquidation(
+        &mut self,
+        margin_scope: MarginScope,
+        slot: u64,
+    ) -> DriftResult<u16> {
+        let market_index = match margin_scope {
+            MarginScope::Cross => return self.enter_liquidation(slot),
+            MarginScope::IsolatedPerp { market_index } => market_index,
+        };
+
+        let is_being_liquidated = self.is_being_liquidated();
+        let isolated_position = self.get_isolated_perp_position_mut(market_index)?;
+        if isolated_position.is_being_liquidated() {
+            return self.next_liquidation_id.safe_sub(1);
+        }
+
+        isolated_position.status |= IsolatedPerpPositionStatus::BeingLiquidated as u8;
+
+        if !is_being_liquidated {
+            self.liquidation_margin_freed = 0;
+            self.last_active_slot = slot;
+        }
+
+        Ok(get_then_update_id!(self, next_liquidation_id))
+    }
+
+    pub fn exit_margin_scope_liquidation(&mut self, margin_scope: MarginScope) -> DriftResult {
+        match margin_scope {
+            MarginScope::Cross => self.exit_liquidation(),
+            MarginScope::IsolatedPerp { market_index } => {
+                self.get_isolated_perp_position_mut(market_index)?.status &=
+                    !(IsolatedPerpPositionStatus::BeingLiquidated as u8);
+            }
+        }
+
+        Ok(())
+    }
+
     pub fn enter_bankruptcy(&mut self) {
         self.remove_user_status(UserStatus::BeingLiquidated);
         self.add_user_status(UserStatus::Bankrupt);
@@ -344,13 +540,27 @@ impl User {
         self.liquidation_margin_freed = 0;
     }
 
+    pub fn enter_margin_call(&mut self, slot: u64) {
+        if self.is_in_margin_call() {
+            return;
+        }
+
+        self.add_user_status(UserStatus::MarginCall);
+        self.last_active_slot = slot;
+    }
+
+    pub fn exit_margin_call(&mut self) {
+        self.remove_user_status(UserStatus::MarginCall);
+    }
+
     pub fn increment_margin_freed(&mut self, margin_free: u64) -> DriftResult {
         self.liquidation_margin_freed = self.liquidation_margin_freed.safe_add(margin_free)?;
         Ok(())
     }
 
     pub fn update_last_active_slot(&mut self, slot: u64) {
-        if !self.is_being_liquidated() {
+        // frozen while liquidated or margin called, measures how long the user has been in that state
+        if !self.is_being_liquidated() && !self.is_in_margin_call() {
             self.last_active_slot = slot;
         }
         self.idle = false;
@@ -1004,6 +1214,115 @@ impl PerpPosition {
 
 pub type PerpPositions = [PerpPosition; 8];
 
+#[derive(Clone, Copy, BorshSerialize, BorshDeserialize, PartialEq, Debug, Eq)]
+pub enum IsolatedPerpPositionStatus {
+    // Inactive = 0
+    Active = 0b00000001,
+    BeingLiquidated = 0b00000010,
+}
+
+#[zero_copy(unsafe)]
+#[derive(Default, Eq, PartialEq, Debug)]
+#[repr(C)]
+pub struct IsolatedPerpPosition {
+    /// The quote deposit backing the perp position. Only the position's pnl is settled against it
+    /// precision: SPOT_BALANCE_PRECISION
+    pub scaled_balance: u64,
+    /// The perp market whose position is margined in isolation
+    pub perp_market_index: u16,
+    /// Bit flags from IsolatedPerpPositionStatus
+    pub status: u8,
+    pub padding: [u8; 5],
+}
+
+impl IsolatedPerpPosition {
+    pub fn is_for(&self, perp_market_index: u16) -> bool {
+        self.is_active() && self.perp_market_index == perp_market_index
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.status & (IsolatedPerpPositionStatus::Active as u8) > 0
+    }
+
+    pub fn is_available(&self) -> bool {
+        !self.is_active()
+    }
+
+    pub fn is_being_liquidated(&self) -> bool {
+        self.status & (IsolatedPerpPositionStatus::BeingLiquidated as u8) > 0
+    }
+
+    pub fn get_token_amount(&self, quote_spot_market: &SpotMarket) -> DriftResult<u128> {
+        get_token_amount(
+            self.scaled_balance.cast()?,
+            quote_spot_market,
+            &SpotBalanceType::Deposit,
+        )
+    }
+
+    /// The deposit viewed as a quote spot position, for logic written against spot positions
+    pub fn get_quote_spot_position(&self) -> SpotPosition {
+        SpotPosition {
+            scaled_balance: self.scaled_balance,
+            market_index: QUOTE_SPOT_MARKET_INDEX,
+            balance_type: SpotBalanceType::Deposit,
+            ..SpotPosition::default()
+        }
+    }
+}
+
+impl SpotBalance for IsolatedPerpPosition {
+    fn market_index(&self) -> u16 {
+        QUOTE_SPOT_MARKET_INDEX
+    }
+
+    fn balance_type(&self) -> &SpotBalanceType {
+        &SpotBalanceType::Deposit
+    }
+
+    fn balance(&self) -> u128 {
+        self.scaled_balance as u128
+    }
+
+    fn increase_balance(&mut self, delta: u128) -> DriftResult {
+        self.scaled_balance = self.scaled_balance.safe_add(delta.cast()?)?;
+        Ok(())
+    }
+
+    fn decrease_balance(&mut self, delta: u128) -> DriftResult {
+        self.scaled_balance = self.scaled_balance.safe_sub(delta.cast()?)?;
+        Ok(())
+    }
+
+    fn update_balance_type(&mut self, _balance_type: SpotBalanceType) -> DriftResult {
+        msg!("isolated perp position deposit cant become a borrow");
+        Err(ErrorCode::InvalidIsolatedPerpPosition)
+    }
+}
+
+#[zero_copy(unsafe)]
+#[derive(Default, Eq, PartialEq, Debug)]
+#[repr(C)]
+pub struct MarketMaxLeverage {
+    /// The market the cap applies to
+    pub market_index: u16,
+    pub market_type: MarketType,
+    /// Max leverage the authority allows in the market, e.g. 3 for 3x. 0 means the entry is unused
+    pub max_leverage: u8,
+}
+
+impl MarketMaxLeverage {
+    pub fn is_for(&self, market_type: MarketType, market_index: u16) -> bool {
+        self.max_leverage != 0
+            && self.market_type == market_type
+            && self.market_index == market_index
+    }
+
+    pub fn is_available(&self) -> bool {
+        self.max_leverage == 0
+    }
+}
+
 #[zero_copy(unsafe)]
 #[repr(C)]
 #[derive(AnchorSerialize, AnchorDeserialize, PartialEq, Debug, Eq)]
@@ -1029,6 +1348,7 @@ pub struct Order {
     /// precision: PRICE_PRECISION
     pub trigger_price: u64,
     /// The start price for the auction. Only relevant for market/oracle orders
+    /// For twap orders, the unix ts the twap started
     /// precision: PRICE_PRECISION
     pub auction_start_price: i64,
     /// The end price for the auction. Only relevant for market/oracle orders
@@ -1037,7 +1357,8 @@ pub struct Order {
     /// The time when the order will expire
     pub max_ts: i64,
     /// If set, the order limit price is the oracle price + this offset
-    /// precision: PRICE_PRECISION
+    /// For twap orders, the max slippage of each slice from the oracle price
+    /// precision: PRICE_PRECISION (PERCENTAGE_PRECISION for twap orders)
     pub oracle_price_offset: i32,
     /// The id for the order. Each users has their own order id space
     pub order_id: u32,
@@ -1065,7 +1386,13 @@ pub struct Order {
     pub trigger_condition: OrderTriggerCondition,
     /// How many slots the auction lasts
     pub auction_duration: u8,
-    pub padding: [u8; 3],
+    /// Bit flags for additional order behavior. See OrderBitFlag
+    pub bit_flags: u8,
+    /// Links orders in the same market. When a non parent order in the group fills or triggers,
+    /// the other open orders in the group are canceled. 0 means the order is not linked
+    pub group_id: u8,
+    /// Number of equal slices a twap order is split into between its start ts and max_ts
+    pub twap_slices: u8,
 }
 
 #[derive(Clone, Copy, BorshSerialize, BorshDeserialize, PartialEq, Eq, Debug)]
@@ -1083,6 +1410,24 @@ impl Order {
         self.oracle_price_offset != 0
     }
 
+    /// The worst price a twap slice can fill at: the oracle (reference) price moved against the
+    /// order by the max slippage
+    pub fn get_twap_limit_price(&self, oracle_price: i64, tick_size: u64) -> DriftResult<u64> {
+        let oracle_price = oracle_price.unsigned_abs();
+        let slippage = oracle_price
+            .cast::<u128>()?
+            .safe_mul(self.oracle_price_offset.unsigned_abs().cast()?)?
+            .safe_div(PERCENTAGE_PRECISION)?
+            .cast::<u64>()?;
+
+        let limit_price = match self.direction {
+            PositionDirection::Long => oracle_price.safe_add(slippage)?,
+            PositionDirection::Short => oracle_price.safe_sub(slippage)?,
+        };
+
+        standardize_price(limit_price, tick_size, self.direction)
+    }
+
     pub fn get_limit_price(
         &self,
         valid_oracle_price: Option<i64>,
@@ -1097,6 +1442,13 @@ impl Order {
                 tick_size,
                 valid_oracle_price,
             )?)
+        } else if self.is_twap() {
+            let oracle_price = valid_oracle_price.ok_or_else(|| {
+                msg!("Could not find oracle too calculate twap slice limit price");
+                ErrorCode::OracleNotFound
+            })?;
+
+            Some(self.get_twap_limit_price(oracle_price, tick_size)?)
         } else if self.has_oracle_price_offset() {
             let oracle_price = valid_oracle_price.ok_or_else(|| {
                 msg!("Could not find oracle too calculate oracle offset limit price");
@@ -1234,10 +1586,105 @@ impl Order {
     pub fn must_be_triggered(&self) -> bool {
         matches!(
             self.order_type,
-            OrderType::TriggerMarket | OrderType::TriggerLimit
+            OrderType::TriggerMarket | OrderType::TriggerLimit | OrderType::TrailingStop
         )
     }
 
+    pub fn is_trailing_stop(&self) -> bool {
+        self.order_type == OrderType::TrailingStop
+    }
+
+    pub fn is_twap(&self) -> bool {
+        self.order_type == OrderType::Twap
+    }
+
+    /// A twap runs from its start ts (stored in auction_start_price) until max_ts. The first slice is
+    /// fillable immediately, each following slice once its share of the twap duration has elapsed
+    pub fn get_twap_base_asset_amount_released(
+        &self,
+        now: i64,
+        step_size: u64,
+    ) -> DriftResult<u64> {
+        let twap_slices = self.twap_slices.cast::<u64>()?;
+        let start_ts = self.get_twap_start_ts();
+
+        let slices_released = if now >= self.max_ts {
+            twap_slices
+        } else {
+            let duration = self.max_ts.safe_sub(start_ts)?.max(1).cast::<u64>()?;
+            let elapsed = now.safe_sub(start_ts)?.max(0).cast::<u64>()?;
+            elapsed
+                .safe_mul(twap_slices)?
+                .safe_div(duration)?
+                .safe_add(1)?
+                .min(twap_slices)
+        };
+
+        if slices_released == twap_slices {
+            return Ok(self.base_asset_amount);
+        }
+
+        standardize_base_asset_amount(
+            self.base_asset_amount
+                .cast::<u128>()?
+                .safe_mul(slices_released.cast()?)?
+                .safe_div(twap_slices.cast()?)?
+                .cast()?,
+            step_size,
+        )
+    }
+
+    /// Twap orders have no auction, so auction_start_price holds the unix ts the twap started
+    pub fn get_twap_start_ts(&self) -> i64 {
+        self.auction_start_price
+    }
+
+    /// The amount of the twap order that can be filled now. Equal to base_asset_amount_unfilled for other orders
+    pub fn get_base_asset_amount_fillable(&self, now: i64, step_size: u64) -> DriftResult<u64> {
+        let base_asset_amount_unfilled = self.get_base_asset_amount_unfilled(None)?;
+
+        if !self.is_twap() {
+            return Ok(base_asset_amount_unfilled);
+        }
+
+        Ok(self
+            .get_twap_base_asset_amount_released(now, step_size)?
+            .saturating_sub(self.base_asset_amount_filled)
+            .min(base_asset_amount_unfilled))
+    }
+
+    pub fn is_bit_flag_set(&self, bit_flag: OrderBitFlag) -> bool {
+        self.bit_flags & (bit_flag as u8) > 0
+    }
+
+    pub fn is_bracket_parent(&self) -> bool {
+        self.is_bit_flag_set(OrderBitFlag::BracketParent)
+    }
+
+    /// The taker order's mode decides how it interacts with makers of the same authority
+    pub fn get_self_trade_prevention_mode(&self) -> SelfTradePreventionMode {
+        if self.is_bit_flag_set(OrderBitFlag::StpExpireMaker) {
+            SelfTradePreventionMode::ExpireMaker
+        } else if self.is_bit_flag_set(OrderBitFlag::StpExpireTaker) {
+            SelfTradePreventionMode::ExpireTaker
+        } else if self.is_bit_flag_set(OrderBitFlag::StpDecrementBoth) {
+            SelfTradePreventionMode::DecrementBoth
+        } else {
+            SelfTradePreventionMode::None
+        }
+    }
+
+    pub fn is_position_linked(&self) -> bool {
+        self.is_bit_flag_set(OrderBitFlag::PositionLinked)
+    }
+
+    pub fn is_linked_to(&self, order: &Order) -> bool {
+        self.group_id != 0
+            && self.group_id == order.group_id
+            && self.market_index == order.market_index
+            && self.market_type == order.market_type
+    }
+
     pub fn triggered(&self) -> bool {
         matches!(
             self.trigger_condition,
@@ -1267,7 +1714,10 @@ impl Order {
     pub fn is_market_order(&self) -> bool {
         matches!(
             self.order_type,
-            OrderType::Market | OrderType::TriggerMarket | OrderType::Oracle
+            OrderType::Market
+                | OrderType::TriggerMarket
+                | OrderType::TrailingStop
+                | OrderType::Oracle
         )
     }
 
@@ -1322,7 +1772,9 @@ impl Default for Order {
             auction_end_price: 0,
             auction_duration: 0,
             max_ts: 0,
-            padding: [0; 3],
+            bit_flags: 0,
+            group_id: 0,
+            twap_slices: 0,
         }
     }
 }
@@ -1347,6 +1799,12 @@ pub enum OrderType {
     TriggerLimit,
     /// Market order where the auction prices are oracle offsets
     Oracle,
+    /// Trigger market order whose trigger price trails the oracle price by oracle_price_offset
+    /// The trigger price only moves in the favorable direction
+    TrailingStop,
+    /// Perp order that releases base_asset_amount in equal slices until max_ts
+    /// The limit price is the oracle price + oracle_price_offset
+    Twap,
 }
 
 impl Default for OrderType {
@@ -1369,6 +1827,32 @@ impl Default for OrderTriggerCondition {
     }
 }
 
+#[derive(Clone, Copy, BorshSerialize, BorshDeserialize, PartialEq, Debug, Eq)]
+pub enum OrderBitFlag {
+    /// The trailing stop offset is a percentage of the oracle price (PERCENTAGE_PRECISION)
+    /// instead of a fixed price offset (PRICE_PRECISION)
+    TrailingStopPercentage = 0b00000001,
+    /// Entry order of a bracket. Its fills do not cancel the other orders in its group
+    BracketParent = 0b00000010,
+    /// Self trade prevention: cancel the resting order of the same authority
+    StpExpireMaker = 0b00000100,
+    /// Self trade prevention: cancel the taking order
+    StpExpireTaker = 0b00001000,
+    /// Self trade prevention: reduce both orders by the overlapping size without trading
+    StpDecrementBoth = 0b00010000,
+    /// Take profit / stop loss attached to the perp position. Sized to the whole position when
+    /// triggered and canceled once the position is closed
+    PositionLinked = 0b00100000,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug, Eq)]
+pub enum SelfTradePreventionMode {
+    None,
+    ExpireMaker,
+    ExpireTaker,
+    DecrementBoth,
+}
+
 #[derive(Clone, Copy, BorshSerialize, BorshDeserialize, PartialEq, Debug, Eq)]
 pub enum MarketType {
     Spot,
@@ -1431,7 +1915,10 @@ pub struct UserStats {
     /// Whether the user is a referrer. Sub account 0 can not be deleted if user is a referrer
     pub is_referrer: bool,
     pub disable_update_perp_bid_ask_twap: bool,
-    pub padding: [u8; 50],
+    /// Whether sub accounts share collateral. A sub account about to be liquidated is first topped up with
+    /// free quote collateral from the authority's other sub accounts
+    pub is_sub_account_cross_margin_enabled: bool,
+    pub padding: [u8; 49],
 }
 
 impl Default for UserStats {
@@ -1452,7 +1939,8 @@ impl Default for UserStats {
             number_of_sub_accounts_created: 0,
             is_referrer: false,
             disable_update_perp_bid_ask_twap: false,
-            padding: [0; 50],
+            is_sub_account_cross_margin_enabled: false,
+            padding: [0; 49],
         }
     }
 }
@@ -1785,6 +1785,32 @@ mod update_user_status {
         assert!(!user.is_bankrupt());
         assert!(user.status & UserStatus::ReduceOnly as u8 > 0);
     }
+
+    #[test]
+    fn margin_call() {
+        let mut user = User::default();
+
+        user.enter_margin_call(10);
+        assert_eq!(user.status, UserStatus::MarginCall as u8);
+        assert!(user.is_in_margin_call());
+        assert_eq!(user.last_active_slot, 10);
+
+        // grace period start is frozen while in margin call
+        user.update_last_active_slot(20);
+        user.enter_margin_call(30);
+        assert_eq!(user.last_active_slot, 10);
+
+        user.exit_margin_call();
+        assert!(!user.is_in_margin_call());
+        user.update_last_active_slot(40);
+        assert_eq!(user.last_active_slot, 40);
+
+        // liquidation takes over from margin call
+        user.enter_margin_call(50);
+        user.enter_liquidation(60).unwrap();
+        assert!(!user.is_in_margin_call());
+        assert!(user.is_being_liquidated());
+    }
 }
 
 mod resting_limit_order {
@@ -1940,3 +1966,262 @@ mod get_user_stats_age_ts {
         assert_eq!(age, 0);
     }
 }
+
+mod has_unfilled_bracket_parent {
+    use crate::state::user::{MarketType, Order, OrderBitFlag, OrderStatus, User};
+    use crate::test_utils::get_orders;
+
+    #[test]
+    fn test() {
+        let parent = Order {
+            status: OrderStatus::Open,
+            market_type: MarketType::Perp,
+            order_id: 1,
+            group_id: 1,
+            bit_flags: OrderBitFlag::BracketParent as u8,
+            ..Order::default()
+        };
+
+        let leg = Order {
+            status: OrderStatus::Open,
+            market_type: MarketType::Perp,
+            order_id: 2,
+            group_id: 1,
+            ..Order::default()
+        };
+
+        let mut user = User {
+            orders: get_orders(parent),
+            ..User::default()
+        };
+        user.orders[1] = leg;
+
+        assert!(user.has_unfilled_bracket_parent(&leg));
+        assert!(!user.has_unfilled_bracket_parent(&parent));
+
+        // leg can trigger once parent starts filling
+        user.orders[0].base_asset_amount_filled = 1;
+        assert!(!user.has_unfilled_bracket_parent(&leg));
+
+        // different group
+        user.orders[0].base_asset_amount_filled = 0;
+        let unlinked = Order { group_id: 2, ..leg };
+        assert!(!user.has_unfilled_bracket_parent(&unlinked));
+    }
+}
+
+mod get_base_asset_amount_fillable {
+    use crate::math::constants::BASE_PRECISION_U64;
+    use crate::state::user::{Order, OrderType};
+
+    #[test]
+    fn twap() {
+        // started at ts 100, runs until ts 200
+        let order = Order {
+            order_type: OrderType::Twap,
+            base_asset_amount: 10 * BASE_PRECISION_U64,
+            auction_start_price: 100,
+            max_ts: 200,
+            twap_slices: 4,
+            ..Order::default()
+        };
+        let step_size = BASE_PRECISION_U64 / 10;
+
+        // first slice is released immediately
+        let fillable = order
+            .get_base_asset_amount_fillable(100, step_size)
+            .unwrap();
+        assert_eq!(fillable, 2500000000);
+
+        let fillable = order
+            .get_base_asset_amount_fillable(124, step_size)
+            .unwrap();
+        assert_eq!(fillable, 2500000000);
+
+        let fillable = order
+            .get_base_asset_amount_fillable(125, step_size)
+            .unwrap();
+        assert_eq!(fillable, 5 * BASE_PRECISION_U64);
+
+        // last slice released
+        let fillable = order
+            .get_base_asset_amount_fillable(175, step_size)
+            .unwrap();
+        assert_eq!(fillable, 10 * BASE_PRECISION_U64);
+
+        let order = Order {
+            base_asset_amount_filled: 2500000000,
+            ..order
+        };
+
+        let fillable = order
+            .get_base_asset_amount_fillable(110, step_size)
+            .unwrap();
+        assert_eq!(fillable, 0);
+
+        let fillable = order
+            .get_base_asset_amount_fillable(150, step_size)
+            .unwrap();
+        assert_eq!(fillable, 5 * BASE_PRECISION_U64);
+
+        // everything is released once max_ts passes
+        let fillable = order
+            .get_base_asset_amount_fillable(300, step_size)
+            .unwrap();
+        assert_eq!(fillable, 7500000000);
+    }
+
+    #[test]
+    fn not_twap() {
+        let order = Order {
+            order_type: OrderType::Limit,
+            base_asset_amount: 10 * BASE_PRECISION_U64,
+            base_asset_amount_filled: BASE_PRECISION_U64,
+            max_ts: 200,
+            ..Order::default()
+        };
+
+        let fillable = order
+            .get_base_asset_amount_fillable(100, BASE_PRECISION_U64 / 10)
+            .unwrap();
+        assert_eq!(fillable, 9 * BASE_PRECISION_U64);
+    }
+}
+
+mod get_twap_limit_price {
+    use crate::controller::position::PositionDirection;
+    use crate::math::constants::{PRICE_PRECISION_I64, PRICE_PRECISION_U64};
+    use crate::state::user::{Order, OrderType};
+
+    #[test]
+    fn test() {
+        // 1% max slippage
+        let order = Order {
+            order_type: OrderType::Twap,
+            direction: PositionDirection::Long,
+            oracle_price_offset: 10000,
+            ..Order::default()
+        };
+        let tick_size = PRICE_PRECISION_U64 / 100;
+
+        let limit_price = order
+            .get_limit_price(Some(100 * PRICE_PRECISION_I64), None, 0, tick_size)
+            .unwrap();
+        assert_eq!(limit_price, Some(101 * PRICE_PRECISION_U64));
+
+        let order = Order {
+            direction: PositionDirection::Short,
+            ..order
+        };
+
+        let limit_price = order
+            .get_limit_price(Some(100 * PRICE_PRECISION_I64), None, 0, tick_size)
+            .unwrap();
+        assert_eq!(limit_price, Some(99 * PRICE_PRECISION_U64));
+    }
+}
+
+mod can_cancel_all_orders_after_ts {
+    use crate::state::user::User;
+
+    #[test]
+    fn test() {
+        let mut user = User::default();
+        assert!(!user.can_cancel_all_orders_after_ts(100));
+
+        user.cancel_all_after_ts = 100;
+        assert!(!user.can_cancel_all_orders_after_ts(99));
+        assert!(user.can_cancel_all_orders_after_ts(100));
+        assert!(user.can_cancel_all_orders_after_ts(101));
+    }
+}
+
+mod update_market_max_leverage {
+    use crate::controller::position::add_new_position;
+    use crate::error::ErrorCode;
+    use crate::state::spot_market::SpotBalanceType;
+    use crate::state::user::{MarketType, PerpPosition, SpotPosition, User};
+
+    #[test]
+    fn perp_cap_follows_market_not_slot() {
+        let mut user = User::default();
+
+        user.update_market_max_leverage(MarketType::Perp, 1, 3)
+            .unwrap();
+
+        let position_index = add_new_position(&mut user.perp_positions, 1).unwrap();
+        assert_eq!(position_index, 0);
+
+        // close the position, another market takes the slot
+        user.perp_positions[position_index] = PerpPosition::default();
+        let position_index = add_new_position(&mut user.perp_positions, 3).unwrap();
+        assert_eq!(position_index, 0);
+
+        assert_eq!(user.get_market_max_leverage(MarketType::Perp, 3), 0);
+        assert_eq!(user.get_market_max_leverage(MarketType::Perp, 1), 3);
+
+        // reopening the capped market in a different slot keeps the cap
+        let position_index = add_new_position(&mut user.perp_positions, 1).unwrap();
+        assert_eq!(position_index, 1);
+        assert_eq!(user.get_market_max_leverage(MarketType::Perp, 1), 3);
+
+        // same index in the other market type isnt capped
+        assert_eq!(user.get_market_max_leverage(MarketType::Spot, 1), 0);
+    }
+
+    #[test]
+    fn spot_cap_follows_market_not_slot() {
+        let mut user = User::default();
+
+        user.update_market_max_leverage(MarketType::Spot, 1, 3)
+            .unwrap();
+
+        let position_index = user.add_spot_position(1, SpotBalanceType::Deposit).unwrap();
+        assert_eq!(position_index, 1);
+
+        user.spot_positions[position_index] = SpotPosition::default();
+        let position_index = user.add_spot_position(2, SpotBalanceType::Deposit).unwrap();
+        assert_eq!(position_index, 1);
+
+        assert_eq!(user.get_market_max_leverage(MarketType::Spot, 2), 0);
+        assert_eq!(user.get_market_max_leverage(MarketType::Spot, 1), 3);
+    }
+
+    #[test]
+    fn update_and_remove_cap() {
+        let mut user = User::default();
+
+        user.update_market_max_leverage(MarketType::Perp, 1, 3)
+            .unwrap();
+        user.update_market_max_leverage(MarketType::Perp, 1, 5)
+            .unwrap();
+        assert_eq!(user.get_market_max_leverage(MarketType::Perp, 1), 5);
+        assert_eq!(
+            user.market_max_leverages
+                .iter()
+                .filter(|market_max_leverage| !market_max_leverage.is_available())
+                .count(),
+            1
+        );
+
+        user.update_market_max_leverage(MarketType::Perp, 1, 0)
+            .unwrap();
+        assert_eq!(user.get_market_max_leverage(MarketType::Perp, 1), 0);
+        assert!(user
+            .market_max_leverages
+            .iter()
+            .all(|market_max_leverage| market_max_leverage.is_available()));
+
+        for market_index in 0..8 {
+            user.update_market_max_leverage(MarketType::Perp, market_index, 2)
+                .unwrap();
+        }
+
+        let result = user.update_market_max_leverage(MarketType::Perp, 8, 2);
+        assert_eq!(result, Err(ErrorCode::MaxNumberOfPositions));
+
+        // removing a cap that was never set is a no op
+        user.update_market_max_leverage(MarketType::Perp, 8, 0)
+            .unwrap();
+    }
+}
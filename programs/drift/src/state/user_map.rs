@@ -273,6 +273,46 @@ impl<'a> UserStatsMap<'a> {
     }
 }
 
+pub fn load_user_map<'a>(
+    account_info_iter: &mut Peekable<Iter<AccountInfo<'a>>>,
+    must_be_writable: bool,
+) -> DriftResult<UserMap<'a>> {
+    let mut user_map = UserMap::empty();
+
+    let user_discriminator: [u8; 8] = User::discriminator();
+    while let Some(user_account_info) = account_info_iter.peek() {
+        let user_key = user_account_info.key;
+
+        let data = user_account_info
+            .try_borrow_data()
+            .or(Err(ErrorCode::CouldNotLoadUserData))?;
+
+        let expected_data_len = User::SIZE;
+        if data.len() < expected_data_len {
+            break;
+        }
+
+        let account_discriminator = array_ref![data, 0, 8];
+        if account_discriminator != &user_discriminator {
+            break;
+        }
+
+        let user_account_info = account_info_iter.next().safe_unwrap()?;
+
+        let is_writable = user_account_info.is_writable;
+        if !is_writable && must_be_writable {
+            return Err(ErrorCode::UserWrongMutability);
+        }
+
+        let user_account_loader: AccountLoader<User> =
+            AccountLoader::try_from(user_account_info).or(Err(ErrorCode::InvalidUserAccount))?;
+
+        user_map.insert(*user_key, user_account_loader)?;
+    }
+
+    Ok(user_map)
+}
+
 pub fn load_user_maps<'a>(
     account_info_iter: &mut Peekable<Iter<AccountInfo<'a>>>,
     must_be_writable: bool,
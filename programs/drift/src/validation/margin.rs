@@ -1,8 +1,10 @@
 use crate::error::{DriftResult, ErrorCode};
+use crate::math::casting::Cast;
 use crate::math::constants::{
-    LIQUIDATION_FEE_TO_MARGIN_PRECISION_RATIO, MAX_MARGIN_RATIO, MIN_MARGIN_RATIO,
-    SPOT_IMF_PRECISION, SPOT_WEIGHT_PRECISION,
+    LIQUIDATION_FEE_TO_MARGIN_PRECISION_RATIO, MARGIN_PRECISION, MAX_MARGIN_RATIO,
+    MIN_MARGIN_RATIO, SPOT_IMF_PRECISION, SPOT_WEIGHT_PRECISION,
 };
+use crate::math::safe_math::SafeMath;
 use crate::validate;
 use solana_program::msg;
 
@@ -39,6 +41,37 @@ pub fn validate_margin(
     Ok(())
 }
 
+pub fn validate_margin_ratio_volatility_scaling(
+    margin_ratio_initial: u32,
+    margin_ratio_maintenance: u32,
+    liquidation_fee: u32,
+    max_spread: u32,
+    scale_min: u16,
+    scale_max: u16,
+) -> DriftResult {
+    validate!(
+        scale_min > 0 && scale_min <= scale_max,
+        ErrorCode::InvalidMarginRatio,
+        "invalid volatility scale bounds min={} max={}",
+        scale_min,
+        scale_max
+    )?;
+
+    // only the initial margin ratio is scaled
+    for scale in [scale_min, scale_max] {
+        validate_margin(
+            margin_ratio_initial
+                .safe_mul(scale.cast()?)?
+                .safe_div(MARGIN_PRECISION)?,
+            margin_ratio_maintenance,
+            liquidation_fee,
+            max_spread,
+        )?;
+    }
+
+    Ok(())
+}
+
 pub fn validate_margin_weights(
     spot_market_index: u16,
     initial_asset_weight: u32,
@@ -4,11 +4,13 @@ use crate::controller::position::PositionDirection;
 use crate::error::{DriftResult, ErrorCode};
 
 use crate::math::casting::Cast;
+use crate::math::constants::PERCENTAGE_PRECISION_U64;
 use crate::math::orders::{
     calculate_base_asset_amount_to_fill_up_to_limit_price, is_multiple_of_step_size,
 };
+use crate::math::safe_math::SafeMath;
 use crate::state::perp_market::PerpMarket;
-use crate::state::user::{Order, OrderTriggerCondition, OrderType};
+use crate::state::user::{MarketType, Order, OrderBitFlag, OrderTriggerCondition, OrderType};
 use crate::validate;
 
 pub fn validate_order(
@@ -35,8 +37,18 @@ pub fn validate_order(
         OrderType::Oracle => {
             validate_oracle_order(order, market.amm.order_step_size, market.amm.min_order_size)?
         }
+        OrderType::TrailingStop => validate_trailing_stop_order(
+            order,
+            market.amm.order_step_size,
+            market.amm.min_order_size,
+        )?,
+        OrderType::Twap => {
+            validate_twap_order(order, market.amm.order_step_size, market.amm.min_order_size)?
+        }
     }
 
+    validate_order_bit_flags(order)?;
+
     Ok(())
 }
 
@@ -340,6 +352,157 @@ fn validate_trigger_market_order(
     Ok(())
 }
 
+fn validate_trailing_stop_order(order: &Order, step_size: u64, min_order_size: u64) -> DriftResult {
+    validate_base_asset_amount(order, step_size, min_order_size, order.reduce_only)?;
+
+    match (order.direction, order.trigger_condition) {
+        (PositionDirection::Long, OrderTriggerCondition::Above)
+        | (PositionDirection::Short, OrderTriggerCondition::Below) => {}
+        _ => {
+            msg!("Trailing stop must trigger Above for longs and Below for shorts");
+            return Err(ErrorCode::InvalidTriggerOrderCondition);
+        }
+    }
+
+    if order.price > 0 {
+        msg!("Trailing stop order should not have price");
+        return Err(ErrorCode::InvalidOrderLimitPrice);
+    }
+
+    if order.trigger_price == 0 {
+        msg!("Trailing stop order trigger_price == 0");
+        return Err(ErrorCode::InvalidOrderTrigger);
+    }
+
+    if order.post_only {
+        msg!("Trailing stop order can not be post only");
+        return Err(ErrorCode::InvalidOrderPostOnly);
+    }
+
+    if order.oracle_price_offset <= 0 {
+        msg!("Trailing stop order must have a positive oracle offset as the trailing distance");
+        return Err(ErrorCode::InvalidOrderOracleOffset);
+    }
+
+    if order.is_bit_flag_set(OrderBitFlag::TrailingStopPercentage) {
+        validate!(
+            order.oracle_price_offset.cast::<u64>()? < PERCENTAGE_PRECISION_U64,
+            ErrorCode::InvalidOrderOracleOffset,
+            "Trailing stop percentage ({}) must be less than 100%",
+            order.oracle_price_offset
+        )?;
+    }
+
+    Ok(())
+}
+
+fn validate_twap_order(order: &Order, step_size: u64, min_order_size: u64) -> DriftResult {
+    validate_base_asset_amount(order, step_size, min_order_size, order.reduce_only)?;
+
+    validate!(
+        order.twap_slices > 0,
+        ErrorCode::InvalidOrder,
+        "Twap order must have at least one slice"
+    )?;
+
+    validate!(
+        order
+            .base_asset_amount
+            .safe_div(order.twap_slices.cast()?)?
+            >= step_size,
+        ErrorCode::InvalidOrderSizeTooSmall,
+        "Twap slice size must be at least the step size ({})",
+        step_size
+    )?;
+
+    validate!(
+        order.max_ts != 0,
+        ErrorCode::InvalidOrderMaxTs,
+        "Twap order must have a max_ts"
+    )?;
+
+    if order.trigger_price > 0 {
+        msg!("Twap order should not have trigger price");
+        return Err(ErrorCode::InvalidOrder);
+    }
+
+    if order.price > 0 {
+        msg!("Twap order should not have price");
+        return Err(ErrorCode::InvalidOrderLimitPrice);
+    }
+
+    validate!(
+        order.oracle_price_offset > 0
+            && order.oracle_price_offset.cast::<u64>()? < PERCENTAGE_PRECISION_U64,
+        ErrorCode::InvalidOrderOracleOffset,
+        "Twap order max slippage must be between 0 and 100% ({})",
+        order.oracle_price_offset
+    )?;
+
+    if order.has_auction() {
+        msg!("Twap order can not have an auction");
+        return Err(ErrorCode::InvalidOrderAuction);
+    }
+
+    if order.post_only {
+        msg!("Twap order can not be post only");
+        return Err(ErrorCode::InvalidOrderPostOnly);
+    }
+
+    if order.immediate_or_cancel {
+        msg!("Twap order can not be immediate or cancel");
+        return Err(ErrorCode::InvalidOrderIOC);
+    }
+
+    Ok(())
+}
+
+fn validate_order_bit_flags(order: &Order) -> DriftResult {
+    validate!(
+        !order.is_bit_flag_set(OrderBitFlag::TrailingStopPercentage) || order.is_trailing_stop(),
+        ErrorCode::InvalidOrder,
+        "Only trailing stop orders can use a percentage offset"
+    )?;
+
+    validate!(
+        !order.is_bracket_parent() || order.group_id != 0,
+        ErrorCode::InvalidOrder,
+        "Bracket parent order must have a group id"
+    )?;
+
+    validate!(
+        order.twap_slices == 0 || order.is_twap(),
+        ErrorCode::InvalidOrder,
+        "Only twap orders can have twap slices"
+    )?;
+
+    let stp_flags = order.bit_flags
+        & (OrderBitFlag::StpExpireMaker as u8
+            | OrderBitFlag::StpExpireTaker as u8
+            | OrderBitFlag::StpDecrementBoth as u8);
+    validate!(
+        stp_flags.count_ones() <= 1,
+        ErrorCode::InvalidOrder,
+        "Only one self trade prevention mode can be set"
+    )?;
+
+    validate!(
+        stp_flags == 0 || order.market_type == MarketType::Perp,
+        ErrorCode::InvalidOrder,
+        "Self trade prevention is only supported for perp orders"
+    )?;
+
+    if order.is_position_linked() {
+        validate!(
+            order.market_type == MarketType::Perp && order.reduce_only && order.must_be_triggered(),
+            ErrorCode::InvalidOrder,
+            "Position linked orders must be reduce only perp trigger orders"
+        )?;
+    }
+
+    Ok(())
+}
+
 fn validate_base_asset_amount(
     order: &Order,
     step_size: u64,
@@ -436,8 +599,15 @@ pub fn validate_spot_order(order: &Order, step_size: u64, min_order_size: u64) -
         }
         OrderType::TriggerLimit => validate_trigger_limit_order(order, step_size, min_order_size)?,
         OrderType::Oracle => validate_oracle_order(order, step_size, min_order_size)?,
+        OrderType::TrailingStop => validate_trailing_stop_order(order, step_size, min_order_size)?,
+        OrderType::Twap => {
+            msg!("Twap orders are only supported for perp markets");
+            return Err(ErrorCode::InvalidOrder);
+        }
     }
 
+    validate_order_bit_flags(order)?;
+
     Ok(())
 }
 
@@ -46,6 +46,15 @@ pub fn validate_user_deletion(
         )?;
     }
 
+    for isolated_perp_position in &user.isolated_perp_positions {
+        validate!(
+            isolated_perp_position.is_available(),
+            ErrorCode::UserCantBeDeleted,
+            "user has isolated deposit for perp market {}",
+            isolated_perp_position.perp_market_index
+        )?;
+    }
+
     for order in &user.orders {
         validate!(
             order.status == OrderStatus::Init,
@@ -98,6 +107,12 @@ pub fn validate_user_is_idle(user: &User, slot: u64, accelerated: bool) -> Drift
         "user being liquidated"
     )?;
 
+    validate!(
+        !user.is_in_margin_call(),
+        ErrorCode::UserNotInactive,
+        "user in margin call"
+    )?;
+
     for perp_position in &user.perp_positions {
         validate!(
             perp_position.is_available(),
@@ -46,6 +46,12 @@ export enum UserStatus {
 	BANKRUPT = 2,
 	REDUCE_ONLY = 4,
 	ADVANCED_LP = 8,
+	MARGIN_CALL = 16,
+}
+
+export enum IsolatedPerpPositionStatus {
+	ACTIVE = 1,
+	BEING_LIQUIDATED = 2,
 }
 
 export class ContractType {
@@ -106,6 +112,8 @@ export class OrderType {
 	static readonly TRIGGER_LIMIT = { triggerLimit: {} };
 	static readonly MARKET = { market: {} };
 	static readonly ORACLE = { oracle: {} };
+	static readonly TRAILING_STOP = { trailingStop: {} };
+	static readonly TWAP = { twap: {} };
 }
 
 export declare type MarketTypeStr = 'perp' | 'spot';
@@ -180,6 +188,30 @@ export class OrderActionExplanation {
 	static readonly DERISK_LP = {
 		deriskLp: {},
 	};
+	static readonly LINKED_ORDER_EXECUTED = {
+		linkedOrderExecuted: {},
+	};
+	static readonly SELF_TRADE_PREVENTED = {
+		selfTradePrevented: {},
+	};
+	static readonly CANCEL_ALL_AFTER_TS = {
+		cancelAllAfterTs: {},
+	};
+	static readonly POSITION_CLOSED = {
+		positionClosed: {},
+	};
+	static readonly MARGIN_CALL = {
+		marginCall: {},
+	};
+}
+
+export enum OrderBitFlag {
+	TRAILING_STOP_PERCENTAGE = 1,
+	BRACKET_PARENT = 2,
+	STP_EXPIRE_MAKER = 4,
+	STP_EXPIRE_TAKER = 8,
+	STP_DECREMENT_BOTH = 16,
+	POSITION_LINKED = 32,
 }
 
 export class OrderTriggerCondition {
@@ -349,6 +381,18 @@ export declare type InsuranceFundStakeRecord = {
 	totalIfSharesAfter: BN;
 };
 
+export declare type BackstopVaultRecord = {
+	ts: BN;
+	userAuthority: PublicKey;
+	action: StakeAction;
+	amount: BN;
+	vaultEquityBefore: BN;
+	sharesBefore: BN;
+	totalSharesBefore: BN;
+	sharesAfter: BN;
+	totalSharesAfter: BN;
+};
+
 export type LPRecord = {
 	ts: BN;
 	user: PublicKey;
@@ -490,6 +534,20 @@ export type SpotBankruptcyRecord = {
 	ifPayment: BN;
 };
 
+export type AdlRecord = {
+	ts: BN;
+	liquidationId: number;
+	marketIndex: number;
+	bankruptUser: PublicKey;
+	user: PublicKey;
+	baseAssetAmount: BN;
+	quoteAssetAmount: BN;
+	bankruptcyPrice: BN;
+	oraclePrice: BN;
+	pnlHaircut: BN;
+	adlScore: BN;
+};
+
 export type SettlePnlRecord = {
 	ts: BN;
 	user: PublicKey;
@@ -576,6 +634,9 @@ export type StateAccount = {
 	initialPctToLiquidate: number;
 	liquidationDuration: number;
 	maxInitializeUserFee: number;
+	initialLiquidatorFeePct: number;
+	marginCallBufferRatio: number;
+	marginCallGracePeriod: number;
 };
 
 export type PerpMarketAccount = {
@@ -613,6 +674,11 @@ export type PerpMarketAccount = {
 	quoteSpotMarketIndex: number;
 	feeAdjustment: number;
 	pausedOperations: number;
+	hedgeSpotMarketIndex: number;
+	stressOracleShock: number;
+	marginRatioVolatilityReference: number;
+	marginRatioVolatilityScaleMin: number;
+	marginRatioVolatilityScaleMax: number;
 };
 
 export type HistoricalOracleData = {
@@ -684,6 +750,7 @@ export type SpotMarketAccount = {
 	liquidatorFee: number;
 	imfFactor: number;
 	scaleInitialAssetWeightStart: BN;
+	stressOracleShock: number;
 
 	withdrawGuardThreshold: BN;
 	depositTokenTwap: BN;
@@ -803,6 +870,7 @@ export type AMM = {
 	askQuoteAssetReserve: BN;
 
 	perLpBase: number; // i8
+	oracleAnchored: boolean;
 	netUnsettledFundingPnl: BN;
 	quoteAssetAmountWithUnsettledLp: BN;
 	referencePriceOffset: number;
@@ -846,6 +914,7 @@ export type UserStatsAccount = {
 	};
 	referrer: PublicKey;
 	isReferrer: boolean;
+	isSubAccountCrossMarginEnabled: boolean;
 	authority: PublicKey;
 	ifStakedQuoteAssetAmount: BN;
 };
@@ -877,6 +946,21 @@ export type UserAccount = {
 	hasOpenOrder: boolean;
 	openAuctions: number;
 	hasOpenAuction: boolean;
+	cancelAllAfterTs: BN;
+	marketMaxLeverages: MarketMaxLeverage[];
+	isolatedPerpPositions: IsolatedPerpPosition[];
+};
+
+export type MarketMaxLeverage = {
+	marketIndex: number;
+	marketType: MarketType;
+	maxLeverage: number;
+};
+
+export type IsolatedPerpPosition = {
+	scaledBalance: BN;
+	perpMarketIndex: number;
+	status: number;
 };
 
 export type SpotPosition = {
@@ -914,6 +998,9 @@ export type Order = {
 	auctionStartPrice: BN;
 	auctionEndPrice: BN;
 	maxTs: BN;
+	bitFlags: number;
+	groupId: number;
+	twapSlices: number;
 };
 
 export type OrderParams = {
@@ -934,6 +1021,12 @@ export type OrderParams = {
 	maxTs: BN | null;
 	auctionStartPrice: BN | null;
 	auctionEndPrice: BN | null;
+	bitFlags: number;
+	groupId: number;
+	twapSlices: number;
+	fillOrKill: boolean;
+	minFillBaseAssetAmount: BN | null;
+	quoteAssetAmount: BN | null;
 };
 
 export class PostOnlyParams {
@@ -958,6 +1051,11 @@ export type ModifyOrderParams = {
 	[Property in keyof OrderParams]?: OrderParams[Property] | null;
 } & { policy?: ModifyOrderPolicy };
 
+export type ModifyOrderByIdParams = {
+	orderId: number;
+	modifyOrderParams: ModifyOrderParams;
+};
+
 export class ModifyOrderPolicy {
 	static readonly MUST_MODIFY = { mustModify: {} };
 	static readonly TRY_MODIFY = { tryModify: {} };
@@ -981,6 +1079,12 @@ export const DefaultOrderParams: OrderParams = {
 	maxTs: null,
 	auctionStartPrice: null,
 	auctionEndPrice: null,
+	bitFlags: 0,
+	groupId: 0,
+	twapSlices: 0,
+	fillOrKill: false,
+	minFillBaseAssetAmount: null,
+	quoteAssetAmount: null,
 };
 
 export type MakerInfo = {
@@ -1099,6 +1203,24 @@ export type InsuranceFundStake = {
 	lastWithdrawRequestTs: BN;
 };
 
+export type BackstopVaultAccount = {
+	pubkey: PublicKey;
+	user: PublicKey;
+	totalShares: BN;
+	unstakingPeriod: BN;
+	liquidationDelay: BN;
+};
+
+export type BackstopVaultDepositor = {
+	authority: PublicKey;
+	shares: BN;
+	costBasis: BN;
+
+	lastWithdrawRequestShares: BN;
+	lastWithdrawRequestValue: BN;
+	lastWithdrawRequestTs: BN;
+};
+
 export type SerumV3FulfillmentConfigAccount = {
 	fulfillmentType: SpotFulfillmentType;
 	status: SpotFulfillmentStatus;